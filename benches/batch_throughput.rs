@@ -0,0 +1,103 @@
+// batch_throughput compares moving datagrams one at a time on a tokio
+// UdpSocket with BatchConn, run it with and without the batch feature:
+//
+//     cargo bench --bench batch_throughput --features batch
+
+use webrtc_rs_turn::batch::{BatchConn, DEFAULT_BATCH_SIZE};
+use webrtc_rs_turn::Error;
+
+use util::Conn;
+
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+const DATAGRAM_SIZE: usize = 200;
+// each round sends a burst small enough to fit the receive buffer
+const BURST: usize = 64;
+const ROUNDS: usize = 2000;
+
+fn report(name: &str, packets: usize, elapsed: Duration) {
+    println!(
+        "{:<28} {:>10.0} datagrams/s",
+        name,
+        packets as f64 / elapsed.as_secs_f64()
+    );
+}
+
+async fn bench_send_to(packets: &[(Vec<u8>, std::net::SocketAddr)]) -> Result<Duration, Error> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for (data, to) in packets {
+            socket.send_to(data, *to).await?;
+        }
+    }
+    Ok(start.elapsed())
+}
+
+async fn bench_send_batch(packets: &[(Vec<u8>, std::net::SocketAddr)]) -> Result<Duration, Error> {
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, DEFAULT_BATCH_SIZE);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        conn.send_batch(packets).await?;
+    }
+    Ok(start.elapsed())
+}
+
+async fn bench_recv_from(receiver: &dyn Conn) -> Result<Duration, Error> {
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    let to = receiver.local_addr()?;
+    let data = vec![0u8; DATAGRAM_SIZE];
+    let mut buf = vec![0u8; 1500];
+
+    let mut elapsed = Duration::from_secs(0);
+    for _ in 0..ROUNDS {
+        for _ in 0..BURST {
+            sender.send_to(&data, to).await?;
+        }
+
+        let start = Instant::now();
+        for _ in 0..BURST {
+            receiver.recv_from(&mut buf).await?;
+        }
+        elapsed += start.elapsed();
+    }
+    Ok(elapsed)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    // the receiver is never read, the kernel drops what doesn't fit
+    let sink = UdpSocket::bind("127.0.0.1:0").await?;
+    let packets: Vec<(Vec<u8>, std::net::SocketAddr)> = (0..BURST)
+        .map(|_| (vec![0u8; DATAGRAM_SIZE], sink.local_addr().unwrap()))
+        .collect();
+    let total = BURST * ROUNDS;
+
+    report(
+        "send: UdpSocket::send_to",
+        total,
+        bench_send_to(&packets).await?,
+    );
+    report(
+        "send: BatchConn::send_batch",
+        total,
+        bench_send_batch(&packets).await?,
+    );
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    report(
+        "recv: UdpSocket::recv_from",
+        total,
+        bench_recv_from(&socket).await?,
+    );
+
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, DEFAULT_BATCH_SIZE);
+    report(
+        "recv: BatchConn::recv_from",
+        total,
+        bench_recv_from(&conn).await?,
+    );
+
+    Ok(())
+}
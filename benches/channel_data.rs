@@ -0,0 +1,80 @@
+// channel_data compares building and parsing ChannelData through
+// encode/decode with the in-place frame/decode_header used on the relay path:
+//
+//     cargo bench --bench channel_data
+
+use webrtc_rs_turn::proto::chandata::{ChannelData, CHANNEL_DATA_HEADER_SIZE};
+use webrtc_rs_turn::proto::channum::{ChannelNumber, MIN_CHANNEL_NUMBER};
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const PAYLOAD_SIZE: usize = 1200;
+const ITERATIONS: usize = 1_000_000;
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<36} {:>8.1} ns/op",
+        name,
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() -> Result<(), util::Error> {
+    let number = ChannelNumber(MIN_CHANNEL_NUMBER);
+    let payload = vec![0xa5u8; PAYLOAD_SIZE];
+
+    // peer to client, the payload has been received into a buffer
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut channel_data = ChannelData {
+            data: black_box(&payload).to_vec(),
+            number,
+            raw: vec![],
+        };
+        channel_data.encode()?;
+        black_box(&channel_data.raw);
+    }
+    report("peer to client: ChannelData::encode", start.elapsed());
+
+    let mut buffer = vec![0u8; CHANNEL_DATA_HEADER_SIZE + PAYLOAD_SIZE + 3];
+    buffer[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + PAYLOAD_SIZE]
+        .copy_from_slice(&payload);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        ChannelData::frame(black_box(&mut buffer), number, PAYLOAD_SIZE)?;
+        black_box(&buffer);
+        // a pooled buffer is resized back before the next read
+        buffer.resize(CHANNEL_DATA_HEADER_SIZE + PAYLOAD_SIZE + 3, 0);
+    }
+    report("peer to client: ChannelData::frame", start.elapsed());
+
+    // client to peer, the ChannelData message has been received
+    let mut channel_data = ChannelData {
+        data: payload.clone(),
+        number,
+        raw: vec![],
+    };
+    channel_data.encode()?;
+    let raw = channel_data.raw;
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut c = ChannelData {
+            raw: black_box(&raw).clone(),
+            ..Default::default()
+        };
+        c.decode()?;
+        black_box(&c.data);
+    }
+    report("client to peer: ChannelData::decode", start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let (_, l) = ChannelData::decode_header(black_box(&raw))?;
+        black_box(&raw[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + l]);
+    }
+    report("client to peer: decode_header", start.elapsed());
+
+    Ok(())
+}
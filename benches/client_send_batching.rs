@@ -0,0 +1,147 @@
+// client_send_batching sends bursts of small ChannelData to 50 peers through
+// an in-process server, written one at a time and with SendBatching, and
+// counts the writes to the client's socket:
+//
+//     cargo bench --bench client_send_batching --features batch,test-util
+
+use webrtc_rs_turn::batch::BatchConn;
+use webrtc_rs_turn::client::send_batch::{BatchSend, SendBatching};
+use webrtc_rs_turn::client::{Client, ClientConfig};
+use webrtc_rs_turn::test_util::*;
+use webrtc_rs_turn::Error;
+
+use util::Conn;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+const PEERS: usize = 50;
+// an Opus frame of 20 ms
+const PAYLOAD_SIZE: usize = 160;
+const ROUNDS: usize = 1000;
+
+// CountingConn counts the write calls on a BatchConn
+struct CountingConn {
+    conn: BatchConn,
+    writes: AtomicU64,
+}
+
+#[async_trait]
+impl Conn for CountingConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+}
+
+#[async_trait]
+impl BatchSend for CountingConn {
+    async fn send_batch(&self, packets: &[Vec<u8>], to: SocketAddr) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        BatchSend::send_batch(&self.conn, packets, to).await
+    }
+}
+
+async fn bench(server_addr: SocketAddr, peers: &[SocketAddr], batched: bool) -> Result<(), Error> {
+    let conn = Arc::new(CountingConn {
+        conn: BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 0),
+        writes: AtomicU64::new(0),
+    });
+    let send_batching = if batched {
+        Some(SendBatching {
+            batch_conn: Some(Arc::clone(&conn) as Arc<dyn BatchSend + Send + Sync>),
+            ..Default::default()
+        })
+    } else {
+        None
+    };
+    let client = Client::new(ClientConfig {
+        send_batching,
+        ..client_config(
+            server_addr,
+            Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>,
+        )
+    })
+    .await?;
+    client.listen().await?;
+    let allocation = client.allocate().await?;
+
+    // bind the channels first, only ChannelData is batched
+    for peer in peers {
+        allocation.send_to(b"bind", *peer).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let payload = vec![0u8; PAYLOAD_SIZE];
+    let writes = conn.writes.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for peer in peers {
+            allocation.send_to(&payload, *peer).await?;
+        }
+        tokio::task::yield_now().await;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let elapsed = start.elapsed() - Duration::from_millis(10);
+    let writes = conn.writes.load(Ordering::Relaxed) - writes;
+
+    let total = (ROUNDS * PEERS) as f64;
+    println!(
+        "{:<10} {:>10.0} datagrams/s {:>10.0} writes/s {:>6.2} writes/datagram",
+        if batched { "batched" } else { "unbatched" },
+        total / elapsed.as_secs_f64(),
+        writes as f64 / elapsed.as_secs_f64(),
+        writes as f64 / total,
+    );
+
+    client.close().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    // the peers are never read, the kernel drops what doesn't fit
+    let mut sinks = vec![];
+    for _ in 0..PEERS {
+        sinks.push(UdpSocket::bind("127.0.0.1:0").await?);
+    }
+    let peers = sinks
+        .iter()
+        .map(|sink| sink.local_addr())
+        .collect::<io::Result<Vec<_>>>()?;
+
+    bench(server_addr, &peers, false).await?;
+    bench(server_addr, &peers, true).await?;
+
+    server.close()?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+// unallocated_traffic measures what a server listener spends on datagrams
+// from a 5-tuple without an allocation. ChannelData and indications are
+// dropped on their first bytes, requests of the same size are parsed and
+// answered:
+//
+//     cargo bench --bench unallocated_traffic
+
+use webrtc_rs_turn::auth::{generate_auth_key, AuthHandler};
+use webrtc_rs_turn::proto::chandata::ChannelData;
+use webrtc_rs_turn::proto::channum::{ChannelNumber, MIN_CHANNEL_NUMBER};
+use webrtc_rs_turn::proto::data::Data;
+use webrtc_rs_turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc_rs_turn::relay::PortSelection;
+use webrtc_rs_turn::server::config::ServerConfig;
+use webrtc_rs_turn::server::Server;
+use webrtc_rs_turn::Error;
+
+use stun::agent::TransactionId;
+use stun::fingerprint::FINGERPRINT;
+use stun::message::*;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+const PAYLOAD_SIZE: usize = 200;
+// each round sends a burst small enough to fit the receive buffer
+const BURST: usize = 64;
+const ROUNDS: usize = 2000;
+
+struct StaticAuthHandler;
+
+impl AuthHandler for StaticAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(generate_auth_key(username, realm, "pass"))
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<36} {:>8.1} ns/packet",
+        name,
+        elapsed.as_nanos() as f64 / (BURST * ROUNDS) as f64
+    );
+}
+
+// bench_listener sends raw in bursts and waits for the listener to have read
+// each burst, the sends are timed too
+async fn bench_listener(
+    server: &Server,
+    server_addr: SocketAddr,
+    raw: &[u8],
+) -> Result<Duration, Error> {
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    let packets_in = || server.listener_metrics()[0].1.packets_in;
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let target = packets_in() + BURST as u64;
+        for _ in 0..BURST {
+            sender.send_to(raw, server_addr).await?;
+        }
+        while packets_in() < target {
+            tokio::task::yield_now().await;
+        }
+    }
+    Ok(start.elapsed())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let server_addr = conn.local_addr()?;
+    let server = Server::new(
+        ServerConfig::builder()
+            .add_conn(
+                conn,
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: "127.0.0.1".parse()?,
+                    address: "127.0.0.1".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(StaticAuthHandler))
+            .build()?,
+    )
+    .await?;
+
+    let mut channel_data = ChannelData {
+        data: vec![0xa5; PAYLOAD_SIZE],
+        number: ChannelNumber(MIN_CHANNEL_NUMBER),
+        raw: vec![],
+    };
+    channel_data.encode()?;
+    report(
+        "garbage ChannelData",
+        bench_listener(&server, server_addr, &channel_data.raw).await?,
+    );
+
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_SEND, CLASS_INDICATION)),
+        Box::new(Data(vec![0xa5; PAYLOAD_SIZE])),
+        Box::new(FINGERPRINT),
+    ])?;
+    report(
+        "Send indication",
+        bench_listener(&server, server_addr, &msg.raw).await?,
+    );
+
+    // the same message as a request without credentials is parsed and
+    // answered with a 401
+    let mut raw = msg.raw.clone();
+    let typ = MessageType::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST);
+    raw[..2].copy_from_slice(&typ.value().to_be_bytes());
+    report(
+        "unauthenticated CreatePermission",
+        bench_listener(&server, server_addr, &raw).await?,
+    );
+
+    let (_, metrics) = server.listener_metrics()[0];
+    println!(
+        "dropped without an allocation: {}",
+        metrics.unallocated_dropped
+    );
+
+    server.close()?;
+
+    Ok(())
+}
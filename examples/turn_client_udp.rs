@@ -1,12 +1,11 @@
 use webrtc_rs_turn as turn;
 
-use turn::client::*;
+use turn::prelude::*;
 
 use clap::{App, AppSettings, Arg};
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::time::Duration;
-use util::{Conn, Error};
 
 // RUST_LOG=trace cargo run --color=always --package webrtc-rs-turn --example turn_client_udp -- --host 0.0.0.0 --user user=pass --ping
 
@@ -85,9 +84,7 @@ async fn main() -> Result<(), Error> {
         username: cred[0].to_string(),
         password: cred[1].to_string(),
         realm: realm.to_string(),
-        software: String::new(),
-        rto_in_ms: 0,
-        conn: Arc::new(conn),
+        ..ClientConfig::new(Arc::new(conn))
     };
 
     let client = Client::new(cfg).await?;
@@ -102,7 +99,7 @@ async fn main() -> Result<(), Error> {
 
     // The relayConn's local address is actually the transport
     // address assigned on the TURN server.
-    println!("relayed-address={}", relay_conn.local_addr()?.to_string());
+    println!("relayed-address={}", relay_conn.local_addr()?);
 
     // If you provided `-ping`, perform a ping test agaist the
     // relayConn we have just allocated.
@@ -185,7 +182,7 @@ async fn do_ping_test(
     // Send 10 packets from relay_conn to the echo server
     for _ in 0..2 {
         let msg = "12345678910".to_owned(); //format!("{:?}", tokio::time::Instant::now());
-        println!("sending msg={} with size={}", msg, msg.as_bytes().len());
+        println!("sending msg={} with size={}", msg, msg.len());
         pinger_conn_tx.send_to(msg.as_bytes(), relay_addr).await?;
 
         // For simplicity, this example does not wait for the pong (reply).
@@ -1,9 +1,6 @@
 use webrtc_rs_turn as turn;
 
-use turn::auth::*;
-use turn::errors::*;
-use turn::relay::relay_static::*;
-use turn::server::{config::*, *};
+use turn::prelude::*;
 
 use clap::{App, AppSettings, Arg};
 use std::collections::HashMap;
@@ -14,8 +11,6 @@ use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::time::Duration;
 
-use util::Error;
-
 use signal_hook::iterator::Signals;
 
 struct MyAuthHandler {
@@ -39,7 +34,7 @@ impl AuthHandler for MyAuthHandler {
             //log::debug!("username={}, password={:?}", username, pw);
             Ok(pw.to_vec())
         } else {
-            Err(ERR_FAKE_ERR.to_owned())
+            Err(Error::Auth(format!("no such user {}", username)))
         }
     }
 }
@@ -115,31 +110,36 @@ async fn main() -> Result<(), Error> {
     // Create a UDP listener to pass into pion/turn
     // turn itself doesn't allocate any UDP sockets, but lets the user pass them in
     // this allows us to add logging, storage or modify inbound/outbound traffic
-    let conn = Arc::new(UdpSocket::bind(format!("0.0.0.0:{}", port)).await?);
+    // BatchConn reads the listener in batches, with recvmmsg when built with the batch feature
+    let conn = Arc::new(BatchConn::new(
+        UdpSocket::bind(format!("0.0.0.0:{}", port)).await?,
+        0,
+    ));
     println!("listening {}...", conn.local_addr()?);
 
-    let server = Server::new(ServerConfig {
-        conn_configs: vec![ConnConfig {
+    let config = ServerConfig::builder()
+        .add_conn(
             conn,
-            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+            Box::new(RelayAddressGeneratorStatic {
                 relay_address: IpAddr::from_str(public_ip)?,
                 address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
             }),
-        }],
-        realm: realm.to_owned(),
-        auth_handler: Arc::new(Box::new(MyAuthHandler::new(cred_map))),
-        channel_bind_timeout: Duration::from_secs(0),
-    })
-    .await?;
-
-    let mut signals = Signals::new(&[signal_hook::consts::SIGINT]).unwrap();
+        )
+        .realm(realm)
+        .auth_handler(Box::new(MyAuthHandler::new(cred_map)))
+        .build()?;
+
+    let server = Server::new(config).await?;
+
+    let mut signals = Signals::new([signal_hook::consts::SIGINT]).unwrap();
     let close_handle = signals.handle();
 
-    for _sig in signals.forever() {
+    if signals.forever().next().is_some() {
         println!("closing connection now");
-        server.close()?;
+        server.shutdown(Duration::from_secs(5)).await?;
         close_handle.close();
-        return Ok(());
     }
 
     Ok(())
@@ -0,0 +1,48 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use stun::message::*;
+
+use webrtc_rs_turn::proto::{
+    chandata::ChannelData, channum::ChannelNumber, data::Data, dontfrag::DontFragmentAttr,
+    evenport::EvenPort, icmp::Icmp, lifetime::Lifetime, origin::Origin, peeraddr::PeerAddress,
+    relayaddr::RelayedAddress, reqfamily::RequestedAddressFamily, reqtrans::RequestedTransport,
+    rsrvtoken::ReservationToken,
+};
+
+// Parse the input the same way the client and server do, then run every
+// proto attribute getter against the decoded message. Errors are expected,
+// panics are not.
+fuzz_target!(|data: &[u8]| {
+    if ChannelData::is_channel_data(data) {
+        let mut c = ChannelData {
+            raw: data.to_vec(),
+            ..Default::default()
+        };
+        let _ = c.decode();
+        return;
+    }
+
+    if !is_message(data) {
+        return;
+    }
+
+    let mut m = Message::new();
+    m.raw = data.to_vec();
+    if m.decode().is_err() {
+        return;
+    }
+
+    let _ = ChannelNumber::default().get_from(&m);
+    let _ = Data::default().get_from(&m);
+    let _ = DontFragmentAttr::default().get_from(&m);
+    let _ = EvenPort::default().get_from(&m);
+    let _ = Icmp::default().get_from(&m);
+    let _ = Lifetime::default().get_from(&m);
+    let _ = Origin::default().get_from(&m);
+    let _ = PeerAddress::default().get_from(&m);
+    let _ = RelayedAddress::default().get_from(&m);
+    let _ = RequestedAddressFamily::default().get_from(&m);
+    let _ = RequestedTransport::default().get_from(&m);
+    let _ = ReservationToken::default().get_from(&m);
+});
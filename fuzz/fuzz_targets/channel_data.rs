@@ -0,0 +1,39 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use webrtc_rs_turn::proto::chandata::ChannelData;
+
+fuzz_target!(|data: &[u8]| {
+    let looks_like_channel_data = ChannelData::is_channel_data(data);
+
+    let mut c = ChannelData {
+        raw: data.to_vec(),
+        ..Default::default()
+    };
+    if c.decode().is_err() {
+        return;
+    }
+    assert!(
+        looks_like_channel_data,
+        "decoded ChannelData rejected by is_channel_data"
+    );
+
+    // Whatever we managed to decode must survive a round trip.
+    let mut encoded = ChannelData {
+        data: c.data.clone(),
+        number: c.number,
+        ..Default::default()
+    };
+    encoded
+        .encode()
+        .expect("decoded ChannelData must re-encode");
+
+    let mut decoded = ChannelData {
+        raw: encoded.raw,
+        ..Default::default()
+    };
+    decoded
+        .decode()
+        .expect("re-encoded ChannelData must decode");
+    assert_eq!(decoded, c);
+});
@@ -0,0 +1,114 @@
+#[cfg(test)]
+mod allocation_limit_test;
+
+use crate::server::event::*;
+
+use stun::error_code::ErrorCode;
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// AllocationLimit caps the allocations of every Manager it is shared with.
+// Each allocation holds an AllocationPermit, the count is only ever raised
+// with a compare and swap below max so concurrent allocates can't overshoot.
+pub struct AllocationLimit {
+    max: usize,
+    // recovered is the count at or below which the limit leaves capacity
+    recovered: usize,
+    code: ErrorCode,
+    count: AtomicUsize,
+    at_capacity: AtomicBool,
+    event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+}
+
+impl fmt::Debug for AllocationLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllocationLimit")
+            .field("max", &self.max)
+            .field("count", &self.count())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AllocationLimit {
+    // new caps allocations at max, Allocate requests over it are rejected with
+    // code. CapacityReached is emitted when the count reaches max and
+    // CapacityRecovered once it has fallen by a tenth of max, rounded up.
+    pub fn new(
+        max: usize,
+        code: ErrorCode,
+        event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    ) -> Self {
+        AllocationLimit {
+            max,
+            recovered: max - max.div_ceil(10),
+            code,
+            count: AtomicUsize::new(0),
+            at_capacity: AtomicBool::new(false),
+            event_handler,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // count is the number of allocations holding a permit
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // code is the error code an Allocate over the limit is answered with
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    // try_acquire takes a slot, it returns None when all max are taken
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<AllocationPermit> {
+        let count = self
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < self.max {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?
+            + 1;
+
+        if count == self.max && !self.at_capacity.swap(true, Ordering::SeqCst) {
+            self.emit(ServerEvent::CapacityReached { allocations: count });
+        }
+
+        Some(AllocationPermit {
+            limit: Arc::clone(self),
+        })
+    }
+
+    fn release(&self) {
+        let count = self.count.fetch_sub(1, Ordering::SeqCst) - 1;
+        if count <= self.recovered && self.at_capacity.swap(false, Ordering::SeqCst) {
+            self.emit(ServerEvent::CapacityRecovered { allocations: count });
+        }
+    }
+
+    fn emit(&self, event: ServerEvent) {
+        log::info!("allocation limit of {}: {:?}", self.max, event);
+        if let Some(event_handler) = &self.event_handler {
+            event_handler.on_event(event);
+        }
+    }
+}
+
+// AllocationPermit is a slot of an AllocationLimit, it is released on drop
+pub(crate) struct AllocationPermit {
+    limit: Arc<AllocationLimit>,
+}
+
+impl Drop for AllocationPermit {
+    fn drop(&mut self) {
+        self.limit.release();
+    }
+}
@@ -0,0 +1,160 @@
+use super::*;
+use crate::allocation::allocation_manager::*;
+use crate::allocation::five_tuple::*;
+use crate::allocation::relay_workers::RelayReadMode;
+use crate::error::Error;
+use crate::proto::lifetime::DEFAULT_LIFETIME;
+use crate::relay::relay_none::*;
+use crate::relay::PortSelection;
+
+use stun::error_code::CODE_ALLOC_QUOTA_REACHED;
+use util::Conn;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+
+use tokio::net::UdpSocket;
+
+#[derive(Default)]
+struct RecordingEventHandler {
+    events: Arc<Mutex<Vec<ServerEvent>>>,
+}
+
+impl EventHandler for RecordingEventHandler {
+    fn on_event(&self, event: ServerEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+fn new_test_limit(max: usize) -> (Arc<AllocationLimit>, Arc<Mutex<Vec<ServerEvent>>>) {
+    let handler = RecordingEventHandler::default();
+    let events = Arc::clone(&handler.events);
+    (
+        Arc::new(AllocationLimit::new(
+            max,
+            CODE_ALLOC_QUOTA_REACHED,
+            Some(Arc::new(handler)),
+        )),
+        events,
+    )
+}
+
+#[test]
+fn test_allocation_limit_hysteresis() {
+    let (limit, events) = new_test_limit(20);
+
+    let mut permits: Vec<AllocationPermit> = (0..20).filter_map(|_| limit.try_acquire()).collect();
+    assert_eq!(20, permits.len());
+    assert!(
+        limit.try_acquire().is_none(),
+        "the 21st allocation is over max"
+    );
+    assert_eq!(
+        vec![ServerEvent::CapacityReached { allocations: 20 }],
+        *events.lock().unwrap()
+    );
+
+    // a slot freed and taken again stays within the hysteresis band
+    permits.pop();
+    permits.push(limit.try_acquire().expect("a slot was released"));
+    permits.pop();
+    assert_eq!(1, events.lock().unwrap().len());
+
+    // 18 is 20 less a tenth
+    permits.pop();
+    assert_eq!(18, limit.count());
+    assert_eq!(
+        Some(&ServerEvent::CapacityRecovered { allocations: 18 }),
+        events.lock().unwrap().last()
+    );
+
+    drop(permits);
+    assert_eq!(0, limit.count());
+    assert_eq!(2, events.lock().unwrap().len());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_allocation_limit_concurrent_allocates() -> Result<(), Error> {
+    const MAX_ALLOCATIONS: usize = 5;
+    const CLIENTS: usize = 40;
+
+    let (limit, events) = new_test_limit(MAX_ALLOCATIONS);
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+
+    // two listeners share the server's limit
+    let managers: Vec<Arc<Manager>> = (0..2)
+        .map(|_| {
+            Arc::new(Manager::new(ManagerConfig {
+                relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+                    address: "127.0.0.1".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+                relay_queue_size: 0,
+                relay_read_mode: RelayReadMode::default(),
+                allocation_limit: Some(Arc::clone(&limit)),
+                event_handler: None,
+            }))
+        })
+        .collect();
+
+    let mut handles = vec![];
+    for i in 0..CLIENTS {
+        let m = Arc::clone(&managers[i % managers.len()]);
+        let turn_socket = Arc::clone(&turn_socket);
+        let limit = Arc::clone(&limit);
+        handles.push(tokio::spawn(async move {
+            let five_tuple = FiveTuple {
+                src_addr: SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 10000 + i as u16),
+                dst_addr: turn_socket.local_addr()?,
+                ..Default::default()
+            };
+            let result = m
+                .create_allocation(five_tuple, turn_socket, 0, DEFAULT_LIFETIME, "user", b"key")
+                .await;
+            assert!(limit.count() <= MAX_ALLOCATIONS, "count overshot max");
+            result.map(|_| ())
+        }));
+    }
+
+    let mut allocated = 0;
+    for handle in handles {
+        match handle.await.map_err(|err| Error::Other(err.to_string()))? {
+            Ok(()) => allocated += 1,
+            Err(Error::MaxAllocationsReached) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(MAX_ALLOCATIONS, allocated);
+    assert_eq!(MAX_ALLOCATIONS, limit.count());
+    assert_eq!(
+        vec![ServerEvent::CapacityReached {
+            allocations: MAX_ALLOCATIONS
+        }],
+        *events.lock().unwrap()
+    );
+
+    // deleting an allocation releases its slot
+    let five_tuple = {
+        let a = managers[0].allocations().await;
+        let a = a.first().expect("an allocation").lock().await;
+        a.five_tuple.clone()
+    };
+    managers[0]
+        .delete_allocation(&five_tuple, AllocationCloseReason::Deleted)
+        .await;
+    assert_eq!(MAX_ALLOCATIONS - 1, limit.count());
+    assert_eq!(
+        Some(&ServerEvent::CapacityRecovered {
+            allocations: MAX_ALLOCATIONS - 1
+        }),
+        events.lock().unwrap().last()
+    );
+
+    for m in &managers {
+        m.close().await?;
+    }
+    assert_eq!(0, limit.count());
+
+    Ok(())
+}
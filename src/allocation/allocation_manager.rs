@@ -2,16 +2,24 @@
 mod allocation_manager_test;
 
 use super::*;
-use crate::errors::*;
 use crate::relay::*;
 
 use std::collections::HashMap;
 
-use util::{Conn, Error};
+use util::Conn;
 
 // ManagerConfig a bag of config params for Manager.
 pub struct ManagerConfig {
     pub relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+    // relay_queue_size is the depth of each allocation's relay queues, zero
+    // selects DEFAULT_RELAY_QUEUE_SIZE
+    pub relay_queue_size: usize,
+    // relay_read_mode selects how the allocations' relay sockets are read
+    pub relay_read_mode: RelayReadMode,
+    // allocation_limit caps the allocations, it may be shared between managers
+    pub allocation_limit: Option<Arc<AllocationLimit>>,
+    // event_handler is told of each allocation's deletion
+    pub event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
 }
 
 // Manager is used to hold active allocations
@@ -19,28 +27,70 @@ pub struct Manager {
     allocations: AllocationMap,
     reservations: Arc<Mutex<HashMap<String, u16>>>,
     relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+    relay_queue_size: usize,
+    relay_workers: RelayWorkers,
+    allocation_limit: Option<Arc<AllocationLimit>>,
+    event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    // listener_counters are the metrics of the listener the manager serves
+    pub(crate) listener_counters: Option<Arc<ListenerCounters>>,
 }
 
 impl Manager {
-    // creates a new instance of Manager.
+    // creates a new instance of Manager, with RelayReadMode::SharedPoll it
+    // must be called within a tokio runtime
     pub fn new(config: ManagerConfig) -> Self {
         Manager {
             allocations: Arc::new(Mutex::new(HashMap::new())),
             reservations: Arc::new(Mutex::new(HashMap::new())),
             relay_addr_generator: config.relay_addr_generator,
+            relay_queue_size: config.relay_queue_size,
+            relay_workers: RelayWorkers::new(config.relay_read_mode),
+            allocation_limit: config.allocation_limit,
+            event_handler: config.event_handler,
+            listener_counters: None,
         }
     }
 
-    // Close closes the manager and closes all allocations it manages
+    // Close closes the manager and closes all allocations it manages. The
+    // allocations are removed, they refer back to the map.
     pub async fn close(&self) -> Result<(), Error> {
-        let allocations = self.allocations.lock().await;
-        for a in allocations.values() {
+        let mut allocations = self.allocations.lock().await;
+        for (_, a) in allocations.drain() {
             let mut a = a.lock().await;
-            a.close().await?;
+            a.close(AllocationCloseReason::Shutdown).await?;
         }
         Ok(())
     }
 
+    // relay_tasks_exited waits until the relay tasks of the allocations closed
+    // so far have exited, after close none are left touching the sockets
+    pub(crate) async fn relay_tasks_exited(&self) {
+        self.relay_workers.exited().await;
+    }
+
+    // allocation_limit is the cap on allocations this manager shares, if any
+    pub fn allocation_limit(&self) -> Option<&Arc<AllocationLimit>> {
+        self.allocation_limit.as_ref()
+    }
+
+    // port_capacity is the number of relay ports the manager's
+    // RelayAddressGenerator can hand out, if it has a range
+    pub fn port_capacity(&self) -> Option<usize> {
+        self.relay_addr_generator.port_capacity()
+    }
+
+    // port_selection is how the manager's RelayAddressGenerator picks relay
+    // ports
+    pub fn port_selection(&self) -> Option<PortSelection> {
+        self.relay_addr_generator.port_selection()
+    }
+
+    // pool_metrics is the occupancy of the manager's RelayAddressGenerator, if
+    // it is a PreallocatedRelayPool
+    pub fn pool_metrics(&self) -> Option<RelayPoolMetrics> {
+        self.relay_addr_generator.pool_metrics()
+    }
+
     // get_allocation fetches the allocation matching the passed FiveTuple
     pub async fn get_allocation(&self, five_tuple: &FiveTuple) -> Option<Arc<Mutex<Allocation>>> {
         let allocations = self.allocations.lock().await;
@@ -51,6 +101,17 @@ impl Manager {
         }
     }
 
+    // allocations returns the active allocations, the map is only locked while copying
+    pub(crate) async fn allocations(&self) -> Vec<Arc<Mutex<Allocation>>> {
+        let allocations = self.allocations.lock().await;
+        allocations.values().cloned().collect()
+    }
+
+    // allocation_count is the number of allocations the manager holds
+    pub(crate) async fn allocation_count(&self) -> usize {
+        self.allocations.lock().await.len()
+    }
+
     // create_allocation creates a new allocation and starts relaying
     pub async fn create_allocation(
         &self,
@@ -58,21 +119,47 @@ impl Manager {
         turn_socket: Arc<dyn Conn + Send + Sync>,
         requested_port: u16,
         lifetime: Duration,
+        username: &str,
+        auth_key: &[u8],
     ) -> Result<Arc<Mutex<Allocation>>, Error> {
         if lifetime == Duration::from_secs(0) {
-            return Err(ERR_LIFETIME_ZERO.to_owned());
+            return Err(Error::LifetimeZero);
         }
 
         if self.get_allocation(&five_tuple).await.is_some() {
-            return Err(ERR_DUPE_FIVE_TUPLE.to_owned());
+            return Err(Error::DupeFiveTuple);
         }
 
+        // the permit is taken first, so the count is exact under concurrent
+        // allocates, and released again if the allocation fails
+        let allocation_permit = match &self.allocation_limit {
+            Some(allocation_limit) => Some(
+                allocation_limit
+                    .try_acquire()
+                    .ok_or(Error::MaxAllocationsReached)?,
+            ),
+            None => None,
+        };
+
         let (relay_socket, relay_addr) = self
             .relay_addr_generator
             .allocate_conn("udp4", requested_port)
             .await?;
         let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, five_tuple.clone());
         a.allocations = Some(Arc::clone(&self.allocations));
+        a.username = username.to_owned();
+        a.auth_key = auth_key.to_vec();
+        a.relay_queue_size = self.relay_queue_size;
+        a.relay_workers = self.relay_workers.clone();
+        a.allocation_permit = allocation_permit;
+        a.event_handler = self.event_handler.clone();
+        a.listener_counters = self.listener_counters.clone();
+        a.span = turn_span!(
+            "allocation",
+            five_tuple = %a.five_tuple,
+            username = %a.username,
+            relayed_addr = %a.relay_addr,
+        );
 
         log::debug!("listening on relay addr: {:?}", a.relay_addr);
         a.start(lifetime).await;
@@ -87,15 +174,16 @@ impl Manager {
         Ok(a)
     }
 
-    // delete_allocation removes an allocation
-    pub async fn delete_allocation(&self, five_tuple: &FiveTuple) {
+    // delete_allocation removes an allocation, reason is reported to the
+    // event handler and the listener's metrics
+    pub async fn delete_allocation(&self, five_tuple: &FiveTuple, reason: AllocationCloseReason) {
         let fingerprint = five_tuple.fingerprint();
 
         let mut allocations = self.allocations.lock().await;
         let allocation = allocations.remove(&fingerprint);
         if let Some(a) = allocation {
             let mut a = a.lock().await;
-            if let Err(err) = a.close().await {
+            if let Err(err) = a.close(reason).await {
                 log::error!("Failed to close allocation: {}", err);
             }
         }
@@ -124,11 +212,14 @@ impl Manager {
     // get_reservation returns the port for a given reservation if it exists
     pub async fn get_reservation(&self, reservation_token: &str) -> Option<u16> {
         let reservations = self.reservations.lock().await;
-        if let Some(port) = reservations.get(reservation_token) {
-            Some(*port)
-        } else {
-            None
-        }
+        reservations.get(reservation_token).copied()
+    }
+
+    // probe_relay binds a relay conn with the generator and releases it at
+    // once, returning its relayed address
+    pub async fn probe_relay(&self) -> Result<SocketAddr, Error> {
+        let (_, addr) = self.relay_addr_generator.allocate_conn("udp4", 0).await?;
+        Ok(addr)
     }
 
     // get_random_even_port returns a random un-allocated udp4 port
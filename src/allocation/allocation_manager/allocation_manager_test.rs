@@ -1,17 +1,24 @@
 use super::*;
 use crate::relay::relay_none::*;
 
+use crate::error::Error;
 use crate::proto::lifetime::DEFAULT_LIFETIME;
+use async_trait::async_trait;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
 use tokio::net::UdpSocket;
-use util::Error;
 
 fn new_test_manager() -> Manager {
     let config = ManagerConfig {
         relay_addr_generator: Box::new(RelayAddressGeneratorNone {
             address: "0.0.0.0".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
         }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
     };
     Manager::new(config)
 }
@@ -28,7 +35,26 @@ fn random_five_tuple() -> FiveTuple {
 #[tokio::test]
 async fn test_packet_handler() -> Result<(), Error> {
     //env_logger::init();
+    check_packet_handler(new_test_manager()).await
+}
+
+#[tokio::test]
+async fn test_packet_handler_shared_poll() -> Result<(), Error> {
+    check_packet_handler(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::SharedPoll { workers: 2 },
+        allocation_limit: None,
+        event_handler: None,
+    }))
+    .await
+}
 
+async fn check_packet_handler(m: Manager) -> Result<(), Error> {
     // turn server initialization
     let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
 
@@ -49,7 +75,6 @@ async fn test_packet_handler() -> Result<(), Error> {
         }
     });
 
-    let m = new_test_manager();
     let a = m
         .create_allocation(
             FiveTuple {
@@ -60,6 +85,8 @@ async fn test_packet_handler() -> Result<(), Error> {
             Arc::new(turn_socket),
             0,
             DEFAULT_LIFETIME,
+            "user",
+            b"key",
         )
         .await?;
 
@@ -95,7 +122,7 @@ async fn test_packet_handler() -> Result<(), Error> {
     let data = data_ch_rx
         .recv()
         .await
-        .ok_or_else(|| Error::new("data ch closed".to_owned()))?;
+        .ok_or_else(|| Error::Other("data ch closed".to_owned()))?;
 
     // resolve stun data message
     assert!(is_message(&data), "should be stun message");
@@ -120,7 +147,7 @@ async fn test_packet_handler() -> Result<(), Error> {
     let data = data_ch_rx
         .recv()
         .await
-        .ok_or_else(|| Error::new("data ch closed".to_owned()))?;
+        .ok_or_else(|| Error::Other("data ch closed".to_owned()))?;
 
     // resolve channel data
     assert!(
@@ -149,6 +176,99 @@ async fn test_packet_handler() -> Result<(), Error> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_packet_handler_burst() -> Result<(), Error> {
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client_listener = UdpSocket::bind("127.0.0.1:0").await?;
+    let src_addr = client_listener.local_addr()?;
+
+    // deep enough for the whole burst, see test_relay_queue_drops_to_slow_client
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 128,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    });
+    let a = m
+        .create_allocation(
+            FiveTuple {
+                src_addr,
+                dst_addr: turn_socket.local_addr()?,
+                ..Default::default()
+            },
+            Arc::new(turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            "user",
+            b"key",
+        )
+        .await?;
+
+    let peer_listener1 = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer_listener2 = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = {
+        let a = a.lock().await;
+        a.add_permission(Permission::new(peer_listener1.local_addr()?))
+            .await;
+        a.add_channel_bind(
+            ChannelBind::new(
+                ChannelNumber(MIN_CHANNEL_NUMBER),
+                peer_listener2.local_addr()?,
+            ),
+            DEFAULT_LIFETIME,
+        )
+        .await?;
+        a.relay_socket.local_addr()?.port()
+    };
+    let relay_addr = SocketAddr::from_str(&format!("127.0.0.1:{}", port))?;
+
+    // more datagrams than a batch, sent before the relay reads any of them
+    const BURST: u8 = 40;
+    for i in 0..BURST {
+        peer_listener1.send_to(&[1, i], relay_addr).await?;
+        peer_listener2.send_to(&[2, i], relay_addr).await?;
+    }
+
+    let mut next = [0u8; 2];
+    let mut buffer = vec![0u8; RTP_MTU];
+    for _ in 0..2 * BURST as usize {
+        let (n, _) = client_listener.recv_from(&mut buffer).await?;
+        let data = if ChannelData::is_channel_data(&buffer[..n]) {
+            let mut channel_data = ChannelData {
+                raw: buffer[..n].to_vec(),
+                ..Default::default()
+            };
+            channel_data.decode()?;
+            assert_eq!(channel_data.number, ChannelNumber(MIN_CHANNEL_NUMBER));
+            channel_data.data
+        } else {
+            let mut msg = Message::new();
+            msg.raw = buffer[..n].to_vec();
+            msg.decode()?;
+            let mut msg_data = Data::default();
+            msg_data.get_from(&msg)?;
+            msg_data.0
+        };
+
+        let peer = data[0] as usize - 1;
+        assert_eq!(
+            data[1], next[peer],
+            "datagrams from a peer should stay in order"
+        );
+        next[peer] += 1;
+    }
+    assert_eq!(next, [BURST, BURST]);
+
+    m.close().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_create_allocation_duplicate_five_tuple() -> Result<(), Error> {
     //env_logger::init();
@@ -166,13 +286,49 @@ async fn test_create_allocation_duplicate_five_tuple() -> Result<(), Error> {
             Arc::clone(&turn_socket),
             0,
             DEFAULT_LIFETIME,
+            "user",
+            b"key",
         )
         .await?;
 
     let result = m
-        .create_allocation(five_tuple, Arc::clone(&turn_socket), 0, DEFAULT_LIFETIME)
+        .create_allocation(
+            five_tuple,
+            Arc::clone(&turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            "user",
+            b"key",
+        )
         .await;
-    assert!(result.is_err(), "expected error, but got ok");
+    assert!(
+        matches!(result, Err(Error::DupeFiveTuple)),
+        "expected DupeFiveTuple error"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_allocation_zero_lifetime() -> Result<(), Error> {
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+
+    let m = new_test_manager();
+
+    let result = m
+        .create_allocation(
+            random_five_tuple(),
+            turn_socket,
+            0,
+            Duration::from_secs(0),
+            "user",
+            b"key",
+        )
+        .await;
+    assert!(
+        matches!(result, Err(Error::LifetimeZero)),
+        "expected LifetimeZero error"
+    );
 
     Ok(())
 }
@@ -194,6 +350,8 @@ async fn test_delete_allocation() -> Result<(), Error> {
             Arc::clone(&turn_socket),
             0,
             DEFAULT_LIFETIME,
+            "user",
+            b"key",
         )
         .await?;
 
@@ -202,7 +360,8 @@ async fn test_delete_allocation() -> Result<(), Error> {
         "Failed to get allocation right after creation"
     );
 
-    m.delete_allocation(&five_tuple).await;
+    m.delete_allocation(&five_tuple, AllocationCloseReason::Deleted)
+        .await;
 
     assert!(
         m.get_allocation(&five_tuple).await.is_none(),
@@ -213,7 +372,127 @@ async fn test_delete_allocation() -> Result<(), Error> {
     Ok(())
 }
 
+#[derive(Default)]
+struct RecordingEventHandler {
+    events: Arc<std::sync::Mutex<Vec<ServerEvent>>>,
+}
+
+impl EventHandler for RecordingEventHandler {
+    fn on_event(&self, event: ServerEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
 #[tokio::test]
+async fn test_delete_allocation_reports_traffic() -> Result<(), Error> {
+    let handler = RecordingEventHandler::default();
+    let events = Arc::clone(&handler.events);
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: Some(Arc::new(handler)),
+    });
+
+    let turn_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let five_tuple = FiveTuple {
+        src_addr: client.local_addr()?,
+        dst_addr: turn_socket.local_addr()?,
+        ..Default::default()
+    };
+    let a = m
+        .create_allocation(
+            five_tuple.clone(),
+            Arc::new(turn_socket),
+            0,
+            DEFAULT_LIFETIME,
+            "user",
+            b"key",
+        )
+        .await?;
+
+    let peer1 = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer2 = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_addr = {
+        let a = a.lock().await;
+        a.add_permission(Permission::new(peer1.local_addr()?)).await;
+        for _ in 0..3 {
+            a.relay_to_peer(vec![0; 100], 0, peer1.local_addr()?)?;
+        }
+        for _ in 0..2 {
+            a.relay_to_peer(vec![0; 54], 4, peer2.local_addr()?)?;
+        }
+        a.relay_socket.local_addr()?
+    };
+
+    // peer1 is relayed to the client in Data indications
+    let mut buf = vec![0u8; 1500];
+    for _ in 0..4 {
+        peer1.send_to(&[0; 200], relay_addr).await?;
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .map_err(|_| Error::Other("client read timed out".to_owned()))??;
+    }
+
+    m.delete_allocation(&five_tuple, AllocationCloseReason::Deleted)
+        .await;
+
+    let events = events.lock().unwrap();
+    let traffic = match events.as_slice() {
+        [ServerEvent::AllocationDeleted {
+            username,
+            reason: AllocationCloseReason::Deleted,
+            traffic,
+            ..
+        }] => {
+            assert_eq!("user", username);
+            traffic.clone()
+        }
+        events => panic!("expected AllocationDeleted, got {:?}", events),
+    };
+    assert_eq!(
+        vec![
+            PeerTraffic {
+                peer: peer1.local_addr()?,
+                traffic: Traffic {
+                    to_peer_bytes: 300,
+                    to_peer_packets: 3,
+                    to_client_bytes: 800,
+                    to_client_packets: 4,
+                },
+            },
+            PeerTraffic {
+                peer: peer2.local_addr()?,
+                traffic: Traffic {
+                    to_peer_bytes: 100,
+                    to_peer_packets: 2,
+                    ..Default::default()
+                },
+            },
+        ],
+        traffic.peers
+    );
+    assert_eq!(Traffic::default(), traffic.other);
+    assert_eq!(
+        Traffic {
+            to_peer_bytes: 400,
+            to_peer_packets: 5,
+            to_client_bytes: 800,
+            to_client_packets: 4,
+        },
+        traffic.total
+    );
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
 async fn test_allocation_timeout() -> Result<(), Error> {
     //env_logger::init();
 
@@ -223,24 +502,38 @@ async fn test_allocation_timeout() -> Result<(), Error> {
     let m = new_test_manager();
 
     let mut allocations = vec![];
-    let lifetime = Duration::from_millis(100);
+    let lifetime = DEFAULT_LIFETIME;
 
     for _ in 0..5 {
         let five_tuple = random_five_tuple();
 
         let a = m
-            .create_allocation(five_tuple, Arc::clone(&turn_socket), 0, lifetime)
+            .create_allocation(
+                five_tuple,
+                Arc::clone(&turn_socket),
+                0,
+                lifetime,
+                "user",
+                b"key",
+            )
             .await?;
 
         allocations.push(a);
     }
 
-    tokio::time::sleep(lifetime + Duration::from_millis(100)).await;
+    // still there just before the lifetime ends
+    tokio::time::sleep(lifetime - Duration::from_secs(1)).await;
+    for allocation in &allocations {
+        let a = allocation.lock().await;
+        assert!(m.get_allocation(&a.five_tuple).await.is_some());
+    }
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
     for allocation in allocations {
         let mut a = allocation.lock().await;
         assert!(
-            a.close().await.is_err(),
+            a.close(AllocationCloseReason::Deleted).await.is_err(),
             "Allocation should be closed if lifetime timeout"
         );
     }
@@ -265,6 +558,8 @@ async fn test_manager_close() -> Result<(), Error> {
             Arc::clone(&turn_socket),
             0,
             Duration::from_millis(100),
+            "user",
+            b"key",
         )
         .await?;
     allocations.push(a1);
@@ -275,6 +570,8 @@ async fn test_manager_close() -> Result<(), Error> {
             Arc::clone(&turn_socket),
             0,
             Duration::from_millis(200),
+            "user",
+            b"key",
         )
         .await?;
     allocations.push(a2);
@@ -288,10 +585,367 @@ async fn test_manager_close() -> Result<(), Error> {
     for allocation in allocations {
         let mut a = allocation.lock().await;
         assert!(
-            a.close().await.is_err(),
+            a.close(AllocationCloseReason::Deleted).await.is_err(),
             "Allocation should be closed if lifetime timeout"
         );
     }
 
     Ok(())
 }
+
+// SlowConn delays every send to slow_addr, standing in for a congested downlink
+struct SlowConn {
+    conn: UdpSocket,
+    slow_addr: SocketAddr,
+}
+
+#[async_trait]
+impl Conn for SlowConn {
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        if target == self.slow_addr {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+}
+
+#[tokio::test]
+async fn test_relay_queue_drops_to_slow_client() -> Result<(), Error> {
+    let slow_client = UdpSocket::bind("127.0.0.1:0").await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let turn_socket = Arc::new(SlowConn {
+        conn: UdpSocket::bind("127.0.0.1:0").await?,
+        slow_addr: slow_client.local_addr()?,
+    });
+
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 4,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    });
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut relay_addrs = vec![];
+    for src_addr in [slow_client.local_addr()?, client.local_addr()?].iter() {
+        let a = m
+            .create_allocation(
+                FiveTuple {
+                    src_addr: *src_addr,
+                    dst_addr: turn_socket.local_addr()?,
+                    ..Default::default()
+                },
+                Arc::clone(&turn_socket) as Arc<dyn Conn + Send + Sync>,
+                0,
+                DEFAULT_LIFETIME,
+                "user",
+                b"key",
+            )
+            .await?;
+        let a = a.lock().await;
+        a.add_permission(Permission::new(peer.local_addr()?)).await;
+        relay_addrs.push((a.relay_addr, Arc::clone(&a.stats)));
+    }
+
+    // flood the slow client's allocation, its writer needs 20ms per datagram
+    for i in 0..100u8 {
+        peer.send_to(&[i], relay_addrs[0].0).await?;
+    }
+
+    // the other allocation on the same listener is not held up
+    let mut buffer = vec![0u8; RTP_MTU];
+    for i in 0..10u8 {
+        let start = Instant::now();
+        peer.send_to(&[i], relay_addrs[1].0).await?;
+        tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buffer))
+            .await
+            .map_err(|_| Error::Other("datagram was not relayed".to_owned()))??;
+        assert!(
+            start.elapsed() < Duration::from_millis(100),
+            "relay latency should stay flat, took {:?}",
+            start.elapsed()
+        );
+    }
+
+    let (slow_stats, stats) = (&relay_addrs[0].1, &relay_addrs[1].1);
+    assert!(
+        slow_stats.dropped_to_client.load(Ordering::Relaxed) > 0,
+        "flooded allocation should drop datagrams"
+    );
+    assert_eq!(stats.dropped_to_client.load(Ordering::Relaxed), 0);
+    assert_eq!(stats.relayed_to_client.load(Ordering::Relaxed), 10);
+
+    m.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_poll_bounds_tasks() -> Result<(), Error> {
+    const ALLOCATIONS: usize = 1000;
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let metrics = tokio::runtime::Handle::current().metrics();
+
+    for (mode, max_tasks) in [
+        (RelayReadMode::TaskPerAllocation, None),
+        (RelayReadMode::SharedPoll { workers: 4 }, Some(4)),
+    ]
+    .iter()
+    {
+        let tasks_before = metrics.num_alive_tasks();
+        let m = Manager::new(ManagerConfig {
+            relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+                address: "127.0.0.1".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+            relay_queue_size: 0,
+            relay_read_mode: *mode,
+            allocation_limit: None,
+            event_handler: None,
+        });
+
+        for _ in 0..ALLOCATIONS {
+            m.create_allocation(
+                random_five_tuple(),
+                Arc::clone(&turn_socket),
+                0,
+                DEFAULT_LIFETIME,
+                "user",
+                b"key",
+            )
+            .await?;
+        }
+
+        let tasks = metrics.num_alive_tasks().saturating_sub(tasks_before);
+        match max_tasks {
+            Some(max_tasks) => assert!(
+                tasks <= *max_tasks,
+                "{:?} should run {} idle allocations on at most {} tasks, got {}",
+                mode,
+                ALLOCATIONS,
+                max_tasks,
+                tasks
+            ),
+            None => assert!(
+                tasks >= ALLOCATIONS,
+                "{:?} should spawn tasks per allocation, got {}",
+                mode,
+                tasks
+            ),
+        }
+
+        // closing the manager stops the relay tasks and the shared workers
+        m.close().await?;
+        drop(m);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.num_alive_tasks() > tasks_before {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| Error::Other("relay tasks were not stopped".to_owned()))?;
+    }
+
+    Ok(())
+}
+
+// deleted_reasons maps the client address of each deleted allocation to the
+// reason it was deleted for
+fn deleted_reasons(events: &[ServerEvent]) -> HashMap<SocketAddr, AllocationCloseReason> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            ServerEvent::AllocationDeleted {
+                src_addr, reason, ..
+            } => Some((*src_addr, *reason)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_allocation_close_reasons() -> Result<(), Error> {
+    let handler = RecordingEventHandler::default();
+    let events = Arc::clone(&handler.events);
+    let mut m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: Some(Arc::new(handler)),
+    });
+    let counters = Arc::new(ListenerCounters::default());
+    m.listener_counters = Some(Arc::clone(&counters));
+
+    let turn_socket: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let (expiring, deleted, shut_down) = (
+        random_five_tuple(),
+        random_five_tuple(),
+        random_five_tuple(),
+    );
+    for (five_tuple, lifetime) in &[
+        (&expiring, Duration::from_millis(100)),
+        (&deleted, DEFAULT_LIFETIME),
+        (&shut_down, DEFAULT_LIFETIME),
+    ] {
+        m.create_allocation(
+            (*five_tuple).clone(),
+            Arc::clone(&turn_socket),
+            0,
+            *lifetime,
+            "user",
+            b"key",
+        )
+        .await?;
+    }
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    m.delete_allocation(&deleted, AllocationCloseReason::Deleted)
+        .await;
+    m.close().await?;
+
+    let reasons = deleted_reasons(&events.lock().unwrap());
+    assert_eq!(3, reasons.len());
+    assert_eq!(
+        Some(&AllocationCloseReason::LifetimeExpired),
+        reasons.get(&expiring.src_addr)
+    );
+    assert_eq!(
+        Some(&AllocationCloseReason::Deleted),
+        reasons.get(&deleted.src_addr)
+    );
+    assert_eq!(
+        Some(&AllocationCloseReason::Shutdown),
+        reasons.get(&shut_down.src_addr)
+    );
+
+    let closed = counters.load(turn_socket.local_addr()?).allocations_closed;
+    assert_eq!(
+        crate::server::metrics::AllocationsClosed {
+            lifetime_expired: 1,
+            deleted: 1,
+            shutdown: 1,
+            ..Default::default()
+        },
+        closed
+    );
+
+    Ok(())
+}
+
+// BrokenRelayGenerator hands out relay sockets that fail every read
+struct BrokenRelayGenerator;
+
+struct BrokenConn {
+    local_addr: SocketAddr,
+}
+
+#[async_trait]
+impl Conn for BrokenConn {
+    async fn connect(&self, _addr: SocketAddr) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::ConnectionReset.into())
+    }
+
+    async fn recv_from(&self, _buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        Err(std::io::ErrorKind::ConnectionReset.into())
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[async_trait]
+impl RelayAddressGenerator for BrokenRelayGenerator {
+    async fn allocate_conn(
+        &self,
+        _network: &str,
+        _requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error> {
+        let local_addr = SocketAddr::from_str("127.0.0.1:40000")?;
+        Ok((Arc::new(BrokenConn { local_addr }), local_addr))
+    }
+}
+
+#[tokio::test]
+async fn test_allocation_close_on_relay_error() -> Result<(), Error> {
+    let handler = RecordingEventHandler::default();
+    let events = Arc::clone(&handler.events);
+    let m = Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(BrokenRelayGenerator),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: Some(Arc::new(handler)),
+    });
+
+    let five_tuple = random_five_tuple();
+    m.create_allocation(
+        five_tuple.clone(),
+        Arc::new(UdpSocket::bind("127.0.0.1:0").await?),
+        0,
+        DEFAULT_LIFETIME,
+        "user",
+        b"key",
+    )
+    .await?;
+
+    // the relay reader deletes the allocation on its first read
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while m.get_allocation(&five_tuple).await.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("allocation not deleted".to_owned()))?;
+
+    assert_eq!(
+        Some(&AllocationCloseReason::RelayError),
+        deleted_reasons(&events.lock().unwrap()).get(&five_tuple.src_addr)
+    );
+
+    Ok(())
+}
@@ -1,9 +1,9 @@
 use super::*;
 
+use crate::error::Error;
 use crate::proto::lifetime::DEFAULT_LIFETIME;
 use std::str::FromStr;
 use tokio::net::UdpSocket;
-use util::Error;
 
 #[tokio::test]
 async fn test_has_permission() -> Result<(), Error> {
@@ -94,14 +94,123 @@ async fn test_add_channel_bind() -> Result<(), Error> {
     let c2 = ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER + 1), addr);
     let result = a.add_channel_bind(c2, DEFAULT_LIFETIME).await;
     assert!(
-        result.is_err(),
+        matches!(result, Err(Error::SameChannelDifferentPeer)),
         "should failed with conflicted peer address"
     );
 
     let addr2 = SocketAddr::from_str("127.0.0.1:3479")?;
     let c3 = ChannelBind::new(ChannelNumber(MIN_CHANNEL_NUMBER), addr2);
     let result = a.add_channel_bind(c3, DEFAULT_LIFETIME).await;
-    assert!(result.is_err(), "should fail with conflicted number.");
+    assert!(
+        matches!(result, Err(Error::SameChannelDifferentPeer)),
+        "should fail with conflicted number."
+    );
+
+    Ok(())
+}
+
+// A client that only ever refreshes its channel binding keeps the permission
+// for the peer's IP, RFC 5766 section 11.2
+#[tokio::test]
+async fn test_channel_bind_refresh_keeps_permission() -> Result<(), Error> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let other_peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let five_tuple = FiveTuple {
+        src_addr: client.local_addr()?,
+        dst_addr: turn_socket.local_addr()?,
+        ..Default::default()
+    };
+    let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, five_tuple);
+    a.packet_handler().await;
+
+    tokio::time::pause();
+    let number = ChannelNumber(MIN_CHANNEL_NUMBER);
+    a.add_channel_bind(
+        ChannelBind::new(number, peer.local_addr()?),
+        DEFAULT_LIFETIME,
+    )
+    .await?;
+    // a permission that isn't refreshed expires meanwhile
+    let unrefreshed = SocketAddr::from_str("127.0.0.2:3478")?;
+    a.add_permission(Permission::new(unrefreshed)).await;
+
+    for _ in 0..3 {
+        tokio::time::advance(Duration::from_secs(4 * 60)).await;
+        tokio::task::yield_now().await;
+        a.add_channel_bind(
+            ChannelBind::new(number, peer.local_addr()?),
+            DEFAULT_LIFETIME,
+        )
+        .await?;
+    }
+    assert!(!a.has_permission(&unrefreshed).await);
+    assert!(a.has_permission(&peer.local_addr()?).await);
+    tokio::time::resume();
+
+    // the permission covers the peer's IP, not only the bound port
+    other_peer.send_to(b"hello", relay_addr).await?;
+    let mut buf = vec![0u8; 1500];
+    let (n, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("client read timed out".to_owned()))??;
+    let mut msg = Message::new();
+    msg.raw = buf[..n].to_vec();
+    msg.decode()?;
+    let mut data = Data::default();
+    data.get_from(&msg)?;
+    assert_eq!(b"hello", &data.0[..]);
+
+    a.close(AllocationCloseReason::Deleted).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_channel_bind_quarantine() -> Result<(), Error> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+    let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, FiveTuple::default());
+
+    let number = ChannelNumber(MIN_CHANNEL_NUMBER);
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+    let other_peer = SocketAddr::from_str("127.0.0.1:5001")?;
+
+    tokio::time::pause();
+    a.add_channel_bind(ChannelBind::new(number, peer), DEFAULT_LIFETIME)
+        .await?;
+    tokio::time::sleep(DEFAULT_LIFETIME + Duration::from_secs(1)).await;
+    assert!(a.get_channel_addr(&number).await.is_none());
+
+    // another peer is refused the number during the quarantine
+    let result = a
+        .add_channel_bind(ChannelBind::new(number, other_peer), DEFAULT_LIFETIME)
+        .await;
+    assert!(matches!(result, Err(Error::ChannelQuarantined(n)) if n == number.0));
+
+    // the same peer may have it back, the quarantine starts over once that
+    // binding expires
+    a.add_channel_bind(ChannelBind::new(number, peer), DEFAULT_LIFETIME)
+        .await?;
+    assert_eq!(a.get_channel_addr(&number).await, Some(peer));
+    tokio::time::sleep(DEFAULT_LIFETIME + Duration::from_secs(1)).await;
+    assert!(a
+        .add_channel_bind(ChannelBind::new(number, other_peer), DEFAULT_LIFETIME)
+        .await
+        .is_err());
+
+    tokio::time::sleep(CHANNEL_QUARANTINE).await;
+    a.add_channel_bind(ChannelBind::new(number, other_peer), DEFAULT_LIFETIME)
+        .await?;
+    assert_eq!(a.get_channel_addr(&number).await, Some(other_peer));
+    tokio::time::resume();
+
+    a.close(AllocationCloseReason::Deleted).await?;
 
     Ok(())
 }
@@ -225,7 +334,37 @@ async fn test_allocation_close() -> Result<(), Error> {
     // add permission
     a.add_permission(Permission::new(addr)).await;
 
-    a.close().await?;
+    a.close(AllocationCloseReason::Deleted).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_to_peer_drops_when_full() -> Result<(), Error> {
+    let turn_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay_socket.local_addr()?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut a = Allocation::new(turn_socket, relay_socket, relay_addr, FiveTuple::default());
+    a.relay_queue_size = 4;
+    a.packet_handler().await;
+
+    // queuing never waits on the relay socket, the writer task has not run
+    // yet so everything past the queue depth is dropped
+    for i in 0..10u8 {
+        a.relay_to_peer(vec![i], 0, peer.local_addr()?)?;
+    }
+    assert_eq!(a.stats.dropped_to_peer.load(Ordering::Relaxed), 6);
+
+    let mut buf = vec![0u8; 16];
+    for i in 0..4u8 {
+        let (n, _) = peer.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..n], &[i]);
+    }
+
+    a.close(AllocationCloseReason::Deleted).await?;
+    assert!(a.relay_to_peer(vec![0], 0, peer.local_addr()?).is_err());
 
     Ok(())
 }
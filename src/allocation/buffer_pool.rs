@@ -0,0 +1,37 @@
+use std::sync::Mutex;
+
+// BufferPool recycles the relay's datagram buffers so the peer to client
+// path doesn't allocate per datagram, at most max buffers are kept
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    size: usize,
+    max: usize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(size: usize, max: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max)),
+            size,
+            max,
+        }
+    }
+
+    // get returns a buffer of size bytes, its contents are unspecified
+    pub(crate) fn get(&self) -> Vec<u8> {
+        let buffer = {
+            let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+            buffers.pop()
+        };
+        let mut buffer = buffer.unwrap_or_default();
+        buffer.resize(self.size, 0);
+        buffer
+    }
+
+    pub(crate) fn put(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap_or_else(|err| err.into_inner());
+        if buffers.len() < self.max {
+            buffers.push(buffer);
+        }
+    }
+}
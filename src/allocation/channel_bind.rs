@@ -8,6 +8,21 @@ use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
 use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
 
+// CHANNEL_QUARANTINE is how long the number of an expired channel binding may
+// only be bound to the same peer again, so late ChannelData for the old peer
+// isn't delivered to a new one, RFC 5766 section 11
+pub const CHANNEL_QUARANTINE: Duration = Duration::from_secs(5 * 60);
+
+// ChannelTombstone is a channel binding that expired less than
+// CHANNEL_QUARANTINE ago
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChannelTombstone {
+    pub(crate) peer: SocketAddr,
+    pub(crate) expired_at: Instant,
+}
+
+pub(crate) type ChannelTombstones = Arc<Mutex<HashMap<ChannelNumber, ChannelTombstone>>>;
+
 // ChannelBind represents a TURN Channel
 // https://tools.ietf.org/html/rfc5766#section-2.5
 #[derive(Clone)]
@@ -15,8 +30,12 @@ pub struct ChannelBind {
     pub(crate) peer: SocketAddr,
     pub(crate) number: ChannelNumber,
     pub(crate) channel_bindings: Option<Arc<Mutex<HashMap<ChannelNumber, ChannelBind>>>>,
+    // tombstones gets the binding once it expires, until CHANNEL_QUARANTINE
+    // has passed
+    pub(crate) tombstones: Option<ChannelTombstones>,
     reset_tx: Option<mpsc::Sender<Duration>>,
     timer_expired: Arc<AtomicBool>,
+    pub(crate) expires_at: Arc<Mutex<Instant>>,
 }
 
 impl ChannelBind {
@@ -26,23 +45,29 @@ impl ChannelBind {
             number,
             peer,
             channel_bindings: None,
+            tombstones: None,
             reset_tx: None,
             timer_expired: Arc::new(AtomicBool::new(false)),
+            expires_at: Arc::new(Mutex::new(Instant::now())),
         }
     }
 
     pub(crate) async fn start(&mut self, lifetime: Duration) {
         let (reset_tx, mut reset_rx) = mpsc::channel(1);
         self.reset_tx = Some(reset_tx);
+        *self.expires_at.lock().await = Instant::now() + lifetime;
 
         let channel_bindings = self.channel_bindings.clone();
+        let tombstones = self.tombstones.clone();
         let number = self.number;
+        let peer = self.peer;
         let timer_expired = Arc::clone(&self.timer_expired);
 
         tokio::spawn(async move {
             let timer = tokio::time::sleep(lifetime);
             tokio::pin!(timer);
             let mut done = false;
+            let mut expired_at = None;
 
             while !done {
                 tokio::select! {
@@ -53,6 +78,7 @@ impl ChannelBind {
                                 log::error!("Failed to remove ChannelBind for {}", number);
                             }
                         }
+                        expired_at = Some(Instant::now());
                         done = true;
                     },
                     result = reset_rx.recv() => {
@@ -66,6 +92,24 @@ impl ChannelBind {
             }
 
             timer_expired.store(true, Ordering::SeqCst);
+
+            // the number is quarantined, then the tombstone is swept unless
+            // the binding was made again meanwhile
+            if let (Some(tombstones), Some(expired_at)) = (tombstones, expired_at) {
+                tombstones
+                    .lock()
+                    .await
+                    .insert(number, ChannelTombstone { peer, expired_at });
+                tokio::time::sleep(CHANNEL_QUARANTINE).await;
+
+                let mut tombstones = tombstones.lock().await;
+                if tombstones
+                    .get(&number)
+                    .is_some_and(|t| t.expired_at == expired_at)
+                {
+                    tombstones.remove(&number);
+                }
+            }
         });
     }
 
@@ -77,6 +121,7 @@ impl ChannelBind {
 
     pub(crate) async fn refresh(&self, lifetime: Duration) {
         if let Some(tx) = &self.reset_tx {
+            *self.expires_at.lock().await = Instant::now() + lifetime;
             let _ = tx.send(lifetime).await;
         }
     }
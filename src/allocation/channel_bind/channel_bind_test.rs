@@ -1,7 +1,8 @@
 use super::*;
 use crate::allocation::*;
 
-use util::Error;
+use crate::error::Error;
+use crate::proto::lifetime::DEFAULT_LIFETIME;
 
 use tokio::net::UdpSocket;
 
@@ -29,15 +30,22 @@ async fn test_channel_bind() -> Result<(), Error> {
     if let Some(addr) = result {
         assert_eq!(addr.ip().to_string(), "0.0.0.0");
     } else {
-        assert!(false, "expected some, but got none");
+        panic!("expected some, but got none");
     }
 
     Ok(())
 }
 
+#[tokio::test(start_paused = true)]
 async fn test_channel_bind_start() -> Result<(), Error> {
-    let a = create_channel_bind(Duration::from_millis(20)).await?;
-    tokio::time::sleep(Duration::from_millis(30)).await;
+    let a = create_channel_bind(DEFAULT_LIFETIME).await?;
+    tokio::time::sleep(DEFAULT_LIFETIME - Duration::from_secs(1)).await;
+    assert!(a
+        .get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER))
+        .await
+        .is_some());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
     assert!(a
         .get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER))
@@ -47,22 +55,30 @@ async fn test_channel_bind_start() -> Result<(), Error> {
     Ok(())
 }
 
+#[tokio::test(start_paused = true)]
 async fn test_channel_bind_reset() -> Result<(), Error> {
-    let a = create_channel_bind(Duration::from_millis(30)).await?;
+    let a = create_channel_bind(DEFAULT_LIFETIME).await?;
 
-    tokio::time::sleep(Duration::from_millis(20)).await;
+    // refreshed a minute before it expires, it lasts another lifetime
+    tokio::time::sleep(DEFAULT_LIFETIME - Duration::from_secs(60)).await;
     {
         let channel_bindings = a.channel_bindings.lock().await;
         if let Some(c) = channel_bindings.get(&ChannelNumber(MIN_CHANNEL_NUMBER)) {
-            c.refresh(Duration::from_millis(30)).await;
+            c.refresh(DEFAULT_LIFETIME).await;
         }
     }
-    tokio::time::sleep(Duration::from_millis(20)).await;
+    tokio::time::sleep(DEFAULT_LIFETIME - Duration::from_secs(1)).await;
 
     assert!(a
         .get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER))
         .await
         .is_some());
 
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(a
+        .get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER))
+        .await
+        .is_none());
+
     Ok(())
 }
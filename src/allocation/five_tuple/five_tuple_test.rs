@@ -1,6 +1,6 @@
 use super::*;
 
-use util::Error;
+use crate::error::Error;
 
 #[test]
 fn test_five_tuple_protocol() -> Result<(), Error> {
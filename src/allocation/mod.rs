@@ -1,32 +1,74 @@
 #[cfg(test)]
 mod allocation_test;
 
+pub mod allocation_limit;
 pub mod allocation_manager;
+mod buffer_pool;
 pub mod channel_bind;
 pub mod five_tuple;
+pub mod peer_traffic;
 pub mod permission;
+pub mod relay_workers;
+pub mod snapshot;
 
-use crate::errors::*;
+use crate::error::Error;
 use crate::proto::{chandata::*, channum::*, data::*, peeraddr::*, *};
+use crate::relay::icmp::{icmp_error, IcmpError};
+use crate::server::event::*;
+use crate::server::metrics::ListenerCounters;
+use crate::trace::{Instrument, Span};
+use allocation_limit::*;
+use buffer_pool::*;
 use channel_bind::*;
 use five_tuple::*;
+use peer_traffic::*;
 use permission::*;
+use relay_workers::*;
 
 use stun::agent::*;
 use stun::message::*;
 
-use util::{Conn, Error};
+use util::Conn;
 
-use tokio::sync::{mpsc, Mutex};
+use futures::FutureExt;
+use tokio::sync::{mpsc, Mutex, Notify};
 use tokio::time::{Duration, Instant};
 
 use std::collections::HashMap;
 use std::marker::{Send, Sync};
 use std::net::SocketAddr;
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
+use std::sync::{atomic::AtomicBool, atomic::AtomicU64, atomic::Ordering, Arc};
 
 const RTP_MTU: usize = 1500;
 
+// RELAY_BUFFER_SIZE fits a datagram of RTP_MTU bytes framed as padded ChannelData
+const RELAY_BUFFER_SIZE: usize = CHANNEL_DATA_HEADER_SIZE + RTP_MTU + 3;
+
+// RELAY_BATCH_SIZE is the most datagrams relayed to the client per wakeup
+const RELAY_BATCH_SIZE: usize = crate::batch::DEFAULT_BATCH_SIZE;
+
+// ICMP_INDICATION_INTERVAL is the least time between two Data indications
+// carrying an ICMP error from the same peer
+const ICMP_INDICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+// MAX_ICMP_PEERS bounds the peers an allocation rate-limits ICMP errors
+// for, the quiet ones are forgotten when it is reached
+const MAX_ICMP_PEERS: usize = 1024;
+
+// DEFAULT_RELAY_QUEUE_SIZE is the depth of each relay direction's queue when
+// the configured size is zero
+pub const DEFAULT_RELAY_QUEUE_SIZE: usize = 64;
+
+// RelayStats counts the datagrams relayed in each direction and those dropped
+// because the direction's queue was full
+#[derive(Debug, Default)]
+pub(crate) struct RelayStats {
+    pub(crate) relayed_to_client: AtomicU64,
+    pub(crate) dropped_to_client: AtomicU64,
+    pub(crate) relayed_to_peer: AtomicU64,
+    pub(crate) dropped_to_peer: AtomicU64,
+}
+
 pub type AllocationMap = Arc<Mutex<HashMap<String, Arc<Mutex<Allocation>>>>>;
 
 // Allocation is tied to a FiveTuple and relays traffic
@@ -39,12 +81,105 @@ pub struct Allocation {
     five_tuple: FiveTuple,
     permissions: Arc<Mutex<HashMap<String, Permission>>>,
     channel_bindings: Arc<Mutex<HashMap<ChannelNumber, ChannelBind>>>,
+    channel_tombstones: ChannelTombstones,
     pub(crate) allocations: Option<AllocationMap>,
     reset_tx: Option<mpsc::Sender<Duration>>,
     timer_expired: Arc<AtomicBool>,
     closed: bool, // Option<mpsc::Receiver<()>>,
+    // username and auth_key authenticated the Allocate request, later requests
+    // on the allocation must use the same credentials
+    pub(crate) username: String,
+    pub(crate) auth_key: Vec<u8>,
+    pub(crate) span: Span,
+    expires_at: Arc<Mutex<Instant>>,
+    // each direction has its own writer task fed by a queue of
+    // relay_queue_size datagrams, a full queue drops the newest datagram so
+    // a slow socket never blocks the reader or other allocations
+    pub(crate) relay_queue_size: usize,
+    to_peer_tx: Option<mpsc::Sender<RelayDatagram>>,
+    pub(crate) stats: Arc<RelayStats>,
+    pub(crate) traffic: Arc<PeerTrafficMap>,
+    // event_handler is told of the allocation's deletion
+    pub(crate) event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    // listener_counters counts the deletion for the listener's metrics
+    pub(crate) listener_counters: Option<Arc<ListenerCounters>>,
+    // relay_workers runs the lifetime timer and the relay tasks, relay_closed
+    // stops the relay reader on close
+    pub(crate) relay_workers: RelayWorkers,
+    relay_closed: Arc<Notify>,
+    // allocation_permit holds the allocation's slot of the server's
+    // max_allocations until it is dropped
+    pub(crate) allocation_permit: Option<AllocationPermit>,
+}
+
+// IcmpRelay tells the client of the ICMP errors answering what was relayed to
+// its peers, in Data indications with an ICMP attribute and no DATA. Those of
+// peers without a permission are dropped, the others are sent at most once
+// per ICMP_INDICATION_INTERVAL for each peer.
+struct IcmpRelay {
+    permissions: Arc<Mutex<HashMap<String, Permission>>>,
+    to_client_tx: mpsc::Sender<RelayDatagram>,
+    stats: Arc<RelayStats>,
+    client: SocketAddr,
+    // sent is when the last indication about each peer was queued
+    sent: HashMap<SocketAddr, Instant>,
+}
+
+impl IcmpRelay {
+    async fn relay(&mut self, icmp_error: IcmpError) {
+        let peer = icmp_error.peer;
+        if !self
+            .permissions
+            .lock()
+            .await
+            .contains_key(&addr2ipfingerprint(&peer))
+        {
+            debug_limited!("dropped {}, no permission for it", icmp_error);
+            return;
+        }
+
+        let now = Instant::now();
+        if self
+            .sent
+            .get(&peer)
+            .is_some_and(|sent| now - *sent < ICMP_INDICATION_INTERVAL)
+        {
+            trace_limited!("dropped {}, one was just relayed", icmp_error);
+            return;
+        }
+        if self.sent.len() >= MAX_ICMP_PEERS {
+            self.sent
+                .retain(|_, sent| now - *sent < ICMP_INDICATION_INTERVAL);
+        }
+        self.sent.insert(peer, now);
+
+        let mut msg = Message::new();
+        if let Err(err) = msg.build(&[
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_DATA, CLASS_INDICATION)),
+            Box::new(PeerAddress {
+                ip: peer.ip(),
+                port: peer.port(),
+            }),
+            Box::new(icmp_error.icmp),
+        ]) {
+            error_limited!("Failed to build ICMP DataIndication for {} {}", peer, err);
+            return;
+        }
+
+        debug_limited!("relaying {} to client at {}", icmp_error, self.client);
+        if let Err(mpsc::error::TrySendError::Full(_)) =
+            self.to_client_tx.try_send((msg.raw, 0, self.client))
+        {
+            self.stats.dropped_to_client.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
 
+// RelayDatagram is queued for a writer task, the datagram is buf[offset..] so
+// a received buffer can be sent on without copying the payload out of it
+type RelayDatagram = (Vec<u8>, usize, SocketAddr);
+
 fn addr2ipfingerprint(addr: &SocketAddr) -> String {
     addr.ip().to_string()
 }
@@ -65,10 +200,24 @@ impl Allocation {
             five_tuple,
             permissions: Arc::new(Mutex::new(HashMap::new())),
             channel_bindings: Arc::new(Mutex::new(HashMap::new())),
+            channel_tombstones: Arc::new(Mutex::new(HashMap::new())),
             allocations: None,
             reset_tx: None,
             timer_expired: Arc::new(AtomicBool::new(false)),
             closed: false,
+            username: String::new(),
+            auth_key: vec![],
+            span: Span::none(),
+            expires_at: Arc::new(Mutex::new(Instant::now())),
+            relay_queue_size: DEFAULT_RELAY_QUEUE_SIZE,
+            to_peer_tx: None,
+            stats: Arc::new(RelayStats::default()),
+            traffic: Arc::new(PeerTrafficMap::default()),
+            event_handler: None,
+            listener_counters: None,
+            relay_workers: RelayWorkers::default(),
+            relay_closed: Arc::new(Notify::new()),
+            allocation_permit: None,
         }
     }
 
@@ -90,6 +239,8 @@ impl Allocation {
             }
         }
 
+        self.span
+            .in_scope(|| state_event!("permission added for {}", p.addr));
         p.permissions = Some(Arc::clone(&self.permissions));
         p.start(PERMISSION_TIMEOUT).await;
 
@@ -115,13 +266,13 @@ impl Allocation {
         {
             if let Some(addr) = self.get_channel_addr(&c.number).await {
                 if addr != c.peer {
-                    return Err(ERR_SAME_CHANNEL_DIFFERENT_PEER.to_owned());
+                    return Err(Error::SameChannelDifferentPeer);
                 }
             }
 
             if let Some(number) = self.get_channel_number(&c.peer).await {
                 if number != c.number {
-                    return Err(ERR_SAME_CHANNEL_DIFFERENT_PEER.to_owned());
+                    return Err(Error::SameChannelDifferentPeer);
                 }
             }
         }
@@ -138,10 +289,25 @@ impl Allocation {
             }
         }
 
+        // an expired binding's number only goes back to its peer until the
+        // quarantine is over
+        {
+            let mut tombstones = self.channel_tombstones.lock().await;
+            if let Some(tombstone) = tombstones.get(&c.number) {
+                if tombstone.peer != c.peer {
+                    return Err(Error::ChannelQuarantined(c.number.0));
+                }
+                tombstones.remove(&c.number);
+            }
+        }
+
         let peer = c.peer;
 
         // Add or refresh this channel.
+        self.span
+            .in_scope(|| state_event!("channel {} bound to {}", c.number, peer));
         c.channel_bindings = Some(Arc::clone(&self.channel_bindings));
+        c.tombstones = Some(Arc::clone(&self.channel_tombstones));
         c.start(lifetime).await;
 
         {
@@ -164,11 +330,7 @@ impl Allocation {
     // get_channel_addr gets the ChannelBind's addr
     pub async fn get_channel_addr(&self, number: &ChannelNumber) -> Option<SocketAddr> {
         let channel_bindings = self.channel_bindings.lock().await;
-        if let Some(cb) = channel_bindings.get(number) {
-            Some(cb.peer)
-        } else {
-            None
-        }
+        channel_bindings.get(number).map(|cb| cb.peer)
     }
 
     // GetChannelByAddr gets the ChannelBind's number from this allocation by net.Addr
@@ -182,14 +344,40 @@ impl Allocation {
         None
     }
 
-    // Close closes the allocation
-    pub async fn close(&mut self) -> Result<(), Error> {
+    // relay_to_peer queues buf[offset..] to be sent to peer from the relay
+    // socket, it never waits and drops the datagram if the queue is full
+    pub(crate) fn relay_to_peer(
+        &self,
+        buf: Vec<u8>,
+        offset: usize,
+        peer: SocketAddr,
+    ) -> Result<(), Error> {
+        let to_peer_tx = self.to_peer_tx.as_ref().ok_or(Error::Closed)?;
+        let n = buf.len() - offset;
+        match to_peer_tx.try_send((buf, offset, peer)) {
+            Ok(()) => {
+                self.traffic.counters(peer).add_to_peer(n);
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.stats.dropped_to_peer.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(Error::Closed),
+        }
+    }
+
+    // Close closes the allocation, reason is reported to the event handler
+    // and the listener's metrics
+    pub async fn close(&mut self, reason: AllocationCloseReason) -> Result<(), Error> {
         if self.closed {
-            return Err(ERR_CLOSED.to_owned());
+            return Err(Error::Closed);
         }
 
         self.closed = true;
         self.stop();
+        self.to_peer_tx.take();
+        self.relay_closed.notify_one();
 
         {
             let mut permissions = self.permissions.lock().await;
@@ -205,7 +393,22 @@ impl Allocation {
             }
         }
 
-        log::trace!("allocation with {} closed!", self.five_tuple);
+        self.span
+            .in_scope(|| state_event!("allocation with {} closed: {:?}", self.five_tuple, reason));
+
+        if let Some(listener_counters) = &self.listener_counters {
+            listener_counters.add_allocation_closed(reason);
+        }
+        if let Some(event_handler) = &self.event_handler {
+            event_handler.on_event(ServerEvent::AllocationDeleted {
+                src_addr: self.five_tuple.src_addr,
+                dst_addr: self.five_tuple.dst_addr,
+                username: self.username.clone(),
+                relay_addr: self.relay_addr,
+                reason,
+                traffic: self.traffic.traffic(),
+            });
+        }
 
         Ok(())
     }
@@ -213,40 +416,45 @@ impl Allocation {
     pub async fn start(&mut self, lifetime: Duration) {
         let (reset_tx, mut reset_rx) = mpsc::channel(1);
         self.reset_tx = Some(reset_tx);
+        *self.expires_at.lock().await = Instant::now() + lifetime;
 
         let allocations = self.allocations.clone();
         let five_tuple = self.five_tuple.clone();
         let timer_expired = Arc::clone(&self.timer_expired);
 
-        tokio::spawn(async move {
-            let timer = tokio::time::sleep(lifetime);
-            tokio::pin!(timer);
-            let mut done = false;
-
-            while !done {
-                tokio::select! {
-                    _ = &mut timer => {
-                        if let Some(allocs) = &allocations{
-                            let mut alls = allocs.lock().await;
-                            if let Some(a) = alls.remove(&five_tuple.fingerprint()) {
-                                let mut a = a.lock().await;
-                                let _ = a.close().await;
+        self.relay_workers.spawn(
+            async move {
+                let timer = tokio::time::sleep(lifetime);
+                tokio::pin!(timer);
+                let mut done = false;
+
+                while !done {
+                    tokio::select! {
+                        _ = &mut timer => {
+                            state_event!("allocation with {} expired", five_tuple);
+                            if let Some(allocs) = &allocations{
+                                let mut alls = allocs.lock().await;
+                                if let Some(a) = alls.remove(&five_tuple.fingerprint()) {
+                                    let mut a = a.lock().await;
+                                    let _ = a.close(AllocationCloseReason::LifetimeExpired).await;
+                                }
                             }
-                        }
-                        done = true;
-                    },
-                    result = reset_rx.recv() => {
-                        if let Some(d) = result {
-                            timer.as_mut().reset(Instant::now() + d);
-                        } else {
                             done = true;
-                        }
-                    },
+                        },
+                        result = reset_rx.recv() => {
+                            if let Some(d) = result {
+                                timer.as_mut().reset(Instant::now() + d);
+                            } else {
+                                done = true;
+                            }
+                        },
+                    }
                 }
-            }
 
-            timer_expired.store(true, Ordering::SeqCst);
-        });
+                timer_expired.store(true, Ordering::SeqCst);
+            }
+            .instrument(self.span.clone()),
+        );
     }
 
     pub fn stop(&mut self) -> bool {
@@ -257,7 +465,10 @@ impl Allocation {
 
     // Refresh updates the allocations lifetime
     pub async fn refresh(&self, lifetime: Duration) {
+        self.span
+            .in_scope(|| state_event!("allocation refreshed for {:?}", lifetime));
         if let Some(tx) = &self.reset_tx {
+            *self.expires_at.lock().await = Instant::now() + lifetime;
             let _ = tx.send(lifetime).await;
         }
     }
@@ -281,80 +492,166 @@ impl Allocation {
     //  datagram, and the XOR-PEER-ADDRESS attribute is set to the source
     //  transport address of the received UDP datagram.  The Data indication
     //  is then sent on the 5-tuple associated with the allocation.
-    async fn packet_handler(&self) {
+    async fn packet_handler(&mut self) {
         let five_tuple = self.five_tuple.clone();
         let relay_addr = self.relay_addr;
         let relay_socket = Arc::clone(&self.relay_socket);
-        let turn_socket = Arc::clone(&self.turn_socket);
         let allocations = self.allocations.clone();
         let channel_bindings = Arc::clone(&self.channel_bindings);
         let permissions = Arc::clone(&self.permissions);
+        let stats = Arc::clone(&self.stats);
+        let traffic = Arc::clone(&self.traffic);
 
-        tokio::spawn(async move {
-            let mut buffer = vec![0u8; RTP_MTU];
+        let queue_size = if self.relay_queue_size == 0 {
+            DEFAULT_RELAY_QUEUE_SIZE
+        } else {
+            self.relay_queue_size
+        };
+        let (to_client_tx, to_client_rx) = mpsc::channel(queue_size);
+        let (to_peer_tx, to_peer_rx) = mpsc::channel(queue_size);
+        self.to_peer_tx = Some(to_peer_tx);
+
+        // every buffer is either being read into, queued or in the pool
+        let pool = Arc::new(BufferPool::new(
+            RELAY_BUFFER_SIZE,
+            queue_size + RELAY_BATCH_SIZE,
+        ));
+
+        self.spawn_writer(
+            Arc::clone(&self.turn_socket),
+            to_client_rx,
+            Some(Arc::clone(&pool)),
+        );
+        self.spawn_writer(Arc::clone(&relay_socket), to_peer_rx, None);
+
+        let mut icmp_relay = IcmpRelay {
+            permissions: Arc::clone(&permissions),
+            to_client_tx: to_client_tx.clone(),
+            stats: Arc::clone(&stats),
+            client: five_tuple.src_addr,
+            sent: HashMap::new(),
+        };
+        let relay_closed = Arc::clone(&self.relay_closed);
+        self.relay_workers.spawn(
+            async move {
+                let mut received: Vec<(Vec<u8>, usize, SocketAddr)> =
+                    Vec::with_capacity(RELAY_BATCH_SIZE);
+                // outbound holds each datagram with the counters of its peer
+                // and its payload length
+                let mut outbound: Vec<(Vec<u8>, Arc<TrafficCounters>, usize)> =
+                    Vec::with_capacity(RELAY_BATCH_SIZE);
+
+                loop {
+                    // datagrams are read past the room for a ChannelData header,
+                    // so a bound channel only needs the header written in front
+                    let mut buffer = pool.get();
+                    let result = tokio::select! {
+                        result = relay_socket.recv_from(&mut buffer[CHANNEL_DATA_HEADER_SIZE..]) => result,
+                        _ = relay_closed.notified() => break,
+                    };
+                    match result {
+                        Ok((n, src_addr)) => received.push((buffer, n, src_addr)),
+                        Err(err) => {
+                            if let Some(icmp_error) = icmp_error(&err) {
+                                pool.put(buffer);
+                                icmp_relay.relay(icmp_error).await;
+                                continue;
+                            }
 
-            loop {
-                let (n, src_addr) = match relay_socket.recv_from(&mut buffer).await {
-                    Ok((n, src_addr)) => (n, src_addr),
-                    Err(_) => {
-                        if let Some(allocs) = &allocations {
-                            let mut alls = allocs.lock().await;
-                            alls.remove(&five_tuple.fingerprint());
-                        }
-                        break;
-                    }
-                };
-
-                log::debug!(
-                    "relay socket {:?} received {} bytes from {}",
-                    relay_socket.local_addr(),
-                    n,
-                    src_addr
-                );
-
-                let cb_number = {
-                    let mut cb_number = None;
-                    let cbs = channel_bindings.lock().await;
-                    for cb in cbs.values() {
-                        if cb.peer == src_addr {
-                            cb_number = Some(cb.number);
+                            if let Some(allocs) = &allocations {
+                                let mut alls = allocs.lock().await;
+                                if let Some(a) = alls.remove(&five_tuple.fingerprint()) {
+                                    let mut a = a.lock().await;
+                                    let _ = a.close(AllocationCloseReason::RelayError).await;
+                                }
+                            }
                             break;
                         }
-                    }
-                    cb_number
-                };
-
-                if let Some(number) = cb_number {
-                    let mut channel_data = ChannelData {
-                        data: buffer[..n].to_vec(),
-                        number,
-                        raw: vec![],
                     };
-                    channel_data.encode();
-
-                    if let Err(err) = turn_socket
-                        .send_to(&channel_data.raw, five_tuple.src_addr)
-                        .await
-                    {
-                        log::error!(
-                            "Failed to send ChannelData from allocation {} {}",
-                            src_addr,
-                            err
-                        );
+
+                    // take whatever else is already readable without waiting, a
+                    // read error is seen again by the next blocking recv_from
+                    while received.len() < RELAY_BATCH_SIZE {
+                        let mut buffer = pool.get();
+                        match relay_socket
+                            .recv_from(&mut buffer[CHANNEL_DATA_HEADER_SIZE..])
+                            .now_or_never()
+                        {
+                            Some(Ok((n, src_addr))) => received.push((buffer, n, src_addr)),
+                            Some(Err(err)) => {
+                                pool.put(buffer);
+                                match icmp_error(&err) {
+                                    Some(icmp_error) => icmp_relay.relay(icmp_error).await,
+                                    None => break,
+                                }
+                            }
+                            None => {
+                                pool.put(buffer);
+                                break;
+                            }
+                        }
                     }
-                } else {
-                    let exist = {
+
+                    debug_limited!(
+                        "relay socket {:?} received {} datagrams",
+                        relay_socket.local_addr(),
+                        received.len()
+                    );
+
+                    // the bindings and permissions are locked once for the batch
+                    let cb_numbers: Vec<Option<ChannelNumber>> = {
+                        let cbs = channel_bindings.lock().await;
+                        received
+                            .iter()
+                            .map(|(_, _, src_addr)| {
+                                cbs.values()
+                                    .find(|cb| cb.peer == *src_addr)
+                                    .map(|cb| cb.number)
+                            })
+                            .collect()
+                    };
+                    let permitted: Vec<bool> = {
                         let ps = permissions.lock().await;
-                        ps.get(&addr2ipfingerprint(&src_addr)).is_some()
+                        received
+                            .iter()
+                            .zip(cb_numbers.iter())
+                            .map(|((_, _, src_addr), cb_number)| {
+                                cb_number.is_some()
+                                    || ps.get(&addr2ipfingerprint(src_addr)).is_some()
+                            })
+                            .collect()
                     };
 
-                    if exist {
-                        let msg = {
+                    outbound.clear();
+                    for (i, (mut buffer, n, src_addr)) in received.drain(..).enumerate() {
+                        debug_limited!(
+                            "relay socket {:?} received {} bytes from {}",
+                            relay_socket.local_addr(),
+                            n,
+                            src_addr
+                        );
+
+                        if let Some(number) = cb_numbers[i] {
+                            if let Err(err) = ChannelData::frame(&mut buffer, number, n) {
+                                error_limited!(
+                                    "Failed to encode ChannelData from allocation {} {}",
+                                    src_addr,
+                                    err
+                                );
+                                pool.put(buffer);
+                                continue;
+                            }
+                            outbound.push((buffer, traffic.counters(src_addr), n));
+                        } else if permitted[i] {
                             let peer_address_attr = PeerAddress {
                                 ip: src_addr.ip(),
                                 port: src_addr.port(),
                             };
-                            let data_attr = Data(buffer[..n].to_vec());
+                            let data_attr = Data(
+                                buffer[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + n]
+                                    .to_vec(),
+                            );
+                            pool.put(buffer);
 
                             let mut msg = Message::new();
                             if let Err(err) = msg.build(&[
@@ -363,42 +660,80 @@ impl Allocation {
                                 Box::new(peer_address_attr),
                                 Box::new(data_attr),
                             ]) {
-                                log::error!(
+                                error_limited!(
                                     "Failed to send DataIndication from allocation {} {}",
                                     src_addr,
                                     err
                                 );
-                                None
-                            } else {
-                                Some(msg)
+                                continue;
                             }
-                        };
 
-                        if let Some(msg) = msg {
-                            log::debug!(
+                            debug_limited!(
                                 "relaying message from {} to client at {}",
                                 src_addr,
                                 five_tuple.src_addr
                             );
-                            if let Err(err) =
-                                turn_socket.send_to(&msg.raw, five_tuple.src_addr).await
-                            {
-                                log::error!(
-                                    "Failed to send DataIndication from allocation {} {}",
-                                    src_addr,
-                                    err
-                                );
+                            outbound.push((msg.raw, traffic.counters(src_addr), n));
+                        } else {
+                            info_limited!(
+                                "No Permission or Channel exists for {} on allocation {}",
+                                src_addr,
+                                relay_addr
+                            );
+                            pool.put(buffer);
+                        }
+                    }
+
+                    // the whole batch is built before any of it is queued
+                    for (raw, counters, n) in outbound.drain(..) {
+                        match to_client_tx.try_send((raw, 0, five_tuple.src_addr)) {
+                            Ok(()) => counters.add_to_client(n),
+                            Err(mpsc::error::TrySendError::Full((raw, _, _))) => {
+                                stats.dropped_to_client.fetch_add(1, Ordering::Relaxed);
+                                pool.put(raw);
                             }
+                            Err(mpsc::error::TrySendError::Closed(_)) => break,
                         }
-                    } else {
-                        log::info!(
-                            "No Permission or Channel exists for {} on allocation {}",
-                            src_addr,
-                            relay_addr
-                        );
                     }
                 }
             }
-        });
+            .instrument(self.span.clone()),
+        );
+    }
+
+    // spawn_writer sends the queued datagrams on conn until every sender is
+    // dropped. Buffers are returned to pool, which is only set for the client
+    // direction and also selects the RelayStats counter.
+    fn spawn_writer(
+        &self,
+        conn: Arc<dyn Conn + Send + Sync>,
+        mut rx: mpsc::Receiver<RelayDatagram>,
+        pool: Option<Arc<BufferPool>>,
+    ) {
+        let stats = Arc::clone(&self.stats);
+        self.relay_workers.spawn(
+            async move {
+                while let Some((buf, offset, to)) = rx.recv().await {
+                    let data = &buf[offset..];
+                    match conn.send_to(data, to).await {
+                        Ok(n) if n == data.len() => {
+                            let relayed = if pool.is_some() {
+                                &stats.relayed_to_client
+                            } else {
+                                &stats.relayed_to_peer
+                            };
+                            relayed.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(_) => error_limited!("Failed to relay to {} {}", to, Error::ShortWrite),
+                        Err(err) => error_limited!("Failed to relay to {} {}", to, err),
+                    }
+
+                    if let Some(pool) = &pool {
+                        pool.put(buf);
+                    }
+                }
+            }
+            .instrument(self.span.clone()),
+        );
     }
 }
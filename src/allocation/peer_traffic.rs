@@ -0,0 +1,153 @@
+#[cfg(test)]
+mod peer_traffic_test;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// MAX_TRACKED_PEERS is how many peers of an allocation get their own
+// counters, traffic with further peers is counted as other
+pub const MAX_TRACKED_PEERS: usize = 64;
+
+// TOP_PEERS is how many peers AllocationTraffic breaks the traffic down by,
+// the busiest first
+pub const TOP_PEERS: usize = 10;
+
+// TrafficCounters counts the payload relayed with one peer, datagrams dropped
+// because a relay queue was full are not counted
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    to_peer_bytes: AtomicU64,
+    to_peer_packets: AtomicU64,
+    to_client_bytes: AtomicU64,
+    to_client_packets: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub(crate) fn add_to_peer(&self, bytes: usize) {
+        self.to_peer_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.to_peer_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_to_client(&self, bytes: usize) {
+        self.to_client_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.to_client_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Traffic {
+        Traffic {
+            to_peer_bytes: self.to_peer_bytes.load(Ordering::Relaxed),
+            to_peer_packets: self.to_peer_packets.load(Ordering::Relaxed),
+            to_client_bytes: self.to_client_bytes.load(Ordering::Relaxed),
+            to_client_packets: self.to_client_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Traffic is the payload relayed in each direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Traffic {
+    pub to_peer_bytes: u64,
+    pub to_peer_packets: u64,
+    pub to_client_bytes: u64,
+    pub to_client_packets: u64,
+}
+
+impl Traffic {
+    fn bytes(&self) -> u64 {
+        self.to_peer_bytes + self.to_client_bytes
+    }
+}
+
+impl std::ops::AddAssign for Traffic {
+    fn add_assign(&mut self, other: Traffic) {
+        self.to_peer_bytes += other.to_peer_bytes;
+        self.to_peer_packets += other.to_peer_packets;
+        self.to_client_bytes += other.to_client_bytes;
+        self.to_client_packets += other.to_client_packets;
+    }
+}
+
+// PeerTraffic is the traffic relayed with one peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PeerTraffic {
+    pub peer: SocketAddr,
+    pub traffic: Traffic,
+}
+
+// AllocationTraffic is the traffic relayed by an allocation, total over all
+// its peers and broken down by the TOP_PEERS busiest, the traffic of every
+// other peer is summed in other
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationTraffic {
+    pub total: Traffic,
+    pub peers: Vec<PeerTraffic>,
+    pub other: Traffic,
+}
+
+// PeerTrafficMap hands out the counters of an allocation's peers. The map is
+// only locked to look a peer up, the relay path then adds to the counters.
+#[derive(Debug, Default)]
+pub(crate) struct PeerTrafficMap {
+    peers: Mutex<HashMap<SocketAddr, Arc<TrafficCounters>>>,
+    other: Arc<TrafficCounters>,
+}
+
+impl PeerTrafficMap {
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Arc<TrafficCounters>>> {
+        self.peers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // counters returns the peer's counters, or those of other once
+    // MAX_TRACKED_PEERS are tracked
+    pub(crate) fn counters(&self, peer: SocketAddr) -> Arc<TrafficCounters> {
+        let mut peers = self.peers();
+        if let Some(counters) = peers.get(&peer) {
+            return Arc::clone(counters);
+        }
+        if peers.len() >= MAX_TRACKED_PEERS {
+            return Arc::clone(&self.other);
+        }
+        Arc::clone(peers.entry(peer).or_default())
+    }
+
+    pub(crate) fn traffic(&self) -> AllocationTraffic {
+        let mut peers: Vec<PeerTraffic> = self
+            .peers()
+            .iter()
+            .map(|(peer, counters)| PeerTraffic {
+                peer: *peer,
+                traffic: counters.load(),
+            })
+            .collect();
+        peers.sort_by(|a, b| {
+            b.traffic
+                .bytes()
+                .cmp(&a.traffic.bytes())
+                .then(a.peer.cmp(&b.peer))
+        });
+
+        let mut other = self.other.load();
+        for p in peers.iter().skip(TOP_PEERS) {
+            other += p.traffic;
+        }
+        peers.truncate(TOP_PEERS);
+
+        let mut total = other;
+        for p in &peers {
+            total += p.traffic;
+        }
+
+        AllocationTraffic {
+            total,
+            peers,
+            other,
+        }
+    }
+}
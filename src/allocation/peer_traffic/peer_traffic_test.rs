@@ -0,0 +1,38 @@
+use super::*;
+
+use std::net::{IpAddr, Ipv4Addr};
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+}
+
+#[test]
+fn test_peer_traffic_map_collapses_to_other() {
+    let map = PeerTrafficMap::default();
+
+    // peer i sends i + 1 bytes, the last peers are past MAX_TRACKED_PEERS
+    let peers = MAX_TRACKED_PEERS as u16 + 6;
+    for i in 0..peers {
+        map.counters(peer(i)).add_to_client(usize::from(i) + 1);
+    }
+    map.counters(peer(0)).add_to_peer(1000);
+
+    let traffic = map.traffic();
+    assert_eq!(TOP_PEERS, traffic.peers.len());
+    assert_eq!(peer(0), traffic.peers[0].peer);
+    for (i, p) in traffic.peers.iter().skip(1).enumerate() {
+        assert_eq!(peer(MAX_TRACKED_PEERS as u16 - 1 - i as u16), p.peer);
+    }
+
+    let total_bytes: u64 = (1..=u64::from(peers)).sum();
+    assert_eq!(total_bytes, traffic.total.to_client_bytes);
+    assert_eq!(u64::from(peers), traffic.total.to_client_packets);
+    assert_eq!(1000, traffic.total.to_peer_bytes);
+
+    let top_bytes: u64 = traffic
+        .peers
+        .iter()
+        .map(|p| p.traffic.to_client_bytes)
+        .sum();
+    assert_eq!(total_bytes - top_bytes, traffic.other.to_client_bytes);
+}
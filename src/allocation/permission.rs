@@ -1,5 +1,6 @@
 use super::*;
 
+use std::fmt;
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
 use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
@@ -14,6 +15,23 @@ pub struct Permission {
     pub(crate) permissions: Option<Arc<Mutex<HashMap<String, Permission>>>>,
     reset_tx: Option<mpsc::Sender<Duration>>,
     timer_expired: Arc<AtomicBool>,
+    pub(crate) expires_at: Arc<Mutex<Instant>>,
+}
+
+impl fmt::Debug for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Permission")
+            .field("addr", &self.addr)
+            .field("running", &self.reset_tx.is_some())
+            .field("expired", &self.timer_expired.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "permission for {}", self.addr.ip())
+    }
 }
 
 impl Permission {
@@ -24,12 +42,14 @@ impl Permission {
             permissions: None,
             reset_tx: None,
             timer_expired: Arc::new(AtomicBool::new(false)),
+            expires_at: Arc::new(Mutex::new(Instant::now())),
         }
     }
 
     pub(crate) async fn start(&mut self, lifetime: Duration) {
         let (reset_tx, mut reset_rx) = mpsc::channel(1);
         self.reset_tx = Some(reset_tx);
+        *self.expires_at.lock().await = Instant::now() + lifetime;
 
         let permissions = self.permissions.clone();
         let addr = self.addr;
@@ -71,6 +91,7 @@ impl Permission {
 
     pub(crate) async fn refresh(&self, lifetime: Duration) {
         if let Some(tx) = &self.reset_tx {
+            *self.expires_at.lock().await = Instant::now() + lifetime;
             let _ = tx.send(lifetime).await;
         }
     }
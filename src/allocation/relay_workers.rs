@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::sync::{mpsc, Notify};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// RelayReadMode selects how the relay sockets and the per-allocation relay
+// tasks are run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RelayReadMode {
+    // each allocation reads its relay socket in its own spawned task
+    #[default]
+    TaskPerAllocation,
+    // allocations are registered with a fixed pool of worker tasks, each
+    // worker only polls the allocations whose sockets are ready. A zero
+    // workers count uses one worker.
+    SharedPoll {
+        workers: usize,
+    },
+}
+
+// RelayWorkers runs an allocation's relay tasks according to a RelayReadMode,
+// counting them so a shutdown can wait for them to exit
+#[derive(Default, Clone)]
+pub(crate) struct RelayWorkers {
+    spawner: Spawner,
+    live: Arc<LiveTasks>,
+}
+
+#[derive(Default, Clone)]
+enum Spawner {
+    #[default]
+    Tokio,
+    Shared {
+        workers: Arc<Vec<mpsc::UnboundedSender<BoxFuture>>>,
+        next: Arc<AtomicUsize>,
+    },
+}
+
+// LiveTasks counts the relay tasks that haven't exited yet
+#[derive(Default)]
+struct LiveTasks {
+    count: AtomicUsize,
+    exited: Notify,
+}
+
+// LiveTask is held by a relay task for as long as it runs, or until it is
+// dropped unfinished with its worker
+struct LiveTask(Arc<LiveTasks>);
+
+impl Drop for LiveTask {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.exited.notify_waiters();
+        }
+    }
+}
+
+impl RelayWorkers {
+    // new starts the workers of a SharedPoll mode, it must be called within
+    // a tokio runtime
+    pub(crate) fn new(mode: RelayReadMode) -> Self {
+        let workers = match mode {
+            RelayReadMode::TaskPerAllocation => return RelayWorkers::default(),
+            RelayReadMode::SharedPoll { workers } => std::cmp::max(workers, 1),
+        };
+
+        let workers = (0..workers)
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(RelayWorkers::run(rx));
+                tx
+            })
+            .collect();
+
+        RelayWorkers {
+            spawner: Spawner::Shared {
+                workers: Arc::new(workers),
+                next: Arc::new(AtomicUsize::new(0)),
+            },
+            live: Arc::default(),
+        }
+    }
+
+    // spawn runs fut in its own task, or on one of the shared workers
+    pub(crate) fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.live.count.fetch_add(1, Ordering::AcqRel);
+        let live = LiveTask(Arc::clone(&self.live));
+        let fut = async move {
+            let _live = live;
+            fut.await
+        };
+        match &self.spawner {
+            Spawner::Tokio => {
+                tokio::spawn(fut);
+            }
+            Spawner::Shared { workers, next } => {
+                let i = next.fetch_add(1, Ordering::Relaxed) % workers.len();
+                // the send only fails once the runtime is gone with the worker
+                let _ = workers[i].send(Box::pin(fut));
+            }
+        }
+    }
+
+    // exited waits until every relay task spawned so far has exited
+    pub(crate) async fn exited(&self) {
+        loop {
+            let exited = self.live.exited.notified();
+            tokio::pin!(exited);
+            exited.as_mut().enable();
+            if self.live.count.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            exited.await;
+        }
+    }
+
+    // run polls the registered futures as they are woken until every sender
+    // is dropped and the futures have completed
+    async fn run(mut rx: mpsc::UnboundedReceiver<BoxFuture>) {
+        let mut futs = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                fut = rx.recv() => match fut {
+                    Some(fut) => futs.push(fut),
+                    None => break,
+                },
+                Some(()) = futs.next(), if !futs.is_empty() => {}
+            }
+        }
+        while futs.next().await.is_some() {}
+    }
+}
@@ -0,0 +1,110 @@
+use super::*;
+
+// AllocationSnapshot is a point-in-time copy of an Allocation
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationSnapshot {
+    pub protocol: String,
+    pub src_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+    pub username: String,
+    pub relay_addr: SocketAddr,
+    pub expires_in: Duration,
+    pub permissions: Vec<PermissionSnapshot>,
+    pub channel_binds: Vec<ChannelBindSnapshot>,
+    // datagrams relayed in each direction and dropped because the
+    // direction's queue was full
+    pub relayed_to_client: u64,
+    pub dropped_to_client: u64,
+    pub relayed_to_peer: u64,
+    pub dropped_to_peer: u64,
+    // traffic is the payload relayed with each peer
+    pub traffic: AllocationTraffic,
+}
+
+// PermissionSnapshot is a point-in-time copy of a Permission
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PermissionSnapshot {
+    pub addr: SocketAddr,
+    pub expires_in: Duration,
+}
+
+// ChannelBindSnapshot is a point-in-time copy of a ChannelBind
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChannelBindSnapshot {
+    pub number: u16,
+    pub peer: SocketAddr,
+    pub expires_in: Duration,
+}
+
+fn expires_in(expires_at: Instant, now: Instant) -> Duration {
+    expires_at.saturating_duration_since(now)
+}
+
+impl AllocationSnapshot {
+    // take copies the allocation out, each lock is only held while copying
+    // so the relay path is not blocked behind the snapshot
+    pub(crate) async fn take(a: &Arc<Mutex<Allocation>>) -> Self {
+        let (mut snapshot, expires_at, permissions, channel_bindings) = {
+            let a = a.lock().await;
+            (
+                AllocationSnapshot {
+                    protocol: a.five_tuple.protocol.to_string(),
+                    src_addr: a.five_tuple.src_addr,
+                    dst_addr: a.five_tuple.dst_addr,
+                    username: a.username.clone(),
+                    relay_addr: a.relay_addr,
+                    expires_in: Duration::from_secs(0),
+                    permissions: vec![],
+                    channel_binds: vec![],
+                    relayed_to_client: a.stats.relayed_to_client.load(Ordering::Relaxed),
+                    dropped_to_client: a.stats.dropped_to_client.load(Ordering::Relaxed),
+                    relayed_to_peer: a.stats.relayed_to_peer.load(Ordering::Relaxed),
+                    dropped_to_peer: a.stats.dropped_to_peer.load(Ordering::Relaxed),
+                    traffic: a.traffic.traffic(),
+                },
+                Arc::clone(&a.expires_at),
+                Arc::clone(&a.permissions),
+                Arc::clone(&a.channel_bindings),
+            )
+        };
+
+        let now = Instant::now();
+        snapshot.expires_in = expires_in(*expires_at.lock().await, now);
+
+        let permissions: Vec<(SocketAddr, Arc<Mutex<Instant>>)> = {
+            let permissions = permissions.lock().await;
+            permissions
+                .values()
+                .map(|p| (p.addr, Arc::clone(&p.expires_at)))
+                .collect()
+        };
+        for (addr, expires_at) in permissions {
+            snapshot.permissions.push(PermissionSnapshot {
+                addr,
+                expires_in: expires_in(*expires_at.lock().await, now),
+            });
+        }
+        snapshot.permissions.sort_by_key(|p| p.addr);
+
+        let channel_bindings: Vec<(ChannelNumber, SocketAddr, Arc<Mutex<Instant>>)> = {
+            let channel_bindings = channel_bindings.lock().await;
+            channel_bindings
+                .values()
+                .map(|c| (c.number, c.peer, Arc::clone(&c.expires_at)))
+                .collect()
+        };
+        for (number, peer, expires_at) in channel_bindings {
+            snapshot.channel_binds.push(ChannelBindSnapshot {
+                number: number.0,
+                peer,
+                expires_in: expires_in(*expires_at.lock().await, now),
+            });
+        }
+        snapshot.channel_binds.sort_by_key(|c| c.number);
+
+        snapshot
+    }
+}
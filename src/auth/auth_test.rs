@@ -1,14 +1,18 @@
 use super::*;
-use crate::client::*;
-use crate::relay::relay_static::*;
-use crate::server::{config::*, *};
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::{
+    client::*,
+    relay::{relay_static::*, PortSelection},
+    server::{config::*, *},
+};
 
-use std::net::IpAddr;
-use std::str::FromStr;
-use std::sync::Arc;
+#[cfg(all(feature = "client", feature = "server"))]
+use std::{net::IpAddr, str::FromStr, sync::Arc};
 
+#[cfg(all(feature = "client", feature = "server"))]
 use tokio::net::UdpSocket;
-use util::Error;
+#[cfg(all(feature = "client", feature = "server"))]
+use util::Conn;
 
 #[test]
 fn test_lt_cred() -> Result<(), Error> {
@@ -45,6 +49,71 @@ fn test_generate_auth_key() -> Result<(), Error> {
     Ok(())
 }
 
+#[cfg(feature = "saslprep")]
+#[test]
+fn test_generate_auth_key_saslprep() -> Result<(), Error> {
+    // MD5("R\u{e9}my:example.org:IX")
+    let expected_key = hex::decode("44c1a608174e5dbcbeed5e1d41b2ffb9").unwrap();
+
+    let tests = vec![
+        ("composed", "R\u{e9}my", "IX"),
+        ("decomposed username", "Re\u{301}my", "IX"),
+        ("soft hyphen in password", "R\u{e9}my", "I\u{ad}X"),
+        (
+            "compatibility character in password",
+            "R\u{e9}my",
+            "\u{2168}",
+        ),
+    ];
+    for (name, username, password) in tests {
+        assert_eq!(
+            expected_key,
+            generate_auth_key(username, "example.org", password),
+            "{}",
+            name
+        );
+    }
+
+    assert_eq!("a b", prepare_credential("a\u{a0}b"));
+    // prohibited characters are left for the peer to reject
+    assert_eq!("a\u{7}b", prepare_credential("a\u{7}b"));
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_legacy_auth_key() -> Result<(), Error> {
+    // MD5("R\u{e9}my:example.org:I\u{ad}X"), byte for byte
+    let expected_key = hex::decode("de6703a283775f817f3a75bf40cdfa7d").unwrap();
+    assert_eq!(
+        expected_key,
+        generate_legacy_auth_key("R\u{e9}my", "example.org", "I\u{ad}X")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_long_term_auth_handler_rejects_username() -> Result<(), Error> {
+    let handler = LongTermAuthHandler::new("HELLO_WORLD".to_owned());
+    let src_addr = SocketAddr::from(([127, 0, 0, 1], 3478));
+
+    for username in &["not-a-timestamp", "60"] {
+        let result = handler.auth_handle(username, "webrtc.rs", src_addr);
+        assert!(
+            matches!(result, Err(Error::Auth(_))),
+            "expected Auth error for {}",
+            username
+        );
+    }
+
+    let (username, _) = generate_long_term_credentials("HELLO_WORLD", Duration::from_secs(60))?;
+    handler.auth_handle(&username, "webrtc.rs", src_addr)?;
+
+    Ok(())
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
 #[tokio::test]
 async fn test_new_long_term_auth_handler() -> Result<(), Error> {
     // env_logger::init();
@@ -63,13 +132,31 @@ async fn test_new_long_term_auth_handler() -> Result<(), Error> {
             relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
                 relay_address: IpAddr::from_str("127.0.0.1")?,
                 address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
             }),
+            bind_device: None,
+            stun_only: false,
         }],
         realm: "webrtc.rs".to_owned(),
         auth_handler: Arc::new(Box::new(LongTermAuthHandler::new(
             SHARED_SECRET.to_string(),
         ))),
         channel_bind_timeout: Duration::from_secs(0),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        lifetime_jitter: None,
+        max_allocations: None,
+        max_allocations_code: MaxAllocationsCode::default(),
+        event_handler: None,
+        username_validator: None,
+        max_username_len: 0,
+        bind_nonce_to_client_ip: false,
+        interceptor: None,
+        intercept_point: InterceptPoint::default(),
+        stun_only_binding: true,
+        max_pending_challenges: 0,
+        load_factor: LoadFactorConfig::default(),
     })
     .await?;
 
@@ -84,9 +171,7 @@ async fn test_new_long_term_auth_handler() -> Result<(), Error> {
         username,
         password,
         realm: "webrtc.rs".to_owned(),
-        software: String::new(),
-        rto_in_ms: 0,
-        conn,
+        ..ClientConfig::new(conn)
     })
     .await?;
 
@@ -99,3 +184,163 @@ async fn test_new_long_term_auth_handler() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(all(feature = "client", feature = "server"))]
+async fn allocate_with(server_port: u16, username: String, password: String) -> Result<(), Error> {
+    let client = Client::new(ClientConfig {
+        stun_serv_addr: format!("0.0.0.0:{}", server_port),
+        turn_serv_addr: format!("0.0.0.0:{}", server_port),
+        username,
+        password,
+        realm: "webrtc.rs".to_owned(),
+        ..ClientConfig::new(Arc::new(UdpSocket::bind("0.0.0.0:0").await?))
+    })
+    .await?;
+    client.listen().await?;
+
+    let result = client.allocate().await.map(|_| ());
+    client.close().await?;
+    result
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+#[tokio::test]
+async fn test_long_term_auth_handler_rotate_secret() -> Result<(), Error> {
+    let handler = LongTermAuthHandler::new("secret-a".to_owned());
+
+    let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let server_port = conn.local_addr()?.port();
+    let server = Server::new(
+        ServerConfig::builder()
+            .add_conn(
+                conn,
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: IpAddr::from_str("127.0.0.1")?,
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(handler.clone()))
+            .build()?,
+    )
+    .await?;
+
+    let (old_username, old_password) = handler.generate_credentials(Duration::from_secs(60))?;
+
+    // the server's handler shares the secrets of its clone
+    handler.add_secret("secret-b".to_owned());
+    let (username, password) = handler.generate_credentials(Duration::from_secs(60))?;
+    assert_eq!(long_term_credentials(&username, "secret-b")?, password);
+
+    allocate_with(server_port, old_username.clone(), old_password.clone()).await?;
+    allocate_with(server_port, username.clone(), password.clone()).await?;
+
+    assert!(handler.retire_secret("secret-a"));
+    assert!(!handler.retire_secret("secret-a"));
+    assert!(
+        allocate_with(server_port, old_username, old_password)
+            .await
+            .is_err(),
+        "credentials of a retired secret must be rejected"
+    );
+    allocate_with(server_port, username, password).await?;
+
+    server.close()?;
+
+    Ok(())
+}
+
+// ContextRecorder accepts the password "pass" and records the method,
+// software and origin of each request it authenticates
+#[cfg(all(feature = "client", feature = "server"))]
+#[derive(Default)]
+struct ContextRecorder {
+    seen: Arc<std::sync::Mutex<Vec<RecordedContext>>>,
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+type RecordedContext = (stun::message::Method, Option<String>, Option<String>);
+
+#[cfg(all(feature = "client", feature = "server"))]
+impl AuthHandler for ContextRecorder {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(generate_auth_key(username, realm, "pass"))
+    }
+
+    fn auth_keys_with_context(&self, ctx: &AuthContext<'_>) -> Result<Vec<Vec<u8>>, Error> {
+        self.seen.lock().unwrap().push((
+            ctx.method,
+            ctx.software.map(str::to_owned),
+            ctx.origin.map(str::to_owned),
+        ));
+        self.auth_keys(ctx.username, ctx.realm, ctx.src_addr)
+    }
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+#[tokio::test]
+async fn test_auth_handler_context() -> Result<(), Error> {
+    let handler = ContextRecorder::default();
+    let seen = Arc::clone(&handler.seen);
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let server_addr = conn.local_addr()?;
+    let server = Server::new(
+        ServerConfig::builder()
+            .add_conn(
+                conn,
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: IpAddr::from_str("127.0.0.1")?,
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(handler))
+            .build()?,
+    )
+    .await?;
+
+    let client = Client::new(ClientConfig {
+        turn_serv_addr: server_addr.to_string(),
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        software: "policy-test/1.0".to_owned(),
+        ..ClientConfig::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+    })
+    .await?;
+    client.listen().await?;
+
+    let allocation = client.allocate().await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    allocation.send_to(b"hello", peer.local_addr()?).await?;
+
+    // the client sends no ORIGIN, CreatePermission gets no metadata
+    {
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            vec![
+                (
+                    stun::message::METHOD_ALLOCATE,
+                    Some("policy-test/1.0".to_owned()),
+                    None
+                ),
+                (stun::message::METHOD_CREATE_PERMISSION, None, None),
+            ],
+            *seen
+        );
+    }
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
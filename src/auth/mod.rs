@@ -1,14 +1,22 @@
 #[cfg(test)]
 mod auth_test;
 
+use std::borrow::Cow;
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use util::Error;
+use crate::error::Error;
+
+use stun::message::Method;
 
 use md5::{Digest, Md5};
 use ring::hmac;
 
+// REDACTED replaces passwords, integrity keys, nonces and auth keys in Debug output
+pub(crate) const REDACTED: &str = "<redacted>";
+
 pub trait AuthHandler {
     fn auth_handle(
         &self,
@@ -16,6 +24,39 @@ pub trait AuthHandler {
         realm: &str,
         src_addr: SocketAddr,
     ) -> Result<Vec<u8>, Error>;
+
+    // auth_keys returns every key a request of username may be signed with,
+    // the request is accepted if any of them checks. Defaults to the key of
+    // auth_handle.
+    fn auth_keys(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(vec![self.auth_handle(username, realm, src_addr)?])
+    }
+
+    // auth_keys_with_context is auth_keys with what else the request told
+    // about the client, the server calls it to authenticate every request.
+    // Defaults to auth_keys.
+    fn auth_keys_with_context(&self, ctx: &AuthContext<'_>) -> Result<Vec<Vec<u8>>, Error> {
+        self.auth_keys(ctx.username, ctx.realm, ctx.src_addr)
+    }
+}
+
+// AuthContext is an authenticated request as the auth handler sees it.
+// software and origin are the SOFTWARE and ORIGIN attributes of Allocate
+// requests, they are None when absent and for other methods. Neither is
+// checked, a handler may log them or refuse clients by them.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthContext<'a> {
+    pub username: &'a str,
+    pub realm: &'a str,
+    pub src_addr: SocketAddr,
+    pub method: Method,
+    pub software: Option<&'a str>,
+    pub origin: Option<&'a str>,
 }
 
 // generate_long_term_credentials can be used to create credentials valid for [duration] time
@@ -38,17 +79,62 @@ fn long_term_credentials(username: &str, shared_secret: &str) -> Result<String,
     Ok(base64::encode(&password))
 }
 
-// generate_auth_key is a convince function to easily generate keys in the format used by AuthHandler
+// generate_auth_key is a convince function to easily generate keys in the format used by AuthHandler.
+// The username, realm and password are prepared with prepare_credential first.
 pub fn generate_auth_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    generate_legacy_auth_key(
+        &prepare_credential(username),
+        &prepare_credential(realm),
+        &prepare_credential(password),
+    )
+}
+
+// generate_legacy_auth_key hashes the strings byte for byte, for peers that
+// don't prepare credentials
+pub fn generate_legacy_auth_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
     let s = format!("{}:{}:{}", username, realm, password);
 
     let mut h = Md5::new();
     h.update(s.as_bytes());
-    h.finalize().as_slice().to_vec()
+    h.finalize().to_vec()
+}
+
+// prepare_credential applies SASLprep (RFC 4013) to a username, realm or password
+// with the saslprep feature, which is what RFC 5389 asks for and compliant peers
+// do. It is a no-op without the feature and for printable ASCII. Strings that
+// SASLprep rejects are used as they are.
+#[cfg(feature = "saslprep")]
+pub fn prepare_credential(s: &str) -> Cow<'_, str> {
+    match stringprep::saslprep(s) {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            log::warn!("credential can't be prepared with SASLprep: {}", err);
+            Cow::Borrowed(s)
+        }
+    }
 }
 
+#[cfg(not(feature = "saslprep"))]
+pub fn prepare_credential(s: &str) -> Cow<'_, str> {
+    Cow::Borrowed(s)
+}
+
+// LongTermAuthHandler authenticates the time-windowed credentials of the TURN
+// REST API. It accepts credentials of any of its shared secrets, newest first,
+// so a secret can be rotated without invalidating credentials minted with the
+// previous one. Clones share their secrets, a clone kept by the application
+// can add and retire secrets of the handler the server runs with.
+#[derive(Clone)]
 pub struct LongTermAuthHandler {
-    shared_secret: String,
+    shared_secrets: Arc<RwLock<Vec<String>>>,
+}
+
+impl fmt::Debug for LongTermAuthHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LongTermAuthHandler")
+            .field("shared_secrets", &REDACTED)
+            .finish()
+    }
 }
 
 impl AuthHandler for LongTermAuthHandler {
@@ -58,6 +144,18 @@ impl AuthHandler for LongTermAuthHandler {
         realm: &str,
         src_addr: SocketAddr,
     ) -> Result<Vec<u8>, Error> {
+        self.auth_keys(username, realm, src_addr)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Auth("no shared secret".to_owned()))
+    }
+
+    fn auth_keys(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<Vec<u8>>, Error> {
         log::trace!(
             "Authentication username={} realm={} src_addr={}",
             username,
@@ -65,22 +163,81 @@ impl AuthHandler for LongTermAuthHandler {
             src_addr
         );
 
-        let t = Duration::from_secs(username.parse::<u64>()?);
+        let t =
+            Duration::from_secs(username.parse::<u64>().map_err(|_| {
+                Error::Auth(format!("Invalid time-windowed username {}", username))
+            })?);
         if t < SystemTime::now().duration_since(UNIX_EPOCH)? {
-            return Err(Error::new(format!(
+            return Err(Error::Auth(format!(
                 "Expired time-windowed username {}",
                 username
             )));
         }
 
-        let password = long_term_credentials(username, &self.shared_secret)?;
-        Ok(generate_auth_key(username, realm, &password))
+        let shared_secrets = self.shared_secrets();
+        if shared_secrets.is_empty() {
+            return Err(Error::Auth("no shared secret".to_owned()));
+        }
+        shared_secrets
+            .iter()
+            .map(|shared_secret| {
+                let password = long_term_credentials(username, shared_secret)?;
+                Ok(generate_auth_key(username, realm, &password))
+            })
+            .collect()
     }
 }
 
 impl LongTermAuthHandler {
     // https://tools.ietf.org/search/rfc5389#section-10.2
     pub fn new(shared_secret: String) -> Self {
-        LongTermAuthHandler { shared_secret }
+        LongTermAuthHandler::with_secrets(vec![shared_secret])
+    }
+
+    // with_secrets accepts credentials of any of shared_secrets, ordered
+    // newest first
+    pub fn with_secrets(shared_secrets: Vec<String>) -> Self {
+        LongTermAuthHandler {
+            shared_secrets: Arc::new(RwLock::new(shared_secrets)),
+        }
+    }
+
+    fn shared_secrets(&self) -> std::sync::RwLockReadGuard<'_, Vec<String>> {
+        self.shared_secrets
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    // generate_credentials creates credentials valid for duration with the
+    // newest shared secret
+    pub fn generate_credentials(&self, duration: Duration) -> Result<(String, String), Error> {
+        let shared_secrets = self.shared_secrets();
+        let shared_secret = shared_secrets
+            .first()
+            .ok_or_else(|| Error::Auth("no shared secret".to_owned()))?;
+        generate_long_term_credentials(shared_secret, duration)
+    }
+
+    // add_secret makes shared_secret the newest, the others stay accepted
+    // until retired
+    pub fn add_secret(&self, shared_secret: String) {
+        let mut shared_secrets = self
+            .shared_secrets
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        shared_secrets.retain(|s| *s != shared_secret);
+        shared_secrets.insert(0, shared_secret);
+    }
+
+    // retire_secret stops accepting credentials of shared_secret, it returns
+    // false if it wasn't accepted
+    pub fn retire_secret(&self, shared_secret: &str) -> bool {
+        let mut shared_secrets = self
+            .shared_secrets
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        let len = shared_secrets.len();
+        shared_secrets.retain(|s| s != shared_secret);
+        shared_secrets.len() != len
     }
 }
@@ -0,0 +1,94 @@
+use super::*;
+
+use crate::error::Error;
+
+#[tokio::test]
+async fn test_batch_conn_batch_size() -> Result<(), Error> {
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 0);
+    assert_eq!(conn.batch_size(), DEFAULT_BATCH_SIZE);
+
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 4);
+    assert_eq!(conn.batch_size(), 4);
+
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, usize::MAX);
+    assert_eq!(conn.batch_size(), MAX_BATCH_SIZE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_conn_recv_from_keeps_sources() -> Result<(), Error> {
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 4);
+    let conn_addr = conn.local_addr()?;
+
+    let sender1 = UdpSocket::bind("127.0.0.1:0").await?;
+    let sender2 = UdpSocket::bind("127.0.0.1:0").await?;
+    for i in 0..10u8 {
+        sender1.send_to(&[1, i], conn_addr).await?;
+        sender2.send_to(&[2, i, i], conn_addr).await?;
+    }
+
+    let mut next = [0u8; 2];
+    let mut buf = vec![0u8; 1500];
+    for _ in 0..20 {
+        let (n, from) = conn.recv_from(&mut buf).await?;
+        let (sender, len) = if from == sender1.local_addr()? {
+            (1, 2)
+        } else if from == sender2.local_addr()? {
+            (2, 3)
+        } else {
+            panic!("unexpected source {}", from);
+        };
+        assert_eq!(n, len, "datagram length should be kept");
+        assert_eq!(buf[0], sender, "datagram should match its source");
+        assert_eq!(
+            buf[1],
+            next[sender as usize - 1],
+            "datagrams should be in order"
+        );
+        next[sender as usize - 1] += 1;
+    }
+    assert_eq!(next, [10, 10]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_conn_recv_from_truncates() -> Result<(), Error> {
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 0);
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+    sender.send_to(b"0123456789", conn.local_addr()?).await?;
+
+    let mut buf = vec![0u8; 4];
+    let (n, from) = conn.recv_from(&mut buf).await?;
+    assert_eq!(n, 4);
+    assert_eq!(&buf, b"0123");
+    assert_eq!(from, sender.local_addr()?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_conn_send_batch() -> Result<(), Error> {
+    let conn = BatchConn::new(UdpSocket::bind("127.0.0.1:0").await?, 4);
+    let receiver1 = UdpSocket::bind("127.0.0.1:0").await?;
+    let receiver2 = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut packets = vec![];
+    for i in 0..10u8 {
+        packets.push((vec![1, i], receiver1.local_addr()?));
+        packets.push((vec![2, i], receiver2.local_addr()?));
+    }
+    assert_eq!(conn.send_batch(&packets).await?, packets.len());
+
+    let mut buf = vec![0u8; 1500];
+    for (receiver, sender) in [(&receiver1, 1u8), (&receiver2, 2u8)].iter() {
+        for i in 0..10u8 {
+            let (n, from) = receiver.recv_from(&mut buf).await?;
+            assert_eq!(from, conn.local_addr()?);
+            assert_eq!(&buf[..n], &[*sender, i]);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// recv reads as many datagrams as there are slot_size slots in buf with a
+// single recvmmsg call and queues their offset, length and source
+pub(crate) fn recv(
+    fd: RawFd,
+    buf: &mut [u8],
+    slot_size: usize,
+    packets: &mut VecDeque<(usize, usize, SocketAddr)>,
+) -> io::Result<()> {
+    let count = buf.len() / slot_size;
+
+    // sockaddr_storage and mmsghdr are plain C structs, all zeroes is valid
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut iovecs: Vec<libc::iovec> = buf
+        .chunks_mut(slot_size)
+        .take(count)
+        .map(|slot| libc::iovec {
+            iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; count];
+    for ((hdr, iovec), addr) in hdrs.iter_mut().zip(iovecs.iter_mut()).zip(addrs.iter_mut()) {
+        hdr.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_hdr.msg_iov = iovec;
+        hdr.msg_hdr.msg_iovlen = 1;
+    }
+
+    // every pointer in hdrs refers to addrs, iovecs or buf, which outlive the call
+    let n = unsafe {
+        libc::recvmmsg(
+            fd,
+            hdrs.as_mut_ptr(),
+            count as libc::c_uint,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (i, (hdr, addr)) in hdrs.iter().zip(addrs.iter()).take(n as usize).enumerate() {
+        if let Some(from) = to_socket_addr(addr) {
+            packets.push_back((i * slot_size, hdr.msg_len as usize, from));
+        }
+    }
+
+    Ok(())
+}
+
+// send writes packets with a single sendmmsg call and returns how many were sent
+pub(crate) fn send<B: AsRef<[u8]>>(fd: RawFd, packets: &[(B, SocketAddr)]) -> io::Result<usize> {
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+        packets.iter().map(|(_, to)| from_socket_addr(to)).collect();
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(data, _)| libc::iovec {
+            // sendmmsg only reads from the buffer
+            iov_base: data.as_ref().as_ptr() as *mut libc::c_void,
+            iov_len: data.as_ref().len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; packets.len()];
+    for ((hdr, iovec), (addr, addr_len)) in
+        hdrs.iter_mut().zip(iovecs.iter_mut()).zip(addrs.iter_mut())
+    {
+        hdr.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hdr.msg_hdr.msg_namelen = *addr_len;
+        hdr.msg_hdr.msg_iov = iovec;
+        hdr.msg_hdr.msg_iovlen = 1;
+    }
+
+    // every pointer in hdrs refers to addrs, iovecs or packets, which outlive the call
+    let n = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as libc::c_uint, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // the storage holds a sockaddr_in when the family is AF_INET
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // the storage holds a sockaddr_in6 when the family is AF_INET6
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            // sockaddr_storage is large and aligned enough for any sockaddr
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from(*addr.ip()).to_be(),
+            };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
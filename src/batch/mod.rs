@@ -0,0 +1,230 @@
+#[cfg(test)]
+mod batch_test;
+
+#[cfg(all(target_os = "linux", feature = "batch"))]
+mod mmsg;
+
+use util::Conn;
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+// DEFAULT_BATCH_SIZE is the number of datagrams moved per wakeup when the
+// batch size is zero
+pub const DEFAULT_BATCH_SIZE: usize = 16;
+
+// MAX_BATCH_SIZE is the limit on the number of messages per recvmmsg/sendmmsg
+pub const MAX_BATCH_SIZE: usize = 1024;
+
+// BatchConn is a UDP socket that receives and sends up to batch_size datagrams
+// per wakeup. With the batch feature on Linux each batch is a single
+// recvmmsg/sendmmsg call, elsewhere the datagrams that are ready are read or
+// written one at a time.
+//
+// recv_from hands out one queued datagram per call with its own source
+// address, so BatchConn can be used anywhere a Conn is, e.g. as a server
+// listener. Datagrams longer than the buffer passed to recv_from are
+// truncated, as they would be by the socket.
+pub struct BatchConn {
+    socket: UdpSocket,
+    batch_size: usize,
+    recv_state: Mutex<RecvState>,
+}
+
+#[derive(Default)]
+struct RecvState {
+    buf: Vec<u8>,
+    slot_size: usize,
+    // offset and length in buf and source of each queued datagram
+    packets: VecDeque<(usize, usize, SocketAddr)>,
+}
+
+impl BatchConn {
+    // new wraps socket, a batch_size of zero selects DEFAULT_BATCH_SIZE
+    pub fn new(socket: UdpSocket, batch_size: usize) -> Self {
+        let batch_size = if batch_size == 0 {
+            DEFAULT_BATCH_SIZE
+        } else {
+            std::cmp::min(batch_size, MAX_BATCH_SIZE)
+        };
+
+        BatchConn {
+            socket,
+            batch_size,
+            recv_state: Mutex::new(RecvState::default()),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    #[cfg(all(feature = "icmp", target_os = "linux"))]
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    // send_batch sends every packet, batch_size at a time, and returns the
+    // number of packets sent
+    pub async fn send_batch<B: AsRef<[u8]>>(
+        &self,
+        packets: &[(B, SocketAddr)],
+    ) -> io::Result<usize> {
+        let mut sent = 0;
+        while sent < packets.len() {
+            let end = std::cmp::min(packets.len(), sent + self.batch_size);
+            self.socket.writable().await?;
+            match self.try_send_batch(&packets[sent..end]) {
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(sent)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "batch"))]
+    fn try_send_batch<B: AsRef<[u8]>>(&self, packets: &[(B, SocketAddr)]) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.socket.as_raw_fd();
+        self.socket
+            .try_io(tokio::io::Interest::WRITABLE, || mmsg::send(fd, packets))
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "batch")))]
+    fn try_send_batch<B: AsRef<[u8]>>(&self, packets: &[(B, SocketAddr)]) -> io::Result<usize> {
+        let mut n = 0;
+        for (data, to) in packets {
+            match self.socket.try_send_to(data.as_ref(), *to) {
+                Ok(_) => n += 1,
+                Err(err) if n > 0 && err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(n)
+    }
+
+    // pop_packet copies the next queued datagram into buf
+    fn pop_packet(&self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let mut recv_state = self
+            .recv_state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let (offset, len, from) = recv_state.packets.pop_front()?;
+        let n = std::cmp::min(len, buf.len());
+        buf[..n].copy_from_slice(&recv_state.buf[offset..offset + n]);
+        Some((n, from))
+    }
+
+    // try_recv_batch queues the datagrams that are ready, up to batch_size,
+    // in slots of slot_size bytes
+    fn try_recv_batch(&self, slot_size: usize) -> io::Result<()> {
+        let mut recv_state = self
+            .recv_state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if !recv_state.packets.is_empty() {
+            return Ok(());
+        }
+
+        if recv_state.slot_size < slot_size {
+            recv_state.slot_size = slot_size;
+        }
+        let buf_len = recv_state.slot_size * self.batch_size;
+        recv_state.buf.resize(buf_len, 0);
+
+        let RecvState {
+            buf,
+            slot_size,
+            packets,
+        } = &mut *recv_state;
+        self.recv_into(buf, *slot_size, packets)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "batch"))]
+    fn recv_into(
+        &self,
+        buf: &mut [u8],
+        slot_size: usize,
+        packets: &mut VecDeque<(usize, usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.socket.as_raw_fd();
+        self.socket.try_io(tokio::io::Interest::READABLE, || {
+            mmsg::recv(fd, buf, slot_size, packets)
+        })
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "batch")))]
+    fn recv_into(
+        &self,
+        buf: &mut [u8],
+        slot_size: usize,
+        packets: &mut VecDeque<(usize, usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        for (i, slot) in buf.chunks_mut(slot_size).enumerate() {
+            match self.socket.try_recv_from(slot) {
+                Ok((n, from)) => packets.push_back((i * slot_size, n, from)),
+                Err(err) if !packets.is_empty() && err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Conn for BatchConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.socket.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            if let Some(received) = self.pop_packet(buf) {
+                return Ok(received);
+            }
+
+            self.socket.readable().await?;
+            match self.try_recv_batch(buf.len()) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[cfg(feature = "client")]
+#[async_trait]
+impl crate::client::send_batch::BatchSend for BatchConn {
+    async fn send_batch(&self, packets: &[Vec<u8>], to: SocketAddr) -> io::Result<usize> {
+        let packets: Vec<(&[u8], SocketAddr)> = packets.iter().map(|p| (&p[..], to)).collect();
+        BatchConn::send_batch(self, &packets).await
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+// AllocationTtl is the remaining life of an allocation as of its last refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationTtl {
+    // expires_at is when the allocation expires unless refreshed again, a
+    // failed refresh leaves it as it was
+    pub expires_at: Instant,
+    // last_refresh_ok is whether the last refresh attempt succeeded, it is
+    // true until the first one
+    pub last_refresh_ok: bool,
+}
+
+impl AllocationTtl {
+    // remaining is the time left until expires_at, zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+}
+
+#[derive(Debug)]
+struct TtlState {
+    ttl: AllocationTtl,
+    tx: Option<watch::Sender<AllocationTtl>>,
+}
+
+// TtlWatch is shared by a RelayConn and its refresh timer. The watch channel
+// is only created once someone subscribes, and updating it takes a std mutex
+// that is never held across an await, not the RelayConn's internal lock.
+#[derive(Debug)]
+pub(crate) struct TtlWatch {
+    state: Mutex<TtlState>,
+}
+
+impl TtlWatch {
+    pub(crate) fn new(lifetime: Duration) -> Self {
+        TtlWatch {
+            state: Mutex::new(TtlState {
+                ttl: AllocationTtl {
+                    expires_at: Instant::now() + lifetime,
+                    last_refresh_ok: true,
+                },
+                tx: None,
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, TtlState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<AllocationTtl> {
+        let mut state = self.state();
+        let ttl = state.ttl;
+        state
+            .tx
+            .get_or_insert_with(|| watch::channel(ttl).0)
+            .subscribe()
+    }
+
+    pub(crate) fn get(&self) -> AllocationTtl {
+        self.state().ttl
+    }
+
+    // refreshed records a refresh attempt, a successful one granting lifetime
+    pub(crate) fn refreshed(&self, lifetime: Option<Duration>) {
+        let mut state = self.state();
+        state.ttl = AllocationTtl {
+            expires_at: lifetime.map_or(state.ttl.expires_at, |lifetime| Instant::now() + lifetime),
+            last_refresh_ok: lifetime.is_some(),
+        };
+        let ttl = state.ttl;
+        if let Some(tx) = &state.tx {
+            tx.send_replace(ttl);
+        }
+    }
+}
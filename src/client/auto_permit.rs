@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+// AUTO_PERMIT_QUEUE_SIZE bounds the peers waiting for their permission, they
+// are permitted one at a time
+pub(crate) const AUTO_PERMIT_QUEUE_SIZE: usize = 16;
+
+// AUTO_PERMIT_RATE is how many new peer IPs per second may be permitted from
+// inbound hints, a server flooding Data indications from made up peers gets
+// no more CreatePermission requests out of us
+pub const AUTO_PERMIT_RATE: u32 = 10;
+
+// MAX_AUTO_PERMIT_PEERS is how many peer IPs are permitted from inbound
+// hints in all, further ones wait for a send_to
+pub const MAX_AUTO_PERMIT_PEERS: usize = 256;
+
+#[derive(Debug)]
+struct AutoPermitState {
+    tx: Option<mpsc::Sender<SocketAddr>>,
+    // hinted is the IPs queued or permitted from hints
+    hinted: HashSet<IpAddr>,
+    tokens: u32,
+    refilled: Instant,
+}
+
+// AutoPermit turns the peers of Data indications into permissions before the
+// application replies to them, see RelayConnConfig::auto_permit_inbound. It
+// is shared by the RelayConn and its InboundQueue, hints are ignored until
+// the RelayConn starts it.
+#[derive(Debug)]
+pub(crate) struct AutoPermit {
+    state: Mutex<AutoPermitState>,
+}
+
+impl Default for AutoPermit {
+    fn default() -> Self {
+        AutoPermit {
+            state: Mutex::new(AutoPermitState {
+                tx: None,
+                hinted: HashSet::new(),
+                tokens: AUTO_PERMIT_RATE,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl AutoPermit {
+    fn state(&self) -> std::sync::MutexGuard<'_, AutoPermitState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // start returns the peers to permit, hinted from then on
+    pub(crate) fn start(&self) -> mpsc::Receiver<SocketAddr> {
+        let (tx, rx) = mpsc::channel(AUTO_PERMIT_QUEUE_SIZE);
+        let mut state = self.state();
+        state.tx = Some(tx);
+        state.refilled = Instant::now();
+        rx
+    }
+
+    // stop ends the receiver returned by start
+    pub(crate) fn stop(&self) {
+        self.state().tx.take();
+    }
+
+    // hint queues the peer of a Data indication for a permission, unless its
+    // IP was hinted already or the rate or queue is exhausted
+    pub(crate) fn hint(&self, peer: SocketAddr) {
+        let mut state = self.state();
+        let state = &mut *state;
+        let tx = match &state.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        if state.hinted.contains(&peer.ip()) || state.hinted.len() >= MAX_AUTO_PERMIT_PEERS {
+            return;
+        }
+
+        let now = Instant::now();
+        let refill = (now - state.refilled).as_millis() as u64 * AUTO_PERMIT_RATE as u64 / 1000;
+        if refill > 0 {
+            state.tokens = (state.tokens as u64 + refill).min(AUTO_PERMIT_RATE as u64) as u32;
+            state.refilled = now;
+        }
+        if state.tokens == 0 {
+            log::trace!("auto permit rate exceeded, {} not permitted", peer.ip());
+            return;
+        }
+        state.tokens -= 1;
+
+        if tx.try_send(peer).is_ok() {
+            state.hinted.insert(peer.ip());
+        } else {
+            log::trace!("auto permit queue full, {} not permitted", peer.ip());
+        }
+    }
+
+    // forget lets the IP of peer be hinted again, its permission failed
+    pub(crate) fn forget(&self, peer: SocketAddr) {
+        self.state().hinted.remove(&peer.ip());
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(test)]
+mod bind_policy_test;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+use tokio::time::{Duration, Instant};
+
+// CHANNEL_BIND_RATE_INTERVAL is the sliding window ChannelBindPolicy::Adaptive
+// counts the packets sent to a peer over
+pub const CHANNEL_BIND_RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// MAX_RATED_PEERS bounds the peers SendRates counts for, the idle ones are
+// forgotten when it is reached
+const MAX_RATED_PEERS: usize = 4096;
+
+// ChannelBindPolicy decides when send_to binds a channel to a peer. A
+// ChannelBind costs a transaction and one of the few channel numbers, in
+// exchange data to the peer goes in ChannelData instead of Send indications.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelBindPolicy {
+    // Always binds on the first send to the peer
+    #[default]
+    Always,
+    // Never binds, only the bindings made by RelayConn::permit_peers are used
+    Never,
+    // Adaptive binds once min_packets_per_interval packets were sent to the
+    // peer within CHANNEL_BIND_RATE_INTERVAL, peers sending less stay on Send
+    // indications
+    Adaptive {
+        min_packets_per_interval: u32,
+    },
+}
+
+// SendRates counts the packets recently sent to each peer that has no
+// binding yet, for ChannelBindPolicy::Adaptive
+#[derive(Debug, Default)]
+pub(crate) struct SendRates {
+    sent: HashMap<SocketAddr, VecDeque<Instant>>,
+}
+
+impl SendRates {
+    // record counts a packet sent to peer and reports if min_packets were sent
+    // to it within CHANNEL_BIND_RATE_INTERVAL, the peer is then forgotten
+    pub(crate) fn record(&mut self, peer: SocketAddr, min_packets: u32) -> bool {
+        let now = Instant::now();
+        let recent = |at: &Instant| now.duration_since(*at) < CHANNEL_BIND_RATE_INTERVAL;
+        if self.sent.len() >= MAX_RATED_PEERS && !self.sent.contains_key(&peer) {
+            self.sent.retain(|_, sent| sent.back().is_some_and(recent));
+            if self.sent.len() >= MAX_RATED_PEERS {
+                return false;
+            }
+        }
+
+        let sent = self.sent.entry(peer).or_default();
+        while sent.front().is_some_and(|at| !recent(at)) {
+            sent.pop_front();
+        }
+        sent.push_back(now);
+        if sent.len() < min_packets as usize {
+            return false;
+        }
+        self.sent.remove(&peer);
+        true
+    }
+}
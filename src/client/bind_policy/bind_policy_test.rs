@@ -0,0 +1,50 @@
+use super::*;
+
+use std::net::Ipv4Addr;
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), port)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_send_rates_threshold() {
+    let mut rates = SendRates::default();
+
+    // 4 packets a second stay under 5, however long they go on
+    for _ in 0..20 {
+        assert!(!rates.record(peer(1), 5));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    // the 5th packet within the window crosses it, the peer is forgotten
+    for _ in 0..4 {
+        assert!(!rates.record(peer(2), 5));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(rates.record(peer(2), 5));
+    assert!(!rates.sent.contains_key(&peer(2)));
+    assert!(rates.sent.contains_key(&peer(1)));
+
+    // a threshold of one or less binds on the first packet
+    assert!(rates.record(peer(3), 1));
+    assert!(rates.record(peer(4), 0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_send_rates_bounded() {
+    let mut rates = SendRates::default();
+    for port in 0..MAX_RATED_PEERS as u16 {
+        rates.record(peer(port), 2);
+    }
+    assert_eq!(MAX_RATED_PEERS, rates.sent.len());
+
+    // while all are recent, further peers aren't counted
+    let extra = peer(MAX_RATED_PEERS as u16);
+    assert!(!rates.record(extra, 1));
+    assert!(!rates.sent.contains_key(&extra));
+
+    // idle ones make room
+    tokio::time::sleep(CHANNEL_BIND_RATE_INTERVAL).await;
+    assert!(!rates.record(extra, 2));
+    assert_eq!(1, rates.sent.len());
+}
@@ -1,8 +1,12 @@
 #[cfg(test)]
 mod binding_test;
 
+use super::peer_addr::canonical_peer_addr;
+use crate::error::BindingError;
+
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 
 use tokio::time::Instant;
 
@@ -12,8 +16,12 @@ use tokio::time::Instant;
 const MIN_CHANNEL_NUMBER: u16 = 0x4000;
 const MAX_CHANNEL_NUMBER: u16 = 0x7fff;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub(crate) enum BindingState {
+// MAX_BINDINGS is the number of channel numbers, a client can't bind more peers
+pub const MAX_BINDINGS: usize = (MAX_CHANNEL_NUMBER - MIN_CHANNEL_NUMBER) as usize + 1;
+
+// BindingState is the state of the channel binding for a peer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BindingState {
     Idle,
     Request,
     Ready,
@@ -27,12 +35,47 @@ pub(crate) struct Binding {
     pub(crate) st: BindingState,
     pub(crate) addr: SocketAddr,
     pub(crate) refreshed_at: Instant,
+    // last_seen_at is when ChannelData last came in on the channel
+    pub(crate) last_seen_at: Option<Instant>,
+}
+
+// BindingInfo describes the channel binding of a peer, see
+// RelayConn::bindings
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BindingInfo {
+    pub peer: SocketAddr,
+    pub number: u16,
+    pub state: BindingState,
+    // refreshed_at is when the channel was bound or last refreshed
+    pub refreshed_at: Instant,
+    // last_seen_at is when ChannelData from the peer last came in on it
+    pub last_seen_at: Option<Instant>,
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "binding {} (ch={}) {:?}",
+            self.addr, self.number, self.st
+        )
+    }
 }
 
 impl Binding {
-    pub(crate) fn set_state(&mut self, state: BindingState) {
+    // set_state returns the state the binding was in
+    pub(crate) fn set_state(&mut self, state: BindingState) -> BindingState {
         //atomic.StoreInt32((*int32)(&b.st), int32(state))
-        self.st = state;
+        if self.st != state {
+            state_event!(
+                "binding {} (ch={}) {:?} -> {:?}",
+                self.addr,
+                self.number,
+                self.st,
+                state
+            );
+        }
+        std::mem::replace(&mut self.st, state)
     }
 
     pub(crate) fn state(&self) -> BindingState {
@@ -47,9 +90,26 @@ impl Binding {
     pub(crate) fn refreshed_at(&self) -> Instant {
         self.refreshed_at
     }
+
+    // received_since_refresh reports if ChannelData came in on the channel
+    // since it was last bound or refreshed
+    pub(crate) fn received_since_refresh(&self) -> bool {
+        self.last_seen_at
+            .is_some_and(|last_seen_at| last_seen_at >= self.refreshed_at)
+    }
+
+    pub(crate) fn info(&self) -> BindingInfo {
+        BindingInfo {
+            peer: self.addr,
+            number: self.number,
+            state: self.st,
+            refreshed_at: self.refreshed_at,
+            last_seen_at: self.last_seen_at,
+        }
+    }
 }
-// Thread-safe Binding map
-#[derive(Default)]
+// Thread-safe Binding map, keyed by the canonical address of the peers
+#[derive(Default, Debug)]
 pub(crate) struct BindingManager {
     chan_map: HashMap<u16, String>,
     addr_map: HashMap<String, Binding>,
@@ -65,35 +125,62 @@ impl BindingManager {
         }
     }
 
-    pub(crate) fn assign_channel_number(&mut self) -> u16 {
-        let n = self.next;
-        if self.next == MAX_CHANNEL_NUMBER {
-            self.next = MIN_CHANNEL_NUMBER;
-        } else {
-            self.next += 1;
+    // assign_channel_number returns the next channel number, skipping those
+    // still bound once the numbers have wrapped around
+    pub(crate) fn assign_channel_number(&mut self) -> Result<u16, BindingError> {
+        if self.chan_map.len() >= MAX_BINDINGS {
+            return Err(BindingError::Exhausted);
+        }
+
+        loop {
+            let n = self.next;
+            if self.next == MAX_CHANNEL_NUMBER {
+                self.next = MIN_CHANNEL_NUMBER;
+            } else {
+                self.next += 1;
+            }
+            if !self.chan_map.contains_key(&n) {
+                return Ok(n);
+            }
         }
-        n
     }
 
-    pub(crate) fn create(&mut self, addr: SocketAddr) -> Option<&Binding> {
+    // create binds addr to a new channel number, unless addr is already bound
+    // or max_bindings peers are
+    pub(crate) fn create(
+        &mut self,
+        addr: SocketAddr,
+        max_bindings: usize,
+    ) -> Result<&mut Binding, BindingError> {
+        let addr = canonical_peer_addr(addr);
+        let key = addr.to_string();
+        if self.addr_map.contains_key(&key) {
+            return Err(BindingError::AlreadyExists(addr));
+        }
+        if self.addr_map.len() >= max_bindings {
+            return Err(BindingError::LimitReached(max_bindings));
+        }
+
         let b = Binding {
-            number: self.assign_channel_number(),
+            number: self.assign_channel_number()?,
             st: BindingState::Idle,
             addr,
             refreshed_at: Instant::now(),
+            last_seen_at: None,
         };
 
-        self.chan_map.insert(b.number, b.addr.to_string());
-        self.addr_map.insert(b.addr.to_string(), b);
-        self.addr_map.get(&addr.to_string())
+        state_event!("binding {} (ch={}) created", b.addr, b.number);
+        self.chan_map.insert(b.number, key.clone());
+        Ok(self.addr_map.entry(key).or_insert(b))
     }
 
     pub(crate) fn find_by_addr(&self, addr: &SocketAddr) -> Option<&Binding> {
-        self.addr_map.get(&addr.to_string())
+        self.addr_map.get(&canonical_peer_addr(*addr).to_string())
     }
 
     pub(crate) fn get_by_addr(&mut self, addr: &SocketAddr) -> Option<&mut Binding> {
-        self.addr_map.get_mut(&addr.to_string())
+        self.addr_map
+            .get_mut(&canonical_peer_addr(*addr).to_string())
     }
 
     pub(crate) fn find_by_number(&self, number: u16) -> Option<&Binding> {
@@ -112,8 +199,24 @@ impl BindingManager {
         }
     }
 
+    // seen_by_number records ChannelData came in on number and returns the
+    // peer bound to it
+    pub(crate) fn seen_by_number(&mut self, number: u16) -> Option<SocketAddr> {
+        let b = self.get_by_number(number)?;
+        b.last_seen_at = Some(Instant::now());
+        Some(b.addr)
+    }
+
+    pub(crate) fn bindings(&self) -> impl Iterator<Item = &Binding> {
+        self.addr_map.values()
+    }
+
     pub(crate) fn delete_by_addr(&mut self, addr: &SocketAddr) -> bool {
-        if let Some(b) = self.addr_map.remove(&addr.to_string()) {
+        if let Some(b) = self
+            .addr_map
+            .remove(&canonical_peer_addr(*addr).to_string())
+        {
+            state_event!("binding {} (ch={}) deleted", b.addr, b.number);
             self.chan_map.remove(&b.number);
             true
         } else {
@@ -121,8 +224,25 @@ impl BindingManager {
         }
     }
 
+    // delete_by_ip deletes the bindings of every port of ip, returning how
+    // many there were
+    pub(crate) fn delete_by_ip(&mut self, ip: IpAddr) -> usize {
+        let ip = canonical_peer_addr(SocketAddr::new(ip, 0)).ip();
+        let addrs: Vec<SocketAddr> = self
+            .addr_map
+            .values()
+            .filter(|b| b.addr.ip() == ip)
+            .map(|b| b.addr)
+            .collect();
+        for addr in &addrs {
+            self.delete_by_addr(addr);
+        }
+        addrs.len()
+    }
+
     pub(crate) fn delete_by_number(&mut self, number: u16) -> bool {
         if let Some(s) = self.chan_map.remove(&number) {
+            state_event!("binding {} (ch={}) deleted", s, number);
             self.addr_map.remove(&s);
             true
         } else {
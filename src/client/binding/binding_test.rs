@@ -1,26 +1,35 @@
 use super::*;
 
+use crate::error::{BindingError, Error};
 use std::net::{Ipv4Addr, SocketAddrV4};
-use util::Error;
 
 #[test]
 fn test_binding_manager_number_assignment() -> Result<(), Error> {
     let mut m = BindingManager::new();
     let mut n: u16;
     for i in 0..10 {
-        n = m.assign_channel_number();
+        n = m.assign_channel_number()?;
         assert_eq!(MIN_CHANNEL_NUMBER + i, n, "should match");
     }
 
     m.next = 0x7ff0;
     for i in 0..16 {
-        n = m.assign_channel_number();
+        n = m.assign_channel_number()?;
         assert_eq!(0x7ff0 + i, n, "should match");
     }
     // back to min
-    n = m.assign_channel_number();
+    n = m.assign_channel_number()?;
     assert_eq!(MIN_CHANNEL_NUMBER, n, "should match");
 
+    // numbers still bound are skipped once wrapped around
+    let lo = Ipv4Addr::new(127, 0, 0, 1);
+    m.next = MAX_CHANNEL_NUMBER;
+    m.create(SocketAddr::V4(SocketAddrV4::new(lo, 1)), MAX_BINDINGS)?;
+    m.create(SocketAddr::V4(SocketAddrV4::new(lo, 2)), MAX_BINDINGS)?;
+    m.next = MAX_CHANNEL_NUMBER;
+    n = m.assign_channel_number()?;
+    assert_eq!(MIN_CHANNEL_NUMBER + 1, n, "should skip bound numbers");
+
     Ok(())
 }
 
@@ -31,10 +40,7 @@ fn test_binding_manager_method() -> Result<(), Error> {
     let mut m = BindingManager::new();
     for i in 0..count {
         let addr = SocketAddr::V4(SocketAddrV4::new(lo, 10000 + i));
-        let b0 = {
-            let b = m.create(addr);
-            b.unwrap().clone()
-        };
+        let b0 = *m.create(addr, MAX_BINDINGS)?;
         let b1 = m.find_by_addr(&addr);
         assert!(b1.is_some(), "should succeed");
         let b2 = m.find_by_number(b0.number);
@@ -81,3 +87,98 @@ fn test_binding_manager_failure() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_binding_manager_create_errors() -> Result<(), Error> {
+    let lo = Ipv4Addr::new(127, 0, 0, 1);
+    let addr = SocketAddr::V4(SocketAddrV4::new(lo, 7777));
+    let mut m = BindingManager::new();
+
+    m.create(addr, 2)?;
+    let err = m.create(addr, 2).unwrap_err();
+    assert_eq!(BindingError::AlreadyExists(addr), err);
+    assert_eq!(
+        "turn: binding for 127.0.0.1:7777 already exists",
+        err.to_string()
+    );
+
+    m.create(SocketAddr::V4(SocketAddrV4::new(lo, 7778)), 2)?;
+    let err = m
+        .create(SocketAddr::V4(SocketAddrV4::new(lo, 7779)), 2)
+        .unwrap_err();
+    assert_eq!(BindingError::LimitReached(2), err);
+    assert_eq!("turn: max_bindings of 2 reached", err.to_string());
+    assert_eq!(2, m.size());
+
+    Ok(())
+}
+
+#[test]
+fn test_binding_manager_exhausted() -> Result<(), Error> {
+    let mut m = BindingManager::new();
+    for i in 0..MAX_BINDINGS {
+        let addr = SocketAddr::new(Ipv4Addr::from(0x7f00_0000 + i as u32).into(), 5000);
+        m.create(addr, usize::MAX)?;
+    }
+    assert_eq!(MAX_BINDINGS, m.size());
+
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000));
+    let err = Error::from(m.create(addr, usize::MAX).unwrap_err());
+    assert!(
+        matches!(err, Error::Binding(BindingError::Exhausted)),
+        "expected Exhausted, got {}",
+        err
+    );
+    assert_eq!("turn: all channel numbers are in use", err.to_string());
+
+    // a deleted binding frees its number
+    assert!(m.delete_by_number(0x5000));
+    assert_eq!(0x5000, m.create(addr, usize::MAX)?.number);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_binding_manager_seen_by_number() -> Result<(), Error> {
+    let mut m = BindingManager::new();
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000));
+    let number = m.create(addr, usize::MAX)?.number;
+    assert!(!m.find_by_number(number).unwrap().received_since_refresh());
+
+    tokio::time::advance(tokio::time::Duration::from_secs(1)).await;
+    assert_eq!(Some(addr), m.seen_by_number(number));
+    assert_eq!(None, m.seen_by_number(number + 1));
+    let info = m.find_by_number(number).unwrap().info();
+    assert_eq!(Some(Instant::now()), info.last_seen_at);
+    assert!(info.refreshed_at < Instant::now());
+    assert!(m.find_by_number(number).unwrap().received_since_refresh());
+
+    // a refresh after it starts the count again
+    tokio::time::advance(tokio::time::Duration::from_secs(1)).await;
+    m.get_by_number(number)
+        .unwrap()
+        .set_refreshed_at(Instant::now());
+    assert!(!m.find_by_number(number).unwrap().received_since_refresh());
+
+    Ok(())
+}
+
+#[test]
+fn test_binding_manager_v4_mapped_peer() -> Result<(), Error> {
+    let mut m = BindingManager::new();
+    let plain = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 5000));
+    let mapped = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().into(), 5000);
+
+    let number = m.create(mapped, MAX_BINDINGS)?.number;
+    assert_eq!(plain, m.find_by_addr(&plain).map(|b| b.addr).unwrap());
+    assert_eq!(Some(number), m.find_by_addr(&mapped).map(|b| b.number));
+    assert!(matches!(
+        m.create(plain, MAX_BINDINGS),
+        Err(BindingError::AlreadyExists(_))
+    ));
+
+    assert!(m.delete_by_addr(&plain));
+    assert_eq!(0, m.size());
+
+    Ok(())
+}
@@ -1,26 +1,22 @@
 use super::*;
-use crate::auth::*;
-use crate::relay::relay_static::*;
-use crate::server::{config::*, *};
+#[cfg(feature = "server")]
+use crate::{server::*, test_util::*};
 
-use std::net::IpAddr;
+use stun::error_code::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::net::UdpSocket;
 use tokio::time::Duration;
 
-use util::Error;
+use crate::error::Error;
 
 async fn create_listening_test_client(rto_in_ms: u16) -> Result<Client, Error> {
     let conn = UdpSocket::bind("0.0.0.0:0").await?;
 
     let c = Client::new(ClientConfig {
-        stun_serv_addr: String::new(),
-        turn_serv_addr: String::new(),
-        username: String::new(),
-        password: String::new(),
-        realm: String::new(),
         software: "TEST SOFTWARE".to_owned(),
         rto_in_ms,
-        conn: Arc::new(conn),
+        ..ClientConfig::new(Arc::new(conn))
     })
     .await?;
 
@@ -34,13 +30,8 @@ async fn create_listening_test_client_with_stun_serv() -> Result<Client, Error>
 
     let c = Client::new(ClientConfig {
         stun_serv_addr: "stun1.l.google.com:19302".to_owned(),
-        turn_serv_addr: String::new(),
-        username: String::new(),
-        password: String::new(),
-        realm: String::new(),
         software: "TEST SOFTWARE".to_owned(),
-        rto_in_ms: 0,
-        conn: Arc::new(conn),
+        ..ClientConfig::new(Arc::new(conn))
     })
     .await?;
 
@@ -82,7 +73,7 @@ async fn test_client_with_stun_send_binding_request_to_parallel() -> Result<(),
 
     tokio::spawn(async move {
         drop(stared_tx);
-        if let Ok(resp) = c2.send_binding_request_to(&to.to_string()).await {
+        if let Ok(resp) = c2.send_binding_request_to(to).await {
             log::debug!("mapped-addr: {}", resp);
         }
         drop(finished_tx);
@@ -90,7 +81,7 @@ async fn test_client_with_stun_send_binding_request_to_parallel() -> Result<(),
 
     let _ = started_rx.recv().await;
 
-    let resp = c1.send_binding_request_to(&to.to_string()).await?;
+    let resp = c1.send_binding_request_to(to).await?;
     log::debug!("mapped-addr: {}", resp);
 
     let _ = finished_rx.recv().await;
@@ -108,68 +99,334 @@ async fn test_client_with_stun_send_binding_request_to_timeout() -> Result<(), E
 
     let to = lookup_host(true, "127.0.0.1:9").await?;
 
-    let result = c.send_binding_request_to(&to.to_string()).await;
-    assert!(result.is_err(), "expected error, but got ok");
+    let result = c.send_binding_request_to(to).await;
+    assert!(
+        matches!(result, Err(Error::AllRetransmissionsFailed(_))),
+        "expected AllRetransmissionsFailed error"
+    );
 
     c.close().await?;
 
     Ok(())
 }
 
-struct TestAuthHandler;
-impl AuthHandler for TestAuthHandler {
-    fn auth_handle(
-        &self,
-        username: &str,
-        realm: &str,
-        _src_addr: SocketAddr,
-    ) -> Result<Vec<u8>, Error> {
-        Ok(generate_auth_key(username, realm, "pass"))
+// run_stun_responder answers Binding requests with the source address after
+// delay
+async fn run_stun_responder(conn: UdpSocket, delay: Duration) {
+    let mut buf = vec![0u8; 1500];
+    while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+        let mut req = Message::new();
+        req.raw = buf[..n].to_vec();
+        if req.decode().is_err() {
+            continue;
+        }
+
+        tokio::time::sleep(delay).await;
+        let mut res = Message::new();
+        if res
+            .build(&[
+                Box::new(req.transaction_id),
+                Box::new(BINDING_SUCCESS),
+                Box::new(XORMappedAddress {
+                    ip: from.ip(),
+                    port: from.port(),
+                }),
+            ])
+            .is_ok()
+        {
+            let _ = conn.send_to(&res.raw, from).await;
+        }
     }
 }
 
-// Create an allocation, and then delete all nonces
-// The subsequent Write on the allocation will cause a CreatePermission
-// which will be forced to handle a stale nonce response
 #[tokio::test]
-async fn test_client_nonce_expiration() -> Result<(), Error> {
-    // env_logger::init();
+async fn test_client_send_binding_request_to_other_servers() -> Result<(), Error> {
+    let c = create_listening_test_client(0).await?;
+    let client_port = c.client_internal.lock().await.conn.local_addr()?.port();
 
-    // here, it should use static port, like "0.0.0.0:3478",
-    // but, due to different test environment, let's fake it by using "0.0.0.0:0"
-    // to auto assign a "static" port
-    let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-    let server_port = conn.local_addr()?.port();
-
-    let server = Server::new(ServerConfig {
-        conn_configs: vec![ConnConfig {
-            conn,
-            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
-                relay_address: IpAddr::from_str("127.0.0.1")?,
-                address: "0.0.0.0".to_owned(),
-            }),
-        }],
-        realm: "webrtc.rs".to_owned(),
-        auth_handler: Arc::new(Box::new(TestAuthHandler {})),
-        channel_bind_timeout: Duration::from_secs(0),
-    })
-    .await?;
+    let slow = UdpSocket::bind("127.0.0.1:0").await?;
+    let slow_addr = slow.local_addr()?;
+    tokio::spawn(run_stun_responder(slow, Duration::from_millis(300)));
+    let fast = UdpSocket::bind("127.0.0.1:0").await?;
+    let fast_addr = fast.local_addr()?;
+    tokio::spawn(run_stun_responder(fast, Duration::from_secs(0)));
 
-    let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    // the slow server's transaction doesn't hold up the fast one
+    let c2 = c.clone();
+    let slow_result = tokio::spawn(async move { c2.send_binding_request_to(slow_addr).await });
+    tokio::time::sleep(Duration::from_millis(20)).await;
 
-    let client = Client::new(ClientConfig {
-        stun_serv_addr: format!("127.0.0.1:{}", server_port),
-        turn_serv_addr: format!("127.0.0.1:{}", server_port),
+    let start = tokio::time::Instant::now();
+    let fast_refl = c.send_binding_request_to(fast_addr).await?;
+    assert!(
+        start.elapsed() < Duration::from_millis(200),
+        "fast server answered after {:?}",
+        start.elapsed()
+    );
+    let slow_refl = slow_result
+        .await
+        .map_err(|err| Error::Other(err.to_string()))??;
+
+    for refl in &[fast_refl, slow_refl] {
+        assert_eq!(client_port, refl.port());
+    }
+    assert_eq!(fast_refl, slow_refl);
+    {
+        let ci = c.client_internal.lock().await;
+        let tm = ci.tr_map.lock().await;
+        assert_eq!(0, tm.size(), "should be no transaction left");
+    }
+
+    c.close().await?;
+
+    Ok(())
+}
+
+async fn create_listening_test_client_with_turn_serv(
+    turn_serv_addr: SocketAddr,
+) -> Result<Client, Error> {
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let c = Client::new(ClientConfig {
+        turn_serv_addr: turn_serv_addr.to_string(),
         username: "foo".to_owned(),
         password: "pass".to_owned(),
-        realm: String::new(),
-        software: String::new(),
-        rto_in_ms: 0,
-        conn,
+        ..ClientConfig::new(Arc::new(conn))
     })
     .await?;
 
-    client.listen().await?;
+    c.listen().await?;
+
+    Ok(c)
+}
+
+type RetryLog = Arc<std::sync::Mutex<Vec<(u16, Duration)>>>;
+
+fn recording_retry_hook() -> (RetryHook, RetryLog) {
+    let retries: RetryLog = Arc::new(std::sync::Mutex::new(vec![]));
+    let log = Arc::clone(&retries);
+    let hook: RetryHook = Arc::new(move |attempt, err: &Error, delay| {
+        assert!(matches!(err, Error::AllRetransmissionsFailed(_)));
+        log.lock().unwrap().push((attempt, delay));
+    });
+    (hook, retries)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_client_allocate_with_retry_backoff() -> Result<(), Error> {
+    // the server never answers
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let c = create_listening_test_client_with_turn_serv(server.local_addr()?).await?;
+
+    let mut policy = RetryPolicy {
+        max_attempts: 4,
+        backoff: Some(Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+            jitter: 0.0,
+            full_jitter: false,
+        }),
+        retry_timeouts: true,
+        retry_server_errors: true,
+    };
+    let (hook, retries) = recording_retry_hook();
+    let result = c.allocate_with_retry(policy, Some(hook)).await;
+    assert!(matches!(result, Err(Error::AllRetransmissionsFailed(_))));
+    assert_eq!(
+        vec![
+            (1, Duration::from_secs(1)),
+            (2, Duration::from_secs(2)),
+            (3, Duration::from_secs(3)),
+        ],
+        *retries.lock().unwrap()
+    );
+
+    // full jitter draws each wait from zero to the nominal delay
+    if let Some(backoff) = policy.backoff.as_mut() {
+        backoff.full_jitter = true;
+    }
+    let (hook, retries) = recording_retry_hook();
+    let result = c.allocate_with_retry(policy, Some(hook)).await;
+    assert!(matches!(result, Err(Error::AllRetransmissionsFailed(_))));
+    let retries = retries.lock().unwrap().clone();
+    assert_eq!(3, retries.len());
+    for (attempt, delay) in retries {
+        let nominal = Duration::from_secs(1 << (attempt - 1)).min(Duration::from_secs(3));
+        assert!(delay <= nominal, "retry {} waited {:?}", attempt, delay);
+    }
+
+    c.close().await?;
+
+    Ok(())
+}
+
+// run_unauthorized_responder answers every request with a 401 (Unauthorized)
+// and counts them
+async fn run_unauthorized_responder(conn: UdpSocket, requests: Arc<AtomicUsize>) {
+    let mut buf = vec![0u8; 1500];
+    while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+        let mut req = Message::new();
+        req.raw = buf[..n].to_vec();
+        if req.decode().is_err() {
+            continue;
+        }
+        requests.fetch_add(1, Ordering::SeqCst);
+
+        let mut res = Message::new();
+        if res
+            .build(&[
+                Box::new(req.transaction_id),
+                Box::new(MessageType::new(req.typ.method, CLASS_ERROR_RESPONSE)),
+                Box::new(ErrorCodeAttribute {
+                    code: CODE_UNAUTHORIZED,
+                    reason: vec![],
+                }),
+                Box::new(Realm::new(ATTR_REALM, "webrtc.rs".to_owned())),
+                Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+            ])
+            .is_ok()
+        {
+            let _ = conn.send_to(&res.raw, from).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_client_allocate_with_retry_aborts_on_unauthorized() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let requests = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(run_unauthorized_responder(server, Arc::clone(&requests)));
+    let c = create_listening_test_client_with_turn_serv(server_addr).await?;
+
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        backoff: Some(Backoff {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(60),
+            jitter: 0.0,
+            full_jitter: true,
+        }),
+        retry_timeouts: true,
+        retry_server_errors: true,
+    };
+    let (hook, retries) = recording_retry_hook();
+    let result = c.allocate_with_retry(policy, Some(hook)).await;
+    assert!(
+        matches!(result, Err(Error::Protocol { code: 401, .. })),
+        "expected 401 error"
+    );
+    assert!(retries.lock().unwrap().is_empty(), "401 was retried");
+    // the anonymous and the authenticated Allocate of a single try
+    assert_eq!(2, requests.load(Ordering::SeqCst));
+
+    c.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_client_allocate_with_retry_dropped() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let c = create_listening_test_client_with_turn_serv(server.local_addr()?).await?;
+
+    let policy = RetryPolicy {
+        retry_timeouts: true,
+        ..Default::default()
+    };
+    let result =
+        tokio::time::timeout(Duration::from_secs(1), c.allocate_with_retry(policy, None)).await;
+    assert!(result.is_err(), "the server never answers");
+
+    // the abandoned Allocate is no longer retransmitted
+    tokio::task::yield_now().await;
+    {
+        let ci = c.client_internal.lock().await;
+        let tm = ci.tr_map.lock().await;
+        assert_eq!(0, tm.size(), "should be no transaction left");
+    }
+
+    c.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_config_validate() -> Result<(), Error> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let config = |username: String, realm: String| ClientConfig {
+        username,
+        realm,
+        ..ClientConfig::new(Arc::clone(&conn))
+    };
+    let invalid_field = |result: Result<(), Error>| match result {
+        Err(Error::CredentialInvalid { field, .. }) => Some(field),
+        _ => None,
+    };
+
+    assert!(config(String::new(), String::new()).validate().is_ok());
+    assert!(config("u".repeat(513), "r".repeat(127)).validate().is_ok());
+    assert_eq!(
+        Some("username"),
+        invalid_field(config("u".repeat(514), String::new()).validate())
+    );
+    assert_eq!(
+        Some("username"),
+        invalid_field(config("us\0er".to_owned(), String::new()).validate())
+    );
+
+    // 127 characters of up to 4 bytes each stay under 763 bytes, the
+    // character count is the limit that matters
+    assert!(config(String::new(), "\u{1F600}".repeat(127))
+        .validate()
+        .is_ok());
+    assert_eq!(
+        Some("realm"),
+        invalid_field(config(String::new(), "r".repeat(128)).validate())
+    );
+    assert_eq!(
+        Some("realm"),
+        invalid_field(config(String::new(), "re\0alm".to_owned()).validate())
+    );
+
+    Ok(())
+}
+
+// A username that can't be sent fails Client::new, not the first
+// authenticated request
+#[tokio::test]
+async fn test_client_new_rejects_invalid_username() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+    let result = Client::new(ClientConfig {
+        turn_serv_addr: server.local_addr()?.to_string(),
+        username: "user\0name".to_owned(),
+        password: "pass".to_owned(),
+        ..ClientConfig::new(Arc::new(conn))
+    })
+    .await;
+
+    match result {
+        Err(err @ Error::CredentialInvalid { .. }) => {
+            assert_eq!("turn: username contains NUL", err.to_string());
+        }
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("invalid username accepted"),
+    }
+
+    Ok(())
+}
+
+// Create an allocation, and then delete all nonces
+// The subsequent Write on the allocation will cause a CreatePermission
+// which will be forced to handle a stale nonce response
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_nonce_expiration() -> Result<(), Error> {
+    // env_logger::init();
+
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
 
     let allocation = client.allocate().await?;
 
@@ -188,3 +445,1853 @@ async fn test_client_nonce_expiration() -> Result<(), Error> {
 
     Ok(())
 }
+
+// relay_round_trip sends from the allocation to peer and back
+#[cfg(feature = "server")]
+async fn relay_round_trip(
+    allocation: &impl Conn,
+    peer: &UdpSocket,
+    data: &[u8],
+) -> Result<(), Error> {
+    let relayed_addr = allocation.local_addr()?;
+    let mut buf = vec![0u8; 1500];
+
+    allocation.send_to(data, peer.local_addr()?).await?;
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("peer read timed out".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+    assert_eq!(relayed_addr.port(), from.port());
+
+    peer.send_to(data, relayed_addr).await?;
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), allocation.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("relay read timed out".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+    assert_eq!(peer.local_addr()?, from);
+
+    Ok(())
+}
+
+// Two clients of two servers tell their allocations apart by AllocationId
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocation_ids() -> Result<(), Error> {
+    let mut ids = vec![];
+    let mut closers = vec![];
+    for username in &["alice", "bob"] {
+        let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+            users: vec![(username.to_string(), TEST_PASSWORD.to_owned())],
+            ..Default::default()
+        })
+        .await?;
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let local_addr = conn.local_addr()?;
+        let client = Client::new(ClientConfig {
+            username: username.to_string(),
+            ..client_config(server_addr, conn)
+        })
+        .await?;
+        client.listen().await?;
+
+        let allocation = client.allocate().await?;
+        let id = allocation.allocation_id().await;
+        assert_eq!(server_addr.to_string(), id.turn_server_addr);
+        assert_eq!(Some(local_addr), id.local_addr);
+        assert_eq!(*username, id.username);
+        assert_eq!(allocation.local_addr()?, id.relayed_addr);
+        ids.push(id);
+        closers.push((server, client, allocation));
+    }
+    assert_ne!(ids[0], ids[1]);
+
+    for (server, client, mut allocation) in closers {
+        allocation.close().await?;
+        // on_deallocated matched the id, the client can allocate again
+        let mut allocation = client.allocate().await?;
+        allocation.close().await?;
+        client.close().await?;
+        server.close()?;
+    }
+
+    Ok(())
+}
+
+// A UDP allocation reports the server, the transport and the local socket it
+// goes through
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocation_path() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr()?;
+    let client = Client::new(client_config(server_addr, conn)).await?;
+    client.listen().await?;
+
+    let mut allocation = client.allocate().await?;
+    assert_eq!(Some(server_addr), allocation.server_addr());
+    assert_eq!(TransportKind::Udp, allocation.transport());
+    assert_eq!(Some(local_addr), allocation.underlying_local_addr());
+    assert_ne!(local_addr, allocation.local_addr()?);
+    assert_eq!(
+        TransportKind::Udp,
+        allocation.allocation_id().await.transport
+    );
+
+    allocation.close().await?;
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// Release the allocation and allocate again on the same client and socket
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocate_after_close() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut allocation = client.allocate().await?;
+    let first_addr = allocation.local_addr()?;
+    relay_round_trip(&allocation, &peer, b"first").await?;
+    assert!(
+        matches!(client.allocate().await, Err(Error::OneAllocateOnly)),
+        "expected OneAllocateOnly while the first allocation is open"
+    );
+
+    allocation.close().await?;
+
+    let mut allocation = client.allocate().await?;
+    assert_ne!(first_addr, allocation.local_addr()?);
+    relay_round_trip(&allocation, &peer, b"second").await?;
+
+    allocation.close().await?;
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// Probe echo peers through the allocation, the replies aren't read by
+// recv_from but data from other peers is
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_probe_peer() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+    let allocation = client.allocate().await?;
+
+    let timeout = Duration::from_secs(5);
+    let (peer1, peer2) = (spawn_udp_echo_peer().await?, spawn_udp_echo_peer().await?);
+    let (rtt1, rtt2) = tokio::join!(
+        allocation.probe_peer(peer1, b"probe", timeout),
+        allocation.probe_peer(peer2, b"probe", timeout),
+    );
+    for rtt in [rtt1?, rtt2?] {
+        assert!(rtt < timeout, "rtt {:?}", rtt);
+    }
+
+    // a silent peer times out, meanwhile another peer's data is queued
+    let silent = UdpSocket::bind("127.0.0.1:0").await?;
+    let other = UdpSocket::bind("127.0.0.1:0").await?;
+    allocation.send_to(b"hello", other.local_addr()?).await?;
+    let (probed, _) = tokio::join!(
+        allocation.probe_peer(silent.local_addr()?, b"probe", Duration::from_millis(300)),
+        other.send_to(b"unrelated", allocation.local_addr()?),
+    );
+    assert!(
+        matches!(probed, Err(Error::ProbeTimedOut(peer)) if peer == silent.local_addr()?),
+        "unexpected {:?}",
+        probed
+    );
+
+    let mut buf = vec![0u8; 1500];
+    let (n, from) = tokio::time::timeout(timeout, allocation.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("relay read timed out".to_owned()))??;
+    assert_eq!((&b"unrelated"[..], other.local_addr()?), (&buf[..n], from));
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// A burst of ChannelData is written in batches through a BatchConn
+#[cfg(feature = "batch")]
+#[tokio::test]
+async fn test_client_send_batching() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let conn = Arc::new(crate::batch::BatchConn::new(
+        UdpSocket::bind("127.0.0.1:0").await?,
+        0,
+    ));
+    let client = Client::new(ClientConfig {
+        send_batching: Some(send_batch::SendBatching {
+            batch_conn: Some(Arc::clone(&conn) as Arc<dyn send_batch::BatchSend + Send + Sync>),
+            ..Default::default()
+        }),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let allocation = client.allocate().await?;
+    let mut events = allocation.events();
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    relay_round_trip(&allocation, &peer, b"bind").await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(RelayConnEvent::BindingStateChanged {
+                new: BindingState::Ready,
+                ..
+            }) = events.recv().await
+            {
+                break;
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("channel not bound".to_owned()))?;
+
+    for i in 0..20u8 {
+        allocation.send_to(&[i], peer.local_addr()?).await?;
+    }
+    let mut buf = vec![0u8; 1500];
+    for i in 0..20u8 {
+        let (n, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+            .await
+            .map_err(|_| Error::Other("peer read timed out".to_owned()))??;
+        assert_eq!(&[i], &buf[..n]);
+    }
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// Three allocations from one pool, only the first is challenged with a 401
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_pool_allocations() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let pool = pool::ClientPool::new(pool::ClientPoolConfig {
+        turn_serv_addr: server_addr.to_string(),
+        username: TEST_USERNAME.to_owned(),
+        password: TEST_PASSWORD.to_owned(),
+        ..Default::default()
+    });
+
+    let mut allocations = vec![];
+    for _ in 0..3 {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        allocations.push(pool.allocate_on(conn).await?);
+    }
+
+    // one anonymous Allocate and three authenticated ones
+    let (_, metrics) = server.listener_metrics()[0];
+    assert_eq!(4, metrics.packets_in);
+    assert_eq!(3, metrics.allocations_created);
+
+    let mut relayed_addrs = vec![];
+    for allocation in &allocations {
+        relayed_addrs.push(allocation.local_addr()?);
+    }
+    relayed_addrs.sort();
+    relayed_addrs.dedup();
+    assert_eq!(3, relayed_addrs.len());
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    for allocation in &allocations {
+        relay_round_trip(allocation, &peer, b"pooled").await?;
+    }
+
+    // the zero lifetime Refreshes aren't waited for
+    pool.close().await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.snapshot().await.allocations > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("allocations not deleted".to_owned()))?;
+
+    server.close()?;
+
+    Ok(())
+}
+
+// A client prearmed with the auth state of another allocates with a single
+// request, a stale nonce costs one more
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_prearmed_auth() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let packets_in = || server.listener_metrics()[0].1.packets_in;
+
+    let allocate = |prearmed_auth: Option<PrearmedAuth>| async move {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let client = Client::new(ClientConfig {
+            prearmed_auth,
+            ..client_config(server_addr, conn)
+        })
+        .await?;
+        client.listen().await?;
+        let allocation = client.allocate().await?;
+        Ok::<_, Error>((client, allocation))
+    };
+
+    let (first, _first_allocation) = allocate(None).await?;
+    assert_eq!(2, packets_in(), "the first allocation should be challenged");
+    let auth = first
+        .export_auth_state()
+        .await
+        .ok_or_else(|| Error::Other("no auth state after allocating".to_owned()))?;
+    assert_eq!(TEST_REALM, auth.realm);
+    assert!(!format!("{:?}", auth).contains(&format!("{:?}", auth.integrity_key)));
+
+    let (second, _second_allocation) = allocate(Some(auth.clone())).await?;
+    assert_eq!(
+        3,
+        packets_in(),
+        "a prearmed allocation should take one request"
+    );
+    assert_eq!(Some(auth.clone()), second.export_auth_state().await);
+
+    let stale = PrearmedAuth {
+        nonce: "stale".to_owned(),
+        ..auth.clone()
+    };
+    let (third, _third_allocation) = allocate(Some(stale)).await?;
+    assert_eq!(5, packets_in(), "a stale nonce should be replaced once");
+    let refreshed = third.export_auth_state().await.unwrap();
+    assert_ne!("stale", refreshed.nonce);
+    assert_eq!(auth.integrity_key, refreshed.integrity_key);
+
+    for client in [first, second, third] {
+        client.close().await?;
+    }
+    server.close()?;
+
+    Ok(())
+}
+
+// A TCP allocation on a server that only relays UDP fails with 442, or is
+// downgraded to a UDP one when falling back is allowed
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocate_tcp_fallback_to_udp() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let strict = Client::new(client_config(server_addr, conn)).await?;
+    strict.listen().await?;
+    let result = strict.allocate_tcp(TcpAllocateParams::default()).await;
+    match result {
+        Err(err @ Error::UnsupportedTransportProtocol(PROTO_TCP)) => {
+            assert_eq!(std::io::ErrorKind::Unsupported, err.io_kind());
+        }
+        result => panic!("expected 442, got {:?}", result.err()),
+    }
+    strict.close().await?;
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(client_config(server_addr, conn)).await?;
+    client.listen().await?;
+    let packets_in = server.listener_metrics()[0].1.packets_in;
+    let allocation = client
+        .allocate_tcp(TcpAllocateParams {
+            fallback_to_udp: true,
+        })
+        .await?;
+    assert!(allocation.downgraded);
+    assert_eq!(PROTO_UDP, allocation.transport);
+    // the UDP Allocate reuses the nonce of the rejected TCP one
+    assert_eq!(3, server.listener_metrics()[0].1.packets_in - packets_in);
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    relay_round_trip(&allocation.relay_conn, &peer, b"downgraded").await?;
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// sent_request_methods are the methods of the STUN requests among packets
+fn sent_request_methods(packets: &[Vec<u8>]) -> Vec<Method> {
+    packets
+        .iter()
+        .filter_map(|raw| {
+            let mut msg = Message::new();
+            msg.raw = raw.clone();
+            msg.decode().ok()?;
+            (msg.typ.class == CLASS_REQUEST).then_some(msg.typ.method)
+        })
+        .collect()
+}
+
+// The peers given to allocate_with_peers are permitted before it returns,
+// sending to them takes no CreatePermission and the first one's data goes
+// over its channel right away
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocate_with_peers() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let on_send_raw: RawPacketHook = {
+        let sent = Arc::clone(&sent);
+        Arc::new(move |data: &[u8], _to: SocketAddr| sent.lock().unwrap().push(data.to_vec()))
+    };
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        on_send_raw: Some(on_send_raw),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let peers = [
+        UdpSocket::bind("127.0.0.1:0").await?,
+        UdpSocket::bind("127.0.0.2:0").await?,
+    ];
+    let peer_addrs = [peers[0].local_addr()?, peers[1].local_addr()?];
+    let allocation = client
+        .allocate_with_peers(
+            &peer_addrs,
+            PeerAllocateParams {
+                bind_first: true,
+                require_permissions: true,
+            },
+        )
+        .await?;
+    assert!(allocation.permission_error.is_none());
+    assert_eq!(
+        vec![
+            METHOD_ALLOCATE,
+            METHOD_ALLOCATE,
+            METHOD_CREATE_PERMISSION,
+            METHOD_CHANNEL_BIND
+        ],
+        sent_request_methods(&sent.lock().unwrap())
+    );
+    let returned_at = sent.lock().unwrap().len();
+
+    for peer in &peers {
+        relay_round_trip(&allocation.relay_conn, peer, b"no wait").await?;
+    }
+
+    let after = sent.lock().unwrap()[returned_at..].to_vec();
+    assert!(ChannelData::is_channel_data(&after[0]));
+    assert!(!sent_request_methods(&after).contains(&METHOD_CREATE_PERMISSION));
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// CreatePermissionBlocker rejects every CreatePermission request
+#[cfg(feature = "server")]
+struct CreatePermissionBlocker;
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for CreatePermissionBlocker {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        if msg.typ.method == METHOD_CREATE_PERMISSION {
+            interceptor::InterceptDecision::Reject {
+                code: CODE_FORBIDDEN,
+                reason: "peers are not allowed".to_owned(),
+            }
+        } else {
+            interceptor::InterceptDecision::Continue
+        }
+    }
+}
+
+// A failed CreatePermission is returned with the allocation, or fails it when
+// the permissions are required
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_allocate_with_peers_permission_failure() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.interceptor(Box::new(CreatePermissionBlocker))
+        })),
+        ..Default::default()
+    })
+    .await?;
+    let peer = SocketAddr::from_str("127.0.0.1:5000")?;
+
+    let client = start_client(server_addr).await?;
+    let allocation = client
+        .allocate_with_peers(&[peer], PeerAllocateParams::default())
+        .await?;
+    assert!(
+        matches!(
+            allocation.permission_error,
+            Some(Error::Protocol { code: 403, .. })
+        ),
+        "{:?}",
+        allocation.permission_error
+    );
+    allocation.relay_conn.local_addr()?;
+    assert_eq!(1, server.snapshot().await.allocations);
+
+    let strict = start_client(server_addr).await?;
+    let result = strict
+        .allocate_with_peers(
+            &[peer],
+            PeerAllocateParams {
+                bind_first: false,
+                require_permissions: true,
+            },
+        )
+        .await;
+    assert!(result.is_err());
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.snapshot().await.allocations > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("allocation not deallocated".to_owned()))?;
+
+    client.close().await?;
+    strict.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// Data indications without a usable XOR-PEER-ADDRESS or DATA, and ChannelData
+// on an unbound channel, are dropped and counted, the read loop goes on after
+// them and after a STUN request
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_drops_malformed_data_indications() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client_addr = conn.local_addr()?;
+    let proxy = RestartProxy::start(client_addr, server_addr).await?;
+    let client = Client::new(client_config(proxy.addr, conn)).await?;
+    client.listen().await?;
+    let allocation = client.allocate().await?;
+
+    let peer = SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), 6000);
+    let indication = |attrs: Vec<Box<dyn Setter>>| -> Result<Vec<u8>, Error> {
+        let mut setters: Vec<Box<dyn Setter>> = vec![
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_DATA, CLASS_INDICATION)),
+        ];
+        setters.extend(attrs);
+        let mut msg = Message::new();
+        msg.build(&setters)?;
+        Ok(msg.raw)
+    };
+    let malformed = vec![
+        indication(vec![Box::new(Data(b"no peer".to_vec()))])?,
+        indication(vec![Box::new(PeerAddress {
+            ip: peer.ip(),
+            port: peer.port(),
+        })])?,
+        indication(vec![
+            Box::new(PeerAddress::default()),
+            Box::new(Data(b"unspecified peer".to_vec())),
+        ])?,
+        vec![0x4f, 0xff, 0x00, 0x04, b'l', b'o', b's', b't'],
+    ];
+    let mut request = Message::new();
+    request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])?;
+    let valid = indication(vec![
+        Box::new(PeerAddress {
+            ip: peer.ip(),
+            port: peer.port(),
+        }),
+        Box::new(Data(b"valid".to_vec())),
+    ])?;
+
+    // an indication off the server's path is dropped whatever it carries
+    let off_path = UdpSocket::bind("127.0.0.1:0").await?;
+    let spoofed = indication(vec![
+        Box::new(PeerAddress {
+            ip: peer.ip(),
+            port: peer.port(),
+        }),
+        Box::new(Data(b"spoofed".to_vec())),
+    ])?;
+    off_path.send_to(&spoofed, client_addr).await?;
+
+    // the middlebox is on the server's path, its indications come from the
+    // server's address
+    let middlebox = UdpSocket::bind("127.0.0.1:0").await?;
+    proxy.restart(middlebox.local_addr()?);
+    for packet in &malformed {
+        middlebox.send_to(packet, proxy.addr).await?;
+    }
+    middlebox.send_to(&request.raw, proxy.addr).await?;
+    middlebox.send_to(&valid, proxy.addr).await?;
+
+    let mut buf = [0u8; 32];
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), allocation.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("valid indication not received".to_owned()))??;
+    assert_eq!((&b"valid"[..], peer), (&buf[..n], from));
+    assert_eq!(4, allocation.inbound_malformed());
+    assert_eq!(1, client.misdirected_dropped());
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// The zero lifetime Refresh to a server that went silent is retransmitted
+// within its deadline only, not for the client's whole retransmission budget
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_deallocate_transaction_deadline() -> Result<(), Error> {
+    let silent = UdpSocket::bind("127.0.0.1:0").await?;
+    let silent_addr = silent.local_addr()?;
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        stun_serv_addr: String::new(),
+        ..client_config(silent_addr, conn)
+    })
+    .await?;
+
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+        Box::new(Lifetime(Duration::from_secs(0))),
+    ])?;
+    let start = tokio::time::Instant::now();
+    {
+        let mut ci = client.client_internal.lock().await;
+        ci.perform_transaction_with(
+            &msg,
+            &silent_addr.to_string(),
+            true,
+            relay_conn::DEALLOCATE_TRANSACTION,
+        )
+        .await?;
+    }
+
+    // the requests at 0, 200 and 600 ms, the next would be at 1400 ms
+    let mut requests = 0;
+    let mut buf = [0u8; 1500];
+    while let Ok(received) =
+        tokio::time::timeout(Duration::from_millis(1500), silent.recv_from(&mut buf)).await
+    {
+        received?;
+        requests += 1;
+    }
+    assert_eq!(3, requests);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        0,
+        client
+            .client_internal
+            .lock()
+            .await
+            .tr_map
+            .lock()
+            .await
+            .size()
+    );
+
+    client.close().await?;
+
+    Ok(())
+}
+
+// A request reusing the id of a pending transaction is signed again under a
+// new id, each waiter gets the response to its own request. A response of
+// another method under a transaction's id isn't its result.
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_transaction_id_collision() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let integrity = MessageIntegrity::new_short_term_integrity("pass".to_owned());
+    let server_integrity = integrity.clone();
+    let requests = tokio::spawn(async move {
+        let mut ids = vec![];
+        let mut buf = [0u8; 1500];
+        while ids.len() < 2 {
+            let (n, from) = server.recv_from(&mut buf).await?;
+            let mut req = Message::new();
+            req.raw = buf[..n].to_vec();
+            req.decode()?;
+            server_integrity.check(&mut req)?;
+            FINGERPRINT.check(&req)?;
+            if ids.contains(&req.transaction_id) {
+                continue;
+            }
+            ids.push(req.transaction_id);
+
+            for method in [METHOD_BINDING, req.typ.method] {
+                let mut res = Message::new();
+                res.build(&[
+                    Box::new(req.transaction_id),
+                    Box::new(MessageType::new(method, CLASS_SUCCESS_RESPONSE)),
+                ])?;
+                server.send_to(&res.raw, from).await?;
+            }
+        }
+        Ok::<_, Error>(ids)
+    });
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        rto_in_ms: 50,
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+        Box::new(Lifetime(Duration::from_secs(600))),
+        Box::new(integrity.clone()),
+        Box::new(FINGERPRINT),
+    ])?;
+    let (first, second) = {
+        let mut ci = client.client_internal.lock().await;
+        ci.integrity = integrity;
+        let to = server_addr.to_string();
+        (
+            ci.begin_transaction(&msg, &to).await?,
+            ci.begin_transaction(&msg, &to).await?,
+        )
+    };
+    let (first, second) = (first.await?, second.await?);
+
+    let ids = requests
+        .await
+        .map_err(|err| Error::Other(err.to_string()))??;
+    assert_eq!(vec![msg.transaction_id, second.msg.transaction_id], ids);
+    assert_eq!(msg.transaction_id, first.msg.transaction_id);
+    assert_ne!(msg.transaction_id, second.msg.transaction_id);
+    for res in [&first.msg, &second.msg] {
+        assert_eq!(
+            MessageType::new(METHOD_REFRESH, CLASS_SUCCESS_RESPONSE),
+            res.typ
+        );
+    }
+
+    client.close().await?;
+
+    Ok(())
+}
+
+// ChannelBindDropper leaves every ChannelBind request unanswered
+#[cfg(feature = "server")]
+struct ChannelBindDropper;
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for ChannelBindDropper {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        if msg.typ.method == METHOD_CHANNEL_BIND {
+            interceptor::InterceptDecision::Drop
+        } else {
+            interceptor::InterceptDecision::Continue
+        }
+    }
+}
+
+// create_test_server_and_client starts a server on 127.0.0.1 and a listening
+// client of it
+#[cfg(feature = "server")]
+async fn create_test_server_and_client(
+    interceptor: Option<Box<dyn interceptor::RequestInterceptor + Send + Sync>>,
+) -> Result<(ServerHandle, Client), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: interceptor.map(|interceptor| -> ConfigureServer {
+            Box::new(move |builder| builder.interceptor(interceptor))
+        }),
+        ..Default::default()
+    })
+    .await?;
+    let client = start_client(server_addr).await?;
+
+    Ok((server, client))
+}
+
+// The client and server share one thread. The clock is paused while waiting
+// for the refresh, it then advances to the next timer whenever every task
+// waits. Packets in flight don't stop it, so it runs while relaying.
+#[cfg(feature = "server")]
+#[tokio::test(flavor = "current_thread")]
+async fn test_client_current_thread_runtime() -> Result<(), Error> {
+    let (server, client) = create_test_server_and_client(None).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut allocation = client.allocate().await?;
+    let mut ttl = allocation.ttl_watch();
+    let allocated = ttl.borrow().expires_at;
+
+    // the first write goes as a Send indication and binds a channel
+    relay_round_trip(&allocation, &peer, b"indication").await?;
+    let mut bound = false;
+    for _ in 0..50 {
+        let snapshot = server.snapshot().await;
+        bound = snapshot
+            .listeners
+            .iter()
+            .flat_map(|l| &l.allocations)
+            .any(|a| !a.channel_binds.is_empty());
+        if bound {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(bound, "channel should be bound");
+    relay_round_trip(&allocation, &peer, b"channel").await?;
+
+    // the allocation is refreshed at half its lifetime, the permission every
+    // two minutes, and the channel on the next write
+    tokio::time::pause();
+    tokio::time::timeout(DEFAULT_LIFETIME, ttl.changed())
+        .await
+        .map_err(|_| Error::Other("allocation not refreshed".to_owned()))?
+        .map_err(|_| Error::Other("ttl watch closed".to_owned()))?;
+    tokio::time::resume();
+    let refreshed = *ttl.borrow();
+    assert!(refreshed.last_refresh_ok);
+    assert!(refreshed.expires_at > allocated);
+    relay_round_trip(&allocation, &peer, b"refreshed").await?;
+
+    allocation.close().await?;
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// A ChannelBind waiting for its response must not hold up writes, which go as
+// Send indications meanwhile
+#[cfg(feature = "server")]
+#[tokio::test(flavor = "current_thread")]
+async fn test_client_send_during_channel_bind() -> Result<(), Error> {
+    let (server, client) =
+        create_test_server_and_client(Some(Box::new(ChannelBindDropper))).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let allocation = client.allocate().await?;
+    relay_round_trip(&allocation, &peer, b"first").await?;
+
+    // the ChannelBind goes unanswered until its retransmissions run out
+    let start = tokio::time::Instant::now();
+    relay_round_trip(&allocation, &peer, b"second").await?;
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "write waited {:?} for the ChannelBind",
+        start.elapsed()
+    );
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_raw_packet_hooks() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let received = Arc::new(std::sync::Mutex::new(vec![]));
+    let (sent2, received2) = (Arc::clone(&sent), Arc::clone(&received));
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        on_send_raw: Some(Arc::new(move |data: &[u8], to: SocketAddr| {
+            sent2.lock().unwrap().push((data.to_vec(), to));
+        })),
+        on_recv_raw: Some(Arc::new(move |data: &[u8], from: SocketAddr| {
+            received2.lock().unwrap().push((data.to_vec(), from));
+        })),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+
+    client.listen().await?;
+    let _allocation = client.allocate().await?;
+
+    client.close().await?;
+    server.close()?;
+
+    let parse = |packets: &[(Vec<u8>, SocketAddr)]| -> Result<Vec<MessageType>, Error> {
+        let mut types = vec![];
+        for (data, addr) in packets {
+            assert_eq!(server_addr, *addr, "unexpected remote address");
+            let mut msg = Message::new();
+            msg.raw = data.clone();
+            msg.decode()?;
+            types.push(msg.typ);
+        }
+        Ok(types)
+    };
+
+    let sent_types = parse(&sent.lock().unwrap())?;
+    assert!(
+        sent_types.contains(&MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        "ALLOCATE request should be captured, got {:?}",
+        sent_types
+    );
+
+    let received_types = parse(&received.lock().unwrap())?;
+    assert!(
+        received_types.contains(&MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE)),
+        "ALLOCATE response should be captured, got {:?}",
+        received_types
+    );
+
+    Ok(())
+}
+
+#[cfg(all(feature = "trace", feature = "server"))]
+#[derive(Clone, Default)]
+struct TraceBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(all(feature = "trace", feature = "server"))]
+impl std::io::Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "trace", feature = "server"))]
+#[tokio::test]
+async fn test_client_trace_spans() -> Result<(), Error> {
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let buffer = TraceBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(FmtSpan::NEW)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+
+    let allocation = client.allocate().await?;
+    allocation
+        .send_to(&[0x00], SocketAddr::from_str("127.0.0.1:8080")?)
+        .await?;
+
+    client.close().await?;
+    server.close()?;
+
+    let output = String::from_utf8_lossy(&buffer.0.lock().unwrap()).into_owned();
+    for name in &["stun_transaction", "allocation"] {
+        assert!(
+            output.contains(&format!("{}{{", name)),
+            "span {} missing from trace output:\n{}",
+            name,
+            output
+        );
+    }
+    assert!(
+        output.contains("username=user"),
+        "allocation span should carry username"
+    );
+    assert!(
+        !output.contains("secret"),
+        "trace output must not contain the password"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_config_debug_redacts_password() -> Result<(), Error> {
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let config = ClientConfig {
+        stun_serv_addr: "127.0.0.1:3478".to_owned(),
+        turn_serv_addr: "127.0.0.1:3478".to_owned(),
+        username: "user".to_owned(),
+        password: "s3cr3t-passw0rd".to_owned(),
+        realm: "webrtc.rs".to_owned(),
+        ..ClientConfig::new(Arc::new(conn))
+    };
+
+    let out = format!("{:?}", config);
+    assert!(out.contains("webrtc.rs"), "{}", out);
+    assert!(out.contains("user"), "{}", out);
+    assert!(out.contains("<redacted>"), "{}", out);
+    assert!(!out.contains("s3cr3t-passw0rd"), "{}", out);
+
+    Ok(())
+}
+
+// RestartProxy stands in for a TURN server that restarts: the client talks to
+// its socket, which relays to the current backend, and switching backends
+// loses the allocation like a restart
+#[cfg(feature = "server")]
+struct RestartProxy {
+    addr: SocketAddr,
+    backend: Arc<std::sync::Mutex<SocketAddr>>,
+}
+
+#[cfg(feature = "server")]
+impl RestartProxy {
+    async fn start(client_addr: SocketAddr, backend: SocketAddr) -> Result<Self, Error> {
+        let conn = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = conn.local_addr()?;
+        let backend = Arc::new(std::sync::Mutex::new(backend));
+        let current = Arc::clone(&backend);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+                let backend = *current.lock().unwrap();
+                // the old backend is dead to the client
+                let to = if from == client_addr {
+                    backend
+                } else if from == backend {
+                    client_addr
+                } else {
+                    continue;
+                };
+                let _ = conn.send_to(&buf[..n], to).await;
+            }
+        });
+        Ok(RestartProxy { addr, backend })
+    }
+
+    fn restart(&self, backend: SocketAddr) {
+        *self.backend.lock().unwrap() = backend;
+    }
+}
+
+// ChannelBindBlocker rejects every ChannelBind request
+#[cfg(feature = "server")]
+struct ChannelBindBlocker;
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for ChannelBindBlocker {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        if msg.typ.method == METHOD_CHANNEL_BIND {
+            interceptor::InterceptDecision::Reject {
+                code: CODE_FORBIDDEN,
+                reason: "channels are not allowed".to_owned(),
+            }
+        } else {
+            interceptor::InterceptDecision::Continue
+        }
+    }
+}
+
+// ChannelBindDelayer holds ChannelBind requests before the server answers them
+#[cfg(feature = "server")]
+struct ChannelBindDelayer(Duration);
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for ChannelBindDelayer {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        if msg.typ.method == METHOD_CHANNEL_BIND {
+            tokio::time::sleep(self.0).await;
+        }
+        interceptor::InterceptDecision::Continue
+    }
+}
+
+// relay_through sends data to peer and back through relay_conn, returning the
+// relayed address the peer saw it from
+#[cfg(feature = "server")]
+async fn relay_through<T: RelayConnObserver + Send + Sync>(
+    relay_conn: &RelayConn<T>,
+    peer: &UdpSocket,
+    data: &[u8],
+) -> Result<SocketAddr, Error> {
+    let mut buf = vec![0u8; 1500];
+    relay_conn.send_to(data, peer.local_addr()?).await?;
+    let (n, relayed_addr) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("peer received nothing".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+
+    peer.send_to(data, relayed_addr).await?;
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), relay_conn.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("client received nothing".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+    assert_eq!(peer.local_addr()?, from);
+    Ok(relayed_addr)
+}
+
+// bound_under_client allocates through a RestartProxy with auto_reallocate
+// and relays to two peers until both are bound. It returns the bindings, the
+// server is closed once its handle is dropped.
+#[cfg(feature = "server")]
+#[allow(clippy::type_complexity)]
+async fn bound_under_client() -> Result<
+    (
+        ServerHandle,
+        RestartProxy,
+        Client,
+        RelayConn<impl RelayConnObserver + Send + Sync>,
+        Vec<UdpSocket>,
+        Vec<BindingInfo>,
+    ),
+    Error,
+> {
+    let (old_server, old_server_addr) =
+        TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let proxy = RestartProxy::start(conn.local_addr()?, old_server_addr).await?;
+    let client = Client::new(ClientConfig {
+        auto_reallocate: true,
+        ..client_config(proxy.addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+    let relay_conn = client.allocate().await?;
+
+    let peers = vec![
+        UdpSocket::bind("127.0.0.1:0").await?,
+        UdpSocket::bind("127.0.0.1:0").await?,
+    ];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            for peer in &peers {
+                relay_through(&relay_conn, peer, b"before").await?;
+            }
+            let bindings = relay_conn.bindings().await;
+            if bindings.len() == 2 && bindings.iter().all(|b| b.state == BindingState::Ready) {
+                return Ok::<_, Error>(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("peers not bound".to_owned()))??;
+    let bound = relay_conn.bindings().await;
+    Ok((old_server, proxy, client, relay_conn, peers, bound))
+}
+
+// restart_under_client binds two peers with bound_under_client, then restarts
+// the server as new_server_addr and lets the permission refresh timer fire. It
+// returns the events emitted by then.
+#[cfg(feature = "server")]
+async fn restart_under_client(
+    new_server_addr: SocketAddr,
+) -> Result<
+    (
+        Client,
+        RelayConn<impl RelayConnObserver + Send + Sync>,
+        Vec<UdpSocket>,
+        Vec<BindingInfo>,
+        Vec<RelayConnEvent>,
+    ),
+    Error,
+> {
+    use periodic_timer::PeriodicTimerTimeoutHandler;
+
+    let (_old_server, proxy, client, relay_conn, peers, bound) = bound_under_client().await?;
+    let mut events = relay_conn.events();
+    proxy.restart(new_server_addr);
+    relay_conn
+        .refresher()
+        .on_timeout(periodic_timer::TimerIdRefresh::Perms)
+        .await;
+
+    let mut emitted = vec![];
+    while let Ok(event) = events.try_recv() {
+        emitted.push(event);
+    }
+    Ok((client, relay_conn, peers, bound, emitted))
+}
+
+// A restarted server answers the permission refresh with 437, the client
+// allocates again and restores the permission, then both channels with their
+// numbers, without the application doing anything
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_auto_reallocate_restores_peers() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let (client, relay_conn, peers, bound, events) = restart_under_client(server_addr).await?;
+
+    let new_relayed_addr = relay_conn.local_addr()?;
+    assert!(
+        matches!(events.first(), Some(RelayConnEvent::RelayedAddressChanged { new, .. }) if *new == new_relayed_addr),
+        "{:?}",
+        events
+    );
+    assert_eq!(
+        Some(&RelayConnEvent::AllocationRestored {
+            restored_permissions: 1,
+            restored_bindings: 2,
+            failed: 0,
+        }),
+        events.last()
+    );
+    assert_eq!(1, server.snapshot().await.allocations);
+
+    let restored = relay_conn.bindings().await;
+    assert_eq!(
+        bound.iter().map(|b| (b.peer, b.number)).collect::<Vec<_>>(),
+        restored
+            .iter()
+            .map(|b| (b.peer, b.number))
+            .collect::<Vec<_>>()
+    );
+    assert!(restored.iter().all(|b| b.state == BindingState::Ready));
+
+    for peer in &peers {
+        assert_eq!(
+            new_relayed_addr,
+            relay_through(&relay_conn, peer, b"after").await?
+        );
+    }
+
+    client.close().await?;
+    Ok(())
+}
+
+// A channel the restarted server won't bind again is left in indication mode,
+// the recovery goes on and its peer still gets the data
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_auto_reallocate_binding_failure() -> Result<(), Error> {
+    let (_server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.interceptor(Box::new(ChannelBindBlocker))
+        })),
+        ..Default::default()
+    })
+    .await?;
+    let (client, relay_conn, peers, bound, events) = restart_under_client(server_addr).await?;
+
+    assert_eq!(
+        Some(&RelayConnEvent::AllocationRestored {
+            restored_permissions: 1,
+            restored_bindings: 0,
+            failed: 2,
+        }),
+        events.last()
+    );
+    let restored = relay_conn.bindings().await;
+    assert_eq!(bound.len(), restored.len());
+    assert!(restored.iter().all(|b| b.state == BindingState::Failed));
+
+    let new_relayed_addr = relay_conn.local_addr()?;
+    for peer in &peers {
+        assert_eq!(
+            new_relayed_addr,
+            relay_through(&relay_conn, peer, b"after").await?
+        );
+    }
+
+    client.close().await?;
+    Ok(())
+}
+
+// The recovery holds neither the RelayConn nor the client while it waits for
+// the server, both answer while the restored channels are being bound
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_auto_reallocate_runs_unlocked() -> Result<(), Error> {
+    use periodic_timer::PeriodicTimerTimeoutHandler;
+
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.interceptor(Box::new(ChannelBindDelayer(Duration::from_millis(300))))
+        })),
+        ..Default::default()
+    })
+    .await?;
+    let (_old_server, proxy, client, relay_conn, _peers, _bound) = bound_under_client().await?;
+
+    let mut events = relay_conn.events();
+    proxy.restart(server_addr);
+    let mut refresher = relay_conn.refresher();
+    let recovery = tokio::spawn(async move {
+        refresher
+            .on_timeout(periodic_timer::TimerIdRefresh::Perms)
+            .await
+    });
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+            Ok(Ok(RelayConnEvent::RelayedAddressChanged { .. })) => break,
+            Ok(Ok(_)) => {}
+            _ => return Err(Error::Other("not reallocated".to_owned())),
+        }
+    }
+
+    let id = tokio::time::timeout(Duration::from_millis(100), relay_conn.allocation_id())
+        .await
+        .map_err(|_| Error::Other("held up by the recovery".to_owned()))?;
+    assert_eq!(relay_conn.local_addr()?, id.relayed_addr);
+
+    recovery
+        .await
+        .map_err(|err| Error::Other(err.to_string()))?;
+    let mut restored = None;
+    while let Ok(event) = events.try_recv() {
+        if let RelayConnEvent::AllocationRestored { .. } = event {
+            restored = Some(event);
+        }
+    }
+    assert_eq!(
+        Some(RelayConnEvent::AllocationRestored {
+            restored_permissions: 1,
+            restored_bindings: 2,
+            failed: 0,
+        }),
+        restored
+    );
+    assert_eq!(1, server.snapshot().await.allocations);
+
+    client.close().await?;
+    Ok(())
+}
+
+// ATTR_VENDOR_TEST is a comprehension-optional attribute, ignored by servers
+// that don't know it
+const ATTR_VENDOR_TEST: AttrType = AttrType(0xC0DE);
+
+// The customizer's attribute and transaction ids go out in every request,
+// which still carry a valid MESSAGE-INTEGRITY and FINGERPRINT
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_message_customizer() -> Result<(), Error> {
+    use stun::fingerprint::FINGERPRINT;
+
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let on_send_raw: RawPacketHook = {
+        let sent = Arc::clone(&sent);
+        Arc::new(move |data: &[u8], _to: SocketAddr| sent.lock().unwrap().push(data.to_vec()))
+    };
+    let customized = Arc::new(AtomicUsize::new(0));
+    let message_customizer: MessageCustomizer = {
+        let customized = Arc::clone(&customized);
+        Arc::new(move |msg: &mut Message| {
+            let n = customized.fetch_add(1, Ordering::SeqCst) as u8;
+            msg.transaction_id = TransactionId([n; TRANSACTION_ID_SIZE]);
+            msg.add(ATTR_VENDOR_TEST, b"vendor");
+        })
+    };
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        on_send_raw: Some(on_send_raw),
+        message_customizer: Some(message_customizer),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let relay_conn = client.allocate().await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    relay_round_trip(&relay_conn, &peer, b"customized").await?;
+
+    let messages: Vec<Message> = sent
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|raw| {
+            let mut msg = Message::new();
+            msg.raw = raw.clone();
+            msg.decode().ok().map(|_| msg)
+        })
+        .collect();
+    let methods: Vec<Method> = messages.iter().map(|msg| msg.typ.method).collect();
+    assert_eq!(&[METHOD_ALLOCATE, METHOD_ALLOCATE], &methods[..2]);
+    assert!(methods.contains(&METHOD_CREATE_PERMISSION), "{:?}", methods);
+    assert!(methods.contains(&METHOD_SEND), "{:?}", methods);
+
+    let count = customized.load(Ordering::SeqCst);
+    for msg in &messages {
+        let n = msg.transaction_id.0[0];
+        assert!((n as usize) < count);
+        assert_eq!(TransactionId([n; TRANSACTION_ID_SIZE]), msg.transaction_id);
+        assert_eq!(b"vendor".to_vec(), msg.get(ATTR_VENDOR_TEST)?);
+        FINGERPRINT.check(msg)?;
+    }
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// relay_to relays data to peer given as to and back, returning who the
+// RelayConn reports the reply from
+#[cfg(feature = "server")]
+async fn relay_to<T: RelayConnObserver + Send + Sync>(
+    relay_conn: &RelayConn<T>,
+    peer: &UdpSocket,
+    to: SocketAddr,
+    data: &[u8],
+) -> Result<SocketAddr, Error> {
+    let mut buf = vec![0u8; 1500];
+    relay_conn.send_to(data, to).await?;
+    let (n, relayed_addr) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("peer received nothing".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+
+    peer.send_to(data, relayed_addr).await?;
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), relay_conn.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("client received nothing".to_owned()))??;
+    assert_eq!(data, &buf[..n]);
+    Ok(from)
+}
+
+// A v4-mapped IPv6 peer address is its IPv4 peer, with a single permission
+// and binding, and replies are reported from the form last sent to
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_v4_mapped_peer() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let on_send_raw: RawPacketHook = {
+        let sent = Arc::clone(&sent);
+        Arc::new(move |data: &[u8], _to: SocketAddr| sent.lock().unwrap().push(data.to_vec()))
+    };
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        on_send_raw: Some(on_send_raw),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+    let relay_conn = client.allocate().await?;
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let plain = peer.local_addr()?;
+    let mapped = SocketAddr::new(
+        std::net::Ipv4Addr::LOCALHOST.to_ipv6_mapped().into(),
+        plain.port(),
+    );
+
+    assert_eq!(
+        mapped,
+        relay_to(&relay_conn, &peer, mapped, b"mapped").await?
+    );
+    assert_eq!(plain, relay_to(&relay_conn, &peer, plain, b"plain").await?);
+    assert_eq!(
+        mapped,
+        relay_to(&relay_conn, &peer, mapped, b"again").await?
+    );
+
+    let bindings = relay_conn.bindings().await;
+    assert_eq!(1, bindings.len());
+    assert_eq!(plain, bindings[0].peer);
+    let methods = sent_request_methods(&sent.lock().unwrap());
+    let count = |method| methods.iter().filter(|m| **m == method).count();
+    assert_eq!(1, count(METHOD_CREATE_PERMISSION), "{:?}", methods);
+    assert_eq!(1, count(METHOD_CHANNEL_BIND), "{:?}", methods);
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// spoof_responses answers every request server gets with a success response
+// sent from spoofer
+#[cfg(feature = "server")]
+fn spoof_responses(server: UdpSocket, spoofer: UdpSocket) {
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, from)) = server.recv_from(&mut buf).await {
+            let mut req = Message::new();
+            req.raw = buf[..n].to_vec();
+            if req.decode().is_err() {
+                continue;
+            }
+            let mut res = Message::new();
+            let built = res.build(&[
+                Box::new(req.transaction_id),
+                Box::new(BINDING_SUCCESS),
+                Box::new(XORMappedAddress {
+                    ip: from.ip(),
+                    port: from.port(),
+                }),
+            ]);
+            if built.is_ok() {
+                let _ = spoofer.send_to(&res.raw, from).await;
+            }
+        }
+    });
+}
+
+// A response from another address than its request went to is dropped and
+// counted, the transaction times out, unless accept_alternate_source lets it in
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_drops_spoofed_response() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let spoofer = UdpSocket::bind("127.0.0.1:0").await?;
+    let spoofer_addr = spoofer.local_addr()?;
+    spoof_responses(server, spoofer);
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        rto_in_ms: 10,
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let result = client.send_binding_request_to(server_addr).await;
+    assert!(
+        matches!(result, Err(Error::AllRetransmissionsFailed(_))),
+        "{:?}",
+        result
+    );
+    assert!(client.misdirected_dropped() > 0);
+    client.close().await?;
+
+    let alternates = Arc::new(std::sync::Mutex::new(vec![]));
+    let accept_alternate_source: AlternateSourceHook = {
+        let alternates = Arc::clone(&alternates);
+        Arc::new(move |to: SocketAddr, from: SocketAddr| {
+            alternates.lock().unwrap().push((to, from));
+            from == spoofer_addr
+        })
+    };
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr()?;
+    let client = Client::new(ClientConfig {
+        rto_in_ms: 10,
+        accept_alternate_source: Some(accept_alternate_source),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    assert_eq!(
+        local_addr,
+        client.send_binding_request_to(server_addr).await?
+    );
+    assert_eq!(0, client.misdirected_dropped());
+    assert_eq!((server_addr, spoofer_addr), alternates.lock().unwrap()[0]);
+    client.close().await?;
+
+    Ok(())
+}
+
+// Data sent to a closed port comes back as ICMP Port Unreachable, which the
+// server relays to the client
+#[cfg(all(feature = "icmp", target_os = "linux"))]
+#[tokio::test]
+async fn test_client_icmp_received() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+    let relay_conn = client.allocate().await?;
+    let mut events = relay_conn.events();
+
+    let closed = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+    relay_conn.send_to(b"anyone there?", closed).await?;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await {
+                Ok(event @ RelayConnEvent::IcmpReceived { .. }) => return Some(event),
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("no ICMP error received".to_owned()))?;
+    assert_eq!(
+        Some(RelayConnEvent::IcmpReceived {
+            peer: closed,
+            icmp: Icmp {
+                typ: 3,
+                code: 3,
+                error_data: 0,
+            },
+        }),
+        event
+    );
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// start_quirks_client starts a listening client of the server with quirks
+#[cfg(feature = "server")]
+async fn start_quirks_client(
+    server_addr: SocketAddr,
+    server_quirks: ServerQuirks,
+) -> Result<Client, Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        server_quirks,
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+    Ok(client)
+}
+
+// FingerprintRejecter stands in for a server rejecting the requests carrying
+// FINGERPRINT
+#[cfg(feature = "server")]
+struct FingerprintRejecter;
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for FingerprintRejecter {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        if msg.contains(ATTR_FINGERPRINT) {
+            interceptor::InterceptDecision::Reject {
+                code: CODE_BAD_REQUEST,
+                reason: "FINGERPRINT is not supported".to_owned(),
+            }
+        } else {
+            interceptor::InterceptDecision::Continue
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_quirk_omit_fingerprint() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.interceptor(Box::new(FingerprintRejecter))
+        })),
+        ..Default::default()
+    })
+    .await?;
+    let client = start_client(server_addr).await?;
+    match client.allocate().await {
+        Err(Error::Protocol { code: 400, .. }) => {}
+        Err(err) => panic!("unexpected {}", err),
+        Ok(_) => panic!("allocated with FINGERPRINT"),
+    }
+
+    let quirky = start_quirks_client(
+        server_addr,
+        ServerQuirks {
+            omit_fingerprint: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let relay_conn = quirky.allocate().await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    relay_to(&relay_conn, &peer, peer.local_addr()?, b"no fingerprint").await?;
+
+    client.close().await?;
+    quirky.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// UsernameGate stands in for a server dropping the Send indications without
+// USERNAME, channels aren't bound so the data goes in Send indications
+#[cfg(feature = "server")]
+struct UsernameGate;
+
+#[cfg(feature = "server")]
+#[async_trait]
+impl interceptor::RequestInterceptor for UsernameGate {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        _ctx: &interceptor::RequestCtx,
+    ) -> interceptor::InterceptDecision {
+        let unnamed_send = msg.typ.method == METHOD_SEND && !msg.contains(ATTR_USERNAME);
+        if unnamed_send || msg.typ.method == METHOD_CHANNEL_BIND {
+            interceptor::InterceptDecision::Drop
+        } else {
+            interceptor::InterceptDecision::Continue
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_quirk_username_on_indications() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.interceptor(Box::new(UsernameGate))
+        })),
+        ..Default::default()
+    })
+    .await?;
+    let client = start_client(server_addr).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut buf = [0u8; 64];
+
+    let relay_conn = client.allocate().await?;
+    relay_conn.send_to(b"unnamed", peer.local_addr()?).await?;
+    let received = tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await;
+    assert!(
+        received.is_err(),
+        "Send indication without USERNAME relayed"
+    );
+
+    let quirky = start_quirks_client(
+        server_addr,
+        ServerQuirks {
+            username_on_indications: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let quirky_relay_conn = quirky.allocate().await?;
+    quirky_relay_conn
+        .send_to(b"named", peer.local_addr()?)
+        .await?;
+    let (n, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("peer received nothing".to_owned()))??;
+    assert_eq!(b"named", &buf[..n]);
+
+    client.close().await?;
+    quirky.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// unprotected_response answers an authenticated request with a success
+// response without MESSAGE-INTEGRITY, and challenges the others
+#[cfg(feature = "server")]
+fn unprotected_response(req: &Message, relayed_addr: SocketAddr) -> Result<Message, Error> {
+    let mut res = Message::new();
+    if req.contains(ATTR_MESSAGE_INTEGRITY) {
+        res.build(&[
+            Box::new(req.transaction_id),
+            Box::new(MessageType::new(req.typ.method, CLASS_SUCCESS_RESPONSE)),
+            Box::new(RelayedAddress {
+                ip: relayed_addr.ip(),
+                port: relayed_addr.port(),
+            }),
+            Box::new(Lifetime(Duration::from_secs(600))),
+        ])?;
+    } else {
+        res.build(&[
+            Box::new(req.transaction_id),
+            Box::new(MessageType::new(req.typ.method, CLASS_ERROR_RESPONSE)),
+            Box::new(ErrorCodeAttribute {
+                code: CODE_UNAUTHORIZED,
+                reason: vec![],
+            }),
+            Box::new(Realm::new(ATTR_REALM, "webrtc.rs".to_owned())),
+            Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+        ])?;
+    }
+    Ok(res)
+}
+
+// run_unprotected_responder stands in for a server that doesn't sign its
+// success responses
+#[cfg(feature = "server")]
+async fn run_unprotected_responder(conn: UdpSocket, relayed_addr: SocketAddr) {
+    let mut buf = vec![0u8; 1500];
+    while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+        let mut req = Message::new();
+        req.raw = buf[..n].to_vec();
+        if req.decode().is_err() || req.typ.class != CLASS_REQUEST {
+            continue;
+        }
+        if let Ok(res) = unprotected_response(&req, relayed_addr) {
+            let _ = conn.send_to(&res.raw, from).await;
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_quirk_allow_unprotected_success() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let relayed_addr = SocketAddr::from_str("127.0.0.1:50000")?;
+    tokio::spawn(run_unprotected_responder(server, relayed_addr));
+
+    let client = start_quirks_client(server_addr, ServerQuirks::default()).await?;
+    match client.allocate().await {
+        Err(Error::UnprotectedResponse(typ)) => {
+            assert_eq!(
+                MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE),
+                typ
+            );
+        }
+        Err(err) => panic!("unexpected {}", err),
+        Ok(_) => panic!("unprotected success accepted"),
+    }
+
+    let quirky = start_quirks_client(
+        server_addr,
+        ServerQuirks {
+            allow_unprotected_success: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let relay_conn = quirky.allocate().await?;
+    assert_eq!(relayed_addr, relay_conn.local_addr()?);
+
+    client.close().await?;
+    quirky.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,319 @@
+// conformance_test compares the messages the client builds for its core
+// flows with the golden vectors in testdata/conformance, byte for byte. The
+// transaction ids are fixed with a MessageCustomizer and the realm and nonce
+// by a scripted server. A change to the layout of a message has to update its
+// vector, by running the tests with TURN_UPDATE_GOLDEN=1.
+use super::*;
+use crate::proto::lifetime::Lifetime;
+use crate::proto::relayaddr::RelayedAddress;
+
+use stun::error_code::*;
+use stun::fingerprint::FINGERPRINT;
+use stun::xoraddr::XORMappedAddress;
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+const GOLDEN_USERNAME: &str = "golden";
+const GOLDEN_PASSWORD: &str = "vectors";
+const GOLDEN_REALM: &str = "example.org";
+const GOLDEN_NONCE: &str = "f1xedn0nce";
+const GOLDEN_SOFTWARE: &str = "conformance";
+
+// golden_transaction_id is the transaction id of every message of a type,
+// the encoded type followed by a fixed filler
+fn golden_transaction_id(typ: MessageType) -> TransactionId {
+    let mut id = [0x5a; TRANSACTION_ID_SIZE];
+    id[..2].copy_from_slice(&typ.value().to_be_bytes());
+    TransactionId(id)
+}
+
+fn golden_integrity() -> MessageIntegrity {
+    MessageIntegrity::new_long_term_integrity(
+        GOLDEN_USERNAME.to_owned(),
+        GOLDEN_REALM.to_owned(),
+        GOLDEN_PASSWORD.to_owned(),
+    )
+}
+
+// scripted_response answers an unauthenticated request with a 401 and the
+// golden realm and nonce, an authenticated one with a signed success
+fn scripted_response(req: &Message, from: SocketAddr) -> Result<Message, Error> {
+    let mut res = Message::new();
+    if !req.contains(ATTR_MESSAGE_INTEGRITY) {
+        res.build(&[
+            Box::new(req.transaction_id),
+            Box::new(MessageType::new(req.typ.method, CLASS_ERROR_RESPONSE)),
+            Box::new(ErrorCodeAttribute {
+                code: CODE_UNAUTHORIZED,
+                reason: vec![],
+            }),
+            Box::new(Realm::new(ATTR_REALM, GOLDEN_REALM.to_owned())),
+            Box::new(Nonce::new(ATTR_NONCE, GOLDEN_NONCE.to_owned())),
+        ])?;
+        return Ok(res);
+    }
+
+    let mut setters: Vec<Box<dyn Setter>> = vec![
+        Box::new(req.transaction_id),
+        Box::new(MessageType::new(req.typ.method, CLASS_SUCCESS_RESPONSE)),
+    ];
+    if req.typ.method == METHOD_ALLOCATE {
+        setters.push(Box::new(RelayedAddress {
+            ip: IpAddr::from_str("192.0.2.1")?,
+            port: 50000,
+        }));
+        setters.push(Box::new(XORMappedAddress {
+            ip: from.ip(),
+            port: from.port(),
+        }));
+    }
+    if req.typ.method == METHOD_ALLOCATE || req.typ.method == METHOD_REFRESH {
+        setters.push(Box::new(Lifetime(Duration::from_secs(600))));
+    }
+    setters.push(Box::new(golden_integrity()));
+    res.build(&setters)?;
+    Ok(res)
+}
+
+async fn run_scripted_server(conn: UdpSocket) {
+    let mut buf = vec![0u8; 1500];
+    while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+        let mut req = Message::new();
+        req.raw = buf[..n].to_vec();
+        if req.decode().is_err() || req.typ.class != CLASS_REQUEST {
+            continue;
+        }
+        if let Ok(res) = scripted_response(&req, from) {
+            let _ = conn.send_to(&res.raw, from).await;
+        }
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/client/testdata/conformance")
+        .join(format!("{}.hex", name))
+}
+
+// read_golden parses a vector, hex words with # comments
+fn read_golden(name: &str) -> Result<Vec<u8>, Error> {
+    let text = std::fs::read_to_string(golden_path(name))?;
+    let hex_text: String = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split_whitespace())
+        .collect();
+    hex::decode(hex_text).map_err(|err| Error::Other(format!("{}: {}", name, err)))
+}
+
+fn write_golden(name: &str, raw: &[u8]) -> Result<(), Error> {
+    let mut text = format!(
+        "# {} golden vector, see src/client/conformance_test.rs\n\
+         # regenerate with TURN_UPDATE_GOLDEN=1 cargo test conformance\n",
+        name
+    );
+    for word in raw.chunks(4) {
+        text.push_str(&hex::encode(word));
+        text.push('\n');
+    }
+    std::fs::write(golden_path(name), text)?;
+    Ok(())
+}
+
+// describe lists the header and attributes of a message, one per line
+fn describe(raw: &[u8]) -> Vec<String> {
+    let mut msg = Message::new();
+    msg.raw = raw.to_vec();
+    if let Err(err) = msg.decode() {
+        return vec![format!("undecodable ({}): {}", err, hex::encode(raw))];
+    }
+    let mut lines = vec![format!(
+        "{} length {} transaction {}",
+        msg.typ,
+        msg.length,
+        hex::encode(msg.transaction_id.0)
+    )];
+    for attr in &msg.attributes.0 {
+        lines.push(format!(
+            "{} ({} bytes) {}",
+            attr.typ,
+            attr.length,
+            hex::encode(&attr.value)
+        ));
+    }
+    lines
+}
+
+// attribute_diff lines up the descriptions of expected and actual, marking
+// the lines that differ with - and +
+fn attribute_diff(expected: &[u8], actual: &[u8]) -> String {
+    let (expected, actual) = (describe(expected), describe(actual));
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => diff.push_str(&format!("  {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    diff.push_str(&format!("- {}\n", e));
+                }
+                if let Some(a) = a {
+                    diff.push_str(&format!("+ {}\n", a));
+                }
+            }
+        }
+    }
+    diff
+}
+
+fn check_golden(name: &str, actual: &[u8]) -> Result<(), Error> {
+    if std::env::var_os("TURN_UPDATE_GOLDEN").is_some() {
+        return write_golden(name, actual);
+    }
+    let expected = read_golden(name)?;
+    if expected != actual {
+        panic!(
+            "{} differs from its golden vector:\n{}\
+             rerun with TURN_UPDATE_GOLDEN=1 if the change is intended",
+            name,
+            attribute_diff(&expected, actual)
+        );
+    }
+    Ok(())
+}
+
+const GOLDEN_VECTORS: &[&str] = &[
+    "allocate",
+    "create_permission",
+    "channel_bind",
+    "refresh",
+    "send_indication",
+];
+
+#[tokio::test]
+async fn test_client_conformance_vectors() -> Result<(), Error> {
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    tokio::spawn(run_scripted_server(server));
+
+    let sent = Arc::new(std::sync::Mutex::new(vec![]));
+    let on_send_raw: RawPacketHook = {
+        let sent = Arc::clone(&sent);
+        Arc::new(move |data: &[u8], _to: SocketAddr| sent.lock().unwrap().push(data.to_vec()))
+    };
+    let message_customizer: MessageCustomizer = Arc::new(|msg: &mut Message| {
+        msg.transaction_id = golden_transaction_id(msg.typ);
+    });
+    let client = Client::new(ClientConfig {
+        stun_serv_addr: server_addr.to_string(),
+        turn_serv_addr: server_addr.to_string(),
+        username: GOLDEN_USERNAME.to_owned(),
+        password: GOLDEN_PASSWORD.to_owned(),
+        software: GOLDEN_SOFTWARE.to_owned(),
+        on_send_raw: Some(on_send_raw),
+        message_customizer: Some(message_customizer),
+        ..ClientConfig::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+    })
+    .await?;
+    client.listen().await?;
+
+    // the first send creates the permission, goes out as a Send indication
+    // and binds a channel in the background, close deallocates with a Refresh
+    let mut relay_conn = client.allocate().await?;
+    let peer = SocketAddr::from_str("192.0.2.10:4000")?;
+    relay_conn.send_to(b"golden", peer).await?;
+    let sent_method = |method: Method| {
+        sent.lock().unwrap().iter().any(|raw| {
+            let mut msg = Message::new();
+            msg.raw = raw.clone();
+            msg.decode().is_ok() && msg.typ.method == method
+        })
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !sent_method(METHOD_CHANNEL_BIND) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("no ChannelBind sent".to_owned()))?;
+    relay_conn.close().await?;
+    client.close().await?;
+
+    let sent = sent.lock().unwrap();
+    let find = |typ: MessageType| -> Result<Vec<u8>, Error> {
+        // the Allocate retried with credentials is the last one
+        sent.iter()
+            .rfind(|raw| {
+                let mut msg = Message::new();
+                msg.raw = raw.to_vec();
+                msg.decode().is_ok() && msg.typ == typ && msg.contains(ATTR_FINGERPRINT)
+            })
+            .cloned()
+            .ok_or_else(|| Error::Other(format!("no {} sent", typ)))
+    };
+    let request = |method| MessageType::new(method, CLASS_REQUEST);
+    let captured = [
+        find(request(METHOD_ALLOCATE))?,
+        find(request(METHOD_CREATE_PERMISSION))?,
+        find(request(METHOD_CHANNEL_BIND))?,
+        find(request(METHOD_REFRESH))?,
+        find(MessageType::new(METHOD_SEND, CLASS_INDICATION))?,
+    ];
+    for (name, raw) in GOLDEN_VECTORS.iter().zip(&captured) {
+        check_golden(name, raw)?;
+    }
+
+    Ok(())
+}
+
+// The vectors must stay valid STUN: they decode, their FINGERPRINT matches
+// and the requests carry a MESSAGE-INTEGRITY of the golden credentials
+#[test]
+fn test_client_conformance_vectors_are_well_formed() -> Result<(), Error> {
+    for name in GOLDEN_VECTORS {
+        let mut msg = Message::new();
+        msg.raw = read_golden(name)?;
+        msg.decode()?;
+        FINGERPRINT.check(&msg)?;
+        assert_eq!(
+            golden_transaction_id(msg.typ),
+            msg.transaction_id,
+            "{}",
+            name
+        );
+        if msg.typ.class == CLASS_REQUEST {
+            golden_integrity().check(&mut msg)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_client_conformance_attribute_diff() -> Result<(), Error> {
+    let build = |software: &str| -> Result<Vec<u8>, Error> {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(golden_transaction_id(MessageType::new(
+                METHOD_REFRESH,
+                CLASS_REQUEST,
+            ))),
+            Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+            Box::new(Software::new(ATTR_SOFTWARE, software.to_owned())),
+            Box::new(Lifetime(Duration::from_secs(600))),
+        ])?;
+        Ok(msg.raw)
+    };
+
+    let diff = attribute_diff(&build("one")?, &build("two")?);
+    let changed: Vec<&str> = diff.lines().filter(|l| !l.starts_with("  ")).collect();
+    assert_eq!(2, changed.len(), "{}", diff);
+    assert!(changed[0].starts_with("- SOFTWARE"), "{}", diff);
+    assert!(changed[1].starts_with("+ SOFTWARE"), "{}", diff);
+    assert!(diff.contains("  LIFETIME"), "{}", diff);
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use super::binding::BindingState;
+use super::permission::PermState;
+use crate::proto::icmp::Icmp;
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+// EVENT_QUEUE_SIZE is how many events a slow subscriber can fall behind
+// before it misses some
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// RelayConnEvent is a change in a RelayConn's state, see RelayConn::events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayConnEvent {
+    // InboundOverflow is emitted when data from a peer is first dropped
+    // because the read queue is full, after a while without drops. dropped is
+    // the count dropped so far.
+    InboundOverflow {
+        dropped: u64,
+    },
+    // PermissionStateChanged is emitted when the permission for the IP of
+    // peer changes state, peer is the address written to
+    PermissionStateChanged {
+        peer: SocketAddr,
+        old: PermState,
+        new: PermState,
+    },
+    // BindingStateChanged is emitted when the channel binding of peer changes
+    // state. error is why the ChannelBind failed when new is Failed, a binding
+    // that failed other than with an unexpected response is then deleted and
+    // bound again on the next write.
+    BindingStateChanged {
+        peer: SocketAddr,
+        number: u16,
+        old: BindingState,
+        new: BindingState,
+        error: Option<String>,
+    },
+    // OversizedSend is emitted the first time a payload over
+    // warn_payload_size is sent to peer, it is likely to be fragmented or
+    // dropped on the way to the server
+    OversizedSend {
+        peer: SocketAddr,
+        size: usize,
+        warn_payload_size: usize,
+    },
+    // RelayedAddressChanged is emitted when a Refresh response moves the
+    // allocation to another relayed address, RelayConn::local_addr returns
+    // new from then on
+    RelayedAddressChanged {
+        old: SocketAddr,
+        new: SocketAddr,
+    },
+    // AllocationRestored is emitted once auto_reallocate has replaced a lost
+    // allocation, after RelayedAddressChanged. restored_permissions counts the
+    // peer IPs permitted again and restored_bindings the channels bound again
+    // with their numbers. failed counts those that weren't, a failed binding
+    // is left Failed and its peer is sent Send indications.
+    AllocationRestored {
+        restored_permissions: usize,
+        restored_bindings: usize,
+        failed: usize,
+    },
+    // IcmpReceived is emitted when the server relays an ICMP error answering
+    // data sent to peer, e.g. Destination Unreachable for a closed port. The
+    // data was lost.
+    IcmpReceived {
+        peer: SocketAddr,
+        icmp: Icmp,
+    },
+}
+
+// RelayConnEvents is shared by a RelayConn and whatever feeds it. The
+// broadcast channel is only created once someone subscribes, until then
+// events go nowhere.
+#[derive(Debug, Default)]
+pub(crate) struct RelayConnEvents {
+    tx: Mutex<Option<broadcast::Sender<RelayConnEvent>>>,
+}
+
+impl RelayConnEvents {
+    fn tx(&self) -> std::sync::MutexGuard<'_, Option<broadcast::Sender<RelayConnEvent>>> {
+        self.tx.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<RelayConnEvent> {
+        self.tx()
+            .get_or_insert_with(|| broadcast::channel(EVENT_QUEUE_SIZE).0)
+            .subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: RelayConnEvent) {
+        log::debug!("relay conn event: {:?}", event);
+        if let Some(tx) = &*self.tx() {
+            // no receiver left is fine
+            let _ = tx.send(event);
+        }
+    }
+}
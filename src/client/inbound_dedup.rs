@@ -0,0 +1,51 @@
+#[cfg(test)]
+mod inbound_dedup_test;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+
+// MAX_DEDUP_PEERS bounds the peers DedupWindow remembers packets of, the
+// windows are all forgotten when it is reached
+const MAX_DEDUP_PEERS: usize = 1024;
+
+// DedupWindow remembers the fingerprints of the last window packets from each
+// peer, a hash of the payload and the peer, to drop exact duplicates a lossy
+// or reconnecting transport delivered twice
+#[derive(Debug)]
+pub(crate) struct DedupWindow {
+    window: usize,
+    recent: HashMap<SocketAddr, VecDeque<u64>>,
+}
+
+impl DedupWindow {
+    pub(crate) fn new(window: usize) -> Self {
+        DedupWindow {
+            window,
+            recent: HashMap::new(),
+        }
+    }
+
+    // is_duplicate reports if data from peer is among its last window
+    // packets, and remembers it otherwise
+    pub(crate) fn is_duplicate(&mut self, data: &[u8], from: SocketAddr) -> bool {
+        let mut hasher = DefaultHasher::new();
+        from.hash(&mut hasher);
+        data.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+
+        if self.recent.len() >= MAX_DEDUP_PEERS && !self.recent.contains_key(&from) {
+            self.recent.clear();
+        }
+        let recent = self.recent.entry(from).or_default();
+        if recent.contains(&fingerprint) {
+            return true;
+        }
+        if recent.len() >= self.window {
+            recent.pop_front();
+        }
+        recent.push_back(fingerprint);
+        false
+    }
+}
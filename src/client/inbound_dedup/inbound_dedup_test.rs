@@ -0,0 +1,37 @@
+use super::*;
+
+use std::net::Ipv4Addr;
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), port)
+}
+
+#[test]
+fn test_dedup_window() {
+    let mut dedup = DedupWindow::new(2);
+    assert!(!dedup.is_duplicate(b"a", peer(1)));
+    assert!(dedup.is_duplicate(b"a", peer(1)));
+
+    // the same payload from another peer is no duplicate
+    assert!(!dedup.is_duplicate(b"a", peer(2)));
+
+    // a packet falls out of the window after two others
+    assert!(!dedup.is_duplicate(b"b", peer(1)));
+    assert!(!dedup.is_duplicate(b"c", peer(1)));
+    assert!(!dedup.is_duplicate(b"a", peer(1)));
+    assert!(dedup.is_duplicate(b"c", peer(1)));
+}
+
+#[test]
+fn test_dedup_window_bounded() {
+    let mut dedup = DedupWindow::new(4);
+    for port in 0..MAX_DEDUP_PEERS as u16 {
+        dedup.is_duplicate(b"a", peer(port));
+    }
+    assert_eq!(MAX_DEDUP_PEERS, dedup.recent.len());
+
+    // one more peer starts over
+    assert!(!dedup.is_duplicate(b"a", peer(MAX_DEDUP_PEERS as u16)));
+    assert_eq!(1, dedup.recent.len());
+    assert!(!dedup.is_duplicate(b"a", peer(0)));
+}
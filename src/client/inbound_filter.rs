@@ -0,0 +1,97 @@
+use super::inbound_dedup::DedupWindow;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// InboundFilter counts the Data indications dropped before the read queue:
+// malformed ones, missing their XOR-PEER-ADDRESS or DATA, as well as
+// ChannelData on an unbound channel, and with
+// drop_unsolicited those from an IP no permission was ever requested for,
+// which a TURN server doesn't relay. With a dedup window it also drops the
+// duplicates of recent data from a peer, however it came. It is shared by the
+// RelayConn and its InboundQueue.
+#[derive(Debug, Default)]
+pub(crate) struct InboundFilter {
+    drop_unsolicited: AtomicBool,
+    // contacted is the peer IPs permissions were requested for
+    contacted: Mutex<HashSet<IpAddr>>,
+    malformed: AtomicU64,
+    unsolicited: AtomicU64,
+    // dedup is only locked while dedup_on is set
+    dedup_on: AtomicBool,
+    dedup: Mutex<Option<DedupWindow>>,
+    duplicates: AtomicU64,
+}
+
+impl InboundFilter {
+    fn contacted(&self) -> std::sync::MutexGuard<'_, HashSet<IpAddr>> {
+        self.contacted.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn set_drop_unsolicited(&self, drop_unsolicited: bool) {
+        self.drop_unsolicited
+            .store(drop_unsolicited, Ordering::SeqCst);
+    }
+
+    // contact lets the indications from the IP of peer through, the
+    // permission for it is being requested
+    pub(crate) fn contact(&self, peer: SocketAddr) {
+        self.contacted().insert(peer.ip());
+    }
+
+    pub(crate) fn record_malformed(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // accept reports if the Data indication from peer goes on to the read
+    // queue, it is counted otherwise
+    pub(crate) fn accept(&self, from: SocketAddr) -> bool {
+        if !self.drop_unsolicited.load(Ordering::SeqCst) || self.contacted().contains(&from.ip()) {
+            return true;
+        }
+        trace_limited!("dropped data indication from uncontacted {}", from);
+        self.unsolicited.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    // set_dedup_window drops the duplicates among the last window packets of
+    // each peer, None or zero doesn't look for duplicates
+    pub(crate) fn set_dedup_window(&self, window: Option<usize>) {
+        let window = window.filter(|window| *window > 0);
+        *self.dedup.lock().unwrap_or_else(|err| err.into_inner()) = window.map(DedupWindow::new);
+        self.dedup_on.store(window.is_some(), Ordering::SeqCst);
+    }
+
+    // accept_unique reports if data from peer goes on to the read queue, a
+    // duplicate within the dedup window is counted otherwise
+    pub(crate) fn accept_unique(&self, data: &[u8], from: SocketAddr) -> bool {
+        if !self.dedup_on.load(Ordering::Relaxed) {
+            return true;
+        }
+        let duplicate = self
+            .dedup
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+            .is_some_and(|dedup| dedup.is_duplicate(data, from));
+        if duplicate {
+            trace_limited!("dropped duplicate data from {}", from);
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+        !duplicate
+    }
+
+    pub(crate) fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn unsolicited(&self) -> u64 {
+        self.unsolicited.load(Ordering::Relaxed)
+    }
+}
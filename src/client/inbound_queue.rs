@@ -0,0 +1,235 @@
+use super::auto_permit::AutoPermit;
+use super::event::*;
+use super::inbound_filter::InboundFilter;
+use super::peer_probe::PeerProbes;
+use super::peer_subscriptions::PeerSubscriptions;
+use super::relay_conn::InboundData;
+use super::stats_reporter::Traffic;
+use crate::error::Error;
+use crate::proto::icmp::Icmp;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// OVERFLOW_LOG_INTERVAL is the least time between two logs of dropped data,
+// and the time without drops after which InboundOverflow is emitted again
+const OVERFLOW_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct OverflowLog {
+    last_drop: Option<Instant>,
+    last_log: Option<Instant>,
+    // logged is the dropped count as of last_log
+    logged: u64,
+}
+
+// InboundOverflow counts the data dropped because a RelayConn's read queue was
+// full. It is shared by the RelayConn and its InboundQueue, a drop costs an
+// atomic add and a std mutex that is never held across an await.
+#[derive(Debug)]
+pub(crate) struct InboundOverflow {
+    dropped: AtomicU64,
+    log: Mutex<OverflowLog>,
+    events: Arc<RelayConnEvents>,
+}
+
+impl InboundOverflow {
+    pub(crate) fn new(events: Arc<RelayConnEvents>) -> Self {
+        InboundOverflow {
+            dropped: AtomicU64::new(0),
+            log: Mutex::new(OverflowLog::default()),
+            events,
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+
+        let first = {
+            let mut log = self.log.lock().unwrap_or_else(|err| err.into_inner());
+            let first = log
+                .last_drop
+                .is_none_or(|last_drop| now - last_drop >= OVERFLOW_LOG_INTERVAL);
+            log.last_drop = Some(now);
+            if log
+                .last_log
+                .is_none_or(|last_log| now - last_log >= OVERFLOW_LOG_INTERVAL)
+            {
+                log::debug!(
+                    "read queue full, dropped {} inbound packets ({} in all)",
+                    dropped - log.logged,
+                    dropped
+                );
+                log.last_log = Some(now);
+                log.logged = dropped;
+            }
+            first
+        };
+
+        if first {
+            self.events
+                .emit(RelayConnEvent::InboundOverflow { dropped });
+        }
+    }
+}
+
+// ReadQueue is the receiving end of a RelayConn's read queue, shared by its
+// readers: recv_from, poll_recv_from and the framed stream. The receiver is
+// polled under a std mutex that is never held across an await, so a reader
+// waiting for data doesn't block the others. The channel only keeps the waker
+// of its last poll, it is polled with ReadWaiters, which wakes every reader
+// waiting.
+pub(crate) struct ReadQueue {
+    rx: Mutex<mpsc::Receiver<InboundData>>,
+    waiters: Arc<ReadWaiters>,
+}
+
+#[derive(Default)]
+struct ReadWaiters {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl ReadWaiters {
+    fn wakers(&self) -> std::sync::MutexGuard<'_, Vec<Waker>> {
+        self.wakers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn unregister(&self, waker: &Waker) {
+        self.wakers().retain(|w| !w.will_wake(waker));
+    }
+}
+
+impl Wake for ReadWaiters {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *self.wakers());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl ReadQueue {
+    pub(crate) fn new(rx: mpsc::Receiver<InboundData>) -> Self {
+        ReadQueue {
+            rx: Mutex::new(rx),
+            waiters: Arc::new(ReadWaiters::default()),
+        }
+    }
+
+    // poll_recv polls for the next inbound data, None once the queue is closed
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<InboundData>> {
+        self.waiters.register(cx.waker());
+        let waker = Waker::from(Arc::clone(&self.waiters));
+        let poll = self
+            .rx
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .poll_recv(&mut Context::from_waker(&waker));
+        if poll.is_ready() {
+            self.waiters.unregister(cx.waker());
+        }
+        poll
+    }
+}
+
+// InboundQueue is the sending end of a RelayConn's read queue
+pub(crate) struct InboundQueue {
+    tx: mpsc::Sender<InboundData>,
+    overflow: Arc<InboundOverflow>,
+    auto_permit: Arc<AutoPermit>,
+    filter: Arc<InboundFilter>,
+    probes: Arc<PeerProbes>,
+    subscriptions: Arc<PeerSubscriptions>,
+    traffic: Arc<Traffic>,
+}
+
+impl InboundQueue {
+    pub(crate) fn new(
+        tx: mpsc::Sender<InboundData>,
+        overflow: Arc<InboundOverflow>,
+        auto_permit: Arc<AutoPermit>,
+        filter: Arc<InboundFilter>,
+        probes: Arc<PeerProbes>,
+        subscriptions: Arc<PeerSubscriptions>,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        InboundQueue {
+            tx,
+            overflow,
+            auto_permit,
+            filter,
+            probes,
+            subscriptions,
+            traffic,
+        }
+    }
+
+    // push_icmp passes on the ICMP error of a Data indication, which answered
+    // data sent to peer
+    pub(crate) fn push_icmp(&self, icmp: Icmp, peer: SocketAddr) {
+        self.overflow
+            .events
+            .emit(RelayConnEvent::IcmpReceived { peer, icmp });
+    }
+
+    // push_indication queues data of a Data indication, its peer may not have
+    // a permission yet. It is dropped when the filter drops unsolicited data.
+    pub(crate) fn push_indication(&self, data: &[u8], from: SocketAddr) -> Result<(), Error> {
+        if !self.filter.accept(from) {
+            return Ok(());
+        }
+        self.auto_permit.hint(from);
+        self.push(data, from)
+    }
+
+    // drop_malformed counts a Data indication without a usable
+    // XOR-PEER-ADDRESS or DATA, or ChannelData on a channel never bound
+    pub(crate) fn drop_malformed(&self) {
+        self.filter.record_malformed();
+    }
+
+    // push queues data from a peer, it is dropped and counted when the queue is
+    // full or it duplicates recent data. The reply to a probe of the peer
+    // isn't queued, the data of a subscribed peer goes to its subscription.
+    pub(crate) fn push(&self, data: &[u8], from: SocketAddr) -> Result<(), Error> {
+        self.traffic.record_received(data.len());
+        if !self.filter.accept_unique(data, from) {
+            return Ok(());
+        }
+        if self.probes.tap(from) || self.subscriptions.route(data, from) {
+            return Ok(());
+        }
+        match self.tx.try_send(InboundData {
+            data: data.to_vec(),
+            from,
+        }) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.overflow.record_drop();
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(Error::AlreadyClosed),
+        }
+    }
+}
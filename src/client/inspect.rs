@@ -0,0 +1,94 @@
+use super::quirks::ServerQuirks;
+use crate::error::Error;
+
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::{Message, Setter};
+use util::Conn;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+// RawPacketHook is called with the raw bytes of a packet and the remote address.
+// It runs inline on the packet path, so it must return quickly; heavy work such as
+// writing a capture file should be offloaded, e.g. by sending the bytes to a channel.
+pub type RawPacketHook = Arc<dyn Fn(&[u8], SocketAddr) + Send + Sync>;
+
+// MessageCustomizer is called with every STUN message the client builds, after
+// the standard attributes and before MESSAGE-INTEGRITY and FINGERPRINT, e.g. to
+// add a vendor attribute with Message::add or set a known transaction id for a
+// golden-file comparison. Header changes, such as a new transaction_id, are
+// written to the raw message afterwards.
+pub type MessageCustomizer = Arc<dyn Fn(&mut Message) + Send + Sync>;
+
+// customize calls customizer on a message built without MESSAGE-INTEGRITY and
+// FINGERPRINT
+pub(crate) fn customize(msg: &mut Message, customizer: Option<&MessageCustomizer>) {
+    if let Some(customizer) = customizer {
+        customizer(msg);
+        msg.write_header();
+    }
+}
+
+// seal customizes msg then adds integrity, if any, and FINGERPRINT, which
+// must come last, unless the quirks omit it
+pub(crate) fn seal(
+    msg: &mut Message,
+    customizer: Option<&MessageCustomizer>,
+    integrity: Option<&MessageIntegrity>,
+    quirks: ServerQuirks,
+) -> Result<(), Error> {
+    customize(msg, customizer);
+    if let Some(integrity) = integrity {
+        integrity.add_to(msg)?;
+    }
+    if !quirks.omit_fingerprint {
+        FINGERPRINT.add_to(msg)?;
+    }
+    Ok(())
+}
+
+// InspectConn wraps the client's conn to call the raw packet hooks on every
+// packet sent or received, including transaction retransmissions
+pub(crate) struct InspectConn {
+    pub(crate) conn: Arc<dyn Conn + Send + Sync>,
+    pub(crate) on_send_raw: Option<RawPacketHook>,
+    pub(crate) on_recv_raw: Option<RawPacketHook>,
+}
+
+#[async_trait]
+impl Conn for InspectConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, from) = self.conn.recv_from(buf).await?;
+        if let Some(on_recv_raw) = &self.on_recv_raw {
+            on_recv_raw(&buf[..n], from);
+        }
+        Ok((n, from))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        if let Some(on_send_raw) = &self.on_send_raw {
+            on_send_raw(buf, target);
+        }
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+}
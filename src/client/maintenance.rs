@@ -0,0 +1,170 @@
+#[cfg(test)]
+mod maintenance_test;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::sync::Weak;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// MaintenanceKind is one of the periodic upkeeps of a RelayConn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MaintenanceKind {
+    // Allocation refreshes the allocation, every half of its lifetime
+    Allocation,
+    // Permissions refreshes the permissions of the peers sent to
+    Permissions,
+    // Bindings refreshes the channel bindings data is received on
+    Bindings,
+}
+
+// Maintained is what registers with a MaintenanceScheduler, a RelayConn's
+// refresher in the crate and a mock in tests
+#[async_trait]
+pub(crate) trait Maintained: Send + Sync {
+    // maintain runs the upkeep of kind and returns the delay to its next run,
+    // None stops it
+    async fn maintain(&self, kind: MaintenanceKind) -> Option<Duration>;
+}
+
+// Scheduled is an upkeep to run at deadline
+struct Scheduled {
+    deadline: Instant,
+    target: Weak<dyn Maintained>,
+    kind: MaintenanceKind,
+}
+
+// Entry is a Scheduled in the queue, seq keeps the upkeeps due at the same
+// deadline in the order they were scheduled
+struct Entry {
+    scheduled: Scheduled,
+    seq: u64,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // reversed, the BinaryHeap pops the earliest deadline first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .scheduled
+            .deadline
+            .cmp(&self.scheduled.deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// MaintenanceScheduler drives the allocation, permission and binding
+// refreshes of the RelayConns given it from one task, instead of two timer
+// tasks per RelayConn. Each upkeep runs in a task of its own when due, so a
+// slow server holds up only its RelayConns. A RelayConn is dropped from the
+// queue once closed or dropped, the task ends with the last clone of the
+// scheduler, RelayConns keeping theirs.
+#[derive(Clone)]
+pub struct MaintenanceScheduler {
+    tx: mpsc::UnboundedSender<Scheduled>,
+}
+
+impl fmt::Debug for MaintenanceScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaintenanceScheduler")
+            .field("running", &!self.tx.is_closed())
+            .finish()
+    }
+}
+
+impl Default for MaintenanceScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MaintenanceScheduler {
+    // new starts the scheduler task, it must be called within a tokio runtime
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(rx, tx.downgrade()));
+        MaintenanceScheduler { tx }
+    }
+
+    // schedule runs the upkeep of kind on target after delay, and again after
+    // each delay it returns
+    pub(crate) fn schedule(
+        &self,
+        target: Weak<dyn Maintained>,
+        kind: MaintenanceKind,
+        delay: Duration,
+    ) {
+        let _ = self.tx.send(Scheduled {
+            deadline: Instant::now() + delay,
+            target,
+            kind,
+        });
+    }
+}
+
+async fn run(mut rx: mpsc::UnboundedReceiver<Scheduled>, tx: mpsc::WeakUnboundedSender<Scheduled>) {
+    let mut queue = BinaryHeap::new();
+    let mut seq = 0u64;
+
+    loop {
+        let next = queue.peek().map(|entry: &Entry| entry.scheduled.deadline);
+        let due = tokio::time::sleep_until(next.unwrap_or_else(Instant::now));
+        tokio::select! {
+            scheduled = rx.recv() => match scheduled {
+                Some(scheduled) => {
+                    queue.push(Entry { scheduled, seq });
+                    seq += 1;
+                }
+                None => break,
+            },
+            _ = due, if next.is_some() => start_due(&mut queue, &tx),
+        }
+    }
+}
+
+// start_due spawns the upkeeps that are due, each sends its next run back
+fn start_due(queue: &mut BinaryHeap<Entry>, tx: &mpsc::WeakUnboundedSender<Scheduled>) {
+    let now = Instant::now();
+    while queue
+        .peek()
+        .is_some_and(|entry| entry.scheduled.deadline <= now)
+    {
+        let Some(Entry { scheduled, .. }) = queue.pop() else {
+            break;
+        };
+        // the RelayConn was closed or dropped
+        let Some(target) = scheduled.target.upgrade() else {
+            continue;
+        };
+        let Some(tx) = tx.upgrade() else {
+            continue;
+        };
+        let kind = scheduled.kind;
+        let weak = scheduled.target;
+        tokio::spawn(async move {
+            if let Some(delay) = target.maintain(kind).await {
+                let _ = tx.send(Scheduled {
+                    deadline: Instant::now() + delay,
+                    target: weak,
+                    kind,
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,112 @@
+use super::*;
+
+use std::sync::{Arc, Mutex};
+
+type MaintenanceLog = Arc<Mutex<Vec<(usize, MaintenanceKind, Instant)>>>;
+
+// MockConn records its upkeeps and asks for the next allocation refresh
+// after period, for the others after twice that
+struct MockConn {
+    id: usize,
+    period: Duration,
+    log: MaintenanceLog,
+}
+
+#[async_trait]
+impl Maintained for MockConn {
+    async fn maintain(&self, kind: MaintenanceKind) -> Option<Duration> {
+        self.log
+            .lock()
+            .unwrap()
+            .push((self.id, kind, Instant::now()));
+        match kind {
+            MaintenanceKind::Allocation => Some(self.period),
+            _ => Some(self.period * 2),
+        }
+    }
+}
+
+fn runs(log: &MaintenanceLog, id: usize, kind: MaintenanceKind, start: Instant) -> Vec<Duration> {
+    log.lock()
+        .unwrap()
+        .iter()
+        .filter(|(i, k, _)| *i == id && *k == kind)
+        .map(|(_, _, at)| at.duration_since(start))
+        .collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_maintenance_scheduler_many_conns() {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let baseline = metrics.num_alive_tasks();
+    let start = Instant::now();
+    let log = MaintenanceLog::default();
+
+    let scheduler = MaintenanceScheduler::new();
+    let mut conns = vec![];
+    for id in 0..100 {
+        let period = Duration::from_secs(10 + id as u64);
+        let conn: Arc<dyn Maintained> = Arc::new(MockConn {
+            id,
+            period,
+            log: Arc::clone(&log),
+        });
+        scheduler.schedule(Arc::downgrade(&conn), MaintenanceKind::Allocation, period);
+        scheduler.schedule(
+            Arc::downgrade(&conn),
+            MaintenanceKind::Permissions,
+            period * 2,
+        );
+        conns.push(conn);
+    }
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(baseline + 1, metrics.num_alive_tasks());
+
+    let horizon = Duration::from_secs(300);
+    tokio::time::sleep(horizon).await;
+    // between upkeeps the scheduler is the only task left
+    assert_eq!(baseline + 1, metrics.num_alive_tasks());
+
+    let at: Vec<Instant> = log.lock().unwrap().iter().map(|(_, _, at)| *at).collect();
+    assert!(
+        at.windows(2).all(|w| w[0] <= w[1]),
+        "upkeeps should run in deadline order"
+    );
+    for id in 0..100 {
+        let period = Duration::from_secs(10 + id as u64);
+        let expected = |every: Duration| -> Vec<Duration> {
+            (1..)
+                .map(|k| every * k)
+                .take_while(|d| *d <= horizon)
+                .collect()
+        };
+        assert_eq!(
+            expected(period),
+            runs(&log, id, MaintenanceKind::Allocation, start),
+            "allocation refreshes of conn {}",
+            id
+        );
+        assert_eq!(
+            expected(period * 2),
+            runs(&log, id, MaintenanceKind::Permissions, start),
+            "permission refreshes of conn {}",
+            id
+        );
+    }
+
+    // a dropped conn is forgotten, the others carry on
+    conns.remove(0);
+    let before = runs(&log, 0, MaintenanceKind::Allocation, start).len();
+    let others = runs(&log, 1, MaintenanceKind::Allocation, start).len();
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert_eq!(
+        before,
+        runs(&log, 0, MaintenanceKind::Allocation, start).len()
+    );
+    assert!(runs(&log, 1, MaintenanceKind::Allocation, start).len() > others);
+
+    // the task ends with the scheduler
+    drop(scheduler);
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(baseline, metrics.num_alive_tasks());
+}
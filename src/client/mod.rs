@@ -1,41 +1,90 @@
 #[cfg(test)]
 mod client_test;
+#[cfg(test)]
+mod conformance_test;
 
+pub mod allocation_ttl;
+pub mod auto_permit;
+pub mod bind_policy;
 pub mod binding;
+pub mod event;
+pub mod inbound_dedup;
+pub mod inbound_filter;
+pub mod inbound_queue;
+pub mod inspect;
+pub mod maintenance;
+pub mod path_stats;
+pub mod peer_addr;
+pub mod peer_probe;
+pub mod peer_subscriptions;
 pub mod periodic_timer;
 pub mod permission;
+pub mod pool;
+#[cfg(feature = "quinn")]
+pub mod quinn;
+pub mod quirks;
+pub mod redundant_relay;
 pub mod relay_conn;
+pub mod relay_conn_stream;
+pub mod retry;
+pub mod send_batch;
+pub mod stats_reporter;
 pub mod transaction;
 
-use crate::errors::*;
+use crate::auth::{generate_auth_key, prepare_credential, REDACTED};
+use crate::error::Error;
 use crate::proto::{
-    chandata::*, data::*, lifetime::*, peeraddr::*, relayaddr::*, reqtrans::*, PROTO_UDP,
+    chandata::*, check_attributes, classify, data::*, errorcodes::UNSUPPORTED_TRANSPORT_PROTOCOL,
+    icmp::*, lifetime::*, peeraddr::*, relayaddr::*, reqtrans::*, PacketClass, Protocol, PROTO_TCP,
+    PROTO_UDP,
 };
+use crate::trace::Instrument;
+use auto_permit::AutoPermit;
+use bind_policy::ChannelBindPolicy;
 use binding::*;
+use event::*;
+use inbound_filter::InboundFilter;
+use inbound_queue::*;
+use inspect::*;
+use peer_addr::canonical_peer_addr;
+use peer_probe::PeerProbes;
+use peer_subscriptions::PeerSubscriptions;
+use pool::ChallengeCache;
+use quirks::ServerQuirks;
 use relay_conn::*;
+use retry::*;
+use send_batch::SendBatching;
+use stats_reporter::Traffic;
 use transaction::*;
 
 use stun::agent::*;
 use stun::attributes::*;
 use stun::error_code::*;
-use stun::fingerprint::*;
+use stun::fingerprint::FINGERPRINT;
 use stun::integrity::*;
 use stun::message::*;
 use stun::textattrs::*;
 use stun::xoraddr::*;
 
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use std::net::SocketAddr;
 use std::str::FromStr;
 use tokio::sync::{mpsc, Mutex};
-use util::{conn::*, Error};
+use tokio::time::Duration;
+use util::conn::*;
 
 use async_trait::async_trait;
 
 const DEFAULT_RTO_IN_MS: u16 = 200;
+// MAX_USERNAME_BYTES, MAX_REALM_BYTES and MAX_REALM_CHARS are the STUN limits
+// on USERNAME and REALM, RFC 5389 Sections 15.3 and 15.7
+const MAX_USERNAME_BYTES: usize = 513;
+const MAX_REALM_BYTES: usize = 763;
+const MAX_REALM_CHARS: usize = 127;
 const MAX_DATA_BUFFER_SIZE: usize = u16::MAX as usize; // message size limit for Chromium
-const MAX_READ_QUEUE_SIZE: usize = 1024;
 
 //              interval [msec]
 // 0: 0 ms      +500
@@ -57,6 +106,214 @@ pub struct ClientConfig {
     pub software: String,
     pub rto_in_ms: u16,
     pub conn: Arc<dyn Conn + Send + Sync>,
+
+    // refresh_jitter randomizes every allocation refresh period by up to this
+    // fraction either way, see RelayConnConfig::refresh_jitter. Defaults to off.
+    pub refresh_jitter: Option<f64>,
+
+    // retry_policy decides how the RelayConn retries failed Refresh and
+    // CreatePermission transactions. Defaults to RetryPolicy::default().
+    pub retry_policy: Option<RetryPolicy>,
+
+    // on_send_raw and on_recv_raw are optional hooks called with every packet
+    // the client sends to or receives from conn, e.g. for pcap-style dumps.
+    // See RawPacketHook, they must not block.
+    pub on_send_raw: Option<RawPacketHook>,
+    pub on_recv_raw: Option<RawPacketHook>,
+
+    // send_batching coalesces the ChannelData of the client's allocations
+    // into fewer writes, see SendBatching. Defaults to off.
+    pub send_batching: Option<SendBatching>,
+
+    // prearmed_auth authenticates the first Allocate right away instead of
+    // getting a 401 first, see PrearmedAuth. Defaults to none.
+    pub prearmed_auth: Option<PrearmedAuth>,
+
+    // auto_reallocate allocates again when the TURN server lost the
+    // allocation, e.g. on a restart, and restores its permissions and channel
+    // bindings, see RelayConnConfig::auto_reallocate. Defaults to off.
+    pub auto_reallocate: bool,
+
+    // message_customizer is called with every STUN message the client and its
+    // RelayConn build, before MESSAGE-INTEGRITY and FINGERPRINT, see
+    // MessageCustomizer. It is meant for tests and interop. Defaults to none.
+    pub message_customizer: Option<MessageCustomizer>,
+
+    // accept_alternate_source lets a response in from another address than
+    // its request was sent to, see AlternateSourceHook. Without it, or when
+    // it refuses, the response is dropped like a spoofed one and the
+    // transaction goes on retransmitting. Defaults to none.
+    pub accept_alternate_source: Option<AlternateSourceHook>,
+
+    // require_refresh_lifetime fails a refresh whose response has no
+    // LIFETIME, see RelayConnConfig::require_refresh_lifetime. Defaults to
+    // off, the lifetime asked for is kept.
+    pub require_refresh_lifetime: bool,
+
+    // expiry_guard is how close to its expiry send_to refreshes an
+    // allocation whose refreshes fail before sending, see
+    // RelayConnConfig::expiry_guard. None selects DEFAULT_EXPIRY_GUARD.
+    pub expiry_guard: Option<Duration>,
+
+    // fail_fast_on_expiry makes send_to fail with Error::AllocationExpiring
+    // instead, see RelayConnConfig::fail_fast_on_expiry. Defaults to off.
+    pub fail_fast_on_expiry: bool,
+
+    // server_quirks work around a TURN server known to stray from the RFCs,
+    // see ServerQuirks. Defaults to none, the strict behavior.
+    pub server_quirks: ServerQuirks,
+}
+
+// PrearmedAuth is a realm, nonce and long-term key from an earlier handshake
+// with the TURN server, e.g. saved with Client::export_auth_state or handed
+// out by a control plane. The key is used as is, the password is only needed
+// when the server rejects it with 401 (Unauthorized) and challenges again; a
+// stale nonce (438) is replaced with the one of the response.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct PrearmedAuth {
+    pub realm: String,
+    pub nonce: String,
+    pub integrity_key: Vec<u8>,
+}
+
+impl fmt::Debug for PrearmedAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrearmedAuth")
+            .field("realm", &self.realm)
+            .field("nonce", &self.nonce)
+            .field("integrity_key", &REDACTED)
+            .finish()
+    }
+}
+
+// TcpAllocateParams are the parameters of Client::allocate_tcp
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TcpAllocateParams {
+    // fallback_to_udp allocates a UDP relayed address instead when the server
+    // doesn't relay TCP
+    pub fallback_to_udp: bool,
+}
+
+// TcpAllocation is the result of Client::allocate_tcp
+pub struct TcpAllocation<T: 'static + RelayConnObserver + Send + Sync> {
+    pub relay_conn: RelayConn<T>,
+    // transport is the transport of the relayed address, PROTO_UDP when
+    // downgraded
+    pub transport: Protocol,
+    pub downgraded: bool,
+}
+
+// PeerAllocateParams are the parameters of Client::allocate_with_peers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAllocateParams {
+    // bind_first binds a channel to the first peer too
+    pub bind_first: bool,
+    // require_permissions fails the allocation, deallocating it, when the
+    // permissions can't be created. Otherwise the error is returned along
+    // with the RelayConn.
+    pub require_permissions: bool,
+}
+
+// PeerAllocation is the result of Client::allocate_with_peers
+pub struct PeerAllocation<T: 'static + RelayConnObserver + Send + Sync> {
+    pub relay_conn: RelayConn<T>,
+    // permission_error is why the peers couldn't be permitted or the first
+    // one bound, they are then set up on their first send as usual
+    pub permission_error: Option<Error>,
+}
+
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("stun_serv_addr", &self.stun_serv_addr)
+            .field("turn_serv_addr", &self.turn_serv_addr)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .field("realm", &self.realm)
+            .field("software", &self.software)
+            .field("rto_in_ms", &self.rto_in_ms)
+            .field("local_addr", &self.conn.local_addr().ok())
+            .field("refresh_jitter", &self.refresh_jitter)
+            .field("retry_policy", &self.retry_policy)
+            .field("on_send_raw", &self.on_send_raw.is_some())
+            .field("on_recv_raw", &self.on_recv_raw.is_some())
+            .field("send_batching", &self.send_batching)
+            .field("prearmed_auth", &self.prearmed_auth)
+            .field("auto_reallocate", &self.auto_reallocate)
+            .field("message_customizer", &self.message_customizer.is_some())
+            .field(
+                "accept_alternate_source",
+                &self.accept_alternate_source.is_some(),
+            )
+            .field("require_refresh_lifetime", &self.require_refresh_lifetime)
+            .field("expiry_guard", &self.expiry_guard)
+            .field("fail_fast_on_expiry", &self.fail_fast_on_expiry)
+            .field("server_quirks", &self.server_quirks)
+            .finish()
+    }
+}
+
+impl ClientConfig {
+    // new is a config on conn with no server, no credentials and every
+    // optional setting off. Set the rest with struct update syntax:
+    // ClientConfig { turn_serv_addr, username, password, ..ClientConfig::new(conn) }
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>) -> Self {
+        ClientConfig {
+            stun_serv_addr: String::new(),
+            turn_serv_addr: String::new(),
+            username: String::new(),
+            password: String::new(),
+            realm: String::new(),
+            software: String::new(),
+            rto_in_ms: 0,
+            conn,
+            refresh_jitter: None,
+            retry_policy: None,
+            on_send_raw: None,
+            on_recv_raw: None,
+            send_batching: None,
+            prearmed_auth: None,
+            auto_reallocate: false,
+            message_customizer: None,
+            accept_alternate_source: None,
+            require_refresh_lifetime: false,
+            expiry_guard: None,
+            fail_fast_on_expiry: false,
+            server_quirks: ServerQuirks::default(),
+        }
+    }
+
+    // validate checks the username and realm fit in their STUN attributes
+    // once prepared with prepare_credential, which Client::new does first so
+    // they don't fail the first authenticated request instead. A Rust string
+    // can't hold unpaired surrogates, NUL is the one character rejected.
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_credential("username", &self.username, MAX_USERNAME_BYTES, None)?;
+        validate_credential("realm", &self.realm, MAX_REALM_BYTES, Some(MAX_REALM_CHARS))
+    }
+}
+
+fn validate_credential(
+    field: &'static str,
+    value: &str,
+    max_bytes: usize,
+    max_chars: Option<usize>,
+) -> Result<(), Error> {
+    let invalid = |reason: String| Err(Error::CredentialInvalid { field, reason });
+    let value = prepare_credential(value);
+    if value.contains('\0') {
+        return invalid("contains NUL".to_owned());
+    }
+    if value.len() > max_bytes {
+        return invalid(format!("is {} bytes, over {}", value.len(), max_bytes));
+    }
+    if let Some(max_chars) = max_chars {
+        let chars = value.chars().count();
+        if chars > max_chars {
+            return invalid(format!("is {} characters, over {}", chars, max_chars));
+        }
+    }
+    Ok(())
 }
 
 struct ClientInternal {
@@ -71,7 +328,29 @@ struct ClientInternal {
     tr_map: Arc<Mutex<TransactionMap>>,
     binding_mgr: Arc<Mutex<BindingManager>>,
     rto_in_ms: u16,
-    read_ch_tx: Arc<Mutex<Option<mpsc::Sender<InboundData>>>>,
+    refresh_jitter: Option<f64>,
+    retry_policy: RetryPolicy,
+    read_ch_tx: Arc<Mutex<Option<InboundQueue>>>,
+    // relayed_addr is the allocation of the RelayConn, until it is closed
+    relayed_addr: Option<SocketAddr>,
+    send_batching: Option<SendBatching>,
+    // challenge is the realm and nonce of the last allocation, which a pool's
+    // clients share
+    challenge: Arc<ChallengeCache>,
+    // auth is the realm, nonce and key of the last allocation, prearmed_auth
+    // until one succeeds
+    auth: Option<PrearmedAuth>,
+    auto_reallocate: bool,
+    require_refresh_lifetime: bool,
+    expiry_guard: Duration,
+    fail_fast_on_expiry: bool,
+    server_quirks: ServerQuirks,
+    message_customizer: Option<MessageCustomizer>,
+    accept_alternate_source: Option<AlternateSourceHook>,
+    // misdirected counts the inbound packets dropped for their source
+    misdirected: Arc<AtomicU64>,
+    // transport is that of the last allocation, a reallocation asks for it
+    transport: Protocol,
 }
 
 #[async_trait]
@@ -97,6 +376,25 @@ impl RelayConnObserver for ClientInternal {
         Ok(n)
     }
 
+    // write_batch uses the batch_conn of send_batching, if any
+    async fn write_batch(&self, packets: &[Vec<u8>], to: &str) -> Result<usize, Error> {
+        match self
+            .send_batching
+            .as_ref()
+            .and_then(|send_batching| send_batching.batch_conn.as_ref())
+        {
+            Some(batch_conn) => Ok(batch_conn
+                .send_batch(packets, SocketAddr::from_str(to)?)
+                .await?),
+            None => {
+                for packet in packets {
+                    self.write_to(packet, to).await?;
+                }
+                Ok(packets.len())
+            }
+        }
+    }
+
     // PerformTransaction performs STUN transaction
     async fn perform_transaction(
         &mut self,
@@ -104,56 +402,98 @@ impl RelayConnObserver for ClientInternal {
         to: &str,
         ignore_result: bool,
     ) -> Result<TransactionResult, Error> {
-        let tr_key = base64::encode(&msg.transaction_id.0);
-
-        let mut tr = Transaction::new(TransactionConfig {
-            key: tr_key.clone(),
-            raw: msg.raw.clone(),
-            to: to.to_string(),
-            interval: self.rto_in_ms,
-            ignore_result,
-        });
-        let result_ch_rx = tr.get_result_channel();
-
-        log::trace!("start {} transaction {} to {}", msg.typ, tr_key, tr.to);
-        {
-            let mut tm = self.tr_map.lock().await;
-            tm.insert(tr_key.clone(), tr);
-        }
-
-        self.conn
-            .send_to(&msg.raw, SocketAddr::from_str(to)?)
-            .await?;
+        self.perform_transaction_with(msg, to, ignore_result, TransactionOptions::default())
+            .await
+    }
 
-        let conn2 = Arc::clone(&self.conn);
-        let tr_map2 = Arc::clone(&self.tr_map);
-        {
-            let mut tm = self.tr_map.lock().await;
-            if let Some(tr) = tm.get(&tr_key) {
-                tr.start_rtx_timer(conn2, tr_map2).await;
-            }
-        }
+    // begin_transaction starts a STUN transaction, the returned future waits
+    // for its result without the client locked
+    async fn begin_transaction(
+        &mut self,
+        msg: &Message,
+        to: &str,
+    ) -> Result<PendingTransaction, Error> {
+        self.begin_transaction_with(msg, to, TransactionOptions::default())
+            .await
+    }
 
+    async fn perform_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        ignore_result: bool,
+        opts: TransactionOptions,
+    ) -> Result<TransactionResult, Error> {
         // If dontWait is true, get the transaction going and return immediately
         if ignore_result {
+            self.transactions()
+                .start(msg, to, true, opts, &self.integrity)
+                .instrument(transaction_span(msg, to))
+                .await?;
             return Ok(TransactionResult::default());
         }
 
-        // wait_for_result waits for the transaction result
-        if let Some(mut result_ch_rx) = result_ch_rx {
-            match result_ch_rx.recv().await {
-                Some(tr) => Ok(tr),
-                None => Err(ERR_TRANSACTION_CLOSED.to_owned()),
-            }
-        } else {
-            Err(ERR_WAIT_FOR_RESULT_ON_NON_RESULT_TRANSACTION.to_owned())
+        self.begin_transaction_with(msg, to, opts).await?.await
+    }
+
+    async fn begin_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        opts: TransactionOptions,
+    ) -> Result<PendingTransaction, Error> {
+        self.transactions()
+            .begin(msg, to, opts, &self.integrity)
+            .await
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.conn.local_addr().ok()
+    }
+
+    // on_deallocated releases the allocation, so allocate can be called again
+    fn on_deallocated(&mut self, id: &AllocationId) {
+        if self.relayed_addr == Some(id.relayed_addr) {
+            self.relayed_addr = None;
         }
     }
+
+    // begin_reallocate allocates again for the RelayConn, keeping its read
+    // queue and channel numbers. The Allocates run on a copy of the
+    // client's credentials, on_reallocated keeps the outcome.
+    async fn begin_reallocate(&mut self) -> Result<PendingReallocation, Error> {
+        let mut allocator = self.allocator();
+        let transport = self.transport;
+        Ok(Box::pin(async move {
+            let (res, nonce) = allocator.authenticate(transport).await?;
+            let (relayed_addr, lifetime) = allocation_of(&res)?;
+            Ok(Reallocation {
+                relayed_addr,
+                realm: allocator.realm,
+                integrity: allocator.integrity,
+                nonce,
+                lifetime,
+            })
+        }))
+    }
+
+    fn on_reallocated(&mut self, reallocation: &Reallocation) {
+        self.relayed_addr = Some(reallocation.relayed_addr);
+        self.realm = reallocation.realm.clone();
+        self.integrity = reallocation.integrity.clone();
+        self.auth = Some(PrearmedAuth {
+            realm: reallocation.realm.text.clone(),
+            nonce: reallocation.nonce.text.clone(),
+            integrity_key: reallocation.integrity.0.clone(),
+        });
+    }
 }
 
 impl ClientInternal {
     // new returns a new Client instance. listeningAddress is the address and port to listen on, default "0.0.0.0:0"
-    async fn new(config: ClientConfig) -> Result<Self, Error> {
+    async fn new(config: ClientConfig, challenge: Arc<ChallengeCache>) -> Result<Self, Error> {
+        config.validate()?;
+
         let stun_serv_addr = if config.stun_serv_addr.is_empty() {
             String::new()
         } else {
@@ -174,11 +514,25 @@ impl ClientInternal {
             turn_serv.to_string()
         };
 
+        let conn: Arc<dyn Conn + Send + Sync> =
+            if config.on_send_raw.is_some() || config.on_recv_raw.is_some() {
+                Arc::new(InspectConn {
+                    conn: config.conn,
+                    on_send_raw: config.on_send_raw,
+                    on_recv_raw: config.on_recv_raw,
+                })
+            } else {
+                config.conn
+            };
+
         Ok(ClientInternal {
-            conn: Arc::clone(&config.conn),
+            conn,
             stun_serv_addr,
             turn_serv_addr,
-            username: Username::new(ATTR_USERNAME, config.username),
+            username: Username::new(
+                ATTR_USERNAME,
+                prepare_credential(&config.username).into_owned(),
+            ),
             password: config.password,
             realm: Realm::new(ATTR_REALM, config.realm),
             software: Software::new(ATTR_SOFTWARE, config.software),
@@ -189,11 +543,53 @@ impl ClientInternal {
             } else {
                 DEFAULT_RTO_IN_MS
             },
+            refresh_jitter: config.refresh_jitter,
+            retry_policy: config.retry_policy.unwrap_or_default(),
             integrity: MessageIntegrity::new_short_term_integrity(String::new()),
             read_ch_tx: Arc::new(Mutex::new(None)),
+            relayed_addr: None,
+            send_batching: config.send_batching,
+            challenge,
+            auth: config.prearmed_auth,
+            auto_reallocate: config.auto_reallocate,
+            require_refresh_lifetime: config.require_refresh_lifetime,
+            expiry_guard: config.expiry_guard.unwrap_or(DEFAULT_EXPIRY_GUARD),
+            fail_fast_on_expiry: config.fail_fast_on_expiry,
+            server_quirks: config.server_quirks,
+            message_customizer: config.message_customizer,
+            accept_alternate_source: config.accept_alternate_source,
+            misdirected: Arc::new(AtomicU64::new(0)),
+            transport: PROTO_UDP,
         })
     }
 
+    // transactions starts transactions like the client does, without it
+    fn transactions(&self) -> Transactions {
+        Transactions {
+            conn: Arc::clone(&self.conn),
+            tr_map: Arc::clone(&self.tr_map),
+            rto_in_ms: self.rto_in_ms,
+        }
+    }
+
+    // allocator authenticates Allocates with the client's credentials and
+    // the challenge it last allocated with
+    fn allocator(&self) -> Allocator {
+        Allocator {
+            transactions: self.transactions(),
+            turn_serv_addr: self.turn_serv_addr.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            realm: self.realm.clone(),
+            integrity: self.integrity.clone(),
+            software: self.software.clone(),
+            challenge: Arc::clone(&self.challenge),
+            auth: self.auth.clone(),
+            server_quirks: self.server_quirks,
+            message_customizer: self.message_customizer.clone(),
+        }
+    }
+
     // stun_server_addr return the STUN server address
     fn stun_server_addr(&self) -> String {
         self.stun_serv_addr.clone()
@@ -204,7 +600,12 @@ impl ClientInternal {
     // to supply incoming data, instead.
     async fn listen(&self) -> Result<(), Error> {
         let conn = Arc::clone(&self.conn);
-        let stun_serv_str = self.stun_serv_addr.clone();
+        let sources = InboundSources {
+            stun_serv_addr: self.stun_serv_addr.clone(),
+            turn_serv_addr: SocketAddr::from_str(&self.turn_serv_addr).ok(),
+            accept_alternate: self.accept_alternate_source.clone(),
+            misdirected: Arc::clone(&self.misdirected),
+        };
         let tr_map = Arc::clone(&self.tr_map);
         let read_ch_tx = Arc::clone(&self.read_ch_tx);
         let binding_mgr = Arc::clone(&self.binding_mgr);
@@ -221,20 +622,20 @@ impl ClientInternal {
                     }
                 };
 
-                log::debug!("received {} bytes of udp from {}", n, from);
+                debug_limited!("received {} bytes of udp from {}", n, from);
 
                 if let Err(err) = ClientInternal::handle_inbound(
                     &read_ch_tx,
                     &buf[..n],
                     from,
-                    &stun_serv_str,
+                    &sources,
                     &tr_map,
                     &binding_mgr,
                 )
                 .await
                 {
-                    log::debug!("exiting read loop: {}", err);
-                    break;
+                    // a bad packet is discarded, the next one may be fine
+                    debug_limited!("discarded packet from {}: {}", from, err);
                 }
             }
         });
@@ -250,10 +651,10 @@ impl ClientInternal {
     // If not handled, it is assumed that the packet is application data.
     // If an error is returned, the caller should discard the packet regardless.
     async fn handle_inbound(
-        read_ch_tx: &Arc<Mutex<Option<mpsc::Sender<InboundData>>>>,
+        read_ch_tx: &Arc<Mutex<Option<InboundQueue>>>,
         data: &[u8],
         from: SocketAddr,
-        stun_serv_str: &str,
+        sources: &InboundSources,
         tr_map: &Arc<Mutex<TransactionMap>>,
         binding_mgr: &Arc<Mutex<BindingManager>>,
     ) -> Result<(), Error> {
@@ -275,49 +676,90 @@ impl ClientInternal {
         //  - STUN message was a request
         //  - Non-STUN message from the STUN server
 
-        if is_message(data) {
-            ClientInternal::handle_stun_message(tr_map, read_ch_tx, data, from).await
-        } else if ChannelData::is_channel_data(data) {
-            ClientInternal::handle_channel_data(binding_mgr, read_ch_tx, data).await
-        } else if !stun_serv_str.is_empty() && from.to_string() == *stun_serv_str {
-            // received from STUN server but it is not a STUN message
-            Err(ERR_NON_STUNMESSAGE.to_owned())
-        } else {
-            // assume, this is an application data
-            log::trace!("non-STUN/TURN packect, unhandled");
-            Ok(())
+        match classify(data) {
+            PacketClass::StunMessage => {
+                ClientInternal::handle_stun_message(tr_map, read_ch_tx, data, from, sources).await
+            }
+            PacketClass::ChannelData { .. } => {
+                if !sources.is_turn_server(from) {
+                    sources.drop_misdirected("channel data", from);
+                    return Ok(());
+                }
+                ClientInternal::handle_channel_data(binding_mgr, read_ch_tx, data).await
+            }
+            PacketClass::Other
+                if !sources.stun_serv_addr.is_empty()
+                    && from.to_string() == sources.stun_serv_addr =>
+            {
+                // received from STUN server but it is not a STUN message
+                Err(Error::NonStunMessage)
+            }
+            PacketClass::Other => {
+                // assume, this is an application data
+                trace_limited!("non-STUN/TURN packect, unhandled");
+                Ok(())
+            }
         }
     }
 
     async fn handle_stun_message(
         tr_map: &Arc<Mutex<TransactionMap>>,
-        read_ch_tx: &Arc<Mutex<Option<mpsc::Sender<InboundData>>>>,
+        read_ch_tx: &Arc<Mutex<Option<InboundQueue>>>,
         data: &[u8],
         mut from: SocketAddr,
+        sources: &InboundSources,
     ) -> Result<(), Error> {
         let mut msg = Message::new();
         msg.raw = data.to_vec();
         msg.decode()?;
 
         if msg.typ.class == CLASS_REQUEST {
-            return Err(Error::new(format!(
-                "{} : {}",
-                *ERR_UNEXPECTED_STUNREQUEST_MESSAGE, msg
-            )));
+            return Err(Error::UnexpectedStunRequestMessage(msg.to_string()));
         }
 
         if msg.typ.class == CLASS_INDICATION {
             if msg.typ.method == METHOD_DATA {
+                if !sources.is_turn_server(from) {
+                    sources.drop_misdirected("data indication", from);
+                    return Ok(());
+                }
+
+                // a broken server's indication is dropped, it must neither
+                // end the read loop nor be passed on from a made up peer
                 let mut peer_addr = PeerAddress::default();
-                peer_addr.get_from(&msg)?;
+                let mut data = Data::default();
+                let mut icmp = Icmp::default();
+                let parsed = peer_addr.get_from(&msg).and_then(|_| {
+                    if msg.contains(ATTR_ICMP) {
+                        icmp.get_from(&msg)
+                    } else {
+                        data.get_from(&msg)
+                    }
+                });
+                if let Err(err) = parsed {
+                    debug_limited!("dropped malformed data indication: {}", err);
+                    ClientInternal::drop_malformed(read_ch_tx).await;
+                    return Ok(());
+                }
+                if peer_addr.ip.is_unspecified() || peer_addr.port == 0 {
+                    debug_limited!("dropped data indication from {}", peer_addr);
+                    ClientInternal::drop_malformed(read_ch_tx).await;
+                    return Ok(());
+                }
                 from = SocketAddr::new(peer_addr.ip, peer_addr.port);
 
-                let mut data = Data::default();
-                data.get_from(&msg)?;
+                if msg.contains(ATTR_ICMP) {
+                    debug_limited!("ICMP error received from {}: {}", from, icmp);
+                    if let Some(queue) = &*read_ch_tx.lock().await {
+                        queue.push_icmp(icmp, from);
+                    }
+                    return Ok(());
+                }
 
-                log::debug!("data indication received from {}", from);
+                debug_limited!("data indication received from {}", from);
 
-                let _ = ClientInternal::handle_inbound_relay_conn(read_ch_tx, &data.0, from).await;
+                let _ = ClientInternal::handle_inbound_relay_conn(read_ch_tx, &data.0, from, true)
+                    .await;
             }
 
             return Ok(());
@@ -328,12 +770,34 @@ impl ClientInternal {
         // - stun.ClassSuccessResponse
         // - stun.ClassErrorResponse
 
-        let tr_key = base64::encode(&msg.transaction_id.0);
+        // a response with two MESSAGE-INTEGRITY, or attributes after it, is
+        // discarded like a corrupt one, the transaction retransmits
+        if let Err(err) = check_attributes(&msg, &[ATTR_MESSAGE_INTEGRITY]) {
+            debug_limited!("discarded malformed response {}: {}", msg, err);
+            return Ok(());
+        }
+
+        let tr_key = base64::encode(msg.transaction_id.0);
 
         let mut tm = tr_map.lock().await;
-        if tm.find(&tr_key).is_none() {
-            // silently discard
-            log::debug!("no transaction for {}", msg);
+        let to = match tm.find(&tr_key) {
+            // the response to another request under the same id isn't the
+            // result, the transaction retransmits
+            Some(tr) if tr.method() != msg.typ.method => {
+                debug_limited!("discarded {} to a {} transaction", msg, tr.method());
+                return Ok(());
+            }
+            Some(tr) => tr.to.clone(),
+            None => {
+                // silently discard
+                debug_limited!("no transaction for {}", msg);
+                return Ok(());
+            }
+        };
+        // a response from elsewhere could be spoofed by anyone guessing the
+        // transaction id
+        if !sources.is_response_source(&to, from) {
+            sources.drop_misdirected("response", from);
             return Ok(());
         }
 
@@ -350,7 +814,7 @@ impl ClientInternal {
                 })
                 .await
             {
-                log::debug!("no listener for msg.raw {:?}", data);
+                debug_limited!("no listener for msg.raw {:?}", data);
             }
         }
 
@@ -359,52 +823,70 @@ impl ClientInternal {
 
     async fn handle_channel_data(
         binding_mgr: &Arc<Mutex<BindingManager>>,
-        read_ch_tx: &Arc<Mutex<Option<mpsc::Sender<InboundData>>>>,
+        read_ch_tx: &Arc<Mutex<Option<InboundQueue>>>,
         data: &[u8],
     ) -> Result<(), Error> {
         let mut ch_data = ChannelData {
             raw: data.to_vec(),
             ..Default::default()
         };
-        ch_data.decode()?;
-
-        let addr = ClientInternal::find_addr_by_channel_number(binding_mgr, ch_data.number.0)
+        // like a malformed Data indication, ChannelData that doesn't decode
+        // or is on a channel never bound is dropped
+        if let Err(err) = ch_data.decode() {
+            debug_limited!("dropped malformed channel data: {}", err);
+            ClientInternal::drop_malformed(read_ch_tx).await;
+            return Ok(());
+        }
+        let addr = match ClientInternal::find_addr_by_channel_number(binding_mgr, ch_data.number.0)
             .await
-            .ok_or_else(|| ERR_CHANNEL_BIND_NOT_FOUND.to_owned())?;
+        {
+            Some(addr) => addr,
+            None => {
+                debug_limited!("dropped channel data on unbound channel {}", ch_data.number);
+                ClientInternal::drop_malformed(read_ch_tx).await;
+                return Ok(());
+            }
+        };
 
-        log::trace!(
+        trace_limited!(
             "channel data received from {} (ch={})",
             addr,
             ch_data.number.0
         );
 
-        let _ = ClientInternal::handle_inbound_relay_conn(read_ch_tx, &ch_data.data, addr).await;
+        let _ =
+            ClientInternal::handle_inbound_relay_conn(read_ch_tx, &ch_data.data, addr, false).await;
 
         Ok(())
     }
 
-    // handle_inbound_relay_conn passes inbound data in RelayConn
+    // drop_malformed counts a malformed Data indication or ChannelData for
+    // the RelayConn
+    async fn drop_malformed(read_ch_tx: &Arc<Mutex<Option<InboundQueue>>>) {
+        if let Some(queue) = &*read_ch_tx.lock().await {
+            queue.drop_malformed();
+        }
+    }
+
+    // handle_inbound_relay_conn passes inbound data in RelayConn, indication is
+    // set for the data of a Data indication
     async fn handle_inbound_relay_conn(
-        read_ch_tx: &Arc<Mutex<Option<mpsc::Sender<InboundData>>>>,
+        read_ch_tx: &Arc<Mutex<Option<InboundQueue>>>,
         data: &[u8],
         from: SocketAddr,
+        indication: bool,
     ) -> Result<(), Error> {
         let read_ch_tx_opt = read_ch_tx.lock().await;
-        log::debug!("read_ch_tx_opt = {}", read_ch_tx_opt.is_some());
-        if let Some(tx) = &*read_ch_tx_opt {
-            log::debug!("try_send data = {:?}, from = {}", data, from);
-            if tx
-                .try_send(InboundData {
-                    data: data.to_vec(),
-                    from,
-                })
-                .is_err()
-            {
-                log::warn!("receive buffer full");
+        debug_limited!("read_ch_tx_opt = {}", read_ch_tx_opt.is_some());
+        if let Some(queue) = &*read_ch_tx_opt {
+            debug_limited!("push data = {:?}, from = {}", data, from);
+            if indication {
+                queue.push_indication(data, from)
+            } else {
+                queue.push(data, from)
             }
-            Ok(())
         } else {
-            Err(ERR_ALREADY_CLOSED.to_owned())
+            Err(Error::AlreadyClosed)
         }
     }
 
@@ -418,39 +900,40 @@ impl ClientInternal {
             let mut tm = self.tr_map.lock().await;
             tm.close_and_delete_all();
         }
+        self.relayed_addr = None;
+    }
+
+    fn binding_request(&self) -> Result<Message, Error> {
+        let attrs: Vec<Box<dyn Setter>> = if !self.software.text.is_empty() {
+            vec![
+                Box::new(TransactionId::new()),
+                Box::new(BINDING_REQUEST),
+                Box::new(self.software.clone()),
+            ]
+        } else {
+            vec![Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)]
+        };
+
+        let mut msg = Message::new();
+        msg.build(&attrs)?;
+        customize(&mut msg, self.message_customizer.as_ref());
+        Ok(msg)
     }
 
     // send_binding_request_to sends a new STUN request to the given transport address
     async fn send_binding_request_to(&mut self, to: &str) -> Result<SocketAddr, Error> {
-        let msg = {
-            let attrs: Vec<Box<dyn Setter>> = if !self.software.text.is_empty() {
-                vec![
-                    Box::new(TransactionId::new()),
-                    Box::new(BINDING_REQUEST),
-                    Box::new(self.software.clone()),
-                ]
-            } else {
-                vec![Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)]
-            };
-
-            let mut msg = Message::new();
-            msg.build(&attrs)?;
-            msg
-        };
+        let msg = self.binding_request()?;
 
         log::debug!("client.SendBindingRequestTo call PerformTransaction 1");
         let tr_res = self.perform_transaction(&msg, to, false).await?;
 
-        let mut refl_addr = XORMappedAddress::default();
-        refl_addr.get_from(&tr_res.msg)?;
-
-        Ok(SocketAddr::new(refl_addr.ip, refl_addr.port))
+        reflexive_address(&tr_res.msg)
     }
 
     // send_binding_request sends a new STUN request to the STUN server
     async fn send_binding_request(&mut self) -> Result<SocketAddr, Error> {
         if self.stun_serv_addr.is_empty() {
-            Err(ERR_STUNSERVER_ADDRESS_NOT_SET.to_owned())
+            Err(Error::StunServerAddressNotSet)
         } else {
             self.send_binding_request_to(&self.stun_serv_addr.clone())
                 .await
@@ -458,150 +941,641 @@ impl ClientInternal {
     }
 
     // find_addr_by_channel_number returns a peer address associated with the
-    // channel number on this UDPConn
+    // channel number on this UDPConn, and records the channel was seen alive
     async fn find_addr_by_channel_number(
         binding_mgr: &Arc<Mutex<BindingManager>>,
         ch_num: u16,
     ) -> Option<SocketAddr> {
-        let bm = binding_mgr.lock().await;
-        if let Some(b) = bm.find_by_number(ch_num) {
-            Some(b.addr)
-        } else {
-            None
+        let mut bm = binding_mgr.lock().await;
+        bm.seen_by_number(ch_num)
+    }
+
+    // Allocate sends a TURN allocation request for a relayed address of the
+    // given transport
+    async fn allocate(&mut self, transport: Protocol) -> Result<RelayConnConfig, Error> {
+        log::debug!("allocate check: relayed_addr = {:?}", self.relayed_addr);
+        if self.relayed_addr.is_some() {
+            return Err(Error::OneAllocateOnly);
+        }
+
+        let (res, nonce) = self.authenticate_allocate(transport).await?;
+        let (relayed_addr, lifetime) = allocation_of(&res)?;
+        self.transport = transport;
+
+        // channels bound for a previous allocation are gone with it
+        {
+            let mut binding_mgr = self.binding_mgr.lock().await;
+            *binding_mgr = BindingManager::new();
         }
+
+        let (read_ch_tx, read_ch_rx) = mpsc::channel(MAX_READ_QUEUE_SIZE);
+        let events = Arc::new(RelayConnEvents::default());
+        let inbound_overflow = Arc::new(InboundOverflow::new(Arc::clone(&events)));
+        let auto_permit = Arc::new(AutoPermit::default());
+        let inbound_filter = Arc::new(InboundFilter::default());
+        let probes = Arc::new(PeerProbes::default());
+        let subscriptions = Arc::new(PeerSubscriptions::default());
+        let traffic = Arc::new(Traffic::default());
+        {
+            let mut read_ch_tx_opt = self.read_ch_tx.lock().await;
+            *read_ch_tx_opt = Some(InboundQueue::new(
+                read_ch_tx,
+                Arc::clone(&inbound_overflow),
+                Arc::clone(&auto_permit),
+                Arc::clone(&inbound_filter),
+                Arc::clone(&probes),
+                Arc::clone(&subscriptions),
+                Arc::clone(&traffic),
+            ));
+            log::debug!("allocate: read_ch_tx_opt = {}", read_ch_tx_opt.is_some());
+        }
+        self.relayed_addr = Some(relayed_addr);
+
+        Ok(RelayConnConfig {
+            relayed_addr,
+            integrity: self.integrity.clone(),
+            nonce,
+            lifetime,
+            refresh_jitter: self.refresh_jitter,
+            max_bindings: 0,
+            keepalive_payload: vec![],
+            warn_payload_size: Some(DEFAULT_WARN_PAYLOAD_SIZE),
+            max_payload_size: None,
+            auto_permit_inbound: false,
+            retry_policy: self.retry_policy,
+            auto_reallocate: self.auto_reallocate,
+            require_refresh_lifetime: self.require_refresh_lifetime,
+            expiry_guard: self.expiry_guard,
+            fail_fast_on_expiry: self.fail_fast_on_expiry,
+            server_quirks: self.server_quirks,
+            channel_bind_policy: ChannelBindPolicy::default(),
+            dedup_window: None,
+            server_addr: SocketAddr::from_str(&self.turn_serv_addr).ok(),
+            transport: TransportKind::Udp,
+            underlying_local_addr: self.conn.local_addr().ok(),
+            message_customizer: self.message_customizer.clone(),
+            maintenance: None,
+            binding_mgr: Arc::clone(&self.binding_mgr),
+            read_ch_rx: Arc::new(ReadQueue::new(read_ch_rx)),
+            inbound_overflow,
+            auto_permit,
+            inbound_filter,
+            probes,
+            subscriptions,
+            traffic,
+            send_batching: self.send_batching.clone(),
+            close_signal: Arc::default(),
+            alloc_refreshed: Arc::default(),
+            events,
+        })
     }
 
-    // Allocate sends a TURN allocation request to the given transport address
-    async fn allocate(&mut self) -> Result<RelayConnConfig, Error> {
+    // authenticate_allocate sends an Allocate authenticated with a trusted
+    // prearmed or cached challenge, or the server's, and returns its success
+    // response and the nonce it was sent with
+    async fn authenticate_allocate(
+        &mut self,
+        transport: Protocol,
+    ) -> Result<(Message, Nonce), Error> {
+        let mut allocator = self.allocator();
+        let result = allocator.authenticate(transport).await;
+        self.realm = allocator.realm;
+        self.integrity = allocator.integrity;
+        self.auth = allocator.auth;
+        result
+    }
+}
+
+// Transactions starts the STUN transactions of a client, a copy runs those of
+// a reallocation without the ClientInternal
+#[derive(Clone)]
+struct Transactions {
+    conn: Arc<dyn Conn + Send + Sync>,
+    tr_map: Arc<Mutex<TransactionMap>>,
+    rto_in_ms: u16,
+}
+
+impl Transactions {
+    // begin starts a transaction, the returned future waits for its result.
+    // A request reusing the id of a pending transaction is signed again with
+    // integrity.
+    async fn begin(
+        &self,
+        msg: &Message,
+        to: &str,
+        opts: TransactionOptions,
+        integrity: &MessageIntegrity,
+    ) -> Result<PendingTransaction, Error> {
+        let span = transaction_span(msg, to);
+        let (tr_key, result_ch_rx) = self
+            .start(msg, to, false, opts, integrity)
+            .instrument(span.clone())
+            .await?;
+
+        let mut guard = TransactionGuard::new(Arc::clone(&self.tr_map), tr_key);
+        Ok(Box::pin(
+            async move {
+                let result = wait_for_result(result_ch_rx).await;
+                guard.disarm();
+                result
+            }
+            .instrument(span),
+        ))
+    }
+
+    // perform runs a transaction to its result
+    async fn perform(
+        &self,
+        msg: &Message,
+        to: &str,
+        integrity: &MessageIntegrity,
+    ) -> Result<TransactionResult, Error> {
+        self.begin(msg, to, TransactionOptions::default(), integrity)
+            .await?
+            .await
+    }
+
+    // start sends a request and starts its retransmissions, the read loop
+    // passes the response to the returned channel by transaction id, which is
+    // returned as the transaction's key. A request reusing the id of a
+    // pending transaction is sent with a new one.
+    async fn start(
+        &self,
+        msg: &Message,
+        to: &str,
+        ignore_result: bool,
+        opts: TransactionOptions,
+        integrity: &MessageIntegrity,
+    ) -> Result<(String, Option<mpsc::Receiver<TransactionResult>>), Error> {
+        let mut renewed = None;
+        let mut attempts = 0;
+        let (tr_key, result_ch_rx) = loop {
+            let msg = renewed.as_ref().unwrap_or(msg);
+            let tr_key = base64::encode(msg.transaction_id.0);
+            let mut tr = Transaction::new(TransactionConfig {
+                key: tr_key.clone(),
+                raw: msg.raw.clone(),
+                to: to.to_string(),
+                interval: opts.rto.map_or(self.rto_in_ms, |rto| {
+                    rto.as_millis().clamp(1, u16::MAX as u128) as u16
+                }),
+                ignore_result,
+                deadline: opts.deadline,
+            });
+            let result_ch_rx = tr.get_result_channel();
+
+            log::trace!("start {} transaction {} to {}", msg.typ, tr_key, tr.to);
+            if self.tr_map.lock().await.insert(tr_key.clone(), tr) {
+                break (tr_key, result_ch_rx);
+            }
+
+            attempts += 1;
+            log::warn!("transaction id {} of {} is in use", tr_key, msg.typ);
+            if attempts >= MAX_TRANSACTION_ID_ATTEMPTS {
+                return Err(Error::TransactionIdCollision);
+            }
+            renewed = Some(with_new_transaction_id(msg, integrity)?);
+        };
+        let msg = renewed.as_ref().unwrap_or(msg);
+
+        self.conn
+            .send_to(&msg.raw, SocketAddr::from_str(to)?)
+            .await?;
+
+        let conn2 = Arc::clone(&self.conn);
+        let tr_map2 = Arc::clone(&self.tr_map);
         {
-            let read_ch_tx = self.read_ch_tx.lock().await;
-            log::debug!("allocate check: read_ch_tx_opt = {}", read_ch_tx.is_some());
-            if read_ch_tx.is_some() {
-                return Err(ERR_ONE_ALLOCATE_ONLY.to_owned());
+            let mut tm = self.tr_map.lock().await;
+            if let Some(tr) = tm.get(&tr_key) {
+                tr.start_rtx_timer(conn2, tr_map2).await;
             }
         }
 
-        let mut msg = Message::new();
-        msg.build(&[
-            Box::new(TransactionId::new()),
-            Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
-            Box::new(RequestedTransport {
-                protocol: PROTO_UDP,
-            }),
-            Box::new(FINGERPRINT),
-        ])?;
+        Ok((tr_key, result_ch_rx))
+    }
+}
+
+// with_new_transaction_id copies msg with a new transaction id, its
+// MESSAGE-INTEGRITY and FINGERPRINT are computed again. A request signed with
+// another key than integrity can't be signed again.
+fn with_new_transaction_id(msg: &Message, integrity: &MessageIntegrity) -> Result<Message, Error> {
+    let mut old = Message::new();
+    old.raw = msg.raw.clone();
+    old.decode()?;
+    let signed = old.contains(ATTR_MESSAGE_INTEGRITY);
+    if signed {
+        integrity.check(&mut old.clone())?;
+    }
+
+    let mut renewed = Message::new();
+    renewed.build(&[Box::new(TransactionId::new()), Box::new(old.typ)])?;
+    for attr in &old.attributes.0 {
+        if attr.typ != ATTR_MESSAGE_INTEGRITY && attr.typ != ATTR_FINGERPRINT {
+            renewed.add(attr.typ, &attr.value);
+        }
+    }
+    if signed {
+        integrity.add_to(&mut renewed)?;
+    }
+    if old.contains(ATTR_FINGERPRINT) {
+        FINGERPRINT.add_to(&mut renewed)?;
+    }
+    Ok(renewed)
+}
+
+// Allocator authenticates Allocates with the credentials of a client. The
+// client allocates with one, a reallocation with a copy, which runs without
+// the ClientInternal.
+struct Allocator {
+    transactions: Transactions,
+    turn_serv_addr: String,
+    username: Username,
+    password: String,
+    realm: Realm,
+    integrity: MessageIntegrity,
+    software: Software,
+    challenge: Arc<ChallengeCache>,
+    auth: Option<PrearmedAuth>,
+    server_quirks: ServerQuirks,
+    message_customizer: Option<MessageCustomizer>,
+}
+
+impl Allocator {
+    // challenge sends an unauthenticated Allocate request, the server answers
+    // with the realm and nonce to authenticate with
+    async fn challenge(&mut self, transport: Protocol) -> Result<(Realm, Nonce), Error> {
+        let msg = {
+            let mut attrs: Vec<Box<dyn Setter>> = vec![
+                Box::new(TransactionId::new()),
+                Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+                Box::new(RequestedTransport {
+                    protocol: transport,
+                }),
+            ];
+            if !self.software.text.is_empty() {
+                attrs.push(Box::new(self.software.clone()));
+            }
+
+            let mut msg = Message::new();
+            msg.build(&attrs)?;
+            seal(
+                &mut msg,
+                self.message_customizer.as_ref(),
+                None,
+                self.server_quirks,
+            )?;
+            msg
+        };
 
         log::debug!("client.Allocate call PerformTransaction 1");
         let tr_res = self
-            .perform_transaction(&msg, &self.turn_serv_addr.clone(), false)
+            .transactions
+            .perform(&msg, &self.turn_serv_addr, &self.integrity)
             .await?;
         let res = tr_res.msg;
 
+        // a server that doesn't challenge refuses to allocate at all
+        if res.typ.class == CLASS_ERROR_RESPONSE && !res.contains(ATTR_NONCE) {
+            return Err(allocate_error(&res, transport));
+        }
+
         // Anonymous allocate failed, trying to authenticate.
         let nonce = Nonce::get_from_as(&res, ATTR_NONCE)?;
-        self.realm = Realm::get_from_as(&res, ATTR_REALM)?;
+        let realm = Realm::get_from_as(&res, ATTR_REALM)?;
+        Ok((realm, nonce))
+    }
 
-        self.integrity = MessageIntegrity::new_long_term_integrity(
-            self.username.text.clone(),
-            self.realm.text.clone(),
-            self.password.clone(),
-        );
+    // authenticate sends an Allocate authenticated with a trusted prearmed or
+    // cached challenge, or the server's, and returns its success response and
+    // the nonce it was sent with
+    async fn authenticate(&mut self, transport: Protocol) -> Result<(Message, Nonce), Error> {
+        // a prearmed or cached challenge saves the 401 round trip, it is only
+        // trusted once
+        let cached = self
+            .auth
+            .as_ref()
+            .map(|auth| {
+                (
+                    Realm::new(ATTR_REALM, auth.realm.clone()),
+                    Nonce::new(ATTR_NONCE, auth.nonce.clone()),
+                )
+            })
+            .or_else(|| self.challenge.get());
+        let mut trust_cached = cached.is_some();
+        let (mut realm, mut nonce) = match cached {
+            Some(challenge) => challenge,
+            None => self.challenge(transport).await?,
+        };
 
-        // Trying to authorize.
-        msg.build(&[
-            Box::new(TransactionId::new()),
-            Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
-            Box::new(RequestedTransport {
-                protocol: PROTO_UDP,
-            }),
-            Box::new(self.username.clone()),
-            Box::new(self.realm.clone()),
-            Box::new(nonce.clone()),
-            Box::new(self.integrity.clone()),
-            Box::new(FINGERPRINT),
-        ])?;
-
-        log::debug!("client.Allocate call PerformTransaction 2");
-        let tr_res = self
-            .perform_transaction(&msg, &self.turn_serv_addr.clone(), false)
-            .await?;
-        let res = tr_res.msg;
+        let res = loop {
+            self.realm = realm.clone();
+            self.integrity = match &self.auth {
+                Some(auth) if auth.realm == self.realm.text => {
+                    MessageIntegrity(auth.integrity_key.clone())
+                }
+                _ => MessageIntegrity(generate_auth_key(
+                    &self.username.text,
+                    &self.realm.text,
+                    &self.password,
+                )),
+            };
 
-        if res.typ.class == CLASS_ERROR_RESPONSE {
-            let mut code = ErrorCodeAttribute::default();
-            let result = code.get_from(&res);
-            if result.is_err() {
-                return Err(Error::new(format!("{}", res.typ)));
-            } else {
-                return Err(Error::new(format!("{} (error {})", res.typ, code)));
+            // Trying to authorize.
+            let msg = {
+                let mut attrs: Vec<Box<dyn Setter>> = vec![
+                    Box::new(TransactionId::new()),
+                    Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+                    Box::new(RequestedTransport {
+                        protocol: transport,
+                    }),
+                ];
+                if !self.software.text.is_empty() {
+                    attrs.push(Box::new(self.software.clone()));
+                }
+                attrs.extend(vec![
+                    Box::new(self.username.clone()) as Box<dyn Setter>,
+                    Box::new(self.realm.clone()),
+                    Box::new(nonce.clone()),
+                ]);
+
+                let mut msg = Message::new();
+                msg.build(&attrs)?;
+                seal(
+                    &mut msg,
+                    self.message_customizer.as_ref(),
+                    Some(&self.integrity),
+                    self.server_quirks,
+                )?;
+                msg
+            };
+
+            log::debug!("client.Allocate call PerformTransaction 2");
+            let tr_res = self
+                .transactions
+                .perform(&msg, &self.turn_serv_addr, &self.integrity)
+                .await?;
+            let res = tr_res.msg;
+
+            if res.typ.class == CLASS_ERROR_RESPONSE {
+                // the cached nonce went stale, the response carries a new one
+                let mut code = ErrorCodeAttribute::default();
+                let rechallenged = code.get_from(&res).is_ok()
+                    && (code.code == CODE_UNAUTHORIZED || code.code == CODE_STALE_NONCE);
+                if trust_cached && rechallenged {
+                    trust_cached = false;
+                    // the key may be what was rejected, the password decides
+                    if code.code == CODE_UNAUTHORIZED {
+                        self.auth = None;
+                    }
+                    nonce = Nonce::get_from_as(&res, ATTR_NONCE)?;
+                    if let Ok(new_realm) = Realm::get_from_as(&res, ATTR_REALM) {
+                        realm = new_realm;
+                    }
+                    continue;
+                }
+                // the transport is checked once authenticated, the nonce
+                // saves the fallback a round trip
+                if code.code == CODE_UNSUPPORTED_TRANS_PROTO {
+                    self.challenge.set(realm, nonce);
+                }
+                return Err(allocate_error(&res, transport));
             }
-        }
+            self.server_quirks.check_success(&res, &self.integrity)?;
+            break res;
+        };
+        self.auth = Some(PrearmedAuth {
+            realm: realm.text.clone(),
+            nonce: nonce.text.clone(),
+            integrity_key: self.integrity.0.clone(),
+        });
+        self.challenge.set(realm, nonce.clone());
+        Ok((res, nonce))
+    }
+}
 
-        // Getting relayed addresses from response.
-        let mut relayed = RelayedAddress::default();
-        relayed.get_from(&res)?;
-        let relayed_addr = SocketAddr::new(relayed.ip, relayed.port);
+// allocation_of reads the relayed address and lifetime of an Allocate success
+// response
+fn allocation_of(res: &Message) -> Result<(SocketAddr, Duration), Error> {
+    let mut relayed = RelayedAddress::default();
+    relayed.get_from(res)?;
+    let mut lifetime = Lifetime::default();
+    lifetime.get_from(res)?;
+    Ok((SocketAddr::new(relayed.ip, relayed.port), lifetime.0))
+}
 
-        // Getting lifetime from response
-        let mut lifetime = Lifetime::default();
-        lifetime.get_from(&res)?;
+// InboundSources are who the read loop takes packets from: a response only
+// from where its request was sent, or a source accept_alternate allows, and
+// relayed data only from the TURN server. Other packets are dropped and
+// counted as misdirected.
+struct InboundSources {
+    stun_serv_addr: String,
+    turn_serv_addr: Option<SocketAddr>,
+    accept_alternate: Option<AlternateSourceHook>,
+    misdirected: Arc<AtomicU64>,
+}
 
-        let (read_ch_tx, read_ch_rx) = mpsc::channel(MAX_READ_QUEUE_SIZE);
-        {
-            let mut read_ch_tx_opt = self.read_ch_tx.lock().await;
-            *read_ch_tx_opt = Some(read_ch_tx);
-            log::debug!("allocate: read_ch_tx_opt = {}", read_ch_tx_opt.is_some());
-        }
+impl InboundSources {
+    fn is_turn_server(&self, from: SocketAddr) -> bool {
+        self.turn_serv_addr
+            .is_none_or(|turn_serv_addr| is_expected_source(turn_serv_addr, from))
+    }
 
-        Ok(RelayConnConfig {
-            relayed_addr,
-            integrity: self.integrity.clone(),
-            nonce,
-            lifetime: lifetime.0,
-            binding_mgr: Arc::clone(&self.binding_mgr),
-            read_ch_rx: Arc::new(Mutex::new(read_ch_rx)),
-        })
+    fn is_response_source(&self, to: &str, from: SocketAddr) -> bool {
+        let to = match SocketAddr::from_str(to) {
+            Ok(to) => to,
+            Err(_) => return false,
+        };
+        is_expected_source(to, from)
+            || self
+                .accept_alternate
+                .as_ref()
+                .is_some_and(|accept_alternate| accept_alternate(to, from))
+    }
+
+    fn drop_misdirected(&self, what: &str, from: SocketAddr) {
+        debug_limited!("dropped {} from unexpected source {}", what, from);
+        self.misdirected.fetch_add(1, Ordering::Relaxed);
     }
 }
 
-// Client is a STUN server client
+// is_expected_source is whether a packet from from answers one sent to to. An
+// unspecified address is this host, which answers from one of its own.
+fn is_expected_source(to: SocketAddr, from: SocketAddr) -> bool {
+    let (to, from) = (canonical_peer_addr(to), canonical_peer_addr(from));
+    if to.ip().is_unspecified() {
+        to.port() == from.port()
+    } else {
+        to == from
+    }
+}
+
+// Client is a STUN server client. It runs on both the multi-thread and the
+// current-thread tokio runtime, listen and the RelayConn spawn their tasks on
+// the runtime they are called from. No lock is held while waiting for the
+// response of a transaction, except that a RelayConn's writes wait for its
+// Refresh and CreatePermission transactions.
 #[derive(Clone)]
 pub struct Client {
     client_internal: Arc<Mutex<ClientInternal>>,
+    misdirected: Arc<AtomicU64>,
 }
 
 impl Client {
     pub async fn new(config: ClientConfig) -> Result<Self, Error> {
-        let ci = ClientInternal::new(config).await?;
+        Client::with_challenge(config, Arc::default()).await
+    }
+
+    // with_challenge creates a client that authenticates its allocation with
+    // the realm and nonce other clients of a ClientPool got
+    pub(crate) async fn with_challenge(
+        config: ClientConfig,
+        challenge: Arc<ChallengeCache>,
+    ) -> Result<Self, Error> {
+        let ci = ClientInternal::new(config, challenge).await?;
         Ok(Client {
+            misdirected: Arc::clone(&ci.misdirected),
             client_internal: Arc::new(Mutex::new(ci)),
         })
     }
 
+    // misdirected_dropped counts the packets the read loop dropped for their
+    // source: responses from another address than their request was sent to,
+    // and Data indications and ChannelData from another than the TURN server
+    pub fn misdirected_dropped(&self) -> u64 {
+        self.misdirected.load(Ordering::Relaxed)
+    }
+
     pub async fn listen(&self) -> Result<(), Error> {
         let ci = self.client_internal.lock().await;
         ci.listen().await
     }
 
-    pub async fn allocate(&self) -> Result<impl Conn, Error> {
+    // allocate returns the relayed conn, it can be wrapped in a RelayConnStream
+    // to talk to a single peer through AsyncRead/AsyncWrite
+    pub async fn allocate(&self) -> Result<RelayConn<impl RelayConnObserver + Send + Sync>, Error> {
         let config = {
             let mut ci = self.client_internal.lock().await;
-            ci.allocate().await?
+            ci.allocate(PROTO_UDP).await?
         };
 
         Ok(RelayConn::new(Arc::clone(&self.client_internal), config))
     }
 
+    // allocate_with_retry is allocate, tried again as the policy allows.
+    // Timeouts and 5xx errors are only retried with retry_timeouts and
+    // retry_server_errors set, other errors such as 401 (Unauthorized) and
+    // 486 (Allocation Quota Reached) are returned at once. on_retry is called
+    // before each retry. The client isn't locked during the backoff, and
+    // dropping the future abandons the Allocate in flight.
+    pub async fn allocate_with_retry(
+        &self,
+        policy: RetryPolicy,
+        on_retry: Option<RetryHook>,
+    ) -> Result<RelayConn<impl RelayConnObserver + Send + Sync>, Error> {
+        let mut client_internal = Arc::clone(&self.client_internal);
+        let config = retry_notify(
+            &policy,
+            &mut client_internal,
+            |ci| Box::pin(async move { ci.lock().await.allocate(PROTO_UDP).await }),
+            on_retry.as_ref(),
+        )
+        .await?;
+
+        Ok(RelayConn::new(Arc::clone(&self.client_internal), config))
+    }
+
+    // export_auth_state returns the realm, nonce and key of the last
+    // successful allocation, to be passed as ClientConfig::prearmed_auth to a
+    // later client. It is prearmed_auth until then, none without it.
+    pub async fn export_auth_state(&self) -> Option<PrearmedAuth> {
+        let ci = self.client_internal.lock().await;
+        ci.auth.clone()
+    }
+
+    // allocate_tcp requests a TCP relayed address (RFC 6062). With
+    // fallback_to_udp a server answering 442 (Unsupported Transport Protocol)
+    // gets a UDP Allocate instead, the result is then downgraded; without it
+    // the error is Error::UnsupportedTransportProtocol. Connect and
+    // ConnectionBind aren't implemented yet, the RelayConn of a TCP
+    // allocation only keeps it and its permissions alive.
+    pub async fn allocate_tcp(
+        &self,
+        params: TcpAllocateParams,
+    ) -> Result<TcpAllocation<impl RelayConnObserver + Send + Sync>, Error> {
+        let (config, transport) = {
+            let mut ci = self.client_internal.lock().await;
+            match ci.allocate(PROTO_TCP).await {
+                Ok(config) => (config, PROTO_TCP),
+                Err(Error::UnsupportedTransportProtocol(_)) if params.fallback_to_udp => {
+                    log::debug!("{} doesn't relay TCP, allocating UDP", ci.turn_serv_addr);
+                    (ci.allocate(PROTO_UDP).await?, PROTO_UDP)
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        Ok(TcpAllocation {
+            relay_conn: RelayConn::new(Arc::clone(&self.client_internal), config),
+            transport,
+            downgraded: transport != PROTO_TCP,
+        })
+    }
+
+    // allocate_with_peers is allocate, followed by a single CreatePermission
+    // for peers before returning, see RelayConn::permit_peers. Sending to them
+    // then doesn't wait for a transaction.
+    pub async fn allocate_with_peers(
+        &self,
+        peers: &[SocketAddr],
+        params: PeerAllocateParams,
+    ) -> Result<PeerAllocation<impl RelayConnObserver + Send + Sync>, Error> {
+        let mut relay_conn = self.allocate().await?;
+        if peers.is_empty() {
+            return Ok(PeerAllocation {
+                relay_conn,
+                permission_error: None,
+            });
+        }
+
+        let permission_error = match relay_conn.permit_peers(peers, params.bind_first).await {
+            Ok(()) => None,
+            Err(err) if params.require_permissions => {
+                if let Err(close_err) = relay_conn.close().await {
+                    log::debug!("failed to deallocate: {}", close_err);
+                }
+                return Err(err);
+            }
+            Err(err) => {
+                log::debug!("failed to permit {:?}: {}", peers, err);
+                Some(err)
+            }
+        };
+
+        Ok(PeerAllocation {
+            relay_conn,
+            permission_error,
+        })
+    }
+
     pub async fn close(&self) -> Result<(), Error> {
         let mut ci = self.client_internal.lock().await;
         ci.close().await;
         Ok(())
     }
 
-    // send_binding_request_to sends a new STUN request to the given transport address
-    pub async fn send_binding_request_to(&self, to: &str) -> Result<SocketAddr, Error> {
-        let mut ci = self.client_internal.lock().await;
-        ci.send_binding_request_to(to).await
+    // send_binding_request_to sends a STUN Binding request to any STUN server
+    // through the client's socket and returns the reflexive address it saw.
+    // The client is only locked to send the request, requests to several
+    // servers run concurrently.
+    pub async fn send_binding_request_to(&self, server: SocketAddr) -> Result<SocketAddr, Error> {
+        let pending = {
+            let mut ci = self.client_internal.lock().await;
+            let msg = ci.binding_request()?;
+            ci.begin_transaction(&msg, &server.to_string()).await?
+        };
+        let tr_res = pending.await?;
+
+        reflexive_address(&tr_res.msg)
     }
 
     // send_binding_request sends a new STUN request to the STUN server
@@ -610,3 +1584,56 @@ impl Client {
         ci.send_binding_request().await
     }
 }
+
+// transaction_span is the span of a STUN transaction, without the trace
+// feature it is a no-op and the arguments go unused
+#[allow(unused_variables)]
+fn transaction_span(msg: &Message, to: &str) -> crate::trace::Span {
+    turn_span!(
+        "stun_transaction",
+        method = %msg.typ.method,
+        transaction_id = %base64::encode(msg.transaction_id.0),
+        server_addr = %to,
+    )
+}
+
+// wait_for_result waits for the result of a transaction started with
+// start_transaction
+async fn wait_for_result(
+    result_ch_rx: Option<mpsc::Receiver<TransactionResult>>,
+) -> Result<TransactionResult, Error> {
+    if let Some(mut result_ch_rx) = result_ch_rx {
+        match result_ch_rx.recv().await {
+            Some(tr) => {
+                if let Some(err) = tr.err {
+                    Err(err)
+                } else {
+                    Ok(tr)
+                }
+            }
+            None => Err(Error::TransactionClosed),
+        }
+    } else {
+        Err(Error::WaitForResultOnNonResultTransaction)
+    }
+}
+
+// reflexive_address returns the XOR-MAPPED-ADDRESS of a Binding response
+fn reflexive_address(res: &Message) -> Result<SocketAddr, Error> {
+    let mut refl_addr = XORMappedAddress::default();
+    refl_addr.get_from(res)?;
+
+    Ok(SocketAddr::new(refl_addr.ip, refl_addr.port))
+}
+
+// allocate_error is the error of an Allocate error response, with 442 told
+// apart so a TCP allocation can fall back to UDP
+fn allocate_error(res: &Message, transport: Protocol) -> Error {
+    match Error::from_error_response(res) {
+        Error::Protocol {
+            code: UNSUPPORTED_TRANSPORT_PROTOCOL,
+            ..
+        } => Error::UnsupportedTransportProtocol(transport),
+        err => err,
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+// PATH_RTT_GAIN is the weight of each new round trip in path_rtt, 1/8 as for
+// the smoothed RTT of RFC 6298
+const PATH_RTT_GAIN: f64 = 0.125;
+
+#[derive(Debug)]
+struct PathSamples {
+    last_rtt: Option<Duration>,
+    path_rtt: Option<Duration>,
+    last_success: Instant,
+    last_failure: Option<Instant>,
+}
+
+// PathStats records the round trips of the transactions a RelayConn makes
+// with the TURN server. It is shared with the channel bind tasks, so it is
+// behind a std mutex that is never held across an await.
+#[derive(Debug)]
+pub(crate) struct PathStats {
+    samples: Mutex<PathSamples>,
+}
+
+impl PathStats {
+    // new counts the allocation that was just made as the last success
+    pub(crate) fn new() -> Self {
+        PathStats {
+            samples: Mutex::new(PathSamples {
+                last_rtt: None,
+                path_rtt: None,
+                last_success: Instant::now(),
+                last_failure: None,
+            }),
+        }
+    }
+
+    fn samples(&self) -> std::sync::MutexGuard<'_, PathSamples> {
+        self.samples.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn record_success(&self, rtt: Duration) {
+        let mut samples = self.samples();
+        samples.last_rtt = Some(rtt);
+        samples.path_rtt = Some(match samples.path_rtt {
+            Some(path_rtt) => path_rtt.mul_f64(1.0 - PATH_RTT_GAIN) + rtt.mul_f64(PATH_RTT_GAIN),
+            None => rtt,
+        });
+        samples.last_success = Instant::now();
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.samples().last_failure = Some(Instant::now());
+    }
+
+    pub(crate) fn last_rtt(&self) -> Option<Duration> {
+        self.samples().last_rtt
+    }
+
+    pub(crate) fn path_rtt(&self) -> Option<Duration> {
+        self.samples().path_rtt
+    }
+
+    pub(crate) fn last_success(&self) -> Instant {
+        self.samples().last_success
+    }
+
+    pub(crate) fn last_failure(&self) -> Option<Instant> {
+        self.samples().last_failure
+    }
+}
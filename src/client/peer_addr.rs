@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+// canonical_peer_addr replaces a v4-mapped IPv6 address, e.g.
+// ::ffff:203.0.113.7, with the IPv4 address it maps, so both forms are the
+// same peer. Sent as is, the mapped form is an IPv6 XOR-PEER-ADDRESS that an
+// IPv4 allocation rejects with 443 (Peer Address Family Mismatch).
+pub(crate) fn canonical_peer_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+// MappedPeers remembers the IPv4 peers last sent to in the v4-mapped form,
+// recv_from reports the data of such a peer from that form too
+#[derive(Debug, Default)]
+pub(crate) struct MappedPeers {
+    ips: Mutex<HashSet<IpAddr>>,
+}
+
+impl MappedPeers {
+    fn ips(&self) -> std::sync::MutexGuard<'_, HashSet<IpAddr>> {
+        self.ips.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // record notes the form addr is given in by the application
+    pub(crate) fn record(&self, addr: SocketAddr) {
+        let canonical = canonical_peer_addr(addr);
+        let mut ips = self.ips();
+        if canonical != addr {
+            ips.insert(canonical.ip());
+        } else if !ips.is_empty() {
+            ips.remove(&canonical.ip());
+        }
+    }
+
+    // to_caller is from in the form its peer was last sent to in
+    pub(crate) fn to_caller(&self, from: SocketAddr) -> SocketAddr {
+        match from.ip() {
+            IpAddr::V4(ip) if self.ips().contains(&from.ip()) => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), from.port())
+            }
+            _ => from,
+        }
+    }
+}
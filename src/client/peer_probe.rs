@@ -0,0 +1,91 @@
+use crate::error::Error;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+// PeerProbes taps the first packet from each peer RelayConn::probe_peer waits
+// on out of the read queue, packets from other peers are queued as usual. It
+// is shared by the RelayConn and its InboundQueue, which only takes the lock
+// while a probe is running.
+#[derive(Debug, Default)]
+pub(crate) struct PeerProbes {
+    active: AtomicUsize,
+    // waiting has the probed peers, the sender is taken by the reply
+    waiting: Mutex<HashMap<SocketAddr, Option<oneshot::Sender<Instant>>>>,
+}
+
+impl PeerProbes {
+    fn waiting(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Option<oneshot::Sender<Instant>>>> {
+        self.waiting.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // start taps the next packet from peer until the returned PeerProbe is
+    // dropped, a peer is probed once at a time
+    pub(crate) fn start(self: &Arc<Self>, peer: SocketAddr) -> Result<PeerProbe, Error> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut waiting = self.waiting();
+            if waiting.contains_key(&peer) {
+                return Err(Error::ProbeInProgress(peer));
+            }
+            waiting.insert(peer, Some(tx));
+        }
+        self.active.fetch_add(1, Ordering::SeqCst);
+
+        Ok(PeerProbe {
+            probes: Arc::clone(self),
+            peer,
+            rx,
+        })
+    }
+
+    // tap reports if the packet from peer was the reply of a probe, it then
+    // isn't queued
+    pub(crate) fn tap(&self, from: SocketAddr) -> bool {
+        if self.active.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        let tx = match self.waiting().get_mut(&from) {
+            Some(tx) => tx.take(),
+            None => return false,
+        };
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(Instant::now());
+                true
+            }
+            // a later packet of a peer that already replied
+            None => false,
+        }
+    }
+}
+
+// PeerProbe is a running probe, see PeerProbes::start
+#[derive(Debug)]
+pub(crate) struct PeerProbe {
+    probes: Arc<PeerProbes>,
+    peer: SocketAddr,
+    rx: oneshot::Receiver<Instant>,
+}
+
+impl PeerProbe {
+    // reply waits for the packet from the peer and returns when it arrived
+    pub(crate) async fn reply(&mut self) -> Result<Instant, Error> {
+        (&mut self.rx).await.map_err(|_| Error::AlreadyClosed)
+    }
+}
+
+impl Drop for PeerProbe {
+    fn drop(&mut self) {
+        self.probes.waiting().remove(&self.peer);
+        self.probes.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
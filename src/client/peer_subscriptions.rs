@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+#[derive(Debug)]
+struct Subscriber {
+    tx: mpsc::Sender<Bytes>,
+    dropped: u64,
+}
+
+// PeerSubscriptions routes the data of subscribed peers to their own channel
+// instead of the read queue, see RelayConn::subscribe_peer. It is shared by
+// the RelayConn and its InboundQueue, which only takes the lock while a peer
+// is subscribed.
+#[derive(Debug, Default)]
+pub(crate) struct PeerSubscriptions {
+    active: AtomicUsize,
+    subscribers: Mutex<HashMap<SocketAddr, Subscriber>>,
+}
+
+impl PeerSubscriptions {
+    fn subscribers(&self) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Subscriber>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    // subscribe routes the data from peer to the returned receiver, until it
+    // is dropped. A previous subscription of peer ends.
+    pub(crate) fn subscribe(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        capacity: usize,
+    ) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        {
+            let mut subscribers = self.subscribers();
+            let subscriber = Subscriber {
+                tx: tx.clone(),
+                dropped: 0,
+            };
+            if subscribers.insert(peer, subscriber).is_none() {
+                self.active.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // the routing entry goes with the receiver
+        let subscriptions = Arc::downgrade(self);
+        tokio::spawn(async move {
+            tx.closed().await;
+            if let Some(subscriptions) = subscriptions.upgrade() {
+                subscriptions.unsubscribe(peer, &tx);
+            }
+        });
+
+        rx
+    }
+
+    fn unsubscribe(&self, peer: SocketAddr, tx: &mpsc::Sender<Bytes>) {
+        let mut subscribers = self.subscribers();
+        if subscribers
+            .get(&peer)
+            .is_some_and(|subscriber| subscriber.tx.same_channel(tx))
+        {
+            subscribers.remove(&peer);
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // route reports if the data from peer went to a subscription, it is
+    // dropped and counted when the subscription's channel is full
+    pub(crate) fn route(&self, data: &[u8], from: SocketAddr) -> bool {
+        if self.active.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        let mut subscribers = self.subscribers();
+        let subscriber = match subscribers.get_mut(&from) {
+            Some(subscriber) => subscriber,
+            None => return false,
+        };
+        match subscriber.tx.try_send(Bytes::copy_from_slice(data)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                subscriber.dropped += 1;
+                trace_limited!("subscription of {} full, data dropped", from);
+                true
+            }
+            // the receiver is being dropped, the read queue gets the data
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // dropped is the data dropped from the subscription of peer
+    pub(crate) fn dropped(&self, peer: &SocketAddr) -> u64 {
+        self.subscribers()
+            .get(peer)
+            .map_or(0, |subscriber| subscriber.dropped)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
@@ -1,22 +1,27 @@
 #[cfg(test)]
 mod periodic_timer_test;
 
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Mutex, Notify};
 use tokio::time::Duration;
 
+use std::fmt;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
 pub enum TimerIdRefresh {
+    #[default]
     Alloc,
     Perms,
 }
 
-impl Default for TimerIdRefresh {
-    fn default() -> Self {
-        TimerIdRefresh::Alloc
+impl fmt::Display for TimerIdRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerIdRefresh::Alloc => write!(f, "alloc"),
+            TimerIdRefresh::Perms => write!(f, "perms"),
+        }
     }
 }
 
@@ -31,19 +36,64 @@ pub trait PeriodicTimerTimeoutHandler {
 pub struct PeriodicTimer {
     id: TimerIdRefresh,
     interval: Duration,
+    jitter: f64,
+    reset: Arc<Notify>,
     close_tx: Option<mpsc::Sender<()>>,
 }
 
+impl fmt::Debug for PeriodicTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeriodicTimer")
+            .field("id", &self.id)
+            .field("interval", &self.interval)
+            .field("jitter", &self.jitter)
+            .field("running", &self.is_running())
+            .finish()
+    }
+}
+
+impl fmt::Display for PeriodicTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} timer every {:?} ({})",
+            self.id,
+            self.interval,
+            if self.is_running() {
+                "running"
+            } else {
+                "stopped"
+            }
+        )
+    }
+}
+
 impl PeriodicTimer {
     // create a new timer
     pub fn new(id: TimerIdRefresh, interval: Duration) -> Self {
         PeriodicTimer {
             id,
             interval,
+            jitter: 0.0,
+            reset: Arc::default(),
             close_tx: None,
         }
     }
 
+    // with_jitter randomizes every period by up to jitter of the interval
+    // either way, jitter must be within 0.0..1.0
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    // with_reset starts the current period over when reset is notified,
+    // without calling the handler
+    pub fn with_reset(mut self, reset: Arc<Notify>) -> Self {
+        self.reset = reset;
+        self
+    }
+
     // Start starts the timer.
     pub fn start<T: 'static + PeriodicTimerTimeoutHandler + std::marker::Send>(
         &mut self,
@@ -56,11 +106,14 @@ impl PeriodicTimer {
 
         let (close_tx, mut close_rx) = mpsc::channel(1);
         let interval = self.interval;
+        let jitter = self.jitter;
         let id = self.id;
+        let reset = Arc::clone(&self.reset);
+        state_event!("timer {:?} started with interval {:?}", id, interval);
 
         tokio::spawn(async move {
             loop {
-                let timer = tokio::time::sleep(interval);
+                let timer = tokio::time::sleep(jitter_interval(interval, jitter));
                 tokio::pin!(timer);
 
                 tokio::select! {
@@ -68,6 +121,7 @@ impl PeriodicTimer {
                         let mut handler = timeout_handler.lock().await;
                         handler.on_timeout(id).await;
                     }
+                    _ = reset.notified() => {}
                     _ = close_rx.recv() => break,
                 }
             }
@@ -79,7 +133,9 @@ impl PeriodicTimer {
 
     // Stop stops the timer.
     pub fn stop(&mut self) {
-        self.close_tx.take();
+        if self.close_tx.take().is_some() {
+            state_event!("timer {:?} stopped", self.id);
+        }
     }
 
     // is_running tests if the timer is running.
@@ -88,3 +144,11 @@ impl PeriodicTimer {
         self.close_tx.is_some()
     }
 }
+
+// jitter_interval returns interval scaled by a random factor within 1 ± jitter
+pub(crate) fn jitter_interval(interval: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return interval;
+    }
+    interval.mul_f64(1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0))
+}
@@ -1,6 +1,6 @@
 use super::*;
 
-use util::Error;
+use crate::error::Error;
 
 struct DummyPeriodicTimerTimeoutHandler;
 
@@ -11,10 +11,10 @@ impl PeriodicTimerTimeoutHandler for DummyPeriodicTimerTimeoutHandler {
     }
 }
 
-#[tokio::test]
+#[tokio::test(start_paused = true)]
 async fn test_periodic_timer() -> Result<(), Error> {
     let timer_id = TimerIdRefresh::Perms;
-    let mut rt = PeriodicTimer::new(timer_id, Duration::from_millis(50));
+    let mut rt = PeriodicTimer::new(timer_id, Duration::from_secs(60));
     let dummy1 = Arc::new(Mutex::new(DummyPeriodicTimerTimeoutHandler {}));
     let dummy2 = Arc::clone(&dummy1);
 
@@ -24,15 +24,74 @@ async fn test_periodic_timer() -> Result<(), Error> {
     assert!(ok, "should be true");
     assert!(rt.is_running(), "should be running");
 
-    tokio::time::sleep(Duration::from_millis(100)).await;
+    tokio::time::sleep(Duration::from_secs(120)).await;
 
     let ok = rt.start(dummy2);
     assert!(!ok, "start again is noop");
 
-    tokio::time::sleep(Duration::from_millis(120)).await;
+    tokio::time::sleep(Duration::from_secs(150)).await;
     rt.stop();
 
     assert!(!rt.is_running(), "should not be running");
 
     Ok(())
 }
+
+struct RecordingTimeoutHandler {
+    fired: Vec<tokio::time::Instant>,
+}
+
+#[async_trait]
+impl PeriodicTimerTimeoutHandler for RecordingTimeoutHandler {
+    async fn on_timeout(&mut self, _id: TimerIdRefresh) {
+        self.fired.push(tokio::time::Instant::now());
+    }
+}
+
+#[test]
+fn test_jitter_interval_bounds() {
+    let interval = Duration::from_secs(300);
+    assert_eq!(interval, jitter_interval(interval, 0.0));
+
+    for _ in 0..1000 {
+        let jittered = jitter_interval(interval, 0.5);
+        assert!(
+            jittered >= Duration::from_secs(150) && jittered <= Duration::from_secs(450),
+            "{:?} is outside 300s ±50%",
+            jittered
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_periodic_timer_jitter_refreshes_before_expiry() -> Result<(), Error> {
+    // an allocation refresh timer runs every lifetime/2, at the largest jitter
+    // every period still ends well before the lifetime
+    let lifetime = Duration::from_secs(600);
+    let mut rt = PeriodicTimer::new(TimerIdRefresh::Alloc, lifetime / 2).with_jitter(0.5);
+    let handler = Arc::new(Mutex::new(RecordingTimeoutHandler { fired: vec![] }));
+
+    let started = tokio::time::Instant::now();
+    rt.start(Arc::clone(&handler));
+    tokio::time::sleep(Duration::from_secs(3600)).await;
+    rt.stop();
+
+    let handler = handler.lock().await;
+    assert!(
+        handler.fired.len() >= 6,
+        "fired {} times",
+        handler.fired.len()
+    );
+    let mut last = started;
+    for fired in &handler.fired {
+        let period = fired.duration_since(last);
+        assert!(
+            period < lifetime,
+            "refresh after {:?} would let the allocation expire",
+            period
+        );
+        last = *fired;
+    }
+
+    Ok(())
+}
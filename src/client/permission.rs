@@ -1,23 +1,28 @@
+use super::peer_addr::canonical_peer_addr;
+
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub(crate) enum PermState {
+// PermState is the state of the permission for a peer's IP
+#[derive(Default, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PermState {
+    #[default]
     Idle,
     Permitted,
 }
 
-impl Default for PermState {
-    fn default() -> Self {
-        PermState::Idle
-    }
-}
-
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug)]
 pub(crate) struct Permission {
     st: PermState,
 }
 
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "permission {:?}", self.st)
+    }
+}
+
 impl Permission {
     pub(crate) fn set_state(&mut self, state: PermState) {
         self.st = state;
@@ -28,8 +33,8 @@ impl Permission {
     }
 }
 
-// Thread-safe Permission map
-#[derive(Default)]
+// Thread-safe Permission map, keyed by the canonical IP of the peers
+#[derive(Default, Debug)]
 pub(crate) struct PermissionMap {
     perm_map: HashMap<String, Permission>,
 }
@@ -42,15 +47,21 @@ impl PermissionMap {
     }
 
     pub(crate) fn insert(&mut self, addr: &SocketAddr, p: Permission) {
+        let addr = canonical_peer_addr(*addr);
+        state_event!("permission for {} {:?}", addr.ip(), p.state());
         self.perm_map.insert(addr.ip().to_string(), p);
     }
 
     pub(crate) fn find(&self, addr: &SocketAddr) -> Option<&Permission> {
-        self.perm_map.get(&addr.ip().to_string())
+        self.perm_map
+            .get(&canonical_peer_addr(*addr).ip().to_string())
     }
 
     pub(crate) fn delete(&mut self, addr: &SocketAddr) {
-        self.perm_map.remove(&addr.ip().to_string());
+        let addr = canonical_peer_addr(*addr);
+        if self.perm_map.remove(&addr.ip().to_string()).is_some() {
+            state_event!("permission for {} deleted", addr.ip());
+        }
     }
 
     pub(crate) fn addrs(&self) -> Vec<SocketAddr> {
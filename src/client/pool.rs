@@ -0,0 +1,141 @@
+use super::relay_conn::*;
+use super::retry::RetryPolicy;
+use super::*;
+use crate::auth::REDACTED;
+use crate::error::Error;
+
+use stun::textattrs::{Nonce, Realm};
+
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use util::Conn;
+
+// ChallengeCache is the realm and nonce the TURN server last challenged a
+// pool's clients with. A client with a cached challenge sends its Allocate
+// authenticated right away instead of getting a 401 first.
+#[derive(Default)]
+pub(crate) struct ChallengeCache {
+    challenge: std::sync::Mutex<Option<(Realm, Nonce)>>,
+}
+
+impl ChallengeCache {
+    pub(crate) fn get(&self) -> Option<(Realm, Nonce)> {
+        self.challenge
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set(&self, realm: Realm, nonce: Nonce) {
+        *self.challenge.lock().unwrap_or_else(|err| err.into_inner()) = Some((realm, nonce));
+    }
+}
+
+// ClientPoolConfig is ClientConfig without the conn, ClientPool::allocate_on
+// takes one per allocation
+#[derive(Clone, Default)]
+pub struct ClientPoolConfig {
+    pub turn_serv_addr: String,
+    pub username: String,
+    pub password: String,
+    pub realm: String,
+    pub software: String,
+    pub rto_in_ms: u16,
+    pub refresh_jitter: Option<f64>,
+    pub retry_policy: Option<RetryPolicy>,
+}
+
+impl fmt::Debug for ClientPoolConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientPoolConfig")
+            .field("turn_serv_addr", &self.turn_serv_addr)
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .field("realm", &self.realm)
+            .field("software", &self.software)
+            .field("rto_in_ms", &self.rto_in_ms)
+            .field("refresh_jitter", &self.refresh_jitter)
+            .field("retry_policy", &self.retry_policy)
+            .finish()
+    }
+}
+
+type PooledAllocation = (Client, Arc<Mutex<RelayConnInternal<ClientInternal>>>);
+
+// ClientPool makes allocations on one TURN server from distinct sockets, each
+// with its own client and read loop. The clients share the server's realm and
+// nonce, so only the first allocation is challenged. The RelayConns are
+// independent, closing one leaves the others allocated.
+pub struct ClientPool {
+    config: ClientPoolConfig,
+    challenge: Arc<ChallengeCache>,
+    allocations: Mutex<Vec<PooledAllocation>>,
+}
+
+impl ClientPool {
+    pub fn new(config: ClientPoolConfig) -> Self {
+        ClientPool {
+            config,
+            challenge: Arc::default(),
+            allocations: Mutex::new(vec![]),
+        }
+    }
+
+    // allocate_on listens on conn and allocates through it
+    pub async fn allocate_on(
+        &self,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<RelayConn<impl RelayConnObserver + Send + Sync>, Error> {
+        let client = Client::with_challenge(
+            ClientConfig {
+                turn_serv_addr: self.config.turn_serv_addr.clone(),
+                username: self.config.username.clone(),
+                password: self.config.password.clone(),
+                realm: self.config.realm.clone(),
+                software: self.config.software.clone(),
+                rto_in_ms: self.config.rto_in_ms,
+                refresh_jitter: self.config.refresh_jitter,
+                retry_policy: self.config.retry_policy,
+                ..ClientConfig::new(conn)
+            },
+            Arc::clone(&self.challenge),
+        )
+        .await?;
+        client.listen().await?;
+
+        let config = {
+            let mut ci = client.client_internal.lock().await;
+            ci.allocate(PROTO_UDP).await
+        };
+        let config = match config {
+            Ok(config) => config,
+            Err(err) => {
+                client.close().await?;
+                return Err(err);
+            }
+        };
+
+        let relay_conn = RelayConn::new(Arc::clone(&client.client_internal), config);
+        let mut allocations = self.allocations.lock().await;
+        allocations.push((client, relay_conn.internal()));
+        Ok(relay_conn)
+    }
+
+    // close deallocates every allocation not closed yet and closes the
+    // clients, the first error is returned after all are closed
+    pub async fn close(&self) -> Result<(), Error> {
+        let allocations: Vec<PooledAllocation> = self.allocations.lock().await.drain(..).collect();
+
+        let mut result = Ok(());
+        for (client, relay_conn) in allocations {
+            let closed = relay_conn.lock().await.close().await;
+            if result.is_ok() {
+                result = closed;
+            }
+            client.close().await?;
+        }
+        result
+    }
+}
@@ -0,0 +1,158 @@
+#[cfg(all(test, feature = "server"))]
+mod quinn_test;
+
+use super::relay_conn::*;
+
+use ::quinn::udp::{RecvMeta, Transmit};
+use ::quinn::{AsyncUdpSocket, UdpPoller};
+use util::Conn;
+
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::sync::mpsc;
+
+// MAX_SEND_QUEUE_SIZE bounds the datagrams waiting for the send task, try_send
+// returns WouldBlock once it is full
+const MAX_SEND_QUEUE_SIZE: usize = 256;
+
+type SendQueueTx = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+// RelayUdpSocket runs a quinn Endpoint over a RelayConn, see
+// quinn::Endpoint::new_with_abstract_socket. The endpoint's address is the
+// relayed address. Sends are handed to a task that calls send_to, so permission
+// and channel bind transactions don't block the endpoint. ECN is not carried
+// by TURN and is always reported as unset.
+pub struct RelayUdpSocket<T: 'static + RelayConnObserver + Send + Sync> {
+    relay_conn: Arc<RelayConn<T>>,
+    send_tx: SendQueueTx,
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> RelayUdpSocket<T> {
+    // new spawns the send task, it must be called within a tokio runtime
+    pub fn new(relay_conn: Arc<RelayConn<T>>) -> Self {
+        let (send_tx, mut send_rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(MAX_SEND_QUEUE_SIZE);
+
+        let relay_conn2 = Arc::clone(&relay_conn);
+        tokio::spawn(async move {
+            while let Some((data, to)) = send_rx.recv().await {
+                if let Err(err) = relay_conn2.send_to(&data, to).await {
+                    log::warn!("relay udp socket failed to send to {}: {}", to, err);
+                }
+            }
+        });
+
+        RelayUdpSocket {
+            relay_conn,
+            send_tx,
+        }
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> fmt::Debug for RelayUdpSocket<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayUdpSocket")
+            .field("local_addr", &self.relay_conn.local_addr().ok())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> AsyncUdpSocket for RelayUdpSocket<T> {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(RelayUdpPoller {
+            send_tx: self.send_tx.clone(),
+            reserve_fut: std::sync::Mutex::new(None),
+        })
+    }
+
+    fn try_send(&self, transmit: &Transmit<'_>) -> io::Result<()> {
+        // max_transmit_segments is 1, so the contents are a single datagram
+        match self
+            .send_tx
+            .try_send((transmit.contents.to_vec(), transmit.destination))
+        {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(io::ErrorKind::ConnectionAborted.into())
+            }
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let (buf, meta) = match (bufs.first_mut(), meta.first_mut()) {
+            (Some(buf), Some(meta)) => (buf, meta),
+            _ => return Poll::Ready(Ok(0)),
+        };
+
+        match self.relay_conn.poll_recv_from(cx, buf) {
+            Poll::Ready(Ok((n, from))) => {
+                *meta = RecvMeta {
+                    addr: from,
+                    len: n,
+                    stride: n,
+                    ecn: None,
+                    dst_ip: None,
+                };
+                Poll::Ready(Ok(1))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.relay_conn.local_addr()
+    }
+}
+
+type ReserveFuture = Pin<Box<dyn Future<Output = Result<(), mpsc::error::SendError<()>>> + Send>>;
+
+// RelayUdpPoller waits for room in the send queue
+struct RelayUdpPoller {
+    send_tx: SendQueueTx,
+    // the mutex is never contended, it makes the boxed future Sync
+    reserve_fut: std::sync::Mutex<Option<ReserveFuture>>,
+}
+
+impl fmt::Debug for RelayUdpPoller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayUdpPoller").finish_non_exhaustive()
+    }
+}
+
+impl UdpPoller for RelayUdpPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let reserve_fut = this
+            .reserve_fut
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+
+        if reserve_fut.is_none() {
+            let send_tx = this.send_tx.clone();
+            *reserve_fut = Some(Box::pin(async move {
+                // the permit is dropped right away, try_send takes the slot
+                send_tx.reserve_owned().await.map(|_| ())
+            }));
+        }
+
+        let result = match reserve_fut.as_mut().map(|f| f.as_mut().poll(cx)) {
+            Some(Poll::Ready(result)) => result,
+            _ => return Poll::Pending,
+        };
+        *reserve_fut = None;
+
+        Poll::Ready(result.map_err(|_| io::ErrorKind::ConnectionAborted.into()))
+    }
+}
@@ -0,0 +1,157 @@
+use super::*;
+use crate::test_util::*;
+
+use ::quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use ::quinn::{
+    ClientConfig as QuinnClientConfig, Endpoint, EndpointConfig, ServerConfig as QuinnServerConfig,
+    TokioRuntime,
+};
+
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn test_quinn_over_relay() -> Result<(), Box<dyn std::error::Error>> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client_a = start_client(server_addr).await?;
+    let client_b = start_client(server_addr).await?;
+    let relay_a = Arc::new(client_a.allocate().await?);
+    let relay_b = Arc::new(client_b.allocate().await?);
+    let (addr_a, addr_b) = (relay_a.local_addr()?, relay_b.local_addr()?);
+
+    // install permissions in both directions, quinn drops the stray datagrams
+    relay_a.send_to(b"permission", addr_b).await?;
+    relay_b.send_to(b"permission", addr_a).await?;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+    let cert_der = CertificateDer::from(cert.cert);
+    let key_der = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+    let server_config =
+        QuinnServerConfig::with_single_cert(vec![cert_der.clone()], key_der.into())?;
+    let endpoint_a = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        Some(server_config),
+        Arc::new(RelayUdpSocket::new(relay_a)),
+        Arc::new(TokioRuntime),
+    )?;
+
+    let mut roots = ::quinn::rustls::RootCertStore::empty();
+    roots.add(cert_der)?;
+    let mut endpoint_b = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        None,
+        Arc::new(RelayUdpSocket::new(relay_b)),
+        Arc::new(TokioRuntime),
+    )?;
+    endpoint_b
+        .set_default_client_config(QuinnClientConfig::with_root_certificates(Arc::new(roots))?);
+    assert_eq!(addr_b, endpoint_b.local_addr()?);
+
+    let accept = tokio::spawn(async move {
+        let conn = endpoint_a.accept().await.expect("endpoint closed").await?;
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let data = recv.read_to_end(1024).await?;
+        send.write_all(&data).await?;
+        send.finish()?;
+        conn.closed().await;
+        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    });
+
+    let conn = endpoint_b.connect(addr_a, "localhost")?.await?;
+    assert_eq!(addr_a, conn.remote_address());
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(b"hello through the relay").await?;
+    send.finish()?;
+    let echoed = recv.read_to_end(1024).await?;
+    assert_eq!(&b"hello through the relay"[..], &echoed[..]);
+
+    conn.close(0u32.into(), b"done");
+    accept.await?.map_err(|err| err.to_string())?;
+    endpoint_b.wait_idle().await;
+
+    client_a.close().await?;
+    client_b.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// CountingWaker counts its wakes
+#[derive(Default)]
+struct CountingWaker {
+    wakes: std::sync::atomic::AtomicUsize,
+}
+
+impl std::task::Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+// poll_recv waits for data alongside a pending recv_from, without being woken
+// to poll in a loop, and is woken when data arrives
+#[tokio::test]
+async fn test_poll_recv_beside_pending_recv_from() -> Result<(), Box<dyn std::error::Error>> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+    let relay_conn = Arc::new(client.allocate().await?);
+    let relay_addr = relay_conn.local_addr()?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    relay_conn
+        .send_to(b"permission", peer.local_addr()?)
+        .await?;
+
+    let reader = {
+        let relay_conn = Arc::clone(&relay_conn);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            relay_conn.recv_from(&mut buf).await.map(|(n, _)| n)
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let socket = RelayUdpSocket::new(Arc::clone(&relay_conn));
+    let counting = Arc::new(CountingWaker::default());
+    let waker = std::task::Waker::from(Arc::clone(&counting));
+    let mut cx = Context::from_waker(&waker);
+    let mut buf = [0u8; 64];
+    let mut meta = [RecvMeta::default()];
+    let mut poll_recv = |cx: &mut Context<'_>| {
+        let mut bufs = [IoSliceMut::new(&mut buf)];
+        socket.poll_recv(cx, &mut bufs, &mut meta)
+    };
+
+    assert!(poll_recv(&mut cx).is_pending());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        0,
+        counting.wakes.load(std::sync::atomic::Ordering::SeqCst),
+        "poll_recv was woken without data"
+    );
+
+    // both readers are woken, one of them gets the datagram
+    peer.send_to(b"data", relay_addr).await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while counting.wakes.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    match poll_recv(&mut cx) {
+        Poll::Ready(received) => assert_eq!(1, received?),
+        Poll::Pending => assert_eq!(
+            4,
+            tokio::time::timeout(Duration::from_secs(5), reader).await???
+        ),
+    }
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
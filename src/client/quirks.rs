@@ -0,0 +1,42 @@
+use crate::error::Error;
+
+use stun::attributes::ATTR_MESSAGE_INTEGRITY;
+use stun::integrity::MessageIntegrity;
+use stun::message::{Message, CLASS_SUCCESS_RESPONSE};
+
+// ServerQuirks relax the client for TURN servers known to stray from the
+// RFCs, each is off by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerQuirks {
+    // omit_fingerprint leaves FINGERPRINT out of the messages sent, for
+    // servers rejecting the requests carrying it
+    pub omit_fingerprint: bool,
+    // username_on_indications adds USERNAME to Send indications
+    pub username_on_indications: bool,
+    // allow_unprotected_success accepts a success response to an
+    // authenticated request without MESSAGE-INTEGRITY, which otherwise fails
+    // with Error::UnprotectedResponse. One carrying it must still be valid.
+    pub allow_unprotected_success: bool,
+}
+
+impl ServerQuirks {
+    // check_success checks the MESSAGE-INTEGRITY of a success response to a
+    // request signed with integrity, other responses pass
+    pub(crate) fn check_success(
+        &self,
+        res: &Message,
+        integrity: &MessageIntegrity,
+    ) -> Result<(), Error> {
+        if res.typ.class != CLASS_SUCCESS_RESPONSE {
+            return Ok(());
+        }
+        if res.contains(ATTR_MESSAGE_INTEGRITY) {
+            integrity.check(&mut res.clone())?;
+            return Ok(());
+        }
+        if self.allow_unprotected_success {
+            return Ok(());
+        }
+        Err(Error::UnprotectedResponse(res.typ))
+    }
+}
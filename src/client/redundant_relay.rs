@@ -0,0 +1,237 @@
+#[cfg(all(test, feature = "server"))]
+mod redundant_relay_test;
+
+use super::relay_conn::*;
+use crate::error::Error;
+
+use util::Conn;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Duration, Instant};
+
+// a relayed datagram is carried in a ChannelData or Data attribute, so it is
+// never larger than this
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+// INBOUND_QUEUE_SIZE is how many datagrams of all paths wait for recv_from
+const INBOUND_QUEUE_SIZE: usize = 256;
+
+// RedundantInbound is a datagram received on one of the paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedundantInbound {
+    pub data: Vec<u8>,
+    pub from: SocketAddr,
+    // path is the index of the RelayConn it came through
+    pub path: usize,
+}
+
+// PathHealth is the state of a path as of its last transaction with the TURN
+// server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathHealth {
+    pub path: usize,
+    pub relayed_addr: SocketAddr,
+    pub path_rtt: Option<Duration>,
+    pub last_failure: Option<Instant>,
+    pub time_since_last_success: Duration,
+    pub last_refresh_ok: bool,
+}
+
+impl PathHealth {
+    // is_degraded is whether the last refresh failed or a transaction failed
+    // since the last one that succeeded
+    pub fn is_degraded(&self) -> bool {
+        !self.last_refresh_ok
+            || self
+                .last_failure
+                .is_some_and(|failure| failure.elapsed() < self.time_since_last_success)
+    }
+}
+
+struct RelayPath<T: 'static + RelayConnObserver + Send + Sync> {
+    relay_conn: Arc<RelayConn<T>>,
+    reader: JoinHandle<()>,
+    // sends still going on after send_to returned
+    sends: std::sync::Mutex<JoinSet<()>>,
+}
+
+// RedundantRelay sends every datagram through each of its RelayConns, usually
+// allocated on different TURN servers, and receives from all of them. Each
+// copy a peer sends is received, tagged with its path, duplicates are left to
+// the caller. A path stays until drop_path or close, e.g. once health reports
+// it degraded.
+pub struct RedundantRelay<T: 'static + RelayConnObserver + Send + Sync> {
+    paths: Vec<Option<RelayPath<T>>>,
+    inbound_rx: Mutex<mpsc::Receiver<RedundantInbound>>,
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> RedundantRelay<T> {
+    // new takes the RelayConns, path i is relay_conns[i]. Their reads are
+    // merged, they shouldn't be read otherwise.
+    pub fn new(relay_conns: Vec<RelayConn<T>>) -> Self {
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_QUEUE_SIZE);
+        let paths = relay_conns
+            .into_iter()
+            .enumerate()
+            .map(|(path, relay_conn)| {
+                let relay_conn = Arc::new(relay_conn);
+                let reader =
+                    tokio::spawn(read_path(path, Arc::clone(&relay_conn), inbound_tx.clone()));
+                Some(RelayPath {
+                    relay_conn,
+                    reader,
+                    sends: std::sync::Mutex::new(JoinSet::new()),
+                })
+            })
+            .collect();
+
+        RedundantRelay {
+            paths,
+            inbound_rx: Mutex::new(inbound_rx),
+        }
+    }
+
+    fn live_paths(&self) -> impl Iterator<Item = (usize, &RelayPath<T>)> {
+        self.paths
+            .iter()
+            .enumerate()
+            .filter_map(|(i, path)| path.as_ref().map(|path| (i, path)))
+    }
+
+    // send_to sends p to addr through every path at once. It returns as soon
+    // as one path has sent, the other sends finish in their own tasks, so a
+    // path whose server is unreachable doesn't hold up the others. It fails
+    // with the error of the lowest path when none succeeds.
+    pub async fn send_to(&self, p: &[u8], addr: SocketAddr) -> Result<usize, Error> {
+        let data: Arc<[u8]> = Arc::from(p);
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+        for (i, path) in self.live_paths() {
+            let relay_conn = Arc::clone(&path.relay_conn);
+            let data = Arc::clone(&data);
+            let result_tx = result_tx.clone();
+            let mut sends = path.sends.lock().unwrap_or_else(|err| err.into_inner());
+            while sends.try_join_next().is_some() {}
+            sends.spawn(async move {
+                let result = relay_conn.send_to(&data, addr).await;
+                if let Err(err) = &result {
+                    log::debug!("send to {} on path {} failed: {}", addr, i, err);
+                }
+                // nobody listens once another path has sent
+                let _ = result_tx.send((i, result));
+            });
+        }
+        drop(result_tx);
+
+        let mut first_err: Option<(usize, std::io::Error)> = None;
+        while let Some((i, result)) = result_rx.recv().await {
+            match result {
+                Ok(_) => return Ok(p.len()),
+                Err(err) => {
+                    if first_err.as_ref().is_none_or(|(first, _)| i < *first) {
+                        first_err = Some((i, err));
+                    }
+                }
+            }
+        }
+        Err(first_err.map_or(Error::AlreadyClosed, |(_, err)| Error::Io(err)))
+    }
+
+    // recv_from waits for a datagram from any path
+    pub async fn recv_from(&self) -> Result<RedundantInbound, Error> {
+        let mut inbound_rx = self.inbound_rx.lock().await;
+        inbound_rx.recv().await.ok_or(Error::AlreadyClosed)
+    }
+
+    // local_addrs are the relayed addresses of the paths left
+    pub fn local_addrs(&self) -> Vec<(usize, SocketAddr)> {
+        self.live_paths()
+            .filter_map(|(i, path)| path.relay_conn.local_addr().ok().map(|addr| (i, addr)))
+            .collect()
+    }
+
+    // health reports on the paths left
+    pub fn health(&self) -> Vec<PathHealth> {
+        self.live_paths()
+            .filter_map(|(i, path)| {
+                let relay_conn = &path.relay_conn;
+                Some(PathHealth {
+                    path: i,
+                    relayed_addr: relay_conn.local_addr().ok()?,
+                    path_rtt: relay_conn.path_rtt(),
+                    last_failure: relay_conn.last_failure(),
+                    time_since_last_success: relay_conn.time_since_last_success(),
+                    last_refresh_ok: relay_conn.ttl_watch().borrow().last_refresh_ok,
+                })
+            })
+            .collect()
+    }
+
+    // drop_path closes a path, the others keep their index
+    pub async fn drop_path(&mut self, path: usize) -> Result<(), Error> {
+        match self.paths.get_mut(path).and_then(Option::take) {
+            Some(path) => close_path(path).await,
+            None => Err(Error::AlreadyClosed),
+        }
+    }
+
+    // close closes every path, returning the first error
+    pub async fn close(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for path in self.paths.iter_mut().filter_map(Option::take) {
+            if let Err(err) = close_path(path).await {
+                log::warn!("closing redundant path failed: {}", err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+}
+
+// read_path forwards what a path receives until it is closed
+async fn read_path<T: 'static + RelayConnObserver + Send + Sync>(
+    path: usize,
+    relay_conn: Arc<RelayConn<T>>,
+    inbound_tx: mpsc::Sender<RedundantInbound>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, from) = match relay_conn.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::debug!("redundant path {} stopped reading: {}", path, err);
+                return;
+            }
+        };
+        let inbound = RedundantInbound {
+            data: buf[..n].to_vec(),
+            from,
+            path,
+        };
+        if inbound_tx.send(inbound).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn close_path<T: 'static + RelayConnObserver + Send + Sync>(
+    path: RelayPath<T>,
+) -> Result<(), Error> {
+    // the reader and the sends hold the other references to the RelayConn
+    path.reader.abort();
+    let _ = path.reader.await;
+    let mut sends = path
+        .sends
+        .into_inner()
+        .unwrap_or_else(|err| err.into_inner());
+    sends.shutdown().await;
+    match Arc::try_unwrap(path.relay_conn) {
+        Ok(mut relay_conn) => relay_conn.close().await,
+        Err(_) => Err(Error::AlreadyClosed),
+    }
+}
@@ -0,0 +1,145 @@
+use super::*;
+use crate::test_util::*;
+
+use std::collections::HashSet;
+
+use tokio::net::UdpSocket;
+
+async fn recv_on_peer(peer: &UdpSocket) -> Result<(Vec<u8>, SocketAddr), Error> {
+    let mut buf = vec![0u8; 1500];
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("peer read timed out".to_owned()))??;
+    Ok((buf[..n].to_vec(), from))
+}
+
+async fn recv_on_relay<T: RelayConnObserver + Send + Sync>(
+    relay: &RedundantRelay<T>,
+) -> Result<RedundantInbound, Error> {
+    tokio::time::timeout(Duration::from_secs(5), relay.recv_from())
+        .await
+        .map_err(|_| Error::Other("relay read timed out".to_owned()))?
+}
+
+// spawn_proxy forwards between one client and server_addr until aborted, a
+// server dying is simulated by aborting it
+async fn spawn_proxy(server_addr: SocketAddr) -> Result<(SocketAddr, JoinHandle<()>), Error> {
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+    let proxy_addr = conn.local_addr()?;
+    let proxy = tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut client_addr = None;
+        while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+            let to = if from == server_addr {
+                match client_addr {
+                    Some(client_addr) => client_addr,
+                    None => continue,
+                }
+            } else {
+                client_addr = Some(from);
+                server_addr
+            };
+            let _ = conn.send_to(&buf[..n], to).await;
+        }
+    });
+    Ok((proxy_addr, proxy))
+}
+
+#[tokio::test]
+async fn test_redundant_relay() -> Result<(), Error> {
+    let (server1, server1_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let (server2, server2_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client1 = start_client(server1_addr).await?;
+    let (proxy_addr, proxy) = spawn_proxy(server2_addr).await?;
+    let client2 = start_client(proxy_addr).await?;
+    let mut relay = RedundantRelay::new(vec![client1.allocate().await?, client2.allocate().await?]);
+    let relayed_addrs = relay.local_addrs();
+    assert_eq!(2, relayed_addrs.len());
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer_addr = peer.local_addr()?;
+
+    // a send reaches the peer through both servers
+    relay.send_to(b"hello", peer_addr).await?;
+    let mut froms = HashSet::new();
+    for _ in 0..2 {
+        let (data, from) = recv_on_peer(&peer).await?;
+        assert_eq!(b"hello", &data[..]);
+        froms.insert(from);
+    }
+    let expected: HashSet<SocketAddr> = relayed_addrs.iter().map(|(_, addr)| *addr).collect();
+    assert_eq!(expected, froms);
+
+    // what the peer sends to either relayed address is received, tagged
+    for (_, relayed_addr) in &relayed_addrs {
+        peer.send_to(b"reply", *relayed_addr).await?;
+    }
+    let mut paths = HashSet::new();
+    for _ in 0..2 {
+        let inbound = recv_on_relay(&relay).await?;
+        assert_eq!(
+            (&b"reply"[..], peer_addr),
+            (&inbound.data[..], inbound.from)
+        );
+        paths.insert(inbound.path);
+    }
+    assert_eq!(HashSet::from([0, 1]), paths);
+
+    for health in relay.health() {
+        assert!(!health.is_degraded(), "{:?}", health);
+        assert!(health.path_rtt.is_some(), "{:?}", health);
+    }
+
+    // with the second server unreachable delivery goes on through the first
+    proxy.abort();
+    let _ = proxy.await;
+    relay.send_to(b"still", peer_addr).await?;
+    let (data, from) = recv_on_peer(&peer).await?;
+    assert_eq!((&b"still"[..], relayed_addrs[0].1), (&data[..], from));
+
+    peer.send_to(b"still here", relayed_addrs[0].1).await?;
+    let inbound = recv_on_relay(&relay).await?;
+    assert_eq!((&b"still here"[..], 0), (&inbound.data[..], inbound.path));
+
+    // a new peer needs a permission on every path, the dead path's doesn't
+    // hold up the send
+    let peer2 = UdpSocket::bind("127.0.0.2:0").await?;
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        relay.send_to(b"new peer", peer2.local_addr()?),
+    )
+    .await
+    .map_err(|_| Error::Other("send held up by the dead path".to_owned()))??;
+    let (data, from) = recv_on_peer(&peer2).await?;
+    assert_eq!((&b"new peer"[..], relayed_addrs[0].1), (&data[..], from));
+
+    // the dead path is dropped, the other keeps its index
+    relay.drop_path(1).await?;
+    assert_eq!(vec![relayed_addrs[0]], relay.local_addrs());
+    assert!(matches!(
+        relay.drop_path(1).await,
+        Err(Error::AlreadyClosed)
+    ));
+    relay.send_to(b"one path", peer_addr).await?;
+    let (data, _) = recv_on_peer(&peer).await?;
+    assert_eq!(b"one path", &data[..]);
+
+    // closing closes every path, the merged reads end
+    relay.close().await?;
+    assert!(relay.local_addrs().is_empty());
+    assert!(matches!(
+        relay.send_to(b"closed", peer_addr).await,
+        Err(Error::AlreadyClosed)
+    ));
+    assert!(matches!(
+        recv_on_relay(&relay).await,
+        Err(Error::AlreadyClosed)
+    ));
+
+    client1.close().await?;
+    client2.close().await?;
+    server1.close()?;
+    server2.close()?;
+
+    Ok(())
+}
@@ -1,131 +1,1292 @@
 #[cfg(test)]
 mod relay_conn_test;
 
+pub mod framed;
+
 // client implements the API for a TURN client
+use super::allocation_ttl::*;
+use super::auto_permit::*;
+use super::bind_policy::*;
 use super::binding::*;
+use super::event::*;
+use super::inbound_filter::*;
+use super::inbound_queue::*;
+use super::inspect::*;
+use super::maintenance::*;
+use super::path_stats::*;
+use super::peer_addr::*;
+use super::peer_probe::*;
+use super::peer_subscriptions::*;
 use super::periodic_timer::*;
 use super::permission::*;
+use super::quirks::*;
+use super::retry::*;
+use super::send_batch::*;
+use super::stats_reporter::*;
 use super::transaction::*;
 use crate::proto;
+use crate::proto::errorcodes::{
+    ALLOCATION_MISMATCH, CODE_PEER_ADDR_FAMILY_MISMATCH, PEER_ADDRESS_FAMILY_MISMATCH,
+};
 
-use crate::errors::*;
+use crate::auth::REDACTED;
+use crate::error::Error;
 
 use stun::agent::*;
 use stun::attributes::*;
 use stun::error_code::*;
-use stun::fingerprint::*;
 use stun::integrity::*;
 use stun::message::*;
 use stun::textattrs::*;
 
-use util::{Conn, Error};
+use util::Conn;
 
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
 
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, Notify};
 use tokio::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures::FutureExt;
 
 const PERM_REFRESH_INTERVAL: Duration = Duration::from_secs(120);
-const MAX_RETRY_ATTEMPTS: u16 = 3;
+// BINDING_REFRESH_INTERVAL is the age past which a channel binding in use is
+// refreshed, half the 10 minutes it lasts on the server
+const BINDING_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub(crate) const MAX_READ_QUEUE_SIZE: usize = 1024;
+
+// MAX_REFRESH_JITTER bounds refresh_jitter, the longest refresh period is then
+// 3/4 of the lifetime
+pub const MAX_REFRESH_JITTER: f64 = 0.5;
+
+// DEFAULT_WARN_PAYLOAD_SIZE leaves room for the Send indication framing and
+// IPv6 headers within the 1280 byte IPv6 minimum MTU
+pub const DEFAULT_WARN_PAYLOAD_SIZE: usize = 1200;
+
+// DEFAULT_EXPIRY_GUARD is how close to its expiry an allocation whose
+// refreshes fail is refreshed by send_to before sending
+pub const DEFAULT_EXPIRY_GUARD: Duration = Duration::from_secs(5);
+
+// DEALLOCATE_TRANSACTION gives the zero lifetime Refresh on close a second,
+// the server lets the allocation expire if it is lost
+pub const DEALLOCATE_TRANSACTION: TransactionOptions = TransactionOptions {
+    deadline: Some(Duration::from_secs(1)),
+    rto: None,
+};
+
+// CHANNEL_BIND_TRANSACTION gives a ChannelBind longer than other requests,
+// data goes in Send indications meanwhile
+pub const CHANNEL_BIND_TRANSACTION: TransactionOptions = TransactionOptions {
+    deadline: Some(Duration::from_secs(30)),
+    rto: None,
+};
 
 pub(crate) struct InboundData {
     pub(crate) data: Vec<u8>,
     pub(crate) from: SocketAddr,
 }
 
-// UDPConnObserver is an interface to UDPConn observer
+// RelayConnObserver is what a RelayConn needs from the layer that owns the
+// socket to the TURN server. Client implements it, other connection managers
+// can implement it to drive a RelayConn directly, see RelayConnConfig::new.
+//
+// The RelayConn holds the observer behind a mutex and never calls it
+// re-entrantly, but calls may come from the refresh timers as well as from
+// send_to, so they must not block on the RelayConn itself. The mutex is not
+// held while waiting for the response of a transaction begun with
+// begin_transaction, or for a reallocation begun with begin_reallocate.
 #[async_trait]
 pub trait RelayConnObserver {
+    // turn_server_addr is passed back as `to` in write_to and perform_transaction
     fn turn_server_addr(&self) -> String;
+    // username and realm are added to every authenticated request
     fn username(&self) -> Username;
     fn realm(&self) -> Realm;
+
+    // write_to sends data to the TURN server as is, it is used for Send
+    // indications and ChannelData, which have no response. An error of kind
+    // io::ErrorKind::WouldBlock means the transport is backed up, the send
+    // fails with Error::WouldBlock and may be retried.
     async fn write_to(&self, data: &[u8], to: &str) -> Result<usize, Error>;
+
+    // write_batch writes the ChannelData coalesced with SendBatching and
+    // returns the number written, one write_to at a time unless overridden
+    async fn write_batch(&self, packets: &[Vec<u8>], to: &str) -> Result<usize, Error> {
+        for (i, packet) in packets.iter().enumerate() {
+            if let Err(err) = self.write_to(packet, to).await {
+                if i == 0 {
+                    return Err(err);
+                }
+                return Ok(i);
+            }
+        }
+        Ok(packets.len())
+    }
+
+    // perform_transaction sends a request and returns its response, retransmitting
+    // as needed. A STUN error response is a successful result, the RelayConn
+    // inspects it (e.g. 438 Stale Nonce is retried with the new nonce).
+    //
+    // With dont_wait set, the request must be sent and the call return right
+    // away, the result is ignored so TransactionResult::default() will do. It is
+    // only used for the zero lifetime Refresh on close.
     async fn perform_transaction(
         &mut self,
         msg: &Message,
         to: &str,
-        ignore_result: bool,
+        dont_wait: bool,
     ) -> Result<TransactionResult, Error>;
+
+    // begin_transaction sends a request and returns its response to be awaited
+    // later, so the observer is free for other calls, e.g. sending data, while
+    // the transaction runs. The default performs the whole transaction first.
+    async fn begin_transaction(
+        &mut self,
+        msg: &Message,
+        to: &str,
+    ) -> Result<PendingTransaction, Error> {
+        let result = self.perform_transaction(msg, to, false).await;
+        Ok(Box::pin(std::future::ready(result)))
+    }
+
+    // perform_transaction_with and begin_transaction_with are the above with
+    // the retransmissions overridden for the call, e.g. a short deadline for
+    // the zero lifetime Refresh on close. The defaults ignore opts.
+    async fn perform_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        dont_wait: bool,
+        _opts: TransactionOptions,
+    ) -> Result<TransactionResult, Error> {
+        self.perform_transaction(msg, to, dont_wait).await
+    }
+
+    async fn begin_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        _opts: TransactionOptions,
+    ) -> Result<PendingTransaction, Error> {
+        self.begin_transaction(msg, to).await
+    }
+
+    // local_addr is the address of the socket to the TURN server, it goes in
+    // AllocationId. The default does not know it.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    // on_deallocated is called once by RelayConn::close, after the zero lifetime
+    // Refresh has been sent, whether or not sending it succeeded. It is passed
+    // the id of the allocation, with the relayed address it was made with. It
+    // is not called when the allocation times out on the server.
+    fn on_deallocated(&mut self, _id: &AllocationId) {}
+
+    // begin_reallocate starts a new allocation of the transport and with the
+    // credentials of the one the server lost, for
+    // RelayConnConfig::auto_reallocate, and returns it to be awaited later
+    // like begin_transaction, the observer isn't locked while the Allocates
+    // are answered. The default can't and fails with
+    // Error::ReallocateUnsupported.
+    async fn begin_reallocate(&mut self) -> Result<PendingReallocation, Error> {
+        Err(Error::ReallocateUnsupported)
+    }
+
+    // on_reallocated is called with the new allocation once it is made,
+    // before the permissions and bindings are restored on it
+    fn on_reallocated(&mut self, _reallocation: &Reallocation) {}
+}
+
+// PendingReallocation is a reallocation that has been started, see
+// RelayConnObserver::begin_reallocate
+pub type PendingReallocation = Pin<Box<dyn Future<Output = Result<Reallocation, Error>> + Send>>;
+
+// Reallocation is the new allocation made by
+// RelayConnObserver::begin_reallocate, with the values of the Allocate success
+// response and the realm and integrity it was authenticated with
+#[derive(Clone)]
+pub struct Reallocation {
+    pub relayed_addr: SocketAddr,
+    pub realm: Realm,
+    pub integrity: MessageIntegrity,
+    pub nonce: Nonce,
+    pub lifetime: Duration,
 }
 
-// RelayConnConfig is a set of configuration params use by NewUDPConn
-pub(crate) struct RelayConnConfig {
+impl fmt::Debug for Reallocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reallocation")
+            .field("relayed_addr", &self.relayed_addr)
+            .field("realm", &self.realm.text)
+            .field("integrity", &REDACTED)
+            .field("nonce", &REDACTED)
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+// TransportKind is the transport a RelayConn reaches its TURN server over
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum TransportKind {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+    Dtls,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TransportKind::Udp => "udp",
+            TransportKind::Tcp => "tcp",
+            TransportKind::Tls => "tls",
+            TransportKind::Dtls => "dtls",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// AllocationId tells allocations apart when a process has many clients of
+// many servers, the relayed address alone can repeat across servers
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationId {
+    pub turn_server_addr: String,
+    // local_addr is None when the observer does not know its socket
+    pub local_addr: Option<SocketAddr>,
+    pub username: String,
+    pub relayed_addr: SocketAddr,
+    pub transport: TransportKind,
+}
+
+// RelayConnConfig is a set of configuration params use by RelayConn::new
+pub struct RelayConnConfig {
     pub(crate) relayed_addr: SocketAddr,
     pub(crate) integrity: MessageIntegrity,
     pub(crate) nonce: Nonce,
     pub(crate) lifetime: Duration,
+    pub(crate) refresh_jitter: Option<f64>,
+    pub(crate) max_bindings: usize,
+    pub(crate) keepalive_payload: Vec<u8>,
+    pub(crate) warn_payload_size: Option<usize>,
+    pub(crate) max_payload_size: Option<usize>,
+    pub(crate) auto_permit_inbound: bool,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) auto_reallocate: bool,
+    pub(crate) require_refresh_lifetime: bool,
+    pub(crate) expiry_guard: Duration,
+    pub(crate) fail_fast_on_expiry: bool,
+    pub(crate) server_quirks: ServerQuirks,
+    pub(crate) channel_bind_policy: ChannelBindPolicy,
+    pub(crate) dedup_window: Option<usize>,
+    // server_addr, transport and underlying_local_addr describe the path to
+    // the TURN server, for RelayConn to report
+    pub(crate) server_addr: Option<SocketAddr>,
+    pub(crate) transport: TransportKind,
+    pub(crate) underlying_local_addr: Option<SocketAddr>,
+    pub(crate) message_customizer: Option<MessageCustomizer>,
+    pub(crate) maintenance: Option<MaintenanceScheduler>,
     pub(crate) binding_mgr: Arc<Mutex<BindingManager>>,
-    pub(crate) read_ch_rx: Arc<Mutex<mpsc::Receiver<InboundData>>>,
+    pub(crate) read_ch_rx: Arc<ReadQueue>,
+    pub(crate) inbound_overflow: Arc<InboundOverflow>,
+    pub(crate) auto_permit: Arc<AutoPermit>,
+    pub(crate) inbound_filter: Arc<InboundFilter>,
+    pub(crate) probes: Arc<PeerProbes>,
+    pub(crate) subscriptions: Arc<PeerSubscriptions>,
+    pub(crate) traffic: Arc<Traffic>,
+    pub(crate) send_batching: Option<SendBatching>,
+    pub(crate) close_signal: Arc<CloseSignal>,
+    // alloc_refreshed restarts the allocation refresh timer after send_to
+    // refreshed the allocation, the other sends wait on it meanwhile
+    pub(crate) alloc_refreshed: Arc<Notify>,
+    pub(crate) events: Arc<RelayConnEvents>,
+}
+
+impl RelayConnConfig {
+    // new configures a RelayConn for an allocation made outside of Client, with
+    // the values from the Allocate success response and the credentials used
+    // for it. Data the TURN server sends for the allocation must be handed to
+    // the returned RelayConnInbound.
+    pub fn new(
+        relayed_addr: SocketAddr,
+        integrity: MessageIntegrity,
+        nonce: Nonce,
+        lifetime: Duration,
+    ) -> (Self, RelayConnInbound) {
+        let (read_ch_tx, read_ch_rx) = mpsc::channel(MAX_READ_QUEUE_SIZE);
+        let binding_mgr = Arc::new(Mutex::new(BindingManager::new()));
+        let events = Arc::new(RelayConnEvents::default());
+        let inbound_overflow = Arc::new(InboundOverflow::new(Arc::clone(&events)));
+        let auto_permit = Arc::new(AutoPermit::default());
+        let inbound_filter = Arc::new(InboundFilter::default());
+        let probes = Arc::new(PeerProbes::default());
+        let subscriptions = Arc::new(PeerSubscriptions::default());
+        let traffic = Arc::new(Traffic::default());
+
+        (
+            RelayConnConfig {
+                relayed_addr,
+                integrity,
+                nonce,
+                lifetime,
+                refresh_jitter: None,
+                max_bindings: 0,
+                keepalive_payload: vec![],
+                warn_payload_size: Some(DEFAULT_WARN_PAYLOAD_SIZE),
+                max_payload_size: None,
+                auto_permit_inbound: false,
+                retry_policy: RetryPolicy::default(),
+                auto_reallocate: false,
+                require_refresh_lifetime: false,
+                expiry_guard: DEFAULT_EXPIRY_GUARD,
+                fail_fast_on_expiry: false,
+                server_quirks: ServerQuirks::default(),
+                channel_bind_policy: ChannelBindPolicy::default(),
+                dedup_window: None,
+                server_addr: None,
+                transport: TransportKind::default(),
+                underlying_local_addr: None,
+                message_customizer: None,
+                maintenance: None,
+                binding_mgr: Arc::clone(&binding_mgr),
+                read_ch_rx: Arc::new(ReadQueue::new(read_ch_rx)),
+                inbound_overflow: Arc::clone(&inbound_overflow),
+                auto_permit: Arc::clone(&auto_permit),
+                inbound_filter: Arc::clone(&inbound_filter),
+                probes: Arc::clone(&probes),
+                subscriptions: Arc::clone(&subscriptions),
+                traffic: Arc::clone(&traffic),
+                send_batching: None,
+                close_signal: Arc::default(),
+                alloc_refreshed: Arc::default(),
+                events: Arc::clone(&events),
+            },
+            RelayConnInbound {
+                queue: InboundQueue::new(
+                    read_ch_tx,
+                    inbound_overflow,
+                    auto_permit,
+                    inbound_filter,
+                    probes,
+                    subscriptions,
+                    traffic,
+                ),
+                binding_mgr,
+            },
+        )
+    }
+
+    // refresh_jitter randomizes every allocation refresh period by up to this
+    // fraction of lifetime/2 either way, so allocations made together don't
+    // refresh together. It is clamped to MAX_REFRESH_JITTER.
+    pub fn refresh_jitter(mut self, refresh_jitter: f64) -> Self {
+        self.refresh_jitter = Some(refresh_jitter);
+        self
+    }
+
+    // max_bindings caps the peers bound to a channel, data to further peers
+    // fails with BindingError::LimitReached. Zero selects MAX_BINDINGS.
+    pub fn max_bindings(mut self, max_bindings: usize) -> Self {
+        self.max_bindings = max_bindings;
+        self
+    }
+
+    // keepalive_payload is the data sent by RelayConn::send_keepalive,
+    // defaults to none
+    pub fn keepalive_payload(mut self, keepalive_payload: Vec<u8>) -> Self {
+        self.keepalive_payload = keepalive_payload;
+        self
+    }
+
+    // warn_payload_size is the payload size over which send_to counts the
+    // send as oversized and emits RelayConnEvent::OversizedSend once per
+    // peer, the data is still sent. None turns the warning off. Defaults to
+    // DEFAULT_WARN_PAYLOAD_SIZE.
+    pub fn warn_payload_size(mut self, warn_payload_size: Option<usize>) -> Self {
+        self.warn_payload_size = warn_payload_size;
+        self
+    }
+
+    // max_payload_size makes send_to fail with Error::PayloadTooLarge, an
+    // io::ErrorKind::InvalidInput through Conn, for larger payloads. Defaults
+    // to no limit.
+    pub fn max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.max_payload_size = Some(max_payload_size);
+        self
+    }
+
+    // auto_permit_inbound creates the permission for the IP of a peer that
+    // sends a Data indication before we sent it anything, in the background,
+    // so the reply does not wait for the CreatePermission. At most
+    // AUTO_PERMIT_RATE new IPs per second and MAX_AUTO_PERMIT_PEERS in all are
+    // permitted this way. Off by default.
+    pub fn auto_permit_inbound(mut self, auto_permit_inbound: bool) -> Self {
+        self.auto_permit_inbound = auto_permit_inbound;
+        self
+    }
+
+    // send_batching coalesces ChannelData into fewer writes, at the cost of
+    // up to its max_delay of latency. Off by default.
+    pub fn send_batching(mut self, send_batching: SendBatching) -> Self {
+        self.send_batching = Some(send_batching);
+        self
+    }
+
+    // retry_policy decides how failed Refresh and CreatePermission
+    // transactions are retried
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // auto_reallocate allocates again when a Refresh or CreatePermission is
+    // answered with 437 (Allocation Mismatch), the server lost the
+    // allocation, e.g. when it restarted. The permissions and channel
+    // bindings are restored on the new allocation, see
+    // RelayConnEvent::AllocationRestored. The observer must implement
+    // RelayConnObserver::reallocate. Off by default.
+    pub fn auto_reallocate(mut self, auto_reallocate: bool) -> Self {
+        self.auto_reallocate = auto_reallocate;
+        self
+    }
+
+    // require_refresh_lifetime fails a refresh whose success response has no
+    // LIFETIME. By default the lifetime that was asked for is kept, as some
+    // servers leave it out, and the first such response is logged.
+    pub fn require_refresh_lifetime(mut self, require_refresh_lifetime: bool) -> Self {
+        self.require_refresh_lifetime = require_refresh_lifetime;
+        self
+    }
+
+    // expiry_guard is how close to its expiry send_to finds the allocation
+    // before refreshing it first, which it only does once the refresh timer
+    // failed to. Defaults to DEFAULT_EXPIRY_GUARD.
+    pub fn expiry_guard(mut self, expiry_guard: Duration) -> Self {
+        self.expiry_guard = expiry_guard;
+        self
+    }
+
+    // fail_fast_on_expiry makes send_to fail with Error::AllocationExpiring
+    // within expiry_guard instead of refreshing. Off by default.
+    pub fn fail_fast_on_expiry(mut self, fail_fast_on_expiry: bool) -> Self {
+        self.fail_fast_on_expiry = fail_fast_on_expiry;
+        self
+    }
+
+    // server_quirks work around a TURN server known to stray from the RFCs,
+    // see ServerQuirks. Defaults to none.
+    pub fn server_quirks(mut self, server_quirks: ServerQuirks) -> Self {
+        self.server_quirks = server_quirks;
+        self
+    }
+
+    // channel_bind_policy decides which peers send_to binds a channel to,
+    // see ChannelBindPolicy. Defaults to ChannelBindPolicy::Always.
+    pub fn channel_bind_policy(mut self, channel_bind_policy: ChannelBindPolicy) -> Self {
+        self.channel_bind_policy = channel_bind_policy;
+        self
+    }
+
+    // dedup_window drops the data from a peer that duplicates one of its last
+    // dedup_window packets, as a lossy or reconnecting transport to the server
+    // may deliver it twice. Those are counted by inbound_duplicates. A zero
+    // window or none, the default, doesn't look for duplicates.
+    pub fn dedup_window(mut self, dedup_window: Option<usize>) -> Self {
+        self.dedup_window = dedup_window;
+        self
+    }
+
+    // server_addr is the address of the TURN server the allocation was made
+    // on, see RelayConn::server_addr. Defaults to none.
+    pub fn server_addr(mut self, server_addr: SocketAddr) -> Self {
+        self.server_addr = Some(server_addr);
+        self
+    }
+
+    // transport is the transport to the TURN server, see RelayConn::transport.
+    // Defaults to TransportKind::Udp.
+    pub fn transport(mut self, transport: TransportKind) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // underlying_local_addr is the local address of the connection to the
+    // TURN server, see RelayConn::underlying_local_addr. Defaults to none.
+    pub fn underlying_local_addr(mut self, underlying_local_addr: SocketAddr) -> Self {
+        self.underlying_local_addr = Some(underlying_local_addr);
+        self
+    }
+
+    // message_customizer is called with every STUN message the RelayConn
+    // builds, before MESSAGE-INTEGRITY and FINGERPRINT, see
+    // MessageCustomizer. Defaults to none.
+    pub fn message_customizer(mut self, message_customizer: MessageCustomizer) -> Self {
+        self.message_customizer = Some(message_customizer);
+        self
+    }
+
+    // maintenance_scheduler has the RelayConn's allocation, permission and
+    // binding refreshes run by a scheduler shared with other RelayConns
+    // rather than by timers of its own, see MaintenanceScheduler. Defaults to
+    // the RelayConn's own timers.
+    pub fn maintenance_scheduler(mut self, maintenance: MaintenanceScheduler) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+}
+
+// RelayConnInbound passes data received from the TURN server to the RelayConn
+// it was created with, this is what Client does for its own allocation
+pub struct RelayConnInbound {
+    queue: InboundQueue,
+    binding_mgr: Arc<Mutex<BindingManager>>,
+}
+
+impl RelayConnInbound {
+    // handle_data passes the DATA attribute of a Data indication from the peer
+    // in its XOR-PEER-ADDRESS. Data is dropped when the read queue is full,
+    // see RelayConn::inbound_dropped.
+    pub fn handle_data(&self, data: &[u8], from: SocketAddr) -> Result<(), Error> {
+        self.queue.push_indication(data, from)
+    }
+
+    // handle_channel_data decodes a ChannelData message and passes its data
+    // from the peer bound to the channel number
+    pub async fn handle_channel_data(&self, data: &[u8]) -> Result<(), Error> {
+        let mut ch_data = proto::chandata::ChannelData {
+            raw: data.to_vec(),
+            ..Default::default()
+        };
+        ch_data.decode()?;
+
+        let from = {
+            let mut binding_mgr = self.binding_mgr.lock().await;
+            binding_mgr
+                .seen_by_number(ch_data.number.0)
+                .ok_or(Error::ChannelBindNotFound)?
+        };
+
+        self.queue.push(&ch_data.data, from)
+    }
+}
+
+impl fmt::Debug for RelayConnConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayConnConfig")
+            .field("relayed_addr", &self.relayed_addr)
+            .field("integrity", &REDACTED)
+            .field("nonce", &REDACTED)
+            .field("lifetime", &self.lifetime)
+            .field("refresh_jitter", &self.refresh_jitter)
+            .field("max_bindings", &self.max_bindings)
+            .field("keepalive_payload", &self.keepalive_payload.len())
+            .field("warn_payload_size", &self.warn_payload_size)
+            .field("max_payload_size", &self.max_payload_size)
+            .field("auto_permit_inbound", &self.auto_permit_inbound)
+            .field("send_batching", &self.send_batching)
+            .field("retry_policy", &self.retry_policy)
+            .field("auto_reallocate", &self.auto_reallocate)
+            .field("require_refresh_lifetime", &self.require_refresh_lifetime)
+            .field("expiry_guard", &self.expiry_guard)
+            .field("fail_fast_on_expiry", &self.fail_fast_on_expiry)
+            .field("server_quirks", &self.server_quirks)
+            .field("channel_bind_policy", &self.channel_bind_policy)
+            .field("dedup_window", &self.dedup_window)
+            .field("server_addr", &self.server_addr)
+            .field("transport", &self.transport)
+            .field("underlying_local_addr", &self.underlying_local_addr)
+            .field("message_customizer", &self.message_customizer.is_some())
+            .field("maintenance", &self.maintenance.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 pub struct RelayConnInternal<T: 'static + RelayConnObserver + Send + Sync> {
     obs: Arc<Mutex<T>>,
-    relayed_addr: SocketAddr,
+    // allocated_addr is the relayed address of the Allocate response, the
+    // observer is told of the deallocation by it
+    allocated_addr: SocketAddr,
+    relayed_addr: Arc<RelayedAddr>,
     perm_map: PermissionMap,
     binding_mgr: Arc<Mutex<BindingManager>>,
     integrity: MessageIntegrity,
     nonce: Nonce,
     lifetime: Duration,
+    max_bindings: usize,
+    keepalive_payload: Vec<u8>,
+    retry_policy: RetryPolicy,
+    auto_reallocate: bool,
+    require_refresh_lifetime: bool,
+    // lifetime_missing is set once a Refresh response had no LIFETIME
+    lifetime_missing: Arc<AtomicBool>,
+    server_quirks: ServerQuirks,
+    alloc_refreshed: Arc<Notify>,
+    channel_bind_policy: ChannelBindPolicy,
+    transport: TransportKind,
+    // send_rates counts the packets sent to unbound peers with
+    // ChannelBindPolicy::Adaptive
+    send_rates: SendRates,
+    message_customizer: Option<MessageCustomizer>,
+    path_stats: Arc<PathStats>,
+    ttl: Arc<TtlWatch>,
+    events: Arc<RelayConnEvents>,
+    inbound_filter: Arc<InboundFilter>,
+    warn_payload_size: Option<usize>,
+    max_payload_size: Option<usize>,
+    oversized_sends: Arc<OversizedSends>,
+    send_backpressure: Arc<AtomicU64>,
+    // send_batcher queues ChannelData with SendBatching
+    send_batcher: Option<Arc<SendBatcher>>,
+    // close_signal is raised by RelayConn::close before it waits for the lock,
+    // a refresh in flight gives up on it
+    close_signal: Arc<CloseSignal>,
+    // send_buf is reused to encode ChannelData, a send takes it while writing
+    send_buf: std::sync::Mutex<Vec<u8>>,
+    // closed is set by the first close, later closes and refresh timeouts do
+    // nothing
+    closed: bool,
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> fmt::Debug for RelayConnInternal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayConnInternal")
+            .field("relayed_addr", &self.relayed_addr.get())
+            .field("perm_map", &self.perm_map)
+            .field("integrity", &REDACTED)
+            .field("nonce", &REDACTED)
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> Drop for RelayConnInternal<T> {
+    // the flush task ends with the queue it flushes
+    fn drop(&mut self) {
+        if let Some(send_batcher) = &self.send_batcher {
+            send_batcher.close();
+        }
+    }
 }
 
 // RelayConn is the implementation of the Conn interfaces for UDP Relayed network connections.
 pub struct RelayConn<T: 'static + RelayConnObserver + Send + Sync> {
-    relayed_addr: SocketAddr,
-    read_ch_rx: Arc<Mutex<mpsc::Receiver<InboundData>>>,
+    relayed_addr: Arc<RelayedAddr>,
+    read_ch_rx: Arc<ReadQueue>,
     relay_conn: Arc<Mutex<RelayConnInternal<T>>>,
     refresh_alloc_timer: PeriodicTimer,
     refresh_perms_timer: PeriodicTimer,
+    // scheduled is what is registered with the MaintenanceScheduler instead
+    // of starting the timers, the scheduler forgets the RelayConn once it is
+    // dropped
+    scheduled: Option<Arc<ScheduledRefresher<T>>>,
+    maintenance: Option<MaintenanceScheduler>,
+    path_stats: Arc<PathStats>,
+    ttl: Arc<TtlWatch>,
+    expiry_guard: Duration,
+    fail_fast_on_expiry: bool,
+    alloc_refreshed: Arc<Notify>,
+    // guard_refreshing is set while a send_to refreshes the expiring
+    // allocation
+    guard_refreshing: AtomicBool,
+    inbound_overflow: Arc<InboundOverflow>,
+    oversized_sends: Arc<OversizedSends>,
+    send_backpressure: Arc<AtomicU64>,
+    auto_permit: Arc<AutoPermit>,
+    inbound_filter: Arc<InboundFilter>,
+    probes: Arc<PeerProbes>,
+    subscriptions: Arc<PeerSubscriptions>,
+    traffic: Arc<Traffic>,
+    close_signal: Arc<CloseSignal>,
+    // report_gate keeps the stats reporters from calling back once closed
+    report_gate: Arc<ReportGate>,
+    events: Arc<RelayConnEvents>,
+    server_addr: Option<SocketAddr>,
+    transport: TransportKind,
+    underlying_local_addr: Option<SocketAddr>,
+    // mapped_peers are the peers sent to in the v4-mapped form, which their
+    // data is received from
+    mapped_peers: MappedPeers,
+    // peer_keepalives holds the close channel of each peer's keepalive task
+    peer_keepalives: std::sync::Mutex<HashMap<SocketAddr, mpsc::Sender<()>>>,
 }
 
 impl<T: 'static + RelayConnObserver + Send + Sync> RelayConn<T> {
-    // new creates a new instance of UDPConn
-    pub(crate) fn new(obs: Arc<Mutex<T>>, config: RelayConnConfig) -> Self {
+    // new creates a new instance of UDPConn and starts its refresh timers
+    pub fn new(obs: Arc<Mutex<T>>, config: RelayConnConfig) -> Self {
         log::debug!("initial lifetime: {} seconds", config.lifetime.as_secs());
 
+        // NaN and negative jitter select none
+        let refresh_jitter = config
+            .refresh_jitter
+            .filter(|jitter| *jitter > 0.0)
+            .map_or(0.0, |jitter| jitter.min(MAX_REFRESH_JITTER));
+
+        let path_stats = Arc::new(PathStats::new());
+        let ttl = Arc::new(TtlWatch::new(config.lifetime));
+        let relayed_addr = Arc::new(RelayedAddr::new(config.relayed_addr));
+        let oversized_sends = Arc::new(OversizedSends::default());
+        let send_backpressure = Arc::new(AtomicU64::new(0));
+        config.inbound_filter.set_dedup_window(config.dedup_window);
+        let auto_permit_rx = if config.auto_permit_inbound {
+            Some(config.auto_permit.start())
+        } else {
+            None
+        };
         let mut c = RelayConn {
-            refresh_alloc_timer: PeriodicTimer::new(TimerIdRefresh::Alloc, config.lifetime / 2),
+            refresh_alloc_timer: PeriodicTimer::new(TimerIdRefresh::Alloc, config.lifetime / 2)
+                .with_jitter(refresh_jitter)
+                .with_reset(Arc::clone(&config.alloc_refreshed)),
             refresh_perms_timer: PeriodicTimer::new(TimerIdRefresh::Perms, PERM_REFRESH_INTERVAL),
-            relayed_addr: config.relayed_addr,
+            scheduled: None,
+            maintenance: config.maintenance.clone(),
+            relayed_addr: Arc::clone(&relayed_addr),
             read_ch_rx: Arc::clone(&config.read_ch_rx),
-            relay_conn: Arc::new(Mutex::new(RelayConnInternal::new(obs, config))),
+            inbound_overflow: Arc::clone(&config.inbound_overflow),
+            auto_permit: Arc::clone(&config.auto_permit),
+            inbound_filter: Arc::clone(&config.inbound_filter),
+            probes: Arc::clone(&config.probes),
+            subscriptions: Arc::clone(&config.subscriptions),
+            traffic: Arc::clone(&config.traffic),
+            close_signal: Arc::clone(&config.close_signal),
+            report_gate: Arc::default(),
+            events: Arc::clone(&config.events),
+            server_addr: config.server_addr,
+            transport: config.transport,
+            underlying_local_addr: config.underlying_local_addr,
+            expiry_guard: config.expiry_guard,
+            fail_fast_on_expiry: config.fail_fast_on_expiry,
+            alloc_refreshed: Arc::clone(&config.alloc_refreshed),
+            guard_refreshing: AtomicBool::new(false),
+            relay_conn: Arc::new(Mutex::new(RelayConnInternal::new(
+                obs,
+                config,
+                Arc::clone(&path_stats),
+                Arc::clone(&ttl),
+                relayed_addr,
+                Arc::clone(&oversized_sends),
+                Arc::clone(&send_backpressure),
+            ))),
+            oversized_sends,
+            send_backpressure,
+            path_stats,
+            ttl,
+            mapped_peers: MappedPeers::default(),
+            peer_keepalives: std::sync::Mutex::new(HashMap::new()),
         };
 
-        let rci1 = Arc::clone(&c.relay_conn);
-        let rci2 = Arc::clone(&c.relay_conn);
+        c.flush_in_background();
 
-        if c.refresh_alloc_timer.start(rci1) {
-            log::debug!("refresh_alloc_timer started");
+        if let Some(maintenance) = c.maintenance.clone() {
+            c.schedule_maintenance(&maintenance, refresh_jitter);
+        } else {
+            // the timers share the refresher, the allocation and the
+            // permissions aren't refreshed at the same time
+            let refresher = Arc::new(Mutex::new(c.refresher()));
+            if c.refresh_alloc_timer.start(Arc::clone(&refresher)) {
+                log::debug!("refresh_alloc_timer started");
+            }
+            if c.refresh_perms_timer.start(refresher) {
+                log::debug!("refresh_perms_timer started");
+            }
         }
-        if c.refresh_perms_timer.start(rci2) {
-            log::debug!("refresh_perms_timer started");
+        if let Some(auto_permit_rx) = auto_permit_rx {
+            c.auto_permit_in_background(auto_permit_rx);
         }
 
         c
     }
 
+    // schedule_maintenance registers the refreshes with maintenance, the
+    // bindings being refreshed apart from the permissions
+    fn schedule_maintenance(&mut self, maintenance: &MaintenanceScheduler, refresh_jitter: f64) {
+        let scheduled = Arc::new(ScheduledRefresher {
+            refresher: Mutex::new(RelayConnRefresher {
+                relay_conn: Arc::clone(&self.relay_conn),
+                bindings_apart: true,
+            }),
+            refresh_jitter,
+        });
+        let lifetime = self
+            .relay_conn
+            .try_lock()
+            .map_or(Duration::ZERO, |rc| rc.lifetime);
+        let target: Arc<dyn Maintained> = Arc::clone(&scheduled) as _;
+        let target = Arc::downgrade(&target);
+        maintenance.schedule(
+            Weak::clone(&target),
+            MaintenanceKind::Allocation,
+            jitter_interval(lifetime / 2, refresh_jitter),
+        );
+        maintenance.schedule(
+            Weak::clone(&target),
+            MaintenanceKind::Permissions,
+            PERM_REFRESH_INTERVAL,
+        );
+        maintenance.schedule(target, MaintenanceKind::Bindings, PERM_REFRESH_INTERVAL);
+        self.scheduled = Some(scheduled);
+        log::debug!("refreshes scheduled");
+    }
+
+    // flush_in_background writes the batches of ChannelData with SendBatching,
+    // until the RelayConn is closed or dropped
+    fn flush_in_background(&self) {
+        // the RelayConn was just made, nothing else holds the lock
+        let (send_batcher, obs) = match self.relay_conn.try_lock() {
+            Ok(relay_conn) => match &relay_conn.send_batcher {
+                Some(send_batcher) => (Arc::clone(send_batcher), Arc::clone(&relay_conn.obs)),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let send_backpressure = Arc::clone(&self.send_backpressure);
+        tokio::spawn(async move {
+            while let Some(batch) = send_batcher.next_batch().await {
+                let obs = obs.lock().await;
+                match obs.write_batch(&batch, &obs.turn_server_addr()).await {
+                    Ok(n) if n < batch.len() => {
+                        debug_limited!("wrote {} of a batch of {} ChannelData", n, batch.len());
+                    }
+                    Ok(_) => {}
+                    Err(err) if err.io_kind() == io::ErrorKind::WouldBlock => {
+                        send_backpressure.fetch_add(1, Ordering::Relaxed);
+                        debug_limited!("batch of {} ChannelData dropped: {}", batch.len(), err);
+                    }
+                    Err(err) => warn_limited!("failed to write a batch of ChannelData: {}", err),
+                }
+            }
+        });
+    }
+
+    // auto_permit_in_background permits the peers hinted by Data indications
+    // one at a time, until the RelayConn is closed or dropped
+    fn auto_permit_in_background(&self, mut auto_permit_rx: mpsc::Receiver<SocketAddr>) {
+        let relay_conn = Arc::downgrade(&self.relay_conn);
+        let auto_permit = Arc::clone(&self.auto_permit);
+        tokio::spawn(async move {
+            while let Some(peer) = auto_permit_rx.recv().await {
+                let relay_conn = match relay_conn.upgrade() {
+                    Some(relay_conn) => relay_conn,
+                    None => break,
+                };
+                let mut relay_conn = relay_conn.lock().await;
+                if relay_conn.closed {
+                    break;
+                }
+                if let Err(err) = relay_conn.permit(peer).await {
+                    log::debug!("auto permit for {} failed: {}", peer.ip(), err);
+                    auto_permit.forget(peer);
+                }
+            }
+        });
+    }
+
+    // last_refresh_rtt is the round trip of the last successful Refresh,
+    // CreatePermission or ChannelBind transaction
+    pub fn last_refresh_rtt(&self) -> Option<Duration> {
+        self.path_stats.last_rtt()
+    }
+
+    // path_rtt is a moving average of those round trips, weighting each new
+    // one by 1/8
+    pub fn path_rtt(&self) -> Option<Duration> {
+        self.path_stats.path_rtt()
+    }
+
+    // time_since_last_success is the time since a transaction last succeeded,
+    // or since the RelayConn was created if none has yet
+    pub fn time_since_last_success(&self) -> Duration {
+        self.path_stats.last_success().elapsed()
+    }
+
+    // last_failure is when a transaction last timed out or was answered with
+    // an error response
+    pub fn last_failure(&self) -> Option<Instant> {
+        self.path_stats.last_failure()
+    }
+
+    // ttl_watch follows the allocation's expiry, it is updated after every
+    // refresh attempt, successful or not
+    pub fn ttl_watch(&self) -> watch::Receiver<AllocationTtl> {
+        self.ttl.subscribe()
+    }
+
+    // inbound_dropped is the count of data from peers dropped because the
+    // read queue was full, it is not read fast enough
+    pub fn inbound_dropped(&self) -> u64 {
+        self.inbound_overflow.dropped()
+    }
+
+    // inbound_malformed is the count of Data indications dropped for a missing
+    // or malformed XOR-PEER-ADDRESS or DATA, and of ChannelData dropped for an
+    // unbound channel
+    pub fn inbound_malformed(&self) -> u64 {
+        self.inbound_filter.malformed()
+    }
+
+    // drop_unsolicited_indications drops the Data indications from peer IPs
+    // no permission was requested for, a TURN server only relays from
+    // permitted ones. Those are counted by inbound_unsolicited. It keeps
+    // auto_permit_inbound from permitting new peers. Off by default.
+    pub fn drop_unsolicited_indications(&self, drop_unsolicited: bool) {
+        self.inbound_filter.set_drop_unsolicited(drop_unsolicited);
+    }
+
+    pub fn inbound_unsolicited(&self) -> u64 {
+        self.inbound_filter.unsolicited()
+    }
+
+    // inbound_duplicates is the count of data dropped as a duplicate within
+    // RelayConnConfig::dedup_window
+    pub fn inbound_duplicates(&self) -> u64 {
+        self.inbound_filter.duplicates()
+    }
+
+    // oversized_sends is the count of payloads over warn_payload_size sent
+    pub fn oversized_sends(&self) -> u64 {
+        self.oversized_sends.count()
+    }
+
+    // send_backpressure is the count of sends that failed with
+    // Error::WouldBlock, the transport to the TURN server was backed up
+    pub fn send_backpressure(&self) -> u64 {
+        self.send_backpressure.load(Ordering::Relaxed)
+    }
+
+    // counters samples the RelayConn's counters
+    pub fn counters(&self) -> RelayConnCounters {
+        self.counter_sources().sample()
+    }
+
+    fn counter_sources(&self) -> CounterSources {
+        CounterSources {
+            traffic: Arc::clone(&self.traffic),
+            inbound_overflow: Arc::clone(&self.inbound_overflow),
+            inbound_filter: Arc::clone(&self.inbound_filter),
+            oversized_sends: Arc::clone(&self.oversized_sends),
+            send_backpressure: Arc::clone(&self.send_backpressure),
+        }
+    }
+
+    // start_stats_reporter calls cb with a sample of the counters every
+    // interval, with their change since the previous sample, until the
+    // returned handle is dropped or the RelayConn is closed. cb isn't called
+    // once close returned, it runs on the reporter's task and should not
+    // block. A zero interval reports nothing.
+    pub fn start_stats_reporter(&self, interval: Duration, cb: StatsCallback) -> ReporterHandle {
+        let (handle, mut stop_rx) = ReporterHandle::new();
+        if interval.is_zero() {
+            return handle;
+        }
+
+        let sources = self.counter_sources();
+        let path_stats = Arc::clone(&self.path_stats);
+        let close_signal = Arc::clone(&self.close_signal);
+        let report_gate = Arc::clone(&self.report_gate);
+        let mut previous = sources.sample();
+        let mut previous_at = Instant::now();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = close_signal.raised() => break,
+                    _ = stop_rx.recv() => break,
+                }
+
+                let counters = sources.sample();
+                let now = Instant::now();
+                let stats = RelayConnStats {
+                    counters,
+                    delta: counters.since(&previous),
+                    elapsed: now.saturating_duration_since(previous_at),
+                    path_rtt: path_stats.path_rtt(),
+                };
+                if !report_gate.report(&cb, stats) {
+                    break;
+                }
+                previous = counters;
+                previous_at = now;
+            }
+        });
+
+        handle
+    }
+
+    // subscribe_peer routes the data from peer to the returned receiver instead
+    // of recv_from, so a slow reader of one peer doesn't hold up the others.
+    // Data that doesn't fit its capacity is dropped, see peer_inbound_dropped.
+    // Dropping the receiver unsubscribes, subscribing again replaces it.
+    pub fn subscribe_peer(&self, peer: SocketAddr, capacity: usize) -> mpsc::Receiver<Bytes> {
+        self.subscriptions.subscribe(peer, capacity)
+    }
+
+    // peer_inbound_dropped is the count of data from a subscribed peer dropped
+    // because its receiver was full
+    pub fn peer_inbound_dropped(&self, peer: SocketAddr) -> u64 {
+        self.subscriptions.dropped(&peer)
+    }
+
+    // events subscribes to the RelayConn's events, those emitted before
+    // subscribing are not seen
+    pub fn events(&self) -> broadcast::Receiver<RelayConnEvent> {
+        self.events.subscribe()
+    }
+
+    // probe_peer sends payload to peer, creating its permission if needed, and
+    // returns the time until the peer sent anything back, to check it is
+    // reachable through the allocation before using it. The reply isn't read
+    // by recv_from, data from other peers is. It fails with
+    // Error::ProbeTimedOut without a reply within timeout.
+    pub async fn probe_peer(
+        &self,
+        peer: SocketAddr,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Duration, Error> {
+        let mut probe = self.probes.start(peer)?;
+        let probed = async {
+            self.guard_expiry().await?;
+            let sent_at = {
+                let mut relay_conn = self.relay_conn.lock().await;
+                relay_conn.permit(peer).await?;
+                let sent_at = Instant::now();
+                relay_conn.send_to(payload, peer).await?;
+                sent_at
+            };
+            let replied_at = probe.reply().await?;
+            Ok(replied_at.saturating_duration_since(sent_at))
+        };
+
+        tokio::time::timeout(timeout, probed)
+            .await
+            .map_err(|_| Error::ProbeTimedOut(peer))?
+    }
+
+    // permit_peers creates the permissions of peers in a single
+    // CreatePermission, and with bind_first binds a channel to the first of
+    // them, so sends to them don't wait for a transaction. A failed ChannelBind
+    // leaves the permissions in place, the first peer is then bound on its
+    // first send as usual.
+    pub async fn permit_peers(&self, peers: &[SocketAddr], bind_first: bool) -> Result<(), Error> {
+        let binding = {
+            let mut relay_conn = self.relay_conn.lock().await;
+            relay_conn.permit_all(peers).await?;
+            match peers.first() {
+                Some(peer) if bind_first => relay_conn.prepare_binding(*peer).await?,
+                _ => None,
+            }
+        };
+
+        match binding {
+            Some(binding) => binding.await,
+            None => Ok(()),
+        }
+    }
+
+    // bindings lists the channel bindings by channel number, with when each
+    // was last refreshed and last received on
+    pub async fn bindings(&self) -> Vec<BindingInfo> {
+        let relay_conn = self.relay_conn.lock().await;
+        let binding_mgr = relay_conn.binding_mgr.lock().await;
+        let mut bindings: Vec<BindingInfo> = binding_mgr.bindings().map(Binding::info).collect();
+        bindings.sort_by_key(|b| b.number);
+        bindings
+    }
+
+    // send_keepalive sends the keepalive_payload to a peer this RelayConn has
+    // already sent to, over its channel once bound or else in a Send
+    // indication. Nothing is created for it, a peer without a permission fails
+    // with Error::NoPermission.
+    pub async fn send_keepalive(&self, peer: SocketAddr) -> Result<usize, Error> {
+        let relay_conn = self.relay_conn.lock().await;
+        relay_conn.send_keepalive(peer).await
+    }
+
+    // set_peer_keepalive calls send_keepalive for peer every interval, until
+    // the peer's permission is removed or the RelayConn is closed. It replaces
+    // the peer's previous keepalive, a zero interval stops it.
+    pub fn set_peer_keepalive(&self, peer: SocketAddr, interval: Duration) {
+        let mut peer_keepalives = self
+            .peer_keepalives
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if peer_keepalives.remove(&peer).is_some() {
+            state_event!("keepalive to {} stopped", peer);
+        }
+        if interval.is_zero() {
+            return;
+        }
+
+        let (close_tx, mut close_rx) = mpsc::channel::<()>(1);
+        let relay_conn = Arc::clone(&self.relay_conn);
+        state_event!("keepalive to {} started every {:?}", peer, interval);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        let result = {
+                            let relay_conn = relay_conn.lock().await;
+                            relay_conn.send_keepalive(peer).await
+                        };
+                        match result {
+                            Ok(_) => {}
+                            Err(Error::NoPermission) => {
+                                state_event!("keepalive to {} stopped, no permission", peer);
+                                break;
+                            }
+                            Err(err) => log::warn!("keepalive to {} failed: {}", peer, err),
+                        }
+                    }
+                    _ = close_rx.recv() => break,
+                }
+            }
+        });
+
+        peer_keepalives.insert(peer, close_tx);
+    }
+
+    // poll_recv_from is recv_from for poll based callers, the task is woken
+    // when data is queued, whichever other readers are waiting
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        p: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        match self.read_ch_rx.poll_recv(cx) {
+            Poll::Ready(Some(ib_data)) => {
+                let n = ib_data.data.len();
+                if p.len() < n {
+                    return Poll::Ready(Err(Error::ShortBuffer.into()));
+                }
+                p[..n].copy_from_slice(&ib_data.data);
+                Poll::Ready(Ok((n, self.mapped_peers.to_caller(ib_data.from))))
+            }
+            Poll::Ready(None) => Poll::Ready(Err(Error::AlreadyClosed.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
     // Close closes the connection.
     // Any blocked ReadFrom or write_to operations will be unblocked and return errors.
     pub async fn close(&mut self) -> Result<(), Error> {
         self.refresh_alloc_timer.stop();
         self.refresh_perms_timer.stop();
+        self.scheduled = None;
+        self.peer_keepalives
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+        self.auto_permit.stop();
+        // a refresh holding the lock is abandoned rather than waited for
+        self.close_signal.raise();
+        self.report_gate.close();
 
         let mut relay_conn = self.relay_conn.lock().await;
         relay_conn.close().await
     }
+
+    // allocation_id identifies the allocation, with the relayed address of
+    // the Allocate response
+    pub async fn allocation_id(&self) -> AllocationId {
+        let relay_conn = self.relay_conn.lock().await;
+        let obs = relay_conn.obs.lock().await;
+        allocation_id(&*obs, relay_conn.allocated_addr, relay_conn.transport)
+    }
+
+    // server_addr is the address of the TURN server the allocation is on,
+    // None when the RelayConnConfig didn't tell
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        self.server_addr
+    }
+
+    // transport is the transport to the TURN server
+    pub fn transport(&self) -> TransportKind {
+        self.transport
+    }
+
+    // underlying_local_addr is the local address of the connection to the
+    // TURN server, local_addr being the relayed address. None when the
+    // RelayConnConfig didn't tell.
+    pub fn underlying_local_addr(&self) -> Option<SocketAddr> {
+        self.underlying_local_addr
+    }
+
+    // internal is the state shared with the refresh timers, a ClientPool
+    // keeps it to deallocate on close
+    pub(crate) fn internal(&self) -> Arc<Mutex<RelayConnInternal<T>>> {
+        Arc::clone(&self.relay_conn)
+    }
+
+    // refresher runs the refreshes of the refresh timers on demand
+    pub(crate) fn refresher(&self) -> RelayConnRefresher<T> {
+        RelayConnRefresher {
+            relay_conn: Arc::clone(&self.relay_conn),
+            bindings_apart: false,
+        }
+    }
+
+    // guard_expiry refreshes the allocation when its refreshes have been
+    // failing and it expires within expiry_guard, or fails with
+    // Error::AllocationExpiring with fail_fast_on_expiry. The refresh is the
+    // refresh timer's, without the RelayConnInternal held, one send runs it
+    // while the others wait on alloc_refreshed. The refresh timer starts over
+    // after a successful refresh.
+    async fn guard_expiry(&self) -> Result<(), Error> {
+        let remaining = loop {
+            let refreshed = self.alloc_refreshed.notified();
+            tokio::pin!(refreshed);
+            refreshed.as_mut().enable();
+
+            let ttl = self.ttl.get();
+            let remaining = ttl.remaining();
+            if ttl.last_refresh_ok || remaining > self.expiry_guard {
+                return Ok(());
+            }
+            if self.fail_fast_on_expiry {
+                return Err(Error::AllocationExpiring { remaining });
+            }
+            if !self.guard_refreshing.swap(true, Ordering::SeqCst) {
+                break remaining;
+            }
+            refreshed.await;
+        };
+
+        log::debug!("allocation expires in {:?}, refreshing", remaining);
+        let result = {
+            let _refreshing = GuardRefreshing {
+                refreshing: &self.guard_refreshing,
+                alloc_refreshed: &self.alloc_refreshed,
+            };
+            self.refresher().refresh_allocation().await
+        };
+        if result.is_ok() {
+            self.alloc_refreshed.notify_one();
+            state_event!("allocation refreshed by send_to");
+        }
+        result
+    }
 }
 
 #[async_trait]
 impl<T: RelayConnObserver + Send + Sync> Conn for RelayConn<T> {
     async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+        Err(io::Error::other("Not applicable"))
     }
 
     async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+        Err(io::Error::other("Not applicable"))
     }
 
     // ReadFrom reads a packet from the connection,
@@ -139,28 +1300,11 @@ impl<T: RelayConnObserver + Send + Sync> Conn for RelayConn<T> {
     // an Error with Timeout() == true after a fixed time limit;
     // see SetDeadline and SetReadDeadline.
     async fn recv_from(&self, p: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        let mut read_ch_rx = self.read_ch_rx.lock().await;
-
-        if let Some(ib_data) = read_ch_rx.recv().await {
-            let n = ib_data.data.len();
-            if p.len() < n {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    ERR_SHORT_BUFFER.to_string(),
-                ));
-            }
-            p[..n].copy_from_slice(&ib_data.data);
-            Ok((n, ib_data.from))
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                ERR_ALREADY_CLOSED.to_string(),
-            ))
-        }
+        std::future::poll_fn(|cx| self.poll_recv_from(cx, p)).await
     }
 
     async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+        Err(io::Error::other("Not applicable"))
     }
 
     // write_to writes a packet with payload p to addr.
@@ -168,31 +1312,86 @@ impl<T: RelayConnObserver + Send + Sync> Conn for RelayConn<T> {
     // an Error with Timeout() == true after a fixed time limit;
     // see SetDeadline and SetWriteDeadline.
     // On packet-oriented connections, write timeouts are rare.
+    // A peer the server can't reach from the relayed address's family fails
+    // with PermissionDenied and Error::PeerAddressFamilyMismatch as source,
+    // another candidate of the peer should be picked.
     async fn send_to(&self, p: &[u8], addr: SocketAddr) -> io::Result<usize> {
-        let mut relay_conn = self.relay_conn.lock().await;
-        match relay_conn.send_to(p, addr).await {
-            Ok(n) => Ok(n),
-            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        self.mapped_peers.record(addr);
+        self.guard_expiry().await?;
+        let result = {
+            let mut relay_conn = self.relay_conn.lock().await;
+            relay_conn.send_to(p, addr).await
+        };
+        match result {
+            Ok(n) => {
+                self.traffic.record_sent(p.len());
+                Ok(n)
+            }
+            Err(err) => {
+                self.refresher().reallocate_if_lost(&err).await;
+                Err(err.into())
+            }
         }
     }
 
-    // LocalAddr returns the local network address.
+    // LocalAddr returns the local network address, it follows the relayed
+    // address a Refresh response moved the allocation to
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        Ok(self.relayed_addr)
+        Ok(self.relayed_addr.get())
     }
 }
 
 impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
     // new creates a new instance of UDPConn
-    fn new(obs: Arc<Mutex<T>>, config: RelayConnConfig) -> Self {
+    fn new(
+        obs: Arc<Mutex<T>>,
+        config: RelayConnConfig,
+        path_stats: Arc<PathStats>,
+        ttl: Arc<TtlWatch>,
+        relayed_addr: Arc<RelayedAddr>,
+        oversized_sends: Arc<OversizedSends>,
+        send_backpressure: Arc<AtomicU64>,
+    ) -> Self {
         RelayConnInternal {
             obs,
-            relayed_addr: config.relayed_addr,
+            allocated_addr: config.relayed_addr,
+            relayed_addr,
             perm_map: PermissionMap::new(),
             binding_mgr: config.binding_mgr,
             integrity: config.integrity,
             nonce: config.nonce,
             lifetime: config.lifetime,
+            max_bindings: if config.max_bindings == 0 {
+                MAX_BINDINGS
+            } else {
+                config.max_bindings
+            },
+            keepalive_payload: config.keepalive_payload,
+            retry_policy: config.retry_policy,
+            auto_reallocate: config.auto_reallocate,
+            require_refresh_lifetime: config.require_refresh_lifetime,
+            lifetime_missing: Arc::new(AtomicBool::new(false)),
+            server_quirks: config.server_quirks,
+            alloc_refreshed: config.alloc_refreshed,
+            channel_bind_policy: config.channel_bind_policy,
+            transport: config.transport,
+            send_rates: SendRates::default(),
+            message_customizer: config.message_customizer,
+            path_stats,
+            ttl,
+            events: config.events,
+            inbound_filter: config.inbound_filter,
+            warn_payload_size: config.warn_payload_size,
+            max_payload_size: config.max_payload_size,
+            oversized_sends,
+            send_backpressure,
+            send_batcher: config
+                .send_batching
+                .as_ref()
+                .map(|send_batching| Arc::new(SendBatcher::new(send_batching))),
+            close_signal: config.close_signal,
+            send_buf: std::sync::Mutex::new(vec![]),
+            closed: false,
         }
     }
 
@@ -202,37 +1401,30 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
     // see SetDeadline and SetWriteDeadline.
     // On packet-oriented connections, write timeouts are rare.
     async fn send_to(&mut self, p: &[u8], addr: SocketAddr) -> Result<usize, Error> {
-        // check if we have a permission for the destination IP addr
-        let mut perm = if let Some(perm) = self.perm_map.find(&addr) {
-            *perm
-        } else {
-            let perm = Permission::default();
-            self.perm_map.insert(&addr, perm);
-            perm
-        };
-
-        let mut result = Ok(());
-        for _ in 0..MAX_RETRY_ATTEMPTS {
-            result = self.create_perm(&mut perm, addr).await;
-            if let Err(err) = &result {
-                if *err != *ERR_TRY_AGAIN {
-                    break;
-                }
+        let addr = canonical_peer_addr(addr);
+        if let Some(max) = self.max_payload_size {
+            if p.len() > max {
+                return Err(Error::PayloadTooLarge { size: p.len(), max });
             }
         }
-        if let Err(err) = result {
-            return Err(err);
+        if let Some(warn_payload_size) = self.warn_payload_size {
+            if p.len() > warn_payload_size {
+                self.oversized_sends
+                    .record(addr, p.len(), warn_payload_size, &self.events);
+            }
+        }
+
+        self.permit(addr).await?;
+        if !self.wants_channel(addr).await {
+            return self.send_indication(p, addr).await;
         }
 
         let number = {
             let (bind_st, bind_at, bind_number, bind_addr) = {
                 let mut binding_mgr = self.binding_mgr.lock().await;
-                let b = if let Some(b) = binding_mgr.find_by_addr(&addr) {
-                    b
-                } else {
-                    binding_mgr
-                        .create(addr)
-                        .ok_or_else(|| Error::new("Addr not found".to_owned()))?
+                let b = match binding_mgr.get_by_addr(&addr) {
+                    Some(b) => b,
+                    None => binding_mgr.create(addr, self.max_bindings)?,
                 };
                 (b.state(), b.refreshed_at(), b.number, b.addr)
             };
@@ -245,108 +1437,185 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
                 // the binding transaction has been complete
                 // binding state may have been changed while waiting. check again.
                 if bind_st == BindingState::Idle {
-                    let binding_mgr = Arc::clone(&self.binding_mgr);
-                    let rc_obs = Arc::clone(&self.obs);
-                    let nonce = self.nonce.clone();
-                    let integrity = self.integrity.clone();
-                    tokio::spawn(async move {
-                        {
-                            let mut bm = binding_mgr.lock().await;
-                            if let Some(b) = bm.get_by_addr(&bind_addr) {
-                                b.set_state(BindingState::Request);
-                            }
-                        }
-
-                        let result = RelayConnInternal::bind(
-                            rc_obs,
-                            bind_addr,
-                            bind_number,
-                            nonce,
-                            integrity,
-                        )
-                        .await;
-
-                        {
-                            let mut bm = binding_mgr.lock().await;
-                            if let Err(err) = result {
-                                if err != *ERR_UNEXPECTED_RESPONSE {
-                                    bm.delete_by_addr(&bind_addr);
-                                } else if let Some(b) = bm.get_by_addr(&bind_addr) {
-                                    b.set_state(BindingState::Failed);
-                                }
-
-                                // keep going...
-                                log::warn!("bind() failed: {}", err);
-                            } else if let Some(b) = bm.get_by_addr(&bind_addr) {
-                                b.set_state(BindingState::Ready);
-                            }
-                        }
-                    });
+                    self.bind_in_background(bind_addr, bind_number, false);
                 }
 
                 // send data using SendIndication
-                let peer_addr = socket_addr2peer_address(&addr);
-                let mut msg = Message::new();
-                msg.build(&[
-                    Box::new(TransactionId::new()),
-                    Box::new(MessageType::new(METHOD_SEND, CLASS_INDICATION)),
-                    Box::new(proto::data::Data(p.to_vec())),
-                    Box::new(peer_addr),
-                    Box::new(FINGERPRINT),
-                ])?;
-
-                // indication has no transaction (fire-and-forget)
-                let obs = self.obs.lock().await;
-                let turn_server_addr = obs.turn_server_addr();
-                return obs.write_to(&msg.raw, &turn_server_addr).await;
+                return self.send_indication(p, addr).await;
             }
 
             // binding is either ready
 
             // check if the binding needs a refresh
             if bind_st == BindingState::Ready
-                && Instant::now().duration_since(bind_at) > Duration::from_secs(5 * 60)
+                && Instant::now().duration_since(bind_at) > BINDING_REFRESH_INTERVAL
             {
-                let binding_mgr = Arc::clone(&self.binding_mgr);
-                let rc_obs = Arc::clone(&self.obs);
-                let nonce = self.nonce.clone();
-                let integrity = self.integrity.clone();
-                tokio::spawn(async move {
-                    {
-                        let mut bm = binding_mgr.lock().await;
-                        if let Some(b) = bm.get_by_addr(&bind_addr) {
-                            b.set_state(BindingState::Refresh);
-                        }
-                    }
+                self.bind_in_background(bind_addr, bind_number, true);
+            }
 
-                    let result =
-                        RelayConnInternal::bind(rc_obs, bind_addr, bind_number, nonce, integrity)
-                            .await;
-
-                    {
-                        let mut bm = binding_mgr.lock().await;
-                        if let Err(err) = result {
-                            if err != *ERR_UNEXPECTED_RESPONSE {
-                                bm.delete_by_addr(&bind_addr);
-                            } else if let Some(b) = bm.get_by_addr(&bind_addr) {
-                                b.set_state(BindingState::Failed);
-                            }
+            bind_number
+        };
+
+        // send via ChannelData
+        self.send_channel_data(p, number).await
+    }
 
-                            // keep going...
+    // wants_channel reports if data to addr goes through a channel binding,
+    // bound or to be bound, as the channel_bind_policy decides
+    async fn wants_channel(&mut self, addr: SocketAddr) -> bool {
+        match self.channel_bind_policy {
+            ChannelBindPolicy::Always => true,
+            _ if self.binding_mgr.lock().await.get_by_addr(&addr).is_some() => true,
+            ChannelBindPolicy::Never => false,
+            ChannelBindPolicy::Adaptive {
+                min_packets_per_interval,
+            } => self.send_rates.record(addr, min_packets_per_interval),
+        }
+    }
+
+    // permit creates the permission for the IP of addr unless it has one
+    async fn permit(&mut self, addr: SocketAddr) -> Result<(), Error> {
+        let mut perm = if let Some(perm) = self.perm_map.find(&addr) {
+            *perm
+        } else {
+            let perm = Permission::default();
+            self.perm_map.insert(&addr, perm);
+            perm
+        };
+
+        let retry_policy = self.retry_policy;
+        retry(&retry_policy, &mut (&mut *self, &mut perm), |(rc, perm)| {
+            Box::pin(rc.create_perm(perm, addr))
+        })
+        .await
+    }
+
+    // bind_in_background binds the channel of a binding, or refreshes it, in a
+    // spawned task
+    fn bind_in_background(&self, bind_addr: SocketAddr, bind_number: u16, refresh: bool) {
+        let binding = self.bind_binding(bind_addr, bind_number, refresh);
+        tokio::spawn(async move {
+            let _ = binding.await;
+        });
+    }
+
+    // bind_binding binds the channel of a binding, or refreshes it, without
+    // holding the RelayConnInternal. The state changes are emitted once the
+    // BindingManager is unlocked.
+    fn bind_binding(
+        &self,
+        bind_addr: SocketAddr,
+        bind_number: u16,
+        refresh: bool,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        let binding_mgr = Arc::clone(&self.binding_mgr);
+        let snapshot = self.auth_snapshot();
+        let events = Arc::clone(&self.events);
+        async move {
+            let state = if refresh {
+                BindingState::Refresh
+            } else {
+                BindingState::Request
+            };
+            let event = {
+                let mut bm = binding_mgr.lock().await;
+                bm.get_by_addr(&bind_addr)
+                    .and_then(|b| set_binding_state(b, state, None))
+            };
+            if let Some(event) = event {
+                events.emit(event);
+            }
+
+            let result = snapshot.bind(bind_addr, bind_number).await;
+
+            let (event, result) = {
+                let mut bm = binding_mgr.lock().await;
+                match result {
+                    Err(err) => {
+                        // keep going...
+                        if refresh {
                             log::warn!("bind() for refresh failed: {}", err);
-                        } else if let Some(b) = bm.get_by_addr(&bind_addr) {
-                            b.set_refreshed_at(Instant::now());
-                            b.set_state(BindingState::Ready);
+                        } else {
+                            log::warn!("bind() failed: {}", err);
+                        }
+
+                        let event = bm.get_by_addr(&bind_addr).and_then(|b| {
+                            set_binding_state(b, BindingState::Failed, Some(err.to_string()))
+                        });
+                        if !matches!(err, Error::UnexpectedResponse(_)) {
+                            bm.delete_by_addr(&bind_addr);
                         }
+                        (event, Err(err))
                     }
-                });
+                    Ok(()) => {
+                        let event = bm.get_by_addr(&bind_addr).and_then(|b| {
+                            if refresh {
+                                b.set_refreshed_at(Instant::now());
+                            }
+                            set_binding_state(b, BindingState::Ready, None)
+                        });
+                        (event, Ok(()))
+                    }
+                }
+            };
+            if let Some(event) = event {
+                events.emit(event);
             }
+            result
+        }
+    }
 
-            bind_number
-        };
+    // permit_all creates the permissions the peers lack in a single
+    // CreatePermission
+    async fn permit_all(&mut self, peers: &[SocketAddr]) -> Result<(), Error> {
+        let mut idle: Vec<SocketAddr> = vec![];
+        for peer in peers {
+            let permitted = self
+                .perm_map
+                .find(peer)
+                .is_some_and(|perm| perm.state() == PermState::Permitted);
+            // a permission covers the IP, one XOR-PEER-ADDRESS per IP is enough
+            if !permitted && !idle.iter().any(|addr| addr.ip() == peer.ip()) {
+                idle.push(*peer);
+            }
+        }
+        if idle.is_empty() {
+            return Ok(());
+        }
 
-        // send via ChannelData
-        self.send_channel_data(p, number).await
+        let retry_policy = self.retry_policy;
+        retry(&retry_policy, &mut (&mut *self, &idle), |(rc, idle)| {
+            Box::pin(rc.create_permissions(idle))
+        })
+        .await?;
+
+        for peer in idle {
+            let mut perm = Permission::default();
+            perm.set_state(PermState::Permitted);
+            self.perm_map.insert(&peer, perm);
+            self.events.emit(RelayConnEvent::PermissionStateChanged {
+                peer,
+                old: PermState::Idle,
+                new: PermState::Permitted,
+            });
+        }
+        Ok(())
+    }
+
+    // prepare_binding makes the binding of peer and returns its bind, none
+    // when it is already bound or being bound
+    async fn prepare_binding(
+        &self,
+        peer: SocketAddr,
+    ) -> Result<Option<impl Future<Output = Result<(), Error>> + Send + 'static>, Error> {
+        let bind_number = {
+            let mut binding_mgr = self.binding_mgr.lock().await;
+            if binding_mgr.find_by_addr(&peer).is_some() {
+                return Ok(None);
+            }
+            binding_mgr.create(peer, self.max_bindings)?.number
+        };
+        Ok(Some(self.bind_binding(peer, bind_number, false)))
     }
 
     // This func-block would block, per destination IP (, or perm), until
@@ -364,71 +1633,187 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
                 return Err(err);
             }
             perm.set_state(PermState::Permitted);
+            self.perm_map.insert(&addr, *perm);
+            self.events.emit(RelayConnEvent::PermissionStateChanged {
+                peer: addr,
+                old: PermState::Idle,
+                new: PermState::Permitted,
+            });
         }
         Ok(())
     }
 
-    async fn send_channel_data(&self, data: &[u8], ch_num: u16) -> Result<usize, Error> {
-        let mut ch_data = proto::chandata::ChannelData {
-            data: data.to_vec(),
-            number: proto::channum::ChannelNumber(ch_num),
-            ..Default::default()
+    // send_keepalive sends keepalive_payload to a permitted peer without
+    // creating a permission or binding
+    async fn send_keepalive(&self, peer: SocketAddr) -> Result<usize, Error> {
+        if self.perm_map.find(&peer).is_none() {
+            return Err(Error::NoPermission);
+        }
+
+        let number = {
+            let binding_mgr = self.binding_mgr.lock().await;
+            binding_mgr
+                .find_by_addr(&peer)
+                .filter(|b| matches!(b.state(), BindingState::Ready | BindingState::Refresh))
+                .map(|b| b.number)
         };
-        ch_data.encode();
 
+        match number {
+            Some(number) => {
+                self.send_channel_data(&self.keepalive_payload, number)
+                    .await
+            }
+            None => self.send_indication(&self.keepalive_payload, peer).await,
+        }
+    }
+
+    async fn send_indication(&self, data: &[u8], addr: SocketAddr) -> Result<usize, Error> {
         let obs = self.obs.lock().await;
-        obs.write_to(&ch_data.raw, &obs.turn_server_addr()).await
+        let mut msg = Message::new();
+        {
+            let mut setters: Vec<Box<dyn Setter>> = vec![
+                Box::new(TransactionId::new()),
+                Box::new(MessageType::new(METHOD_SEND, CLASS_INDICATION)),
+                Box::new(proto::data::Data(data.to_vec())),
+                Box::new(socket_addr2peer_address(&addr)),
+            ];
+            if self.server_quirks.username_on_indications {
+                setters.push(Box::new(obs.username()));
+            }
+            msg.build(&setters)?;
+        }
+        seal(
+            &mut msg,
+            self.message_customizer.as_ref(),
+            None,
+            self.server_quirks,
+        )?;
+
+        // indication has no transaction (fire-and-forget)
+        let turn_server_addr = obs.turn_server_addr();
+        let result = obs.write_to(&msg.raw, &turn_server_addr).await;
+        self.classify_write(result)
     }
 
-    async fn create_permissions(&mut self, addrs: &[SocketAddr]) -> Result<(), Error> {
-        let res = {
-            let msg = {
-                let obs = self.obs.lock().await;
-                let mut setters: Vec<Box<dyn Setter>> = vec![
-                    Box::new(TransactionId::new()),
-                    Box::new(MessageType::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST)),
-                ];
-
-                for addr in addrs {
-                    setters.push(Box::new(socket_addr2peer_address(addr)));
-                }
+    async fn send_channel_data(&self, data: &[u8], ch_num: u16) -> Result<usize, Error> {
+        if let Some(send_batcher) = &self.send_batcher {
+            let mut frame = vec![];
+            proto::chandata::ChannelData::encode_data_to(
+                proto::channum::ChannelNumber(ch_num),
+                data,
+                &mut frame,
+            )?;
+            return send_batcher.push(frame).await;
+        }
 
-                setters.push(Box::new(obs.username()));
-                setters.push(Box::new(obs.realm()));
-                setters.push(Box::new(self.nonce.clone()));
-                setters.push(Box::new(self.integrity.clone()));
-                setters.push(Box::new(FINGERPRINT));
-
-                let mut msg = Message::new();
-                msg.build(&setters)?;
-                msg
-            };
+        let mut buf =
+            std::mem::take(&mut *self.send_buf.lock().unwrap_or_else(|err| err.into_inner()));
+        proto::chandata::ChannelData::encode_data_to(
+            proto::channum::ChannelNumber(ch_num),
+            data,
+            &mut buf,
+        )?;
+
+        let result = {
+            let obs = self.obs.lock().await;
+            obs.write_to(&buf, &obs.turn_server_addr()).await
+        };
+        *self.send_buf.lock().unwrap_or_else(|err| err.into_inner()) = buf;
+        self.classify_write(result)
+    }
 
-            let mut obs = self.obs.lock().await;
-            let turn_server_addr = obs.turn_server_addr();
+    // classify_write turns a write_to that would block into Error::WouldBlock
+    // and counts it, other errors are returned as they are
+    fn classify_write(&self, result: Result<usize, Error>) -> Result<usize, Error> {
+        match result {
+            Err(err) if err.io_kind() == io::ErrorKind::WouldBlock => {
+                self.send_backpressure.fetch_add(1, Ordering::Relaxed);
+                trace_limited!("write to the TURN server would block: {}", err);
+                Err(Error::WouldBlock)
+            }
+            result => result,
+        }
+    }
 
-            log::debug!("UDPConn.createPermissions call PerformTransaction 1");
-            let tr_res = obs
-                .perform_transaction(&msg, &turn_server_addr, false)
-                .await?;
+    async fn create_permissions(&mut self, addrs: &[SocketAddr]) -> Result<(), Error> {
+        for addr in addrs {
+            self.inbound_filter.contact(*addr);
+        }
+        let mut snapshot = self.auth_snapshot();
+        let result = snapshot.create_permissions(addrs).await;
+        self.commit(&snapshot);
+        match result {
+            Err(Error::Protocol { code, .. }) if code == PEER_ADDRESS_FAMILY_MISMATCH => {
+                Err(self.forget_mismatched_peers(addrs).await)
+            }
+            result => result,
+        }
+    }
 
-            tr_res.msg
-        };
+    // auth_snapshot copies what authenticated requests are sent with, for a
+    // transaction run without holding the RelayConnInternal
+    fn auth_snapshot(&self) -> AuthSnapshot<T> {
+        AuthSnapshot {
+            obs: Arc::clone(&self.obs),
+            issued: self.nonce.clone(),
+            nonce: self.nonce.clone(),
+            integrity: self.integrity.clone(),
+            path_stats: Arc::clone(&self.path_stats),
+            customizer: self.message_customizer.clone(),
+            quirks: self.server_quirks,
+            require_lifetime: self.require_refresh_lifetime,
+            lifetime_missing: Arc::clone(&self.lifetime_missing),
+        }
+    }
 
-        if res.typ.class == CLASS_ERROR_RESPONSE {
-            let mut code = ErrorCodeAttribute::default();
-            let result = code.get_from(&res);
-            if result.is_err() {
-                return Err(Error::new(format!("{}", res.typ)));
-            } else if code.code == CODE_STALE_NONCE {
-                self.set_nonce_from_msg(&res);
-                return Err(ERR_TRY_AGAIN.to_owned());
-            } else {
-                return Err(Error::new(format!("{} (error {})", res.typ, code)));
+    // commit keeps the nonce a 438 gave the snapshot, unless the snapshot
+    // didn't get one, as another transaction may have since
+    fn commit(&mut self, snapshot: &AuthSnapshot<T>) {
+        if snapshot.nonce.text != snapshot.issued.text {
+            self.nonce = snapshot.nonce.clone();
+        }
+    }
+
+    // forget_mismatched_peers drops the permissions and bindings of the peers
+    // a 443 was answered for, so they aren't refreshed again. Those of the
+    // other family than the relayed address are picked, all of addrs when
+    // none is.
+    async fn forget_mismatched_peers(&mut self, addrs: &[SocketAddr]) -> Error {
+        let relayed_addr = self.relayed_addr.get();
+        let mut mismatched: Vec<SocketAddr> = addrs
+            .iter()
+            .filter(|addr| addr.is_ipv4() != relayed_addr.is_ipv4())
+            .copied()
+            .collect();
+        if mismatched.is_empty() {
+            mismatched = addrs.to_vec();
+        }
+
+        let mut binding_mgr = self.binding_mgr.lock().await;
+        for addr in &mismatched {
+            log::warn!(
+                "peer {} is not of the family of relayed address {}",
+                addr,
+                relayed_addr
+            );
+            if self
+                .perm_map
+                .find(addr)
+                .is_some_and(|perm| perm.state() == PermState::Permitted)
+            {
+                self.events.emit(RelayConnEvent::PermissionStateChanged {
+                    peer: *addr,
+                    old: PermState::Permitted,
+                    new: PermState::Idle,
+                });
             }
+            self.perm_map.delete(addr);
+            binding_mgr.delete_by_ip(addr.ip());
         }
 
-        Ok(())
+        Error::PeerAddressFamilyMismatch {
+            peer: mismatched[0],
+        }
     }
 
     pub fn set_nonce_from_msg(&mut self, msg: &Message) {
@@ -445,8 +1830,24 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
     // Close closes the connection.
     // Any blocked ReadFrom or write_to operations will be unblocked and return errors.
     pub async fn close(&mut self) -> Result<(), Error> {
-        self.refresh_allocation(Duration::from_secs(0), true /* dontWait=true */)
-            .await
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.close_signal.raise();
+        if let Some(send_batcher) = &self.send_batcher {
+            send_batcher.close();
+        }
+
+        let result = self
+            .refresh_allocation(Duration::from_secs(0), true /* dontWait=true */)
+            .await;
+
+        let mut obs = self.obs.lock().await;
+        let id = allocation_id(&*obs, self.allocated_addr, self.transport);
+        obs.on_deallocated(&id);
+
+        result
     }
 
     async fn refresh_allocation(
@@ -454,86 +1855,586 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
         lifetime: Duration,
         dont_wait: bool,
     ) -> Result<(), Error> {
-        let res = {
+        if dont_wait {
             let mut obs = self.obs.lock().await;
-
-            let mut msg = Message::new();
-            msg.build(&[
-                Box::new(TransactionId::new()),
-                Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
-                Box::new(proto::lifetime::Lifetime(lifetime)),
-                Box::new(obs.username()),
-                Box::new(obs.realm()),
-                Box::new(self.nonce.clone()),
-                Box::new(self.integrity.clone()),
-                Box::new(FINGERPRINT),
-            ])?;
-
+            let msg = refresh_request(
+                &*obs,
+                lifetime,
+                &self.nonce,
+                &self.integrity,
+                self.message_customizer.as_ref(),
+                self.server_quirks,
+            )?;
             log::debug!("send refresh request (dont_wait={})", dont_wait);
             let turn_server_addr = obs.turn_server_addr();
-            let tr_res = obs
-                .perform_transaction(&msg, &turn_server_addr, dont_wait)
+            obs.perform_transaction_with(&msg, &turn_server_addr, true, DEALLOCATE_TRANSACTION)
                 .await?;
+            log::debug!("refresh request sent");
+            return Ok(());
+        }
 
-            if dont_wait {
-                log::debug!("refresh request sent");
-                return Ok(());
+        let mut snapshot = self.auth_snapshot();
+        let result = snapshot.refresh(lifetime).await;
+        self.commit(&snapshot);
+        let (updated_lifetime, relayed_addr) = result?;
+        self.refreshed(updated_lifetime, relayed_addr);
+        Ok(())
+    }
+
+    // refreshed takes the lifetime and relayed address of a Refresh response
+    fn refreshed(&mut self, lifetime: Duration, relayed_addr: Option<SocketAddr>) {
+        self.lifetime = lifetime;
+        log::debug!("updated lifetime: {} seconds", self.lifetime.as_secs());
+
+        // a server may move the allocation to another relayed address, most
+        // send none
+        if let Some(new) = relayed_addr {
+            let old = self.relayed_addr.replace(new);
+            if old != new {
+                state_event!("relayed address moved from {} to {}", old, new);
+                self.events
+                    .emit(RelayConnEvent::RelayedAddressChanged { old, new });
             }
+        }
+    }
 
-            log::debug!("refresh request sent, and waiting response");
+    async fn refresh_permissions(&mut self) -> Result<(), Error> {
+        let addrs = self.perm_map.addrs();
+        if addrs.is_empty() {
+            log::debug!("no permission to refresh");
+            return Ok(());
+        }
+
+        if let Err(err) = self.create_permissions(&addrs).await {
+            if !matches!(err, Error::TryAgain) {
+                log::error!("fail to refresh permissions: {}", err);
+            }
+            return Err(err);
+        }
+
+        log::debug!("refresh permissions successful");
+        Ok(())
+    }
+
+    // refresh_received_bindings refreshes in the background the bindings past
+    // BINDING_REFRESH_INTERVAL that ChannelData came in on since, those only
+    // sent on are refreshed by send_to. A binding seeing no traffic either
+    // way is left to lapse on the server.
+    async fn refresh_received_bindings(&self) {
+        let now = Instant::now();
+        let due: Vec<(SocketAddr, u16)> = {
+            let binding_mgr = self.binding_mgr.lock().await;
+            binding_mgr
+                .bindings()
+                .filter(|b| {
+                    b.state() == BindingState::Ready
+                        && now.duration_since(b.refreshed_at()) > BINDING_REFRESH_INTERVAL
+                        && b.received_since_refresh()
+                })
+                .map(|b| (b.addr, b.number))
+                .collect()
+        };
+        for (addr, number) in due {
+            self.bind_in_background(addr, number, true);
+        }
+    }
+
+    // reallocated takes the new allocation of a reallocation
+    fn reallocated(&mut self, reallocation: Reallocation) {
+        self.integrity = reallocation.integrity;
+        self.nonce = reallocation.nonce;
+        self.lifetime = reallocation.lifetime;
+        self.allocated_addr = reallocation.relayed_addr;
+        self.ttl.refreshed(Some(self.lifetime));
+        let new = reallocation.relayed_addr;
+        let old = self.relayed_addr.replace(new);
+        if old != new {
+            state_event!("reallocated, relayed address moved from {} to {}", old, new);
+            self.events
+                .emit(RelayConnEvent::RelayedAddressChanged { old, new });
+        }
+    }
+}
+
+// RelayConnRefresher is the handler of the refresh timers. It only holds the
+// RelayConnInternal to take an AuthSnapshot and to commit the outcome, so
+// send_to isn't held up by a Refresh or CreatePermission being retransmitted
+// or retried.
+pub(crate) struct RelayConnRefresher<T: 'static + RelayConnObserver + Send + Sync> {
+    relay_conn: Arc<Mutex<RelayConnInternal<T>>>,
+    // bindings_apart leaves the bindings to refresh_bindings, a permission
+    // refresh refreshes them otherwise
+    bindings_apart: bool,
+}
+
+impl<T: RelayConnObserver + Send + Sync> RelayConnRefresher<T> {
+    // refresh_allocation refreshes the allocation, and reallocates when the
+    // server lost it. It fails with Error::AlreadyClosed once closing.
+    async fn refresh_allocation(&self) -> Result<(), Error> {
+        let (mut snapshot, lifetime, retry_policy, close_signal) = {
+            let rc = self.relay_conn.lock().await;
+            if rc.closed || rc.close_signal.is_raised() {
+                return Err(Error::AlreadyClosed);
+            }
+            (
+                rc.auth_snapshot(),
+                rc.lifetime,
+                rc.retry_policy,
+                Arc::clone(&rc.close_signal),
+            )
+        };
+
+        // when stale nonce returns, the second try should succeed. Closing
+        // drops the refresh with its transaction, whichever retry it is at.
+        let result = tokio::select! {
+            result = retry(&retry_policy, &mut snapshot, |snapshot| {
+                Box::pin(snapshot.refresh(lifetime))
+            }) => result,
+            _ = close_signal.raised() => {
+                log::debug!("refresh allocation abandoned, closing");
+                return Err(Error::AlreadyClosed);
+            }
+        };
+
+        let err = {
+            let mut rc = self.relay_conn.lock().await;
+            if rc.closed {
+                return Err(Error::AlreadyClosed);
+            }
+            rc.commit(&snapshot);
+            match result {
+                Ok((updated_lifetime, relayed_addr)) => {
+                    rc.refreshed(updated_lifetime, relayed_addr);
+                    rc.ttl.refreshed(Some(rc.lifetime));
+                    state_event!(
+                        "allocation refreshed for {:?}, rtt {:?}",
+                        lifetime,
+                        rc.path_stats.last_rtt()
+                    );
+                    return Ok(());
+                }
+                Err(err) => {
+                    rc.ttl.refreshed(None);
+                    log::warn!("refresh allocation failed: {}", err);
+                    err
+                }
+            }
+        };
+        self.reallocate_if_lost(&err).await;
+        Err(err)
+    }
+
+    async fn refresh_permissions(&self) {
+        let (mut snapshot, addrs, retry_policy, close_signal) = {
+            let rc = self.relay_conn.lock().await;
+            if rc.closed || rc.close_signal.is_raised() {
+                return;
+            }
+            let addrs = rc.perm_map.addrs();
+            for addr in &addrs {
+                rc.inbound_filter.contact(*addr);
+            }
+            (
+                rc.auth_snapshot(),
+                addrs,
+                rc.retry_policy,
+                Arc::clone(&rc.close_signal),
+            )
+        };
+        if addrs.is_empty() {
+            log::debug!("no permission to refresh");
+            return;
+        }
 
-            tr_res.msg
+        let mut state = (&mut snapshot, &addrs);
+        let result = tokio::select! {
+            result = retry(&retry_policy, &mut state, |(snapshot, addrs)| {
+                Box::pin(snapshot.create_permissions(addrs))
+            }) => result,
+            _ = close_signal.raised() => {
+                log::debug!("refresh permissions abandoned, closing");
+                return;
+            }
         };
 
+        let err = {
+            let mut rc = self.relay_conn.lock().await;
+            if rc.closed {
+                return;
+            }
+            rc.commit(&snapshot);
+            match result {
+                Ok(()) => {
+                    state_event!("permissions refreshed, rtt {:?}", rc.path_stats.last_rtt());
+                    if !self.bindings_apart {
+                        rc.refresh_received_bindings().await;
+                    }
+                    return;
+                }
+                Err(Error::Protocol { code, .. }) if code == PEER_ADDRESS_FAMILY_MISMATCH => {
+                    let err = rc.forget_mismatched_peers(&addrs).await;
+                    log::warn!("refresh permissions failed: {}", err);
+                    return;
+                }
+                Err(err) => {
+                    log::warn!("refresh permissions failed: {}", err);
+                    err
+                }
+            }
+        };
+        self.reallocate_if_lost(&err).await;
+    }
+
+    // reallocate_if_lost makes a new allocation with auto_reallocate when err
+    // is the 437 of a server that lost it. Closing abandons the reallocation.
+    async fn reallocate_if_lost(&self, err: &Error) {
+        if !is_allocation_lost(err) {
+            return;
+        }
+        let close_signal = {
+            let rc = self.relay_conn.lock().await;
+            if !rc.auto_reallocate || rc.closed || rc.close_signal.is_raised() {
+                return;
+            }
+            Arc::clone(&rc.close_signal)
+        };
+        state_event!("allocation lost by the server, reallocating");
+        tokio::select! {
+            result = self.reallocate() => {
+                if let Err(err) = result {
+                    log::warn!("reallocation failed: {}", err);
+                }
+            }
+            _ = close_signal.raised() => {
+                log::debug!("reallocation abandoned, closing");
+            }
+        }
+    }
+
+    // reallocate replaces the lost allocation with a new one and restores the
+    // peer state on it, in order: the permissions in a single
+    // CreatePermission, then each channel binding with its number. A binding
+    // that can't be bound again is left Failed, sending with Send
+    // indications, rather than failing the rest. Like the refreshes it runs
+    // on an AuthSnapshot, the RelayConnInternal is only held to commit the
+    // new allocation and the permissions.
+    async fn reallocate(&self) -> Result<(), Error> {
+        let obs = Arc::clone(&self.relay_conn.lock().await.obs);
+        let pending = obs.lock().await.begin_reallocate().await?;
+        let reallocation = pending.await?;
+        obs.lock().await.on_reallocated(&reallocation);
+
+        let (mut snapshot, addrs, retry_policy, binding_mgr, events) = {
+            let mut rc = self.relay_conn.lock().await;
+            if rc.closed {
+                return Err(Error::AlreadyClosed);
+            }
+            rc.reallocated(reallocation);
+            (
+                rc.auth_snapshot(),
+                rc.perm_map.addrs(),
+                rc.retry_policy,
+                Arc::clone(&rc.binding_mgr),
+                Arc::clone(&rc.events),
+            )
+        };
+
+        let mut restored_permissions = 0;
+        let mut failed = 0;
+        if !addrs.is_empty() {
+            let mut state = (&mut snapshot, &addrs);
+            let result = retry(&retry_policy, &mut state, |(snapshot, addrs)| {
+                Box::pin(snapshot.create_permissions(addrs))
+            })
+            .await;
+            let mut rc = self.relay_conn.lock().await;
+            rc.commit(&snapshot);
+            match result {
+                Ok(()) => restored_permissions = addrs.len(),
+                Err(err) => {
+                    // the next send to a peer permits it again
+                    log::warn!("failed to restore permissions: {}", err);
+                    failed += addrs.len();
+                    for addr in &addrs {
+                        if rc.perm_map.find(addr).is_some() {
+                            rc.perm_map.delete(addr);
+                            events.emit(RelayConnEvent::PermissionStateChanged {
+                                peer: *addr,
+                                old: PermState::Permitted,
+                                new: PermState::Idle,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut bindings: Vec<(SocketAddr, u16)> = {
+            let binding_mgr = binding_mgr.lock().await;
+            binding_mgr
+                .bindings()
+                .filter(|b| matches!(b.state(), BindingState::Ready | BindingState::Refresh))
+                .map(|b| (b.addr, b.number))
+                .collect()
+        };
+        bindings.sort_by_key(|(_, number)| *number);
+        let mut restored_bindings = 0;
+        for (addr, number) in bindings {
+            let result = snapshot.bind(addr, number).await;
+            let event = {
+                let mut binding_mgr = binding_mgr.lock().await;
+                binding_mgr.get_by_addr(&addr).and_then(|b| match &result {
+                    Ok(()) => {
+                        b.set_refreshed_at(Instant::now());
+                        set_binding_state(b, BindingState::Ready, None)
+                    }
+                    Err(err) => {
+                        log::warn!("failed to restore channel {} to {}: {}", number, addr, err);
+                        set_binding_state(b, BindingState::Failed, Some(err.to_string()))
+                    }
+                })
+            };
+            if let Some(event) = event {
+                events.emit(event);
+            }
+            match result {
+                Ok(()) => restored_bindings += 1,
+                Err(_) => failed += 1,
+            }
+        }
+
+        state_event!(
+            "allocation restored, {} permissions and {} bindings, {} failed",
+            restored_permissions,
+            restored_bindings,
+            failed
+        );
+        events.emit(RelayConnEvent::AllocationRestored {
+            restored_permissions,
+            restored_bindings,
+            failed,
+        });
+        Ok(())
+    }
+}
+
+impl<T: RelayConnObserver + Send + Sync> RelayConnRefresher<T> {
+    // refresh_bindings refreshes the bindings data is received on, see
+    // RelayConnInternal::refresh_received_bindings
+    async fn refresh_bindings(&self) -> Option<Duration> {
+        let rc = self.relay_conn.lock().await;
+        if rc.closed || rc.close_signal.is_raised() {
+            return None;
+        }
+        rc.refresh_received_bindings().await;
+        Some(PERM_REFRESH_INTERVAL)
+    }
+
+    // refresh_allocation_scheduled refreshes the allocation and returns the
+    // delay to the next refresh. Once send_to refreshed it the period starts
+    // over from then, like the timer's reset.
+    async fn refresh_allocation_scheduled(&self, refresh_jitter: f64) -> Option<Duration> {
+        let (lifetime, alloc_refreshed, ttl) = {
+            let rc = self.relay_conn.lock().await;
+            if rc.closed || rc.close_signal.is_raised() {
+                return None;
+            }
+            (
+                rc.lifetime,
+                Arc::clone(&rc.alloc_refreshed),
+                Arc::clone(&rc.ttl),
+            )
+        };
+        let period = jitter_interval(lifetime / 2, refresh_jitter);
+        if alloc_refreshed.notified().now_or_never().is_some() {
+            let since = lifetime.saturating_sub(ttl.get().remaining());
+            return Some(period.saturating_sub(since));
+        }
+
+        let _ = self.refresh_allocation().await;
+        Some(period)
+    }
+}
+
+// ScheduledRefresher is what a RelayConn registers with a
+// MaintenanceScheduler, its refreshes take turns like with the timers
+pub(crate) struct ScheduledRefresher<T: 'static + RelayConnObserver + Send + Sync> {
+    refresher: Mutex<RelayConnRefresher<T>>,
+    refresh_jitter: f64,
+}
+
+#[async_trait]
+impl<T: RelayConnObserver + Send + Sync> Maintained for ScheduledRefresher<T> {
+    async fn maintain(&self, kind: MaintenanceKind) -> Option<Duration> {
+        let refresher = self.refresher.lock().await;
+        state_event!("{:?} maintenance due", kind);
+        match kind {
+            MaintenanceKind::Allocation => {
+                refresher
+                    .refresh_allocation_scheduled(self.refresh_jitter)
+                    .await
+            }
+            MaintenanceKind::Permissions => {
+                refresher.refresh_permissions().await;
+                Some(PERM_REFRESH_INTERVAL)
+            }
+            MaintenanceKind::Bindings => refresher.refresh_bindings().await,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: RelayConnObserver + Send + Sync> PeriodicTimerTimeoutHandler for RelayConnRefresher<T> {
+    async fn on_timeout(&mut self, id: TimerIdRefresh) {
+        state_event!("refresh timer {:?} expired", id);
+        match id {
+            TimerIdRefresh::Alloc => {
+                let _ = self.refresh_allocation().await;
+            }
+            TimerIdRefresh::Perms => self.refresh_permissions().await,
+        }
+    }
+}
+
+// AuthSnapshot is what authenticated requests for the allocation are sent
+// with, taken from the RelayConnInternal so a transaction runs without
+// holding it. A 438 (Stale Nonce) updates the snapshot's nonce, see
+// RelayConnInternal::commit.
+struct AuthSnapshot<T: 'static + RelayConnObserver + Send + Sync> {
+    obs: Arc<Mutex<T>>,
+    // issued is the nonce the snapshot was taken with
+    issued: Nonce,
+    nonce: Nonce,
+    integrity: MessageIntegrity,
+    path_stats: Arc<PathStats>,
+    customizer: Option<MessageCustomizer>,
+    quirks: ServerQuirks,
+    require_lifetime: bool,
+    lifetime_missing: Arc<AtomicBool>,
+}
+
+impl<T: RelayConnObserver + Send + Sync> AuthSnapshot<T> {
+    // refresh sends a Refresh and returns the lifetime and, when the server
+    // moved the allocation, the relayed address of the response
+    async fn refresh(
+        &mut self,
+        lifetime: Duration,
+    ) -> Result<(Duration, Option<SocketAddr>), Error> {
+        let (msg, turn_server_addr) = {
+            let obs = self.obs.lock().await;
+            let msg = refresh_request(
+                &*obs,
+                lifetime,
+                &self.nonce,
+                &self.integrity,
+                self.customizer.as_ref(),
+                self.quirks,
+            )?;
+            (msg, obs.turn_server_addr())
+        };
+
+        log::debug!("send refresh request, and waiting response");
+        let res = perform_timed_transaction(
+            &self.obs,
+            &msg,
+            &turn_server_addr,
+            &self.path_stats,
+            TransactionOptions::default(),
+        )
+        .await?
+        .msg;
+
         if res.typ.class == CLASS_ERROR_RESPONSE {
             let mut code = ErrorCodeAttribute::default();
             let result = code.get_from(&res);
             if result.is_err() {
-                return Err(Error::new(format!("{}", res.typ)));
+                return Err(Error::UnexpectedResponse(res.typ));
             } else if code.code == CODE_STALE_NONCE {
                 self.set_nonce_from_msg(&res);
-                return Err(ERR_TRY_AGAIN.to_owned());
+                return Err(Error::TryAgain);
             } else {
-                return Ok(());
+                return Err(Error::from_error_response(&res));
             }
         }
+        self.quirks.check_success(&res, &self.integrity)?;
 
-        // Getting lifetime from response
+        // Getting lifetime from response, some servers leave it out and keep
+        // the one asked for
         let mut updated_lifetime = proto::lifetime::Lifetime::default();
-        updated_lifetime.get_from(&res)?;
+        if let Err(err) = updated_lifetime.get_from(&res) {
+            if self.require_lifetime {
+                return Err(err.into());
+            }
+            if !self.lifetime_missing.swap(true, Ordering::Relaxed) {
+                log::warn!("refresh response without LIFETIME, keeping {:?}", lifetime);
+            }
+            updated_lifetime.0 = lifetime;
+        }
 
-        self.lifetime = updated_lifetime.0;
-        log::debug!("updated lifetime: {} seconds", self.lifetime.as_secs());
-        Ok(())
+        let mut relayed = proto::relayaddr::RelayedAddress::default();
+        let relayed_addr = relayed
+            .get_from(&res)
+            .ok()
+            .map(|_| SocketAddr::new(relayed.ip, relayed.port));
+        Ok((updated_lifetime.0, relayed_addr))
     }
 
-    async fn refresh_permissions(&mut self) -> Result<(), Error> {
-        let addrs = self.perm_map.addrs();
-        if addrs.is_empty() {
-            log::debug!("no permission to refresh");
-            return Ok(());
-        }
+    // create_permissions sends a CreatePermission for addrs, a 443 is
+    // returned as an Error::Protocol for the caller to forget the peers
+    async fn create_permissions(&mut self, addrs: &[SocketAddr]) -> Result<(), Error> {
+        let (msg, turn_server_addr) = {
+            let obs = self.obs.lock().await;
+            let mut setters: Vec<Box<dyn Setter>> = vec![
+                Box::new(TransactionId::new()),
+                Box::new(MessageType::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST)),
+            ];
 
-        if let Err(err) = self.create_permissions(&addrs).await {
-            if err != *ERR_TRY_AGAIN {
-                log::error!("fail to refresh permissions: {}", err);
+            for addr in addrs {
+                setters.push(Box::new(socket_addr2peer_address(addr)));
             }
-            return Err(err);
+
+            setters.push(Box::new(obs.username()));
+            setters.push(Box::new(obs.realm()));
+            setters.push(Box::new(self.nonce.clone()));
+
+            let mut msg = Message::new();
+            msg.build(&setters)?;
+            seal(
+                &mut msg,
+                self.customizer.as_ref(),
+                Some(&self.integrity),
+                self.quirks,
+            )?;
+            (msg, obs.turn_server_addr())
+        };
+
+        log::debug!("UDPConn.createPermissions call PerformTransaction 1");
+        let res = perform_timed_transaction(
+            &self.obs,
+            &msg,
+            &turn_server_addr,
+            &self.path_stats,
+            TransactionOptions::default(),
+        )
+        .await?
+        .msg;
+
+        if res.typ.class == CLASS_ERROR_RESPONSE {
+            let mut code = ErrorCodeAttribute::default();
+            if code.get_from(&res).is_ok() && code.code == CODE_STALE_NONCE {
+                self.set_nonce_from_msg(&res);
+                return Err(Error::TryAgain);
+            }
+            return Err(Error::from_error_response(&res));
         }
 
-        log::debug!("refresh permissions successful");
-        Ok(())
+        self.quirks.check_success(&res, &self.integrity)
     }
 
-    async fn bind(
-        rc_obs: Arc<Mutex<T>>,
-        bind_addr: SocketAddr,
-        bind_number: u16,
-        nonce: Nonce,
-        integrity: MessageIntegrity,
-    ) -> Result<(), Error> {
+    // bind sends a ChannelBind of bind_number to bind_addr
+    async fn bind(&self, bind_addr: SocketAddr, bind_number: u16) -> Result<(), Error> {
         let (msg, turn_server_addr) = {
-            let obs = rc_obs.lock().await;
+            let obs = self.obs.lock().await;
 
             let setters: Vec<Box<dyn Setter>> = vec![
                 Box::new(TransactionId::new()),
@@ -542,78 +2443,293 @@ impl<T: RelayConnObserver + Send + Sync> RelayConnInternal<T> {
                 Box::new(proto::channum::ChannelNumber(bind_number)),
                 Box::new(obs.username()),
                 Box::new(obs.realm()),
-                Box::new(nonce),
-                Box::new(integrity),
-                Box::new(FINGERPRINT),
+                Box::new(self.nonce.clone()),
             ];
 
             let mut msg = Message::new();
             msg.build(&setters)?;
+            seal(
+                &mut msg,
+                self.customizer.as_ref(),
+                Some(&self.integrity),
+                self.quirks,
+            )?;
 
             (msg, obs.turn_server_addr())
         };
 
         log::debug!("UDPConn.bind call PerformTransaction 1");
-        let tr_res = {
-            let mut obs = rc_obs.lock().await;
-            obs.perform_transaction(&msg, &turn_server_addr, false)
-                .await?
-        };
+        let tr_res = perform_timed_transaction(
+            &self.obs,
+            &msg,
+            &turn_server_addr,
+            &self.path_stats,
+            CHANNEL_BIND_TRANSACTION,
+        )
+        .await?;
 
         let res = tr_res.msg;
 
+        // bind_in_background drops the binding on this error
+        let mut code = ErrorCodeAttribute::default();
+        if res.typ.class == CLASS_ERROR_RESPONSE
+            && code.get_from(&res).is_ok()
+            && code.code == CODE_PEER_ADDR_FAMILY_MISMATCH
+        {
+            return Err(Error::PeerAddressFamilyMismatch { peer: bind_addr });
+        }
+
         if res.typ != MessageType::new(METHOD_CHANNEL_BIND, CLASS_SUCCESS_RESPONSE) {
-            return Err(ERR_UNEXPECTED_RESPONSE.to_owned());
+            return Err(Error::UnexpectedResponse(res.typ));
         }
+        self.quirks.check_success(&res, &self.integrity)?;
 
         log::debug!("channel binding successful: {} {}", bind_addr, bind_number);
 
         // Success.
         Ok(())
     }
-}
 
-#[async_trait]
-impl<T: RelayConnObserver + Send + Sync> PeriodicTimerTimeoutHandler for RelayConnInternal<T> {
-    async fn on_timeout(&mut self, id: TimerIdRefresh) {
-        log::debug!("refresh timer {:?} expired", id);
-        match id {
-            TimerIdRefresh::Alloc => {
-                let lifetime = self.lifetime;
-                // limit the max retries on errTryAgain to 3
-                // when stale nonce returns, sencond retry should succeed
-                let mut result = Ok(());
-                for _ in 0..MAX_RETRY_ATTEMPTS {
-                    result = self.refresh_allocation(lifetime, false).await;
-                    if let Err(err) = &result {
-                        if *err != *ERR_TRY_AGAIN {
-                            break;
-                        }
-                    }
-                }
-                if result.is_err() {
-                    log::warn!("refresh allocation failed");
-                }
-            }
-            TimerIdRefresh::Perms => {
-                let mut result = Ok(());
-                for _ in 0..MAX_RETRY_ATTEMPTS {
-                    result = self.refresh_permissions().await;
-                    if let Err(err) = &result {
-                        if *err != *ERR_TRY_AGAIN {
-                            break;
-                        }
-                    }
-                }
-                if result.is_err() {
-                    log::warn!("refresh permissions failed");
-                }
+    fn set_nonce_from_msg(&mut self, msg: &Message) {
+        match Nonce::get_from_as(msg, ATTR_NONCE) {
+            Ok(nonce) => {
+                self.nonce = nonce;
+                log::debug!("438, got new nonce.");
             }
+            Err(_) => log::warn!("438 but no nonce."),
+        }
+    }
+}
+
+// refresh_request builds a Refresh request for lifetime
+fn refresh_request<T: RelayConnObserver>(
+    obs: &T,
+    lifetime: Duration,
+    nonce: &Nonce,
+    integrity: &MessageIntegrity,
+    customizer: Option<&MessageCustomizer>,
+    quirks: ServerQuirks,
+) -> Result<Message, Error> {
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+        Box::new(proto::lifetime::Lifetime(lifetime)),
+        Box::new(obs.username()),
+        Box::new(obs.realm()),
+        Box::new(nonce.clone()),
+    ])?;
+    seal(&mut msg, customizer, Some(integrity), quirks)?;
+    Ok(msg)
+}
+
+// perform_timed_transaction performs a transaction that waits for its response
+// and records the round trip in path_stats, or the failure when it times out or
+// is answered with an error response. obs is only locked to send the request.
+async fn perform_timed_transaction<T: RelayConnObserver + Send>(
+    obs: &Mutex<T>,
+    msg: &Message,
+    to: &str,
+    path_stats: &PathStats,
+    opts: TransactionOptions,
+) -> Result<TransactionResult, Error> {
+    let start = Instant::now();
+    let pending = obs.lock().await.begin_transaction_with(msg, to, opts).await;
+    let result = match pending {
+        Ok(pending) => pending.await,
+        Err(err) => Err(err),
+    };
+    match &result {
+        Ok(tr_res) if tr_res.msg.typ.class == CLASS_SUCCESS_RESPONSE => {
+            path_stats.record_success(start.elapsed())
+        }
+        _ => path_stats.record_failure(),
+    }
+    result
+}
+
+// is_allocation_lost is whether err is the 437 (Allocation Mismatch) a server
+// answers a request for an allocation it doesn't have with
+fn is_allocation_lost(err: &Error) -> bool {
+    matches!(err, Error::Protocol { code, .. } if *code == ALLOCATION_MISMATCH)
+}
+
+// allocation_id identifies the allocation of obs made with relayed_addr
+fn allocation_id<T: RelayConnObserver>(
+    obs: &T,
+    relayed_addr: SocketAddr,
+    transport: TransportKind,
+) -> AllocationId {
+    AllocationId {
+        turn_server_addr: obs.turn_server_addr(),
+        local_addr: obs.local_addr(),
+        username: obs.username().text,
+        relayed_addr,
+        transport,
+    }
+}
+
+// GuardRefreshing marks the send_to refreshing the expiring allocation, the
+// sends waiting for it are woken once it is done or dropped
+struct GuardRefreshing<'a> {
+    refreshing: &'a AtomicBool,
+    alloc_refreshed: &'a Notify,
+}
+
+impl Drop for GuardRefreshing<'_> {
+    fn drop(&mut self) {
+        self.refreshing.store(false, Ordering::SeqCst);
+        self.alloc_refreshed.notify_waiters();
+    }
+}
+
+// CloseSignal tells the refresh timers of a RelayConn that it is closing, it
+// is shared by the RelayConn and its RelayConnInternal
+#[derive(Debug, Default)]
+pub(crate) struct CloseSignal {
+    raised: AtomicBool,
+    notify: Notify,
+}
+
+impl CloseSignal {
+    fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    // raised returns once the signal is raised
+    async fn raised(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.is_raised() {
+            notified.await;
+        }
+    }
+}
+
+// CounterSources are the counters of a RelayConn, shared with its stats
+// reporters
+struct CounterSources {
+    traffic: Arc<Traffic>,
+    inbound_overflow: Arc<InboundOverflow>,
+    inbound_filter: Arc<InboundFilter>,
+    oversized_sends: Arc<OversizedSends>,
+    send_backpressure: Arc<AtomicU64>,
+}
+
+impl CounterSources {
+    fn sample(&self) -> RelayConnCounters {
+        let mut counters = RelayConnCounters {
+            inbound_dropped: self.inbound_overflow.dropped(),
+            inbound_malformed: self.inbound_filter.malformed(),
+            inbound_unsolicited: self.inbound_filter.unsolicited(),
+            inbound_duplicates: self.inbound_filter.duplicates(),
+            oversized_sends: self.oversized_sends.count(),
+            send_backpressure: self.send_backpressure.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        self.traffic.fill(&mut counters);
+        counters
+    }
+}
+
+// RelayedAddr is the current relayed address of a RelayConn, shared with its
+// internal state so local_addr needn't lock it
+#[derive(Debug)]
+pub(crate) struct RelayedAddr {
+    addr: std::sync::Mutex<SocketAddr>,
+}
+
+impl RelayedAddr {
+    fn new(addr: SocketAddr) -> Self {
+        RelayedAddr {
+            addr: std::sync::Mutex::new(addr),
+        }
+    }
+
+    fn get(&self) -> SocketAddr {
+        *self.addr.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // replace returns the previous address
+    fn replace(&self, addr: SocketAddr) -> SocketAddr {
+        std::mem::replace(
+            &mut *self.addr.lock().unwrap_or_else(|err| err.into_inner()),
+            addr,
+        )
+    }
+}
+
+// OversizedSends counts the payloads over warn_payload_size a RelayConn sent,
+// it is shared by the RelayConn and its RelayConnInternal
+#[derive(Debug, Default)]
+pub(crate) struct OversizedSends {
+    count: AtomicU64,
+    // warned are the peers OversizedSend was emitted for
+    warned: std::sync::Mutex<HashSet<SocketAddr>>,
+}
+
+impl OversizedSends {
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn record(
+        &self,
+        peer: SocketAddr,
+        size: usize,
+        warn_payload_size: usize,
+        events: &RelayConnEvents,
+    ) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let first = self
+            .warned
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(peer);
+        if first {
+            log::warn!(
+                "sending {} bytes to {}, over warn_payload_size of {}",
+                size,
+                peer,
+                warn_payload_size
+            );
+            events.emit(RelayConnEvent::OversizedSend {
+                peer,
+                size,
+                warn_payload_size,
+            });
         }
     }
 }
 
+// set_binding_state moves b to state and returns the event to emit, if the
+// state changed
+fn set_binding_state(
+    b: &mut Binding,
+    state: BindingState,
+    error: Option<String>,
+) -> Option<RelayConnEvent> {
+    let old = b.set_state(state);
+    if old == state {
+        return None;
+    }
+    Some(RelayConnEvent::BindingStateChanged {
+        peer: b.addr,
+        number: b.number,
+        old,
+        new: state,
+        error,
+    })
+}
+
 fn socket_addr2peer_address(addr: &SocketAddr) -> proto::peeraddr::PeerAddress {
+    let addr = canonical_peer_addr(*addr);
     proto::peeraddr::PeerAddress {
         ip: addr.ip(),
         port: addr.port(),
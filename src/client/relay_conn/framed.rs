@@ -0,0 +1,115 @@
+#[cfg(all(test, feature = "server"))]
+mod framed_test;
+
+use super::*;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::{Sink, Stream};
+
+// MAX_IN_FLIGHT is how many datagrams the sink buffers before poll_ready
+// waits for earlier sends to complete
+pub const MAX_IN_FLIGHT: usize = 64;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+// RelayConnFramed is a Stream of inbound datagrams and a Sink of outbound
+// datagrams on a RelayConn, see RelayConn::framed.
+//
+// Closing the sink only flushes it, the allocation stays up until the
+// RelayConn is closed.
+pub struct RelayConnFramed<T: 'static + RelayConnObserver + Send + Sync> {
+    read_ch_rx: Arc<ReadQueue>,
+    relay_conn: Arc<Mutex<RelayConnInternal<T>>>,
+
+    send_fut: Option<BoxFuture<Result<usize, Error>>>,
+    send_queue: VecDeque<(Bytes, SocketAddr)>,
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> RelayConn<T> {
+    // framed returns a Stream/Sink of (Bytes, SocketAddr) over this conn. It
+    // shares the inbound queue with recv_from, so each datagram is delivered to
+    // only one of them.
+    pub fn framed(&self) -> RelayConnFramed<T> {
+        RelayConnFramed {
+            read_ch_rx: Arc::clone(&self.read_ch_rx),
+            relay_conn: Arc::clone(&self.relay_conn),
+            send_fut: None,
+            send_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> RelayConnFramed<T> {
+    // poll_send drives queued datagrams until the queue is empty or a send is pending
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            if let Some(send_fut) = self.send_fut.as_mut() {
+                let result = match send_fut.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.send_fut = None;
+                result?;
+            }
+
+            let (data, to) = match self.send_queue.pop_front() {
+                Some(next) => next,
+                None => return Poll::Ready(Ok(())),
+            };
+            let relay_conn = Arc::clone(&self.relay_conn);
+            self.send_fut = Some(Box::pin(async move {
+                let mut relay_conn = relay_conn.lock().await;
+                relay_conn.send_to(&data, to).await
+            }));
+        }
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> Stream for RelayConnFramed<T> {
+    type Item = Result<(Bytes, SocketAddr), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let ib_data = match self.read_ch_rx.poll_recv(cx) {
+            Poll::Ready(ib_data) => ib_data,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        Poll::Ready(ib_data.map(|ib_data| Ok((Bytes::from(ib_data.data), ib_data.from))))
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> Sink<(Bytes, SocketAddr)>
+    for RelayConnFramed<T>
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.send_queue.len() >= MAX_IN_FLIGHT {
+            if let Poll::Ready(Err(err)) = self.poll_send(cx) {
+                return Poll::Ready(Err(err));
+            }
+            if self.send_queue.len() >= MAX_IN_FLIGHT {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: (Bytes, SocketAddr)) -> Result<(), Error> {
+        self.send_queue.push_back(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_send(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_send(cx)
+    }
+}
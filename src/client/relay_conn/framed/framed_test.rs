@@ -0,0 +1,42 @@
+use super::*;
+use crate::test_util::*;
+
+use futures::{SinkExt, StreamExt};
+
+#[tokio::test]
+async fn test_relay_conn_framed() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let peer_addr = spawn_udp_echo_peer().await?;
+    let client = start_client(server_addr).await?;
+
+    let relay_conn = client.allocate().await?;
+    let mut framed = relay_conn.framed();
+
+    // datagrams keep their boundaries in both directions
+    let datagrams: Vec<Bytes> = (0..4u8)
+        .map(|i| Bytes::from(vec![i; 10 + i as usize]))
+        .collect();
+    for data in &datagrams {
+        framed.feed((data.clone(), peer_addr)).await?;
+    }
+    framed.flush().await?;
+
+    for data in &datagrams {
+        let (echoed, from) = framed.next().await.expect("stream ended")?;
+        assert_eq!(peer_addr, from);
+        assert_eq!(*data, echoed);
+    }
+
+    // closing the sink keeps the allocation
+    framed.close().await?;
+    relay_conn.send_to(b"still up", peer_addr).await?;
+    let mut buf = vec![0u8; 1500];
+    let (n, from) = relay_conn.recv_from(&mut buf).await?;
+    assert_eq!(peer_addr, from);
+    assert_eq!(b"still up", &buf[..n]);
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
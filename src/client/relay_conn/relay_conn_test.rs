@@ -1,12 +1,20 @@
 use super::*;
 
-use std::net::Ipv4Addr;
-use util::Error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+type TransactionResultFn = fn() -> Result<TransactionResult, Error>;
+
+// test_integrity is the key the tests allocate with, the observers sign their
+// success responses with it
+fn test_integrity() -> MessageIntegrity {
+    MessageIntegrity::new_short_term_integrity("pass".to_owned())
+}
 
 struct DummyRelayConnObserver {
     turn_server_addr: String,
     username: Username,
     realm: Realm,
+    transaction_result: TransactionResultFn,
 }
 
 #[async_trait]
@@ -33,7 +41,7 @@ impl RelayConnObserver for DummyRelayConnObserver {
         _to: &str,
         _dont_wait: bool,
     ) -> Result<TransactionResult, Error> {
-        Err(ERR_FAKE_ERR.to_owned())
+        (self.transaction_result)()
     }
 }
 
@@ -43,6 +51,7 @@ async fn test_relay_conn() -> Result<(), Error> {
         turn_server_addr: String::new(),
         username: Username::new(ATTR_USERNAME, "username".to_owned()),
         realm: Realm::new(ATTR_REALM, "realm".to_owned()),
+        transaction_result: || Err(Error::Other("fake error".to_owned())),
     };
 
     let (_read_ch_tx, read_ch_rx) = mpsc::channel(100);
@@ -52,8 +61,37 @@ async fn test_relay_conn() -> Result<(), Error> {
         integrity: MessageIntegrity::default(),
         nonce: Nonce::new(ATTR_NONCE, "nonce".to_owned()),
         lifetime: Duration::from_secs(0),
+        refresh_jitter: None,
+        max_bindings: 0,
+        keepalive_payload: vec![],
+        warn_payload_size: Some(DEFAULT_WARN_PAYLOAD_SIZE),
+        max_payload_size: None,
+        auto_permit_inbound: false,
+        retry_policy: RetryPolicy::default(),
+        auto_reallocate: false,
+        message_customizer: None,
+        maintenance: None,
+        require_refresh_lifetime: false,
+        expiry_guard: DEFAULT_EXPIRY_GUARD,
+        fail_fast_on_expiry: false,
+        server_quirks: ServerQuirks::default(),
+        channel_bind_policy: ChannelBindPolicy::default(),
+        dedup_window: None,
+        server_addr: None,
+        transport: TransportKind::default(),
+        underlying_local_addr: None,
         binding_mgr: Arc::new(Mutex::new(BindingManager::new())),
-        read_ch_rx: Arc::new(Mutex::new(read_ch_rx)),
+        read_ch_rx: Arc::new(ReadQueue::new(read_ch_rx)),
+        inbound_overflow: Arc::new(InboundOverflow::new(Arc::default())),
+        auto_permit: Arc::default(),
+        inbound_filter: Arc::default(),
+        probes: Arc::default(),
+        subscriptions: Arc::default(),
+        traffic: Arc::default(),
+        send_batching: None,
+        close_signal: Arc::default(),
+        alloc_refreshed: Arc::default(),
+        events: Arc::default(),
     };
 
     let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
@@ -61,24 +99,2238 @@ async fn test_relay_conn() -> Result<(), Error> {
     let rci = rc.relay_conn.lock().await;
     let (bind_addr, bind_number) = {
         let mut bm = rci.binding_mgr.lock().await;
-        let b = bm
-            .create(SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1234))
-            .unwrap();
+        let b = bm.create(
+            SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1234),
+            MAX_BINDINGS,
+        )?;
         (b.addr, b.number)
     };
 
-    //let binding_mgr = Arc::clone(&rci.binding_mgr);
-    let rc_obs = Arc::clone(&rci.obs);
-    let nonce = rci.nonce.clone();
-    let integrity = rci.integrity.clone();
+    if let Err(err) = rci.auth_snapshot().bind(bind_addr, bind_number).await {
+        assert!(
+            matches!(err, Error::Other(_)),
+            "expected transaction error, got {}",
+            err
+        );
+    } else {
+        panic!("should fail");
+    }
+    assert!(rc.last_failure().is_some());
+    assert_eq!(None, rc.last_refresh_rtt());
+
+    Ok(())
+}
+
+fn new_test_relay_conn(
+    transaction_result: TransactionResultFn,
+) -> (RelayConn<DummyRelayConnObserver>, mpsc::Sender<InboundData>) {
+    let obs = DummyRelayConnObserver {
+        turn_server_addr: String::new(),
+        username: Username::new(ATTR_USERNAME, "username".to_owned()),
+        realm: Realm::new(ATTR_REALM, "realm".to_owned()),
+        transaction_result,
+    };
+
+    let (read_ch_tx, read_ch_rx) = mpsc::channel(100);
+
+    let config = RelayConnConfig {
+        relayed_addr: SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
+        integrity: MessageIntegrity::default(),
+        nonce: Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        lifetime: Duration::from_secs(0),
+        refresh_jitter: None,
+        max_bindings: 0,
+        keepalive_payload: vec![],
+        warn_payload_size: Some(DEFAULT_WARN_PAYLOAD_SIZE),
+        max_payload_size: None,
+        auto_permit_inbound: false,
+        retry_policy: RetryPolicy::default(),
+        auto_reallocate: false,
+        message_customizer: None,
+        maintenance: None,
+        require_refresh_lifetime: false,
+        expiry_guard: DEFAULT_EXPIRY_GUARD,
+        fail_fast_on_expiry: false,
+        server_quirks: ServerQuirks::default(),
+        channel_bind_policy: ChannelBindPolicy::default(),
+        dedup_window: None,
+        server_addr: None,
+        transport: TransportKind::default(),
+        underlying_local_addr: None,
+        binding_mgr: Arc::new(Mutex::new(BindingManager::new())),
+        read_ch_rx: Arc::new(ReadQueue::new(read_ch_rx)),
+        inbound_overflow: Arc::new(InboundOverflow::new(Arc::default())),
+        auto_permit: Arc::default(),
+        inbound_filter: Arc::default(),
+        probes: Arc::default(),
+        subscriptions: Arc::default(),
+        traffic: Arc::default(),
+        send_batching: None,
+        close_signal: Arc::default(),
+        alloc_refreshed: Arc::default(),
+        events: Arc::default(),
+    };
+
+    (
+        RelayConn::new(Arc::new(Mutex::new(obs)), config),
+        read_ch_tx,
+    )
+}
+
+fn error_response(code: ErrorCode) -> Result<TransactionResult, Error> {
+    let mut msg = Message::new();
+    msg.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(
+            METHOD_CREATE_PERMISSION,
+            CLASS_ERROR_RESPONSE,
+        )),
+        Box::new(ErrorCodeAttribute {
+            code,
+            reason: vec![],
+        }),
+    ])?;
+
+    Ok(TransactionResult {
+        msg,
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn test_relay_conn_send_to_error_kind() -> Result<(), Error> {
+    let peer = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1234);
+
+    let tests: Vec<(&str, TransactionResultFn, io::ErrorKind)> = vec![
+        (
+            "timeout",
+            || Err(Error::AllRetransmissionsFailed("tr".to_owned())),
+            io::ErrorKind::TimedOut,
+        ),
+        (
+            "closed",
+            || Err(Error::TransactionClosed),
+            io::ErrorKind::ConnectionAborted,
+        ),
+        (
+            "quota",
+            || Err(Error::QuotaReached),
+            io::ErrorKind::PermissionDenied,
+        ),
+        (
+            "forbidden",
+            || error_response(CODE_FORBIDDEN),
+            io::ErrorKind::PermissionDenied,
+        ),
+        (
+            "quota response",
+            || error_response(CODE_ALLOC_QUOTA_REACHED),
+            io::ErrorKind::PermissionDenied,
+        ),
+        (
+            "server error",
+            || error_response(CODE_SERVER_ERROR),
+            io::ErrorKind::Other,
+        ),
+    ];
+
+    for (name, transaction_result, kind) in tests {
+        let (rc, _read_ch_tx) = new_test_relay_conn(transaction_result);
 
-    if let Err(err) =
-        RelayConnInternal::bind(rc_obs, bind_addr, bind_number, nonce, integrity).await
+        let err = rc.send_to(&[1, 2, 3], peer).await.unwrap_err();
+        assert_eq!(kind, err.kind(), "{}: unexpected error kind", name);
+        assert!(
+            err.get_ref().is_some_and(|e| e.is::<Error>()),
+            "{}: source error should be preserved",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_peer_address_family_mismatch() -> Result<(), Error> {
+    // the relayed address is IPv4
+    let v4_peer = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1234);
+    let v6_peer = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1234);
+
+    let (rc, _read_ch_tx) = new_test_relay_conn(|| error_response(CODE_PEER_ADDR_FAMILY_MISMATCH));
+
+    // a send is told the peer is unreachable, no permission is left behind
+    let err = rc.send_to(&[1, 2, 3], v6_peer).await.unwrap_err();
+    assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    assert!(
+        matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::PeerAddressFamilyMismatch { peer }) if *peer == v6_peer
+        ),
+        "unexpected {:?}",
+        err
+    );
+
+    let mut rci = rc.relay_conn.lock().await;
+    assert!(rci.perm_map.find(&v6_peer).is_none());
+
+    // a refresh answered with 443 drops the mismatched peer only
+    let mut perm = Permission::default();
+    perm.set_state(PermState::Permitted);
+    rci.perm_map.insert(&v4_peer, perm);
+    rci.perm_map.insert(&v6_peer, perm);
     {
-        assert_ne!(err, *ERR_UNEXPECTED_RESPONSE);
-    } else {
-        assert!(false, "should fail");
+        let mut bm = rci.binding_mgr.lock().await;
+        bm.create(v4_peer, MAX_BINDINGS)?;
+        bm.create(v6_peer, MAX_BINDINGS)?;
+    }
+    let mut events = rci.events.subscribe();
+
+    let err = rci.refresh_permissions().await.unwrap_err();
+    assert!(
+        matches!(err, Error::PeerAddressFamilyMismatch { peer } if peer.ip() == v6_peer.ip()),
+        "unexpected {}",
+        err
+    );
+    assert!(rci.perm_map.find(&v4_peer).is_some());
+    assert!(rci.perm_map.find(&v6_peer).is_none());
+    {
+        let bm = rci.binding_mgr.lock().await;
+        assert!(bm.find_by_addr(&v4_peer).is_some());
+        assert!(bm.find_by_addr(&v6_peer).is_none());
+    }
+    assert!(matches!(
+        events.try_recv(),
+        Ok(RelayConnEvent::PermissionStateChanged {
+            old: PermState::Permitted,
+            new: PermState::Idle,
+            ..
+        })
+    ));
+
+    // a ChannelBind answered with 443 fails the same way
+    let err = rci
+        .auth_snapshot()
+        .bind(v6_peer, proto::channum::MIN_CHANNEL_NUMBER)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::PeerAddressFamilyMismatch { peer } if peer == v6_peer),
+        "unexpected {}",
+        err
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_recv_from_error_kind() -> Result<(), Error> {
+    let (rc, read_ch_tx) = new_test_relay_conn(|| Err(Error::Other("fake error".to_owned())));
+
+    read_ch_tx
+        .send(InboundData {
+            data: vec![0; 8],
+            from: SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1234),
+        })
+        .await
+        .map_err(|_| Error::Other("read_ch closed".to_owned()))?;
+
+    let mut buf = [0u8; 4];
+    let err = rc.recv_from(&mut buf).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    drop(read_ch_tx);
+
+    let err = rc.recv_from(&mut buf).await.unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionAborted, err.kind());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_refresh_jitter_is_clamped() -> Result<(), Error> {
+    let obs = DummyRelayConnObserver {
+        turn_server_addr: String::new(),
+        username: Username::new(ATTR_USERNAME, "username".to_owned()),
+        realm: Realm::new(ATTR_REALM, "realm".to_owned()),
+        transaction_result: || Err(Error::Other("fake error".to_owned())),
+    };
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.refresh_jitter(5.0));
+    let out = format!("{:?}", rc.refresh_alloc_timer);
+    assert!(
+        out.contains(&format!("jitter: {:?}", MAX_REFRESH_JITTER)),
+        "{}",
+        out
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_config_debug_redacts_secrets() -> Result<(), Error> {
+    let (_read_ch_tx, read_ch_rx) = mpsc::channel(1);
+
+    let config = RelayConnConfig {
+        relayed_addr: SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        integrity: MessageIntegrity::new_short_term_integrity("integrity-key".to_owned()),
+        nonce: Nonce::new(ATTR_NONCE, "nonce-value".to_owned()),
+        lifetime: Duration::from_secs(600),
+        refresh_jitter: None,
+        max_bindings: 0,
+        keepalive_payload: vec![],
+        warn_payload_size: Some(DEFAULT_WARN_PAYLOAD_SIZE),
+        max_payload_size: None,
+        auto_permit_inbound: false,
+        retry_policy: RetryPolicy::default(),
+        auto_reallocate: false,
+        message_customizer: None,
+        maintenance: None,
+        require_refresh_lifetime: false,
+        expiry_guard: DEFAULT_EXPIRY_GUARD,
+        fail_fast_on_expiry: false,
+        server_quirks: ServerQuirks::default(),
+        channel_bind_policy: ChannelBindPolicy::default(),
+        dedup_window: None,
+        server_addr: None,
+        transport: TransportKind::default(),
+        underlying_local_addr: None,
+        binding_mgr: Arc::new(Mutex::new(BindingManager::new())),
+        read_ch_rx: Arc::new(ReadQueue::new(read_ch_rx)),
+        inbound_overflow: Arc::new(InboundOverflow::new(Arc::default())),
+        auto_permit: Arc::default(),
+        inbound_filter: Arc::default(),
+        probes: Arc::default(),
+        subscriptions: Arc::default(),
+        traffic: Arc::default(),
+        send_batching: None,
+        close_signal: Arc::default(),
+        alloc_refreshed: Arc::default(),
+        events: Arc::default(),
+    };
+
+    let out = format!("{:?}", config);
+    assert!(out.contains("10.0.0.1:5000"), "{}", out);
+    assert!(!out.contains("integrity-key"), "{}", out);
+    assert!(!out.contains("nonce-value"), "{}", out);
+
+    let (relay_conn, _read_ch_tx) = new_test_relay_conn(|| error_response(CODE_FORBIDDEN));
+    let out = format!("{:?}", relay_conn.relay_conn.lock().await);
+    assert!(out.contains("<redacted>"), "{}", out);
+    assert!(!out.contains("\"nonce\""), "{}", out);
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordedCalls {
+    transactions: Vec<(Method, bool)>,
+    writes: Vec<Vec<u8>>,
+    // write_error fails every write_to with this kind
+    write_error: Option<io::ErrorKind>,
+    // batches are the sizes passed to write_batch
+    batches: Vec<usize>,
+    // stall_refresh leaves Refresh requests waiting for a response forever
+    stall_refresh: bool,
+    deallocated: Option<AllocationId>,
+    // options are the TransactionOptions of each transaction
+    options: Vec<(Method, TransactionOptions)>,
+}
+
+// RecordingObserver stands in for a connection manager other than Client,
+// every transaction succeeds
+struct RecordingObserver {
+    calls: Arc<std::sync::Mutex<RecordedCalls>>,
+}
+
+#[async_trait]
+impl RelayConnObserver for RecordingObserver {
+    fn turn_server_addr(&self) -> String {
+        "127.0.0.1:3478".to_owned()
+    }
+
+    fn username(&self) -> Username {
+        Username::new(ATTR_USERNAME, "username".to_owned())
+    }
+
+    fn realm(&self) -> Realm {
+        Realm::new(ATTR_REALM, "realm".to_owned())
+    }
+
+    async fn write_to(&self, data: &[u8], to: &str) -> Result<usize, Error> {
+        assert_eq!("127.0.0.1:3478", to);
+        let mut calls = self.calls.lock().unwrap();
+        if let Some(kind) = calls.write_error {
+            return Err(io::Error::from(kind).into());
+        }
+        calls.writes.push(data.to_vec());
+        Ok(data.len())
+    }
+
+    async fn write_batch(&self, packets: &[Vec<u8>], to: &str) -> Result<usize, Error> {
+        assert_eq!("127.0.0.1:3478", to);
+        let mut calls = self.calls.lock().unwrap();
+        calls.batches.push(packets.len());
+        calls.writes.extend(packets.iter().cloned());
+        Ok(packets.len())
+    }
+
+    async fn perform_transaction(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        dont_wait: bool,
+    ) -> Result<TransactionResult, Error> {
+        assert_eq!("127.0.0.1:3478", to);
+        let stall = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.transactions.push((msg.typ.method, dont_wait));
+            calls.stall_refresh && msg.typ.method == METHOD_REFRESH
+        };
+        if dont_wait {
+            return Ok(TransactionResult::default());
+        }
+        if stall {
+            std::future::pending::<()>().await;
+        }
+
+        let mut res = Message::new();
+        res.build(&[
+            Box::new(msg.transaction_id),
+            Box::new(MessageType::new(msg.typ.method, CLASS_SUCCESS_RESPONSE)),
+            Box::new(test_integrity()),
+        ])?;
+
+        Ok(TransactionResult {
+            msg: res,
+            ..Default::default()
+        })
+    }
+
+    async fn perform_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        dont_wait: bool,
+        opts: TransactionOptions,
+    ) -> Result<TransactionResult, Error> {
+        self.calls
+            .lock()
+            .unwrap()
+            .options
+            .push((msg.typ.method, opts));
+        self.perform_transaction(msg, to, dont_wait).await
+    }
+
+    async fn begin_transaction_with(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        opts: TransactionOptions,
+    ) -> Result<PendingTransaction, Error> {
+        self.calls
+            .lock()
+            .unwrap()
+            .options
+            .push((msg.typ.method, opts));
+        self.begin_transaction(msg, to).await
+    }
+
+    fn on_deallocated(&mut self, id: &AllocationId) {
+        let mut calls = self.calls.lock().unwrap();
+        assert!(calls.deallocated.is_none(), "on_deallocated called twice");
+        calls.deallocated = Some(id.clone());
+    }
+}
+
+#[tokio::test]
+async fn test_relay_conn_with_custom_observer() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let relayed_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000);
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    let (config, inbound) = RelayConnConfig::new(
+        relayed_addr,
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    assert_eq!(relayed_addr, rc.local_addr()?);
+
+    // the first send creates the permission, then goes out as a Send
+    // indication while the channel is bound in the background
+    rc.send_to(b"hello", peer).await?;
+    {
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            Some(&(METHOD_CREATE_PERMISSION, false)),
+            calls.transactions.first()
+        );
+
+        let mut msg = Message::new();
+        msg.raw = calls.writes[0].clone();
+        msg.decode()?;
+        assert_eq!(MessageType::new(METHOD_SEND, CLASS_INDICATION), msg.typ);
+        let mut data = proto::data::Data::default();
+        data.get_from(&msg)?;
+        assert_eq!(b"hello", &data.0[..]);
+    }
+
+    for _ in 0..100 {
+        let state = {
+            let rci = rc.relay_conn.lock().await;
+            let bm = rci.binding_mgr.lock().await;
+            bm.find_by_addr(&peer).map(|b| b.state())
+        };
+        if state == Some(BindingState::Ready) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(calls
+        .lock()
+        .unwrap()
+        .transactions
+        .contains(&(METHOD_CHANNEL_BIND, false)));
+
+    // once bound, data goes out as ChannelData
+    rc.send_to(b"bound", peer).await?;
+    let mut ch_data = proto::chandata::ChannelData {
+        raw: calls.lock().unwrap().writes[1].clone(),
+        ..Default::default()
+    };
+    ch_data.decode()?;
+    assert_eq!(b"bound", &ch_data.data[..]);
+
+    // inbound data from either path is read from the peer
+    let mut buf = vec![0u8; 1500];
+    inbound.handle_data(b"world", peer)?;
+    let (n, from) = rc.recv_from(&mut buf).await?;
+    assert_eq!((&b"world"[..], peer), (&buf[..n], from));
+
+    let mut reply = proto::chandata::ChannelData {
+        data: b"channel".to_vec(),
+        number: ch_data.number,
+        ..Default::default()
+    };
+    reply.encode()?;
+    inbound.handle_channel_data(&reply.raw).await?;
+    let (n, from) = rc.recv_from(&mut buf).await?;
+    assert_eq!((&b"channel"[..], peer), (&buf[..n], from));
+
+    let mut unbound = proto::chandata::ChannelData {
+        data: b"unbound".to_vec(),
+        number: proto::channum::ChannelNumber(ch_data.number.0 + 1),
+        ..Default::default()
+    };
+    unbound.encode()?;
+    let result = inbound.handle_channel_data(&unbound.raw).await;
+    assert!(
+        matches!(result, Err(Error::ChannelBindNotFound)),
+        "expected ChannelBindNotFound"
+    );
+
+    // close sends a zero lifetime Refresh without waiting, then reports the deallocation
+    let id = rc.allocation_id().await;
+    rc.close().await?;
+    let calls = calls.lock().unwrap();
+    assert_eq!(Some(&(METHOD_REFRESH, true)), calls.transactions.last());
+    assert_eq!(
+        Some(AllocationId {
+            turn_server_addr: "127.0.0.1:3478".to_owned(),
+            local_addr: None,
+            username: "username".to_owned(),
+            relayed_addr,
+            transport: TransportKind::Udp,
+        }),
+        calls.deallocated
+    );
+    assert_eq!(Some(id), calls.deallocated.clone());
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_close_during_refresh() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls {
+        stall_refresh: true,
+        ..Default::default()
+    }));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    // the refresh at lifetime/2 gets no response
+    tokio::time::sleep(Duration::from_secs(301)).await;
+    assert_eq!(
+        vec![(METHOD_REFRESH, false)],
+        calls.lock().unwrap().transactions
+    );
+
+    // close doesn't wait for it, and only the zero lifetime Refresh is sent
+    tokio::time::timeout(Duration::from_secs(1), rc.close())
+        .await
+        .map_err(|_| Error::Other("close waited for the refresh".to_owned()))??;
+    assert_eq!(
+        vec![(METHOD_REFRESH, false), (METHOD_REFRESH, true)],
+        calls.lock().unwrap().transactions
+    );
+
+    tokio::time::sleep(Duration::from_secs(3600)).await;
+    assert_eq!(2, calls.lock().unwrap().transactions.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_state_events() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut events = rc.events();
+
+    rc.send_to(b"hello", peer).await?;
+    let mut received = vec![];
+    while received.len() < 3 {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .map_err(|_| Error::Other("no state event".to_owned()))?
+            .map_err(|err| Error::Other(err.to_string()))?;
+        received.push(event);
+    }
+    let binding_changed = |old, new| RelayConnEvent::BindingStateChanged {
+        peer,
+        number: 0x4000,
+        old,
+        new,
+        error: None,
+    };
+    assert_eq!(
+        vec![
+            RelayConnEvent::PermissionStateChanged {
+                peer,
+                old: PermState::Idle,
+                new: PermState::Permitted,
+            },
+            binding_changed(BindingState::Idle, BindingState::Request),
+            binding_changed(BindingState::Request, BindingState::Ready),
+        ],
+        received
+    );
+
+    // the peer stays permitted and bound
+    rc.send_to(b"bound", peer).await?;
+    assert!(events.try_recv().is_err(), "expected no more events");
+    let creates = calls
+        .lock()
+        .unwrap()
+        .transactions
+        .iter()
+        .filter(|(method, _)| *method == METHOD_CREATE_PERMISSION)
+        .count();
+    assert_eq!(1, creates);
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_binding_refresh() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut events = rc.events();
+    let channel_binds = || {
+        calls
+            .lock()
+            .unwrap()
+            .transactions
+            .iter()
+            .filter(|(method, _)| *method == METHOD_CHANNEL_BIND)
+            .count()
+    };
+    let binding = || async {
+        let rci = rc.relay_conn.lock().await;
+        let bm = rci.binding_mgr.lock().await;
+        bm.find_by_addr(&peer)
+            .map(|b| (b.state(), b.refreshed_at()))
+    };
+
+    rc.send_to(b"hello", peer).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let (state, bound_at) = binding().await.unwrap();
+    assert_eq!(BindingState::Ready, state);
+    assert_eq!(1, channel_binds());
+
+    // a send within 5 minutes of the bind doesn't refresh it
+    tokio::time::sleep(Duration::from_secs(4 * 60)).await;
+    rc.send_to(b"fresh", peer).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(1, channel_binds());
+
+    // past them the send refreshes it in the background
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    while events.try_recv().is_ok() {}
+    rc.send_to(b"stale", peer).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(2, channel_binds());
+    let (state, refreshed_at) = binding().await.unwrap();
+    assert_eq!(BindingState::Ready, state);
+    assert!(refreshed_at >= bound_at + Duration::from_secs(5 * 60));
+
+    let binding_changed = |old, new| RelayConnEvent::BindingStateChanged {
+        peer,
+        number: 0x4000,
+        old,
+        new,
+        error: None,
+    };
+    assert_eq!(
+        Ok(binding_changed(BindingState::Ready, BindingState::Refresh)),
+        events.try_recv()
+    );
+    assert_eq!(
+        Ok(binding_changed(BindingState::Refresh, BindingState::Ready)),
+        events.try_recv()
+    );
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+// A binding that is only received on after it was bound is refreshed by the
+// background refresher, one seeing no traffic isn't
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_receive_only_binding_refresh() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let receiver = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let idle = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6000);
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let channel_binds = || {
+        calls
+            .lock()
+            .unwrap()
+            .transactions
+            .iter()
+            .filter(|(method, _)| *method == METHOD_CHANNEL_BIND)
+            .count()
+    };
+
+    rc.send_to(b"hello", receiver).await?;
+    rc.send_to(b"hello", idle).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(2, channel_binds());
+    let bindings = rc.bindings().await;
+    assert_eq!(
+        vec![(receiver, BindingState::Ready), (idle, BindingState::Ready)],
+        bindings
+            .iter()
+            .map(|b| (b.peer, b.state))
+            .collect::<Vec<_>>()
+    );
+    let bound_at = bindings[0].refreshed_at;
+    assert_eq!(None, bindings[0].last_seen_at);
+
+    // only ChannelData comes in from the receiver, past the refresh threshold
+    let mut buf = vec![0u8; 1500];
+    for _ in 0..7 {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let mut ch_data = proto::chandata::ChannelData {
+            data: b"inbound".to_vec(),
+            number: proto::channum::ChannelNumber(bindings[0].number),
+            ..Default::default()
+        };
+        ch_data.encode()?;
+        inbound.handle_channel_data(&ch_data.raw).await?;
+        rc.recv_from(&mut buf).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(3, channel_binds());
+    let bindings = rc.bindings().await;
+    assert_eq!(BindingState::Ready, bindings[0].state);
+    assert!(bindings[0].refreshed_at >= bound_at + Duration::from_secs(5 * 60));
+    assert!(bindings[0]
+        .last_seen_at
+        .is_some_and(|last_seen_at| last_seen_at > bindings[0].refreshed_at));
+    assert_eq!(idle, bindings[1].peer);
+    assert_eq!(bound_at, bindings[1].refreshed_at);
+    assert_eq!(None, bindings[1].last_seen_at);
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+// DelayedObserver answers every transaction with a success response after
+// delay, or fails them all once fail is set
+struct DelayedObserver {
+    delay: Duration,
+    fail: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[async_trait]
+impl RelayConnObserver for DelayedObserver {
+    fn turn_server_addr(&self) -> String {
+        "127.0.0.1:3478".to_owned()
+    }
+
+    fn username(&self) -> Username {
+        Username::new(ATTR_USERNAME, "username".to_owned())
+    }
+
+    fn realm(&self) -> Realm {
+        Realm::new(ATTR_REALM, "realm".to_owned())
+    }
+
+    async fn write_to(&self, data: &[u8], _to: &str) -> Result<usize, Error> {
+        Ok(data.len())
+    }
+
+    async fn perform_transaction(
+        &mut self,
+        msg: &Message,
+        _to: &str,
+        _dont_wait: bool,
+    ) -> Result<TransactionResult, Error> {
+        tokio::time::sleep(self.delay).await;
+        if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(Error::AllRetransmissionsFailed("tr".to_owned()));
+        }
+
+        let mut res = Message::new();
+        res.build(&[
+            Box::new(msg.transaction_id),
+            Box::new(MessageType::new(msg.typ.method, CLASS_SUCCESS_RESPONSE)),
+            Box::new(proto::lifetime::Lifetime(Duration::from_secs(600))),
+            Box::new(test_integrity()),
+        ])?;
+
+        Ok(TransactionResult {
+            msg: res,
+            ..Default::default()
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_relay_conn_path_rtt() -> Result<(), Error> {
+    let delay = Duration::from_millis(30);
+    let plausible = |rtt: Option<Duration>| {
+        rtt.is_some_and(|rtt| rtt >= delay && rtt < delay + Duration::from_millis(500))
+    };
+
+    let fail = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let obs = DelayedObserver {
+        delay,
+        fail: Arc::clone(&fail),
+    };
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    assert_eq!(None, rc.last_refresh_rtt());
+    assert_eq!(None, rc.path_rtt());
+    assert_eq!(None, rc.last_failure());
+
+    // CreatePermission
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(b"hello", peer).await?;
+    assert!(
+        plausible(rc.last_refresh_rtt()),
+        "{:?}",
+        rc.last_refresh_rtt()
+    );
+
+    {
+        let mut rci = rc.relay_conn.lock().await;
+        rci.refresh_allocation(Duration::from_secs(600), false)
+            .await?;
+        rci.refresh_permissions().await?;
+    }
+    assert!(
+        plausible(rc.last_refresh_rtt()),
+        "{:?}",
+        rc.last_refresh_rtt()
+    );
+    assert!(plausible(rc.path_rtt()), "{:?}", rc.path_rtt());
+    assert!(rc.time_since_last_success() < Duration::from_secs(1));
+    assert_eq!(None, rc.last_failure());
+
+    // a failed refresh leaves the round trips and the last success as they were
+    fail.store(true, std::sync::atomic::Ordering::SeqCst);
+    let last_refresh_rtt = rc.last_refresh_rtt();
+    let before = Instant::now();
+    {
+        let mut rci = rc.relay_conn.lock().await;
+        assert!(rci.refresh_permissions().await.is_err());
+    }
+    assert!(rc.last_failure().is_some_and(|at| at >= before));
+    assert_eq!(last_refresh_rtt, rc.last_refresh_rtt());
+    assert!(rc.time_since_last_success() >= delay);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_max_bindings() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.max_bindings(1));
+
+    rc.send_to(
+        b"hello",
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000),
+    )
+    .await?;
+
+    let err = rc
+        .send_to(
+            b"hello",
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6000),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::Binding(crate::error::BindingError::LimitReached(1)))
+        ),
+        "unexpected error {}",
+        err
+    );
+    assert_eq!("turn: max_bindings of 1 reached", err.to_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_oversized_sends() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut events = rc.events();
+
+    let (peer1, peer2) = (
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000),
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6000),
+    );
+    let big = vec![0u8; DEFAULT_WARN_PAYLOAD_SIZE + 1];
+    rc.send_to(&big[..DEFAULT_WARN_PAYLOAD_SIZE], peer1).await?;
+    assert_eq!(0, rc.oversized_sends());
+
+    // oversized data is still sent, each peer is warned of once
+    for peer in &[peer1, peer1, peer2] {
+        rc.send_to(&big, *peer).await?;
+    }
+    assert_eq!(3, rc.oversized_sends());
+
+    let mut warned = vec![];
+    while let Ok(event) = events.try_recv() {
+        if let RelayConnEvent::OversizedSend {
+            peer,
+            size,
+            warn_payload_size,
+        } = event
+        {
+            assert_eq!(big.len(), size);
+            assert_eq!(DEFAULT_WARN_PAYLOAD_SIZE, warn_payload_size);
+            warned.push(peer);
+        }
+    }
+    assert_eq!(vec![peer1, peer2], warned);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_max_payload_size() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(
+        Arc::new(Mutex::new(obs)),
+        config.warn_payload_size(None).max_payload_size(1000),
+    );
+
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(&[0u8; 1000], peer).await?;
+
+    let err = rc.send_to(&[0u8; 1001], peer).await.unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert!(
+        matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(Error::PayloadTooLarge {
+                size: 1001,
+                max: 1000
+            })
+        ),
+        "unexpected error {}",
+        err
+    );
+    assert_eq!(0, rc.oversized_sends());
+
+    // nothing of the rejected payload reached the server
+    let writes = calls.lock().unwrap().writes.len();
+    assert_eq!(1, writes);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_send_backpressure() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    // a backed up transport is a typed, counted WouldBlock
+    calls.lock().unwrap().write_error = Some(io::ErrorKind::WouldBlock);
+    for _ in 0..2 {
+        let err = rc.send_to(b"hello", peer).await.unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert!(
+            matches!(
+                err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+                Some(Error::WouldBlock)
+            ),
+            "unexpected error {}",
+            err
+        );
     }
+    assert_eq!(2, rc.send_backpressure());
+
+    // other errors propagate as they are and aren't counted
+    calls.lock().unwrap().write_error = Some(io::ErrorKind::BrokenPipe);
+    let err = rc.send_to(b"hello", peer).await.unwrap_err();
+    assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+    assert_eq!(2, rc.send_backpressure());
+
+    calls.lock().unwrap().write_error = None;
+    rc.send_to(b"hello", peer).await?;
+    assert_eq!(2, rc.send_backpressure());
+
+    Ok(())
+}
+
+// create_permissions counts the recorded CreatePermission transactions
+fn create_permissions(calls: &std::sync::Mutex<RecordedCalls>) -> usize {
+    calls
+        .lock()
+        .unwrap()
+        .transactions
+        .iter()
+        .filter(|(method, _)| *method == METHOD_CREATE_PERMISSION)
+        .count()
+}
+
+#[tokio::test]
+async fn test_relay_conn_auto_permit_inbound() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.auto_permit_inbound(true));
+    let mut events = rc.events();
+
+    // the peer writes first, repeated Data indications are permitted once
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    for _ in 0..5 {
+        inbound.handle_data(b"offer", peer)?;
+    }
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .map_err(|_| Error::Other("no permission event".to_owned()))?
+        .map_err(|err| Error::Other(err.to_string()))?;
+    assert_eq!(
+        RelayConnEvent::PermissionStateChanged {
+            peer,
+            old: PermState::Idle,
+            new: PermState::Permitted,
+        },
+        event
+    );
+    assert_eq!(1, create_permissions(&calls));
+
+    // the reply goes out without a CreatePermission of its own
+    let mut buf = [0u8; 16];
+    let (n, from) = rc.recv_from(&mut buf).await?;
+    assert_eq!((&b"offer"[..], peer), (&buf[..n], from));
+    rc.send_to(b"answer", peer).await?;
+    assert_eq!(1, create_permissions(&calls));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_auto_permit_rate_limit() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.auto_permit_inbound(true));
+
+    // a flood of Data indications from distinct IPs
+    for i in 0..100u8 {
+        let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 1, i).into(), 6000);
+        inbound.handle_data(b"flood", peer)?;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let permitted = create_permissions(&calls);
+    assert!(
+        permitted > 0 && permitted <= AUTO_PERMIT_RATE as usize,
+        "{} permissions created",
+        permitted
+    );
+
+    // no hint is followed once closed
+    rc.close().await?;
+    inbound.handle_data(
+        b"late",
+        SocketAddr::new(Ipv4Addr::new(10, 0, 2, 1).into(), 6000),
+    )?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(permitted, create_permissions(&calls));
+
+    Ok(())
+}
+
+// ChannelBind gets a longer budget than other requests, the Refresh on close
+// a short one
+#[tokio::test]
+async fn test_relay_conn_transaction_options() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(b"hello", peer).await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !calls
+            .lock()
+            .unwrap()
+            .transactions
+            .contains(&(METHOD_CHANNEL_BIND, false))
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("no ChannelBind".to_owned()))?;
+    rc.close().await?;
+
+    let options = calls.lock().unwrap().options.clone();
+    assert_eq!(
+        vec![
+            (METHOD_CREATE_PERMISSION, TransactionOptions::default()),
+            (METHOD_CHANNEL_BIND, CHANNEL_BIND_TRANSACTION),
+            (METHOD_REFRESH, DEALLOCATE_TRANSACTION),
+        ],
+        options
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_drop_unsolicited_indications() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.auto_permit_inbound(true));
+    rc.drop_unsolicited_indications(true);
+
+    // nothing was sent to the stranger, its data is neither read nor permitted
+    let stranger = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6000);
+    inbound.handle_data(b"spoofed", stranger)?;
+    assert_eq!(1, rc.inbound_unsolicited());
+
+    // any port of a contacted IP gets through
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(b"hello", peer).await?;
+    let reply_from = SocketAddr::new(peer.ip(), 6001);
+    inbound.handle_data(b"reply", reply_from)?;
+
+    let mut buf = [0u8; 16];
+    let (n, from) = rc.recv_from(&mut buf).await?;
+    assert_eq!((&b"reply"[..], reply_from), (&buf[..n], from));
+    assert_eq!(1, rc.inbound_unsolicited());
+    assert_eq!(1, create_permissions(&calls));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_dedup_window() -> Result<(), Error> {
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::default(),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config.dedup_window(Some(4)));
+
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    inbound.handle_data(b"hello", peer)?;
+    inbound.handle_data(b"hello", peer)?;
+    inbound.handle_data(b"world", peer)?;
+    assert_eq!(1, rc.inbound_duplicates());
+
+    let mut buf = [0u8; 16];
+    for expected in [&b"hello"[..], b"world"] {
+        let (n, from) = rc.recv_from(&mut buf).await?;
+        assert_eq!((expected, peer), (&buf[..n], from));
+    }
+    let duplicate = tokio::time::timeout(Duration::from_millis(10), rc.recv_from(&mut buf)).await;
+    assert!(duplicate.is_err(), "duplicate delivered");
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_stats_reporter() -> Result<(), Error> {
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::default(),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    let (stats_tx, mut stats_rx) = mpsc::unbounded_channel();
+    let _reporter = rc.start_stats_reporter(
+        Duration::from_secs(1),
+        Arc::new(move |stats| {
+            let _ = stats_tx.send(stats);
+        }),
+    );
+
+    rc.send_to(b"hello", peer).await?;
+    inbound.handle_data(b"hi", peer)?;
+    inbound.handle_data(b"abc", peer)?;
+    let first = stats_rx.recv().await.unwrap();
+    assert_eq!(Duration::from_secs(1), first.elapsed);
+    assert_eq!((1, 5), (first.delta.packets_sent, first.delta.bytes_sent));
+    assert_eq!(
+        (2, 5),
+        (first.delta.packets_received, first.delta.bytes_received)
+    );
+
+    inbound.handle_data(b"abcd", peer)?;
+    let second = stats_rx.recv().await.unwrap();
+    assert_eq!(0, second.delta.packets_sent);
+    assert_eq!(
+        (1, 4),
+        (second.delta.packets_received, second.delta.bytes_received)
+    );
+    assert_eq!(
+        (3, 9),
+        (
+            second.counters.packets_received,
+            second.counters.bytes_received
+        )
+    );
+
+    let third = stats_rx.recv().await.unwrap();
+    assert_eq!(RelayConnCounters::default(), third.delta);
+    assert_eq!(second.counters, third.counters);
+    assert_eq!(rc.counters(), third.counters);
+
+    // no sample comes after close
+    rc.close().await?;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert!(stats_rx.try_recv().is_err(), "sample after close");
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_stats_reporter_dropped() -> Result<(), Error> {
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::default(),
+    };
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    let samples = Arc::new(AtomicU64::new(0));
+    let counted = Arc::clone(&samples);
+    let reporter = rc.start_stats_reporter(
+        Duration::from_secs(1),
+        Arc::new(move |_| {
+            counted.fetch_add(1, Ordering::Relaxed);
+        }),
+    );
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(2, samples.load(Ordering::Relaxed));
+
+    drop(reporter);
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(2, samples.load(Ordering::Relaxed));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_auto_permit_off() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let _rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    inbound.handle_data(
+        b"offer",
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000),
+    )?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(0, create_permissions(&calls));
+
+    Ok(())
+}
+
+// keepalive_writes counts the recorded writes carrying payload to peer,
+// as ChannelData and as Send indications
+fn keepalive_writes(calls: &std::sync::Mutex<RecordedCalls>, payload: &[u8]) -> (usize, usize) {
+    let calls = calls.lock().unwrap();
+    let (mut channel_data, mut indications) = (0, 0);
+    for raw in &calls.writes {
+        if proto::chandata::ChannelData::is_channel_data(raw) {
+            let mut ch_data = proto::chandata::ChannelData {
+                raw: raw.clone(),
+                ..Default::default()
+            };
+            if ch_data.decode().is_ok() && ch_data.data == payload {
+                channel_data += 1;
+            }
+        } else {
+            let mut msg = Message::new();
+            msg.raw = raw.clone();
+            let mut data = proto::data::Data::default();
+            if msg.decode().is_ok() && data.get_from(&msg).is_ok() && data.0 == payload {
+                indications += 1;
+            }
+        }
+    }
+    (channel_data, indications)
+}
+
+// wait_bound waits for the channel of peer to be bound
+async fn wait_bound<T: RelayConnObserver + Send + Sync>(rc: &RelayConn<T>, peer: SocketAddr) {
+    for _ in 0..100 {
+        let state = {
+            let rci = rc.relay_conn.lock().await;
+            let bm = rci.binding_mgr.lock().await;
+            bm.find_by_addr(&peer).map(|b| b.state())
+        };
+        if state == Some(BindingState::Ready) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("channel of {} not bound", peer);
+}
+
+#[tokio::test]
+async fn test_relay_conn_send_batching() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(
+        Arc::new(Mutex::new(obs)),
+        config.send_batching(SendBatching {
+            max_delay: Duration::from_millis(50),
+            max_packets: 4,
+            ..Default::default()
+        }),
+    );
+
+    // the first send is a Send indication, written at once
+    rc.send_to(b"first", peer).await?;
+    wait_bound(&rc, peer).await;
+    assert!(calls.lock().unwrap().batches.is_empty());
+
+    // full batches are written at once, the rest after max_delay
+    for i in 0..10u8 {
+        rc.send_to(&[i], peer).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(vec![4, 4], calls.lock().unwrap().batches);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(vec![4, 4, 2], calls.lock().unwrap().batches);
+
+    // in order, as ChannelData
+    let sent: Vec<Vec<u8>> = calls.lock().unwrap().writes[1..]
+        .iter()
+        .map(|raw| {
+            let mut ch_data = proto::chandata::ChannelData {
+                raw: raw.clone(),
+                ..Default::default()
+            };
+            ch_data.decode().unwrap();
+            ch_data.data
+        })
+        .collect();
+    assert_eq!((0..10u8).map(|i| vec![i]).collect::<Vec<_>>(), sent);
+
+    // closing writes what is queued
+    rc.send_to(b"last", peer).await?;
+    rc.close().await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(vec![4, 4, 2, 1], calls.lock().unwrap().batches);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_peer_keepalive() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let payload = b"ka";
+
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        MessageIntegrity::new_short_term_integrity("pass".to_owned()),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(
+        Arc::new(Mutex::new(obs)),
+        config.keepalive_payload(payload.to_vec()),
+    );
+
+    // no permission is created for a keepalive
+    let result = rc.send_keepalive(peer).await;
+    assert!(
+        matches!(result, Err(Error::NoPermission)),
+        "expected NoPermission"
+    );
+    assert!(calls.lock().unwrap().transactions.is_empty());
+
+    rc.send_to(b"hello", peer).await?;
+    for _ in 0..100 {
+        let state = {
+            let rci = rc.relay_conn.lock().await;
+            let bm = rci.binding_mgr.lock().await;
+            bm.find_by_addr(&peer).map(|b| b.state())
+        };
+        if state == Some(BindingState::Ready) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    rc.send_keepalive(peer).await?;
+    assert_eq!((1, 0), keepalive_writes(&calls, payload));
+
+    rc.set_peer_keepalive(peer, Duration::from_millis(20));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let (channel_data, _) = keepalive_writes(&calls, payload);
+    assert!(
+        channel_data >= 4,
+        "expected periodic keepalives, got {}",
+        channel_data
+    );
+
+    // without the binding the keepalive goes in a Send indication
+    {
+        let rci = rc.relay_conn.lock().await;
+        rci.binding_mgr.lock().await.delete_by_addr(&peer);
+    }
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let (_, indications) = keepalive_writes(&calls, payload);
+    assert!(indications >= 1, "expected a Send indication keepalive");
+
+    // and it stops with the permission
+    {
+        let mut rci = rc.relay_conn.lock().await;
+        rci.perm_map.delete(&peer);
+    }
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let stopped = keepalive_writes(&calls, payload);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(stopped, keepalive_writes(&calls, payload));
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+async fn next_ttl(rx: &mut watch::Receiver<AllocationTtl>) -> Result<AllocationTtl, Error> {
+    tokio::time::timeout(Duration::from_secs(5), rx.changed())
+        .await
+        .map_err(|_| Error::Other("no ttl update".to_owned()))?
+        .map_err(|err| Error::Other(err.to_string()))?;
+    Ok(*rx.borrow_and_update())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_ttl_watch() -> Result<(), Error> {
+    let fail = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let obs = DelayedObserver {
+        delay: Duration::from_millis(10),
+        fail: Arc::clone(&fail),
+    };
+    // refreshed every 100ms, each refresh grants 600s
+    let lifetime = Duration::from_millis(200);
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        lifetime,
+    );
+    let start = Instant::now();
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    let mut rx = rc.ttl_watch();
+    let initial = *rx.borrow_and_update();
+    assert!(initial.last_refresh_ok);
+    assert!(initial.expires_at >= start + lifetime);
+    assert!(initial.expires_at <= Instant::now() + lifetime);
+
+    let first = next_ttl(&mut rx).await?;
+    let second = next_ttl(&mut rx).await?;
+    for ttl in &[first, second] {
+        assert!(ttl.last_refresh_ok);
+        assert!(ttl.remaining() > Duration::from_secs(590), "{:?}", ttl);
+    }
+    assert!(first.expires_at > initial.expires_at);
+    assert!(second.expires_at > first.expires_at);
+
+    // a failed refresh keeps the expiry of the last successful one, a refresh
+    // may already be in flight
+    fail.store(true, std::sync::atomic::Ordering::SeqCst);
+    let mut failed = next_ttl(&mut rx).await?;
+    for _ in 0..3 {
+        if !failed.last_refresh_ok {
+            break;
+        }
+        failed = next_ttl(&mut rx).await?;
+    }
+    assert!(!failed.last_refresh_ok);
+    assert!(failed.expires_at >= second.expires_at);
+    let again = next_ttl(&mut rx).await?;
+    assert!(!again.last_refresh_ok);
+    assert_eq!(failed.expires_at, again.expires_at);
+
+    fail.store(false, std::sync::atomic::Ordering::SeqCst);
+    rc.close().await?;
+
+    Ok(())
+}
+
+// RelocatingObserver answers every transaction with a success response,
+// Refresh responses carry relayed_addr once it is set
+struct RelocatingObserver {
+    relayed_addr: Arc<std::sync::Mutex<Option<SocketAddr>>>,
+}
+
+#[async_trait]
+impl RelayConnObserver for RelocatingObserver {
+    fn turn_server_addr(&self) -> String {
+        "127.0.0.1:3478".to_owned()
+    }
+
+    fn username(&self) -> Username {
+        Username::new(ATTR_USERNAME, "username".to_owned())
+    }
+
+    fn realm(&self) -> Realm {
+        Realm::new(ATTR_REALM, "realm".to_owned())
+    }
+
+    async fn write_to(&self, data: &[u8], _to: &str) -> Result<usize, Error> {
+        Ok(data.len())
+    }
+
+    async fn perform_transaction(
+        &mut self,
+        msg: &Message,
+        _to: &str,
+        _dont_wait: bool,
+    ) -> Result<TransactionResult, Error> {
+        let mut setters: Vec<Box<dyn Setter>> = vec![
+            Box::new(msg.transaction_id),
+            Box::new(MessageType::new(msg.typ.method, CLASS_SUCCESS_RESPONSE)),
+            Box::new(proto::lifetime::Lifetime(Duration::from_secs(600))),
+        ];
+        if let Some(addr) = *self.relayed_addr.lock().unwrap() {
+            if msg.typ.method == METHOD_REFRESH {
+                setters.push(Box::new(proto::relayaddr::RelayedAddress {
+                    ip: addr.ip(),
+                    port: addr.port(),
+                }));
+            }
+        }
+        setters.push(Box::new(test_integrity()));
+
+        let mut res = Message::new();
+        res.build(&setters)?;
+        Ok(TransactionResult {
+            msg: res,
+            ..Default::default()
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_relay_conn_relayed_address_changed() -> Result<(), Error> {
+    let relayed_addr = Arc::new(std::sync::Mutex::new(None));
+    let obs = RelocatingObserver {
+        relayed_addr: Arc::clone(&relayed_addr),
+    };
+    let old = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000);
+    let new = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 7000);
+    // refreshed every 100ms
+    let (config, _inbound) = RelayConnConfig::new(
+        old,
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_millis(200),
+    );
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut events = rc.events();
+    let mut ttl = rc.ttl_watch();
+
+    // without XOR-RELAYED-ADDRESS nothing changes
+    next_ttl(&mut ttl).await?;
+    assert_eq!(old, rc.local_addr()?);
+    assert!(events.try_recv().is_err(), "expected no event");
+
+    *relayed_addr.lock().unwrap() = Some(new);
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .map_err(|_| Error::Other("relayed address unchanged".to_owned()))?
+        .map_err(|err| Error::Other(err.to_string()))?;
+    assert_eq!(RelayConnEvent::RelayedAddressChanged { old, new }, event);
+    assert_eq!(new, rc.local_addr()?);
+
+    // the same address again is no change
+    next_ttl(&mut ttl).await?;
+    next_ttl(&mut ttl).await?;
+    assert!(events.try_recv().is_err(), "expected a single event");
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_inbound_overflow() -> Result<(), Error> {
+    let obs = DummyRelayConnObserver {
+        turn_server_addr: String::new(),
+        username: Username::new(ATTR_USERNAME, "username".to_owned()),
+        realm: Realm::new(ATTR_REALM, "realm".to_owned()),
+        transaction_result: || Err(Error::Other("fake error".to_owned())),
+    };
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut events = rc.events();
+    let peer = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 6000);
+
+    for _ in 0..MAX_READ_QUEUE_SIZE + 10 {
+        inbound.handle_data(b"data", peer)?;
+    }
+    assert_eq!(10, rc.inbound_dropped());
+    assert_eq!(
+        RelayConnEvent::InboundOverflow { dropped: 1 },
+        events
+            .try_recv()
+            .map_err(|err| Error::Other(err.to_string()))?
+    );
+
+    // dropping again right away is the same overflow
+    let mut buf = vec![0u8; 16];
+    rc.recv_from(&mut buf).await?;
+    for _ in 0..3 {
+        inbound.handle_data(b"data", peer)?;
+    }
+    assert_eq!(12, rc.inbound_dropped());
+    assert!(events.try_recv().is_err(), "expected a single event");
+
+    // after a while without drops, the next one is a new overflow
+    tokio::time::advance(Duration::from_secs(10)).await;
+    inbound.handle_data(b"data", peer)?;
+    assert_eq!(13, rc.inbound_dropped());
+    assert_eq!(
+        RelayConnEvent::InboundOverflow { dropped: 13 },
+        events
+            .try_recv()
+            .map_err(|err| Error::Other(err.to_string()))?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_conn_subscribe_peer() -> Result<(), Error> {
+    let obs = DummyRelayConnObserver {
+        turn_server_addr: String::new(),
+        username: Username::new(ATTR_USERNAME, "username".to_owned()),
+        realm: Realm::new(ATTR_REALM, "realm".to_owned()),
+        transaction_result: || Err(Error::Other("fake error".to_owned())),
+    };
+    let (config, inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let slow = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 6000);
+    let fast = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 6001);
+    let other = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 6002);
+
+    let mut slow_rx = rc.subscribe_peer(slow, 2);
+    let mut fast_rx = rc.subscribe_peer(fast, 2);
+
+    // the slow subscriber isn't read, the fast one and recv_from are
+    let mut buf = vec![0u8; 16];
+    for i in 0..5u8 {
+        inbound.handle_data(&[i], slow)?;
+        inbound.handle_data(&[i], fast)?;
+        inbound.handle_data(&[i], other)?;
+        assert_eq!(Some(Bytes::from(vec![i])), fast_rx.recv().await);
+        assert_eq!((1, other), rc.recv_from(&mut buf).await?);
+        assert_eq!(i, buf[0]);
+    }
+    assert_eq!(3, rc.peer_inbound_dropped(slow));
+    assert_eq!(0, rc.peer_inbound_dropped(fast));
+    assert_eq!(0, rc.inbound_dropped());
+    assert_eq!(Some(Bytes::from(vec![0])), slow_rx.recv().await);
+    assert_eq!(Some(Bytes::from(vec![1])), slow_rx.recv().await);
+
+    // dropping the receiver unsubscribes, the data goes to recv_from again
+    drop(slow_rx);
+    for _ in 0..10 {
+        if rc.subscriptions.len() == 1 {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(1, rc.subscriptions.len());
+    inbound.handle_data(b"back", slow)?;
+    let (n, from) = rc.recv_from(&mut buf).await?;
+    assert_eq!((&b"back"[..], slow), (&buf[..n], from));
+
+    Ok(())
+}
+
+// SlowServerObserver answers every transaction with a success response after
+// latency, without holding the observer meanwhile, and records the methods
+struct SlowServerObserver {
+    latency: Duration,
+    methods: Arc<std::sync::Mutex<Vec<Method>>>,
+}
+
+#[async_trait]
+impl RelayConnObserver for SlowServerObserver {
+    fn turn_server_addr(&self) -> String {
+        "127.0.0.1:3478".to_owned()
+    }
+
+    fn username(&self) -> Username {
+        Username::new(ATTR_USERNAME, "username".to_owned())
+    }
+
+    fn realm(&self) -> Realm {
+        Realm::new(ATTR_REALM, "realm".to_owned())
+    }
+
+    async fn write_to(&self, data: &[u8], _to: &str) -> Result<usize, Error> {
+        Ok(data.len())
+    }
+
+    async fn perform_transaction(
+        &mut self,
+        msg: &Message,
+        to: &str,
+        _dont_wait: bool,
+    ) -> Result<TransactionResult, Error> {
+        self.begin_transaction(msg, to).await?.await
+    }
+
+    async fn begin_transaction(
+        &mut self,
+        msg: &Message,
+        _to: &str,
+    ) -> Result<PendingTransaction, Error> {
+        self.methods.lock().unwrap().push(msg.typ.method);
+        let mut res = Message::new();
+        res.build(&[
+            Box::new(msg.transaction_id),
+            Box::new(MessageType::new(msg.typ.method, CLASS_SUCCESS_RESPONSE)),
+            Box::new(proto::lifetime::Lifetime(Duration::from_secs(600))),
+            Box::new(test_integrity()),
+        ])?;
+        let latency = self.latency;
+        Ok(Box::pin(async move {
+            tokio::time::sleep(latency).await;
+            Ok(TransactionResult {
+                msg: res,
+                ..Default::default()
+            })
+        }))
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_send_during_refresh() -> Result<(), Error> {
+    let latency = Duration::from_millis(200);
+    let methods = Arc::new(std::sync::Mutex::new(vec![]));
+    let obs = SlowServerObserver {
+        latency,
+        methods: Arc::clone(&methods),
+    };
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+
+    // the first send waits for the CreatePermission
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(b"hello", peer).await?;
+
+    // through the permission refreshes and the allocation refresh at 300s
+    let mut slowest = Duration::from_secs(0);
+    for _ in 0..(310 * 20) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        rc.send_to(b"hello", peer).await?;
+        slowest = slowest.max(start.elapsed());
+    }
+    assert!(slowest < latency / 4, "slowest send_to took {:?}", slowest);
+    assert!(methods.lock().unwrap().contains(&METHOD_REFRESH));
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+// RecordingObserver answers Refresh without LIFETIME, the lifetime asked for
+// is kept and the allocation stays alive across refreshes
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_refresh_without_lifetime() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let lifetime = Duration::from_secs(600);
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        lifetime,
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(Arc::new(Mutex::new(obs)), config);
+    let mut ttl = rc.ttl_watch();
+
+    // refreshed every 300s
+    for _ in 0..3 {
+        tokio::time::timeout(Duration::from_secs(310), ttl.changed())
+            .await
+            .map_err(|_| Error::Other("no refresh".to_owned()))?
+            .map_err(|err| Error::Other(err.to_string()))?;
+        let refreshed = *ttl.borrow_and_update();
+        assert!(refreshed.last_refresh_ok);
+        assert_eq!(lifetime, refreshed.remaining());
+    }
+    assert_eq!(lifetime, rc.relay_conn.lock().await.lifetime);
+    let refreshes = calls
+        .lock()
+        .unwrap()
+        .transactions
+        .iter()
+        .filter(|(method, _)| *method == METHOD_REFRESH)
+        .count();
+    assert_eq!(3, refreshes);
+    rc.close().await?;
+
+    // unless asked not to
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        lifetime,
+    );
+    let obs = RecordingObserver {
+        calls: Arc::new(std::sync::Mutex::new(RecordedCalls::default())),
+    };
+    let mut rc = RelayConn::new(
+        Arc::new(Mutex::new(obs)),
+        config.require_refresh_lifetime(true),
+    );
+    assert!(rc
+        .relay_conn
+        .lock()
+        .await
+        .refresh_allocation(lifetime, false)
+        .await
+        .is_err());
+    rc.close().await?;
+
+    Ok(())
+}
+
+// expiring_relay_conn makes a RelayConn whose first refresh, at 300s, fails
+// and waits until it is within 2s of its expiry. The server takes requests
+// again meanwhile, the next refresh would only be at 600s.
+async fn expiring_relay_conn(
+    config: impl FnOnce(RelayConnConfig) -> RelayConnConfig,
+) -> Result<(RelayConn<DelayedObserver>, watch::Receiver<AllocationTtl>), Error> {
+    let fail = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let obs = DelayedObserver {
+        delay: Duration::from_millis(10),
+        fail: Arc::clone(&fail),
+    };
+    let (relay_config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let rc = RelayConn::new(Arc::new(Mutex::new(obs)), config(relay_config));
+    let mut ttl = rc.ttl_watch();
+    tokio::time::timeout(Duration::from_secs(310), ttl.changed())
+        .await
+        .map_err(|_| Error::Other("no refresh".to_owned()))?
+        .map_err(|err| Error::Other(err.to_string()))?;
+    let failed = *ttl.borrow_and_update();
+    assert!(!failed.last_refresh_ok);
+    fail.store(false, std::sync::atomic::Ordering::SeqCst);
+
+    // far from the expiry send_to doesn't refresh
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    rc.send_to(b"early", peer).await?;
+    assert!(!ttl.has_changed().unwrap_or(true));
+
+    tokio::time::sleep_until(failed.expires_at - Duration::from_secs(2)).await;
+    Ok((rc, ttl))
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_send_refreshes_expiring_allocation() -> Result<(), Error> {
+    let (mut rc, mut ttl) = expiring_relay_conn(|config| config).await?;
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    rc.send_to(b"late", peer).await?;
+    let refreshed = *ttl.borrow_and_update();
+    assert!(refreshed.last_refresh_ok);
+    assert!(refreshed.remaining() > Duration::from_secs(590));
+
+    // the refresh timer started over, it doesn't fire at 600s
+    let refreshed_at = Instant::now();
+    tokio::time::timeout(Duration::from_secs(310), ttl.changed())
+        .await
+        .map_err(|_| Error::Other("no refresh".to_owned()))?
+        .map_err(|err| Error::Other(err.to_string()))?;
+    assert!(Instant::now() - refreshed_at >= Duration::from_secs(300));
+    assert!(ttl.borrow_and_update().last_refresh_ok);
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+// The send refreshing the expiring allocation doesn't hold the
+// RelayConnInternal, another send waits for that refresh to be done
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_send_refreshes_expiring_allocation_unlocked() -> Result<(), Error> {
+    let (mut rc, mut ttl) = expiring_relay_conn(|config| config).await?;
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    let (first, second, unlocked) = tokio::join!(
+        rc.send_to(b"first", peer),
+        rc.send_to(b"second", peer),
+        async { rc.relay_conn.try_lock().is_ok() },
+    );
+    first?;
+    second?;
+    assert!(unlocked);
+    assert!(ttl.borrow_and_update().last_refresh_ok);
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_send_fails_fast_on_expiry() -> Result<(), Error> {
+    let (mut rc, ttl) = expiring_relay_conn(|config| config.fail_fast_on_expiry(true)).await?;
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+
+    // the server would take the refresh, none is sent
+    let err = rc.send_to(b"late", peer).await.unwrap_err();
+    assert_eq!(io::ErrorKind::NotConnected, err.kind());
+    match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::AllocationExpiring { remaining }) => {
+            assert!(*remaining <= Duration::from_secs(2), "{:?}", remaining);
+        }
+        other => panic!("expected AllocationExpiring, got {:?}", other),
+    }
+    assert!(!ttl.has_changed().unwrap_or(true));
+    assert!(!ttl.borrow().last_refresh_ok);
+
+    rc.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_adaptive_channel_bind() -> Result<(), Error> {
+    let relay_conn = |policy| {
+        let obs = DelayedObserver {
+            delay: Duration::from_millis(1),
+            fail: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        };
+        let (config, _inbound) = RelayConnConfig::new(
+            SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+            test_integrity(),
+            Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+            Duration::from_secs(600),
+        );
+        RelayConn::new(
+            Arc::new(Mutex::new(obs)),
+            config.channel_bind_policy(policy),
+        )
+    };
+    let is_bound = |rc: &RelayConn<DelayedObserver>, peer| {
+        let relay_conn = Arc::clone(&rc.relay_conn);
+        async move {
+            let rc = relay_conn.lock().await;
+            let bound = rc.binding_mgr.lock().await.find_by_addr(&peer).is_some();
+            bound
+        }
+    };
+    let low = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let high = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), 6000);
+
+    let mut rc = relay_conn(ChannelBindPolicy::Adaptive {
+        min_packets_per_interval: 10,
+    });
+    // 5 packets a second for 10s
+    for _ in 0..50 {
+        rc.send_to(b"low", low).await?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert!(!is_bound(&rc, low).await);
+
+    // 100 packets a second, bound from the 10th
+    for i in 0..20 {
+        rc.send_to(b"high", high).await?;
+        assert_eq!(i >= 9, is_bound(&rc, high).await, "packet {}", i);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!is_bound(&rc, low).await);
+    rc.close().await?;
+
+    let mut rc = relay_conn(ChannelBindPolicy::Never);
+    for _ in 0..20 {
+        rc.send_to(b"high", high).await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!is_bound(&rc, high).await);
+    rc.close().await?;
+
+    let mut rc = relay_conn(ChannelBindPolicy::Always);
+    rc.send_to(b"low", low).await?;
+    assert!(is_bound(&rc, low).await);
+    rc.close().await?;
+
+    Ok(())
+}
+
+// A RelayConn given a MaintenanceScheduler starts no timers of its own, the
+// scheduler refreshes it until it is closed
+#[tokio::test(start_paused = true)]
+async fn test_relay_conn_maintenance_scheduler() -> Result<(), Error> {
+    let calls = Arc::new(std::sync::Mutex::new(RecordedCalls::default()));
+    let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+    let scheduler = MaintenanceScheduler::new();
+    let (config, _inbound) = RelayConnConfig::new(
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5000),
+        test_integrity(),
+        Nonce::new(ATTR_NONCE, "nonce".to_owned()),
+        Duration::from_secs(600),
+    );
+    let obs = RecordingObserver {
+        calls: Arc::clone(&calls),
+    };
+    let mut rc = RelayConn::new(
+        Arc::new(Mutex::new(obs)),
+        config.maintenance_scheduler(scheduler.clone()),
+    );
+    assert!(!rc.refresh_alloc_timer.is_running());
+    assert!(!rc.refresh_perms_timer.is_running());
+    let count = |method| {
+        calls
+            .lock()
+            .unwrap()
+            .transactions
+            .iter()
+            .filter(|(m, _)| *m == method)
+            .count()
+    };
+
+    rc.send_to(b"hello", peer).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let permissions = count(METHOD_CREATE_PERMISSION);
+    assert_eq!(0, count(METHOD_REFRESH));
+
+    // permissions are refreshed at 2 and 4 minutes, the allocation at 5
+    tokio::time::sleep(Duration::from_secs(301)).await;
+    assert_eq!(permissions + 2, count(METHOD_CREATE_PERMISSION));
+    assert_eq!(1, count(METHOD_REFRESH));
+
+    // close deallocates, then nothing is refreshed
+    rc.close().await?;
+    let refreshes = count(METHOD_REFRESH);
+    tokio::time::sleep(Duration::from_secs(600)).await;
+    assert_eq!(refreshes, count(METHOD_REFRESH));
+    assert_eq!(permissions + 2, count(METHOD_CREATE_PERMISSION));
 
     Ok(())
 }
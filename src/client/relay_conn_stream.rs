@@ -0,0 +1,169 @@
+#[cfg(all(test, feature = "server"))]
+mod relay_conn_stream_test;
+
+use super::relay_conn::*;
+use crate::error::Error;
+
+use util::Conn;
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// a relayed datagram is carried in a ChannelData or Data attribute, so it is
+// never larger than this
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+// RelayConnStream adapts a RelayConn to AsyncRead/AsyncWrite toward a single peer.
+//
+// Every poll_write is sent to the peer as one datagram and every datagram
+// received from the peer is appended to the read side, datagrams from other
+// addresses are dropped. Datagram boundaries are lost and nothing is
+// retransmitted or reordered, so this is only appropriate for protocols that
+// tolerate a lossy byte stream or do their own framing and recovery.
+// poll_shutdown closes the relay conn.
+pub struct RelayConnStream<T: 'static + RelayConnObserver + Send + Sync> {
+    relay_conn: Option<Arc<RelayConn<T>>>,
+    peer: SocketAddr,
+
+    // the rest of a datagram that did not fit the caller's buffer
+    read_buf: Vec<u8>,
+    read_pos: usize,
+
+    read_fut: Option<BoxFuture<io::Result<Vec<u8>>>>,
+    write_fut: Option<BoxFuture<io::Result<usize>>>,
+    shutdown_fut: Option<BoxFuture<Result<(), Error>>>,
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> RelayConnStream<T> {
+    pub fn new(relay_conn: RelayConn<T>, peer: SocketAddr) -> Self {
+        RelayConnStream {
+            relay_conn: Some(Arc::new(relay_conn)),
+            peer,
+            read_buf: vec![],
+            read_pos: 0,
+            read_fut: None,
+            write_fut: None,
+            shutdown_fut: None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn relay_conn(&self) -> io::Result<Arc<RelayConn<T>>> {
+        self.relay_conn
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| Error::AlreadyClosed.into())
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> AsyncRead for RelayConnStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_pos == self.read_buf.len() {
+            if self.read_fut.is_none() {
+                let relay_conn = self.relay_conn()?;
+                let peer = self.peer;
+                self.read_fut = Some(Box::pin(async move {
+                    let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
+                    loop {
+                        let (n, from) = relay_conn.recv_from(&mut data).await?;
+                        if from == peer {
+                            data.truncate(n);
+                            return Ok(data);
+                        }
+                        log::debug!("relay stream dropped {} bytes from {}", n, from);
+                    }
+                }));
+            }
+
+            let data = match self.read_fut.as_mut().map(|f| f.as_mut().poll(cx)) {
+                Some(Poll::Ready(result)) => {
+                    self.read_fut = None;
+                    result?
+                }
+                _ => return Poll::Pending,
+            };
+            self.read_buf = data;
+            self.read_pos = 0;
+        }
+
+        let n = std::cmp::min(buf.remaining(), self.read_buf.len() - self.read_pos);
+        buf.put_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: 'static + RelayConnObserver + Send + Sync> AsyncWrite for RelayConnStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_fut.is_none() {
+            let relay_conn = self.relay_conn()?;
+            let peer = self.peer;
+            let data = buf[..std::cmp::min(buf.len(), MAX_DATAGRAM_SIZE)].to_vec();
+            self.write_fut = Some(Box::pin(async move {
+                // send_to reports the bytes written on the wire, which include
+                // the ChannelData or Send indication header
+                relay_conn.send_to(&data, peer).await?;
+                Ok(data.len())
+            }));
+        }
+
+        match self.write_fut.as_mut().map(|f| f.as_mut().poll(cx)) {
+            Some(Poll::Ready(result)) => {
+                self.write_fut = None;
+                Poll::Ready(result)
+            }
+            _ => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // every write is sent as soon as it completes
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.shutdown_fut.is_none() {
+            // pending reads and writes hold a reference to the relay conn
+            self.read_fut = None;
+            self.write_fut = None;
+
+            let relay_conn = match self.relay_conn.take() {
+                Some(relay_conn) => relay_conn,
+                None => return Poll::Ready(Ok(())),
+            };
+            let mut relay_conn = match Arc::try_unwrap(relay_conn) {
+                Ok(relay_conn) => relay_conn,
+                Err(_) => return Poll::Ready(Err(Error::AlreadyClosed.into())),
+            };
+            self.shutdown_fut = Some(Box::pin(async move { relay_conn.close().await }));
+        }
+
+        match self.shutdown_fut.as_mut().map(|f| f.as_mut().poll(cx)) {
+            Some(Poll::Ready(result)) => {
+                self.shutdown_fut = None;
+                Poll::Ready(result.map_err(io::Error::from))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use super::*;
+use crate::test_util::*;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8]) -> io::Result<()> {
+    stream
+        .write_all(&(frame.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut frame = vec![0u8; u16::from_be_bytes(header) as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[tokio::test]
+async fn test_relay_conn_stream_framed_echo() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let peer_addr = spawn_udp_echo_peer().await?;
+    let client = start_client(server_addr).await?;
+
+    let mut stream = RelayConnStream::new(client.allocate().await?, peer_addr);
+    assert_eq!(peer_addr, stream.peer_addr());
+
+    for frame in &[&b"hello"[..], &b""[..], &[0xabu8; 1000][..]] {
+        write_frame(&mut stream, frame).await?;
+        let echoed = read_frame(&mut stream).await?;
+        assert_eq!(*frame, &echoed[..], "echoed frame should match");
+    }
+
+    // short reads are served from the buffered datagram
+    stream.write_all(b"abcdef").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(b"abcd", &buf);
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(b"ef", &buf);
+
+    stream.shutdown().await?;
+    let result = stream.write_all(b"closed").await;
+    assert!(result.is_err(), "write after shutdown should fail");
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod retry_test;
+
+use super::periodic_timer::jitter_interval;
+use crate::error::Error;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::time::Duration;
+
+// DEFAULT_MAX_ATTEMPTS is how often a transaction is tried by default, enough
+// for a 438 (Stale Nonce) to be retried with the new nonce
+pub const DEFAULT_MAX_ATTEMPTS: u16 = 3;
+
+// Backoff is the wait before each retry, initial before the first and doubled
+// for each further one up to max, randomized by up to jitter of it either way.
+// With full_jitter each wait is instead drawn uniformly from zero to the
+// doubled delay, which spreads out clients that failed together the most.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub jitter: f64,
+    pub full_jitter: bool,
+}
+
+impl Backoff {
+    // delay is the wait before the retry-th retry, counting from zero
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(self.max, |delay| delay.min(self.max));
+        if self.full_jitter {
+            return delay.mul_f64(rand::random::<f64>());
+        }
+        // NaN and negative jitter select none
+        let jitter = if self.jitter > 0.0 {
+            self.jitter.min(1.0)
+        } else {
+            0.0
+        };
+        jitter_interval(delay, jitter)
+    }
+}
+
+// RetryPolicy decides how the RelayConn retries a failed Refresh or
+// CreatePermission transaction, and how Client::allocate_with_retry retries
+// an Allocate. The default tries 3 times, at once, and only after a 438
+// (Stale Nonce).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // max_attempts counts the first try, zero is treated as one
+    pub max_attempts: u16,
+    // backoff is the wait before each retry, None retries at once
+    pub backoff: Option<Backoff>,
+    // retry_timeouts also retries transactions that got no response
+    pub retry_timeouts: bool,
+    // retry_server_errors also retries requests answered with a 5xx error,
+    // e.g. 508 (Insufficient Capacity)
+    pub retry_server_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: None,
+            retry_timeouts: false,
+            retry_server_errors: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // is_retryable is whether a try failing with err is tried again
+    pub fn is_retryable(&self, err: &Error) -> bool {
+        match err {
+            Error::TryAgain => true,
+            Error::AllRetransmissionsFailed(_) => self.retry_timeouts,
+            Error::Protocol { code, .. } => self.retry_server_errors && (500..600).contains(code),
+            _ => false,
+        }
+    }
+}
+
+// RetryHook is called before each retry with the number of the try that
+// failed, counting from one, its error and the wait before the next try
+pub type RetryHook = Arc<dyn Fn(u16, &Error, Duration) + Send + Sync>;
+
+// RetryFuture is a try of a retried operation, borrowing its state
+pub(crate) type RetryFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+// retry calls f with state until it succeeds, fails with an error the policy
+// doesn't retry or has been called max_attempts times, returning its last
+// result. The state is passed in rather than captured so each try can borrow
+// it mutably.
+pub(crate) async fn retry<S, T, F>(policy: &RetryPolicy, state: &mut S, f: F) -> Result<T, Error>
+where
+    F: for<'a> FnMut(&'a mut S) -> RetryFuture<'a, T>,
+{
+    retry_notify(policy, state, f, None).await
+}
+
+// retry_notify is retry, calling on_retry before each retry
+pub(crate) async fn retry_notify<S, T, F>(
+    policy: &RetryPolicy,
+    state: &mut S,
+    mut f: F,
+    on_retry: Option<&RetryHook>,
+) -> Result<T, Error>
+where
+    F: for<'a> FnMut(&'a mut S) -> RetryFuture<'a, T>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = f(state).await;
+        match &result {
+            Err(err) if attempt < policy.max_attempts && policy.is_retryable(err) => {
+                let delay = policy.backoff.map_or(Duration::from_secs(0), |backoff| {
+                    backoff.delay(u32::from(attempt - 1))
+                });
+                if let Some(on_retry) = on_retry {
+                    on_retry(attempt, err, delay);
+                }
+                if policy.backoff.is_some() {
+                    tokio::time::sleep(delay).await;
+                }
+                attempt += 1;
+            }
+            _ => return result,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use super::*;
+
+use tokio::time::Instant;
+
+// failing_tries records the time of each try and fails it with err
+async fn failing_tries(
+    policy: &RetryPolicy,
+    err: fn() -> Error,
+) -> (Result<(), Error>, Vec<Instant>) {
+    let mut tries = vec![];
+    let result = retry(policy, &mut tries, |tries| {
+        Box::pin(async move {
+            tries.push(Instant::now());
+            Err(err())
+        })
+    })
+    .await;
+    (result, tries)
+}
+
+#[tokio::test]
+async fn test_retry_default_policy() -> Result<(), Error> {
+    let policy = RetryPolicy::default();
+
+    let (result, tries) = failing_tries(&policy, || Error::TryAgain).await;
+    assert!(matches!(result, Err(Error::TryAgain)));
+    assert_eq!(DEFAULT_MAX_ATTEMPTS as usize, tries.len());
+
+    let (result, tries) =
+        failing_tries(&policy, || Error::AllRetransmissionsFailed("tr".to_owned())).await;
+    assert!(matches!(result, Err(Error::AllRetransmissionsFailed(_))));
+    assert_eq!(1, tries.len());
+
+    // a success ends the retries
+    let mut tries = 0;
+    retry(&policy, &mut tries, |tries| {
+        Box::pin(async move {
+            *tries += 1;
+            if *tries < 2 {
+                Err(Error::TryAgain)
+            } else {
+                Ok(())
+            }
+        })
+    })
+    .await?;
+    assert_eq!(2, tries);
+
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_retry_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 4,
+        backoff: Some(Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(250),
+            jitter: 0.0,
+            full_jitter: false,
+        }),
+        retry_timeouts: true,
+        retry_server_errors: false,
+    };
+
+    let (result, tries) =
+        failing_tries(&policy, || Error::AllRetransmissionsFailed("tr".to_owned())).await;
+    assert!(matches!(result, Err(Error::AllRetransmissionsFailed(_))));
+    let waits: Vec<Duration> = tries.windows(2).map(|w| w[1] - w[0]).collect();
+    assert_eq!(
+        vec![
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+            Duration::from_millis(250)
+        ],
+        waits
+    );
+
+    // other errors fail at once, without waiting
+    let start = Instant::now();
+    let (result, tries) = failing_tries(&policy, || Error::NoPermission).await;
+    assert!(matches!(result, Err(Error::NoPermission)));
+    assert_eq!(1, tries.len());
+    assert_eq!(start, Instant::now());
+}
+
+#[test]
+fn test_backoff_jitter() {
+    let backoff = Backoff {
+        initial: Duration::from_millis(100),
+        max: Duration::from_secs(10),
+        jitter: 0.5,
+        full_jitter: false,
+    };
+    for retry in 0..8 {
+        let delay = backoff.delay(retry);
+        let nominal = Duration::from_millis(100 * 2u64.pow(retry)).min(backoff.max);
+        assert!(
+            delay >= nominal / 2 && delay <= nominal * 3 / 2,
+            "{:?} for {:?}",
+            delay,
+            nominal
+        );
+    }
+
+    // the doubling saturates at max
+    assert_eq!(
+        Duration::from_secs(10),
+        Backoff {
+            jitter: f64::NAN,
+            ..backoff
+        }
+        .delay(40)
+    );
+}
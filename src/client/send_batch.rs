@@ -0,0 +1,170 @@
+use crate::error::Error;
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tokio::time::Duration;
+
+// DEFAULT_SEND_BATCH_DELAY is how long ChannelData waits for more to be sent
+// with it
+pub const DEFAULT_SEND_BATCH_DELAY: Duration = Duration::from_millis(1);
+
+// DEFAULT_SEND_BATCH_SIZE is how many ChannelData are written at once at most
+pub const DEFAULT_SEND_BATCH_SIZE: usize = 32;
+
+// QUEUED_BATCHES is how many batches may wait to be written, send_to waits
+// beyond that
+const QUEUED_BATCHES: usize = 4;
+
+// BatchSend writes several datagrams to one address with as few syscalls as
+// it can, e.g. BatchConn with sendmmsg. It returns the number written.
+#[async_trait]
+pub trait BatchSend {
+    async fn send_batch(&self, packets: &[Vec<u8>], to: SocketAddr) -> io::Result<usize>;
+}
+
+// SendBatching coalesces the ChannelData a RelayConn sends. send_to queues the
+// frame and returns, the queue is written when it has max_packets frames or
+// max_delay after the first one, whichever comes first. Errors of those
+// writes are logged, not returned.
+//
+// It is off by default, each send is then written before send_to returns.
+#[derive(Clone)]
+pub struct SendBatching {
+    pub max_delay: Duration,
+    pub max_packets: usize,
+    // batch_conn writes the batches of a Client, it should send from the
+    // client's conn, e.g. the same BatchConn. Without it the frames are
+    // written one at a time. The client's on_send_raw hook doesn't see what
+    // batch_conn sends. A RelayConnObserver other than Client overrides
+    // write_batch instead.
+    pub batch_conn: Option<Arc<dyn BatchSend + Send + Sync>>,
+}
+
+impl Default for SendBatching {
+    fn default() -> Self {
+        SendBatching {
+            max_delay: DEFAULT_SEND_BATCH_DELAY,
+            max_packets: DEFAULT_SEND_BATCH_SIZE,
+            batch_conn: None,
+        }
+    }
+}
+
+impl fmt::Debug for SendBatching {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendBatching")
+            .field("max_delay", &self.max_delay)
+            .field("max_packets", &self.max_packets)
+            .field("batch_conn", &self.batch_conn.is_some())
+            .finish()
+    }
+}
+
+// SendBatcher is the queue of a RelayConn with SendBatching, it is filled by
+// RelayConnInternal and emptied by the RelayConn's flush task
+#[derive(Debug)]
+pub(crate) struct SendBatcher {
+    max_delay: Duration,
+    max_packets: usize,
+    queue: Mutex<Vec<Vec<u8>>>,
+    // pending is notified when the queue stops being empty, full when it
+    // reaches max_packets and drained when a batch is taken from it
+    pending: Notify,
+    full: Notify,
+    drained: Notify,
+    closed: AtomicBool,
+}
+
+impl SendBatcher {
+    pub(crate) fn new(batching: &SendBatching) -> Self {
+        SendBatcher {
+            max_delay: batching.max_delay,
+            max_packets: batching.max_packets.max(1),
+            queue: Mutex::new(vec![]),
+            pending: Notify::new(),
+            full: Notify::new(),
+            drained: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.queue.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // push queues a frame and returns its length, it waits while
+    // QUEUED_BATCHES are queued
+    pub(crate) async fn push(&self, frame: Vec<u8>) -> Result<usize, Error> {
+        let n = frame.len();
+        let len = loop {
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(Error::AlreadyClosed);
+            }
+
+            {
+                let mut queue = self.queue();
+                if queue.len() < self.max_packets * QUEUED_BATCHES {
+                    queue.push(frame);
+                    break queue.len();
+                }
+            }
+            drained.await;
+        };
+        if len == 1 {
+            self.pending.notify_one();
+        }
+        if len >= self.max_packets {
+            self.full.notify_waiters();
+        }
+        Ok(n)
+    }
+
+    // next_batch waits for a batch to write, it is None once closed and empty
+    pub(crate) async fn next_batch(&self) -> Option<Vec<Vec<u8>>> {
+        loop {
+            if self.queue().is_empty() {
+                if self.closed.load(Ordering::SeqCst) {
+                    return None;
+                }
+                self.pending.notified().await;
+                continue;
+            }
+
+            // full is waited on from before the queue is checked, so a batch
+            // filled meanwhile isn't missed
+            let full = self.full.notified();
+            tokio::pin!(full);
+            full.as_mut().enable();
+            if !self.closed.load(Ordering::SeqCst) && self.queue().len() < self.max_packets {
+                let _ = tokio::time::timeout(self.max_delay, full).await;
+            }
+
+            let batch = {
+                let mut queue = self.queue();
+                let n = queue.len().min(self.max_packets);
+                queue.drain(..n).collect::<Vec<_>>()
+            };
+            self.drained.notify_waiters();
+            if !batch.is_empty() {
+                return Some(batch);
+            }
+        }
+    }
+
+    // close lets the flush task write what is queued and end
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pending.notify_one();
+        self.full.notify_waiters();
+        self.drained.notify_waiters();
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+// RelayConnCounters are the counters of a RelayConn, each counting from its
+// creation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RelayConnCounters {
+    // packets_sent and bytes_sent count the payloads send_to sent
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    // packets_received and bytes_received count the data from peers, before
+    // it is dropped for a full read queue
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub inbound_dropped: u64,
+    pub inbound_malformed: u64,
+    pub inbound_unsolicited: u64,
+    pub inbound_duplicates: u64,
+    pub oversized_sends: u64,
+    pub send_backpressure: u64,
+}
+
+impl RelayConnCounters {
+    // since is the change of each counter from an earlier sample
+    pub fn since(&self, earlier: &RelayConnCounters) -> RelayConnCounters {
+        RelayConnCounters {
+            packets_sent: self.packets_sent.saturating_sub(earlier.packets_sent),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            packets_received: self
+                .packets_received
+                .saturating_sub(earlier.packets_received),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            inbound_dropped: self.inbound_dropped.saturating_sub(earlier.inbound_dropped),
+            inbound_malformed: self
+                .inbound_malformed
+                .saturating_sub(earlier.inbound_malformed),
+            inbound_unsolicited: self
+                .inbound_unsolicited
+                .saturating_sub(earlier.inbound_unsolicited),
+            inbound_duplicates: self
+                .inbound_duplicates
+                .saturating_sub(earlier.inbound_duplicates),
+            oversized_sends: self.oversized_sends.saturating_sub(earlier.oversized_sends),
+            send_backpressure: self
+                .send_backpressure
+                .saturating_sub(earlier.send_backpressure),
+        }
+    }
+}
+
+// RelayConnStats is a sample of RelayConn::start_stats_reporter, delta being
+// the change of the counters over elapsed, since the previous sample or the
+// start of the reporter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayConnStats {
+    pub counters: RelayConnCounters,
+    pub delta: RelayConnCounters,
+    pub elapsed: Duration,
+    pub path_rtt: Option<Duration>,
+}
+
+pub type StatsCallback = Arc<dyn Fn(RelayConnStats) + Send + Sync>;
+
+// ReporterHandle stops its stats reporter when dropped
+#[derive(Debug)]
+pub struct ReporterHandle {
+    _stop_tx: mpsc::Sender<()>,
+}
+
+impl ReporterHandle {
+    pub(crate) fn new() -> (Self, mpsc::Receiver<()>) {
+        let (stop_tx, stop_rx) = mpsc::channel(1);
+        (ReporterHandle { _stop_tx: stop_tx }, stop_rx)
+    }
+}
+
+// Traffic counts the packets a RelayConn sent and received. It is shared by
+// the RelayConn and its InboundQueue.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Traffic {
+    pub(crate) fn record_sent(&self, n: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, n: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // fill sets the traffic counters of counters
+    pub(crate) fn fill(&self, counters: &mut RelayConnCounters) {
+        counters.packets_sent = self.packets_sent.load(Ordering::Relaxed);
+        counters.bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        counters.packets_received = self.packets_received.load(Ordering::Relaxed);
+        counters.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+    }
+}
+
+// ReportGate is closed by RelayConn::close. The reporters call back under its
+// lock, so close can't return while a callback runs, nor one start after.
+#[derive(Debug)]
+pub(crate) struct ReportGate {
+    open: Mutex<bool>,
+}
+
+impl Default for ReportGate {
+    fn default() -> Self {
+        ReportGate {
+            open: Mutex::new(true),
+        }
+    }
+}
+
+impl ReportGate {
+    pub(crate) fn close(&self) {
+        *self.open.lock().unwrap_or_else(|err| err.into_inner()) = false;
+    }
+
+    // report calls cb with stats unless the gate is closed, which it returns
+    pub(crate) fn report(&self, cb: &StatsCallback, stats: RelayConnStats) -> bool {
+        let open = self.open.lock().unwrap_or_else(|err| err.into_inner());
+        if *open {
+            cb(stats);
+        }
+        *open
+    }
+}
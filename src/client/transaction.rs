@@ -1,26 +1,70 @@
-use crate::errors::*;
+use crate::error::Error;
+use crate::trace::{Instrument, Span};
 
 use stun::message::*;
 
 use tokio::sync::{mpsc, Mutex};
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use std::sync::atomic::{AtomicU16, Ordering};
-use util::{Conn, Error};
+use util::Conn;
 
 const MAX_RTX_INTERVAL_IN_MS: u16 = 1600;
 const MAX_RTX_COUNT: u16 = 7; // total 7 requests (Rc)
 
+// MAX_TRANSACTION_ID_ATTEMPTS is how many transaction ids a request is given
+// before its transaction fails with Error::TransactionIdCollision
+pub(crate) const MAX_TRANSACTION_ID_ATTEMPTS: usize = 3;
+
+// TransactionOptions override the retransmissions of a single transaction,
+// see RelayConnObserver::perform_transaction_with. The default keeps the
+// observer's own policy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionOptions {
+    // deadline fails the transaction with Error::AllRetransmissionsFailed this
+    // long after the request was sent, it is retransmitted until then instead
+    // of a fixed number of times
+    pub deadline: Option<Duration>,
+    // rto is the first retransmission timeout, doubled on every retransmission
+    // up to 1.6s
+    pub rto: Option<Duration>,
+}
+
+// AlternateSourceHook is called with the address a request was sent to and
+// the source of a response to it, when they differ. The response is accepted
+// if it returns true, e.g. from the address of an ALTERNATE-SERVER the
+// request was redirected to. It must return quickly.
+pub type AlternateSourceHook = Arc<dyn Fn(SocketAddr, SocketAddr) -> bool + Send + Sync>;
+
+// fail_transaction ends a transaction that got no response
+async fn fail_transaction(tm: &mut TransactionMap, tr_key: &str) {
+    if let Some(tr) = tm.delete(tr_key) {
+        if !tr
+            .write_result(TransactionResult {
+                err: Some(Error::AllRetransmissionsFailed(tr_key.to_owned())),
+                ..Default::default()
+            })
+            .await
+        {
+            log::debug!("no listener for transaction");
+        }
+    }
+}
+
 async fn on_rtx_timeout(
     conn: &Arc<dyn Conn + Send + Sync>,
     tr_map: &Arc<Mutex<TransactionMap>>,
     tr_key: &str,
     n_rtx: u16,
+    max_rtx: u16,
 ) -> bool {
     let mut tm = tr_map.lock().await;
     let (tr_raw, tr_to) = match tm.find(tr_key) {
@@ -28,22 +72,9 @@ async fn on_rtx_timeout(
         None => return true, // already gone
     };
 
-    if n_rtx == MAX_RTX_COUNT {
+    if n_rtx == max_rtx {
         // all retransmisstions failed
-        if let Some(tr) = tm.delete(tr_key) {
-            if !tr
-                .write_result(TransactionResult {
-                    err: Some(Error::new(format!(
-                        "{} {}",
-                        *ERR_ALL_RETRANSMISSIONS_FAILED, tr_key
-                    ))),
-                    ..Default::default()
-                })
-                .await
-            {
-                log::debug!("no listener for transaction");
-            }
-        }
+        fail_transaction(&mut tm, tr_key).await;
         return true;
     }
 
@@ -60,20 +91,7 @@ async fn on_rtx_timeout(
     };
 
     if conn.send_to(&tr_raw, dst).await.is_err() {
-        if let Some(tr) = tm.delete(tr_key) {
-            if !tr
-                .write_result(TransactionResult {
-                    err: Some(Error::new(format!(
-                        "{} {}",
-                        *ERR_ALL_RETRANSMISSIONS_FAILED, tr_key
-                    ))),
-                    ..Default::default()
-                })
-                .await
-            {
-                log::debug!("no listener for transaction");
-            }
-        }
+        fail_transaction(&mut tm, tr_key).await;
         return true;
     }
 
@@ -81,7 +99,7 @@ async fn on_rtx_timeout(
 }
 
 // TransactionResult is a bag of result values of a transaction
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct TransactionResult {
     pub msg: Message,
     pub from: SocketAddr,
@@ -108,6 +126,8 @@ pub struct TransactionConfig {
     pub to: String,
     pub interval: u16,
     pub ignore_result: bool, // true to throw away the result of this transaction (it will not be readable using wait_for_result)
+    // deadline replaces the MAX_RTX_COUNT requests, see TransactionOptions
+    pub deadline: Option<Duration>,
 }
 
 // Transaction represents a transaction
@@ -118,6 +138,9 @@ pub struct Transaction {
     pub to: String,
     pub n_rtx: Arc<AtomicU16>,
     pub interval: Arc<AtomicU16>,
+    // method is the request's, a response of another method isn't its result
+    method: Method,
+    deadline: Option<Duration>,
     timer_ch_tx: Option<mpsc::Sender<()>>,
     result_ch_tx: Option<mpsc::Sender<TransactionResult>>,
     result_ch_rx: Option<mpsc::Receiver<TransactionResult>>,
@@ -131,6 +154,8 @@ impl Default for Transaction {
             to: String::new(),
             n_rtx: Arc::new(AtomicU16::new(0)),
             interval: Arc::new(AtomicU16::new(0)),
+            method: Method::default(),
+            deadline: None,
             //timer: None,
             timer_ch_tx: None,
             result_ch_tx: None,
@@ -149,11 +174,18 @@ impl Transaction {
             (None, None)
         };
 
+        let mut typ = MessageType::default();
+        if let [b0, b1, ..] = config.raw[..] {
+            typ.read_value(u16::from_be_bytes([b0, b1]));
+        }
+
         Transaction {
+            method: typ.method,
             key: config.key,
             raw: config.raw,
             to: config.to,
             interval: Arc::new(AtomicU16::new(config.interval)),
+            deadline: config.deadline,
             result_ch_tx,
             result_ch_rx,
             ..Default::default()
@@ -169,32 +201,52 @@ impl Transaction {
         let (timer_ch_tx, mut timer_ch_rx) = mpsc::channel(1);
         self.timer_ch_tx = Some(timer_ch_tx);
         let (n_rtx, interval, key) = (self.n_rtx.clone(), self.interval.clone(), self.key.clone());
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        let max_rtx = if deadline.is_some() {
+            u16::MAX
+        } else {
+            MAX_RTX_COUNT
+        };
 
-        tokio::spawn(async move {
-            let mut done = false;
-            while !done {
-                let timer = tokio::time::sleep(Duration::from_millis(
-                    interval.load(Ordering::SeqCst) as u64,
-                ));
-                tokio::pin!(timer);
-
-                tokio::select! {
-                    _ = timer.as_mut() => {
-                        let rtx = n_rtx.fetch_add(1, Ordering::SeqCst);
-
-                        let mut val = interval.load(Ordering::SeqCst);
-                        val *= 2;
-                        if val > MAX_RTX_INTERVAL_IN_MS {
-                            val = MAX_RTX_INTERVAL_IN_MS;
+        tokio::spawn(
+            async move {
+                let mut done = false;
+                while !done {
+                    let timer = tokio::time::sleep(Duration::from_millis(
+                        interval.load(Ordering::SeqCst) as u64,
+                    ));
+                    tokio::pin!(timer);
+                    let expired = async {
+                        match deadline {
+                            Some(deadline) => tokio::time::sleep_until(deadline).await,
+                            None => std::future::pending().await,
                         }
-                        interval.store(val, Ordering::SeqCst);
+                    };
+
+                    tokio::select! {
+                        _ = timer.as_mut() => {
+                            let rtx = n_rtx.fetch_add(1, Ordering::SeqCst);
 
-                        done = on_rtx_timeout(&conn, &tr_map, &key, rtx + 1).await;
+                            let mut val = interval.load(Ordering::SeqCst);
+                            val *= 2;
+                            if val > MAX_RTX_INTERVAL_IN_MS {
+                                val = MAX_RTX_INTERVAL_IN_MS;
+                            }
+                            interval.store(val, Ordering::SeqCst);
+
+                            done = on_rtx_timeout(&conn, &tr_map, &key, rtx + 1, max_rtx).await;
+                        }
+                        _ = expired => {
+                            log::trace!("transaction {} passed its deadline", key);
+                            fail_transaction(&mut *tr_map.lock().await, &key).await;
+                            done = true;
+                        }
+                        _ = timer_ch_rx.recv() => done = true,
                     }
-                    _ = timer_ch_rx.recv() => done = true,
                 }
             }
-        });
+            .instrument(Span::current()),
+        );
     }
 
     // stop_rtx_timer stop the transaction timer
@@ -225,11 +277,60 @@ impl Transaction {
     }
 
     // retries returns the number of retransmission it has made
+    // method is the method of the request, that of its response
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
     pub fn retries(&self) -> u16 {
         self.n_rtx.load(Ordering::SeqCst)
     }
 }
 
+// PendingTransaction is the response of a transaction that has been sent, see
+// RelayConnObserver::begin_transaction
+pub type PendingTransaction =
+    Pin<Box<dyn Future<Output = Result<TransactionResult, Error>> + Send>>;
+
+// TransactionGuard deletes a transaction from the map when the future waiting
+// for its result is dropped, which also stops its retransmissions. It is
+// disarmed once the result is in.
+pub(crate) struct TransactionGuard {
+    tr_map: Arc<Mutex<TransactionMap>>,
+    key: Option<String>,
+}
+
+impl TransactionGuard {
+    pub(crate) fn new(tr_map: Arc<Mutex<TransactionMap>>, key: String) -> Self {
+        TransactionGuard {
+            tr_map,
+            key: Some(key),
+        }
+    }
+
+    pub(crate) fn disarm(&mut self) {
+        self.key.take();
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        log::trace!("abandoning transaction {}", key);
+        if let Ok(mut tm) = self.tr_map.try_lock() {
+            tm.delete(&key);
+        } else if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let tr_map = Arc::clone(&self.tr_map);
+            handle.spawn(async move {
+                tr_map.lock().await.delete(&key);
+            });
+        }
+    }
+}
+
 // TransactionMap is a thread-safe transaction map
 #[derive(Default, Debug)]
 pub struct TransactionMap {
@@ -244,10 +345,16 @@ impl TransactionMap {
         }
     }
 
-    // Insert inserts a trasaction to the map
+    // Insert inserts a trasaction to the map, a transaction already under
+    // key is kept and false returned
     pub fn insert(&mut self, key: String, tr: Transaction) -> bool {
-        self.tr_map.insert(key, tr);
-        true
+        match self.tr_map.entry(key) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(tr);
+                true
+            }
+        }
     }
 
     // Find looks up a transaction by its key
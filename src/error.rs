@@ -0,0 +1,277 @@
+use crate::proto::errorcodes::*;
+use crate::proto::Protocol;
+use stun::attributes::{AttrType, ATTR_ERROR_CODE};
+use stun::message::{Message, MessageType};
+
+use std::io;
+use std::net::{self, IpAddr};
+use std::time::{Duration, SystemTimeError};
+
+use thiserror::Error;
+
+// Error is the error type returned by the TURN client and server.
+//
+// proto attributes still go through the stun Setter/Getter traits and
+// produce util::Error, those are wrapped by the Util variant.
+#[derive(Debug, Error)]
+pub enum Error {
+    // Protocol errors
+
+    // Protocol is returned when the server answered with an error response
+    // carrying an ERROR-CODE attribute.
+    #[error("{typ} (error {code}: {reason})")]
+    Protocol {
+        typ: MessageType,
+        code: u16,
+        reason: String,
+    },
+    #[error("unexpected response type {0}")]
+    UnexpectedResponse(MessageType),
+    #[error("turn: {0} without MESSAGE-INTEGRITY")]
+    UnprotectedResponse(MessageType),
+    #[error("try again")]
+    TryAgain,
+    #[error("non-STUN message from STUN server")]
+    NonStunMessage,
+    #[error("unexpected STUN request message: {0}")]
+    UnexpectedStunRequestMessage(String),
+    #[error("unexpected class")]
+    UnexpectedClass,
+    #[error("no binding found for channel")]
+    ChannelBindNotFound,
+    #[error("relay already allocated for 5-TUPLE")]
+    RelayAlreadyAllocatedForFiveTuple,
+    #[error("RequestedTransport must be UDP")]
+    RequestedTransportMustBeUdp,
+    #[error("no support for DONT-FRAGMENT")]
+    NoDontFragmentSupport,
+    #[error("Request must not contain RESERVATION-TOKEN and EVEN-PORT")]
+    RequestWithReservationTokenAndEvenPort,
+    #[error("you cannot use the same channel number with different peer")]
+    SameChannelDifferentPeer,
+    #[error("channel {0} expired recently and may only be bound to the same peer")]
+    ChannelQuarantined(u16),
+    #[error("duplicate {0} attribute")]
+    DuplicateAttribute(AttrType),
+    #[error("{0} attribute after {1}")]
+    AttributeAfter(AttrType, AttrType),
+    #[error("{0}")]
+    Binding(#[from] BindingError),
+
+    // Transport errors
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("all retransmissions failed for {0}")]
+    AllRetransmissionsFailed(String),
+    #[error("packet write smaller than packet")]
+    ShortWrite,
+    // the transport to the TURN server is backed up, the write may be retried
+    #[error("turn: write would block")]
+    WouldBlock,
+    #[error("turn: {0} is already being probed")]
+    ProbeInProgress(net::SocketAddr),
+    #[error("turn: no reply from {0} to the probe")]
+    ProbeTimedOut(net::SocketAddr),
+    // 443 (Peer Address Family Mismatch), the server doesn't relay between
+    // the relayed address and peer, a candidate of peer of the relayed
+    // address's family should be tried instead
+    #[error("turn: peer {peer} is not of the relayed address's family")]
+    PeerAddressFamilyMismatch { peer: net::SocketAddr },
+    // 442 (Unsupported Transport Protocol), the server doesn't allocate
+    // relayed addresses of this transport
+    #[error("turn: the server doesn't relay {0}")]
+    UnsupportedTransportProtocol(Protocol),
+    #[error("too short buffer")]
+    ShortBuffer,
+    #[error("{0}")]
+    AddrParse(#[from] net::AddrParseError),
+
+    // Auth errors
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("duplicated Nonce generated, discarding request")]
+    DuplicatedNonce,
+
+    // State errors
+    #[error("use of closed network connection")]
+    Closed,
+    #[error("already closed")]
+    AlreadyClosed,
+    #[error("transaction closed")]
+    TransactionClosed,
+    #[error("wait_for_result called on non-result transaction")]
+    WaitForResultOnNonResultTransaction,
+    // Server::shutdown aborted the tasks still running after its timeout
+    #[error("turn: shutdown timed out after {0:?}")]
+    ShutdownTimedOut(Duration),
+    #[error("turn: no unused transaction id was found for the request")]
+    TransactionIdCollision,
+    #[error("only one Allocate() caller is allowed")]
+    OneAllocateOnly,
+    #[error("the relay conn observer can't reallocate")]
+    ReallocateUnsupported,
+    #[error("STUN server address is not set for the client")]
+    StunServerAddressNotSet,
+    #[error("allocation quota reached")]
+    QuotaReached,
+    #[error("no allocation found")]
+    NoAllocationFound,
+    #[error("unable to handle send-indication, no permission added")]
+    NoPermission,
+    #[error("payload of {size} bytes is over max_payload_size of {max}")]
+    PayloadTooLarge { size: usize, max: usize },
+    #[error("allocation expires in {remaining:?} and its refreshes are failing")]
+    AllocationExpiring { remaining: Duration },
+    #[error("no such channel bind")]
+    NoSuchChannelBind,
+    #[error("allocations must not be created with a lifetime of 0")]
+    LifetimeZero,
+    #[error("allocation attempt created with duplicate FiveTuple")]
+    DupeFiveTuple,
+
+    // Configuration errors
+    #[error("turn: PacketConnConfigs and ConnConfigs are empty, unable to proceed")]
+    NoAvailableConns,
+    #[error("turn: RelayAddressGenerator has invalid ListeningAddress")]
+    ListeningAddressInvalid,
+    #[error("turn: relay address {0} is not a unicast address")]
+    RelayAddressInvalid(IpAddr),
+    #[error("turn: failed to bind relay address {address}: {err}")]
+    RelayBind { address: String, err: io::Error },
+    #[error("turn: listener {listener}: {err}")]
+    ListenerInvalid { listener: String, err: Box<Error> },
+    #[error("turn: {listeners} listeners are configured but {conns} conns were given")]
+    ListenerConnsMismatch { listeners: usize, conns: usize },
+    #[error("turn: relay is unset")]
+    RelayUnset,
+    #[error("turn: no TURN listener to self-test")]
+    NoTurnListener,
+    #[error("turn: MinPort must be not 0")]
+    MinPortNotZero,
+    #[error("turn: MaxPort must be not 0")]
+    MaxPortNotZero,
+    #[error("turn: MaxPort less than MinPort")]
+    MaxPortLessThanMinPort,
+    #[error("turn: max retries exceeded")]
+    MaxRetriesExceeded,
+    #[error("turn: relay pool has no sockets")]
+    RelayPoolEmpty,
+    #[error("turn: all sockets of the relay pool are in use")]
+    RelayPoolExhausted,
+    #[error("turn: bind_device is only supported on Linux")]
+    BindDeviceUnsupported,
+    #[error("turn: failed to bind relay to device {device}: {err}")]
+    BindDevice { device: String, err: io::Error },
+    #[error("turn: AuthHandler is unset")]
+    AuthHandlerUnset,
+    #[error("turn: invalid realm: {0}")]
+    RealmInvalid(String),
+    // a ClientConfig credential that doesn't fit its STUN attribute
+    #[error("turn: {field} {reason}")]
+    CredentialInvalid { field: &'static str, reason: String },
+    #[error("turn: channel_bind_timeout of {0:?} is out of range")]
+    ChannelBindTimeoutInvalid(Duration),
+    #[error("turn: lifetime_jitter of {0} is out of range")]
+    LifetimeJitterInvalid(f64),
+    #[error("turn: max_allocations must be not 0")]
+    MaxAllocationsZero,
+    #[error("turn: max_allocations reached")]
+    MaxAllocationsReached,
+    #[error("turn: max_username_len of {0} is over the STUN maximum")]
+    MaxUsernameLenInvalid(usize),
+    #[error("turn: load factor weight of {0} is negative or not finite")]
+    LoadFactorWeightInvalid(f64),
+    #[error("turn: bandwidth_budget must be not 0")]
+    BandwidthBudgetZero,
+
+    #[error("{0}")]
+    SystemTime(#[from] SystemTimeError),
+    #[error("{0}")]
+    Util(#[from] util::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+// BindingError is why the client could not create a channel binding
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum BindingError {
+    // every channel number, 0x4000 through 0x7FFF, is bound to a peer
+    #[error("turn: all channel numbers are in use")]
+    Exhausted,
+    // the RelayConn has max_bindings peers bound
+    #[error("turn: max_bindings of {0} reached")]
+    LimitReached(usize),
+    #[error("turn: binding for {0} already exists")]
+    AlreadyExists(net::SocketAddr),
+}
+
+impl Error {
+    // from_error_response converts an error response into a Protocol error,
+    // keeping the ERROR-CODE number and reason phrase.
+    pub(crate) fn from_error_response(res: &Message) -> Self {
+        // ERROR-CODE: 2 bytes reserved, class, number, reason phrase.
+        match res.get(ATTR_ERROR_CODE) {
+            Ok(v) if v.len() >= 4 => Error::Protocol {
+                typ: res.typ,
+                code: (v[2] & 0x07) as u16 * 100 + v[3] as u16,
+                reason: String::from_utf8_lossy(&v[4..]).into_owned(),
+            },
+            _ => Error::UnexpectedResponse(res.typ),
+        }
+    }
+
+    // io_kind picks the io::ErrorKind reported through the Conn interface,
+    // so callers can tell timeouts, closed connections and denials apart.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(err) => err.kind(),
+            Error::Closed | Error::AlreadyClosed | Error::TransactionClosed => {
+                io::ErrorKind::ConnectionAborted
+            }
+            Error::StunServerAddressNotSet | Error::AllocationExpiring { .. } => {
+                io::ErrorKind::NotConnected
+            }
+            Error::AllRetransmissionsFailed(_)
+            | Error::ProbeTimedOut(_)
+            | Error::ShutdownTimedOut(_) => io::ErrorKind::TimedOut,
+            Error::ShortBuffer
+            | Error::PayloadTooLarge { .. }
+            | Error::CredentialInvalid { .. } => io::ErrorKind::InvalidInput,
+            Error::ShortWrite => io::ErrorKind::WriteZero,
+            Error::WouldBlock => io::ErrorKind::WouldBlock,
+            Error::BindDeviceUnsupported | Error::UnsupportedTransportProtocol(_) => {
+                io::ErrorKind::Unsupported
+            }
+            Error::BindDevice { err, .. } | Error::RelayBind { err, .. } => err.kind(),
+            Error::Binding(BindingError::AlreadyExists(_)) => io::ErrorKind::AlreadyExists,
+            Error::QuotaReached
+            | Error::NoPermission
+            | Error::Auth(_)
+            | Error::PeerAddressFamilyMismatch { .. } => io::ErrorKind::PermissionDenied,
+            Error::Protocol {
+                code: UNAUTHORIZED | FORBIDDEN | WRONG_CREDENTIALS | ALLOCATION_QUOTA_REACHED,
+                ..
+            } => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+// Keep existing callers that work with util::Error compiling.
+impl From<Error> for util::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Util(err) => err,
+            err => util::Error::new(err.to_string()),
+        }
+    }
+}
@@ -5,10 +5,26 @@
 #[macro_use]
 extern crate lazy_static;
 
+#[macro_use]
+mod trace;
+#[macro_use]
+mod log_limit;
+
+#[cfg(feature = "server")]
 pub mod allocation;
 pub mod auth;
+#[cfg(feature = "server")]
+pub mod batch;
+#[cfg(feature = "client")]
 pub mod client;
-pub mod errors;
+pub mod error;
+pub mod prelude;
 pub mod proto;
+#[cfg(feature = "server")]
 pub mod relay;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(all(feature = "server", any(test, feature = "test-util")))]
+pub mod test_util;
+
+pub use error::Error;
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// LOG_INTERVAL_MS is the least time between two records of a call site
+pub(crate) const LOG_INTERVAL_MS: u64 = 1000;
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+// RateLimit is the token bucket of a log call site, holding a single token
+// refilled every LOG_INTERVAL_MS. It counts the records it suppresses so the
+// next one logged can tell how many were left out.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    // next_ms is when the token is refilled, in ms since START
+    next_ms: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::new()
+    }
+}
+
+impl RateLimit {
+    pub(crate) const fn new() -> Self {
+        RateLimit {
+            next_ms: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    // check takes the token, returning the number of records suppressed since
+    // the last one, or None if the record is to be suppressed
+    pub(crate) fn check(&self) -> Option<u64> {
+        let now = START.elapsed().as_millis() as u64;
+        let next = self.next_ms.load(Ordering::Relaxed);
+        if now < next
+            || self
+                .next_ms
+                .compare_exchange(
+                    next,
+                    now + LOG_INTERVAL_MS,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+// log_limited! logs like log::log! at most once per LOG_INTERVAL_MS for each
+// call site, for the statements on per-packet paths. The next record logged
+// after some were suppressed ends with their count. The format string must
+// be a literal with positional arguments.
+#[allow(unused_macros)]
+macro_rules! log_limited {
+    ($lvl:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        static LIMIT: $crate::log_limit::RateLimit = $crate::log_limit::RateLimit::new();
+        if log::log_enabled!($lvl) {
+            match LIMIT.check() {
+                Some(0) => log::log!($lvl, $fmt $(, $arg)*),
+                Some(suppressed) => log::log!(
+                    $lvl,
+                    concat!($fmt, " ({} similar suppressed)") $(, $arg)*,
+                    suppressed
+                ),
+                None => {}
+            }
+        }
+    }};
+}
+
+#[allow(unused_macros)]
+macro_rules! error_limited {
+    ($($arg:tt)+) => {
+        log_limited!(log::Level::Error, $($arg)+)
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! warn_limited {
+    ($($arg:tt)+) => {
+        log_limited!(log::Level::Warn, $($arg)+)
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! info_limited {
+    ($($arg:tt)+) => {
+        log_limited!(log::Level::Info, $($arg)+)
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! debug_limited {
+    ($($arg:tt)+) => {
+        log_limited!(log::Level::Debug, $($arg)+)
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! trace_limited {
+    ($($arg:tt)+) => {
+        log_limited!(log::Level::Trace, $($arg)+)
+    };
+}
+
+// declared after the macros, which are only in scope below them
+#[cfg(test)]
+mod log_limit_test;
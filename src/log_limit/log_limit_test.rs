@@ -0,0 +1,74 @@
+use super::*;
+
+use std::sync::Mutex;
+
+// CountingLogger keeps the records of this module, the only logger set in
+// the test binary
+struct CountingLogger {
+    records: Mutex<Vec<String>>,
+}
+
+impl log::Log for CountingLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.target() == module_path!()
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CountingLogger = CountingLogger {
+    records: Mutex::new(Vec::new()),
+};
+
+fn suppressed_count(record: &str) -> u64 {
+    record
+        .strip_suffix(" similar suppressed)")
+        .and_then(|s| s.rsplit('(').next())
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_rate_limit_check() {
+    let limit = RateLimit::new();
+    assert_eq!(Some(0), limit.check());
+    for _ in 0..10 {
+        assert_eq!(None, limit.check());
+    }
+}
+
+#[test]
+fn test_log_limited_hot_path() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let guarded = |i: usize| warn_limited!("packet {} dropped", i);
+    for i in 0..10_000 {
+        guarded(i);
+    }
+    let logged = LOGGER.records.lock().unwrap().len();
+    assert!(
+        (1..=3).contains(&logged),
+        "{} records for 10000 calls",
+        logged
+    );
+
+    std::thread::sleep(std::time::Duration::from_millis(LOG_INTERVAL_MS + 100));
+    guarded(10_000);
+
+    let records = LOGGER.records.lock().unwrap();
+    assert_eq!(logged + 1, records.len());
+    assert!(
+        records.last().unwrap().ends_with("similar suppressed)"),
+        "{:?}",
+        records.last()
+    );
+    let suppressed: u64 = records.iter().map(|r| suppressed_count(r)).sum();
+    assert_eq!(10_001, records.len() as u64 + suppressed);
+}
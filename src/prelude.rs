@@ -0,0 +1,38 @@
+// prelude re-exports the types needed to run a TURN client or server:
+//
+//     use webrtc_rs_turn::prelude::*;
+//
+// The crate's own types re-exported here are semver-stable, they only change
+// on a major version bump. Conn, Username, Realm and MessageIntegrity are
+// re-exported from the util and stun crates because they appear in public
+// signatures, they follow the versions of those crates pinned by this one,
+// so use them from here rather than from a separate dependency.
+
+pub use crate::auth::{
+    generate_auth_key, generate_long_term_credentials, AuthHandler, LongTermAuthHandler,
+};
+pub use crate::error::Error;
+
+#[cfg(feature = "client")]
+pub use crate::client::{
+    inspect::RawPacketHook, quirks::ServerQuirks, relay_conn::framed::RelayConnFramed,
+    relay_conn_stream::RelayConnStream, Client, ClientConfig,
+};
+
+#[cfg(feature = "server")]
+pub use crate::batch::BatchConn;
+#[cfg(feature = "server")]
+pub use crate::relay::{
+    relay_none::RelayAddressGeneratorNone, relay_pool::PreallocatedRelayPool,
+    relay_range::RelayAddressGeneratorRanges, relay_static::RelayAddressGeneratorStatic,
+    PortSelection, RelayAddressGenerator,
+};
+#[cfg(feature = "server")]
+pub use crate::server::{
+    config::{ConnConfig, ServerConfig, ServerConfigBuilder},
+    Server,
+};
+
+pub use stun::integrity::MessageIntegrity;
+pub use stun::textattrs::{Realm, Username};
+pub use util::Conn;
@@ -2,7 +2,7 @@
 mod chandata_test;
 
 use super::channum::*;
-use crate::errors::*;
+use super::errors::*;
 
 use util::Error;
 
@@ -18,7 +18,7 @@ fn nearest_padded_value_length(l: usize) -> usize {
 
 const CHANNEL_DATA_LENGTH_SIZE: usize = 2;
 const CHANNEL_DATA_NUMBER_SIZE: usize = CHANNEL_DATA_LENGTH_SIZE;
-const CHANNEL_DATA_HEADER_SIZE: usize = CHANNEL_DATA_LENGTH_SIZE + CHANNEL_DATA_NUMBER_SIZE;
+pub const CHANNEL_DATA_HEADER_SIZE: usize = CHANNEL_DATA_LENGTH_SIZE + CHANNEL_DATA_NUMBER_SIZE;
 
 // ChannelData represents The ChannelData Message.
 //
@@ -53,26 +53,54 @@ impl ChannelData {
     }
 
     // Encode encodes ChannelData Message to Raw.
-    pub fn encode(&mut self) {
+    pub fn encode(&mut self) -> Result<(), Error> {
         self.raw.clear();
-        self.write_header();
+        self.write_header()?;
         self.raw.extend_from_slice(&self.data);
         let padded = nearest_padded_value_length(self.raw.len());
         let bytes_to_add = padded - self.raw.len();
         if bytes_to_add > 0 {
             self.raw.extend_from_slice(&vec![0; bytes_to_add]);
         }
+        Ok(())
+    }
+
+    // encode_to encodes the ChannelData Message to buf like encode, leaving raw
+    // untouched. buf is overwritten, so one buffer can be reused per message.
+    pub fn encode_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        ChannelData::encode_data_to(self.number, &self.data, buf)
+    }
+
+    // encode_data_to encodes a ChannelData Message of data to buf without
+    // building a ChannelData
+    pub fn encode_data_to(
+        number: ChannelNumber,
+        data: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        buf.clear();
+        buf.resize(CHANNEL_DATA_HEADER_SIZE, 0);
+        buf.extend_from_slice(data);
+        ChannelData::frame(buf, number, data.len())
     }
 
     // Decode decodes The ChannelData Message from Raw.
     pub fn decode(&mut self) -> Result<(), Error> {
-        let buf = &self.raw;
+        let (number, l) = ChannelData::decode_header(&self.raw)?;
+        self.number = number;
+        self.data = self.raw[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + l].to_vec();
+
+        Ok(())
+    }
+
+    // decode_header returns the channel number and data length of the
+    // ChannelData Message in buf, the data is buf[CHANNEL_DATA_HEADER_SIZE..][..length]
+    pub fn decode_header(buf: &[u8]) -> Result<(ChannelNumber, usize), Error> {
         if buf.len() < CHANNEL_DATA_HEADER_SIZE {
             return Err(ERR_UNEXPECTED_EOF.to_owned());
         }
-        let num = u16::from_be_bytes([buf[0], buf[1]]);
-        self.number = ChannelNumber(num);
-        if !self.number.valid() {
+        let number = ChannelNumber(u16::from_be_bytes([buf[0], buf[1]]));
+        if !number.valid() {
             return Err(ERR_INVALID_CHANNEL_NUMBER.to_owned());
         }
         let l = u16::from_be_bytes([
@@ -82,13 +110,35 @@ impl ChannelData {
         if l > buf[CHANNEL_DATA_HEADER_SIZE..].len() {
             return Err(ERR_BAD_CHANNEL_DATA_LENGTH.to_owned());
         }
-        self.data = buf[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + l].to_vec();
 
+        Ok((number, l))
+    }
+
+    // frame turns the l bytes of data at raw[CHANNEL_DATA_HEADER_SIZE..] into a
+    // ChannelData Message in place, writing the header in front and padding
+    // like encode does, raw is resized to the padded message
+    pub fn frame(raw: &mut Vec<u8>, number: ChannelNumber, l: usize) -> Result<(), Error> {
+        if l > u16::MAX as usize {
+            return Err(ERR_CHANNEL_DATA_TOO_LARGE.to_owned());
+        }
+        if raw.len() < CHANNEL_DATA_HEADER_SIZE + l {
+            return Err(ERR_UNEXPECTED_EOF.to_owned());
+        }
+        raw.truncate(CHANNEL_DATA_HEADER_SIZE + l);
+        raw.resize(nearest_padded_value_length(CHANNEL_DATA_HEADER_SIZE + l), 0);
+        raw[..CHANNEL_DATA_NUMBER_SIZE].copy_from_slice(&number.0.to_be_bytes());
+        raw[CHANNEL_DATA_NUMBER_SIZE..CHANNEL_DATA_HEADER_SIZE]
+            .copy_from_slice(&(l as u16).to_be_bytes());
         Ok(())
     }
 
     // WriteHeader writes channel number and length.
-    pub fn write_header(&mut self) {
+    pub fn write_header(&mut self) -> Result<(), Error> {
+        // The length field is 16 bits wide, so larger payloads can't be
+        // represented and must not be silently truncated.
+        if self.data.len() > u16::MAX as usize {
+            return Err(ERR_CHANNEL_DATA_TOO_LARGE.to_owned());
+        }
         if self.raw.len() < CHANNEL_DATA_HEADER_SIZE {
             // Making WriteHeader call valid even when c.Raw
             // is nil or len(c.Raw) is less than needed for header.
@@ -97,6 +147,7 @@ impl ChannelData {
         self.raw[..CHANNEL_DATA_NUMBER_SIZE].copy_from_slice(&self.number.0.to_be_bytes());
         self.raw[CHANNEL_DATA_NUMBER_SIZE..CHANNEL_DATA_HEADER_SIZE]
             .copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        Ok(())
     }
 
     // is_channel_data returns true if buf looks like the ChannelData Message.
@@ -105,10 +156,12 @@ impl ChannelData {
             return false;
         }
 
+        // compare as usize, buf may be longer than u16::MAX
         if u16::from_be_bytes([
             buf[CHANNEL_DATA_NUMBER_SIZE],
             buf[CHANNEL_DATA_NUMBER_SIZE + 1],
-        ]) > buf[CHANNEL_DATA_HEADER_SIZE..].len() as u16
+        ]) as usize
+            > buf[CHANNEL_DATA_HEADER_SIZE..].len()
         {
             return false;
         }
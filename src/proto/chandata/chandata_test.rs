@@ -9,7 +9,7 @@ fn test_channel_data_encode() -> Result<(), Error> {
         number: ChannelNumber(MIN_CHANNEL_NUMBER + 1),
         ..Default::default()
     };
-    d.encode();
+    d.encode()?;
 
     let mut b = ChannelData::default();
     b.raw.extend_from_slice(&d.raw);
@@ -25,6 +25,91 @@ fn test_channel_data_encode() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn test_channel_data_encode_too_large() -> Result<(), Error> {
+    let mut d = ChannelData {
+        data: vec![0; u16::MAX as usize + 1],
+        number: ChannelNumber(MIN_CHANNEL_NUMBER),
+        ..Default::default()
+    };
+    if let Err(err) = d.encode() {
+        assert_eq!(err, ERR_CHANNEL_DATA_TOO_LARGE.to_owned());
+    } else {
+        panic!("expected error, but got ok");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_channel_data_frame_matches_encode() -> Result<(), Error> {
+    // every padding length, and the largest payload
+    for l in [0, 1, 2, 3, 4, 5, 1500, u16::MAX as usize].iter() {
+        let payload: Vec<u8> = (0..*l).map(|i| i as u8).collect();
+        let number = ChannelNumber(MIN_CHANNEL_NUMBER + 1);
+
+        let mut d = ChannelData {
+            data: payload.clone(),
+            number,
+            ..Default::default()
+        };
+        d.encode()?;
+
+        // a pooled buffer is larger than the payload and holds stale bytes
+        let mut raw = vec![0xff; CHANNEL_DATA_HEADER_SIZE + l + 7];
+        raw[CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + l].copy_from_slice(&payload);
+        ChannelData::frame(&mut raw, number, *l)?;
+        assert_eq!(raw, d.raw, "frame of {} bytes should match encode", l);
+
+        assert_eq!(ChannelData::decode_header(&raw)?, (number, *l));
+    }
+
+    let mut raw = vec![0; CHANNEL_DATA_HEADER_SIZE + 1];
+    assert_eq!(
+        ChannelData::frame(&mut raw, ChannelNumber(MIN_CHANNEL_NUMBER), 2),
+        Err(ERR_UNEXPECTED_EOF.to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_channel_data_encode_to_matches_encode() -> Result<(), Error> {
+    // the buffer is reused, it holds the previous, longer message
+    let mut buf = vec![0xff; 2000];
+    for l in [1500, 0, 1, 2, 3, 4, 5, u16::MAX as usize].iter() {
+        let mut d = ChannelData {
+            data: (0..*l).map(|i| i as u8).collect(),
+            number: ChannelNumber(MIN_CHANNEL_NUMBER + 1),
+            ..Default::default()
+        };
+        d.encode_to(&mut buf)?;
+        assert!(d.raw.is_empty(), "encode_to should leave raw untouched");
+
+        // padded to 4 bytes as stream transports require
+        assert_eq!(0, buf.len() % 4);
+        d.encode()?;
+        assert_eq!(d.raw, buf, "encode_to of {} bytes should match encode", l);
+
+        let mut b = ChannelData::default();
+        b.raw.extend_from_slice(&buf);
+        b.decode()?;
+        assert_eq!(b, d);
+    }
+
+    let d = ChannelData {
+        data: vec![0; u16::MAX as usize + 1],
+        number: ChannelNumber(MIN_CHANNEL_NUMBER),
+        ..Default::default()
+    };
+    assert_eq!(
+        d.encode_to(&mut buf),
+        Err(ERR_CHANNEL_DATA_TOO_LARGE.to_owned())
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_channel_data_equal() -> Result<(), Error> {
     let tests = vec![
@@ -127,7 +212,7 @@ fn test_channel_data_decode() -> Result<(), Error> {
                 name, want_err, err
             );
         } else {
-            assert!(false, "expected error, but got ok");
+            panic!("expected error, but got ok");
         }
     }
 
@@ -141,7 +226,7 @@ fn test_channel_data_reset() -> Result<(), Error> {
         number: ChannelNumber(MIN_CHANNEL_NUMBER + 1),
         ..Default::default()
     };
-    d.encode();
+    d.encode()?;
     let mut buf = vec![0; d.raw.len()];
     buf.copy_from_slice(&d.raw);
     d.reset();
@@ -156,6 +241,16 @@ fn test_is_channel_data() -> Result<(), Error> {
     let tests = vec![
         ("small", vec![1, 2, 3, 4], false),
         ("zeroes", vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], false),
+        (
+            "big",
+            {
+                // 65536 bytes after the header wrap around to 0 when truncated to u16
+                let mut b = vec![0x40, 0x00, 0x00, 0x04];
+                b.extend_from_slice(&vec![0; u16::MAX as usize + 1]);
+                b
+            },
+            true,
+        ),
     ];
 
     for (name, buf, r) in tests {
@@ -188,17 +283,21 @@ fn test_chrome_channel_data() -> Result<(), Error> {
     // All hex streams decoded to raw binary format and stored in data slice.
     // Decoding packets to messages.
     for packet in data {
-        let mut m = ChannelData::default();
-        m.raw = packet;
+        let mut m = ChannelData {
+            raw: packet,
+            ..Default::default()
+        };
         m.decode()?;
         let mut encoded = ChannelData {
             data: m.data.clone(),
             number: m.number,
             ..Default::default()
         };
-        encoded.encode();
-        let mut decoded = ChannelData::default();
-        decoded.raw = encoded.raw.clone();
+        encoded.encode()?;
+        let mut decoded = ChannelData {
+            raw: encoded.raw.clone(),
+            ..Default::default()
+        };
         decoded.decode()?;
         assert_eq!(decoded, m, "should be equal");
 
@@ -47,7 +47,7 @@ fn test_channel_number_add_to() -> Result<(), Error> {
     //"GetFrom"
     {
         let mut decoded = Message::new();
-        decoded.write(&mut m.raw)?;
+        decoded.write(&m.raw)?;
 
         let mut num_decoded = ChannelNumber::default();
         num_decoded.get_from(&decoded)?;
@@ -65,7 +65,7 @@ fn test_channel_number_add_to() -> Result<(), Error> {
                     err
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
 
             m.add(ATTR_CHANNEL_NUMBER, &[1, 2, 3]);
@@ -76,7 +76,7 @@ fn test_channel_number_add_to() -> Result<(), Error> {
                     "IsAttrSizeInvalid should be true"
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
         }
     }
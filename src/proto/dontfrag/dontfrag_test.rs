@@ -4,7 +4,7 @@ use util::Error;
 
 #[test]
 fn test_dont_fragment_false() -> Result<(), Error> {
-    let mut dont_fragment = DontFragmentAttr::default();
+    let mut dont_fragment = DontFragmentAttr;
 
     let mut m = Message::new();
     m.write_header();
@@ -15,7 +15,7 @@ fn test_dont_fragment_false() -> Result<(), Error> {
 
 #[test]
 fn test_dont_fragment_add_to() -> Result<(), Error> {
-    let mut dont_fragment = DontFragmentAttr::default();
+    let mut dont_fragment = DontFragmentAttr;
 
     let mut m = Message::new();
     dont_fragment.add_to(&mut m)?;
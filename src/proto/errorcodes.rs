@@ -0,0 +1,64 @@
+#[cfg(test)]
+mod errorcodes_test;
+
+use stun::error_code::*;
+
+// The ERROR-CODE values of TURN, from RFC 5766 Section 15, RFC 6062 Section
+// 6.3, RFC 6156 Section 10.2 and RFC 8656 Section 19.
+pub use stun::error_code::{
+    CODE_ADDR_FAMILY_NOT_SUPPORTED, CODE_ALLOC_MISMATCH, CODE_ALLOC_QUOTA_REACHED,
+    CODE_CONN_ALREADY_EXISTS, CODE_CONN_TIMEOUT_OR_FAILURE, CODE_FORBIDDEN,
+    CODE_INSUFFICIENT_CAPACITY, CODE_PEER_ADDR_FAMILY_MISMATCH, CODE_UNSUPPORTED_TRANS_PROTO,
+    CODE_WRONG_CREDENTIALS,
+};
+
+// The same codes as numbers, as Error::Protocol carries them. The STUN codes
+// this crate answers with are included.
+pub const BAD_REQUEST: u16 = 400;
+pub const UNAUTHORIZED: u16 = 401;
+pub const FORBIDDEN: u16 = 403;
+pub const UNKNOWN_ATTRIBUTE: u16 = 420;
+pub const ALLOCATION_MISMATCH: u16 = 437;
+pub const STALE_NONCE: u16 = 438;
+pub const ADDRESS_FAMILY_NOT_SUPPORTED: u16 = 440;
+pub const WRONG_CREDENTIALS: u16 = 441;
+pub const UNSUPPORTED_TRANSPORT_PROTOCOL: u16 = 442;
+pub const PEER_ADDRESS_FAMILY_MISMATCH: u16 = 443;
+pub const CONNECTION_ALREADY_EXISTS: u16 = 446;
+pub const CONNECTION_TIMEOUT_OR_FAILURE: u16 = 447;
+pub const ALLOCATION_QUOTA_REACHED: u16 = 486;
+pub const SERVER_ERROR: u16 = 500;
+pub const INSUFFICIENT_CAPACITY: u16 = 508;
+
+// reason_phrase is the reason phrase the RFCs give code, None for codes this
+// crate doesn't use
+pub fn reason_phrase(code: ErrorCode) -> Option<&'static str> {
+    let reason = match code {
+        CODE_BAD_REQUEST => "Bad Request",
+        CODE_UNAUTHORIZED => "Unauthorized",
+        CODE_FORBIDDEN => "Forbidden",
+        CODE_UNKNOWN_ATTRIBUTE => "Unknown Attribute",
+        CODE_ALLOC_MISMATCH => "Allocation Mismatch",
+        CODE_STALE_NONCE => "Stale Nonce",
+        CODE_ADDR_FAMILY_NOT_SUPPORTED => "Address Family not Supported",
+        CODE_WRONG_CREDENTIALS => "Wrong Credentials",
+        CODE_UNSUPPORTED_TRANS_PROTO => "Unsupported Transport Protocol",
+        CODE_PEER_ADDR_FAMILY_MISMATCH => "Peer Address Family Mismatch",
+        CODE_CONN_ALREADY_EXISTS => "Connection Already Exists",
+        CODE_CONN_TIMEOUT_OR_FAILURE => "Connection Timeout or Failure",
+        CODE_ALLOC_QUOTA_REACHED => "Allocation Quota Reached",
+        CODE_SERVER_ERROR => "Server Error",
+        CODE_INSUFFICIENT_CAPACITY => "Insufficient Capacity",
+        _ => return None,
+    };
+    Some(reason)
+}
+
+// error_code_attribute builds the ERROR-CODE of code with its reason phrase,
+// which is left empty for codes reason_phrase doesn't know
+pub fn error_code_attribute(code: ErrorCode) -> ErrorCodeAttribute {
+    ErrorCodeAttribute {
+        code,
+        reason: reason_phrase(code).unwrap_or_default().as_bytes().to_vec(),
+    }
+}
@@ -0,0 +1,97 @@
+use super::*;
+use crate::error::Error;
+
+use stun::agent::*;
+use stun::message::*;
+
+#[test]
+fn test_error_code_attribute() -> Result<(), Error> {
+    // the code and reason phrase of each error as the RFCs word them
+    let tests = vec![
+        (CODE_BAD_REQUEST, BAD_REQUEST, "Bad Request"),
+        (CODE_UNAUTHORIZED, UNAUTHORIZED, "Unauthorized"),
+        (CODE_FORBIDDEN, FORBIDDEN, "Forbidden"),
+        (
+            CODE_UNKNOWN_ATTRIBUTE,
+            UNKNOWN_ATTRIBUTE,
+            "Unknown Attribute",
+        ),
+        (
+            CODE_ALLOC_MISMATCH,
+            ALLOCATION_MISMATCH,
+            "Allocation Mismatch",
+        ),
+        (CODE_STALE_NONCE, STALE_NONCE, "Stale Nonce"),
+        (
+            CODE_ADDR_FAMILY_NOT_SUPPORTED,
+            ADDRESS_FAMILY_NOT_SUPPORTED,
+            "Address Family not Supported",
+        ),
+        (
+            CODE_WRONG_CREDENTIALS,
+            WRONG_CREDENTIALS,
+            "Wrong Credentials",
+        ),
+        (
+            CODE_UNSUPPORTED_TRANS_PROTO,
+            UNSUPPORTED_TRANSPORT_PROTOCOL,
+            "Unsupported Transport Protocol",
+        ),
+        (
+            CODE_PEER_ADDR_FAMILY_MISMATCH,
+            PEER_ADDRESS_FAMILY_MISMATCH,
+            "Peer Address Family Mismatch",
+        ),
+        (
+            CODE_CONN_ALREADY_EXISTS,
+            CONNECTION_ALREADY_EXISTS,
+            "Connection Already Exists",
+        ),
+        (
+            CODE_CONN_TIMEOUT_OR_FAILURE,
+            CONNECTION_TIMEOUT_OR_FAILURE,
+            "Connection Timeout or Failure",
+        ),
+        (
+            CODE_ALLOC_QUOTA_REACHED,
+            ALLOCATION_QUOTA_REACHED,
+            "Allocation Quota Reached",
+        ),
+        (CODE_SERVER_ERROR, SERVER_ERROR, "Server Error"),
+        (
+            CODE_INSUFFICIENT_CAPACITY,
+            INSUFFICIENT_CAPACITY,
+            "Insufficient Capacity",
+        ),
+    ];
+
+    for (code, number, reason) in tests {
+        assert_eq!(Some(reason), reason_phrase(code), "{}", number);
+
+        let mut m = Message::new();
+        m.build(&[
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE)),
+            Box::new(error_code_attribute(code)),
+        ])?;
+        match Error::from_error_response(&m) {
+            Error::Protocol {
+                code: got_number,
+                reason: got_reason,
+                ..
+            } => {
+                assert_eq!(number, got_number);
+                assert_eq!(reason, got_reason);
+            }
+            err => panic!("{}: unexpected {}", number, err),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_error_code_attribute_unknown() {
+    assert_eq!(None, reason_phrase(CODE_ROLE_CONFLICT));
+    assert!(error_code_attribute(CODE_ROLE_CONFLICT).reason.is_empty());
+}
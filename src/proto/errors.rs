@@ -0,0 +1,18 @@
+use util::Error;
+
+lazy_static! {
+    // ErrInvalidChannelNumber means that channel number is not valid as by RFC 5766 Section 11.
+    pub static ref ERR_INVALID_CHANNEL_NUMBER: Error =
+        Error::new("channel number not in [0x4000, 0x7FFF]".to_owned());
+    // ErrBadChannelDataLength means that channel data length is not equal
+    // to actual data length.
+    pub static ref ERR_BAD_CHANNEL_DATA_LENGTH: Error =
+        Error::new("channelData length != len(Data)".to_owned());
+    // ErrChannelDataTooLarge means that ChannelData payload doesn't fit
+    // into the 16-bit length field.
+    pub static ref ERR_CHANNEL_DATA_TOO_LARGE: Error =
+        Error::new("channelData length exceeds 65535 bytes".to_owned());
+    pub static ref ERR_UNEXPECTED_EOF: Error = Error::new("unexpected EOF".to_owned());
+    pub static ref ERR_LIFETIME_OVERFLOW: Error = Error::new("lifetime does not fit into 32 bits".to_owned());
+    pub static ref ERR_INVALID_REQUESTED_FAMILY_VALUE: Error = Error::new("invalid value for requested family attribute".to_owned());
+}
@@ -76,7 +76,7 @@ fn test_even_port_add_to() -> Result<(), Error> {
                     "IsAttrSizeInvalid should be true"
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
         }
     }
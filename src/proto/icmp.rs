@@ -0,0 +1,68 @@
+#[cfg(test)]
+mod icmp_test;
+
+use stun::attributes::*;
+use stun::checks::*;
+use stun::message::*;
+
+use util::Error;
+
+use std::fmt;
+
+// ATTR_ICMP is the ICMP attribute of RFC 8656, in the comprehension-optional
+// range
+pub const ATTR_ICMP: AttrType = AttrType(0x8004);
+
+// Icmp represents ICMP attribute.
+//
+// A server sends it in a Data indication, without DATA, when a datagram
+// relayed to the peer in XOR-PEER-ADDRESS was answered with an ICMP error.
+// error_data is the next-hop MTU of a Packet Too Big or Fragmentation Needed
+// error, zero otherwise.
+//
+// RFC 8656 Section 18.13
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Icmp {
+    pub typ: u8,
+    pub code: u8,
+    pub error_data: u32,
+}
+
+impl fmt::Display for Icmp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type: {}, code: {}, error data: {}",
+            self.typ, self.code, self.error_data
+        )
+    }
+}
+
+const ICMP_SIZE: usize = 8;
+
+impl Setter for Icmp {
+    // AddTo adds ICMP to message.
+    fn add_to(&self, m: &mut Message) -> Result<(), Error> {
+        let mut v = vec![0; ICMP_SIZE];
+        // the first two bytes are reserved
+        v[2] = self.typ;
+        v[3] = self.code;
+        v[4..].copy_from_slice(&self.error_data.to_be_bytes());
+        m.add(ATTR_ICMP, &v);
+        Ok(())
+    }
+}
+
+impl Getter for Icmp {
+    // GetFrom decodes ICMP from message.
+    fn get_from(&mut self, m: &Message) -> Result<(), Error> {
+        let v = m.get(ATTR_ICMP)?;
+
+        check_size(ATTR_ICMP, v.len(), ICMP_SIZE)?;
+
+        self.typ = v[2];
+        self.code = v[3];
+        self.error_data = u32::from_be_bytes([v[4], v[5], v[6], v[7]]);
+        Ok(())
+    }
+}
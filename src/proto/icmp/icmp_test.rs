@@ -0,0 +1,46 @@
+use super::*;
+
+use stun::errors::*;
+
+use util::Error;
+
+#[test]
+fn test_icmp() -> Result<(), Error> {
+    let mut m = Message::new();
+    let icmp = Icmp {
+        typ: 3,
+        code: 4,
+        error_data: 1400,
+    };
+    icmp.add_to(&mut m)?;
+    m.write_header();
+    assert_eq!(
+        &[0, 0, 3, 4, 0, 0, 0x05, 0x78],
+        m.get(ATTR_ICMP)?.as_slice(),
+        "the first two bytes are reserved"
+    );
+    assert_eq!("type: 3, code: 4, error data: 1400", icmp.to_string());
+
+    let mut decoded = Message::new();
+    decoded.write(&m.raw)?;
+    let mut got = Icmp::default();
+    got.get_from(&decoded)?;
+    assert_eq!(icmp, got);
+
+    // wrong size
+    let mut m = Message::new();
+    m.add(ATTR_ICMP, &[0, 0, 3, 4]);
+    let result = got.get_from(&m);
+    assert!(result.is_err(), "should error");
+    if let Err(err) = result {
+        assert!(is_attr_size_invalid(&err), "should error on size");
+    }
+
+    // missing
+    match got.get_from(&Message::new()) {
+        Err(err) => assert_eq!(ERR_ATTRIBUTE_NOT_FOUND.to_owned(), err),
+        Ok(()) => panic!("expected error, but got ok"),
+    }
+
+    Ok(())
+}
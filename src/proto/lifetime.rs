@@ -5,6 +5,8 @@ use stun::attributes::*;
 use stun::checks::*;
 use stun::message::*;
 
+use super::errors::*;
+
 use util::Error;
 
 use std::fmt;
@@ -39,6 +41,9 @@ const LIFETIME_SIZE: usize = 4; // 4 bytes, 32 bits
 impl Setter for Lifetime {
     // AddTo adds LIFETIME to message.
     fn add_to(&self, m: &mut Message) -> Result<(), Error> {
+        if self.0.as_secs() > u32::MAX as u64 {
+            return Err(ERR_LIFETIME_OVERFLOW.to_owned());
+        }
         let mut v = vec![0; LIFETIME_SIZE];
         v.copy_from_slice(&(self.0.as_secs() as u32).to_be_bytes());
         m.add(ATTR_LIFETIME, &v);
@@ -40,7 +40,7 @@ fn test_lifetime_add_to() -> Result<(), Error> {
                     err
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
             m.add(ATTR_LIFETIME, &[1, 2, 3]);
 
@@ -50,10 +50,23 @@ fn test_lifetime_add_to() -> Result<(), Error> {
                     "IsAttrSizeInvalid should be true"
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
         }
     }
 
     Ok(())
 }
+
+#[test]
+fn test_lifetime_add_to_overflow() -> Result<(), Error> {
+    let mut m = Message::new();
+    let l = Lifetime(Duration::from_secs(u32::MAX as u64 + 1));
+    if let Err(err) = l.add_to(&mut m) {
+        assert_eq!(err, ERR_LIFETIME_OVERFLOW.to_owned());
+    } else {
+        panic!("expected error, but got ok");
+    }
+
+    Ok(())
+}
@@ -6,16 +6,25 @@ pub mod chandata;
 pub mod channum;
 pub mod data;
 pub mod dontfrag;
+pub mod errorcodes;
+pub mod errors;
 pub mod evenport;
+pub mod icmp;
 pub mod lifetime;
+pub mod origin;
 pub mod peeraddr;
 pub mod relayaddr;
 pub mod reqfamily;
 pub mod reqtrans;
 pub mod rsrvtoken;
 
+use crate::error::Error;
+use chandata::ChannelData;
+use channum::ChannelNumber;
+
 use std::fmt;
 
+use stun::attributes::*;
 use stun::message::*;
 
 // proto implements RFC 5766 Traversal Using Relays around NAT.
@@ -48,6 +57,32 @@ pub const DEFAULT_PORT: u16 = stun::DEFAULT_PORT;
 // DEFAULT_TLSPORT is for TURN over TLS and is same as STUN.
 pub const DEFAULT_TLS_PORT: u16 = stun::DEFAULT_TLS_PORT;
 
+// PacketClass is what a datagram on a socket shared with TURN is, see
+// classify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketClass {
+    StunMessage,
+    ChannelData { number: ChannelNumber },
+    Other,
+}
+
+// classify demultiplexes a datagram like the client and the server do. A
+// STUN message starts with two zero bits and carries the magic cookie within
+// its 20 byte header. ChannelData starts with a channel number, within
+// 0x4000..=0x7FFF, and a length that fits in buf. Anything else, e.g.
+// application data, is Other.
+pub fn classify(buf: &[u8]) -> PacketClass {
+    if is_message(buf) && buf[0] & 0xC0 == 0 {
+        PacketClass::StunMessage
+    } else if ChannelData::is_channel_data(buf) {
+        PacketClass::ChannelData {
+            number: ChannelNumber(u16::from_be_bytes([buf[0], buf[1]])),
+        }
+    } else {
+        PacketClass::Other
+    }
+}
+
 // create_permission_request is shorthand for create permission request type.
 pub fn create_permission_request() -> MessageType {
     MessageType::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST)
@@ -67,3 +102,31 @@ pub fn send_indication() -> MessageType {
 pub fn refresh_request() -> MessageType {
     MessageType::new(METHOD_REFRESH, CLASS_REQUEST)
 }
+
+// check_attributes rejects a message with more than one of any attribute in
+// unique, or with anything but FINGERPRINT after MESSAGE-INTEGRITY, or
+// anything after FINGERPRINT. Attributes are otherwise read from their first
+// copy, which needn't be the one MESSAGE-INTEGRITY was checked with.
+pub fn check_attributes(m: &Message, unique: &[AttrType]) -> Result<(), Error> {
+    let mut seen: Vec<AttrType> = Vec::with_capacity(unique.len());
+    let mut last = None;
+    for attr in &m.attributes.0 {
+        match last {
+            Some(ATTR_FINGERPRINT) => {
+                return Err(Error::AttributeAfter(attr.typ, ATTR_FINGERPRINT))
+            }
+            Some(ATTR_MESSAGE_INTEGRITY) if attr.typ != ATTR_FINGERPRINT => {
+                return Err(Error::AttributeAfter(attr.typ, ATTR_MESSAGE_INTEGRITY))
+            }
+            _ => {}
+        }
+        if unique.contains(&attr.typ) {
+            if seen.contains(&attr.typ) {
+                return Err(Error::DuplicateAttribute(attr.typ));
+            }
+            seen.push(attr.typ);
+        }
+        last = Some(attr.typ);
+    }
+    Ok(())
+}
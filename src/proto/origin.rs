@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod origin_test;
+
+use stun::attributes::*;
+use stun::checks::*;
+use stun::message::*;
+
+use util::Error;
+
+// ORIGIN carries at most as many bytes as the other STUN text attributes
+const MAX_ORIGIN_SIZE: usize = 763;
+
+// Origin represents ORIGIN attribute.
+//
+// The ORIGIN attribute carries the origin of the web page a browser's
+// request was made for, it is informational and never checked.
+//
+// draft-ietf-tram-stun-origin
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Origin(pub String);
+
+impl Setter for Origin {
+    // add_to adds ORIGIN to message.
+    fn add_to(&self, m: &mut Message) -> Result<(), Error> {
+        check_overflow(ATTR_ORIGIN, self.0.len(), MAX_ORIGIN_SIZE)?;
+        m.add(ATTR_ORIGIN, self.0.as_bytes());
+        Ok(())
+    }
+}
+
+impl Getter for Origin {
+    // get_from decodes ORIGIN from message.
+    fn get_from(&mut self, m: &Message) -> Result<(), Error> {
+        let v = m.get(ATTR_ORIGIN)?;
+        check_overflow(ATTR_ORIGIN, v.len(), MAX_ORIGIN_SIZE)?;
+        self.0 = String::from_utf8(v)?;
+        Ok(())
+    }
+}
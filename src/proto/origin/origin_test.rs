@@ -0,0 +1,53 @@
+use super::*;
+
+use stun::errors::*;
+
+use util::Error;
+
+#[test]
+fn test_origin() -> Result<(), Error> {
+    let mut m = Message::new();
+    let origin = Origin("https://example.org".to_owned());
+    origin.add_to(&mut m)?;
+    m.write_header();
+
+    //"HandleErr"
+    {
+        let bad_origin = Origin("a".repeat(MAX_ORIGIN_SIZE + 1));
+        if let Err(err) = bad_origin.add_to(&mut m) {
+            assert!(
+                is_attr_size_overflow(&err),
+                "IsAttrSizeOverflow should be true"
+            );
+        } else {
+            panic!("expected error, but got ok");
+        }
+    }
+
+    //"GetFrom"
+    {
+        let mut decoded = Message::new();
+        decoded.write(&m.raw)?;
+        let mut got = Origin::default();
+        got.get_from(&decoded)?;
+        assert_eq!(got, origin, "Decoded {:?}, expected {:?}", got, origin);
+
+        //"HandleErr"
+        {
+            let m = Message::new();
+            let mut handle = Origin::default();
+            if let Err(err) = handle.get_from(&m) {
+                assert_eq!(
+                    err,
+                    ERR_ATTRIBUTE_NOT_FOUND.to_owned(),
+                    "{} should be not found",
+                    err
+                );
+            } else {
+                panic!("expected error, but got ok");
+            }
+        }
+    }
+
+    Ok(())
+}
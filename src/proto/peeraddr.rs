@@ -63,6 +63,29 @@ impl Getter for PeerAddress {
     }
 }
 
+impl PeerAddress {
+    // get_all_from decodes every XOR-PEER-ADDRESS of message, in order. A
+    // CreatePermission may carry several, get_from only decodes the first.
+    pub fn get_all_from(m: &Message) -> Result<Vec<PeerAddress>, Error> {
+        let mut addrs = vec![];
+        for attr in &m.attributes.0 {
+            if attr.typ != ATTR_XOR_PEER_ADDRESS {
+                continue;
+            }
+
+            // the address is XORed with the transaction id of m
+            let mut single = Message::new();
+            single.transaction_id = m.transaction_id;
+            single.add(attr.typ, &attr.value);
+
+            let mut addr = PeerAddress::default();
+            addr.get_from(&single)?;
+            addrs.push(addr);
+        }
+        Ok(addrs)
+    }
+}
+
 // XORPeerAddress implements XOR-PEER-ADDRESS attribute.
 //
 // The XOR-PEER-ADDRESS specifies the address and port of the peer as
@@ -25,3 +25,32 @@ fn test_peer_address() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_peer_address_get_all_from() -> Result<(), Error> {
+    let addrs = [
+        PeerAddress {
+            ip: IpAddr::V4(Ipv4Addr::new(111, 11, 1, 2)),
+            port: 333,
+        },
+        PeerAddress {
+            ip: IpAddr::V6("2001:db8::1".parse().unwrap()),
+            port: 4444,
+        },
+    ];
+
+    let mut m = Message::new();
+    m.new_transaction_id()?;
+    for a in &addrs {
+        a.add_to(&mut m)?;
+    }
+    m.write_header();
+
+    let mut decoded = Message::new();
+    decoded.write(&m.raw)?;
+    assert_eq!(Vec::from(addrs), PeerAddress::get_all_from(&decoded)?);
+
+    assert!(PeerAddress::get_all_from(&Message::new())?.is_empty());
+
+    Ok(())
+}
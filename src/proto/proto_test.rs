@@ -1,5 +1,6 @@
 use super::*;
 
+use stun::agent::TransactionId;
 use util::Error;
 
 const CHROME_ALLOC_REQ_TEST_HEX: [&str; 4] = [
@@ -34,3 +35,140 @@ fn test_chrome_alloc_request() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_check_attributes() -> Result<(), Error> {
+    let unique = [ATTR_USERNAME, ATTR_MESSAGE_INTEGRITY];
+    let new_message = |attrs: &[AttrType]| {
+        let mut m = Message::new();
+        for typ in attrs {
+            m.add(*typ, b"test");
+        }
+        m
+    };
+
+    let tests = vec![
+        (vec![ATTR_USERNAME, ATTR_REALM, ATTR_REALM], None),
+        (
+            vec![ATTR_USERNAME, ATTR_MESSAGE_INTEGRITY, ATTR_FINGERPRINT],
+            None,
+        ),
+        (
+            vec![ATTR_USERNAME, ATTR_REALM, ATTR_USERNAME],
+            Some(crate::error::Error::DuplicateAttribute(ATTR_USERNAME)),
+        ),
+        (
+            vec![ATTR_MESSAGE_INTEGRITY, ATTR_MESSAGE_INTEGRITY],
+            Some(crate::error::Error::AttributeAfter(
+                ATTR_MESSAGE_INTEGRITY,
+                ATTR_MESSAGE_INTEGRITY,
+            )),
+        ),
+        (
+            vec![ATTR_MESSAGE_INTEGRITY, ATTR_SOFTWARE],
+            Some(crate::error::Error::AttributeAfter(
+                ATTR_SOFTWARE,
+                ATTR_MESSAGE_INTEGRITY,
+            )),
+        ),
+        (
+            vec![ATTR_FINGERPRINT, ATTR_SOFTWARE],
+            Some(crate::error::Error::AttributeAfter(
+                ATTR_SOFTWARE,
+                ATTR_FINGERPRINT,
+            )),
+        ),
+    ];
+
+    for (attrs, expected) in tests {
+        let m = new_message(&attrs);
+        assert_eq!(
+            expected.map(|err| err.to_string()),
+            check_attributes(&m, &unique)
+                .err()
+                .map(|err| err.to_string()),
+            "{:?}",
+            attrs
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_classify() {
+    // too short for anything but a header-only ChannelData
+    for len in 0..4 {
+        assert_eq!(
+            PacketClass::Other,
+            classify(&vec![0x40; len]),
+            "len {}",
+            len
+        );
+        assert_eq!(PacketClass::Other, classify(&vec![0; len]), "len {}", len);
+    }
+    assert_eq!(
+        PacketClass::ChannelData {
+            number: ChannelNumber(0x4040)
+        },
+        classify(&[0x40, 0x40, 0, 0])
+    );
+    assert_eq!(PacketClass::Other, classify(&[0; 4]));
+
+    for (number, want) in [
+        (0x3FFF, false),
+        (0x4000, true),
+        (0x7FFF, true),
+        (0x8000, false),
+    ] {
+        let mut buf = vec![0; 8];
+        buf[..2].copy_from_slice(&u16::to_be_bytes(number));
+        buf[2..4].copy_from_slice(&4u16.to_be_bytes());
+        let class = classify(&buf);
+        if want {
+            assert_eq!(
+                PacketClass::ChannelData {
+                    number: ChannelNumber(number)
+                },
+                class
+            );
+        } else {
+            assert_eq!(PacketClass::Other, class, "{:#x}", number);
+        }
+
+        // the length must fit in buf, padding may follow
+        buf[2..4].copy_from_slice(&5u16.to_be_bytes());
+        assert_eq!(PacketClass::Other, classify(&buf), "{:#x}", number);
+        buf[2..4].copy_from_slice(&3u16.to_be_bytes());
+        assert_eq!(want, classify(&buf) != PacketClass::Other, "{:#x}", number);
+    }
+
+    // a Binding request header with the magic cookie
+    let mut header = vec![0, 1, 0, 0, 0x21, 0x12, 0xA4, 0x42];
+    header.extend_from_slice(&[0; 12]);
+    assert_eq!(PacketClass::StunMessage, classify(&header));
+    assert_eq!(PacketClass::Other, classify(&header[..19]));
+
+    // without the cookie
+    let mut no_cookie = header.clone();
+    no_cookie[4] ^= 0xFF;
+    assert_eq!(PacketClass::Other, classify(&no_cookie));
+
+    // with the cookie, the first two bits still have to be zero
+    let mut first_bits = header.clone();
+    first_bits[0] = 0x80;
+    assert_eq!(PacketClass::Other, classify(&first_bits));
+    first_bits[0] = 0x40;
+    first_bits[2..4].copy_from_slice(&16u16.to_be_bytes());
+    assert_eq!(
+        PacketClass::ChannelData {
+            number: ChannelNumber(0x4001)
+        },
+        classify(&first_bits)
+    );
+
+    let mut m = Message::new();
+    m.build(&[Box::new(TransactionId::new()), Box::new(allocate_request())])
+        .unwrap();
+    assert_eq!(PacketClass::StunMessage, classify(&m.raw));
+}
@@ -5,7 +5,7 @@ use stun::attributes::*;
 use stun::checks::*;
 use stun::message::*;
 
-use crate::errors::*;
+use super::errors::*;
 
 use util::Error;
 
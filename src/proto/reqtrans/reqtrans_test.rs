@@ -61,7 +61,7 @@ fn test_requested_transport_add_to() -> Result<(), Error> {
                     err
                 );
             } else {
-                assert!(false, "expected error, got ok");
+                panic!("expected error, got ok");
             }
 
             m.add(ATTR_REQUESTED_TRANSPORT, &[1, 2, 3]);
@@ -71,7 +71,7 @@ fn test_requested_transport_add_to() -> Result<(), Error> {
                     "IsAttrSizeInvalid should be true"
                 );
             } else {
-                assert!(false, "expected error, got ok");
+                panic!("expected error, got ok");
             }
         }
     }
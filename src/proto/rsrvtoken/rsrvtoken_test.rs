@@ -23,7 +23,7 @@ fn test_reservation_token() -> Result<(), Error> {
                 "IsAttrSizeInvalid should be true"
             );
         } else {
-            assert!(false, "expected error, but got ok");
+            panic!("expected error, but got ok");
         }
     }
 
@@ -47,7 +47,7 @@ fn test_reservation_token() -> Result<(), Error> {
                     err
                 );
             } else {
-                assert!(false, "expected error, but got ok");
+                panic!("expected error, but got ok");
             }
             m.add(ATTR_RESERVATION_TOKEN, &[1, 2, 3]);
             if let Err(err) = handle.get_from(&m) {
@@ -56,7 +56,7 @@ fn test_reservation_token() -> Result<(), Error> {
                     "IsAttrSizeInvalid should be true"
                 );
             } else {
-                assert!(false, "expected error, got ok");
+                panic!("expected error, got ok");
             }
         }
     }
@@ -0,0 +1,271 @@
+use crate::proto::icmp::Icmp;
+
+use util::Conn;
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// IcmpError is an ICMP error answering a datagram the relay socket sent to
+// peer. A relay conn's recv_from returns it as the inner error of an
+// io::Error, see icmp_error, the allocation then tells its client in a Data
+// indication. The relay conns of the provided generators do so with the icmp
+// feature on Linux, a custom RelayAddressGenerator's conn may too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpError {
+    pub peer: SocketAddr,
+    pub icmp: Icmp,
+}
+
+impl fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ICMP error from {}: {}", self.peer, self.icmp)
+    }
+}
+
+impl std::error::Error for IcmpError {}
+
+impl From<IcmpError> for io::Error {
+    fn from(err: IcmpError) -> Self {
+        io::Error::other(err)
+    }
+}
+
+// icmp_error is the IcmpError of a relay conn's recv_from error, if it is one
+pub fn icmp_error(err: &io::Error) -> Option<IcmpError> {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<IcmpError>())
+        .copied()
+}
+
+// report_icmp wraps a relay socket so its recv_from returns the ICMP errors
+// answering what it sent, with the icmp feature on Linux
+#[cfg(all(feature = "icmp", target_os = "linux"))]
+pub(crate) fn report_icmp<C: linux::UdpConn + Send + Sync + 'static>(
+    conn: C,
+) -> Arc<dyn Conn + Send + Sync> {
+    match linux::set_recverr(conn.udp_socket()) {
+        Ok(()) => Arc::new(linux::IcmpConn { conn }),
+        Err(err) => {
+            log::warn!("ICMP errors of the relay socket aren't reported: {}", err);
+            Arc::new(conn)
+        }
+    }
+}
+
+#[cfg(not(all(feature = "icmp", target_os = "linux")))]
+pub(crate) fn report_icmp<C: Conn + Send + Sync + 'static>(conn: C) -> Arc<dyn Conn + Send + Sync> {
+    Arc::new(conn)
+}
+
+#[cfg(all(feature = "icmp", target_os = "linux"))]
+mod linux {
+    use super::*;
+
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    use async_trait::async_trait;
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+
+    // UdpConn is a Conn over a UdpSocket, whose error queue IcmpConn reads
+    pub(crate) trait UdpConn: Conn {
+        fn udp_socket(&self) -> &UdpSocket;
+    }
+
+    impl UdpConn for UdpSocket {
+        fn udp_socket(&self) -> &UdpSocket {
+            self
+        }
+    }
+
+    #[cfg(feature = "batch")]
+    impl UdpConn for crate::batch::BatchConn {
+        fn udp_socket(&self) -> &UdpSocket {
+            self.socket()
+        }
+    }
+
+    // set_recverr queues the ICMP errors of the socket on its error queue
+    pub(crate) fn set_recverr(socket: &UdpSocket) -> io::Result<()> {
+        let (level, name) = if socket.local_addr()?.is_ipv4() {
+            (libc::IPPROTO_IP, libc::IP_RECVERR)
+        } else {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+        };
+        let on: libc::c_int = 1;
+        // the option value is a c_int that outlives the call
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // IcmpConn is a relay socket with IP_RECVERR set, recv_from returns an
+    // IcmpError when one is on the error queue
+    pub(crate) struct IcmpConn<C> {
+        pub(crate) conn: C,
+    }
+
+    impl<C: UdpConn + Send + Sync> IcmpConn<C> {
+        // recv_icmp waits for an ICMP error, the other errors queued are
+        // dropped
+        async fn recv_icmp(&self) -> io::Result<IcmpError> {
+            let socket = self.conn.udp_socket();
+            loop {
+                let received = socket
+                    .async_io(Interest::ERROR, || recv_error(socket.as_raw_fd()))
+                    .await?;
+                if let Some(icmp_error) = received {
+                    return Ok(icmp_error);
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl<C: UdpConn + Send + Sync> Conn for IcmpConn<C> {
+        async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+            self.conn.connect(addr).await
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let (n, _) = self.recv_from(buf).await?;
+            Ok(n)
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            loop {
+                tokio::select! {
+                    result = self.conn.recv_from(buf) => match result {
+                        // the socket error of a queued ICMP error, which
+                        // recv_icmp reads
+                        Err(err) if is_icmp_errno(&err) => continue,
+                        result => return result,
+                    },
+                    result = self.recv_icmp() => return Err(result?.into()),
+                }
+            }
+        }
+
+        async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.conn.send(buf).await
+        }
+
+        async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+            self.conn.send_to(buf, target).await
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.conn.local_addr()
+        }
+    }
+
+    fn is_icmp_errno(err: &io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(
+                libc::ECONNREFUSED
+                    | libc::EHOSTUNREACH
+                    | libc::ENETUNREACH
+                    | libc::EMSGSIZE
+                    | libc::EPROTO
+            )
+        )
+    }
+
+    // recv_error reads one error from the error queue of fd, it is an
+    // IcmpError when it came in an ICMP message. The queued message's
+    // destination, the peer, is its name.
+    fn recv_error(fd: RawFd) -> io::Result<Option<IcmpError>> {
+        // sockaddr_storage, msghdr and the control buffer are plain C data,
+        // all zeroes is valid
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = [0u64; 64];
+        let mut payload = [0u8; 1];
+        let mut iovec = libc::iovec {
+            iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iovec;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of_val(&control);
+
+        // every pointer in hdr refers to name, iovec, payload or control,
+        // which outlive the call
+        let n = unsafe { libc::recvmsg(fd, &mut hdr, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let peer = match to_socket_addr(&name) {
+            Some(peer) => peer,
+            None => return Ok(None),
+        };
+        // the control messages are within control, as recvmsg left them
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            let (level, typ) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level == libc::IPPROTO_IP && typ == libc::IP_RECVERR)
+                || (level == libc::IPPROTO_IPV6 && typ == libc::IPV6_RECVERR)
+            {
+                // the data of a RECVERR control message is a
+                // sock_extended_err, which may be unaligned
+                let err = unsafe {
+                    std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+                };
+                if err.ee_origin == libc::SO_EE_ORIGIN_ICMP
+                    || err.ee_origin == libc::SO_EE_ORIGIN_ICMP6
+                {
+                    return Ok(Some(IcmpError {
+                        peer,
+                        icmp: Icmp {
+                            typ: err.ee_type,
+                            code: err.ee_code,
+                            error_data: err.ee_info,
+                        },
+                    }));
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+        }
+        Ok(None)
+    }
+
+    fn to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // the storage holds a sockaddr_in when the family is AF_INET
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::new(
+                    u32::from_be(addr.sin_addr.s_addr).to_be_bytes().into(),
+                    u16::from_be(addr.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                // the storage holds a sockaddr_in6 when the family is AF_INET6
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::new(
+                    addr.sin6_addr.s6_addr.into(),
+                    u16::from_be(addr.sin6_port),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
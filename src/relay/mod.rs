@@ -1,20 +1,65 @@
+#[cfg(test)]
+mod relay_test;
+
+pub mod icmp;
 pub mod relay_none;
+pub mod relay_pool;
 pub mod relay_range;
 pub mod relay_static;
 
-use util::{Conn, Error};
+use crate::error::Error;
+
+use util::Conn;
 
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use rand::Rng;
+use tokio::net::UdpSocket;
+
+// DYNAMIC_PORTS is the IANA dynamic port range, generators without a port
+// range of their own pick their random relay ports in it
+pub const DYNAMIC_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+// DEFAULT_MAX_RETRIES is how many random ports a generator tries before it
+// gives up, when a port is taken
+pub const DEFAULT_MAX_RETRIES: u16 = 10;
+
+// PortSelection is how a generator picks the port of a relay the client
+// didn't request a port for. Unpredictable ports make it harder for an
+// off-path attacker to spoof peer traffic to a relay.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PortSelection {
+    // Sequential binds the lowest free port of the range, each allocation
+    // trying the ports in use before it. Generators without a range let the
+    // OS pick.
+    Sequential,
+    // Random picks the ports with a CSPRNG, uniformly within the range or
+    // DYNAMIC_PORTS, and tries another when one is taken
+    #[default]
+    Random,
+}
 
 // RelayAddressGenerator is used to generate a RelayAddress when creating an allocation.
 // You can use one of the provided ones or provide your own.
 #[async_trait]
 pub trait RelayAddressGenerator {
-    // validate confirms that the RelayAddressGenerator is properly initialized
-    fn validate(&self) -> Result<(), Error>;
+    // validate confirms that the RelayAddressGenerator is properly initialized,
+    // Server::new calls it for every listener
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // probe completes validate with the checks that can't be made without
+    // blocking, e.g. resolving a hostname. Server::new awaits it for every
+    // listener once the config is valid.
+    async fn probe(&self) -> Result<(), Error> {
+        Ok(())
+    }
 
     // Allocate a RelayAddress
     async fn allocate_conn(
@@ -22,4 +67,231 @@ pub trait RelayAddressGenerator {
         network: &str,
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error>;
+
+    // set_bind_device is called with ConnConfig's bind_device, generators that
+    // bind to a network device use it unless they were given their own
+    fn set_bind_device(&mut self, _bind_device: &str) {}
+
+    // port_capacity is the number of relay ports the generator can hand out,
+    // None when it lets the OS pick them
+    fn port_capacity(&self) -> Option<usize> {
+        None
+    }
+
+    // port_selection is how the generator picks relay ports, None when it
+    // doesn't tell
+    fn port_selection(&self) -> Option<PortSelection> {
+        None
+    }
+
+    // pool_metrics is the occupancy of a generator handing out preallocated
+    // sockets, None for the others
+    fn pool_metrics(&self) -> Option<RelayPoolMetrics> {
+        None
+    }
+}
+
+// RelayPoolMetrics is the occupancy of a PreallocatedRelayPool, exhausted
+// counting the allocations refused because all its sockets were in use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RelayPoolMetrics {
+    pub size: usize,
+    pub in_use: usize,
+    pub exhausted: u64,
+}
+
+// validate_bind_device rejects a bind_device on platforms without SO_BINDTODEVICE
+pub(crate) fn validate_bind_device(bind_device: &Option<String>) -> Result<(), Error> {
+    if bind_device.is_some() && !cfg!(target_os = "linux") {
+        Err(Error::BindDeviceUnsupported)
+    } else {
+        Ok(())
+    }
+}
+
+// validate_relay_address rejects a relay address clients can't send to
+pub(crate) fn validate_relay_address(relay_address: IpAddr) -> Result<(), Error> {
+    let broadcast = match relay_address {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    if relay_address.is_unspecified() || relay_address.is_multicast() || broadcast {
+        Err(Error::RelayAddressInvalid(relay_address))
+    } else {
+        Ok(())
+    }
+}
+
+// probe_bind binds and drops a UDP socket on address:port to check the
+// address is local. A port that is taken counts as bindable. validate must not
+// block on DNS, so a hostname such as localhost is only checked for syntax
+// here and left to probe_bind_hostname.
+pub(crate) fn probe_bind(address: &str, port: u16) -> Result<(), Error> {
+    match address.parse::<IpAddr>() {
+        Ok(ip) => bind_probe(address, &[SocketAddr::new(ip, port)]),
+        Err(_) if is_hostname(address) => Ok(()),
+        Err(_) => Err(Error::ListeningAddressInvalid),
+    }
+}
+
+// probe_bind_hostname is probe_bind for a hostname, resolved as bind_relay
+// resolves it. An IP address was probed by probe_bind already.
+pub(crate) async fn probe_bind_hostname(address: &str, port: u16) -> Result<(), Error> {
+    if address.parse::<IpAddr>().is_ok() {
+        return Ok(());
+    }
+    bind_probe(address, &lookup_host(address, port).await?)
+}
+
+// is_hostname checks the syntax of a hostname (RFC 1123): dot separated labels
+// of letters, digits and hyphens, not starting or ending with a hyphen, and a
+// last label that isn't all digits so malformed IPv4 addresses are rejected
+fn is_hostname(address: &str) -> bool {
+    let address = address.strip_suffix('.').unwrap_or(address);
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    address.len() <= 253
+        && address.split('.').all(valid_label)
+        && !address
+            .rsplit('.')
+            .next()
+            .is_some_and(|label| label.bytes().all(|b| b.is_ascii_digit()))
+}
+
+async fn lookup_host(address: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((address, port))
+        .await
+        .map_err(|_| Error::ListeningAddressInvalid)?
+        .collect();
+    if addrs.is_empty() {
+        return Err(Error::ListeningAddressInvalid);
+    }
+    Ok(addrs)
+}
+
+fn bind_probe(address: &str, addrs: &[SocketAddr]) -> Result<(), Error> {
+    match std::net::UdpSocket::bind(addrs) {
+        Err(err) if err.kind() != io::ErrorKind::AddrInUse => Err(Error::RelayBind {
+            address: address.to_owned(),
+            err,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// bind_random_port binds a port of ports picked with the thread's CSPRNG,
+// trying up to max_retries ports when they are taken
+pub(crate) async fn bind_random_port(
+    address: &str,
+    ports: RangeInclusive<u16>,
+    max_retries: u16,
+    bind_device: Option<&str>,
+) -> Result<UdpSocket, Error> {
+    for _ in 0..max_retries {
+        let port = rand::thread_rng().gen_range(ports.clone());
+        match bind_relay(address, port, bind_device).await {
+            Ok(conn) => return Ok(conn),
+            // the port is taken, a failure to bind the device is not retried
+            Err(Error::Io(_)) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Error::MaxRetriesExceeded)
+}
+
+// bind_lowest_port binds the lowest free port of ports
+pub(crate) async fn bind_lowest_port(
+    address: &str,
+    ports: RangeInclusive<u16>,
+    bind_device: Option<&str>,
+) -> Result<UdpSocket, Error> {
+    for port in ports {
+        match bind_relay(address, port, bind_device).await {
+            Ok(conn) => return Ok(conn),
+            Err(Error::Io(_)) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Error::MaxRetriesExceeded)
+}
+
+// bind_unranged_port binds a relay of a generator without a port range, a
+// random port of DYNAMIC_PORTS or the OS's pick with Sequential. The OS
+// picks too once DEFAULT_MAX_RETRIES random ports were taken.
+pub(crate) async fn bind_unranged_port(
+    address: &str,
+    port_selection: PortSelection,
+    bind_device: Option<&str>,
+) -> Result<UdpSocket, Error> {
+    if port_selection == PortSelection::Random {
+        match bind_random_port(address, DYNAMIC_PORTS, DEFAULT_MAX_RETRIES, bind_device).await {
+            Err(Error::MaxRetriesExceeded) => {
+                log::debug!("no free random relay port, the OS picks one");
+            }
+            result => return result,
+        }
+    }
+    bind_relay(address, 0, bind_device).await
+}
+
+// bind_relay binds a UDP socket on address:port, the address being an IP or a
+// hostname such as localhost. With a bind_device the socket is bound to that
+// network device with SO_BINDTODEVICE before the address, so return traffic
+// leaves through it
+pub(crate) async fn bind_relay(
+    address: &str,
+    port: u16,
+    bind_device: Option<&str>,
+) -> Result<UdpSocket, Error> {
+    let addrs = lookup_host(address, port).await?;
+    let bind_device = match bind_device {
+        Some(bind_device) => bind_device,
+        None => return Ok(UdpSocket::bind(addrs.as_slice()).await?),
+    };
+    let addr = addrs[0];
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    set_bind_device(&socket, bind_device)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(target_os = "linux")]
+fn set_bind_device(socket: &socket2::Socket, bind_device: &str) -> Result<(), Error> {
+    socket
+        .bind_device(Some(bind_device.as_bytes()))
+        .map_err(|err| Error::BindDevice {
+            device: bind_device.to_owned(),
+            err,
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_bind_device(_socket: &socket2::Socket, _bind_device: &str) -> Result<(), Error> {
+    Err(Error::BindDeviceUnsupported)
+}
+
+// relay_conn wraps a socket bound by one of the provided generators, with the
+// batch feature the relay is read in batches, with the icmp feature its ICMP
+// errors are reported
+#[cfg(feature = "batch")]
+pub(crate) fn relay_conn(conn: UdpSocket) -> Arc<dyn Conn + Send + Sync> {
+    icmp::report_icmp(crate::batch::BatchConn::new(conn, 0))
+}
+
+#[cfg(not(feature = "batch"))]
+pub(crate) fn relay_conn(conn: UdpSocket) -> Arc<dyn Conn + Send + Sync> {
+    icmp::report_icmp(conn)
 }
@@ -1,25 +1,35 @@
 use super::*;
-use crate::errors::*;
-
-use tokio::net::UdpSocket;
 
 use async_trait::async_trait;
 
 // RelayAddressGeneratorNone returns the listener with no modifications
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RelayAddressGeneratorNone {
     // Address is passed to Listen/ListenPacket when creating the Relay
     pub address: String,
+
+    // bind_device binds the relay sockets to a network device (SO_BINDTODEVICE),
+    // only supported on Linux
+    pub bind_device: Option<String>,
+
+    // port_selection is how the port of a relay is picked when the client
+    // doesn't request one
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_selection: PortSelection,
 }
 
 #[async_trait]
 impl RelayAddressGenerator for RelayAddressGeneratorNone {
     // validate confirms that the RelayAddressGenerator is properly initialized
+    // and the address can be bound, it may be a hostname such as localhost
     fn validate(&self) -> Result<(), Error> {
-        if self.address.is_empty() {
-            Err(ERR_LISTENING_ADDRESS_INVALID.to_owned())
-        } else {
-            Ok(())
-        }
+        validate_bind_device(&self.bind_device)?;
+        probe_bind(&self.address, 0)
+    }
+
+    async fn probe(&self) -> Result<(), Error> {
+        probe_bind_hostname(&self.address, 0).await
     }
 
     // Allocate a PacketConn (UDP) RelayAddress
@@ -28,8 +38,23 @@ impl RelayAddressGenerator for RelayAddressGeneratorNone {
         _network: &str,
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error> {
-        let conn = UdpSocket::bind(format!("{}:{}", self.address, requested_port)).await?;
+        let bind_device = self.bind_device.as_deref();
+        let conn = if requested_port == 0 {
+            bind_unranged_port(&self.address, self.port_selection, bind_device).await?
+        } else {
+            bind_relay(&self.address, requested_port, bind_device).await?
+        };
         let relay_addr = conn.local_addr()?;
-        Ok((Arc::new(conn), relay_addr))
+        Ok((relay_conn(conn), relay_addr))
+    }
+
+    fn port_selection(&self) -> Option<PortSelection> {
+        Some(self.port_selection)
+    }
+
+    fn set_bind_device(&mut self, bind_device: &str) {
+        if self.bind_device.is_none() {
+            self.bind_device = Some(bind_device.to_owned());
+        }
     }
 }
@@ -0,0 +1,200 @@
+#[cfg(test)]
+mod relay_pool_test;
+
+use super::*;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// PooledSocket is a socket of the pool with the IP advertised for it
+struct PooledSocket {
+    socket: UdpSocket,
+    relay_address: IpAddr,
+}
+
+// Pool is shared by the generator and the sockets it handed out, which put
+// themselves back when dropped
+struct Pool {
+    free: Mutex<Vec<PooledSocket>>,
+    size: usize,
+    exhausted: AtomicU64,
+}
+
+impl Pool {
+    fn free(&self) -> std::sync::MutexGuard<'_, Vec<PooledSocket>> {
+        self.free.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+// PreallocatedRelayPool hands out relay sockets bound by the caller before
+// Server::new, so privileged ports can be bound as root and the privileges
+// dropped before the server starts. A socket goes back to the pool once the
+// allocation using it is deleted and its relay tasks are done, an Allocate
+// finding the pool empty is refused with a 508 (Insufficient Capacity).
+// The sockets are used as is, without the icmp and batch wrappers of the
+// other generators' relay sockets.
+pub struct PreallocatedRelayPool {
+    pool: Arc<Pool>,
+}
+
+impl fmt::Debug for PreallocatedRelayPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metrics = self.metrics();
+        f.debug_struct("PreallocatedRelayPool")
+            .field("size", &metrics.size)
+            .field("in_use", &metrics.in_use)
+            .finish()
+    }
+}
+
+impl PreallocatedRelayPool {
+    // new makes a pool of bound sockets, each with the IP advertised to
+    // clients as its relay address
+    pub fn new(sockets: Vec<(UdpSocket, IpAddr)>) -> Self {
+        let free: Vec<PooledSocket> = sockets
+            .into_iter()
+            .map(|(socket, relay_address)| PooledSocket {
+                socket,
+                relay_address,
+            })
+            .collect();
+        PreallocatedRelayPool {
+            pool: Arc::new(Pool {
+                size: free.len(),
+                free: Mutex::new(free),
+                exhausted: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn metrics(&self) -> RelayPoolMetrics {
+        RelayPoolMetrics {
+            size: self.pool.size,
+            in_use: self.pool.size - self.pool.free().len(),
+            exhausted: self.pool.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[async_trait]
+impl RelayAddressGenerator for PreallocatedRelayPool {
+    // validate confirms that the pool has sockets, bound with a relay address
+    // clients can send to
+    fn validate(&self) -> Result<(), Error> {
+        let free = self.pool.free();
+        if free.is_empty() {
+            return Err(Error::RelayPoolEmpty);
+        }
+        for pooled in free.iter() {
+            validate_relay_address(pooled.relay_address)?;
+            pooled.socket.local_addr()?;
+        }
+        Ok(())
+    }
+
+    // Allocate a free socket of the pool, the one bound on requested_port if
+    // it is not 0
+    async fn allocate_conn(
+        &self,
+        _network: &str,
+        requested_port: u16,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error> {
+        let pooled = {
+            let mut free = self.pool.free();
+            let index = if requested_port == 0 {
+                free.len().checked_sub(1)
+            } else {
+                free.iter().position(|pooled| {
+                    pooled
+                        .socket
+                        .local_addr()
+                        .is_ok_and(|addr| addr.port() == requested_port)
+                })
+            };
+            match index {
+                Some(index) => free.swap_remove(index),
+                None if requested_port == 0 => {
+                    self.pool.exhausted.fetch_add(1, Ordering::Relaxed);
+                    return Err(Error::RelayPoolExhausted);
+                }
+                None => return Err(io::Error::from(io::ErrorKind::AddrInUse).into()),
+            }
+        };
+
+        drain(&pooled.socket);
+        let mut relay_addr = pooled.socket.local_addr()?;
+        relay_addr.set_ip(pooled.relay_address);
+        let conn = PooledConn {
+            pooled: Some(pooled),
+            pool: Arc::clone(&self.pool),
+        };
+        Ok((Arc::new(conn), relay_addr))
+    }
+
+    fn port_capacity(&self) -> Option<usize> {
+        Some(self.pool.size)
+    }
+
+    fn pool_metrics(&self) -> Option<RelayPoolMetrics> {
+        Some(self.metrics())
+    }
+}
+
+// drain drops the datagrams peers of the socket's previous allocation sent
+// after it was deleted
+fn drain(socket: &UdpSocket) {
+    let mut buf = [0u8; 1];
+    while socket.try_recv_from(&mut buf).is_ok() {}
+}
+
+// PooledConn is a socket handed out by the pool, it goes back to the pool when
+// dropped
+struct PooledConn {
+    pooled: Option<PooledSocket>,
+    pool: Arc<Pool>,
+}
+
+impl PooledConn {
+    fn socket(&self) -> io::Result<&UdpSocket> {
+        self.pooled
+            .as_ref()
+            .map(|pooled| &pooled.socket)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if let Some(pooled) = self.pooled.take() {
+            self.pool.free().push(pooled);
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for PooledConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.socket()?.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket()?.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket()?.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket()?.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket()?.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket()?.local_addr()
+    }
+}
@@ -0,0 +1,108 @@
+use super::*;
+
+use tokio::time::Duration;
+
+async fn new_pool(n: usize) -> Result<(PreallocatedRelayPool, Vec<u16>), Error> {
+    let mut sockets = vec![];
+    let mut ports = vec![];
+    for _ in 0..n {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        ports.push(socket.local_addr()?.port());
+        sockets.push((socket, IpAddr::from([192, 0, 2, 1])));
+    }
+    Ok((PreallocatedRelayPool::new(sockets), ports))
+}
+
+#[tokio::test]
+async fn test_relay_pool_exhausted_and_reused() -> Result<(), Error> {
+    let (pool, mut ports) = new_pool(2).await?;
+    pool.validate()?;
+    assert_eq!(Some(2), pool.port_capacity());
+
+    let (first, first_addr) = pool.allocate_conn("udp4", 0).await?;
+    let (_second, second_addr) = pool.allocate_conn("udp4", 0).await?;
+    // the advertised IP, the bound port
+    assert_eq!(IpAddr::from([192, 0, 2, 1]), first_addr.ip());
+    let mut allocated = vec![first_addr.port(), second_addr.port()];
+    allocated.sort_unstable();
+    ports.sort_unstable();
+    assert_eq!(ports, allocated);
+
+    assert!(matches!(
+        pool.allocate_conn("udp4", 0).await,
+        Err(Error::RelayPoolExhausted)
+    ));
+    assert_eq!(
+        RelayPoolMetrics {
+            size: 2,
+            in_use: 2,
+            exhausted: 1,
+        },
+        pool.metrics()
+    );
+
+    // dropping the conn puts its socket back
+    drop(first);
+    assert_eq!(1, pool.metrics().in_use);
+    let (_third, third_addr) = pool.allocate_conn("udp4", 0).await?;
+    assert_eq!(first_addr, third_addr);
+    assert_eq!(Some(pool.metrics()), pool.pool_metrics());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_pool_requested_port() -> Result<(), Error> {
+    let (pool, ports) = new_pool(2).await?;
+
+    let (_conn, relay_addr) = pool.allocate_conn("udp4", ports[0]).await?;
+    assert_eq!(ports[0], relay_addr.port());
+
+    // in use, or not in the pool
+    for port in [ports[0], 9] {
+        let result = pool.allocate_conn("udp4", port).await;
+        assert!(
+            matches!(result, Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::AddrInUse),
+            "port {}",
+            port
+        );
+    }
+    assert_eq!(0, pool.metrics().exhausted);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_pool_drops_stale_datagrams() -> Result<(), Error> {
+    let (pool, ports) = new_pool(1).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    // a datagram sent to the socket after its allocation was deleted
+    let (conn, _) = pool.allocate_conn("udp4", 0).await?;
+    drop(conn);
+    peer.send_to(b"stale", ("127.0.0.1", ports[0])).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (conn, _) = pool.allocate_conn("udp4", 0).await?;
+    peer.send_to(b"fresh", ("127.0.0.1", ports[0])).await?;
+    let mut buf = [0u8; 16];
+    let (n, _) = conn.recv_from(&mut buf).await?;
+    assert_eq!(b"fresh", &buf[..n]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_pool_validate() -> Result<(), Error> {
+    let pool = PreallocatedRelayPool::new(vec![]);
+    assert!(matches!(pool.validate(), Err(Error::RelayPoolEmpty)));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let pool = PreallocatedRelayPool::new(vec![(socket, IpAddr::from([0, 0, 0, 0]))]);
+    assert!(matches!(
+        pool.validate(),
+        Err(Error::RelayAddressInvalid(_))
+    ));
+
+    Ok(())
+}
@@ -1,12 +1,12 @@
 use super::*;
-use crate::errors::*;
 
 use std::net::IpAddr;
-use tokio::net::UdpSocket;
 
 use async_trait::async_trait;
 
 // RelayAddressGeneratorRanges can be used to only allocate connections inside a defined port range
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RelayAddressGeneratorRanges {
     // relay_address is the IP returned to the user when the relay is created
     pub relay_address: IpAddr,
@@ -22,23 +22,44 @@ pub struct RelayAddressGeneratorRanges {
 
     // Address is passed to Listen/ListenPacket when creating the Relay
     pub address: String,
+
+    // bind_device binds the relay sockets to a network device (SO_BINDTODEVICE),
+    // only supported on Linux
+    pub bind_device: Option<String>,
+
+    // port_selection is how the ports of the range are picked
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_selection: PortSelection,
 }
 
 #[async_trait]
 impl RelayAddressGenerator for RelayAddressGeneratorRanges {
-    // validate confirms that the RelayAddressGenerator is properly initialized
+    // validate confirms that the RelayAddressGenerator is properly initialized,
+    // the range is sane, the relay address is unicast and a port of the range
+    // can be bound
     fn validate(&self) -> Result<(), Error> {
         if self.min_port == 0 {
-            Err(ERR_MIN_PORT_NOT_ZERO.to_owned())
+            return Err(Error::MinPortNotZero);
         } else if self.max_port == 0 {
-            Err(ERR_MAX_PORT_NOT_ZERO.to_owned())
+            return Err(Error::MaxPortNotZero);
         } else if self.max_port < self.min_port {
-            Err(ERR_MAX_PORT_LESS_THAN_MIN_PORT.to_owned())
-        } else if self.address.is_empty() {
-            Err(ERR_LISTENING_ADDRESS_INVALID.to_owned())
-        } else {
-            Ok(())
+            return Err(Error::MaxPortLessThanMinPort);
         }
+        validate_relay_address(self.relay_address)?;
+        validate_bind_device(&self.bind_device)?;
+        probe_bind(&self.address, self.random_port())
+    }
+
+    async fn probe(&self) -> Result<(), Error> {
+        probe_bind_hostname(&self.address, self.random_port()).await
+    }
+
+    fn port_capacity(&self) -> Option<usize> {
+        Some(usize::from(self.max_port.saturating_sub(self.min_port)) + 1)
+    }
+
+    fn port_selection(&self) -> Option<PortSelection> {
+        Some(self.port_selection)
     }
 
     // Allocate a PacketConn (UDP) relay_address
@@ -48,30 +69,44 @@ impl RelayAddressGenerator for RelayAddressGeneratorRanges {
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error> {
         let max_retries = if self.max_retries == 0 {
-            10
+            DEFAULT_MAX_RETRIES
         } else {
             self.max_retries
         };
 
         if requested_port != 0 {
-            let conn = UdpSocket::bind(format!("{}:{}", self.address, requested_port)).await?;
+            let conn =
+                bind_relay(&self.address, requested_port, self.bind_device.as_deref()).await?;
             let mut relay_addr = conn.local_addr()?;
             relay_addr.set_ip(self.relay_address);
-            return Ok((Arc::new(conn), relay_addr));
+            return Ok((relay_conn(conn), relay_addr));
         }
 
-        for _ in 0..max_retries {
-            let port = self.min_port + rand::random::<u16>() % (self.max_port + 1 - self.min_port);
-            let conn = match UdpSocket::bind(format!("{}:{}", self.address, port)).await {
-                Ok(conn) => conn,
-                Err(_) => continue,
-            };
+        let ports = self.min_port..=self.max_port;
+        let bind_device = self.bind_device.as_deref();
+        let conn = match self.port_selection {
+            PortSelection::Random => {
+                bind_random_port(&self.address, ports, max_retries, bind_device).await?
+            }
+            PortSelection::Sequential => {
+                bind_lowest_port(&self.address, ports, bind_device).await?
+            }
+        };
 
-            let mut relay_addr = conn.local_addr()?;
-            relay_addr.set_ip(self.relay_address);
-            return Ok((Arc::new(conn), relay_addr));
+        let mut relay_addr = conn.local_addr()?;
+        relay_addr.set_ip(self.relay_address);
+        Ok((relay_conn(conn), relay_addr))
+    }
+
+    fn set_bind_device(&mut self, bind_device: &str) {
+        if self.bind_device.is_none() {
+            self.bind_device = Some(bind_device.to_owned());
         }
+    }
+}
 
-        Err(ERR_MAX_RETRIES_EXCEEDED.to_owned())
+impl RelayAddressGeneratorRanges {
+    fn random_port(&self) -> u16 {
+        rand::thread_rng().gen_range(self.min_port..=self.max_port)
     }
 }
@@ -1,30 +1,42 @@
 use super::*;
-use crate::errors::*;
 
 use std::net::IpAddr;
-use tokio::net::UdpSocket;
 
 use async_trait::async_trait;
 
 // RelayAddressGeneratorStatic can be used to return static IP address each time a relay is created.
 // This can be used when you have a single static IP address that you want to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RelayAddressGeneratorStatic {
     // RelayAddress is the IP returned to the user when the relay is created
     pub relay_address: IpAddr,
 
     // Address is passed to Listen/ListenPacket when creating the Relay
     pub address: String,
+
+    // bind_device binds the relay sockets to a network device (SO_BINDTODEVICE),
+    // only supported on Linux
+    pub bind_device: Option<String>,
+
+    // port_selection is how the port of a relay is picked when the client
+    // doesn't request one
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_selection: PortSelection,
 }
 
 #[async_trait]
 impl RelayAddressGenerator for RelayAddressGeneratorStatic {
-    // validate confirms that the RelayAddressGenerator is properly initialized
+    // validate confirms that the RelayAddressGenerator is properly initialized,
+    // the relay address is unicast and the address can be bound
     fn validate(&self) -> Result<(), Error> {
-        if self.address.is_empty() {
-            Err(ERR_LISTENING_ADDRESS_INVALID.to_owned())
-        } else {
-            Ok(())
-        }
+        validate_relay_address(self.relay_address)?;
+        validate_bind_device(&self.bind_device)?;
+        probe_bind(&self.address, 0)
+    }
+
+    async fn probe(&self) -> Result<(), Error> {
+        probe_bind_hostname(&self.address, 0).await
     }
 
     // Allocate a PacketConn (UDP) RelayAddress
@@ -33,9 +45,24 @@ impl RelayAddressGenerator for RelayAddressGeneratorStatic {
         _network: &str,
         requested_port: u16,
     ) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), Error> {
-        let conn = UdpSocket::bind(format!("{}:{}", self.address, requested_port)).await?;
+        let bind_device = self.bind_device.as_deref();
+        let conn = if requested_port == 0 {
+            bind_unranged_port(&self.address, self.port_selection, bind_device).await?
+        } else {
+            bind_relay(&self.address, requested_port, bind_device).await?
+        };
         let mut relay_addr = conn.local_addr()?;
         relay_addr.set_ip(self.relay_address);
-        return Ok((Arc::new(conn), relay_addr));
+        return Ok((relay_conn(conn), relay_addr));
+    }
+
+    fn port_selection(&self) -> Option<PortSelection> {
+        Some(self.port_selection)
+    }
+
+    fn set_bind_device(&mut self, bind_device: &str) {
+        if self.bind_device.is_none() {
+            self.bind_device = Some(bind_device.to_owned());
+        }
     }
 }
@@ -0,0 +1,217 @@
+use super::relay_none::*;
+use super::relay_range::*;
+use super::relay_static::*;
+use super::*;
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+#[ignore = "binding to a device requires CAP_NET_RAW"]
+async fn test_bind_relay_sets_bind_device() -> Result<(), Error> {
+    let conn = bind_relay("127.0.0.1", 0, Some("lo")).await?;
+    let device = socket2::SockRef::from(&conn).device()?;
+    assert_eq!(Some(b"lo".to_vec()), device);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_allocate_conn_bind_device_failure() -> Result<(), Error> {
+    let generator = RelayAddressGeneratorNone {
+        address: "127.0.0.1".to_owned(),
+        bind_device: Some("turn-no-such0".to_owned()),
+        port_selection: PortSelection::default(),
+    };
+    generator.validate()?;
+
+    // without the device, or the privilege to bind to it, the allocation fails
+    let result = generator.allocate_conn("udp4", 0).await;
+    assert!(
+        matches!(result, Err(Error::BindDevice { ref device, .. }) if device == "turn-no-such0"),
+        "expected BindDevice error"
+    );
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+#[tokio::test]
+async fn test_bind_device_unsupported() -> Result<(), Error> {
+    let generator = RelayAddressGeneratorNone {
+        address: "127.0.0.1".to_owned(),
+        bind_device: Some("lo0".to_owned()),
+        port_selection: PortSelection::default(),
+    };
+    assert!(matches!(
+        generator.validate(),
+        Err(Error::BindDeviceUnsupported)
+    ));
+    assert!(matches!(
+        generator.allocate_conn("udp4", 0).await,
+        Err(Error::BindDeviceUnsupported)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_set_bind_device_keeps_generator_device() {
+    let mut generator = RelayAddressGeneratorNone {
+        address: "127.0.0.1".to_owned(),
+        bind_device: None,
+        port_selection: PortSelection::default(),
+    };
+    generator.set_bind_device("eth0");
+    assert_eq!(Some("eth0"), generator.bind_device.as_deref());
+
+    generator.set_bind_device("eth1");
+    assert_eq!(Some("eth0"), generator.bind_device.as_deref());
+}
+
+// allocate_ports allocates n relays with generator, keeping them bound so
+// each port is fresh, and returns their ports
+async fn allocate_ports(
+    generator: &dyn RelayAddressGenerator,
+    n: usize,
+) -> Result<Vec<u16>, Error> {
+    let mut conns = vec![];
+    let mut ports = vec![];
+    for _ in 0..n {
+        let (conn, relay_addr) = generator.allocate_conn("udp4", 0).await?;
+        conns.push(conn);
+        ports.push(relay_addr.port());
+    }
+    Ok(ports)
+}
+
+fn ranges(port_selection: PortSelection) -> RelayAddressGeneratorRanges {
+    RelayAddressGeneratorRanges {
+        relay_address: IpAddr::from([127, 0, 0, 1]),
+        min_port: 20000,
+        max_port: 60000,
+        max_retries: 0,
+        address: "127.0.0.1".to_owned(),
+        bind_device: None,
+        port_selection,
+    }
+}
+
+// Random ports don't follow each other: the ports are neither sorted nor
+// reverse sorted, and about half of the neighbours are ascending
+#[tokio::test]
+async fn test_ranges_random_ports_are_not_monotonic() -> Result<(), Error> {
+    let generator = ranges(PortSelection::Random);
+    let ports = allocate_ports(&generator, 200).await?;
+
+    assert!(ports.iter().all(|port| (20000..=60000).contains(port)));
+    let ascending = ports.windows(2).filter(|w| w[0] < w[1]).count();
+    let descending = ports.windows(2).filter(|w| w[0] > w[1]).count();
+    assert!(ascending > 0 && descending > 0, "{:?}", ports);
+    // 199 pairs, a uniform pick ascends with p = 0.5, sigma ~ 7
+    assert!((60..=140).contains(&ascending), "{} ascending", ascending);
+
+    let mut longest_run = 1;
+    let mut run = 1;
+    for w in ports.windows(2) {
+        run = if w[0] < w[1] { run + 1 } else { 1 };
+        longest_run = longest_run.max(run);
+    }
+    assert!(longest_run < 20, "ascending run of {}", longest_run);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ranges_sequential_ports() -> Result<(), Error> {
+    let generator = ranges(PortSelection::Sequential);
+    let ports = allocate_ports(&generator, 20).await?;
+
+    assert!(ports.windows(2).all(|w| w[0] < w[1]), "{:?}", ports);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unranged_random_ports_are_dynamic() -> Result<(), Error> {
+    let generator = RelayAddressGeneratorNone {
+        address: "127.0.0.1".to_owned(),
+        bind_device: None,
+        port_selection: PortSelection::Random,
+    };
+    let ports = allocate_ports(&generator, 20).await?;
+
+    assert!(
+        ports.iter().all(|port| DYNAMIC_PORTS.contains(port)),
+        "{:?}",
+        ports
+    );
+
+    // a requested port is bound as is
+    let requested = bind_relay("127.0.0.1", 0, None).await?.local_addr()?.port();
+    let (_conn, relay_addr) = generator.allocate_conn("udp4", requested).await?;
+    assert_eq!(requested, relay_addr.port());
+
+    Ok(())
+}
+
+#[test]
+fn test_port_selection_is_reported() {
+    assert_eq!(PortSelection::Random, PortSelection::default());
+
+    let generator = ranges(PortSelection::default());
+    assert_eq!(Some(PortSelection::Random), generator.port_selection());
+    assert!(format!("{:?}", generator).contains("port_selection: Random"));
+
+    let generator = ranges(PortSelection::Sequential);
+    assert_eq!(Some(PortSelection::Sequential), generator.port_selection());
+    assert!(format!("{:?}", generator).contains("port_selection: Sequential"));
+}
+
+// Validation accepts the hostnames allocation binds
+#[tokio::test]
+async fn test_ranges_and_static_accept_hostname() -> Result<(), Error> {
+    let mut ranges = ranges(PortSelection::default());
+    ranges.address = "localhost".to_owned();
+    let generators: [Box<dyn RelayAddressGenerator + Send + Sync>; 2] = [
+        Box::new(ranges),
+        Box::new(RelayAddressGeneratorStatic {
+            relay_address: IpAddr::from([127, 0, 0, 1]),
+            address: "localhost".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+    ];
+    for generator in generators {
+        generator.validate()?;
+        generator.probe().await?;
+        generator.allocate_conn("udp4", 0).await?;
+    }
+
+    for address in [
+        "not an ip",
+        "",
+        "0.0.0",
+        "256.0.0.1",
+        "-host",
+        "host..local",
+    ] {
+        assert!(
+            matches!(probe_bind(address, 0), Err(Error::ListeningAddressInvalid)),
+            "{:?}",
+            address
+        );
+    }
+    assert!(matches!(
+        bind_relay("not an ip", 0, None).await,
+        Err(Error::ListeningAddressInvalid)
+    ));
+
+    // validate doesn't resolve hostnames, probe does (.invalid never resolves)
+    probe_bind("relay.invalid", 0)?;
+    assert!(matches!(
+        probe_bind_hostname("relay.invalid", 0).await,
+        Err(Error::ListeningAddressInvalid)
+    ));
+
+    Ok(())
+}
@@ -1,13 +1,111 @@
+#[cfg(test)]
+mod config_test;
+pub mod file;
+
+pub use crate::allocation::relay_workers::RelayReadMode;
 use crate::auth::*;
-use crate::errors::*;
+use crate::error::Error;
+use crate::relay::relay_none::RelayAddressGeneratorNone;
 use crate::relay::*;
+use crate::server::event::*;
+pub use crate::server::interceptor::InterceptPoint;
+use crate::server::interceptor::RequestInterceptor;
 
-use util::{Conn, Error};
+use stun::error_code::*;
+use util::Conn;
 
 use tokio::time::Duration;
 
+use std::fmt;
 use std::sync::Arc;
 
+// MAX_REALM_LENGTH is the limit on REALM characters, RFC 5389 Section 15.7
+pub const MAX_REALM_LENGTH: usize = 127;
+
+// MAX_USERNAME_LEN is the limit on USERNAME bytes, RFC 5389 Section 15.3
+pub const MAX_USERNAME_LEN: usize = 513;
+
+// channel_bind_timeout bounds, zero is allowed and selects the default
+pub const MIN_CHANNEL_BIND_TIMEOUT: Duration = Duration::from_secs(1);
+pub const MAX_CHANNEL_BIND_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// MAX_LIFETIME_JITTER is the largest fraction lifetime_jitter may perturb
+// the granted lifetime by
+pub const MAX_LIFETIME_JITTER: f64 = 0.5;
+
+// UsernameValidator is called with the USERNAME of each authenticated request,
+// returning false rejects the request before the auth handler is called
+pub type UsernameValidator = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+// MaxAllocationsCode selects the error an Allocate is rejected with once the
+// server holds max_allocations
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MaxAllocationsCode {
+    // 486 Allocation Quota Reached, clients usually retry the same server later
+    #[default]
+    AllocationQuotaReached,
+    // 508 Insufficient Capacity, clients usually try another server
+    InsufficientCapacity,
+}
+
+impl From<MaxAllocationsCode> for ErrorCode {
+    fn from(code: MaxAllocationsCode) -> Self {
+        match code {
+            MaxAllocationsCode::AllocationQuotaReached => CODE_ALLOC_QUOTA_REACHED,
+            MaxAllocationsCode::InsufficientCapacity => CODE_INSUFFICIENT_CAPACITY,
+        }
+    }
+}
+
+// LoadFactorConfig weighs the components of Server::load_factor. A component
+// counts only when its cap is known and its weight is positive, the factor
+// is their weighted mean, or 1.0 once any of them reaches its cap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct LoadFactorConfig {
+    // allocation_weight weighs the allocations against max_allocations
+    pub allocation_weight: f64,
+    // port_weight weighs the relay ports in use against the ports of the
+    // listeners whose RelayAddressGenerator has a port range
+    pub port_weight: f64,
+    // bandwidth_weight weighs the listeners' traffic against bandwidth_budget
+    pub bandwidth_weight: f64,
+    // bandwidth_budget is the bytes per second, in and out, the server is
+    // meant to carry. Defaults to none, leaving bandwidth out of the factor.
+    pub bandwidth_budget: Option<u64>,
+}
+
+impl Default for LoadFactorConfig {
+    fn default() -> Self {
+        LoadFactorConfig {
+            allocation_weight: 1.0,
+            port_weight: 1.0,
+            bandwidth_weight: 1.0,
+            bandwidth_budget: None,
+        }
+    }
+}
+
+impl LoadFactorConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        for weight in [
+            self.allocation_weight,
+            self.port_weight,
+            self.bandwidth_weight,
+        ] {
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(Error::LoadFactorWeightInvalid(weight));
+            }
+        }
+        if self.bandwidth_budget == Some(0) {
+            return Err(Error::BandwidthBudgetZero);
+        }
+        Ok(())
+    }
+}
+
 // ConnConfig is used for UDP listeners
 pub struct ConnConfig {
     pub conn: Arc<dyn Conn + Send + Sync>,
@@ -15,12 +113,56 @@ pub struct ConnConfig {
     // When an allocation is generated the RelayAddressGenerator
     // creates the net.PacketConn and returns the IP/Port it is available at
     pub relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+
+    // bind_device binds the relay sockets of this listener to a network
+    // device (SO_BINDTODEVICE) when the RelayAddressGenerator has none set.
+    // Only supported on Linux.
+    pub bind_device: Option<String>,
+
+    // stun_only makes this a plain STUN listener: Binding requests are
+    // answered and every TURN request is rejected with 403 (Forbidden). The
+    // relay_addr_generator is never used.
+    pub stun_only: bool,
+}
+
+impl fmt::Debug for ConnConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnConfig")
+            .field("local_addr", &self.conn.local_addr().ok())
+            .field("bind_device", &self.bind_device)
+            .field("stun_only", &self.stun_only)
+            .field(
+                "port_selection",
+                &self.relay_addr_generator.port_selection(),
+            )
+            .finish_non_exhaustive()
+    }
 }
 
 impl ConnConfig {
     pub fn validate(&self) -> Result<(), Error> {
+        validate_bind_device(&self.bind_device)?;
+        if self.stun_only {
+            return Ok(());
+        }
         self.relay_addr_generator.validate()
     }
+
+    // probe runs the relay address generator's probe, see
+    // RelayAddressGenerator::probe
+    pub async fn probe(&self) -> Result<(), Error> {
+        if self.stun_only {
+            return Ok(());
+        }
+        self.relay_addr_generator.probe().await
+    }
+
+    // listener names the listener in errors, by its address or else its index
+    fn listener(&self, index: usize) -> String {
+        self.conn
+            .local_addr()
+            .map_or_else(|_| format!("#{}", index), |addr| addr.to_string())
+    }
 }
 
 // ServerConfig configures the Pion TURN Server
@@ -37,17 +179,385 @@ pub struct ServerConfig {
 
     // channel_bind_timeout sets the lifetime of channel binding. Defaults to 10 minutes.
     pub channel_bind_timeout: Duration,
+
+    // relay_queue_size is the number of datagrams queued in each direction of
+    // an allocation before new ones are dropped. Defaults to 64.
+    pub relay_queue_size: usize,
+
+    // relay_read_mode selects how relay sockets are read. Defaults to a task
+    // per allocation.
+    pub relay_read_mode: RelayReadMode,
+
+    // lifetime_jitter perturbs the LIFETIME granted to each allocation by up to
+    // this fraction either way, e.g. 0.1 for ±10%, so clients that allocated
+    // together don't refresh together. Defaults to off.
+    pub lifetime_jitter: Option<f64>,
+
+    // max_allocations caps the allocations over all listeners, Allocate
+    // requests over it are rejected with max_allocations_code. Defaults to no
+    // limit.
+    pub max_allocations: Option<usize>,
+    pub max_allocations_code: MaxAllocationsCode,
+
+    // event_handler is notified of ServerEvents, e.g. reaching max_allocations
+    pub event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+
+    // username_validator and max_username_len reject malformed USERNAMEs with
+    // 401 (Unauthorized) before the auth handler is called. max_username_len
+    // defaults to the STUN maximum of 513 bytes.
+    pub username_validator: Option<UsernameValidator>,
+    pub max_username_len: usize,
+
+    // bind_nonce_to_client_ip ties each NONCE to the IP it was issued to, a
+    // request presenting it from another IP is answered with 438 (Stale Nonce)
+    // and a fresh nonce. Clients behind a NAT that changes their IP simply
+    // re-challenge. Defaults to off.
+    pub bind_nonce_to_client_ip: bool,
+
+    // interceptor sees each request before it is handled and may reject or
+    // drop it, intercept_point selects whether requests with credentials are
+    // intercepted before or after authentication. Defaults to no interceptor.
+    pub interceptor: Option<Arc<dyn RequestInterceptor + Send + Sync>>,
+    pub intercept_point: InterceptPoint,
+
+    // stun_only_binding answers Binding requests from clients that never
+    // allocated, so ICE can gather server reflexive candidates from the TURN
+    // server. Without it only clients with an allocation get an answer.
+    // Binding requests are never authenticated. Defaults to on.
+    pub stun_only_binding: bool,
+
+    // max_pending_challenges caps the nonces handed out and not yet expired,
+    // which unauthenticated requests can make the server issue. Once reached
+    // the oldest nonce is evicted. Defaults to DEFAULT_MAX_PENDING_CHALLENGES.
+    pub max_pending_challenges: usize,
+
+    // load_factor weighs the components of Server::load_factor
+    pub load_factor: LoadFactorConfig,
+}
+
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("conn_configs", &self.conn_configs)
+            .field("realm", &self.realm)
+            .field("auth_handler", &REDACTED)
+            .field("channel_bind_timeout", &self.channel_bind_timeout)
+            .field("relay_queue_size", &self.relay_queue_size)
+            .field("relay_read_mode", &self.relay_read_mode)
+            .field("lifetime_jitter", &self.lifetime_jitter)
+            .field("max_allocations", &self.max_allocations)
+            .field("max_allocations_code", &self.max_allocations_code)
+            .field("event_handler", &self.event_handler.is_some())
+            .field("username_validator", &self.username_validator.is_some())
+            .field("max_username_len", &self.max_username_len)
+            .field("bind_nonce_to_client_ip", &self.bind_nonce_to_client_ip)
+            .field("interceptor", &self.interceptor.is_some())
+            .field("intercept_point", &self.intercept_point)
+            .field("stun_only_binding", &self.stun_only_binding)
+            .field("max_pending_challenges", &self.max_pending_challenges)
+            .field("load_factor", &self.load_factor)
+            .finish()
+    }
 }
 
 impl ServerConfig {
+    // builder returns a ServerConfigBuilder, which validates the config on build()
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
     pub fn validate(&self) -> Result<(), Error> {
         if self.conn_configs.is_empty() {
-            return Err(ERR_NO_AVAILABLE_CONNS.to_owned());
+            return Err(Error::NoAvailableConns);
         }
 
-        for cc in &self.conn_configs {
-            cc.validate()?;
+        for (i, cc) in self.conn_configs.iter().enumerate() {
+            cc.validate().map_err(|err| Error::ListenerInvalid {
+                listener: cc.listener(i),
+                err: Box::new(err),
+            })?;
         }
+
+        validate_realm(&self.realm)?;
+
+        if self.channel_bind_timeout != Duration::from_secs(0)
+            && (self.channel_bind_timeout < MIN_CHANNEL_BIND_TIMEOUT
+                || self.channel_bind_timeout > MAX_CHANNEL_BIND_TIMEOUT)
+        {
+            return Err(Error::ChannelBindTimeoutInvalid(self.channel_bind_timeout));
+        }
+
+        if let Some(lifetime_jitter) = self.lifetime_jitter {
+            if !(0.0..=MAX_LIFETIME_JITTER).contains(&lifetime_jitter) {
+                return Err(Error::LifetimeJitterInvalid(lifetime_jitter));
+            }
+        }
+
+        if self.max_allocations == Some(0) {
+            return Err(Error::MaxAllocationsZero);
+        }
+
+        if self.max_username_len > MAX_USERNAME_LEN {
+            return Err(Error::MaxUsernameLenInvalid(self.max_username_len));
+        }
+
+        self.load_factor.validate()?;
+
         Ok(())
     }
+
+    // probe runs the probe of every listener, Server::new awaits it after
+    // validate
+    pub async fn probe(&self) -> Result<(), Error> {
+        for (i, cc) in self.conn_configs.iter().enumerate() {
+            cc.probe().await.map_err(|err| Error::ListenerInvalid {
+                listener: cc.listener(i),
+                err: Box::new(err),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// validate_realm checks the realm can be carried in a REALM attribute,
+// which is a quoted-string of less than 128 characters
+fn validate_realm(realm: &str) -> Result<(), Error> {
+    if realm.is_empty() {
+        return Err(Error::RealmInvalid("realm is empty".to_owned()));
+    }
+
+    if realm.chars().count() > MAX_REALM_LENGTH {
+        return Err(Error::RealmInvalid(format!(
+            "realm is longer than {} characters",
+            MAX_REALM_LENGTH
+        )));
+    }
+
+    if let Some(c) = realm
+        .chars()
+        .find(|c| c.is_control() || *c == '"' || *c == '\\')
+    {
+        return Err(Error::RealmInvalid(format!(
+            "realm contains illegal character {:?}",
+            c
+        )));
+    }
+
+    Ok(())
+}
+
+// ServerConfigBuilder builds a validated ServerConfig
+#[derive(Default)]
+pub struct ServerConfigBuilder {
+    conn_configs: Vec<ConnConfig>,
+    realm: String,
+    auth_handler: Option<Arc<Box<dyn AuthHandler + Send + Sync>>>,
+    channel_bind_timeout: Duration,
+    relay_queue_size: usize,
+    relay_read_mode: RelayReadMode,
+    lifetime_jitter: Option<f64>,
+    max_allocations: Option<usize>,
+    max_allocations_code: MaxAllocationsCode,
+    event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    username_validator: Option<UsernameValidator>,
+    max_username_len: usize,
+    bind_nonce_to_client_ip: bool,
+    interceptor: Option<Arc<dyn RequestInterceptor + Send + Sync>>,
+    intercept_point: InterceptPoint,
+    stun_only_binding: Option<bool>,
+    max_pending_challenges: usize,
+    load_factor: LoadFactorConfig,
+}
+
+impl fmt::Debug for ServerConfigBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfigBuilder")
+            .field("conn_configs", &self.conn_configs)
+            .field("realm", &self.realm)
+            .field(
+                "auth_handler",
+                &self.auth_handler.as_ref().map(|_| REDACTED),
+            )
+            .field("channel_bind_timeout", &self.channel_bind_timeout)
+            .field("relay_queue_size", &self.relay_queue_size)
+            .field("relay_read_mode", &self.relay_read_mode)
+            .field("lifetime_jitter", &self.lifetime_jitter)
+            .field("max_allocations", &self.max_allocations)
+            .field("max_allocations_code", &self.max_allocations_code)
+            .field("event_handler", &self.event_handler.is_some())
+            .field("username_validator", &self.username_validator.is_some())
+            .field("max_username_len", &self.max_username_len)
+            .field("bind_nonce_to_client_ip", &self.bind_nonce_to_client_ip)
+            .field("interceptor", &self.interceptor.is_some())
+            .field("intercept_point", &self.intercept_point)
+            .field("stun_only_binding", &self.stun_only_binding)
+            .field("max_pending_challenges", &self.max_pending_challenges)
+            .field("load_factor", &self.load_factor)
+            .finish()
+    }
+}
+
+impl ServerConfigBuilder {
+    // add_conn adds a turn listener, relays are created with relay_addr_generator
+    pub fn add_conn(
+        mut self,
+        conn: Arc<dyn Conn + Send + Sync>,
+        relay_addr_generator: Box<dyn RelayAddressGenerator + Send + Sync>,
+    ) -> Self {
+        self.conn_configs.push(ConnConfig {
+            conn,
+            relay_addr_generator,
+            bind_device: None,
+            stun_only: false,
+        });
+        self
+    }
+
+    // add_conn_config adds a turn listener configured as a whole, e.g. with
+    // bind_device set
+    pub fn add_conn_config(mut self, conn_config: ConnConfig) -> Self {
+        self.conn_configs.push(conn_config);
+        self
+    }
+
+    // add_stun_only_conn adds a listener that only answers STUN Binding
+    // requests, see ConnConfig::stun_only
+    pub fn add_stun_only_conn(mut self, conn: Arc<dyn Conn + Send + Sync>) -> Self {
+        self.conn_configs.push(ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+                address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+            bind_device: None,
+            stun_only: true,
+        });
+        self
+    }
+
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    pub fn auth_handler(mut self, auth_handler: Box<dyn AuthHandler + Send + Sync>) -> Self {
+        self.auth_handler = Some(Arc::new(auth_handler));
+        self
+    }
+
+    // channel_bind_timeout of zero selects the default of 10 minutes
+    pub fn channel_bind_timeout(mut self, channel_bind_timeout: Duration) -> Self {
+        self.channel_bind_timeout = channel_bind_timeout;
+        self
+    }
+
+    // relay_queue_size of zero selects the default of 64 datagrams
+    pub fn relay_queue_size(mut self, relay_queue_size: usize) -> Self {
+        self.relay_queue_size = relay_queue_size;
+        self
+    }
+
+    pub fn relay_read_mode(mut self, relay_read_mode: RelayReadMode) -> Self {
+        self.relay_read_mode = relay_read_mode;
+        self
+    }
+
+    // lifetime_jitter is a fraction of the granted lifetime, at most
+    // MAX_LIFETIME_JITTER
+    pub fn lifetime_jitter(mut self, lifetime_jitter: f64) -> Self {
+        self.lifetime_jitter = Some(lifetime_jitter);
+        self
+    }
+
+    pub fn max_allocations(mut self, max_allocations: usize) -> Self {
+        self.max_allocations = Some(max_allocations);
+        self
+    }
+
+    pub fn max_allocations_code(mut self, max_allocations_code: MaxAllocationsCode) -> Self {
+        self.max_allocations_code = max_allocations_code;
+        self
+    }
+
+    pub fn event_handler(mut self, event_handler: Box<dyn EventHandler + Send + Sync>) -> Self {
+        self.event_handler = Some(Arc::from(event_handler));
+        self
+    }
+
+    // username_validator is called with the USERNAME of each authenticated
+    // request, e.g. to check the format before the auth handler is consulted
+    pub fn username_validator(
+        mut self,
+        username_validator: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.username_validator = Some(Arc::new(username_validator));
+        self
+    }
+
+    // max_username_len of zero selects the default of MAX_USERNAME_LEN bytes
+    pub fn max_username_len(mut self, max_username_len: usize) -> Self {
+        self.max_username_len = max_username_len;
+        self
+    }
+
+    pub fn bind_nonce_to_client_ip(mut self, bind_nonce_to_client_ip: bool) -> Self {
+        self.bind_nonce_to_client_ip = bind_nonce_to_client_ip;
+        self
+    }
+
+    pub fn interceptor(mut self, interceptor: Box<dyn RequestInterceptor + Send + Sync>) -> Self {
+        self.interceptor = Some(Arc::from(interceptor));
+        self
+    }
+
+    pub fn intercept_point(mut self, intercept_point: InterceptPoint) -> Self {
+        self.intercept_point = intercept_point;
+        self
+    }
+
+    pub fn stun_only_binding(mut self, stun_only_binding: bool) -> Self {
+        self.stun_only_binding = Some(stun_only_binding);
+        self
+    }
+
+    // max_pending_challenges of zero selects the default of
+    // DEFAULT_MAX_PENDING_CHALLENGES nonces
+    pub fn max_pending_challenges(mut self, max_pending_challenges: usize) -> Self {
+        self.max_pending_challenges = max_pending_challenges;
+        self
+    }
+
+    pub fn load_factor(mut self, load_factor: LoadFactorConfig) -> Self {
+        self.load_factor = load_factor;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, Error> {
+        let auth_handler = self.auth_handler.ok_or(Error::AuthHandlerUnset)?;
+
+        let config = ServerConfig {
+            conn_configs: self.conn_configs,
+            realm: self.realm,
+            auth_handler,
+            channel_bind_timeout: self.channel_bind_timeout,
+            relay_queue_size: self.relay_queue_size,
+            relay_read_mode: self.relay_read_mode,
+            lifetime_jitter: self.lifetime_jitter,
+            max_allocations: self.max_allocations,
+            max_allocations_code: self.max_allocations_code,
+            event_handler: self.event_handler,
+            username_validator: self.username_validator,
+            max_username_len: self.max_username_len,
+            bind_nonce_to_client_ip: self.bind_nonce_to_client_ip,
+            interceptor: self.interceptor,
+            intercept_point: self.intercept_point,
+            stun_only_binding: self.stun_only_binding.unwrap_or(true),
+            max_pending_challenges: self.max_pending_challenges,
+            load_factor: self.load_factor,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
 }
@@ -0,0 +1,431 @@
+use super::*;
+use crate::relay::relay_none::*;
+use crate::relay::relay_range::*;
+use crate::relay::relay_static::*;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+
+struct TestAuthHandler;
+impl AuthHandler for TestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(generate_auth_key(username, realm, "pass"))
+    }
+}
+
+fn new_test_generator(address: &str) -> Box<dyn RelayAddressGenerator + Send + Sync> {
+    Box::new(RelayAddressGeneratorStatic {
+        relay_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        address: address.to_owned(),
+        bind_device: None,
+        port_selection: PortSelection::default(),
+    })
+}
+
+async fn new_test_builder() -> Result<ServerConfigBuilder, Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+
+    Ok(ServerConfig::builder()
+        .add_conn(conn, new_test_generator("0.0.0.0"))
+        .realm("webrtc.rs")
+        .auth_handler(Box::new(TestAuthHandler {})))
+}
+
+#[tokio::test]
+async fn test_server_config_builder() -> Result<(), Error> {
+    let config = new_test_builder()
+        .await?
+        .channel_bind_timeout(Duration::from_secs(300))
+        .relay_queue_size(16)
+        .bind_nonce_to_client_ip(true)
+        .build()?;
+
+    assert_eq!(1, config.conn_configs.len());
+    assert_eq!("webrtc.rs", config.realm);
+    assert_eq!(Duration::from_secs(300), config.channel_bind_timeout);
+    assert_eq!(16, config.relay_queue_size);
+    assert!(config.bind_nonce_to_client_ip);
+    assert!(config.stun_only_binding);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_no_conns() -> Result<(), Error> {
+    let result = ServerConfig::builder()
+        .realm("webrtc.rs")
+        .auth_handler(Box::new(TestAuthHandler {}))
+        .build();
+    assert!(
+        matches!(result, Err(Error::NoAvailableConns)),
+        "expected NoAvailableConns error"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_no_auth_handler() -> Result<(), Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+
+    let result = ServerConfig::builder()
+        .add_conn(conn, new_test_generator("0.0.0.0"))
+        .realm("webrtc.rs")
+        .build();
+    assert!(
+        matches!(result, Err(Error::AuthHandlerUnset)),
+        "expected AuthHandlerUnset error"
+    );
+
+    Ok(())
+}
+
+// listener_error is the error of the listener that failed validation
+fn listener_error(result: Result<ServerConfig, Error>, listener: SocketAddr) -> Error {
+    match result {
+        Err(Error::ListenerInvalid {
+            listener: name,
+            err,
+        }) => {
+            assert_eq!(listener.to_string(), name);
+            *err
+        }
+        result => panic!("expected ListenerInvalid error, got {:?}", result.err()),
+    }
+}
+
+#[tokio::test]
+async fn test_server_config_invalid_relay_address() -> Result<(), Error> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let listener = conn.local_addr()?;
+
+    for address in &["", "256.0.0.1", "not an ip"] {
+        let result = new_test_builder()
+            .await?
+            .add_conn(Arc::clone(&conn), new_test_generator(address))
+            .build();
+        assert!(
+            matches!(
+                listener_error(result, listener),
+                Error::ListeningAddressInvalid
+            ),
+            "expected ListeningAddressInvalid error for {:?}",
+            address
+        );
+    }
+
+    // 192.0.2.1 is TEST-NET-1, not an address of this host
+    let result = new_test_builder()
+        .await?
+        .add_conn(Arc::clone(&conn), new_test_generator("192.0.2.1"))
+        .build();
+    assert!(
+        matches!(listener_error(result, listener), Error::RelayBind { ref address, .. } if address == "192.0.2.1"),
+        "expected RelayBind error"
+    );
+
+    for relay_address in &["0.0.0.0", "224.0.0.1", "255.255.255.255", "::"] {
+        let result = new_test_builder()
+            .await?
+            .add_conn(
+                Arc::clone(&conn),
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: relay_address.parse()?,
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .build();
+        assert!(
+            matches!(
+                listener_error(result, listener),
+                Error::RelayAddressInvalid(_)
+            ),
+            "expected RelayAddressInvalid error for {}",
+            relay_address
+        );
+    }
+
+    let result = new_test_builder()
+        .await?
+        .add_conn(
+            Arc::clone(&conn),
+            Box::new(RelayAddressGeneratorRanges {
+                relay_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                min_port: 50000,
+                max_port: 40000,
+                max_retries: 10,
+                address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+        )
+        .build();
+    assert!(
+        matches!(
+            listener_error(result, listener),
+            Error::MaxPortLessThanMinPort
+        ),
+        "expected MaxPortLessThanMinPort error"
+    );
+
+    let config = new_test_builder()
+        .await?
+        .add_conn(
+            conn,
+            Box::new(RelayAddressGeneratorRanges {
+                relay_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                min_port: 1,
+                max_port: u16::MAX,
+                max_retries: 10,
+                address: "127.0.0.1".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+        )
+        .build()?;
+    assert_eq!(2, config.conn_configs.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_none_relay_hostname() -> Result<(), Error> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let listener = conn.local_addr()?;
+    let none = |address: &str| {
+        Box::new(RelayAddressGeneratorNone {
+            address: address.to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        })
+    };
+
+    new_test_builder()
+        .await?
+        .add_conn(Arc::clone(&conn), none("localhost"))
+        .build()?;
+
+    for address in &["", "not an ip"] {
+        let result = new_test_builder()
+            .await?
+            .add_conn(Arc::clone(&conn), none(address))
+            .build();
+        assert!(
+            matches!(
+                listener_error(result, listener),
+                Error::ListeningAddressInvalid
+            ),
+            "expected ListeningAddressInvalid error for {:?}",
+            address
+        );
+    }
+
+    // 192.0.2.1 is TEST-NET-1, not an address of this host
+    let result = new_test_builder()
+        .await?
+        .add_conn(conn, none("192.0.2.1"))
+        .build();
+    assert!(
+        matches!(listener_error(result, listener), Error::RelayBind { ref address, .. } if address == "192.0.2.1"),
+        "expected RelayBind error"
+    );
+
+    Ok(())
+}
+
+// A hostname passes validate and is resolved by Server::new
+#[tokio::test]
+async fn test_server_new_probes_relay_hostname() -> Result<(), Error> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let listener = conn.local_addr()?;
+
+    let config = new_test_builder()
+        .await?
+        .add_conn(conn, new_test_generator("relay.invalid"))
+        .build()?;
+    let err = crate::server::Server::new(config)
+        .await
+        .err()
+        .expect("expected Server::new to fail");
+    assert!(
+        matches!(
+            listener_error(Err(err), listener),
+            Error::ListeningAddressInvalid
+        ),
+        "expected ListeningAddressInvalid error"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_invalid_realm() -> Result<(), Error> {
+    let long_realm = "a".repeat(MAX_REALM_LENGTH + 1);
+    let tests = vec![
+        ("empty", ""),
+        ("too long", long_realm.as_str()),
+        ("control character", "webrtc\n.rs"),
+        ("quote", "webrtc\".rs"),
+        ("backslash", "webrtc\\.rs"),
+    ];
+
+    for (name, realm) in tests {
+        let result = new_test_builder().await?.realm(realm).build();
+        assert!(
+            matches!(result, Err(Error::RealmInvalid(_))),
+            "{}: expected RealmInvalid error",
+            name
+        );
+    }
+
+    let max_realm = "ä".repeat(MAX_REALM_LENGTH);
+    new_test_builder().await?.realm(&max_realm).build()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_channel_bind_timeout() -> Result<(), Error> {
+    for timeout in &[Duration::from_millis(500), Duration::from_secs(60 * 60 + 1)] {
+        let result = new_test_builder()
+            .await?
+            .channel_bind_timeout(*timeout)
+            .build();
+        assert!(
+            matches!(result, Err(Error::ChannelBindTimeoutInvalid(_))),
+            "expected ChannelBindTimeoutInvalid error for {:?}",
+            timeout
+        );
+    }
+
+    // zero selects the default
+    new_test_builder()
+        .await?
+        .channel_bind_timeout(Duration::from_secs(0))
+        .build()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_lifetime_jitter() -> Result<(), Error> {
+    for jitter in &[-0.1, MAX_LIFETIME_JITTER + 0.1, f64::NAN] {
+        let result = new_test_builder().await?.lifetime_jitter(*jitter).build();
+        assert!(
+            matches!(result, Err(Error::LifetimeJitterInvalid(_))),
+            "expected LifetimeJitterInvalid error for {}",
+            jitter
+        );
+    }
+
+    let config = new_test_builder().await?.lifetime_jitter(0.1).build()?;
+    assert_eq!(Some(0.1), config.lifetime_jitter);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_max_allocations() -> Result<(), Error> {
+    let result = new_test_builder().await?.max_allocations(0).build();
+    assert!(matches!(result, Err(Error::MaxAllocationsZero)));
+
+    let config = new_test_builder()
+        .await?
+        .max_allocations(100)
+        .max_allocations_code(MaxAllocationsCode::InsufficientCapacity)
+        .build()?;
+    assert_eq!(Some(100), config.max_allocations);
+    assert_eq!(
+        MaxAllocationsCode::InsufficientCapacity,
+        config.max_allocations_code
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_load_factor() -> Result<(), Error> {
+    for weight in [-1.0, f64::NAN, f64::INFINITY] {
+        let result = new_test_builder()
+            .await?
+            .load_factor(LoadFactorConfig {
+                port_weight: weight,
+                ..Default::default()
+            })
+            .build();
+        assert!(
+            matches!(result, Err(Error::LoadFactorWeightInvalid(_))),
+            "expected LoadFactorWeightInvalid error for {}",
+            weight
+        );
+    }
+
+    let result = new_test_builder()
+        .await?
+        .load_factor(LoadFactorConfig {
+            bandwidth_budget: Some(0),
+            ..Default::default()
+        })
+        .build();
+    assert!(matches!(result, Err(Error::BandwidthBudgetZero)));
+
+    let load_factor = LoadFactorConfig {
+        allocation_weight: 2.0,
+        port_weight: 0.0,
+        bandwidth_weight: 1.0,
+        bandwidth_budget: Some(1_000_000),
+    };
+    let config = new_test_builder().await?.load_factor(load_factor).build()?;
+    assert_eq!(load_factor, config.load_factor);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_debug_redacts_auth() -> Result<(), Error> {
+    let builder = new_test_builder().await?;
+    let out = format!("{:?}", builder);
+    assert!(out.contains("webrtc.rs"), "{}", out);
+    assert!(out.contains("<redacted>"), "{}", out);
+
+    let config = builder.build()?;
+    let out = format!("{:?}", config);
+    assert!(out.contains("webrtc.rs"), "{}", out);
+    assert!(out.contains("127.0.0.1"), "{}", out);
+    assert!(out.contains("<redacted>"), "{}", out);
+
+    let handler = LongTermAuthHandler::new("shared-s3cret".to_owned());
+    let out = format!("{:?}", handler);
+    assert!(!out.contains("shared-s3cret"), "{}", out);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_max_username_len() -> Result<(), Error> {
+    let result = new_test_builder()
+        .await?
+        .max_username_len(MAX_USERNAME_LEN + 1)
+        .build();
+    assert!(matches!(result, Err(Error::MaxUsernameLenInvalid(_))));
+
+    let config = new_test_builder()
+        .await?
+        .max_username_len(64)
+        .username_validator(|username| username.contains(':'))
+        .build()?;
+    assert_eq!(64, config.max_username_len);
+    let username_validator = config.username_validator.expect("username_validator");
+    assert!(username_validator("tenant:session"));
+    assert!(!username_validator("tenant"));
+
+    Ok(())
+}
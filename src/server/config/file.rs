@@ -0,0 +1,130 @@
+#[cfg(all(test, feature = "serde"))]
+mod file_test;
+
+use super::*;
+use crate::relay::relay_none::RelayAddressGeneratorNone;
+use crate::relay::relay_range::RelayAddressGeneratorRanges;
+use crate::relay::relay_static::RelayAddressGeneratorStatic;
+
+// RelayConfigFile is the relay address generator of a listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type"))]
+pub enum RelayConfigFile {
+    Static(RelayAddressGeneratorStatic),
+    Ranges(RelayAddressGeneratorRanges),
+    None(RelayAddressGeneratorNone),
+}
+
+impl RelayConfigFile {
+    fn into_generator(self) -> Box<dyn RelayAddressGenerator + Send + Sync> {
+        match self {
+            RelayConfigFile::Static(generator) => Box::new(generator),
+            RelayConfigFile::Ranges(generator) => Box::new(generator),
+            RelayConfigFile::None(generator) => Box::new(generator),
+        }
+    }
+}
+
+// ListenerConfigFile is a ConnConfig without its conn
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ListenerConfigFile {
+    // address is where the conn of the listener is to be bound, it isn't
+    // used by into_config
+    pub address: String,
+    // relay may only be left unset with stun_only
+    pub relay: Option<RelayConfigFile>,
+    pub bind_device: Option<String>,
+    pub stun_only: bool,
+}
+
+// ServerConfigFile is the plain data of a ServerConfig, e.g. read from a
+// config file with the serde feature. into_config attaches the runtime
+// objects and validates the result. Durations are in seconds, zeroes select
+// the defaults like with ServerConfigBuilder.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ServerConfigFile {
+    pub listeners: Vec<ListenerConfigFile>,
+    pub realm: String,
+    pub channel_bind_timeout_secs: u64,
+    pub relay_queue_size: usize,
+    pub relay_read_mode: RelayReadMode,
+    pub lifetime_jitter: Option<f64>,
+    pub max_allocations: Option<usize>,
+    pub max_allocations_code: MaxAllocationsCode,
+    pub max_username_len: usize,
+    pub bind_nonce_to_client_ip: bool,
+    pub intercept_point: InterceptPoint,
+    pub stun_only_binding: Option<bool>,
+    pub max_pending_challenges: usize,
+    pub load_factor: LoadFactorConfig,
+}
+
+impl ServerConfigFile {
+    // into_config takes a conn per listener, bound to its address and in the
+    // same order, and the auth handler. Event handlers, username validators
+    // and interceptors can be set on the returned config.
+    pub fn into_config(
+        self,
+        conns: Vec<Arc<dyn Conn + Send + Sync>>,
+        auth_handler: Box<dyn AuthHandler + Send + Sync>,
+    ) -> Result<ServerConfig, Error> {
+        if conns.len() != self.listeners.len() {
+            return Err(Error::ListenerConnsMismatch {
+                listeners: self.listeners.len(),
+                conns: conns.len(),
+            });
+        }
+
+        let mut builder = ServerConfig::builder()
+            .realm(&self.realm)
+            .auth_handler(auth_handler)
+            .channel_bind_timeout(Duration::from_secs(self.channel_bind_timeout_secs))
+            .relay_queue_size(self.relay_queue_size)
+            .relay_read_mode(self.relay_read_mode)
+            .max_allocations_code(self.max_allocations_code)
+            .max_username_len(self.max_username_len)
+            .bind_nonce_to_client_ip(self.bind_nonce_to_client_ip)
+            .intercept_point(self.intercept_point)
+            .max_pending_challenges(self.max_pending_challenges)
+            .load_factor(self.load_factor);
+        if let Some(lifetime_jitter) = self.lifetime_jitter {
+            builder = builder.lifetime_jitter(lifetime_jitter);
+        }
+        if let Some(max_allocations) = self.max_allocations {
+            builder = builder.max_allocations(max_allocations);
+        }
+        if let Some(stun_only_binding) = self.stun_only_binding {
+            builder = builder.stun_only_binding(stun_only_binding);
+        }
+
+        for (listener, conn) in self.listeners.into_iter().zip(conns) {
+            let relay_addr_generator = match (listener.relay, listener.stun_only) {
+                (Some(relay), _) => relay.into_generator(),
+                (None, true) => Box::new(RelayAddressGeneratorNone {
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+                (None, false) => {
+                    return Err(Error::ListenerInvalid {
+                        listener: listener.address,
+                        err: Box::new(Error::RelayUnset),
+                    })
+                }
+            };
+            builder = builder.add_conn_config(ConnConfig {
+                conn,
+                relay_addr_generator,
+                bind_device: listener.bind_device,
+                stun_only: listener.stun_only,
+            });
+        }
+
+        builder.build()
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+
+const SAMPLE: &str = r#"
+realm: webrtc.rs
+channel_bind_timeout_secs: 300
+relay_read_mode: !SharedPoll
+  workers: 4
+lifetime_jitter: 0.1
+max_allocations: 1000
+max_allocations_code: InsufficientCapacity
+bind_nonce_to_client_ip: true
+listeners:
+  - address: 127.0.0.1:3478
+    relay:
+      type: Ranges
+      relay_address: 127.0.0.1
+      min_port: 50000
+      max_port: 50100
+      max_retries: 5
+      address: 127.0.0.1
+  - address: 127.0.0.1:3479
+    relay:
+      type: Static
+      relay_address: 127.0.0.1
+      address: 127.0.0.1
+  - address: 127.0.0.1:3480
+    stun_only: true
+"#;
+
+struct TestAuthHandler;
+impl AuthHandler for TestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(generate_auth_key(username, realm, "pass"))
+    }
+}
+
+fn parse(yaml: &str) -> Result<ServerConfigFile, Error> {
+    serde_yaml::from_str(yaml).map_err(|err| Error::Other(err.to_string()))
+}
+
+async fn bind_conns(n: usize) -> Result<Vec<Arc<dyn Conn + Send + Sync>>, Error> {
+    let mut conns: Vec<Arc<dyn Conn + Send + Sync>> = vec![];
+    for _ in 0..n {
+        conns.push(Arc::new(UdpSocket::bind("127.0.0.1:0").await?));
+    }
+    Ok(conns)
+}
+
+#[test]
+fn test_server_config_file_round_trip() -> Result<(), Error> {
+    let file = parse(SAMPLE)?;
+    assert_eq!("webrtc.rs", file.realm);
+    assert_eq!(
+        RelayReadMode::SharedPoll { workers: 4 },
+        file.relay_read_mode
+    );
+    assert_eq!(
+        MaxAllocationsCode::InsufficientCapacity,
+        file.max_allocations_code
+    );
+    assert_eq!(InterceptPoint::default(), file.intercept_point);
+    assert_eq!(None, file.stun_only_binding);
+    assert_eq!(3, file.listeners.len());
+    assert_eq!(
+        Some(RelayConfigFile::Ranges(RelayAddressGeneratorRanges {
+            relay_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            min_port: 50000,
+            max_port: 50100,
+            max_retries: 5,
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        })),
+        file.listeners[0].relay
+    );
+    assert!(file.listeners[2].stun_only);
+    assert_eq!(None, file.listeners[2].relay);
+
+    let yaml = serde_yaml::to_string(&file).map_err(|err| Error::Other(err.to_string()))?;
+    assert_eq!(file, parse(&yaml)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_file_into_config() -> Result<(), Error> {
+    let file = parse(SAMPLE)?;
+    let config = file.into_config(bind_conns(3).await?, Box::new(TestAuthHandler {}))?;
+
+    assert_eq!("webrtc.rs", config.realm);
+    assert_eq!(Duration::from_secs(300), config.channel_bind_timeout);
+    assert_eq!(
+        RelayReadMode::SharedPoll { workers: 4 },
+        config.relay_read_mode
+    );
+    assert_eq!(Some(0.1), config.lifetime_jitter);
+    assert_eq!(Some(1000), config.max_allocations);
+    assert!(config.bind_nonce_to_client_ip);
+    assert!(config.stun_only_binding, "unset should select the default");
+    assert_eq!(3, config.conn_configs.len());
+    assert!(!config.conn_configs[0].stun_only);
+    assert!(config.conn_configs[2].stun_only);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_config_file_invalid() -> Result<(), Error> {
+    let file = parse(SAMPLE)?;
+    let result = file
+        .clone()
+        .into_config(bind_conns(2).await?, Box::new(TestAuthHandler {}));
+    assert!(
+        matches!(
+            result,
+            Err(Error::ListenerConnsMismatch {
+                listeners: 3,
+                conns: 2
+            })
+        ),
+        "{:?}",
+        result.err()
+    );
+
+    let mut no_relay = file.clone();
+    no_relay.listeners[1].relay = None;
+    let result = no_relay.into_config(bind_conns(3).await?, Box::new(TestAuthHandler {}));
+    match result {
+        Err(Error::ListenerInvalid { listener, err }) => {
+            assert_eq!("127.0.0.1:3479", listener);
+            assert!(matches!(*err, Error::RelayUnset), "{}", err);
+        }
+        result => panic!("expected an unset relay, got {:?}", result.err()),
+    }
+
+    // the config is validated like a built one
+    let result =
+        parse("realm: \"\"\nlisteners: []\n")?.into_config(vec![], Box::new(TestAuthHandler {}));
+    assert!(
+        matches!(result, Err(Error::NoAvailableConns)),
+        "{:?}",
+        result.err()
+    );
+
+    let mut bad_jitter = file;
+    bad_jitter.lifetime_jitter = Some(0.9);
+    let result = bad_jitter.into_config(bind_conns(3).await?, Box::new(TestAuthHandler {}));
+    assert!(
+        matches!(result, Err(Error::LifetimeJitterInvalid(_))),
+        "{:?}",
+        result.err()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use crate::allocation::peer_traffic::AllocationTraffic;
+
+use std::net::SocketAddr;
+
+// ServerEvent is a change in server state reported to the EventHandler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    // CapacityReached is emitted when the allocations first reach
+    // max_allocations, further Allocate requests are rejected
+    CapacityReached {
+        allocations: usize,
+    },
+    // CapacityRecovered is emitted once the allocations have fallen to the
+    // hysteresis threshold after CapacityReached
+    CapacityRecovered {
+        allocations: usize,
+    },
+    // AuthFailure is emitted when a request carrying credentials is rejected
+    AuthFailure {
+        src_addr: SocketAddr,
+        reason: AuthFailureReason,
+    },
+    // AllocationDeleted is emitted when an allocation is deleted, with why
+    // and the traffic it relayed
+    AllocationDeleted {
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        username: String,
+        relay_addr: SocketAddr,
+        reason: AllocationCloseReason,
+        traffic: AllocationTraffic,
+    },
+}
+
+// AllocationCloseReason is why an allocation was deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AllocationCloseReason {
+    // the client refreshed it with a zero lifetime
+    ClientDeallocated,
+    // its lifetime ran out without a refresh
+    LifetimeExpired,
+    // the application deleted it with Manager::delete_allocation
+    Deleted,
+    // its Manager was closed, the listener's read loop closes it when the
+    // listener's conn fails or is closed
+    Shutdown,
+    // reading its relay socket failed
+    RelayError,
+}
+
+// AuthFailureReason is why a request failed authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailureReason {
+    // the USERNAME is over max_username_len or failed the username_validator,
+    // the auth handler was not consulted
+    MalformedUsername,
+    // the auth handler returned no key for the USERNAME
+    UnknownUser,
+    // the MESSAGE-INTEGRITY did not match the key of the USERNAME
+    IntegrityMismatch,
+}
+
+// EventHandler is notified of ServerEvents. on_event is called inline from
+// request handling and allocation teardown, it must not block.
+pub trait EventHandler {
+    fn on_event(&self, event: ServerEvent);
+}
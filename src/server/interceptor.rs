@@ -0,0 +1,69 @@
+use stun::error_code::ErrorCode;
+use stun::message::*;
+
+use async_trait::async_trait;
+
+use std::fmt;
+use std::net::SocketAddr;
+
+// InterceptPoint selects when requests that carry credentials are intercepted.
+// Binding requests and Send indications carry none and are intercepted before
+// they are handled either way.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InterceptPoint {
+    BeforeAuth,
+    // after the MESSAGE-INTEGRITY checked, requests failing authentication are
+    // answered by the server without being intercepted
+    #[default]
+    AfterAuth,
+}
+
+// RequestCtx is what the server knows of an intercepted request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestCtx {
+    // local_addr is the address of the listener the request arrived on
+    pub local_addr: SocketAddr,
+    // username is the authenticated USERNAME, None before authentication and
+    // for requests without credentials
+    pub username: Option<String>,
+}
+
+// InterceptDecision is what the server does with an intercepted request
+#[derive(Clone, PartialEq, Eq)]
+pub enum InterceptDecision {
+    // Continue handles the request as usual
+    Continue,
+    // Reject answers the request with an error response, signed with the
+    // request's key if it was authenticated. Indications are dropped.
+    Reject { code: ErrorCode, reason: String },
+    // Drop discards the request without an answer
+    Drop,
+}
+
+// ErrorCode has no Debug, a rejection is shown by its reason
+impl fmt::Debug for InterceptDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterceptDecision::Continue => f.write_str("Continue"),
+            InterceptDecision::Reject { reason, .. } => f
+                .debug_struct("Reject")
+                .field("reason", reason)
+                .finish_non_exhaustive(),
+            InterceptDecision::Drop => f.write_str("Drop"),
+        }
+    }
+}
+
+// RequestInterceptor sees each STUN request and indication after parsing.
+// on_request is awaited by the listener before the request is handled, so it
+// must return promptly.
+#[async_trait]
+pub trait RequestInterceptor {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        src: SocketAddr,
+        ctx: &RequestCtx,
+    ) -> InterceptDecision;
+}
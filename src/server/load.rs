@@ -0,0 +1,153 @@
+use super::*;
+
+use tokio::time::Instant;
+
+// LoadComponents are the raw measures Server::load_factor is computed from,
+// for operators weighing them their own way. The caps are None when the
+// server has none configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LoadComponents {
+    pub allocations: usize,
+    pub max_allocations: Option<usize>,
+    // ports_in_use are the allocations of the listeners whose relay
+    // generator has a port range, port_capacity the ports of those ranges
+    pub ports_in_use: usize,
+    pub port_capacity: Option<usize>,
+    // bandwidth is the bytes per second the listeners received and sent
+    // since the previous call to Server::load_components, zero on the first
+    pub bandwidth: u64,
+    pub bandwidth_budget: Option<u64>,
+}
+
+impl LoadComponents {
+    // load_factor weighs the components with config, see LoadFactorConfig.
+    // It is 0.0 when no component counts.
+    pub fn load_factor(&self, config: &LoadFactorConfig) -> f64 {
+        let ratios = [
+            (
+                config.allocation_weight,
+                ratio(
+                    self.allocations as u64,
+                    self.max_allocations.map(|m| m as u64),
+                ),
+            ),
+            (
+                config.port_weight,
+                ratio(
+                    self.ports_in_use as u64,
+                    self.port_capacity.map(|c| c as u64),
+                ),
+            ),
+            (
+                config.bandwidth_weight,
+                ratio(self.bandwidth, self.bandwidth_budget),
+            ),
+        ];
+
+        let mut weighted = 0.0;
+        let mut weights = 0.0;
+        for (weight, ratio) in ratios {
+            let Some(ratio) = ratio else { continue };
+            if weight <= 0.0 {
+                continue;
+            }
+            // a component at its cap leaves no room, whatever the others say
+            if ratio >= 1.0 {
+                return 1.0;
+            }
+            weighted += weight * ratio;
+            weights += weight;
+        }
+
+        if weights > 0.0 {
+            (weighted / weights).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+fn ratio(used: u64, cap: Option<u64>) -> Option<f64> {
+    match cap {
+        Some(cap) if cap > 0 => Some((used as f64 / cap as f64).min(1.0)),
+        _ => None,
+    }
+}
+
+// BandwidthSample is the listeners' byte count at the last load_components
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BandwidthSample {
+    at: Instant,
+    bytes: u64,
+}
+
+impl Server {
+    // load_factor is how loaded the server is, from 0.0 for idle to 1.0 for
+    // full, e.g. for an external load balancer. It weighs the components of
+    // load_components with ServerConfig::load_factor.
+    pub async fn load_factor(&self) -> f64 {
+        self.load_components()
+            .await
+            .load_factor(&self.load_factor_config)
+    }
+
+    // load_components measures the server's usage against its caps. The
+    // bandwidth is averaged since the previous call, so the measures should
+    // be taken by one poller.
+    pub async fn load_components(&self) -> LoadComponents {
+        let mut allocations = 0;
+        let mut ports_in_use = 0;
+        let mut port_capacity = None;
+        for l in &self.listeners {
+            let count = l.allocation_manager.allocation_count().await;
+            allocations += count;
+            if let Some(capacity) = l.allocation_manager.port_capacity() {
+                ports_in_use += count;
+                port_capacity = Some(port_capacity.unwrap_or(0) + capacity);
+            }
+        }
+
+        LoadComponents {
+            allocations,
+            max_allocations: self.allocation_limit.as_ref().map(|limit| limit.max()),
+            ports_in_use,
+            port_capacity,
+            bandwidth: self.sample_bandwidth(),
+            bandwidth_budget: self.load_factor_config.bandwidth_budget,
+        }
+    }
+
+    // sample_bandwidth is the bytes per second since the previous sample
+    fn sample_bandwidth(&self) -> u64 {
+        let bytes = self
+            .listeners
+            .iter()
+            .map(|l| {
+                let metrics = l.counters.load(l.local_addr);
+                metrics.bytes_in + metrics.bytes_out
+            })
+            .sum();
+        let now = BandwidthSample {
+            at: Instant::now(),
+            bytes,
+        };
+
+        let mut last = self
+            .bandwidth_sample
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let previous = last.replace(now);
+        match previous {
+            Some(previous) => {
+                let elapsed = now.at.duration_since(previous.at).as_secs_f64();
+                if elapsed > 0.0 {
+                    (now.bytes.saturating_sub(previous.bytes) as f64 / elapsed) as u64
+                } else {
+                    0
+                }
+            }
+            None => 0,
+        }
+    }
+}
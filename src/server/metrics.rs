@@ -0,0 +1,187 @@
+use crate::relay::RelayPoolMetrics;
+use crate::server::event::AllocationCloseReason;
+
+use util::Conn;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+// ListenerId identifies a turn listener, it is the index of its ConnConfig in
+// ServerConfig::conn_configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerId(pub usize);
+
+// ListenerMetrics counts what went through one turn listener since the server
+// started. Packets and bytes are the datagrams received and sent on the
+// listener's conn, relayed data to its clients included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerMetrics {
+    pub local_addr: SocketAddr,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub allocations_created: u64,
+    pub allocations_closed: AllocationsClosed,
+    pub auth_failures: u64,
+    // unallocated_dropped counts the ChannelData and indications dropped
+    // because their 5-tuple has no allocation
+    pub unallocated_dropped: u64,
+    // relay_pool is the occupancy of the listener's PreallocatedRelayPool
+    pub relay_pool: Option<RelayPoolMetrics>,
+}
+
+// AllocationsClosed counts the allocations of a listener that were deleted,
+// by AllocationCloseReason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationsClosed {
+    pub client_deallocated: u64,
+    pub lifetime_expired: u64,
+    pub deleted: u64,
+    pub shutdown: u64,
+    pub relay_error: u64,
+}
+
+impl AllocationsClosed {
+    // get is the count of allocations closed for reason
+    pub fn get(&self, reason: AllocationCloseReason) -> u64 {
+        match reason {
+            AllocationCloseReason::ClientDeallocated => self.client_deallocated,
+            AllocationCloseReason::LifetimeExpired => self.lifetime_expired,
+            AllocationCloseReason::Deleted => self.deleted,
+            AllocationCloseReason::Shutdown => self.shutdown,
+            AllocationCloseReason::RelayError => self.relay_error,
+        }
+    }
+}
+
+// NonceMetrics describes the server's nonce table, which is shared by the
+// listeners. evicted counts the nonces dropped to stay within
+// max_pending_challenges before they expired, a steadily growing count means
+// the table is too small or is being flooded with unauthenticated requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NonceMetrics {
+    pub pending: usize,
+    pub capacity: usize,
+    pub evicted: u64,
+    pub expired: u64,
+}
+
+// ListenerCounters are the counters of one listener. They outlive its read
+// loop, so a listener whose conn has closed keeps its last counts for as long
+// as the server lives.
+#[derive(Debug, Default)]
+pub(crate) struct ListenerCounters {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    allocations_created: AtomicU64,
+    // allocations_closed is indexed by AllocationCloseReason
+    allocations_closed: [AtomicU64; 5],
+    auth_failures: AtomicU64,
+    unallocated_dropped: AtomicU64,
+}
+
+impl ListenerCounters {
+    fn add_in(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn add_out(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_allocation(&self) {
+        self.allocations_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_allocation_closed(&self, reason: AllocationCloseReason) {
+        self.allocations_closed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_unallocated_dropped(&self) {
+        self.unallocated_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn closed(&self, reason: AllocationCloseReason) -> u64 {
+        self.allocations_closed[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn load(&self, local_addr: SocketAddr) -> ListenerMetrics {
+        ListenerMetrics {
+            local_addr,
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            allocations_created: self.allocations_created.load(Ordering::Relaxed),
+            allocations_closed: AllocationsClosed {
+                client_deallocated: self.closed(AllocationCloseReason::ClientDeallocated),
+                lifetime_expired: self.closed(AllocationCloseReason::LifetimeExpired),
+                deleted: self.closed(AllocationCloseReason::Deleted),
+                shutdown: self.closed(AllocationCloseReason::Shutdown),
+                relay_error: self.closed(AllocationCloseReason::RelayError),
+            },
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            unallocated_dropped: self.unallocated_dropped.load(Ordering::Relaxed),
+            relay_pool: None,
+        }
+    }
+}
+
+// MeteredConn wraps a listener's conn to count the datagrams it receives and
+// sends, the allocations made through the listener send on it too
+pub(crate) struct MeteredConn {
+    pub(crate) conn: Arc<dyn Conn + Send + Sync>,
+    pub(crate) counters: Arc<ListenerCounters>,
+}
+
+#[async_trait]
+impl Conn for MeteredConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.conn.recv(buf).await?;
+        self.counters.add_in(n);
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, from) = self.conn.recv_from(buf).await?;
+        self.counters.add_in(n);
+        Ok((n, from))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.send(buf).await?;
+        self.counters.add_out(n);
+        Ok(n)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let n = self.conn.send_to(buf, target).await?;
+        self.counters.add_out(n);
+        Ok(n)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+}
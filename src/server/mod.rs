@@ -1,21 +1,40 @@
-#[cfg(test)]
+#[cfg(all(test, feature = "client"))]
 mod server_test;
 
 pub mod config;
+pub mod event;
+pub mod interceptor;
+pub mod load;
+pub mod metrics;
+pub mod nonce_table;
 pub mod request;
+#[cfg(feature = "client")]
+pub mod self_test;
+pub mod snapshot;
 
+use crate::allocation::allocation_limit::AllocationLimit;
 use crate::allocation::allocation_manager::*;
+use crate::allocation::five_tuple::FiveTuple;
 use crate::auth::AuthHandler;
 use crate::proto::lifetime::DEFAULT_LIFETIME;
+use crate::proto::PROTO_UDP;
 use config::*;
+use event::EventHandler;
+use interceptor::*;
+use load::BandwidthSample;
+use metrics::*;
+use nonce_table::NonceTable;
 use request::*;
 
-use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
 
-use util::{Conn, Error};
+use crate::error::Error;
+
+use util::Conn;
 
 const INBOUND_MTU: usize = 1500;
 
@@ -24,46 +43,160 @@ pub struct Server {
     auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>>,
     realm: String,
     channel_bind_timeout: Duration,
-    pub(crate) nonces: Arc<Mutex<HashMap<String, Instant>>>,
+    lifetime_jitter: Option<f64>,
+    pub(crate) nonces: Arc<Mutex<NonceTable>>,
+    listeners: Vec<Listener>,
+    // shutdown tells the read loops to stop, their handles are joined by
+    // Server::shutdown
+    shutdown: watch::Sender<bool>,
+    read_loops: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    allocation_limit: Option<Arc<AllocationLimit>>,
+    load_factor_config: LoadFactorConfig,
+    bandwidth_sample: std::sync::Mutex<Option<BandwidthSample>>,
+    #[cfg(feature = "client")]
+    self_test_credentials: Arc<self_test::SelfTestCredentials>,
+}
+
+// RequestConfig is the user configuration each Request is handled with
+#[derive(Clone)]
+struct RequestConfig {
+    auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>>,
+    realm: String,
+    channel_bind_timeout: Duration,
+    lifetime_jitter: Option<f64>,
+    username_validator: Option<UsernameValidator>,
+    max_username_len: usize,
+    event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    bind_nonce_to_client_ip: bool,
+    interceptor: Option<Arc<dyn RequestInterceptor + Send + Sync>>,
+    intercept_point: InterceptPoint,
+    stun_only_binding: bool,
+    stun_only: bool,
+}
+
+// Listener is a turn listener with the allocations made through it
+struct Listener {
+    id: ListenerId,
+    local_addr: SocketAddr,
+    allocation_manager: Arc<Manager>,
+    counters: Arc<ListenerCounters>,
+    stun_only: bool,
 }
 
 impl Server {
     // creates the TURN server
     pub async fn new(config: ServerConfig) -> Result<Self, Error> {
         config.validate()?;
+        config.probe().await?;
+
+        // max_allocations is shared by the listeners' allocation managers
+        let allocation_limit = config.max_allocations.map(|max_allocations| {
+            Arc::new(AllocationLimit::new(
+                max_allocations,
+                config.max_allocations_code.into(),
+                config.event_handler.clone(),
+            ))
+        });
 
         let mut s = Server {
             auth_handler: config.auth_handler,
             realm: config.realm,
             channel_bind_timeout: config.channel_bind_timeout,
-            nonces: Arc::new(Mutex::new(HashMap::new())),
+            lifetime_jitter: config.lifetime_jitter,
+            nonces: Arc::new(Mutex::new(NonceTable::new(
+                config.max_pending_challenges,
+                NONCE_LIFETIME,
+            ))),
+            listeners: vec![],
+            shutdown: watch::channel(false).0,
+            read_loops: std::sync::Mutex::new(vec![]),
+            allocation_limit: allocation_limit.clone(),
+            load_factor_config: config.load_factor,
+            bandwidth_sample: std::sync::Mutex::new(None),
+            #[cfg(feature = "client")]
+            self_test_credentials: Arc::default(),
         };
 
         if s.channel_bind_timeout == Duration::from_secs(0) {
             s.channel_bind_timeout = DEFAULT_LIFETIME;
         }
 
-        for p in config.conn_configs.into_iter() {
+        // the self-test's temporary credentials are accepted too
+        #[cfg(feature = "client")]
+        let auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>> =
+            Arc::new(Box::new(self_test::SelfTestAuthHandler {
+                inner: Arc::clone(&s.auth_handler),
+                credentials: Arc::clone(&s.self_test_credentials),
+            }));
+        #[cfg(not(feature = "client"))]
+        let auth_handler = Arc::clone(&s.auth_handler);
+
+        let request_config = RequestConfig {
+            auth_handler,
+            realm: s.realm.clone(),
+            channel_bind_timeout: s.channel_bind_timeout,
+            lifetime_jitter: s.lifetime_jitter,
+            username_validator: config.username_validator,
+            max_username_len: if config.max_username_len == 0 {
+                MAX_USERNAME_LEN
+            } else {
+                config.max_username_len
+            },
+            event_handler: config.event_handler,
+            bind_nonce_to_client_ip: config.bind_nonce_to_client_ip,
+            interceptor: config.interceptor,
+            intercept_point: config.intercept_point,
+            stun_only_binding: config.stun_only_binding,
+            stun_only: false,
+        };
+
+        for (i, p) in config.conn_configs.into_iter().enumerate() {
             let nonces = Arc::clone(&s.nonces);
-            let auth_handler = Arc::clone(&s.auth_handler);
-            let realm = s.realm.clone();
-            let channel_bind_timeout = s.channel_bind_timeout;
+            let request_config = RequestConfig {
+                stun_only: p.stun_only,
+                ..request_config.clone()
+            };
+            let mut relay_addr_generator = p.relay_addr_generator;
+            if let Some(bind_device) = &p.bind_device {
+                relay_addr_generator.set_bind_device(bind_device);
+            }
+            let counters = Arc::new(ListenerCounters::default());
+            let mut allocation_manager = Manager::new(ManagerConfig {
+                relay_addr_generator,
+                relay_queue_size: config.relay_queue_size,
+                relay_read_mode: config.relay_read_mode,
+                allocation_limit: allocation_limit.clone(),
+                event_handler: request_config.event_handler.clone(),
+            });
+            allocation_manager.listener_counters = Some(Arc::clone(&counters));
+            let allocation_manager = Arc::new(allocation_manager);
+
+            let conn: Arc<dyn Conn + Send + Sync> = Arc::new(MeteredConn {
+                conn: p.conn,
+                counters: Arc::clone(&counters),
+            });
 
-            tokio::spawn(async move {
-                let allocation_manager = Arc::new(Manager::new(ManagerConfig {
-                    relay_addr_generator: p.relay_addr_generator,
-                }));
+            s.listeners.push(Listener {
+                id: ListenerId(i),
+                local_addr: conn.local_addr()?,
+                allocation_manager: Arc::clone(&allocation_manager),
+                counters: Arc::clone(&counters),
+                stun_only: request_config.stun_only,
+            });
 
-                let _ = Server::read_loop(
-                    p.conn,
+            let shutdown = s.shutdown.subscribe();
+            let read_loop = tokio::spawn(async move {
+                Server::read_loop(
+                    conn,
                     allocation_manager,
                     nonces,
-                    auth_handler,
-                    realm,
-                    channel_bind_timeout,
+                    request_config,
+                    counters,
+                    shutdown,
                 )
                 .await;
             });
+            s.read_loops().push(read_loop);
         }
 
         Ok(s)
@@ -72,44 +205,193 @@ impl Server {
     async fn read_loop(
         conn: Arc<dyn Conn + Send + Sync>,
         allocation_manager: Arc<Manager>,
-        nonces: Arc<Mutex<HashMap<String, Instant>>>,
-        auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>>,
-        realm: String,
-        channel_bind_timeout: Duration,
+        nonces: Arc<Mutex<NonceTable>>,
+        config: RequestConfig,
+        counters: Arc<ListenerCounters>,
+        mut shutdown: watch::Receiver<bool>,
     ) {
         let mut buf = vec![0u8; INBOUND_MTU];
+        let local_addr = match conn.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(err) => {
+                log::error!("listener has no local address: {}", err);
+                return;
+            }
+        };
 
         loop {
-            //TODO: gracefully exit loop
-            let (n, addr) = match conn.recv_from(&mut buf).await {
-                Ok((n, addr)) => (n, addr),
-                Err(err) => {
-                    log::debug!("exit read loop on error: {}", err);
-                    break;
+            // a request being handled is finished first, the shutdown is
+            // only seen between requests
+            let (n, addr) = tokio::select! {
+                result = conn.recv_from(&mut buf) => match result {
+                    Ok((n, addr)) => (n, addr),
+                    Err(err) => {
+                        log::debug!("exit read loop on error: {}", err);
+                        break;
+                    }
+                },
+                _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                    // Server::shutdown and Server::close close the
+                    // allocations once every read loop has stopped
+                    log::debug!("exit read loop on shutdown");
+                    return;
                 }
             };
 
+            // ChannelData and indications are only relayed for an allocation,
+            // a flood of them costs a lookup each and no parsing
+            if is_relay_traffic(&buf[..n]) {
+                let five_tuple = FiveTuple {
+                    src_addr: addr,
+                    dst_addr: local_addr,
+                    protocol: PROTO_UDP,
+                };
+                if allocation_manager
+                    .get_allocation(&five_tuple)
+                    .await
+                    .is_none()
+                {
+                    trace_limited!("dropped {} bytes from {} without an allocation", n, addr);
+                    counters.add_unallocated_dropped();
+                    continue;
+                }
+            }
+
             let mut r = Request {
                 conn: Arc::clone(&conn),
                 src_addr: addr,
                 buff: buf[..n].to_vec(),
                 allocation_manager: Arc::clone(&allocation_manager),
                 nonces: Arc::clone(&nonces),
-                auth_handler: Arc::clone(&auth_handler),
-                realm: realm.clone(),
-                channel_bind_timeout,
+                auth_handler: Arc::clone(&config.auth_handler),
+                realm: config.realm.clone(),
+                channel_bind_timeout: config.channel_bind_timeout,
+                lifetime_jitter: config.lifetime_jitter,
+                username_validator: config.username_validator.clone(),
+                max_username_len: config.max_username_len,
+                event_handler: config.event_handler.clone(),
+                bind_nonce_to_client_ip: config.bind_nonce_to_client_ip,
+                interceptor: config.interceptor.clone(),
+                intercept_point: config.intercept_point,
+                stun_only_binding: config.stun_only_binding,
+                stun_only: config.stun_only,
+                listener_counters: Arc::clone(&counters),
             };
 
             if let Err(err) = r.handle_request().await {
-                log::error!("error when handling datagram: {}", err);
+                error_limited!("error when handling datagram: {}", err);
             }
         }
 
         let _ = allocation_manager.close().await;
     }
 
-    // Close stops the TURN Server. It cleans up any associated state and closes all connections it is managing
+    // listener_metrics returns the counters of each listener, in the order of
+    // ServerConfig::conn_configs
+    pub fn listener_metrics(&self) -> Vec<(ListenerId, ListenerMetrics)> {
+        self.listeners
+            .iter()
+            .map(|l| {
+                let mut metrics = l.counters.load(l.local_addr);
+                metrics.relay_pool = l.allocation_manager.pool_metrics();
+                (l.id, metrics)
+            })
+            .collect()
+    }
+
+    // nonce_metrics returns the counters of the nonce table
+    pub async fn nonce_metrics(&self) -> NonceMetrics {
+        let nonces = self.nonces.lock().await;
+        NonceMetrics {
+            pending: nonces.len(),
+            capacity: nonces.capacity(),
+            evicted: nonces.evicted(),
+            expired: nonces.expired(),
+        }
+    }
+
+    fn read_loops(&self) -> std::sync::MutexGuard<'_, Vec<JoinHandle<()>>> {
+        self.read_loops
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Close tells the read loops to stop without waiting for them. A task then
+    // waits for them and closes the allocations, no Refresh can reach them
+    // anymore. See shutdown to wait for both and for the relay tasks.
     pub fn close(&self) -> Result<(), Error> {
+        self.stop_read_loops();
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("server closed outside a tokio runtime, allocations are left to expire");
+            return Ok(());
+        };
+        let read_loops: Vec<JoinHandle<()>> = self.read_loops().drain(..).collect();
+        let allocation_managers: Vec<Arc<Manager>> = self
+            .listeners
+            .iter()
+            .map(|l| Arc::clone(&l.allocation_manager))
+            .collect();
+        runtime.spawn(async move {
+            futures::future::join_all(read_loops).await;
+            for allocation_manager in allocation_managers {
+                if let Err(err) = allocation_manager.close().await {
+                    log::warn!("failed to close allocations: {}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn stop_read_loops(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    // shutdown stops the TURN Server in two phases. The read loops are told to
+    // stop and waited for, each finishes the request it is handling. Then the
+    // allocations are closed and their relay tasks waited for, so no task is
+    // left using an allocation or a socket when it returns. Each phase waits
+    // up to timeout, read loops still running are then aborted and the error
+    // is Error::ShutdownTimedOut. A panic of a read loop is resumed.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), Error> {
+        self.stop_read_loops();
+
+        let read_loops: Vec<JoinHandle<()>> = self.read_loops().drain(..).collect();
+        let aborts: Vec<_> = read_loops.iter().map(|h| h.abort_handle()).collect();
+        let mut timed_out = false;
+        match tokio::time::timeout(timeout, futures::future::join_all(read_loops)).await {
+            Ok(results) => {
+                for result in results {
+                    if let Err(err) = result {
+                        if err.is_panic() {
+                            std::panic::resume_unwind(err.into_panic());
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                log::warn!("read loops still running after {:?}, aborted", timeout);
+                aborts.iter().for_each(|abort| abort.abort());
+                timed_out = true;
+            }
+        }
+
+        for l in &self.listeners {
+            l.allocation_manager.close().await?;
+        }
+        let relay_tasks = futures::future::join_all(
+            self.listeners
+                .iter()
+                .map(|l| l.allocation_manager.relay_tasks_exited()),
+        );
+        if tokio::time::timeout(timeout, relay_tasks).await.is_err() {
+            log::warn!("relay tasks still running after {:?}", timeout);
+            timed_out = true;
+        }
+
+        if timed_out {
+            return Err(Error::ShutdownTimedOut(timeout));
+        }
         Ok(())
     }
 }
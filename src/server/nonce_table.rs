@@ -0,0 +1,140 @@
+#[cfg(test)]
+mod nonce_table_test;
+
+use super::request::NONCE_LIFETIME;
+
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::time::{Duration, Instant};
+
+// DEFAULT_MAX_PENDING_CHALLENGES is the default capacity of the NonceTable
+pub const DEFAULT_MAX_PENDING_CHALLENGES: usize = 100_000;
+
+// NonceTable holds the nonces the server handed out, at most capacity of
+// them. Anyone can make the server issue one with an unauthenticated request,
+// so once full the oldest nonce is evicted, its client is challenged again
+// with 438 (Stale Nonce). Expired nonces are dropped as new ones come in.
+#[derive(Debug)]
+pub struct NonceTable {
+    capacity: usize,
+    lifetime: Duration,
+    // nonces maps a nonce to its creation time and its key in by_age
+    nonces: HashMap<String, (Instant, u64)>,
+    by_age: BTreeMap<(Instant, u64), String>,
+    next_seq: u64,
+    evicted: u64,
+    expired: u64,
+}
+
+impl Default for NonceTable {
+    fn default() -> Self {
+        NonceTable::new(DEFAULT_MAX_PENDING_CHALLENGES, NONCE_LIFETIME)
+    }
+}
+
+impl NonceTable {
+    // new makes a table of capacity nonces, a capacity of zero selects
+    // DEFAULT_MAX_PENDING_CHALLENGES
+    pub fn new(capacity: usize, lifetime: Duration) -> Self {
+        NonceTable {
+            capacity: if capacity == 0 {
+                DEFAULT_MAX_PENDING_CHALLENGES
+            } else {
+                capacity
+            },
+            lifetime,
+            nonces: HashMap::new(),
+            by_age: BTreeMap::new(),
+            next_seq: 0,
+            evicted: 0,
+            expired: 0,
+        }
+    }
+
+    pub fn contains_key(&self, nonce: &str) -> bool {
+        self.nonces.contains_key(nonce)
+    }
+
+    // insert adds a nonce created at created, making room for it first
+    pub fn insert(&mut self, nonce: String, created: Instant) {
+        self.remove(&nonce);
+        self.purge_expired(Instant::now());
+        while self.nonces.len() >= self.capacity {
+            match self.by_age.pop_first() {
+                Some((_, oldest)) => {
+                    self.nonces.remove(&oldest);
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+
+        let key = (created, self.next_seq);
+        self.next_seq += 1;
+        self.by_age.insert(key, nonce.clone());
+        self.nonces.insert(nonce, key);
+    }
+
+    pub fn remove(&mut self, nonce: &str) -> bool {
+        match self.nonces.remove(nonce) {
+            Some(key) => {
+                self.by_age.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // is_fresh reports if nonce was issued and hasn't expired, an expired one
+    // is removed
+    pub fn is_fresh(&mut self, nonce: &str) -> bool {
+        let created = match self.nonces.get(nonce) {
+            Some((created, _)) => *created,
+            None => return false,
+        };
+        if Instant::now().duration_since(created) < self.lifetime {
+            return true;
+        }
+        self.remove(nonce);
+        self.expired += 1;
+        false
+    }
+
+    fn purge_expired(&mut self, now: Instant) {
+        while let Some(entry) = self.by_age.first_entry() {
+            if now.duration_since(entry.key().0) < self.lifetime {
+                break;
+            }
+            let oldest = entry.remove();
+            self.nonces.remove(&oldest);
+            self.expired += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.nonces.clear();
+        self.by_age.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.nonces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nonces.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // evicted counts the nonces dropped to make room before they expired
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    // expired counts the nonces dropped once past their lifetime
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+}
@@ -0,0 +1,77 @@
+use super::*;
+
+// a flood of challenges keeps the table at its capacity, the newest nonces
+// are kept
+#[test]
+fn test_nonce_table_flood_is_bounded() {
+    let mut nonces = NonceTable::new(1000, NONCE_LIFETIME);
+    let now = Instant::now();
+    for i in 0..100_000 {
+        nonces.insert(format!("nonce-{}", i), now);
+        assert!(nonces.len() <= 1000, "table grew to {}", nonces.len());
+    }
+
+    assert_eq!(1000, nonces.len());
+    assert_eq!(99_000, nonces.evicted());
+    assert_eq!(0, nonces.expired());
+    assert!(!nonces.contains_key("nonce-98999"));
+    assert!(nonces.is_fresh("nonce-99000"));
+    assert!(nonces.is_fresh("nonce-99999"));
+}
+
+#[test]
+fn test_nonce_table_evicts_oldest() {
+    let mut nonces = NonceTable::new(2, NONCE_LIFETIME);
+    let now = Instant::now();
+    nonces.insert("b".to_owned(), now);
+    nonces.insert("a".to_owned(), now - Duration::from_secs(1));
+    nonces.insert("c".to_owned(), now);
+
+    assert!(!nonces.contains_key("a"));
+    assert!(nonces.contains_key("b"));
+    assert!(nonces.contains_key("c"));
+    assert_eq!(1, nonces.evicted());
+
+    // a removed nonce makes room without an eviction
+    assert!(nonces.remove("b"));
+    assert!(!nonces.remove("b"));
+    nonces.insert("d".to_owned(), now);
+    assert_eq!(2, nonces.len());
+    assert_eq!(1, nonces.evicted());
+
+    // reinserting a nonce replaces it
+    nonces.insert("d".to_owned(), now);
+    assert_eq!(2, nonces.len());
+    assert_eq!(1, nonces.evicted());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_nonce_table_expiry() {
+    let lifetime = Duration::from_secs(60);
+    let mut nonces = NonceTable::new(10, lifetime);
+    nonces.insert("old".to_owned(), Instant::now());
+    nonces.insert("stale".to_owned(), Instant::now());
+    tokio::time::advance(Duration::from_secs(30)).await;
+    nonces.insert("new".to_owned(), Instant::now());
+    assert!(nonces.is_fresh("old"));
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert!(!nonces.is_fresh("old"), "should have expired");
+    assert!(!nonces.contains_key("old"));
+    assert_eq!(1, nonces.expired());
+
+    // expired nonces make room for the new ones
+    nonces.insert("newer".to_owned(), Instant::now());
+    assert!(!nonces.contains_key("stale"));
+    assert_eq!(2, nonces.expired());
+    assert_eq!(0, nonces.evicted());
+    assert_eq!(2, nonces.len());
+    assert!(nonces.is_fresh("new"));
+}
+
+#[test]
+fn test_nonce_table_zero_capacity_selects_default() {
+    let nonces = NonceTable::new(0, NONCE_LIFETIME);
+    assert_eq!(DEFAULT_MAX_PENDING_CHALLENGES, nonces.capacity());
+    assert!(nonces.is_empty());
+}
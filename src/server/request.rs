@@ -5,18 +5,26 @@ use crate::allocation::allocation_manager::*;
 use crate::allocation::channel_bind::ChannelBind;
 use crate::allocation::five_tuple::*;
 use crate::allocation::permission::Permission;
+use crate::allocation::Allocation;
 use crate::auth::*;
-use crate::errors::*;
-use crate::proto::chandata::ChannelData;
+use crate::error::Error;
+use crate::proto::chandata::{ChannelData, CHANNEL_DATA_HEADER_SIZE};
 use crate::proto::channum::ChannelNumber;
 use crate::proto::data::Data;
+use crate::proto::errorcodes::*;
 use crate::proto::evenport::EvenPort;
 use crate::proto::lifetime::*;
+use crate::proto::origin::Origin;
 use crate::proto::peeraddr::PeerAddress;
 use crate::proto::relayaddr::RelayedAddress;
 use crate::proto::reqtrans::RequestedTransport;
 use crate::proto::rsrvtoken::ReservationToken;
 use crate::proto::*;
+use crate::server::config::{UsernameValidator, MAX_USERNAME_LEN};
+use crate::server::event::*;
+use crate::server::interceptor::*;
+use crate::server::metrics::ListenerCounters;
+use crate::server::nonce_table::NonceTable;
 
 use stun::agent::*;
 use stun::attributes::*;
@@ -28,11 +36,10 @@ use stun::textattrs::*;
 use stun::uattrs::*;
 use stun::xoraddr::*;
 
-use util::{Conn, Error};
+use util::Conn;
 
-use std::collections::HashMap;
 use std::marker::{Send, Sync};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::SystemTime;
 
@@ -53,12 +60,22 @@ pub struct Request {
 
     // Server State
     pub allocation_manager: Arc<Manager>,
-    pub nonces: Arc<Mutex<HashMap<String, Instant>>>,
+    pub nonces: Arc<Mutex<NonceTable>>,
 
     // User Configuration
     pub auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>>,
     pub realm: String,
     pub channel_bind_timeout: Duration,
+    pub lifetime_jitter: Option<f64>,
+    pub username_validator: Option<UsernameValidator>,
+    pub max_username_len: usize,
+    pub event_handler: Option<Arc<dyn EventHandler + Send + Sync>>,
+    pub bind_nonce_to_client_ip: bool,
+    pub interceptor: Option<Arc<dyn RequestInterceptor + Send + Sync>>,
+    pub intercept_point: InterceptPoint,
+    pub stun_only_binding: bool,
+    pub stun_only: bool,
+    pub(crate) listener_counters: Arc<ListenerCounters>,
 }
 
 impl Request {
@@ -73,23 +90,33 @@ impl Request {
             src_addr,
             buff: vec![],
             allocation_manager,
-            nonces: Arc::new(Mutex::new(HashMap::new())),
+            nonces: Arc::new(Mutex::new(NonceTable::default())),
             auth_handler,
             realm: String::new(),
             channel_bind_timeout: Duration::from_secs(0),
+            lifetime_jitter: None,
+            username_validator: None,
+            max_username_len: MAX_USERNAME_LEN,
+            event_handler: None,
+            bind_nonce_to_client_ip: false,
+            interceptor: None,
+            intercept_point: InterceptPoint::default(),
+            stun_only_binding: true,
+            stun_only: false,
+            listener_counters: Arc::default(),
         }
     }
 
     // handle_request processes the give Request
     pub async fn handle_request(&mut self) -> Result<(), Error> {
-        log::debug!(
+        debug_limited!(
             "received {} bytes of udp from {} on {}",
             self.buff.len(),
             self.src_addr,
             self.conn.local_addr()?
         );
 
-        if ChannelData::is_channel_data(&self.buff) {
+        if let PacketClass::ChannelData { .. } = classify(&self.buff) {
             self.handle_data_packet().await
         } else {
             self.handle_turn_packet().await
@@ -97,17 +124,18 @@ impl Request {
     }
 
     async fn handle_data_packet(&mut self) -> Result<(), Error> {
-        log::debug!("received DataPacket from {}", self.src_addr);
-        let mut c = ChannelData {
-            raw: self.buff.clone(),
-            ..Default::default()
-        };
-        c.decode()?;
-        self.handle_channel_data(&c).await
+        debug_limited!("received DataPacket from {}", self.src_addr);
+        let (number, l) = ChannelData::decode_header(&self.buff)?;
+
+        // the payload is relayed from the received buffer, only the header
+        // and padding are stripped
+        let mut buff = std::mem::take(&mut self.buff);
+        buff.truncate(CHANNEL_DATA_HEADER_SIZE + l);
+        self.handle_channel_data(number, buff).await
     }
 
     async fn handle_turn_packet(&mut self) -> Result<(), Error> {
-        log::debug!("handle_turn_packet");
+        debug_limited!("handle_turn_packet");
         let mut m = Message {
             raw: self.buff.clone(),
             ..Default::default()
@@ -118,10 +146,23 @@ impl Request {
     }
 
     async fn process_message_handler(&mut self, m: &Message) -> Result<(), Error> {
+        // requests with credentials are intercepted by authenticate_request
+        // after authentication
+        if (self.intercept_point == InterceptPoint::BeforeAuth || !is_authenticated(m))
+            && !self.intercept(m, None).await?
+        {
+            return Ok(());
+        }
+
+        // a STUN-only listener answers Binding requests and nothing else
+        if self.stun_only && m.typ.method != METHOD_BINDING {
+            return self.reject_stun_only(m).await;
+        }
+
         if m.typ.class == CLASS_INDICATION {
             match m.typ.method {
                 METHOD_SEND => self.handle_send_indication(m).await,
-                _ => Err(ERR_UNEXPECTED_CLASS.to_owned()),
+                _ => Err(Error::UnexpectedClass),
             }
         } else if m.typ.class == CLASS_REQUEST {
             match m.typ.method {
@@ -130,10 +171,10 @@ impl Request {
                 METHOD_CREATE_PERMISSION => self.handle_create_permission_request(m).await,
                 METHOD_CHANNEL_BIND => self.handle_channel_bind_request(m).await,
                 METHOD_BINDING => self.handle_binding_request(m).await,
-                _ => Err(ERR_UNEXPECTED_CLASS.to_owned()),
+                _ => Err(Error::UnexpectedClass),
             }
         } else {
-            Err(ERR_UNEXPECTED_CLASS.to_owned())
+            Err(Error::UnexpectedClass)
         }
     }
 
@@ -154,73 +195,239 @@ impl Request {
         let bad_request_msg = build_msg(
             m.transaction_id,
             MessageType::new(calling_method, CLASS_ERROR_RESPONSE),
-            vec![Box::new(ErrorCodeAttribute {
-                code: CODE_BAD_REQUEST,
-                reason: vec![],
-            })],
+            vec![Box::new(error_code_attribute(CODE_BAD_REQUEST))],
         )?;
 
-        if let Err(err) = nonce_attr.get_from(m) {
+        // each credential is read from the one copy MESSAGE-INTEGRITY covers
+        if let Err(err) = check_attributes(
+            m,
+            &[
+                ATTR_USERNAME,
+                ATTR_REALM,
+                ATTR_NONCE,
+                ATTR_MESSAGE_INTEGRITY,
+            ],
+        ) {
+            log::debug!("malformed request from {}: {}", self.src_addr, err);
             build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await?;
             return Ok(None);
         }
 
-        let to_be_deleted = {
-            // Assert Nonce exists and is not expired
-            let mut nonces = self.nonces.lock().await;
-
-            let to_be_deleted = if let Some(nonce_creation_time) = nonces.get(&nonce_attr.text) {
-                Instant::now().duration_since(*nonce_creation_time) >= NONCE_LIFETIME
-            } else {
-                true
-            };
+        if let Err(err) = nonce_attr.get_from(m) {
+            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into()).await?;
+            return Ok(None);
+        }
 
-            if to_be_deleted {
-                nonces.remove(&nonce_attr.text);
-            }
-            to_be_deleted
-        };
+        // Assert Nonce exists and is not expired
+        let is_fresh = self.nonces.lock().await.is_fresh(&nonce_attr.text);
+        if !is_fresh {
+            self.respond_with_nonce(m, calling_method, CODE_STALE_NONCE)
+                .await?;
+            return Ok(None);
+        }
 
-        if to_be_deleted {
+        // a nonce issued to another IP is left for its owner, the sender is
+        // challenged again with one of its own
+        if self.bind_nonce_to_client_ip && !is_nonce_bound_to(&nonce_attr.text, self.src_addr.ip())
+        {
+            log::debug!("nonce from {} was issued to another IP", self.src_addr);
             self.respond_with_nonce(m, calling_method, CODE_STALE_NONCE)
                 .await?;
             return Ok(None);
         }
 
         if let Err(err) = realm_attr.get_from(m) {
-            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await?;
+            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into()).await?;
             return Ok(None);
         }
         if let Err(err) = username_attr.get_from(m) {
-            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await?;
+            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into()).await?;
             return Ok(None);
         }
 
-        let our_key = match self.auth_handler.auth_handle(
-            &username_attr.to_string(),
-            &realm_attr.to_string(),
-            self.src_addr,
-        ) {
-            Ok(key) => key,
+        // malformed usernames are challenged like a request without
+        // credentials, so the response doesn't tell them from unknown users
+        if !self.is_username_valid(&username_attr.text) {
+            self.emit_auth_failure(AuthFailureReason::MalformedUsername);
+            self.respond_with_nonce(m, calling_method, CODE_UNAUTHORIZED)
+                .await?;
+            return Ok(None);
+        }
+
+        // the client's metadata is only looked at for Allocate requests
+        let (software, origin) = if calling_method == METHOD_ALLOCATE {
+            let mut origin = Origin::default();
+            (
+                Software::get_from_as(m, ATTR_SOFTWARE).ok(),
+                origin.get_from(m).ok().map(|_| origin),
+            )
+        } else {
+            (None, None)
+        };
+        let ctx = AuthContext {
+            username: &username_attr.text,
+            realm: &realm_attr.text,
+            src_addr: self.src_addr,
+            method: calling_method,
+            software: software.as_ref().map(|software| software.text.as_str()),
+            origin: origin.as_ref().map(|origin| origin.0.as_str()),
+        };
+        let our_keys = match self.auth_handler.auth_keys_with_context(&ctx) {
+            Ok(keys) => keys,
             Err(_) => {
+                self.emit_auth_failure(AuthFailureReason::UnknownUser);
                 build_and_send_err(
                     &self.conn,
                     self.src_addr,
                     bad_request_msg,
-                    ERR_NO_SUCH_USER.to_owned(),
+                    Error::Auth(format!("no such user exists: {}", username_attr)),
                 )
                 .await?;
                 return Ok(None);
             }
         };
 
-        let mi = MessageIntegrity(our_key);
-        if let Err(err) = mi.check(&mut m.clone()) {
-            build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await?;
-            Ok(None)
-        } else {
-            Ok(Some(mi))
+        // the request is signed with one of the keys, e.g. of a shared
+        // secret that is being rotated out
+        let mut result = Err(Error::Auth("no key for user".to_owned()));
+        for key in our_keys {
+            let mi = MessageIntegrity(key);
+            result = mi.check(&mut m.clone()).map(|_| mi).map_err(Error::from);
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        match result {
+            Ok(mi) => {
+                if self.intercept_point == InterceptPoint::AfterAuth
+                    && !self.intercept(m, Some((&username_attr.text, &mi))).await?
+                {
+                    return Ok(None);
+                }
+                Ok(Some(mi))
+            }
+            Err(err) => {
+                self.emit_auth_failure(AuthFailureReason::IntegrityMismatch);
+                build_and_send_err(
+                    &self.conn,
+                    self.src_addr,
+                    bad_request_msg,
+                    Error::Auth(err.to_string()),
+                )
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    // intercept passes m to the interceptor with the authenticated username and
+    // key if any, it answers rejected requests and returns whether to go on
+    async fn intercept(
+        &self,
+        m: &Message,
+        auth: Option<(&str, &MessageIntegrity)>,
+    ) -> Result<bool, Error> {
+        let interceptor = match &self.interceptor {
+            Some(interceptor) => interceptor,
+            None => return Ok(true),
+        };
+
+        let ctx = RequestCtx {
+            local_addr: self.conn.local_addr()?,
+            username: auth.map(|(username, _)| username.to_owned()),
+        };
+        let (code, reason) = match interceptor.on_request(m, self.src_addr, &ctx).await {
+            InterceptDecision::Continue => return Ok(true),
+            InterceptDecision::Drop => {
+                log::debug!("interceptor dropped {} from {}", m.typ, self.src_addr);
+                return Ok(false);
+            }
+            InterceptDecision::Reject { code, reason } => (code, reason),
+        };
+
+        log::debug!(
+            "interceptor rejected {} from {}: {}",
+            m.typ,
+            self.src_addr,
+            reason
+        );
+        if m.typ.class != CLASS_REQUEST {
+            return Ok(false);
+        }
+
+        let msg = {
+            let mut attrs: Vec<Box<dyn Setter>> = vec![Box::new(ErrorCodeAttribute {
+                code,
+                reason: reason.into_bytes(),
+            })];
+            if let Some((_, mi)) = auth {
+                attrs.push(Box::new(mi.clone()));
+            }
+            build_msg(
+                m.transaction_id,
+                MessageType::new(m.typ.method, CLASS_ERROR_RESPONSE),
+                attrs,
+            )?
+        };
+        build_and_send(&self.conn, self.src_addr, msg).await?;
+
+        Ok(false)
+    }
+
+    fn is_username_valid(&self, username: &str) -> bool {
+        if username.len() > self.max_username_len {
+            return false;
+        }
+        match &self.username_validator {
+            Some(username_validator) => username_validator(username),
+            None => true,
+        }
+    }
+
+    fn emit_auth_failure(&self, reason: AuthFailureReason) {
+        log::debug!("authentication from {} failed: {:?}", self.src_addr, reason);
+        self.listener_counters.add_auth_failure();
+        if let Some(event_handler) = &self.event_handler {
+            event_handler.on_event(ServerEvent::AuthFailure {
+                src_addr: self.src_addr,
+                reason,
+            });
+        }
+    }
+
+    // check_allocation_credentials answers 441 (Wrong Credentials) unless the
+    // request is authenticated as the user that created the allocation, so a
+    // spoofed 5-tuple is not enough to refresh or extend someone else's
+    // allocation, RFC 5766 Section 4. Send indications and ChannelData carry no
+    // credentials, they are only bound to the allocation by the 5-tuple.
+    async fn check_allocation_credentials(
+        &self,
+        a: &Arc<Mutex<Allocation>>,
+        m: &Message,
+        message_integrity: &MessageIntegrity,
+        calling_method: Method,
+    ) -> Result<(), Error> {
+        let username = Username::get_from_as(m, ATTR_USERNAME)?;
+        {
+            let a = a.lock().await;
+            if a.username == username.text && a.auth_key == message_integrity.0 {
+                return Ok(());
+            }
         }
+
+        let msg = build_msg(
+            m.transaction_id,
+            MessageType::new(calling_method, CLASS_ERROR_RESPONSE),
+            vec![Box::new(error_code_attribute(CODE_WRONG_CREDENTIALS))],
+        )?;
+        build_and_send_err(
+            &self.conn,
+            self.src_addr,
+            msg,
+            Error::Auth(format!("wrong credentials for allocation: {}", username)),
+        )
+        .await
     }
 
     async fn respond_with_nonce(
@@ -229,13 +436,16 @@ impl Request {
         calling_method: Method,
         response_code: ErrorCode,
     ) -> Result<(), Error> {
-        let nonce = build_nonce()?;
+        let mut nonce = build_nonce()?;
+        if self.bind_nonce_to_client_ip {
+            nonce.push_str(&nonce_ip_tag(&nonce, self.src_addr.ip()));
+        }
 
         {
             // Nonce has already been taken
             let mut nonces = self.nonces.lock().await;
             if nonces.contains_key(&nonce) {
-                return Err(ERR_DUPLICATED_NONCE.to_owned());
+                return Err(Error::DuplicatedNonce);
             }
             nonces.insert(nonce.clone(), Instant::now());
         }
@@ -244,10 +454,7 @@ impl Request {
             m.transaction_id,
             MessageType::new(calling_method, CLASS_ERROR_RESPONSE),
             vec![
-                Box::new(ErrorCodeAttribute {
-                    code: response_code,
-                    reason: vec![],
-                }),
+                Box::new(error_code_attribute(response_code)),
                 Box::new(Nonce::new(ATTR_NONCE, nonce)),
                 Box::new(Realm::new(ATTR_REALM, self.realm.clone())),
             ],
@@ -256,9 +463,45 @@ impl Request {
         build_and_send(&self.conn, self.src_addr, msg).await
     }
 
+    // reject_stun_only answers a TURN request on a STUN-only listener with 403
+    // (Forbidden), indications are dropped
+    async fn reject_stun_only(&self, m: &Message) -> Result<(), Error> {
+        log::debug!("{} from {} on a STUN-only listener", m.typ, self.src_addr);
+        if m.typ.class != CLASS_REQUEST {
+            return Ok(());
+        }
+
+        let msg = build_msg(
+            m.transaction_id,
+            MessageType::new(m.typ.method, CLASS_ERROR_RESPONSE),
+            vec![Box::new(ErrorCodeAttribute {
+                code: CODE_FORBIDDEN,
+                reason: b"STUN only".to_vec(),
+            })],
+        )?;
+        build_and_send(&self.conn, self.src_addr, msg).await
+    }
+
+    // handle_binding_request answers without authentication, from clients
+    // without an allocation only with stun_only_binding set
     pub(crate) async fn handle_binding_request(&mut self, m: &Message) -> Result<(), Error> {
         log::debug!("received BindingRequest from {}", self.src_addr);
 
+        if !self.stun_only_binding && !self.stun_only {
+            let a = self
+                .allocation_manager
+                .get_allocation(&FiveTuple {
+                    src_addr: self.src_addr,
+                    dst_addr: self.conn.local_addr()?,
+                    protocol: PROTO_UDP,
+                })
+                .await;
+            if a.is_none() {
+                log::debug!("no allocation for BindingRequest from {}", self.src_addr);
+                return Ok(());
+            }
+        }
+
         let (ip, port) = (self.src_addr.ip(), self.src_addr.port());
 
         let msg = build_msg(
@@ -310,16 +553,13 @@ impl Request {
             let msg = build_msg(
                 m.transaction_id,
                 MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                vec![Box::new(ErrorCodeAttribute {
-                    code: CODE_ALLOC_MISMATCH,
-                    reason: vec![],
-                })],
+                vec![Box::new(error_code_attribute(CODE_ALLOC_MISMATCH))],
             )?;
             return build_and_send_err(
                 &self.conn,
                 self.src_addr,
                 msg,
-                ERR_RELAY_ALREADY_ALLOCATED_FOR_FIVE_TUPLE.to_owned(),
+                Error::RelayAlreadyAllocatedForFiveTuple,
             )
             .await;
         }
@@ -335,26 +575,21 @@ impl Request {
             let bad_request_msg = build_msg(
                 m.transaction_id,
                 MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                vec![Box::new(ErrorCodeAttribute {
-                    code: CODE_BAD_REQUEST,
-                    reason: vec![],
-                })],
+                vec![Box::new(error_code_attribute(CODE_BAD_REQUEST))],
             )?;
-            return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await;
+            return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into())
+                .await;
         } else if requested_transport.protocol != PROTO_UDP {
             let msg = build_msg(
                 m.transaction_id,
                 MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                vec![Box::new(ErrorCodeAttribute {
-                    code: CODE_UNSUPPORTED_TRANS_PROTO,
-                    reason: vec![],
-                })],
+                vec![Box::new(error_code_attribute(CODE_UNSUPPORTED_TRANS_PROTO))],
             )?;
             return build_and_send_err(
                 &self.conn,
                 self.src_addr,
                 msg,
-                ERR_REQUESTED_TRANSPORT_MUST_BE_UDP.to_owned(),
+                Error::RequestedTransportMustBeUdp,
             )
             .await;
         }
@@ -369,10 +604,7 @@ impl Request {
                 m.transaction_id,
                 MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
                 vec![
-                    Box::new(ErrorCodeAttribute {
-                        code: CODE_UNKNOWN_ATTRIBUTE,
-                        reason: vec![],
-                    }),
+                    Box::new(error_code_attribute(CODE_UNKNOWN_ATTRIBUTE)),
                     Box::new(UnknownAttributes(vec![ATTR_DONT_FRAGMENT])),
                 ],
             )?;
@@ -380,7 +612,7 @@ impl Request {
                 &self.conn,
                 self.src_addr,
                 msg,
-                ERR_NO_DONT_FRAGMENT_SUPPORT.to_owned(),
+                Error::NoDontFragmentSupport,
             )
             .await;
         }
@@ -400,16 +632,13 @@ impl Request {
                 let bad_request_msg = build_msg(
                     m.transaction_id,
                     MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                    vec![Box::new(ErrorCodeAttribute {
-                        code: CODE_BAD_REQUEST,
-                        reason: vec![],
-                    })],
+                    vec![Box::new(error_code_attribute(CODE_BAD_REQUEST))],
                 )?;
                 return build_and_send_err(
                     &self.conn,
                     self.src_addr,
                     bad_request_msg,
-                    ERR_REQUEST_WITH_RESERVATION_TOKEN_AND_EVEN_PORT.to_owned(),
+                    Error::RequestWithReservationTokenAndEvenPort,
                 )
                 .await;
             }
@@ -432,10 +661,7 @@ impl Request {
                         let insufficent_capacity_msg = build_msg(
                             m.transaction_id,
                             MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                            vec![Box::new(ErrorCodeAttribute {
-                                code: CODE_INSUFFICIENT_CAPACITY,
-                                reason: vec![],
-                            })],
+                            vec![Box::new(error_code_attribute(CODE_INSUFFICIENT_CAPACITY))],
                         )?;
                         return build_and_send_err(
                             &self.conn,
@@ -463,7 +689,8 @@ impl Request {
         //    with a 300 (Try Alternate) error if it wishes to redirect the
         //    client to a different server.  The use of this error code and
         //    attribute follow the specification in [RFC5389].
-        let lifetime_duration = allocation_lifetime(m);
+        let lifetime_duration = jitter_lifetime(allocation_lifetime(m), self.lifetime_jitter);
+        let username = Username::get_from_as(m, ATTR_USERNAME)?;
         let a = match self
             .allocation_manager
             .create_allocation(
@@ -471,18 +698,27 @@ impl Request {
                 Arc::clone(&self.conn),
                 requested_port,
                 lifetime_duration,
+                &username.text,
+                &message_integrity.0,
             )
             .await
         {
-            Ok(a) => a,
+            Ok(a) => {
+                self.listener_counters.add_allocation();
+                a
+            }
             Err(err) => {
+                // over max_allocations the configured code is used, 486 or 508
+                let code = match (&err, self.allocation_manager.allocation_limit()) {
+                    (Error::MaxAllocationsReached, Some(allocation_limit)) => {
+                        allocation_limit.code()
+                    }
+                    _ => CODE_INSUFFICIENT_CAPACITY,
+                };
                 let insufficent_capacity_msg = build_msg(
                     m.transaction_id,
                     MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
-                    vec![Box::new(ErrorCodeAttribute {
-                        code: CODE_INSUFFICIENT_CAPACITY,
-                        reason: vec![],
-                    })],
+                    vec![Box::new(error_code_attribute(code))],
                 )?;
                 return build_and_send_err(
                     &self.conn,
@@ -565,16 +801,23 @@ impl Request {
             protocol: PROTO_UDP,
         };
 
+        let a = self.allocation_manager.get_allocation(&five_tuple).await;
+        if let Some(a) = &a {
+            self.check_allocation_credentials(a, m, &message_integrity, METHOD_REFRESH)
+                .await?;
+        }
+
         if lifetime_duration != Duration::from_secs(0) {
-            let a = self.allocation_manager.get_allocation(&five_tuple).await;
             if let Some(a) = a {
                 let a = a.lock().await;
                 a.refresh(lifetime_duration).await;
             } else {
-                return Err(ERR_NO_ALLOCATION_FOUND.to_owned());
+                return self.reject_no_allocation(m, METHOD_REFRESH).await;
             }
         } else {
-            self.allocation_manager.delete_allocation(&five_tuple).await;
+            self.allocation_manager
+                .delete_allocation(&five_tuple, AllocationCloseReason::ClientDeallocated)
+                .await;
         }
 
         let msg = build_msg(
@@ -614,24 +857,18 @@ impl Request {
                 log::debug!("no MessageIntegrity");
                 return Ok(());
             };
+            self.check_allocation_credentials(&a, m, &message_integrity, METHOD_CREATE_PERMISSION)
+                .await?;
             let mut add_count = 0;
 
-            {
+            // a malformed XOR-PEER-ADDRESS fails the whole request
+            if let Ok(peer_addresses) = PeerAddress::get_all_from(m) {
                 let a = a.lock().await;
-                for attr in &m.attributes.0 {
-                    if attr.typ != ATTR_XOR_PEER_ADDRESS {
-                        continue;
-                    }
-
-                    let mut peer_address = PeerAddress::default();
-                    if peer_address.get_from(m).is_err() {
-                        add_count = 0;
-                        break;
-                    }
-
+                for peer_address in peer_addresses {
                     log::debug!(
-                        "adding permission for {}",
-                        format!("{}:{}", peer_address.ip, peer_address.port)
+                        "adding permission for {}:{}",
+                        peer_address.ip,
+                        peer_address.port
                     );
 
                     a.add_permission(Permission::new(SocketAddr::new(
@@ -656,12 +893,12 @@ impl Request {
 
             build_and_send(&self.conn, self.src_addr, msg).await
         } else {
-            Err(ERR_NO_ALLOCATION_FOUND.to_owned())
+            self.reject_no_allocation(m, METHOD_CREATE_PERMISSION).await
         }
     }
 
     pub(crate) async fn handle_send_indication(&mut self, m: &Message) -> Result<(), Error> {
-        log::debug!("received SendIndication from {}", self.src_addr);
+        debug_limited!("received SendIndication from {}", self.src_addr);
 
         let a = self
             .allocation_manager
@@ -686,18 +923,13 @@ impl Request {
                 a.has_permission(&msg_dst).await
             };
             if !has_perm {
-                return Err(ERR_NO_PERMISSION.to_owned());
+                return Err(Error::NoPermission);
             }
 
             let a = a.lock().await;
-            let l = a.relay_socket.send_to(&data_attr.0, msg_dst).await?;
-            if l != data_attr.0.len() {
-                Err(ERR_SHORT_WRITE.to_owned())
-            } else {
-                Ok(())
-            }
+            a.relay_to_peer(data_attr.0, 0, msg_dst)
         } else {
-            Err(ERR_NO_ALLOCATION_FOUND.to_owned())
+            Err(Error::NoAllocationFound)
         }
     }
 
@@ -717,10 +949,7 @@ impl Request {
             let bad_request_msg = build_msg(
                 m.transaction_id,
                 MessageType::new(METHOD_CHANNEL_BIND, CLASS_ERROR_RESPONSE),
-                vec![Box::new(ErrorCodeAttribute {
-                    code: CODE_BAD_REQUEST,
-                    reason: vec![],
-                })],
+                vec![Box::new(error_code_attribute(CODE_BAD_REQUEST))],
             )?;
 
             let message_integrity =
@@ -730,20 +959,25 @@ impl Request {
                     log::debug!("no MessageIntegrity");
                     return Ok(());
                 };
+            self.check_allocation_credentials(&a, m, &message_integrity, METHOD_CHANNEL_BIND)
+                .await?;
             let mut channel = ChannelNumber::default();
             if let Err(err) = channel.get_from(m) {
-                return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await;
+                return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into())
+                    .await;
             }
 
             let mut peer_addr = PeerAddress::default();
             if let Err(err) = peer_addr.get_from(m) {
-                return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err).await;
+                return build_and_send_err(&self.conn, self.src_addr, bad_request_msg, err.into())
+                    .await;
             }
 
             log::debug!(
-                "binding channel {} to {}",
+                "binding channel {} to {}:{}",
                 channel,
-                format!("{}:{}", peer_addr.ip, peer_addr.port)
+                peer_addr.ip,
+                peer_addr.port
             );
 
             let result = {
@@ -765,12 +999,30 @@ impl Request {
             )?;
             return build_and_send(&self.conn, self.src_addr, msg).await;
         } else {
-            Err(ERR_NO_ALLOCATION_FOUND.to_owned())
+            self.reject_no_allocation(m, METHOD_CHANNEL_BIND).await
         }
     }
 
-    pub(crate) async fn handle_channel_data(&mut self, c: &ChannelData) -> Result<(), Error> {
-        log::debug!("received ChannelData from {}", self.src_addr);
+    // reject_no_allocation answers a request for an allocation the 5-tuple
+    // doesn't have, e.g. lost when the server restarted, with 437 (Allocation
+    // Mismatch) so the client can allocate again instead of timing out
+    async fn reject_no_allocation(&self, m: &Message, method: Method) -> Result<(), Error> {
+        let msg = build_msg(
+            m.transaction_id,
+            MessageType::new(method, CLASS_ERROR_RESPONSE),
+            vec![Box::new(error_code_attribute(CODE_ALLOC_MISMATCH))],
+        )?;
+        build_and_send_err(&self.conn, self.src_addr, msg, Error::NoAllocationFound).await
+    }
+
+    // handle_channel_data relays the ChannelData message in raw, its data
+    // starts at CHANNEL_DATA_HEADER_SIZE
+    pub(crate) async fn handle_channel_data(
+        &mut self,
+        number: ChannelNumber,
+        raw: Vec<u8>,
+    ) -> Result<(), Error> {
+        debug_limited!("received ChannelData from {}", self.src_addr);
 
         let a = self
             .allocation_manager
@@ -783,34 +1035,48 @@ impl Request {
 
         if let Some(a) = a {
             let a = a.lock().await;
-            let channel = a.get_channel_addr(&c.number).await;
+            let channel = a.get_channel_addr(&number).await;
             if let Some(peer) = channel {
-                let l = a.relay_socket.send_to(&c.data, peer).await?;
-                if l != c.data.len() {
-                    Err(ERR_SHORT_WRITE.to_owned())
-                } else {
-                    Ok(())
-                }
+                a.relay_to_peer(raw, CHANNEL_DATA_HEADER_SIZE, peer)
             } else {
-                Err(ERR_NO_SUCH_CHANNEL_BIND.to_owned())
+                Err(Error::NoSuchChannelBind)
             }
         } else {
-            Err(ERR_NO_ALLOCATION_FOUND.to_owned())
+            Err(Error::NoAllocationFound)
         }
     }
 }
 
+// is_relay_traffic is whether buf is ChannelData or a STUN indication, going
+// by the first two bytes only
+pub(crate) fn is_relay_traffic(buf: &[u8]) -> bool {
+    if let PacketClass::ChannelData { .. } = classify(buf) {
+        return true;
+    }
+    if buf.len() < 2 {
+        return false;
+    }
+    let mut typ = MessageType::default();
+    typ.read_value(u16::from_be_bytes([buf[0], buf[1]]));
+    typ.class == CLASS_INDICATION
+}
+
+// is_authenticated is whether m is a request that carries credentials
+fn is_authenticated(m: &Message) -> bool {
+    m.typ.class == CLASS_REQUEST
+        && matches!(
+            m.typ.method,
+            METHOD_ALLOCATE | METHOD_REFRESH | METHOD_CREATE_PERMISSION | METHOD_CHANNEL_BIND
+        )
+}
+
 pub(crate) fn rand_seq(n: usize) -> String {
     let letters = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ".as_bytes();
     let mut buf = vec![0u8; n];
     for b in &mut buf {
         *b = letters[rand::random::<usize>() % letters.len()];
     }
-    if let Ok(s) = String::from_utf8(buf) {
-        s
-    } else {
-        String::new()
-    }
+    String::from_utf8(buf).unwrap_or_default()
 }
 
 pub(crate) fn build_nonce() -> Result<String, Error> {
@@ -832,6 +1098,27 @@ pub(crate) fn build_nonce() -> Result<String, Error> {
     Ok(format!("{:x}", h.finalize()))
 }
 
+// nonce_ip_tag binds a nonce to ip. The tag needs no secret, a nonce is only
+// accepted while it is in the server's nonce table so a forged one is stale.
+fn nonce_ip_tag(nonce: &str, ip: IpAddr) -> String {
+    let mut h = Md5::new();
+    h.update(nonce.as_bytes());
+    h.update(ip.to_string().as_bytes());
+    format!("{:x}", h.finalize())
+}
+
+// is_nonce_bound_to checks a nonce ends with the nonce_ip_tag of ip
+fn is_nonce_bound_to(nonce: &str, ip: IpAddr) -> bool {
+    // the tag is a hex MD5 digest
+    const TAG_LEN: usize = 32;
+    if nonce.len() <= TAG_LEN || !nonce.is_char_boundary(nonce.len() - TAG_LEN) {
+        return false;
+    }
+
+    let (nonce, tag) = nonce.split_at(nonce.len() - TAG_LEN);
+    nonce_ip_tag(nonce, ip) == tag
+}
+
 pub(crate) async fn build_and_send(
     conn: &Arc<dyn Conn + Send + Sync>,
     dst: SocketAddr,
@@ -885,3 +1172,17 @@ pub(crate) fn allocation_lifetime(m: &Message) -> Duration {
 
     lifetime_duration
 }
+
+// jitter_lifetime perturbs lifetime by a random fraction of up to ±jitter, the
+// result is in whole seconds, as LIFETIME carries, and within 1 second and
+// MAXIMUM_ALLOCATION_LIFETIME
+pub(crate) fn jitter_lifetime(lifetime: Duration, jitter: Option<f64>) -> Duration {
+    let jitter = match jitter {
+        Some(jitter) if jitter > 0.0 => jitter,
+        _ => return lifetime,
+    };
+
+    let factor = 1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0);
+    let secs = (lifetime.as_secs_f64() * factor).round() as u64;
+    Duration::from_secs(secs.clamp(1, MAXIMUM_ALLOCATION_LIFETIME.as_secs()))
+}
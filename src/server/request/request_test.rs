@@ -1,10 +1,17 @@
 use super::*;
+use crate::allocation::allocation_limit::AllocationLimit;
+use crate::allocation::relay_workers::RelayReadMode;
+use crate::proto::channum::MIN_CHANNEL_NUMBER;
 use crate::relay::relay_none::*;
+use crate::relay::PortSelection;
+use crate::server::config::MaxAllocationsCode;
+use crate::server::event::*;
 
-use util::Error;
+use crate::error::Error;
 
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use tokio::net::UdpSocket;
 use tokio::time::{Duration, Instant};
@@ -73,7 +80,13 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<(), Error>
     let allocation_manager = Arc::new(Manager::new(ManagerConfig {
         relay_addr_generator: Box::new(RelayAddressGeneratorNone {
             address: "0.0.0.0".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
         }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
     }));
 
     let socket = SocketAddr::new(IpAddr::from_str("127.0.0.1")?, 5000);
@@ -102,6 +115,8 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<(), Error>
             Arc::clone(&r.conn),
             0,
             Duration::from_secs(3600),
+            STATIC_KEY,
+            STATIC_KEY.as_bytes(),
         )
         .await?;
     assert!(r
@@ -112,10 +127,10 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<(), Error>
 
     let mut m = Message::new();
     Lifetime::default().add_to(&mut m)?;
-    MessageIntegrity(STATIC_KEY.as_bytes().to_vec()).add_to(&mut m)?;
     Nonce::new(ATTR_NONCE, STATIC_KEY.to_owned()).add_to(&mut m)?;
     Realm::new(ATTR_REALM, STATIC_KEY.to_owned()).add_to(&mut m)?;
     Username::new(ATTR_USERNAME, STATIC_KEY.to_owned()).add_to(&mut m)?;
+    MessageIntegrity(STATIC_KEY.as_bytes().to_vec()).add_to(&mut m)?;
 
     r.handle_refresh_request(&m).await?;
     assert!(r
@@ -126,3 +141,733 @@ async fn test_allocation_lifetime_deletion_zero_lifetime() -> Result<(), Error>
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_allocation_rejects_other_username() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "0.0.0.0".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+
+    // requests from the same 5-tuple as the allocation, authenticated as
+    // another valid user
+    let mut r = Request::new(
+        l,
+        client.local_addr()?,
+        allocation_manager,
+        Arc::new(Box::new(TestAuthHandler {})),
+    );
+    {
+        let mut nonces = r.nonces.lock().await;
+        nonces.insert(STATIC_KEY.to_owned(), Instant::now());
+    }
+
+    let five_tuple = FiveTuple {
+        src_addr: r.src_addr,
+        dst_addr: r.conn.local_addr()?,
+        protocol: PROTO_UDP,
+    };
+    r.allocation_manager
+        .create_allocation(
+            five_tuple.clone(),
+            Arc::clone(&r.conn),
+            0,
+            Duration::from_secs(3600),
+            "alice",
+            STATIC_KEY.as_bytes(),
+        )
+        .await?;
+
+    let new_request = |method: Method, setters: Vec<Box<dyn Setter>>| -> Result<Message, Error> {
+        let mut m = Message::new();
+        let mut all: Vec<Box<dyn Setter>> = vec![
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(method, CLASS_REQUEST)),
+        ];
+        all.extend(setters);
+        all.push(Box::new(Username::new(ATTR_USERNAME, "mallory".to_owned())));
+        all.push(Box::new(Realm::new(ATTR_REALM, STATIC_KEY.to_owned())));
+        all.push(Box::new(Nonce::new(ATTR_NONCE, STATIC_KEY.to_owned())));
+        all.push(Box::new(MessageIntegrity(STATIC_KEY.as_bytes().to_vec())));
+        m.build(&all)?;
+        Ok(m)
+    };
+
+    let peer = SocketAddr::new(IpAddr::from_str("127.0.0.1")?, 6000);
+    let tests: Vec<(Method, Vec<Box<dyn Setter>>)> = vec![
+        (
+            METHOD_REFRESH,
+            vec![Box::new(Lifetime(Duration::from_secs(0)))],
+        ),
+        (
+            METHOD_CREATE_PERMISSION,
+            vec![Box::new(PeerAddress {
+                ip: peer.ip(),
+                port: peer.port(),
+            })],
+        ),
+        (
+            METHOD_CHANNEL_BIND,
+            vec![
+                Box::new(ChannelNumber(MIN_CHANNEL_NUMBER)),
+                Box::new(PeerAddress {
+                    ip: peer.ip(),
+                    port: peer.port(),
+                }),
+            ],
+        ),
+    ];
+
+    for (method, setters) in tests {
+        let m = new_request(method, setters)?;
+        let result = match method {
+            METHOD_REFRESH => r.handle_refresh_request(&m).await,
+            METHOD_CREATE_PERMISSION => r.handle_create_permission_request(&m).await,
+            _ => r.handle_channel_bind_request(&m).await,
+        };
+        assert!(
+            matches!(result, Err(Error::Auth(_))),
+            "{}: expected Auth error",
+            method
+        );
+
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = client.recv_from(&mut buf).await?;
+        let mut res = Message::new();
+        res.raw = buf[..n].to_vec();
+        res.decode()?;
+        assert_eq!(MessageType::new(method, CLASS_ERROR_RESPONSE), res.typ);
+        assert!(
+            matches!(
+                Error::from_error_response(&res),
+                Error::Protocol { code: 441, .. }
+            ),
+            "{}: expected 441 Wrong Credentials",
+            method
+        );
+    }
+
+    let a = r
+        .allocation_manager
+        .get_allocation(&five_tuple)
+        .await
+        .expect("allocation should survive");
+    let a = a.lock().await;
+    assert!(!a.has_permission(&peer).await);
+    assert!(a
+        .get_channel_addr(&ChannelNumber(MIN_CHANNEL_NUMBER))
+        .await
+        .is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_channel_data_relay_keeps_payload() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+    let a = allocation_manager
+        .create_allocation(
+            FiveTuple {
+                src_addr: client.local_addr()?,
+                dst_addr: l.local_addr()?,
+                protocol: PROTO_UDP,
+            },
+            Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+            0,
+            Duration::from_secs(3600),
+            STATIC_KEY,
+            STATIC_KEY.as_bytes(),
+        )
+        .await?;
+    let number = ChannelNumber(MIN_CHANNEL_NUMBER);
+    let relay_addr = {
+        let a = a.lock().await;
+        a.add_channel_bind(
+            ChannelBind::new(number, peer.local_addr()?),
+            Duration::from_secs(3600),
+        )
+        .await?;
+        SocketAddr::new(IpAddr::from_str("127.0.0.1")?, a.relay_addr.port())
+    };
+
+    let mut buf = vec![0u8; 2048];
+    // lengths around the 4 byte padding boundary and a full MTU
+    for len in [0usize, 1, 2, 3, 4, 5, 1500].iter() {
+        let payload: Vec<u8> = (0..*len).map(|i| (i * 7 + len) as u8).collect();
+        let mut channel_data = ChannelData {
+            data: payload.clone(),
+            number,
+            raw: vec![],
+        };
+        channel_data.encode()?;
+
+        // client to peer, the padded ChannelData is stripped to the payload
+        let mut r = Request::new(
+            Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+            client.local_addr()?,
+            Arc::clone(&allocation_manager),
+            Arc::new(Box::new(TestAuthHandler {})),
+        );
+        r.buff = channel_data.raw.clone();
+        r.handle_request().await?;
+        let (n, from) = peer.recv_from(&mut buf).await?;
+        assert_eq!(from, relay_addr);
+        assert_eq!(&buf[..n], &payload[..], "payload of {} bytes to peer", len);
+
+        // peer to client, the payload is framed exactly as encode does
+        peer.send_to(&payload, relay_addr).await?;
+        let (n, _) = client.recv_from(&mut buf).await?;
+        assert_eq!(
+            &buf[..n],
+            &channel_data.raw[..],
+            "ChannelData of {} bytes to client",
+            len
+        );
+    }
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+fn new_allocate_message(username: &str, lifetime: Duration) -> Result<Message, Error> {
+    new_allocate_message_with_nonce(username, lifetime, STATIC_KEY)
+}
+
+fn new_allocate_message_with_nonce(
+    username: &str,
+    lifetime: Duration,
+    nonce: &str,
+) -> Result<Message, Error> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        Box::new(RequestedTransport {
+            protocol: PROTO_UDP,
+        }),
+        Box::new(Lifetime(lifetime)),
+        Box::new(Username::new(ATTR_USERNAME, username.to_owned())),
+        Box::new(Realm::new(ATTR_REALM, STATIC_KEY.to_owned())),
+        Box::new(Nonce::new(ATTR_NONCE, nonce.to_owned())),
+        Box::new(MessageIntegrity(STATIC_KEY.as_bytes().to_vec())),
+    ])?;
+    Ok(m)
+}
+
+#[tokio::test]
+async fn test_allocation_lifetime_jitter() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+
+    let requested = Duration::from_secs(600);
+    let mut granted = vec![];
+    for _ in 0..20 {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut r = Request::new(
+            Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+            client.local_addr()?,
+            Arc::clone(&allocation_manager),
+            Arc::new(Box::new(TestAuthHandler {})),
+        );
+        r.lifetime_jitter = Some(0.2);
+        {
+            let mut nonces = r.nonces.lock().await;
+            nonces.insert(STATIC_KEY.to_owned(), Instant::now());
+        }
+
+        r.handle_allocate_request(&new_allocate_message(STATIC_KEY, requested)?)
+            .await?;
+
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = client.recv_from(&mut buf).await?;
+        let mut res = Message::new();
+        res.raw = buf[..n].to_vec();
+        res.decode()?;
+        assert_eq!(
+            MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE),
+            res.typ
+        );
+
+        let mut lifetime = Lifetime::default();
+        lifetime.get_from(&res)?;
+        assert!(
+            lifetime.0 >= Duration::from_secs(480) && lifetime.0 <= Duration::from_secs(720),
+            "granted lifetime {:?} is outside 600s ±20%",
+            lifetime.0
+        );
+        granted.push(lifetime.0);
+    }
+
+    assert!(
+        granted.iter().any(|lifetime| *lifetime != granted[0]),
+        "granted lifetimes should vary, got {:?}",
+        granted
+    );
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_jitter_lifetime_bounds() {
+    let lifetime = Duration::from_secs(600);
+    assert_eq!(lifetime, jitter_lifetime(lifetime, None));
+    assert_eq!(lifetime, jitter_lifetime(lifetime, Some(0.0)));
+
+    for _ in 0..1000 {
+        let jittered = jitter_lifetime(lifetime, Some(0.5));
+        assert!(jittered >= Duration::from_secs(300) && jittered <= Duration::from_secs(900));
+        assert_eq!(0, jittered.subsec_nanos(), "LIFETIME is in whole seconds");
+
+        // never zero, which would delete the allocation, nor above the maximum
+        let jittered = jitter_lifetime(Duration::from_secs(1), Some(0.5));
+        assert_eq!(Duration::from_secs(1), jittered);
+        let jittered = jitter_lifetime(MAXIMUM_ALLOCATION_LIFETIME, Some(0.5));
+        assert!(jittered <= MAXIMUM_ALLOCATION_LIFETIME);
+    }
+}
+
+#[tokio::test]
+async fn test_allocate_over_max_allocations() -> Result<(), Error> {
+    for (code, expected) in [
+        (MaxAllocationsCode::default(), CODE_ALLOC_QUOTA_REACHED),
+        (
+            MaxAllocationsCode::InsufficientCapacity,
+            CODE_INSUFFICIENT_CAPACITY,
+        ),
+    ] {
+        let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+            relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+                address: "127.0.0.1".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+            relay_queue_size: 0,
+            relay_read_mode: RelayReadMode::default(),
+            allocation_limit: Some(Arc::new(AllocationLimit::new(1, code.into(), None))),
+            event_handler: None,
+        }));
+
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let client = UdpSocket::bind("127.0.0.1:0").await?;
+            let mut r = Request::new(
+                Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+                client.local_addr()?,
+                Arc::clone(&allocation_manager),
+                Arc::new(Box::new(TestAuthHandler {})),
+            );
+            {
+                let mut nonces = r.nonces.lock().await;
+                nonces.insert(STATIC_KEY.to_owned(), Instant::now());
+            }
+            let result = r
+                .handle_allocate_request(&new_allocate_message(STATIC_KEY, DEFAULT_LIFETIME)?)
+                .await;
+
+            let mut buf = vec![0u8; 1500];
+            let (n, _) = client.recv_from(&mut buf).await?;
+            let mut res = Message::new();
+            res.raw = buf[..n].to_vec();
+            res.decode()?;
+            responses.push((result, res));
+        }
+
+        let (result, res) = &responses[0];
+        assert!(result.is_ok());
+        assert_eq!(
+            MessageType::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE),
+            res.typ
+        );
+
+        // the second client is over the cap of one
+        let (result, res) = &responses[1];
+        assert!(matches!(result, Err(Error::MaxAllocationsReached)));
+        assert_eq!(
+            MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE),
+            res.typ
+        );
+        let mut error_code = ErrorCodeAttribute::default();
+        error_code.get_from(res)?;
+        assert!(expected == error_code.code, "unexpected {}", error_code);
+
+        allocation_manager.close().await?;
+    }
+
+    Ok(())
+}
+
+struct CountingAuthHandler {
+    calls: Arc<AtomicUsize>,
+}
+
+impl AuthHandler for CountingAuthHandler {
+    fn auth_handle(
+        &self,
+        _username: &str,
+        _realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(STATIC_KEY.as_bytes().to_vec())
+    }
+}
+
+struct RecordingEventHandler {
+    events: Arc<std::sync::Mutex<Vec<ServerEvent>>>,
+}
+
+impl EventHandler for RecordingEventHandler {
+    fn on_event(&self, event: ServerEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[tokio::test]
+async fn test_authenticate_malformed_username() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>> =
+        Arc::new(Box::new(CountingAuthHandler {
+            calls: Arc::clone(&calls),
+        }));
+    let event_handler: Arc<dyn EventHandler + Send + Sync> = Arc::new(RecordingEventHandler {
+        events: Arc::clone(&events),
+    });
+    let pattern = regex::Regex::new("^t[0-9]+:[0-9a-f]{8}$").unwrap();
+    let username_validator: UsernameValidator =
+        Arc::new(move |username: &str| pattern.is_match(username));
+
+    let tests = vec![
+        ("t42:0badcafe", true),
+        ("t42:0BADCAFE", false),
+        ("t42", false),
+        ("", false),
+        // matches the pattern but is over max_username_len
+        ("t4242424242:0badcafe", false),
+    ];
+
+    for (username, valid) in tests {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut r = Request::new(
+            Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+            client.local_addr()?,
+            Arc::clone(&allocation_manager),
+            Arc::clone(&auth_handler),
+        );
+        r.username_validator = Some(Arc::clone(&username_validator));
+        r.max_username_len = 16;
+        r.event_handler = Some(Arc::clone(&event_handler));
+        {
+            let mut nonces = r.nonces.lock().await;
+            nonces.insert(STATIC_KEY.to_owned(), Instant::now());
+        }
+
+        let calls_before = calls.load(Ordering::SeqCst);
+        let m = new_allocate_message(username, DEFAULT_LIFETIME)?;
+        let mi = r.authenticate_request(&m, METHOD_ALLOCATE).await?;
+        assert_eq!(valid, mi.is_some(), "username {:?}", username);
+        if valid {
+            assert_eq!(calls_before + 1, calls.load(Ordering::SeqCst));
+            continue;
+        }
+        assert_eq!(
+            calls_before,
+            calls.load(Ordering::SeqCst),
+            "auth handler called for {:?}",
+            username
+        );
+        assert_eq!(
+            Some(&ServerEvent::AuthFailure {
+                src_addr: client.local_addr()?,
+                reason: AuthFailureReason::MalformedUsername,
+            }),
+            events.lock().unwrap().last()
+        );
+
+        // rejected like a request without credentials, not with 400
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = client.recv_from(&mut buf).await?;
+        let mut res = Message::new();
+        res.raw = buf[..n].to_vec();
+        res.decode()?;
+        let mut error_code = ErrorCodeAttribute::default();
+        error_code.get_from(&res)?;
+        assert!(
+            CODE_UNAUTHORIZED == error_code.code,
+            "unexpected {}",
+            error_code
+        );
+        assert!(res.contains(ATTR_NONCE) && res.contains(ATTR_REALM));
+    }
+    assert_eq!(4, events.lock().unwrap().len());
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_authenticate_rejects_malformed_attributes() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+
+    let mut r = Request::new(
+        l,
+        client.local_addr()?,
+        Arc::clone(&allocation_manager),
+        Arc::new(Box::new(TestAuthHandler {})),
+    );
+    {
+        let mut nonces = r.nonces.lock().await;
+        nonces.insert(STATIC_KEY.to_owned(), Instant::now());
+    }
+
+    let username = || Box::new(Username::new(ATTR_USERNAME, "alice".to_owned()));
+    let realm = || Box::new(Realm::new(ATTR_REALM, STATIC_KEY.to_owned()));
+    let nonce = || Box::new(Nonce::new(ATTR_NONCE, STATIC_KEY.to_owned()));
+    let integrity = || Box::new(MessageIntegrity(STATIC_KEY.as_bytes().to_vec()));
+    let software = || Box::new(Software::new(ATTR_SOFTWARE, "mallory".to_owned()));
+
+    type Setters = Vec<Box<dyn Setter>>;
+    let tests: Vec<(&str, Setters, bool)> = vec![
+        (
+            "fingerprint after integrity",
+            vec![
+                username(),
+                realm(),
+                nonce(),
+                integrity(),
+                Box::new(FINGERPRINT),
+            ],
+            true,
+        ),
+        (
+            "duplicate username",
+            vec![username(), username(), realm(), nonce(), integrity()],
+            false,
+        ),
+        (
+            "duplicate realm",
+            vec![username(), realm(), realm(), nonce(), integrity()],
+            false,
+        ),
+        (
+            "duplicate nonce",
+            vec![username(), realm(), nonce(), nonce(), integrity()],
+            false,
+        ),
+        (
+            "duplicate integrity",
+            vec![username(), realm(), nonce(), integrity(), integrity()],
+            false,
+        ),
+        (
+            "attribute after integrity",
+            vec![username(), realm(), nonce(), integrity(), software()],
+            false,
+        ),
+        (
+            "attribute after fingerprint",
+            vec![
+                username(),
+                realm(),
+                nonce(),
+                integrity(),
+                Box::new(FINGERPRINT),
+                software(),
+            ],
+            false,
+        ),
+    ];
+
+    for (name, setters, valid) in tests {
+        let mut m = Message::new();
+        let mut all: Vec<Box<dyn Setter>> = vec![
+            Box::new(TransactionId::new()),
+            Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        ];
+        all.extend(setters);
+        m.build(&all)?;
+
+        let result = r.authenticate_request(&m, METHOD_ALLOCATE).await;
+        if valid {
+            assert!(matches!(result, Ok(Some(_))), "{}: expected success", name);
+            continue;
+        }
+        assert!(
+            matches!(
+                result,
+                Err(Error::DuplicateAttribute(_)) | Err(Error::AttributeAfter(_, _))
+            ),
+            "{}: unexpected {:?}",
+            name,
+            result.err()
+        );
+
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = client.recv_from(&mut buf).await?;
+        let mut res = Message::new();
+        res.raw = buf[..n].to_vec();
+        res.decode()?;
+        let mut error_code = ErrorCodeAttribute::default();
+        error_code.get_from(&res)?;
+        assert!(
+            CODE_BAD_REQUEST == error_code.code,
+            "{}: unexpected {}",
+            name,
+            error_code
+        );
+    }
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
+
+// recv_error_response reads an error response, returning its code and NONCE
+async fn recv_error_response(conn: &UdpSocket) -> Result<(ErrorCode, String), Error> {
+    let mut buf = vec![0u8; 1500];
+    let (n, _) = conn.recv_from(&mut buf).await?;
+    let mut res = Message::new();
+    res.raw = buf[..n].to_vec();
+    res.decode()?;
+    let mut error_code = ErrorCodeAttribute::default();
+    error_code.get_from(&res)?;
+    let nonce = Nonce::get_from_as(&res, ATTR_NONCE)?;
+    Ok((error_code.code, nonce.text))
+}
+
+#[tokio::test]
+async fn test_authenticate_nonce_bound_to_client_ip() -> Result<(), Error> {
+    let l = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let allocation_manager = Arc::new(Manager::new(ManagerConfig {
+        relay_addr_generator: Box::new(RelayAddressGeneratorNone {
+            address: "127.0.0.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        allocation_limit: None,
+        event_handler: None,
+    }));
+    let auth_handler: Arc<Box<dyn AuthHandler + Send + Sync>> =
+        Arc::new(Box::new(CountingAuthHandler {
+            calls: Arc::new(AtomicUsize::new(0)),
+        }));
+    let nonces = Arc::new(Mutex::new(NonceTable::default()));
+
+    let owner = UdpSocket::bind("127.0.0.1:0").await?;
+    let other = UdpSocket::bind("127.0.0.2:0").await?;
+    let new_request = |src_addr: SocketAddr| {
+        let mut r = Request::new(
+            Arc::clone(&l) as Arc<dyn Conn + Send + Sync>,
+            src_addr,
+            Arc::clone(&allocation_manager),
+            Arc::clone(&auth_handler),
+        );
+        r.nonces = Arc::clone(&nonces);
+        r.bind_nonce_to_client_ip = true;
+        r
+    };
+
+    // the owner is challenged with a nonce minted for its IP
+    let mut r = new_request(owner.local_addr()?);
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+    ])?;
+    assert!(r.authenticate_request(&m, METHOD_ALLOCATE).await?.is_none());
+    let (code, nonce) = recv_error_response(&owner).await?;
+    assert!(CODE_UNAUTHORIZED == code);
+
+    // replayed from another IP it is stale, and the sender gets its own
+    let m = new_allocate_message_with_nonce("user", DEFAULT_LIFETIME, &nonce)?;
+    let mut r = new_request(other.local_addr()?);
+    assert!(r.authenticate_request(&m, METHOD_ALLOCATE).await?.is_none());
+    let (code, other_nonce) = recv_error_response(&other).await?;
+    assert!(CODE_STALE_NONCE == code);
+    assert_ne!(nonce, other_nonce);
+
+    // the replay didn't invalidate the nonce for its owner
+    let mut r = new_request(owner.local_addr()?);
+    assert!(r.authenticate_request(&m, METHOD_ALLOCATE).await?.is_some());
+
+    // a nonce that isn't bound to any IP is stale too
+    nonces
+        .lock()
+        .await
+        .insert(STATIC_KEY.to_owned(), Instant::now());
+    let m = new_allocate_message("user", DEFAULT_LIFETIME)?;
+    let mut r = new_request(owner.local_addr()?);
+    assert!(r.authenticate_request(&m, METHOD_ALLOCATE).await?.is_none());
+    let (code, _) = recv_error_response(&owner).await?;
+    assert!(CODE_STALE_NONCE == code);
+
+    allocation_manager.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use super::*;
+use crate::auth::{generate_auth_key, AuthContext};
+use crate::client::{Client, ClientConfig};
+use crate::relay::PortSelection;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+// SELF_TEST_TIMEOUT bounds each step of the self-test
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SELF_TEST_PAYLOAD: &[u8] = b"turn self-test";
+
+// SelfTestStep is a step of Server::self_test, run in this order for each
+// TURN listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SelfTestStep {
+    // BindRelay binds and releases a relay port with the listener's generator
+    BindRelay,
+    // Allocate allocates through the listener with a temporary credential
+    Allocate,
+    // Relay sends a datagram to a local peer and back through the allocation
+    Relay,
+    // Deallocate deletes the allocation
+    Deallocate,
+}
+
+// SelfTestStepResult is the outcome of a step, error is None when it passed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SelfTestStepResult {
+    pub listener: ListenerId,
+    pub step: SelfTestStep,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+// SelfTestReport lists the steps run by Server::self_test. The steps after a
+// failed one are skipped for its listener. port_selection is the relay port
+// policy of each TURN listener's generator.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SelfTestReport {
+    pub steps: Vec<SelfTestStepResult>,
+    pub port_selection: Vec<(ListenerId, Option<PortSelection>)>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &SelfTestStepResult> {
+        self.steps.iter().filter(|step| step.error.is_some())
+    }
+}
+
+// SelfTestCredentials are the temporary credentials of the running
+// self-tests, each only accepted from the address of its client
+#[derive(Debug, Default)]
+pub(crate) struct SelfTestCredentials {
+    credentials: std::sync::Mutex<HashMap<String, (IpAddr, Vec<u8>)>>,
+}
+
+impl SelfTestCredentials {
+    fn credentials(&self) -> std::sync::MutexGuard<'_, HashMap<String, (IpAddr, Vec<u8>)>> {
+        self.credentials
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.credentials().is_empty()
+    }
+
+    fn key(&self, username: &str, src_addr: SocketAddr) -> Option<Vec<u8>> {
+        self.credentials()
+            .get(username)
+            .filter(|(ip, _)| *ip == src_addr.ip())
+            .map(|(_, key)| key.clone())
+    }
+}
+
+// SelfTestAuthHandler accepts the self-test credentials on top of the
+// configured auth handler
+pub(crate) struct SelfTestAuthHandler {
+    pub(crate) inner: Arc<Box<dyn AuthHandler + Send + Sync>>,
+    pub(crate) credentials: Arc<SelfTestCredentials>,
+}
+
+impl AuthHandler for SelfTestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        match self.credentials.key(username, src_addr) {
+            Some(key) => Ok(key),
+            None => self.inner.auth_handle(username, realm, src_addr),
+        }
+    }
+
+    fn auth_keys(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        match self.credentials.key(username, src_addr) {
+            Some(key) => Ok(vec![key]),
+            None => self.inner.auth_keys(username, realm, src_addr),
+        }
+    }
+
+    fn auth_keys_with_context(&self, ctx: &AuthContext<'_>) -> Result<Vec<Vec<u8>>, Error> {
+        match self.credentials.key(ctx.username, ctx.src_addr) {
+            Some(key) => Ok(vec![key]),
+            None => self.inner.auth_keys_with_context(ctx),
+        }
+    }
+}
+
+// TemporaryCredential is a self-test credential, removed when dropped
+struct TemporaryCredential {
+    credentials: Arc<SelfTestCredentials>,
+    username: String,
+    password: String,
+}
+
+impl TemporaryCredential {
+    fn new(credentials: &Arc<SelfTestCredentials>, realm: &str, client_ip: IpAddr) -> Self {
+        let username = format!("self-test-{}", rand_seq(16));
+        let password = rand_seq(32);
+        let key = generate_auth_key(&username, realm, &password);
+        credentials
+            .credentials()
+            .insert(username.clone(), (client_ip, key));
+        TemporaryCredential {
+            credentials: Arc::clone(credentials),
+            username,
+            password,
+        }
+    }
+}
+
+impl Drop for TemporaryCredential {
+    fn drop(&mut self) {
+        self.credentials.credentials().remove(&self.username);
+    }
+}
+
+// timed runs a step, failing it after SELF_TEST_TIMEOUT
+async fn timed<T>(
+    report: &mut SelfTestReport,
+    listener: ListenerId,
+    step: SelfTestStep,
+    f: impl std::future::Future<Output = Result<T, Error>>,
+) -> Option<T> {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(SELF_TEST_TIMEOUT, f).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Other(format!(
+            "timed out after {:?}",
+            SELF_TEST_TIMEOUT
+        ))),
+    };
+    let (value, error) = match result {
+        Ok(value) => (Some(value), None),
+        Err(err) => {
+            log::warn!("self-test {:?} on {:?} failed: {}", step, listener, err);
+            (None, Some(err.to_string()))
+        }
+    };
+    report.steps.push(SelfTestStepResult {
+        listener,
+        step,
+        elapsed: start.elapsed(),
+        error,
+    });
+    value
+}
+
+// loopback_target is where the self-test client reaches a listener, an
+// unspecified address is reached on loopback
+fn loopback_target(local_addr: SocketAddr) -> SocketAddr {
+    match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), local_addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), local_addr.port())
+        }
+        _ => local_addr,
+    }
+}
+
+async fn relay_round_trip(relay_conn: &impl Conn, peer: &UdpSocket) -> Result<(), Error> {
+    let mut buf = vec![0u8; INBOUND_MTU];
+    relay_conn
+        .send_to(SELF_TEST_PAYLOAD, peer.local_addr()?)
+        .await?;
+    let (n, relayed_from) = peer.recv_from(&mut buf).await?;
+    if &buf[..n] != SELF_TEST_PAYLOAD {
+        return Err(Error::Other("peer received another payload".to_owned()));
+    }
+
+    peer.send_to(SELF_TEST_PAYLOAD, relayed_from).await?;
+    let (n, _) = relay_conn.recv_from(&mut buf).await?;
+    if &buf[..n] != SELF_TEST_PAYLOAD {
+        return Err(Error::Other("client received another payload".to_owned()));
+    }
+    Ok(())
+}
+
+impl Server {
+    // self_test checks each TURN listener can do its job before the server is
+    // put in service: a relay port is bound with its generator, an allocation
+    // is made through it by a client on the same host with a temporary
+    // credential, a datagram is relayed to a local peer and back and the
+    // allocation is deleted. The credential is only accepted from the
+    // client's IP for the duration of the test, so it is safe to run on a
+    // live server, whose metrics and event handler see the allocation.
+    // STUN-only listeners are skipped, without a TURN listener the error is
+    // Error::NoTurnListener.
+    pub async fn self_test(&self) -> Result<SelfTestReport, Error> {
+        let mut report = SelfTestReport::default();
+        let listeners: Vec<&Listener> = self.listeners.iter().filter(|l| !l.stun_only).collect();
+        if listeners.is_empty() {
+            return Err(Error::NoTurnListener);
+        }
+
+        for l in listeners {
+            self.self_test_listener(l, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    async fn self_test_listener(
+        &self,
+        l: &Listener,
+        report: &mut SelfTestReport,
+    ) -> Result<(), Error> {
+        let id = l.id;
+        report
+            .port_selection
+            .push((id, l.allocation_manager.port_selection()));
+        let relay_probe = l.allocation_manager.probe_relay();
+        if timed(report, id, SelfTestStep::BindRelay, relay_probe)
+            .await
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        let target = loopback_target(l.local_addr);
+        let conn = Arc::new(UdpSocket::bind(SocketAddr::new(target.ip(), 0)).await?);
+        let five_tuple = FiveTuple {
+            src_addr: conn.local_addr()?,
+            dst_addr: l.local_addr,
+            protocol: PROTO_UDP,
+        };
+        let peer = UdpSocket::bind(SocketAddr::new(target.ip(), 0)).await?;
+        let credential =
+            TemporaryCredential::new(&self.self_test_credentials, &self.realm, target.ip());
+        let client = Client::new(ClientConfig {
+            stun_serv_addr: target.to_string(),
+            turn_serv_addr: target.to_string(),
+            username: credential.username.clone(),
+            password: credential.password.clone(),
+            ..ClientConfig::new(conn)
+        })
+        .await?;
+        client.listen().await?;
+
+        let allocated = timed(report, id, SelfTestStep::Allocate, client.allocate()).await;
+        if let Some(mut relay_conn) = allocated {
+            let relayed = timed(
+                report,
+                id,
+                SelfTestStep::Relay,
+                relay_round_trip(&relay_conn, &peer),
+            )
+            .await;
+
+            // the zero lifetime Refresh isn't answered before the allocation
+            // is gone, which is waited for
+            let deallocate = async {
+                relay_conn.close().await?;
+                while l
+                    .allocation_manager
+                    .get_allocation(&five_tuple)
+                    .await
+                    .is_some()
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Ok(())
+            };
+            if relayed.is_some() {
+                timed(report, id, SelfTestStep::Deallocate, deallocate).await;
+            } else if let Err(err) = deallocate.await {
+                log::debug!("self-test deallocation failed: {}", err);
+            }
+        }
+
+        client.close().await?;
+        drop(credential);
+        Ok(())
+    }
+}
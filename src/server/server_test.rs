@@ -1,14 +1,30 @@
 use super::config::*;
+use super::event::AllocationCloseReason;
+use super::interceptor::*;
+use super::load::*;
+use super::self_test::*;
 use super::*;
 use crate::auth::generate_auth_key;
+use crate::client::inspect::RawPacketHook;
 use crate::client::*;
-use crate::errors::*;
+use crate::proto::chandata::ChannelData;
+use crate::proto::channum::{ChannelNumber, MIN_CHANNEL_NUMBER};
+use crate::proto::errorcodes::INSUFFICIENT_CAPACITY;
+use crate::relay::relay_pool::PreallocatedRelayPool;
 use crate::relay::relay_static::*;
+use crate::relay::{PortSelection, RelayPoolMetrics};
+use crate::test_util::*;
 
-use std::net::{IpAddr, SocketAddr};
+use stun::agent::TransactionId;
+use stun::attributes::ATTR_MESSAGE_INTEGRITY;
+use stun::error_code::*;
+use stun::fingerprint::FINGERPRINT;
+use stun::message::*;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
 use tokio::net::UdpSocket;
-use util::Error;
 
 struct TestAuthHandler {
     cred_map: HashMap<String, Vec<u8>>,
@@ -36,7 +52,7 @@ impl AuthHandler for TestAuthHandler {
         if let Some(pw) = self.cred_map.get(username) {
             Ok(pw.to_vec())
         } else {
-            Err(ERR_FAKE_ERR.to_owned())
+            Err(Error::Auth(format!("no such user {}", username)))
         }
     }
 }
@@ -55,11 +71,29 @@ async fn test_server_simple() -> Result<(), Error> {
             relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
                 relay_address: IpAddr::from_str("127.0.0.1")?,
                 address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
             }),
+            bind_device: None,
+            stun_only: false,
         }],
         realm: "webrtc.rs".to_owned(),
         auth_handler: Arc::new(Box::new(TestAuthHandler::new())),
         channel_bind_timeout: Duration::from_secs(0),
+        relay_queue_size: 0,
+        relay_read_mode: RelayReadMode::default(),
+        lifetime_jitter: None,
+        max_allocations: None,
+        max_allocations_code: MaxAllocationsCode::default(),
+        event_handler: None,
+        username_validator: None,
+        max_username_len: 0,
+        bind_nonce_to_client_ip: false,
+        interceptor: None,
+        intercept_point: InterceptPoint::default(),
+        stun_only_binding: true,
+        max_pending_challenges: 0,
+        load_factor: LoadFactorConfig::default(),
     })
     .await?;
 
@@ -71,21 +105,14 @@ async fn test_server_simple() -> Result<(), Error> {
     let conn = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
 
     let client = Client::new(ClientConfig {
-        stun_serv_addr: String::new(),
-        turn_serv_addr: String::new(),
-        username: String::new(),
-        password: String::new(),
-        realm: String::new(),
-        software: String::new(),
-        rto_in_ms: 0,
-        conn,
+        ..ClientConfig::new(conn)
     })
     .await?;
 
     client.listen().await?;
 
     client
-        .send_binding_request_to(format!("127.0.0.1:{}", server_port).as_str())
+        .send_binding_request_to(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), server_port))
         .await?;
 
     client.close().await?;
@@ -94,8 +121,810 @@ async fn test_server_simple() -> Result<(), Error> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_server_snapshot() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client_addr = conn.local_addr()?;
+
+    let client = Client::new(client_config(server_addr, conn)).await?;
+    client.listen().await?;
+
+    // the first write creates the permission and starts binding a channel
+    let peer = SocketAddr::from_str("127.0.0.1:8080")?;
+    let allocation = client.allocate().await?;
+    allocation.send_to(&[0x00], peer).await?;
+
+    let mut snapshot = server.snapshot().await;
+    for _ in 0..50 {
+        let bound = snapshot
+            .listeners
+            .iter()
+            .flat_map(|l| &l.allocations)
+            .any(|a| !a.channel_binds.is_empty());
+        if bound {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        snapshot = server.snapshot().await;
+    }
+
+    assert_eq!("webrtc.rs", snapshot.realm);
+    assert_eq!(DEFAULT_LIFETIME, snapshot.channel_bind_timeout);
+    assert!(snapshot.nonces > 0, "nonce table should not be empty");
+    assert_eq!(1, snapshot.allocations);
+    assert_eq!(1, snapshot.listeners.len());
+
+    let listener = &snapshot.listeners[0];
+    assert_eq!(server_addr, listener.local_addr);
+    assert_eq!(1, listener.allocations.len());
+
+    let a = &listener.allocations[0];
+    assert_eq!("UDP", a.protocol);
+    assert_eq!(client_addr, a.src_addr);
+    assert_eq!(server_addr, a.dst_addr);
+    assert_eq!("user", a.username);
+    assert_eq!(allocation.local_addr()?, a.relay_addr);
+    assert!(a.expires_in > Duration::from_secs(0));
+
+    assert_eq!(1, a.permissions.len());
+    assert_eq!(peer, a.permissions[0].addr);
+    assert!(a.permissions[0].expires_in > Duration::from_secs(0));
+
+    assert_eq!(1, a.channel_binds.len(), "channel should be bound");
+    assert_eq!(0x4000, a.channel_binds[0].number);
+    assert_eq!(peer, a.channel_binds[0].peer);
+    assert!(a.channel_binds[0].expires_in > Duration::from_secs(0));
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_value(&snapshot).map_err(|e| Error::Other(e.to_string()))?;
+        assert_eq!(
+            "user", json["listeners"][0]["allocations"][0]["username"],
+            "snapshot should serialize to JSON"
+        );
+    }
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// ChannelBindBlocker rejects ChannelBind requests and records the method and
+// username of each request it saw
+#[derive(Default)]
+struct ChannelBindBlocker {
+    seen: Arc<std::sync::Mutex<Vec<InterceptedRequest>>>,
+}
+
+type InterceptedRequest = (Method, Option<String>);
+
+#[async_trait::async_trait]
+impl RequestInterceptor for ChannelBindBlocker {
+    async fn on_request(
+        &self,
+        msg: &Message,
+        _src: SocketAddr,
+        ctx: &RequestCtx,
+    ) -> InterceptDecision {
+        self.seen
+            .lock()
+            .unwrap()
+            .push((msg.typ.method, ctx.username.clone()));
+        if msg.typ.method == METHOD_CHANNEL_BIND {
+            InterceptDecision::Reject {
+                code: CODE_FORBIDDEN,
+                reason: "channels are disabled".to_owned(),
+            }
+        } else {
+            InterceptDecision::Continue
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_interceptor_rejects_channel_bind() -> Result<(), Error> {
+    let interceptor = ChannelBindBlocker::default();
+    let seen = Arc::clone(&interceptor.seen);
+
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(move |builder| {
+            builder.interceptor(Box::new(interceptor))
+        })),
+        ..Default::default()
+    })
+    .await?;
+
+    // the rejection is signed, the client sees it as a ChannelBind error
+    let rejections = Arc::new(std::sync::Mutex::new(vec![]));
+    let on_recv_raw: RawPacketHook = {
+        let rejections = Arc::clone(&rejections);
+        Arc::new(move |raw: &[u8], _from: SocketAddr| {
+            let mut m = Message::new();
+            m.raw = raw.to_vec();
+            if m.decode().is_ok()
+                && m.typ == MessageType::new(METHOD_CHANNEL_BIND, CLASS_ERROR_RESPONSE)
+            {
+                let mut error_code = ErrorCodeAttribute::default();
+                let forbidden =
+                    error_code.get_from(&m).is_ok() && error_code.code == CODE_FORBIDDEN;
+                rejections
+                    .lock()
+                    .unwrap()
+                    .push((forbidden, m.contains(ATTR_MESSAGE_INTEGRITY)));
+            }
+        })
+    };
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(ClientConfig {
+        on_recv_raw: Some(on_recv_raw),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    let allocation = client.allocate().await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut buf = vec![0u8; 1500];
+
+    // every write gets through as a Send indication
+    for i in 0..3u8 {
+        allocation.send_to(&[i], peer.local_addr()?).await?;
+        let (n, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+            .await
+            .map_err(|_| Error::Other("peer read timed out".to_owned()))??;
+        assert_eq!(&[i], &buf[..n]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    assert!(seen
+        .lock()
+        .unwrap()
+        .contains(&(METHOD_CHANNEL_BIND, Some("user".to_owned()))));
+    assert_eq!(vec![(true, true)], *rejections.lock().unwrap());
+    let snapshot = server.snapshot().await;
+    assert!(snapshot
+        .listeners
+        .iter()
+        .flat_map(|l| &l.allocations)
+        .all(|a| a.channel_binds.is_empty()));
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_new_rejects_unbindable_relay_address() -> Result<(), Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let mut config = ServerConfig::builder()
+        .add_conn(
+            conn,
+            Box::new(RelayAddressGeneratorStatic {
+                relay_address: IpAddr::from_str("127.0.0.1")?,
+                address: "127.0.0.1".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+        )
+        .realm("webrtc.rs")
+        .auth_handler(Box::new(TestAuthHandler::new()))
+        .build()?;
+
+    // a second listener relays from an address this host doesn't have
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let listener = conn.local_addr()?;
+    config.conn_configs.push(ConnConfig {
+        conn,
+        relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+            relay_address: IpAddr::from_str("192.0.2.1")?,
+            address: "192.0.2.1".to_owned(),
+            bind_device: None,
+            port_selection: PortSelection::default(),
+        }),
+        bind_device: None,
+        stun_only: false,
+    });
+
+    match Server::new(config).await {
+        Err(Error::ListenerInvalid {
+            listener: name,
+            err,
+        }) => {
+            assert_eq!(listener.to_string(), name);
+            assert!(matches!(*err, Error::RelayBind { .. }), "{}", err);
+        }
+        result => panic!("expected ListenerInvalid error, got {:?}", result.err()),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_listener_metrics() -> Result<(), Error> {
+    let mut builder = ServerConfig::builder()
+        .realm("webrtc.rs")
+        .auth_handler(Box::new(TestAuthHandler::new()));
+    let mut server_addrs = vec![];
+    for _ in 0..2 {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        server_addrs.push(conn.local_addr()?);
+        builder = builder.add_conn(
+            conn,
+            Box::new(RelayAddressGeneratorStatic {
+                relay_address: IpAddr::from_str("127.0.0.1")?,
+                address: "0.0.0.0".to_owned(),
+                bind_device: None,
+                port_selection: PortSelection::default(),
+            }),
+        );
+    }
+    let server = Server::new(builder.build()?).await?;
+
+    let new_client = |server_addr: SocketAddr, password: &str| {
+        let password = password.to_owned();
+        async move {
+            let client = Client::new(ClientConfig {
+                stun_serv_addr: server_addr.to_string(),
+                turn_serv_addr: server_addr.to_string(),
+                username: "user".to_owned(),
+                password,
+                ..ClientConfig::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+            })
+            .await?;
+            client.listen().await?;
+            Ok::<Client, Error>(client)
+        }
+    };
+
+    // the first listener gets an allocation, the second a wrong password
+    let client = new_client(server_addrs[0], "pass").await?;
+    let mut allocation = client.allocate().await?;
+    let intruder = new_client(server_addrs[1], "wrong").await?;
+    assert!(intruder.allocate().await.is_err());
+
+    let metrics = server.listener_metrics();
+    assert_eq!(2, metrics.len());
+    let (id, first) = metrics[0];
+    assert_eq!(ListenerId(0), id);
+    assert_eq!(server_addrs[0], first.local_addr);
+    assert_eq!(1, first.allocations_created);
+    assert_eq!(0, first.auth_failures);
+    // Allocate is answered 401 first, then succeeds
+    assert_eq!(2, first.packets_in);
+    assert_eq!(2, first.packets_out);
+    assert!(first.bytes_in > 0 && first.bytes_out > 0);
+
+    let (id, second) = metrics[1];
+    assert_eq!(ListenerId(1), id);
+    assert_eq!(server_addrs[1], second.local_addr);
+    assert_eq!(0, second.allocations_created);
+    assert_eq!(1, second.auth_failures);
+    assert_eq!(2, second.packets_in);
+    assert_eq!(2, second.packets_out);
+
+    // the zero lifetime Refresh isn't waited for
+    allocation.close().await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.listener_metrics()[0]
+            .1
+            .allocations_closed
+            .get(AllocationCloseReason::ClientDeallocated)
+            == 0
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("allocation not deallocated".to_owned()))?;
+
+    client.close().await?;
+    intruder.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_load_factor() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| builder.max_allocations(4))),
+        ..Default::default()
+    })
+    .await?;
+
+    let components = server.load_components().await;
+    assert_eq!(0, components.allocations);
+    assert_eq!(Some(4), components.max_allocations);
+    // the static relay generator lets the OS pick the ports
+    assert_eq!(None, components.port_capacity);
+    assert_eq!(0.0, server.load_factor().await);
+
+    let mut clients = vec![];
+    let mut allocations = vec![];
+    let mut factors = vec![];
+    for _ in 0..4 {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let client = Client::new(client_config(server_addr, conn)).await?;
+        client.listen().await?;
+        allocations.push(client.allocate().await?);
+        clients.push(client);
+        factors.push(server.load_factor().await);
+    }
+    assert_eq!(vec![0.25, 0.5, 0.75, 1.0], factors);
+    assert_eq!(4, server.load_components().await.allocations);
+
+    for client in clients {
+        client.close().await?;
+    }
+    server.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_load_components_load_factor() {
+    let components = LoadComponents {
+        allocations: 10,
+        max_allocations: Some(100),
+        ports_in_use: 10,
+        port_capacity: Some(20),
+        bandwidth: 300,
+        bandwidth_budget: None,
+    };
+
+    // allocations at 0.1 and ports at 0.5, bandwidth has no budget
+    let config = LoadFactorConfig::default();
+    assert!((components.load_factor(&config) - 0.3).abs() < 1e-9);
+
+    let config = LoadFactorConfig {
+        allocation_weight: 3.0,
+        port_weight: 1.0,
+        ..Default::default()
+    };
+    assert!((components.load_factor(&config) - 0.2).abs() < 1e-9);
+
+    // a component at its cap makes the server full
+    let saturated = LoadComponents {
+        bandwidth_budget: Some(300),
+        ..components
+    };
+    assert_eq!(1.0, saturated.load_factor(&LoadFactorConfig::default()));
+
+    let uncapped = LoadComponents {
+        max_allocations: None,
+        port_capacity: None,
+        ..components
+    };
+    assert_eq!(0.0, uncapped.load_factor(&LoadFactorConfig::default()));
+}
+
+// A shutdown while clients are allocating waits for the requests being
+// handled and the relay tasks of the allocations made, then nothing is left
+#[tokio::test]
+async fn test_server_shutdown_during_allocates() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let mut clients = vec![];
+    for _ in 0..50 {
+        clients.push(tokio::spawn(async move {
+            let client = start_client(server_addr).await?;
+            // the Allocates after the shutdown are never answered
+            let allocated =
+                tokio::time::timeout(Duration::from_millis(500), client.allocate()).await;
+            client.close().await?;
+            Ok::<_, Error>(allocated.is_ok_and(|allocated| allocated.is_ok()))
+        }));
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.listener_metrics()[0].1.allocations_created == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("nothing allocated".to_owned()))?;
+
+    server.shutdown(Duration::from_secs(5)).await?;
+    assert_eq!(0, server.snapshot().await.allocations);
+    let created = server.listener_metrics()[0].1.allocations_created;
+
+    let mut allocated = 0;
+    for client in clients {
+        let client = client
+            .await
+            .map_err(|err| Error::Other(format!("client task failed: {}", err)))?;
+        if client? {
+            allocated += 1;
+        }
+    }
+    assert!(allocated as u64 <= created);
+
+    Ok(())
+}
+
+// close doesn't wait, but the allocations are closed once the read loops
+// have stopped, with their relay tasks and sockets
+#[tokio::test]
+async fn test_server_close_closes_allocations() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let client = start_client(server_addr).await?;
+    let relay_conn = client.allocate().await?;
+    assert_eq!(1, server.snapshot().await.allocations);
+
+    server.close()?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.snapshot().await.allocations != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for l in &server.listeners {
+            l.allocation_manager.relay_tasks_exited().await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("allocations not closed".to_owned()))?;
+    assert_eq!(
+        1,
+        server.listener_metrics()[0]
+            .1
+            .allocations_closed
+            .get(AllocationCloseReason::Shutdown)
+    );
+
+    drop(relay_conn);
+    client.close().await?;
+
+    Ok(())
+}
+
+// ChannelData and indications from a 5-tuple without an allocation are
+// dropped on their first bytes and counted, requests are still answered
+#[tokio::test]
+async fn test_server_drops_unallocated_relay_traffic() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let sender = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut channel_data = ChannelData {
+        data: vec![0xff; 100],
+        number: ChannelNumber(MIN_CHANNEL_NUMBER),
+        raw: vec![],
+    };
+    channel_data.encode()?;
+    let mut indication = Message::new();
+    indication.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_SEND, CLASS_INDICATION)),
+        Box::new(FINGERPRINT),
+    ])?;
+    // a truncated indication would not even decode
+    for raw in &[
+        &channel_data.raw[..],
+        &indication.raw[..],
+        &indication.raw[..4],
+    ] {
+        sender.send_to(raw, server_addr).await?;
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.listener_metrics()[0].1.unallocated_dropped < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("relay traffic not dropped".to_owned()))?;
+
+    let client = start_client(server_addr).await?;
+    let _allocation = client.allocate().await?;
+    let (_, metrics) = server.listener_metrics()[0];
+    assert_eq!(3, metrics.unallocated_dropped);
+    assert_eq!(1, metrics.allocations_created);
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// Unauthenticated Allocates from many sources each get a nonce, the table
+// stays within max_pending_challenges and a client still authenticates
+#[tokio::test]
+async fn test_server_nonce_flood_is_bounded() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| builder.max_pending_challenges(64))),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut buf = vec![0u8; 1500];
+    for _ in 0..10 {
+        let flooder = UdpSocket::bind("127.0.0.1:0").await?;
+        for _ in 0..100 {
+            let mut m = Message::new();
+            m.build(&[
+                Box::new(TransactionId::new()),
+                Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+            ])?;
+            flooder.send_to(&m.raw, server_addr).await?;
+            tokio::time::timeout(Duration::from_secs(5), flooder.recv_from(&mut buf))
+                .await
+                .map_err(|_| Error::Other("challenge not received".to_owned()))??;
+        }
+    }
+
+    let metrics = server.nonce_metrics().await;
+    assert_eq!(64, metrics.capacity);
+    assert_eq!(64, metrics.pending);
+    assert_eq!(1000 - 64, metrics.evicted);
+    assert_eq!(0, metrics.expired);
+
+    let client = start_client(server_addr).await?;
+    let _allocation = client.allocate().await?;
+    assert_eq!(64, server.nonce_metrics().await.pending);
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// The self-test of a fresh server passes every step on its TURN listener and
+// leaves nothing behind
+#[tokio::test]
+async fn test_server_self_test() -> Result<(), Error> {
+    let server = Server::new(
+        ServerConfig::builder()
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(TestAuthHandler::new()))
+            .add_conn(
+                Arc::new(UdpSocket::bind("0.0.0.0:0").await?),
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: IpAddr::from_str("127.0.0.1")?,
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .add_stun_only_conn(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+            .build()?,
+    )
+    .await?;
+
+    let report = server.self_test().await?;
+    assert!(report.passed(), "{:?}", report);
+    assert_eq!(
+        vec![
+            SelfTestStep::BindRelay,
+            SelfTestStep::Allocate,
+            SelfTestStep::Relay,
+            SelfTestStep::Deallocate
+        ],
+        report.steps.iter().map(|s| s.step).collect::<Vec<_>>()
+    );
+    assert!(report.steps.iter().all(|s| s.listener == ListenerId(0)));
+    assert_eq!(
+        vec![(ListenerId(0), Some(PortSelection::Random))],
+        report.port_selection
+    );
+
+    assert_eq!(0, server.snapshot().await.allocations);
+    assert!(server.self_test_credentials.is_empty());
+    let (_, metrics) = server.listener_metrics()[0];
+    assert_eq!(1, metrics.allocations_created);
+    assert_eq!(
+        1,
+        metrics
+            .allocations_closed
+            .get(AllocationCloseReason::ClientDeallocated)
+    );
+
+    server.close()?;
+
+    Ok(())
+}
+
+// A failed step is reported and ends the self-test of its listener
+#[tokio::test]
+async fn test_server_self_test_failure() -> Result<(), Error> {
+    let (server, _) = TestTurnServer::start(TestTurnServerOpts {
+        configure: Some(Box::new(|builder| {
+            builder.username_validator(|username| !username.starts_with("self-test-"))
+        })),
+        ..Default::default()
+    })
+    .await?;
+
+    let report = server.self_test().await?;
+    assert!(!report.passed());
+    let failures: Vec<_> = report.failures().map(|s| s.step).collect();
+    assert_eq!(vec![SelfTestStep::Allocate], failures);
+    assert_eq!(2, report.steps.len());
+    server.close()?;
+
+    let stun_only = Server::new(
+        ServerConfig::builder()
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(TestAuthHandler::new()))
+            .add_stun_only_conn(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+            .build()?,
+    )
+    .await?;
+    assert!(matches!(
+        stun_only.self_test().await,
+        Err(Error::NoTurnListener)
+    ));
+
+    Ok(())
+}
+
 /* TODO: use vnet
 func TestServerVNet(t *testing.T) {
 
 }
  */
+
+// A client that never allocates gets its reflexive address from any listener,
+// a STUN-only listener refuses to allocate
+#[tokio::test]
+async fn test_server_stun_only() -> Result<(), Error> {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let turn_addr = conn.local_addr()?;
+    let stun_conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let stun_addr = stun_conn.local_addr()?;
+
+    let server = Server::new(
+        ServerConfig::builder()
+            .add_conn(
+                conn,
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: IpAddr::from_str("127.0.0.1")?,
+                    address: "0.0.0.0".to_owned(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .add_stun_only_conn(stun_conn)
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(TestAuthHandler::new()))
+            .build()?,
+    )
+    .await?;
+
+    let client_conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client_addr = client_conn.local_addr()?;
+    let client = Client::new(ClientConfig {
+        turn_serv_addr: stun_addr.to_string(),
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        ..ClientConfig::new(client_conn)
+    })
+    .await?;
+    client.listen().await?;
+
+    assert_eq!(
+        client_addr,
+        client.send_binding_request_to(turn_addr).await?
+    );
+    assert_eq!(
+        client_addr,
+        client.send_binding_request_to(stun_addr).await?
+    );
+
+    let result = client.allocate().await;
+    assert!(
+        matches!(result, Err(Error::Protocol { code: 403, .. })),
+        "expected 403 from the STUN-only listener, got {:?}",
+        result.err()
+    );
+    assert_eq!(0, server.snapshot().await.allocations);
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+// The relay sockets of a PreallocatedRelayPool are bound before the server is
+// made, an Allocate finding them all in use gets a 508 and a deallocated
+// socket is handed out again
+#[tokio::test]
+async fn test_server_preallocated_relay_pool() -> Result<(), Error> {
+    let mut sockets = vec![];
+    let mut relay_ports = vec![];
+    for _ in 0..2 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        relay_ports.push(socket.local_addr()?.port());
+        sockets.push((socket, IpAddr::from_str("127.0.0.1")?));
+    }
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let server_addr = conn.local_addr()?;
+    let server = Server::new(
+        ServerConfig::builder()
+            .realm("webrtc.rs")
+            .auth_handler(Box::new(TestAuthHandler::new()))
+            .add_conn(conn, Box::new(PreallocatedRelayPool::new(sockets)))
+            .build()?,
+    )
+    .await?;
+
+    let new_client = || async move {
+        let client = Client::new(ClientConfig {
+            stun_serv_addr: server_addr.to_string(),
+            turn_serv_addr: server_addr.to_string(),
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+            ..ClientConfig::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await?))
+        })
+        .await?;
+        client.listen().await?;
+        Ok::<Client, Error>(client)
+    };
+    let pool_metrics = || server.listener_metrics()[0].1.relay_pool;
+
+    let mut clients = vec![];
+    let mut allocations = vec![];
+    for _ in 0..2 {
+        let client = new_client().await?;
+        allocations.push(client.allocate().await?);
+        clients.push(client);
+    }
+    let mut allocated_ports: Vec<u16> = allocations
+        .iter()
+        .map(|a| a.local_addr().map(|addr| addr.port()))
+        .collect::<Result<_, _>>()?;
+    allocated_ports.sort_unstable();
+    relay_ports.sort_unstable();
+    assert_eq!(relay_ports, allocated_ports);
+
+    // the pool is exhausted
+    let third = new_client().await?;
+    let result = third.allocate().await;
+    assert!(
+        matches!(
+            result,
+            Err(Error::Protocol {
+                code: INSUFFICIENT_CAPACITY,
+                ..
+            })
+        ),
+        "expected 508"
+    );
+    assert_eq!(
+        Some(RelayPoolMetrics {
+            size: 2,
+            in_use: 2,
+            exhausted: 1,
+        }),
+        pool_metrics()
+    );
+
+    // the socket is back once the allocation's relay tasks are done
+    let mut released = allocations.remove(0);
+    let released_port = released.local_addr()?.port();
+    released.close().await?;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while pool_metrics().map(|m| m.in_use) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| Error::Other("relay socket not reclaimed".to_owned()))?;
+
+    let mut reused = third.allocate().await?;
+    assert_eq!(released_port, reused.local_addr()?.port());
+    assert_eq!(Some(2), pool_metrics().map(|m| m.in_use));
+
+    reused.close().await?;
+    for mut allocation in allocations {
+        allocation.close().await?;
+    }
+    third.close().await?;
+    for client in clients {
+        client.close().await?;
+    }
+    server.close()?;
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use super::*;
+
+pub use crate::allocation::snapshot::*;
+
+// ServerSnapshot is a point-in-time view of a Server for debugging
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ServerSnapshot {
+    pub realm: String,
+    pub channel_bind_timeout: Duration,
+    pub nonces: usize,
+    pub allocations: usize,
+    // relay queue drops summed over the allocations
+    pub dropped_to_client: u64,
+    pub dropped_to_peer: u64,
+    pub listeners: Vec<ListenerSnapshot>,
+}
+
+// ListenerSnapshot lists the allocations made through one turn listener
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerSnapshot {
+    pub local_addr: SocketAddr,
+    pub allocations: Vec<AllocationSnapshot>,
+}
+
+impl Server {
+    // snapshot copies out the current server state, locks are only held
+    // while copying so request handling is not stalled
+    pub async fn snapshot(&self) -> ServerSnapshot {
+        let nonces = {
+            let nonces = self.nonces.lock().await;
+            nonces.len()
+        };
+
+        let mut listeners = vec![];
+        for l in &self.listeners {
+            let mut allocations = vec![];
+            for a in l.allocation_manager.allocations().await {
+                allocations.push(AllocationSnapshot::take(&a).await);
+            }
+            allocations.sort_by_key(|a| (a.src_addr, a.dst_addr));
+
+            listeners.push(ListenerSnapshot {
+                local_addr: l.local_addr,
+                allocations,
+            });
+        }
+
+        let allocations = listeners.iter().flat_map(|l| l.allocations.iter());
+        let dropped_to_client = allocations.clone().map(|a| a.dropped_to_client).sum();
+        let dropped_to_peer = allocations.map(|a| a.dropped_to_peer).sum();
+
+        ServerSnapshot {
+            realm: self.realm.clone(),
+            channel_bind_timeout: self.channel_bind_timeout,
+            nonces,
+            allocations: listeners.iter().map(|l| l.allocations.len()).sum(),
+            dropped_to_client,
+            dropped_to_peer,
+            listeners,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// test_util starts an in-process TURN server, echo peers and clients for
+// integration tests, with the `test-util` feature. It is for tests only and
+// not covered by semver, anything in it may change in any release.
+#[cfg(all(test, feature = "client"))]
+mod test_util_test;
+
+use crate::auth::{generate_auth_key, AuthHandler};
+use crate::error::Error;
+use crate::relay::relay_static::RelayAddressGeneratorStatic;
+use crate::relay::PortSelection;
+use crate::server::config::{ServerConfig, ServerConfigBuilder};
+use crate::server::Server;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+pub const TEST_REALM: &str = "webrtc.rs";
+pub const TEST_USERNAME: &str = "user";
+pub const TEST_PASSWORD: &str = "pass";
+
+// StaticAuthHandler authenticates a fixed set of users of one realm
+pub struct StaticAuthHandler {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl StaticAuthHandler {
+    // new takes the username and password of each user
+    pub fn new(realm: &str, users: &[(String, String)]) -> Self {
+        StaticAuthHandler {
+            keys: users
+                .iter()
+                .map(|(username, password)| {
+                    (
+                        username.clone(),
+                        generate_auth_key(username, realm, password),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for StaticAuthHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut usernames: Vec<&String> = self.keys.keys().collect();
+        usernames.sort();
+        f.debug_struct("StaticAuthHandler")
+            .field("usernames", &usernames)
+            .finish()
+    }
+}
+
+impl AuthHandler for StaticAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        _realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        self.keys
+            .get(username)
+            .cloned()
+            .ok_or_else(|| Error::Auth(format!("no such user {}", username)))
+    }
+}
+
+// ConfigureServer changes the server config of a TestTurnServer before it is
+// built, e.g. to add an interceptor
+pub type ConfigureServer = Box<dyn FnOnce(ServerConfigBuilder) -> ServerConfigBuilder + Send>;
+
+// TestTurnServerOpts is how TestTurnServer::start sets the server up, the
+// default listens on 127.0.0.1, relays on 127.0.0.1 and lets TEST_USERNAME
+// in with TEST_PASSWORD in TEST_REALM
+pub struct TestTurnServerOpts {
+    pub listen_addr: SocketAddr,
+    pub relay_address: IpAddr,
+    pub realm: String,
+    pub users: Vec<(String, String)>,
+    pub configure: Option<ConfigureServer>,
+}
+
+impl Default for TestTurnServerOpts {
+    fn default() -> Self {
+        TestTurnServerOpts {
+            listen_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            relay_address: Ipv4Addr::LOCALHOST.into(),
+            realm: TEST_REALM.to_owned(),
+            users: vec![(TEST_USERNAME.to_owned(), TEST_PASSWORD.to_owned())],
+            configure: None,
+        }
+    }
+}
+
+impl fmt::Debug for TestTurnServerOpts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestTurnServerOpts")
+            .field("listen_addr", &self.listen_addr)
+            .field("relay_address", &self.relay_address)
+            .field("realm", &self.realm)
+            .field("configure", &self.configure.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+// ServerHandle is a running TestTurnServer, it derefs to the Server
+pub struct ServerHandle {
+    server: Server,
+}
+
+impl Deref for ServerHandle {
+    type Target = Server;
+
+    fn deref(&self) -> &Server {
+        &self.server
+    }
+}
+
+// TestTurnServer starts TURN servers for tests
+pub struct TestTurnServer;
+
+impl TestTurnServer {
+    // start runs a server with one UDP listener and returns it with the
+    // listener's address
+    pub async fn start(opts: TestTurnServerOpts) -> Result<(ServerHandle, SocketAddr), Error> {
+        let conn = Arc::new(UdpSocket::bind(opts.listen_addr).await?);
+        let server_addr = conn.local_addr()?;
+
+        let mut builder = ServerConfig::builder()
+            .add_conn(
+                conn,
+                Box::new(RelayAddressGeneratorStatic {
+                    relay_address: opts.relay_address,
+                    address: opts.relay_address.to_string(),
+                    bind_device: None,
+                    port_selection: PortSelection::default(),
+                }),
+            )
+            .realm(&opts.realm)
+            .auth_handler(Box::new(StaticAuthHandler::new(&opts.realm, &opts.users)));
+        if let Some(configure) = opts.configure {
+            builder = configure(builder);
+        }
+        let server = Server::new(builder.build()?).await?;
+
+        Ok((ServerHandle { server }, server_addr))
+    }
+}
+
+// spawn_udp_echo_peer binds a peer on 127.0.0.1 that sends every datagram
+// back to where it came from, for as long as the runtime lives
+pub async fn spawn_udp_echo_peer() -> Result<SocketAddr, Error> {
+    let peer = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+    let peer_addr = peer.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, from)) = peer.recv_from(&mut buf).await {
+            if peer.send_to(&buf[..n], from).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(peer_addr)
+}
+
+// client_config is the config of a client of the TURN and STUN server at
+// server_addr on conn, logging in as TEST_USERNAME
+#[cfg(feature = "client")]
+pub fn client_config(
+    server_addr: SocketAddr,
+    conn: Arc<dyn util::Conn + Send + Sync>,
+) -> crate::client::ClientConfig {
+    crate::client::ClientConfig {
+        stun_serv_addr: server_addr.to_string(),
+        turn_serv_addr: server_addr.to_string(),
+        username: TEST_USERNAME.to_owned(),
+        password: TEST_PASSWORD.to_owned(),
+        ..crate::client::ClientConfig::new(conn)
+    }
+}
+
+// start_client starts a listening client of the server at server_addr on a
+// socket of its own, with client_config
+#[cfg(feature = "client")]
+pub async fn start_client(server_addr: SocketAddr) -> Result<crate::client::Client, Error> {
+    let conn = Arc::new(UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?);
+    let client = crate::client::Client::new(client_config(server_addr, conn)).await?;
+    client.listen().await?;
+    Ok(client)
+}
@@ -0,0 +1,45 @@
+use super::*;
+use crate::client::Client;
+
+use tokio::time::Duration;
+use util::Conn;
+
+#[tokio::test]
+async fn test_test_turn_server_relays_to_echo_peer() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+    let peer = spawn_udp_echo_peer().await?;
+
+    let client = start_client(server_addr).await?;
+    let allocation = client.allocate().await?;
+    allocation.send_to(b"echo", peer).await?;
+
+    let mut buf = vec![0u8; 1500];
+    let (n, from) = tokio::time::timeout(Duration::from_secs(5), allocation.recv_from(&mut buf))
+        .await
+        .map_err(|_| Error::Other("relay read timed out".to_owned()))??;
+    assert_eq!((&b"echo"[..], peer), (&buf[..n], from));
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_test_turn_server_rejects_unknown_user() -> Result<(), Error> {
+    let (server, server_addr) = TestTurnServer::start(TestTurnServerOpts::default()).await?;
+
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let client = Client::new(crate::client::ClientConfig {
+        username: "nobody".to_owned(),
+        ..client_config(server_addr, conn)
+    })
+    .await?;
+    client.listen().await?;
+    assert!(client.allocate().await.is_err(), "unknown user allocated");
+
+    client.close().await?;
+    server.close()?;
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+// trace routes spans and state transition events to tracing when the trace
+// feature is enabled, and falls back to log (without spans) otherwise.
+//
+// Span fields must never carry secrets such as integrity keys or passwords.
+
+#[cfg(feature = "trace")]
+pub(crate) use tracing::{Instrument, Span};
+
+// Span is a no-op stand-in for tracing::Span
+#[cfg(not(feature = "trace"))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "trace"))]
+impl Span {
+    pub(crate) fn none() -> Self {
+        Span
+    }
+
+    pub(crate) fn current() -> Self {
+        Span
+    }
+
+    pub(crate) fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+        f()
+    }
+}
+
+// Instrument is a no-op stand-in for tracing::Instrument
+#[cfg(not(feature = "trace"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+impl<T: Sized> Instrument for T {}
+
+// turn_span! creates a span, the field arguments are dropped without tracing
+#[cfg(feature = "trace")]
+#[allow(unused_macros)]
+macro_rules! turn_span {
+    ($($arg:tt)+) => {
+        tracing::debug_span!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+#[allow(unused_macros)]
+macro_rules! turn_span {
+    ($($arg:tt)+) => {
+        $crate::trace::Span::none()
+    };
+}
+
+// state_event! reports a state transition, it only takes a format string
+// and arguments so it works with both tracing and log
+#[cfg(feature = "trace")]
+#[allow(unused_macros)]
+macro_rules! state_event {
+    ($($arg:tt)+) => {
+        tracing::debug!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "trace"))]
+#[allow(unused_macros)]
+macro_rules! state_event {
+    ($($arg:tt)+) => {
+        log::debug!($($arg)+)
+    };
+}